@@ -0,0 +1,193 @@
+// メッセージとファイルのチャンクを、テキストにする → メモリ上の通信路 (WebSocket、TLSあり・なし) → 解釈する までの速さを測る
+// cargo bench --bench throughput (結果は target/criterion に残り、前回との差が表示される)
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use rust_p2p_chat::builder::TlsMode;
+use rust_p2p_chat::identity::{self, Identity};
+use rust_p2p_chat::protocol::Envelope;
+use rust_p2p_chat::runtime::BoxStream;
+use rust_p2p_chat::tls;
+use tokio::runtime::Runtime;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+// 1回の計測で送るメッセージの数
+const MESSAGES: u64 = 1000;
+// 1回の計測で送るファイルの大きさ
+const FILE_BYTES: usize = 4 * 1024 * 1024;
+// fanoutで出来事を受け取る購読者の数と、メッセージの本文の長さ (文字数)
+const FANOUT_SUBSCRIBERS: usize = 8;
+const FANOUT_BODY_CHARS: usize = 4096;
+// メモリ上の通信路が溜めておけるバイト数
+const PIPE_BUFFER: usize = 256 * 1024;
+
+type Socket = WebSocketStream<BoxStream>;
+
+// 通信路の種類
+#[derive(Debug, Clone, Copy)]
+enum Link {
+    Plain,
+    Tls,
+}
+
+struct Pair {
+    sender: SplitSink<Socket, Message>,
+    receiver: SplitStream<Socket>,
+    identity: Identity,
+}
+
+fn identity() -> Identity {
+    let dir = std::env::temp_dir().join(format!("p2pchat-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let identity = Identity::load_or_generate(dir.join("cert.der"), dir.join("key.der")).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    identity
+}
+
+async fn pair(link: Link) -> Pair {
+    let identity = identity();
+    let (client, server) = tokio::io::duplex(PIPE_BUFFER);
+    let (client, server): (BoxStream, BoxStream) = match link {
+        Link::Plain => (Box::new(client), Box::new(server)),
+        Link::Tls => {
+            let acceptor = tls::acceptor(&identity, TlsMode::default()).unwrap();
+            let connector = tls::connector(TlsMode::default());
+            let domain = tokio_rustls::rustls::pki_types::ServerName::try_from("localhost").unwrap();
+            let (client, server) = tokio::join!(connector.connect(domain, client), acceptor.accept(server));
+            (Box::new(client.unwrap()), Box::new(server.unwrap()))
+        }
+    };
+    let (client, server) = tokio::join!(
+        WebSocketStream::from_raw_socket(client, Role::Client, None),
+        WebSocketStream::from_raw_socket(server, Role::Server, None),
+    );
+    let (sender, _) = client.split();
+    let (_, receiver) = server.split();
+    Pair { sender, receiver, identity }
+}
+
+// envelopesを送り、受信側ですべて解釈し終えるまで待つ。verifyがtrueなら署名も検証する
+async fn transfer(pair: &mut Pair, envelopes: Vec<Envelope>, verify: bool) {
+    let count = envelopes.len();
+    let send = async {
+        for envelope in envelopes {
+            pair.sender.feed(Message::Text(envelope.encode())).await.unwrap();
+        }
+        pair.sender.flush().await.unwrap();
+    };
+    let cert = pair.identity.cert_der.clone();
+    let receive = async {
+        for _ in 0..count {
+            let Some(Ok(Message::Text(text))) = pair.receiver.next().await else {
+                panic!("テキストフレームが届きません");
+            };
+            match Envelope::decode(&text) {
+                Envelope::Chat { seq, body, sig: Some(sig) } if verify => {
+                    assert!(identity::verify(&cert, &identity::signed_content(seq, &body), &sig));
+                }
+                Envelope::FileChunk { data, .. } => {
+                    std::hint::black_box(data);
+                }
+                envelope => {
+                    std::hint::black_box(envelope);
+                }
+            }
+        }
+    };
+    tokio::join!(send, receive);
+}
+
+fn messages(identity: &Identity, sign: bool) -> Vec<Envelope> {
+    (1..=MESSAGES)
+        .map(|seq| {
+            let body = format!("メッセージ {} の本文です。ふつうのチャットくらいの長さにします。", seq);
+            let sig = sign.then(|| identity.sign(&identity::signed_content(seq, &body)).unwrap());
+            Envelope::Chat { seq, body, sig }
+        })
+        .collect()
+}
+
+// 送信側と同じく、読み込んだ内容を共有したままチャンクに分ける
+fn file_chunks(content: &Bytes) -> Vec<Envelope> {
+    (0..content.len())
+        .step_by(rust_p2p_chat::transfer::CHUNK_SIZE)
+        .map(|start| Envelope::FileChunk {
+            id: 1,
+            offset: start as u64,
+            data: content.slice(start..(start + rust_p2p_chat::transfer::CHUNK_SIZE).min(content.len())),
+        })
+        .collect()
+}
+
+fn bench_messages(c: &mut Criterion) {
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("messages");
+    group.throughput(Throughput::Elements(MESSAGES));
+    for link in [Link::Plain, Link::Tls] {
+        // 署名ありは送信側の署名と受信側の検証を含める
+        for sign in [false, true] {
+            let mut pair = runtime.block_on(pair(link));
+            let name = if sign { "signed" } else { "unsigned" };
+            group.bench_with_input(BenchmarkId::new(format!("{:?}", link), name), &sign, |b, &sign| {
+                b.iter(|| {
+                    let envelopes = messages(&pair.identity, sign);
+                    runtime.block_on(transfer(&mut pair, envelopes, sign));
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_file(c: &mut Criterion) {
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("file");
+    group.throughput(Throughput::Bytes(FILE_BYTES as u64));
+    group.sample_size(20);
+    let content: Bytes = (0..FILE_BYTES).map(|i| (i % 251) as u8).collect::<Vec<u8>>().into();
+    for link in [Link::Plain, Link::Tls] {
+        let mut pair = runtime.block_on(pair(link));
+        group.bench_function(format!("{:?}", link), |b| {
+            b.iter(|| runtime.block_on(transfer(&mut pair, file_chunks(&content), false)))
+        });
+    }
+    group.finish();
+}
+
+// デーモンの出来事 (jsonlの1行) を、購読しているもの (ctl events・gRPC・プッシュ通知) のそれぞれに配る
+fn bench_fanout(c: &mut Criterion) {
+    type Line = std::sync::Arc<str>;
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("fanout");
+    group.throughput(Throughput::Elements(MESSAGES));
+    let body = "長めのメッセージ。".repeat(FANOUT_BODY_CHARS / 9);
+    let line = serde_json::json!({ "event": "message", "room": "192.0.2.5", "body": body }).to_string();
+    for subscribers in [1, FANOUT_SUBSCRIBERS] {
+        group.bench_with_input(BenchmarkId::from_parameter(subscribers), &subscribers, |b, &subscribers| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let (events, _) = tokio::sync::broadcast::channel::<Line>(MESSAGES as usize);
+                    let mut receivers: Vec<_> = (0..subscribers).map(|_| events.subscribe()).collect();
+                    for _ in 0..MESSAGES {
+                        events.send(Line::from(line.as_str())).unwrap();
+                    }
+                    for receiver in &mut receivers {
+                        for _ in 0..MESSAGES {
+                            std::hint::black_box(receiver.recv().await.unwrap().len());
+                        }
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_messages, bench_file, bench_fanout);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+// ffi フィーチャーでは、C言語から使う関数の宣言を include/p2pchat.h に書き出す
+// grpc フィーチャーでは、proto/p2pchat.proto からサービスとメッセージの型を生成する
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR が設定されていません");
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).expect("cbindgen.toml を読み込めません");
+        cbindgen::Builder::new()
+            .with_crate(&dir)
+            .with_config(config)
+            .generate()
+            .expect("C言語のヘッダーを生成できません")
+            .write_to_file(format!("{}/include/p2pchat.h", dir));
+    }
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/p2pchat.proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("protocが見つかりません"));
+        tonic_build::compile_protos("proto/p2pchat.proto").expect("proto/p2pchat.proto をコンパイルできません");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::protocol::Envelope;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+// クライアント側の1つの接続の状態 (ブラウザなど、履歴やファイルを持たない組み込み先用)
+// 受け取ったテキストフレームを出来事と相手への返信に変え、送るメッセージに通し番号を付ける
+// ファイルは受け取らず、相手には受け取らなかったことを知らせる
+#[derive(Debug)]
+pub struct Conversation {
+    /// 次に送るメッセージの通し番号
+    next_seq: u64,
+    /// 受け取った相手側の通し番号の最大値 (再接続時の再送要求に使う)
+    last_remote_seq: u64,
+    /// 前回のセッションを再開するためのトークン
+    token: Option<String>,
+    /// 署名を検証するための相手の証明書 (DER)
+    peer_cert: Option<Vec<u8>>,
+}
+
+impl Default for Conversation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 受け取ったフレームから分かったこと
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    /// 相手のメッセージ。sigは相手の鍵による署名 (base64、protocol::signed_content参照)
+    /// backfilledは再接続時に再送されたもの
+    Message { seq: u64, body: String, sig: Option<String>, backfilled: bool },
+    /// 自分のメッセージが相手に届いた
+    Delivered { seq: u64 },
+    /// 相手がセッションを再開した・新しく始めた
+    Session { resumed: bool },
+    /// 相手がファイルを送ろうとした (受け取らない)
+    FileRefused { name: String },
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self { next_seq: 1, last_remote_seq: 0, token: None, peer_cert: None }
+    }
+
+    // 接続した直後に送る。再接続の場合は前回のセッションを再開し、切断中のメッセージの再送を求める
+    pub fn hello(&self) -> Envelope {
+        Envelope::Resume { token: self.token.clone(), since: self.last_remote_seq }
+    }
+
+    // 送るメッセージに通し番号を付ける。届くと同じseqで Received::Delivered が返る
+    pub fn send(&mut self, body: &str) -> (u64, Envelope) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        (seq, Envelope::Chat { seq, body: body.to_string(), sig: None })
+    }
+
+    pub fn peer_cert(&self) -> Option<&[u8]> {
+        self.peer_cert.as_deref()
+    }
+
+    // 受け取ったテキストフレームを解釈し、分かったことと、相手に返すEnvelopeを返す
+    pub fn receive(&mut self, text: &str) -> (Vec<Received>, Vec<Envelope>) {
+        let mut received = Vec::new();
+        let mut replies = Vec::new();
+        match Envelope::decode(text) {
+            Envelope::Chat { seq, body, sig } => {
+                self.message(seq, body, sig, false, &mut received, &mut replies);
+            }
+            Envelope::Backfill { messages } => {
+                for message in messages {
+                    self.message(message.seq, message.body, message.sig, true, &mut received, &mut replies);
+                }
+            }
+            Envelope::Identity { cert } => self.peer_cert = BASE64.decode(cert).ok(),
+            Envelope::Delivered { seq } => received.push(Received::Delivered { seq }),
+            Envelope::Session { token, resumed, .. } => {
+                self.token = Some(token);
+                received.push(Received::Session { resumed });
+            }
+            Envelope::Ping { sent_at } => replies.push(Envelope::Pong { sent_at }),
+            // 履歴を持たないので、再送するメッセージはない
+            Envelope::BackfillRequest { .. } => replies.push(Envelope::Backfill { messages: Vec::new() }),
+            Envelope::FileStart { name, .. } | Envelope::StreamStart { name, .. } => received.push(Received::FileRefused { name }),
+            // 受け取らないチャンクにも応答し、送信側がFileEndまで送れるようにする
+            Envelope::FileChunk { id, offset, data } => replies.push(Envelope::FileAck { id, received: offset + data.len() as u64 }),
+            Envelope::FileEnd { id } | Envelope::StreamEnd { id, .. } => replies.push(Envelope::FileReceived { id, ok: false }),
+            Envelope::Resume { .. }
+            | Envelope::FileAck { .. }
+            | Envelope::FileCancel { .. }
+            | Envelope::FileReceived { .. }
+            | Envelope::Pong { .. } => {}
+        }
+        (received, replies)
+    }
+
+    fn message(
+        &mut self,
+        seq: u64,
+        body: String,
+        sig: Option<String>,
+        backfilled: bool,
+        received: &mut Vec<Received>,
+        replies: &mut Vec<Envelope>,
+    ) {
+        // 通し番号のない (旧バージョンの) メッセージには受信確認を送らない
+        if seq > 0 {
+            if backfilled && seq <= self.last_remote_seq {
+                return;
+            }
+            self.last_remote_seq = self.last_remote_seq.max(seq);
+            replies.push(Envelope::Delivered { seq });
+        }
+        received.push(Received::Message { seq, body, sig, backfilled });
+    }
+}
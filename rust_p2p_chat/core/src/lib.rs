@@ -0,0 +1,12 @@
+// 相手とやり取りするメッセージの形式と、通信路に依存しないチャットの進め方
+// tokio・TLS・ファイルに依存しないため、wasm32-unknown-unknown (ブラウザ) でもビルドできる
+
+pub mod conversation;
+pub mod pool;
+pub mod protocol;
+pub mod vectors;
+#[cfg(feature = "web")]
+mod web;
+
+pub use conversation::{Conversation, Received};
+pub use protocol::{BackfillMessage, Envelope};
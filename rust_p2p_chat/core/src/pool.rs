@@ -0,0 +1,126 @@
+use bytes::Bytes;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// ファイル転送のチャンクを入れるバッファの使い回し
+// 受け取ったFileChunkを戻すたび・標準入力から読むたびに新しく確保せず、使い終わったバッファを次のチャンクに使う
+// (数GBの転送でもアロケータへの要求がチャンクの数だけ増えない)
+
+// 待機させておくバッファの数 (使い終わったものがこれより多ければ解放する)
+const MAX_IDLE: usize = 64;
+// これより大きくなったバッファは待機させない (大きすぎるチャンクでメモリを抱え込まないように)
+const MAX_CAPACITY: usize = 1024 * 1024;
+
+// FileChunkのdataに使うバッファ
+pub static CHUNKS: BufferPool = BufferPool::new();
+
+pub struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    in_use: AtomicU64,
+}
+
+// /stats で表示する使い回しの状況
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// 新しく確保した回数
+    pub allocated: u64,
+    /// 待機していたバッファを使った回数
+    pub reused: u64,
+    /// 貸し出している (チャンクが使っている) 数
+    pub in_use: u64,
+    /// 待機している数
+    pub idle: usize,
+}
+
+impl BufferPool {
+    pub const fn new() -> Self {
+        Self { idle: Mutex::new(Vec::new()), allocated: AtomicU64::new(0), reused: AtomicU64::new(0), in_use: AtomicU64::new(0) }
+    }
+
+    // 空のバッファを借りる (少なくともcapacityバイトは確保済み)
+    pub fn take(&'static self, capacity: usize) -> PooledBuffer {
+        let idle = self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).pop();
+        let mut buffer = match idle {
+            Some(buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        };
+        buffer.reserve(capacity);
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        PooledBuffer { buffer, pool: self }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            idle: self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len(),
+        }
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        if buffer.capacity() > MAX_CAPACITY {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if idle.len() < MAX_IDLE {
+            buffer.clear();
+            idle.push(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 借りたバッファ。捨てると (Bytesにした場合は最後の参照がなくなると) プールに戻る
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: &'static BufferPool,
+}
+
+impl PooledBuffer {
+    // 書き込んだ内容をFileChunkのdataにする (写さずに共有し、使い終わるとプールに戻る)
+    pub fn freeze(self) -> Bytes {
+        Bytes::from_owner(self)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buffer));
+    }
+}
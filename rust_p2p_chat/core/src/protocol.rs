@@ -0,0 +1,165 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+use crate::pool;
+
+// WebSocketのテキストフレーム (ブラウザではWebRTCのデータチャネルでも) でやり取りするメッセージの形式
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope {
+    /// チャットメッセージ。seqは送信側の履歴上の通し番号
+    /// sigは送信側の鍵による署名 (base64、identity::signed_content参照)
+    Chat {
+        seq: u64,
+        body: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sig: Option<String>,
+    },
+    /// 接続直後に互いに送る。メッセージの署名を検証するための証明書 (DER、base64)
+    Identity { cert: String },
+    /// 指定した通し番号より後のメッセージを再送してもらう
+    BackfillRequest { since: u64 },
+    /// 接続直後にクライアントが送る。tokenがあれば前回のセッションを再開し、sinceより後のメッセージを再送してもらう
+    Resume { token: Option<String>, since: u64 },
+    /// Resumeへの応答。以降の再接続で使うトークンと、このセッションを再開した回数
+    Session {
+        token: String,
+        resumed: bool,
+        #[serde(default)]
+        resumes: u32,
+    },
+    /// BackfillRequestへの応答
+    Backfill { messages: Vec<BackfillMessage> },
+    /// ファイル送信の開始。sha256は送信前に計算したファイル全体のハッシュ(hex)
+    FileStart {
+        id: u64,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// ファイルの一部。JSONではdataをbase64で表す
+    FileChunk {
+        id: u64,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Bytes,
+    },
+    /// FileChunkを受け取ったことを送信側に知らせる。receivedはその転送でこれまでに受け取ったバイト数
+    /// 送信側はreceivedより決まった量以上先のチャンクを送らずに待つ (フロー制御)
+    FileAck { id: u64, received: u64 },
+    /// ファイル送信の終了
+    FileEnd { id: u64 },
+    /// サイズの分からないデータ (send --stream --raw) の送信の開始。続けてFileChunkを送る
+    StreamStart { id: u64, name: String },
+    /// ストリームの終了。sizeとsha256は送ったデータ全体のもの
+    StreamEnd { id: u64, size: u64, sha256: String },
+    /// ファイル送信の取り消し (FileEndは送らない)。受信側は受信途中のデータを破棄する
+    FileCancel { id: u64 },
+    /// FileEnd・StreamEndへの応答。okは受信したファイルを保存できたか (受け取りを断った場合と検証に失敗した場合はfalse)
+    FileReceived { id: u64, ok: bool },
+    /// Chat・Backfillで受け取ったメッセージが届いたことを送信側に知らせる (seqは送信側の通し番号)
+    Delivered { seq: u64 },
+    /// /ping による往復時間の測定。sent_atは送信側で接続してからの経過時間 (ナノ秒)
+    Ping { sent_at: u64 },
+    /// Pingへの応答。sent_atは受け取ったPingの値をそのまま返す
+    Pong { sent_at: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackfillMessage {
+    pub seq: u64,
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<String>,
+}
+
+// FileChunkの先頭 (encodeが書き出す形)。この形で届いたものはdecodeで中間の値を作らずに解釈する
+const FILE_CHUNK_PREFIX: &str = r#"{"type":"file_chunk","#;
+
+// decodeでFileChunkを直接解釈するときの形 (typeは読み飛ばす)。dataはJSONの文字列をそのまま借りる
+#[derive(Deserialize)]
+struct RawFileChunk<'a> {
+    id: u64,
+    offset: u64,
+    data: &'a str,
+}
+
+impl Envelope {
+    pub fn encode(&self) -> String {
+        // ファイルの一部は大きいので、base64を出力に直接書き込む (base64にはJSONでエスケープする文字がない)
+        if let Envelope::FileChunk { id, offset, data } = self {
+            let mut text = String::with_capacity(FILE_CHUNK_PREFIX.len() + 64 + data.len().div_ceil(3) * 4);
+            let _ = write!(text, r#"{}"id":{},"offset":{},"data":""#, FILE_CHUNK_PREFIX, id, offset);
+            BASE64.encode_string(data, &mut text);
+            text.push_str("\"}");
+            return text;
+        }
+        // Envelopeは常にJSONに変換できる
+        serde_json::to_string(self).expect("Envelopeのシリアライズに失敗しました")
+    }
+
+    // 旧バージョンとの互換性のため、JSONとして解釈できないテキストは通常のチャットとして扱う
+    pub fn decode(text: &str) -> Envelope {
+        // encodeが書き出した形のFileChunkは、base64の文字列を写さずにバイト列へ戻す
+        // (タグ付きのenumとして解釈すると、いったん文字列を写した中間の値を作る)
+        if text.starts_with(FILE_CHUNK_PREFIX) {
+            if let Ok(RawFileChunk { id, offset, data }) = serde_json::from_str(text) {
+                if let Ok(data) = decode_chunk(data) {
+                    return Envelope::FileChunk { id, offset, data };
+                }
+            }
+        }
+        serde_json::from_str(text).unwrap_or_else(|_| Envelope::Chat {
+            seq: 0,
+            body: text.to_string(),
+            sig: None,
+        })
+    }
+}
+
+// base64のdataを、プールから借りたバッファに戻す
+fn decode_chunk(data: &str) -> Result<Bytes, base64::DecodeError> {
+    let mut buffer = pool::CHUNKS.take(base64::decoded_len_estimate(data.len()));
+    BASE64.decode_vec(data, &mut buffer)?;
+    Ok(buffer.freeze())
+}
+
+// 署名の対象。送信側の通し番号と本文を結び付ける
+pub fn signed_content(seq: u64, body: &str) -> Vec<u8> {
+    format!("rust_p2p_chat message v1\n{}\n{}", seq, body).into_bytes()
+}
+
+// FileChunkのdataをJSONではbase64の文字列で表す
+// (encode・decodeはFileChunkを直接書き出し・解釈するので、ここを通るのは項目の順序が違う形で届いた場合など)
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use bytes::Bytes;
+    use serde::{de, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        struct Base64Visitor;
+
+        impl de::Visitor<'_> for Base64Visitor {
+            type Value = Bytes;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("base64の文字列")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Bytes, E> {
+                super::decode_chunk(text).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(Base64Visitor)
+    }
+}
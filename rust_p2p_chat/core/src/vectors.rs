@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use chrono::{FixedOffset, Local, TimeZone};
+
+use crate::protocol::{signed_content, BackfillMessage, Envelope};
+
+// 他の実装が同じ形式でやり取りできるかを確かめるための、メッセージの例 (テストベクター)
+// text を受け取って envelope と同じものとして解釈でき、envelope を送った形が text と同じものとして解釈されれば互換がある
+// (項目の順序と空白は問わない。conformance --vectors で1行に1つのJSONとして出力する)
+
+// 1つのメッセージの例
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    pub name: &'static str,
+    /// WebSocketのテキストフレームで届く形
+    pub text: &'static str,
+    pub envelope: Envelope,
+}
+
+// 署名の対象 (signed_content) の例
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedContentVector {
+    pub seq: u64,
+    pub body: &'static str,
+    /// 署名するバイト列 (UTF-8)
+    pub content: &'static str,
+}
+
+pub fn envelopes() -> Vec<Vector> {
+    let timestamp = FixedOffset::east_opt(9 * 3600).unwrap().with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap().with_timezone(&Local);
+    vec![
+        Vector {
+            name: "chat",
+            text: r#"{"type":"chat","seq":1,"body":"こんにちは"}"#,
+            envelope: Envelope::Chat { seq: 1, body: "こんにちは".to_string(), sig: None },
+        },
+        Vector {
+            name: "chat_signed",
+            text: r#"{"type":"chat","seq":2,"body":"line1\nline2 \"quoted\"","sig":"MEUCIQ=="}"#,
+            envelope: Envelope::Chat { seq: 2, body: "line1\nline2 \"quoted\"".to_string(), sig: Some("MEUCIQ==".to_string()) },
+        },
+        // JSONとして解釈できないテキストは、通し番号0のチャットとして扱う (旧バージョンとの互換)
+        Vector {
+            name: "legacy_text",
+            text: "plain text",
+            envelope: Envelope::Chat { seq: 0, body: "plain text".to_string(), sig: None },
+        },
+        Vector { name: "identity", text: r#"{"type":"identity","cert":"MIIB"}"#, envelope: Envelope::Identity { cert: "MIIB".to_string() } },
+        Vector {
+            name: "resume_new",
+            text: r#"{"type":"resume","token":null,"since":0}"#,
+            envelope: Envelope::Resume { token: None, since: 0 },
+        },
+        Vector {
+            name: "resume_token",
+            text: r#"{"type":"resume","token":"3f2a","since":41}"#,
+            envelope: Envelope::Resume { token: Some("3f2a".to_string()), since: 41 },
+        },
+        Vector {
+            name: "session",
+            text: r#"{"type":"session","token":"3f2a","resumed":true,"resumes":2}"#,
+            envelope: Envelope::Session { token: "3f2a".to_string(), resumed: true, resumes: 2 },
+        },
+        // resumes のない古い応答は0回として扱う
+        Vector {
+            name: "session_without_resumes",
+            text: r#"{"type":"session","token":"3f2a","resumed":false}"#,
+            envelope: Envelope::Session { token: "3f2a".to_string(), resumed: false, resumes: 0 },
+        },
+        Vector { name: "backfill_request", text: r#"{"type":"backfill_request","since":7}"#, envelope: Envelope::BackfillRequest { since: 7 } },
+        Vector {
+            name: "backfill",
+            text: r#"{"type":"backfill","messages":[{"seq":8,"timestamp":"2024-05-01T12:30:00+09:00","body":"missed"}]}"#,
+            envelope: Envelope::Backfill { messages: vec![BackfillMessage { seq: 8, timestamp, body: "missed".to_string(), sig: None }] },
+        },
+        Vector {
+            name: "file_start",
+            text: r#"{"type":"file_start","id":3,"name":"a.txt","size":5,"sha256":"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}"#,
+            envelope: Envelope::FileStart {
+                id: 3,
+                name: "a.txt".to_string(),
+                size: 5,
+                sha256: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string(),
+            },
+        },
+        Vector {
+            name: "file_chunk",
+            text: r#"{"type":"file_chunk","id":3,"offset":0,"data":"aGVsbG8="}"#,
+            envelope: Envelope::FileChunk { id: 3, offset: 0, data: Bytes::from_static(b"hello") },
+        },
+        // 項目の順序は問わない
+        Vector {
+            name: "file_chunk_reordered",
+            text: r#"{"offset":5,"data":"d29ybGQ=","id":3,"type":"file_chunk"}"#,
+            envelope: Envelope::FileChunk { id: 3, offset: 5, data: Bytes::from_static(b"world") },
+        },
+        Vector { name: "file_ack", text: r#"{"type":"file_ack","id":3,"received":10}"#, envelope: Envelope::FileAck { id: 3, received: 10 } },
+        Vector { name: "file_end", text: r#"{"type":"file_end","id":3}"#, envelope: Envelope::FileEnd { id: 3 } },
+        Vector {
+            name: "stream_start",
+            text: r#"{"type":"stream_start","id":4,"name":"stdin"}"#,
+            envelope: Envelope::StreamStart { id: 4, name: "stdin".to_string() },
+        },
+        Vector {
+            name: "stream_end",
+            text: r#"{"type":"stream_end","id":4,"size":0,"sha256":"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"}"#,
+            envelope: Envelope::StreamEnd { id: 4, size: 0, sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string() },
+        },
+        Vector { name: "file_cancel", text: r#"{"type":"file_cancel","id":3}"#, envelope: Envelope::FileCancel { id: 3 } },
+        Vector { name: "file_received", text: r#"{"type":"file_received","id":3,"ok":true}"#, envelope: Envelope::FileReceived { id: 3, ok: true } },
+        Vector { name: "delivered", text: r#"{"type":"delivered","seq":1}"#, envelope: Envelope::Delivered { seq: 1 } },
+        Vector { name: "ping", text: r#"{"type":"ping","sent_at":1500000}"#, envelope: Envelope::Ping { sent_at: 1_500_000 } },
+        Vector { name: "pong", text: r#"{"type":"pong","sent_at":1500000}"#, envelope: Envelope::Pong { sent_at: 1_500_000 } },
+    ]
+}
+
+pub fn signed_contents() -> Vec<SignedContentVector> {
+    vec![
+        SignedContentVector { seq: 1, body: "こんにちは", content: "rust_p2p_chat message v1\n1\nこんにちは" },
+        SignedContentVector { seq: 42, body: "", content: "rust_p2p_chat message v1\n42\n" },
+        SignedContentVector { seq: 7, body: "a\nb", content: "rust_p2p_chat message v1\n7\na\nb" },
+    ]
+}
+
+// 例がこの実装で成り立つかを確かめ、成り立たない例の名前と理由を返す
+pub fn check() -> Vec<(&'static str, String)> {
+    let mut failures = Vec::new();
+    for vector in envelopes() {
+        let decoded = Envelope::decode(vector.text);
+        if decoded != vector.envelope {
+            failures.push((vector.name, format!("{} を {:?} と解釈しました", vector.text, decoded)));
+        }
+        let encoded = vector.envelope.encode();
+        if Envelope::decode(&encoded) != vector.envelope {
+            failures.push((vector.name, format!("{} として送った形を解釈し直せません", encoded)));
+        }
+    }
+    for vector in signed_contents() {
+        if signed_content(vector.seq, vector.body) != vector.content.as_bytes() {
+            failures.push(("signed_content", format!("seq {} の署名の対象が一致しません", vector.seq)));
+        }
+    }
+    failures
+}
@@ -0,0 +1,154 @@
+use crate::conversation::{Conversation, Received};
+use crate::protocol::signed_content;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+// ブラウザから使うクライアント (wasm-pack build --target web -- --features web)
+//
+//     const client = new ChatClient("wss://192.0.2.1:8080", (event) => console.log(event));
+//     client.send("こんにちは");
+//
+// 相手の証明書はブラウザが検証するため、自己署名の証明書は事前にブラウザで開いて信頼しておく
+// WebRTCのデータチャネルなど別の通信路を使う場合は、Conversationに受け取ったテキストを渡す
+
+// JavaScriptのコールバックに渡す出来事 (typeで種類を見分ける)
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WebEvent<'a> {
+    Open,
+    /// verifiedは署名と相手の証明書がある場合だけtrue・false
+    Message { seq: u64, body: &'a str, verified: Option<bool>, backfilled: bool },
+    Delivered { seq: u64 },
+    Session { resumed: bool },
+    FileRefused { name: &'a str },
+    Closed { code: u16, reason: String },
+    Error,
+}
+
+#[wasm_bindgen]
+pub struct ChatClient {
+    socket: WebSocket,
+    conversation: Rc<RefCell<Conversation>>,
+    // ソケットのハンドラー。ChatClientを捨てるまで残す
+    _on_open: Closure<dyn FnMut()>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+    _on_error: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+#[wasm_bindgen]
+impl ChatClient {
+    // urlに接続する。出来事はon_eventに ({type: "message", seq, body, verified, backfilled} など) で渡す
+    #[wasm_bindgen(constructor)]
+    pub fn new(url: &str, on_event: js_sys::Function) -> Result<ChatClient, JsValue> {
+        let socket = WebSocket::new(url)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let conversation = Rc::new(RefCell::new(Conversation::new()));
+
+        let on_open = {
+            let (socket, conversation, on_event) = (socket.clone(), conversation.clone(), on_event.clone());
+            Closure::<dyn FnMut()>::new(move || {
+                let _ = socket.send_with_str(&conversation.borrow().hello().encode());
+                emit(&on_event, &WebEvent::Open);
+            })
+        };
+        let on_message = {
+            let (socket, conversation, on_event) = (socket.clone(), conversation.clone(), on_event.clone());
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                // テキストフレームだけを使う
+                let Some(text) = event.data().as_string() else {
+                    return;
+                };
+                let (received, replies) = conversation.borrow_mut().receive(&text);
+                for reply in replies {
+                    let _ = socket.send_with_str(&reply.encode());
+                }
+                let conversation = conversation.borrow();
+                for item in &received {
+                    emit(&on_event, &web_event(&conversation, item));
+                }
+            })
+        };
+        let on_close = {
+            let on_event = on_event.clone();
+            Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+                emit(&on_event, &WebEvent::Closed { code: event.code(), reason: event.reason() });
+            })
+        };
+        let on_error = Closure::<dyn FnMut(web_sys::Event)>::new(move |_| emit(&on_event, &WebEvent::Error));
+
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        Ok(ChatClient {
+            socket,
+            conversation,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+            _on_error: on_error,
+        })
+    }
+
+    // メッセージを送り、通し番号を返す (届くと同じseqで {type: "delivered"} が来る)
+    pub fn send(&self, body: &str) -> Result<f64, JsValue> {
+        let (seq, envelope) = self.conversation.borrow_mut().send(body);
+        self.socket.send_with_str(&envelope.encode())?;
+        Ok(seq as f64)
+    }
+
+    // 相手に切断を知らせる
+    pub fn close(&self) -> Result<(), JsValue> {
+        self.socket.close_with_code(1000)
+    }
+}
+
+fn web_event<'a>(conversation: &Conversation, received: &'a Received) -> WebEvent<'a> {
+    match received {
+        Received::Message { seq, body, sig, backfilled } => {
+            let verified = match (sig, conversation.peer_cert()) {
+                (Some(sig), Some(cert)) => Some(verify(cert, &signed_content(*seq, body), sig)),
+                _ => None,
+            };
+            WebEvent::Message { seq: *seq, body, verified, backfilled: *backfilled }
+        }
+        Received::Delivered { seq } => WebEvent::Delivered { seq: *seq },
+        Received::Session { resumed } => WebEvent::Session { resumed: *resumed },
+        Received::FileRefused { name } => WebEvent::FileRefused { name },
+    }
+}
+
+fn emit(callback: &js_sys::Function, event: &WebEvent) {
+    let Ok(json) = serde_json::to_string(event) else {
+        return;
+    };
+    let value = js_sys::JSON::parse(&json).unwrap_or(JsValue::NULL);
+    let _ = callback.call1(&JsValue::NULL, &value);
+}
+
+// 証明書の公開鍵 (ECDSA P-256) でbase64の署名 (DER) を検証する。ネイティブ側のidentity::verifyと同じ結果になる
+fn verify(cert_der: &[u8], message: &[u8], signature: &str) -> bool {
+    use p256::ecdsa::signature::Verifier;
+    use p256::ecdsa::{DerSignature, VerifyingKey};
+    use x509_cert::der::Decode;
+
+    let Ok(cert) = x509_cert::Certificate::from_der(cert_der) else {
+        return false;
+    };
+    let Some(key) = cert.tbs_certificate.subject_public_key_info.subject_public_key.as_bytes() else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_sec1_bytes(key) else {
+        return false;
+    };
+    let Some(signature) = BASE64.decode(signature).ok().and_then(|bytes| DerSignature::try_from(bytes.as_slice()).ok()) else {
+        return false;
+    };
+    key.verify(message, &signature).is_ok()
+}
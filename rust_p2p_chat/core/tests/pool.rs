@@ -0,0 +1,47 @@
+// チャンクのバッファが使い終わるとプールに戻り、次のチャンクで使い回されることを確かめる
+
+use bytes::Bytes;
+use p2pchat_core::pool::{self, BufferPool};
+use p2pchat_core::Envelope;
+
+static POOL: BufferPool = BufferPool::new();
+
+#[test]
+fn buffers_return_and_are_reused() {
+    let mut first = POOL.take(1024);
+    first.extend_from_slice(b"hello");
+    let frozen = first.freeze();
+    assert_eq!(&frozen[..], b"hello");
+    assert_eq!(POOL.stats().in_use, 1);
+
+    // 最後の参照がなくなるとプールに戻る
+    let shared = frozen.clone();
+    drop(frozen);
+    assert_eq!(POOL.stats().in_use, 1);
+    drop(shared);
+    assert_eq!(POOL.stats().in_use, 0);
+    assert_eq!(POOL.stats().idle, 1);
+
+    // 戻ったバッファは空にしてから貸し出す
+    let second = POOL.take(1024);
+    assert!(second.is_empty());
+    assert!(second.capacity() >= 1024);
+    drop(second);
+    let stats = POOL.stats();
+    assert_eq!((stats.allocated, stats.reused, stats.in_use, stats.idle), (1, 1, 0, 1));
+}
+
+#[test]
+fn decoded_chunks_reuse_buffers() {
+    let chunk = Envelope::FileChunk { id: 1, offset: 0, data: Bytes::from(vec![7u8; 64 * 1024]) };
+    let text = chunk.encode();
+    let before = pool::CHUNKS.stats();
+    for _ in 0..100 {
+        assert_eq!(Envelope::decode(&text), chunk);
+    }
+    let after = pool::CHUNKS.stats();
+    // 1つずつ受け取って捨てる間は、最初に確保したバッファを使い回す
+    assert!(after.allocated - before.allocated <= 1, "{:?} -> {:?}", before, after);
+    assert!(after.reused - before.reused >= 99, "{:?} -> {:?}", before, after);
+    assert_eq!(after.in_use, 0);
+}
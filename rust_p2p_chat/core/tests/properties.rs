@@ -0,0 +1,131 @@
+// メッセージの形式と通し番号の扱いについて、任意の入力で成り立つ性質を確かめる
+
+use chrono::{Local, TimeZone};
+use p2pchat_core::{BackfillMessage, Conversation, Envelope, Received};
+use proptest::prelude::*;
+
+fn text() -> impl Strategy<Value = String> {
+    // 制御文字・改行・JSONの記号・全角文字を含める
+    proptest::string::string_regex("[a-z0-9 {}\":\\\\\\n\\t\\u{0}-\\u{1f}あ-ん😀]{0,40}").unwrap()
+}
+
+fn backfill_message() -> impl Strategy<Value = BackfillMessage> {
+    (any::<u64>(), 0i64..4_000_000_000, 0u32..1_000_000_000, text(), proptest::option::of(text())).prop_map(
+        |(seq, secs, nanos, body, sig)| BackfillMessage { seq, timestamp: Local.timestamp_opt(secs, nanos).unwrap(), body, sig },
+    )
+}
+
+fn envelope() -> impl Strategy<Value = Envelope> {
+    prop_oneof![
+        (any::<u64>(), text(), proptest::option::of(text())).prop_map(|(seq, body, sig)| Envelope::Chat { seq, body, sig }),
+        text().prop_map(|cert| Envelope::Identity { cert }),
+        any::<u64>().prop_map(|since| Envelope::BackfillRequest { since }),
+        (proptest::option::of(text()), any::<u64>()).prop_map(|(token, since)| Envelope::Resume { token, since }),
+        (text(), any::<bool>(), any::<u32>()).prop_map(|(token, resumed, resumes)| Envelope::Session { token, resumed, resumes }),
+        proptest::collection::vec(backfill_message(), 0..4).prop_map(|messages| Envelope::Backfill { messages }),
+        (any::<u64>(), text(), any::<u64>(), text()).prop_map(|(id, name, size, sha256)| Envelope::FileStart { id, name, size, sha256 }),
+        (any::<u64>(), any::<u64>(), proptest::collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(id, offset, data)| Envelope::FileChunk { id, offset, data: data.into() }),
+        any::<u64>().prop_map(|id| Envelope::FileEnd { id }),
+        (any::<u64>(), text()).prop_map(|(id, name)| Envelope::StreamStart { id, name }),
+        (any::<u64>(), any::<u64>(), text()).prop_map(|(id, size, sha256)| Envelope::StreamEnd { id, size, sha256 }),
+        (any::<u64>(), any::<u64>()).prop_map(|(id, received)| Envelope::FileAck { id, received }),
+        any::<u64>().prop_map(|id| Envelope::FileCancel { id }),
+        (any::<u64>(), any::<bool>()).prop_map(|(id, ok)| Envelope::FileReceived { id, ok }),
+        any::<u64>().prop_map(|seq| Envelope::Delivered { seq }),
+        any::<u64>().prop_map(|sent_at| Envelope::Ping { sent_at }),
+        any::<u64>().prop_map(|sent_at| Envelope::Pong { sent_at }),
+    ]
+}
+
+// 相手から届くメッセージ。通常の送信か、再接続時の再送 (まとめて届く)
+#[derive(Debug, Clone)]
+enum Delivery {
+    Live(u64),
+    Backfill(Vec<u64>),
+}
+
+fn delivery() -> impl Strategy<Value = Delivery> {
+    // 重複が起きやすいよう、通し番号の範囲を狭くする
+    prop_oneof![
+        (0u64..20).prop_map(Delivery::Live),
+        proptest::collection::vec(0u64..20, 0..6).prop_map(Delivery::Backfill),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn envelope_round_trip(envelope in envelope()) {
+        prop_assert_eq!(Envelope::decode(&envelope.encode()), envelope);
+    }
+
+    // JSONでないテキストは旧バージョンのチャットとしてそのまま本文になる
+    #[test]
+    fn plain_text_is_chat(body in "[^{\\s].*") {
+        prop_assert_eq!(Envelope::decode(&body), Envelope::Chat { seq: 0, body: body.clone(), sig: None });
+    }
+
+    #[test]
+    fn send_numbers_increase(bodies in proptest::collection::vec(text(), 1..20)) {
+        let mut conversation = Conversation::new();
+        for (expected, body) in (1u64..).zip(&bodies) {
+            let (seq, envelope) = conversation.send(body);
+            prop_assert_eq!(seq, expected);
+            prop_assert_eq!(envelope, Envelope::Chat { seq, body: body.clone(), sig: None });
+        }
+    }
+
+    // 再送されたメッセージは、すでに受け取った通し番号までのものを表示しない
+    // 表示したメッセージ (通し番号あり) にはそれぞれ1回だけ受信確認を返し、再接続時には受け取った最大の通し番号から再送を求める
+    #[test]
+    fn sequence_numbers_dedup(deliveries in proptest::collection::vec(delivery(), 0..20)) {
+        let mut conversation = Conversation::new();
+        let mut highest = 0;
+        for delivery in deliveries {
+            let envelope = match &delivery {
+                Delivery::Live(seq) => Envelope::Chat { seq: *seq, body: format!("#{}", seq), sig: None },
+                Delivery::Backfill(seqs) => Envelope::Backfill {
+                    messages: seqs
+                        .iter()
+                        .map(|&seq| BackfillMessage { seq, timestamp: Local::now(), body: format!("#{}", seq), sig: None })
+                        .collect(),
+                },
+            };
+            let (received, replies) = conversation.receive(&envelope.encode());
+
+            let mut expected_replies = Vec::new();
+            let mut expected = Vec::new();
+            match delivery {
+                Delivery::Live(seq) => {
+                    expected.push((seq, false));
+                    if seq > 0 {
+                        highest = highest.max(seq);
+                        expected_replies.push(Envelope::Delivered { seq });
+                    }
+                }
+                Delivery::Backfill(seqs) => {
+                    for seq in seqs {
+                        if seq > 0 && seq <= highest {
+                            continue;
+                        }
+                        expected.push((seq, true));
+                        if seq > 0 {
+                            highest = seq;
+                            expected_replies.push(Envelope::Delivered { seq });
+                        }
+                    }
+                }
+            }
+            let shown: Vec<(u64, bool)> = received
+                .iter()
+                .map(|item| match item {
+                    Received::Message { seq, backfilled, .. } => (*seq, *backfilled),
+                    other => panic!("メッセージ以外の出来事: {:?}", other),
+                })
+                .collect();
+            prop_assert_eq!(shown, expected);
+            prop_assert_eq!(replies, expected_replies);
+            prop_assert_eq!(conversation.hello(), Envelope::Resume { token: None, since: highest });
+        }
+    }
+}
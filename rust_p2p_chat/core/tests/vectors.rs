@@ -0,0 +1,15 @@
+// テストベクターがこの実装で成り立ち、名前で区別できることを確かめる
+
+use p2pchat_core::vectors;
+use std::collections::HashSet;
+
+#[test]
+fn vectors_hold() {
+    assert_eq!(vectors::check(), Vec::new());
+}
+
+#[test]
+fn vector_names_are_unique() {
+    let names: Vec<_> = vectors::envelopes().iter().map(|vector| vector.name).collect();
+    assert_eq!(names.iter().collect::<HashSet<_>>().len(), names.len());
+}
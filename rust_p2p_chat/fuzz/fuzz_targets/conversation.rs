@@ -0,0 +1,23 @@
+#![no_main]
+
+// 接続の状態 (Conversation) に任意のフレームを順に渡す
+// panicせず、1つのフレームへの返信がそのフレームに含まれるメッセージの数を超えないこと (返信が膨らまないこと) を確かめる
+
+use libfuzzer_sys::fuzz_target;
+use p2pchat_core::{Conversation, Envelope};
+
+fuzz_target!(|data: &[u8]| {
+    let mut conversation = Conversation::new();
+    let _ = conversation.hello().encode();
+    // NULで区切った部分をそれぞれ1つのテキストフレームとして扱う
+    for frame in data.split(|&b| b == 0) {
+        let text = String::from_utf8_lossy(frame);
+        let (received, replies) = conversation.receive(&text);
+        assert!(replies.len() <= received.len().max(1), "{} 件の出来事に {} 件の返信", received.len(), replies.len());
+        for reply in &replies {
+            let _ = reply.encode();
+        }
+        let _ = conversation.send("fuzz");
+    }
+    let _ = conversation.hello().encode();
+});
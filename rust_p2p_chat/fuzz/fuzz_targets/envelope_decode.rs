@@ -0,0 +1,16 @@
+#![no_main]
+
+// 受け取ったテキストフレームの解釈 (Envelope::decode)
+// どんな入力でもpanicせず、解釈した結果は送り直しても同じ内容に戻ることを確かめる
+
+use libfuzzer_sys::fuzz_target;
+use p2pchat_core::Envelope;
+
+fuzz_target!(|data: &[u8]| {
+    // WebSocketのテキストフレームはUTF-8なので、それ以外はtungsteniteの段階で拒否される
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let encoded = Envelope::decode(text).encode();
+    assert_eq!(Envelope::decode(&encoded).encode(), encoded);
+});
@@ -0,0 +1,149 @@
+#ifndef P2PCHAT_H
+#define P2PCHAT_H
+
+/* cargo build --features ffi で生成したファイルです。編集しないでください */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ 出来事の種類
+ */
+typedef enum P2pChatEventKind {
+  /*
+   相手と接続した (peer)
+   */
+  P2P_CHAT_EVENT_KIND_CONNECTED,
+  /*
+   相手のメッセージ (peer・text・seq)
+   */
+  P2P_CHAT_EVENT_KIND_MESSAGE_RECEIVED,
+  /*
+   自分のメッセージを送った (text・seq)
+   */
+  P2P_CHAT_EVENT_KIND_MESSAGE_SENT,
+  /*
+   自分のメッセージが相手に届いた (seq)
+   */
+  P2P_CHAT_EVENT_KIND_DELIVERY_ACK,
+  /*
+   ファイルの送受信の進み具合 (transfer_id・text にファイル名・incoming・bytes・total)
+   */
+  P2P_CHAT_EVENT_KIND_TRANSFER_PROGRESS,
+  /*
+   案内 (text)
+   */
+  P2P_CHAT_EVENT_KIND_INFO,
+  /*
+   警告 (text)
+   */
+  P2P_CHAT_EVENT_KIND_WARNING,
+  /*
+   接続が終わった (peer・text に理由)。これが最後の出来事になる
+   */
+  P2P_CHAT_EVENT_KIND_DISCONNECTED,
+} P2pChatEventKind;
+
+/*
+ 1つの接続 (中身は公開しない)
+ */
+typedef struct P2pChatSession P2pChatSession;
+
+/*
+ コールバックに渡す出来事。文字列はコールバックの間だけ有効 (残す場合は複製する)
+ */
+typedef struct P2pChatEvent {
+  enum P2pChatEventKind kind;
+  /*
+   相手の名前 (使わない出来事では空文字列)
+   */
+  const char *peer;
+  /*
+   本文・案内・ファイル名・切断の理由 (使わない出来事では空文字列)
+   */
+  const char *text;
+  uint64_t seq;
+  uint64_t transfer_id;
+  /*
+   受信中の転送ならtrue
+   */
+  bool incoming;
+  uint64_t bytes;
+  /*
+   ファイルの大きさ。分からない場合は -1
+   */
+  int64_t total;
+} P2pChatEvent;
+
+/*
+ 出来事を受け取るコールバック。user_dataには接続時に渡したものをそのまま渡す
+ */
+typedef void (*P2pChatEventCallback)(void *user_data, const struct P2pChatEvent *event);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ 相手 (URIまたは連絡先の名前) に接続する。data_dirがNULLの場合はOSごとの標準の保存先を使う
+ 失敗した場合はNULLを返す (理由は p2pchat_last_error)
+
+ # Safety
+ data_dir・targetはNULL終端の文字列 (data_dirはNULLも可)。callbackはp2pchat_closeまで呼ばれ得る
+ */
+struct P2pChatSession *p2pchat_connect(const char *data_dir,
+                                       const char *target,
+                                       P2pChatEventCallback callback,
+                                       void *user_data);
+
+/*
+ addr (例: 127.0.0.1:8080) で待ち受け、最初に受け付けた相手とのセッションを返す。接続されるまで戻らない
+ 失敗した場合はNULLを返す (理由は p2pchat_last_error)
+
+ # Safety
+ p2pchat_connectと同じ
+ */
+struct P2pChatSession *p2pchat_listen(const char *data_dir,
+                                      const char *addr,
+                                      P2pChatEventCallback callback,
+                                      void *user_data);
+
+/*
+ メッセージを送る。成功すれば0、セッションが終わっていれば-1
+
+ # Safety
+ sessionはp2pchat_connect・p2pchat_listenが返したもので、p2pchat_closeの前であること。textはNULL終端の文字列
+ */
+int p2pchat_send(struct P2pChatSession *session,
+                 const char *text);
+
+/*
+ ファイルを送る。進み具合は TransferProgress の出来事で届く。成功すれば0、セッションが終わっていれば-1
+
+ # Safety
+ p2pchat_sendと同じ (pathはNULL終端の文字列)
+ */
+int p2pchat_send_file(struct P2pChatSession *session,
+                      const char *path);
+
+/*
+ 相手に切断を知らせ、最後の出来事 (Disconnected) をコールバックに渡し終えるまで待ってからセッションを解放する
+
+ # Safety
+ sessionはp2pchat_connect・p2pchat_listenが返したもの (NULLも可)。この後は使わない
+ */
+void p2pchat_close(struct P2pChatSession *session);
+
+/*
+ このスレッドで直前に失敗した関数のエラー。次にこのスレッドで関数を呼ぶまで有効。なければNULL
+ */
+const char *p2pchat_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* P2PCHAT_H */
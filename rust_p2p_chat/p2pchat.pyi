@@ -0,0 +1,40 @@
+# p2pchat モジュールの型 (src/python.rs)
+from os import PathLike
+from types import TracebackType
+from typing import Literal, Optional
+
+from typing_extensions import Self
+
+EventKind = Literal[
+    "connected", "message", "sent", "delivered", "progress", "info", "warning", "disconnected",
+    "earlier", "renamed", "resumed", "verification", "latency",
+]
+
+class Event:
+    kind: EventKind
+    peer: Optional[str]
+    text: Optional[str]
+    seq: Optional[int]
+    transfer_id: Optional[int]
+    incoming: Optional[bool]
+    bytes: Optional[int]
+    total: Optional[int]
+
+class Session:
+    @property
+    def peer(self) -> Optional[str]: ...
+    async def __aenter__(self) -> Self: ...
+    async def __aexit__(
+        self,
+        exc_type: Optional[type[BaseException]],
+        exc: Optional[BaseException],
+        traceback: Optional[TracebackType],
+    ) -> bool: ...
+    def __aiter__(self) -> Self: ...
+    async def __anext__(self) -> Event: ...
+    def send(self, text: str) -> None: ...
+    def send_file(self, path: str | PathLike[str]) -> None: ...
+    def close(self) -> None: ...
+
+def connect(target: str, data_dir: Optional[str | PathLike[str]] = None) -> Session: ...
+def listen(addr: str, data_dir: Optional[str | PathLike[str]] = None) -> Session: ...
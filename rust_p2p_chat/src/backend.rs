@@ -0,0 +1,416 @@
+use futures_util::StreamExt;
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{dcutr, identify, kad, noise, ping, relay, tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::Instrument;
+
+use crate::chat::{handle_connection, open_history, ChatOptions, Role};
+use crate::config::{Config, Libp2pConfig};
+use crate::contacts::{self, AddressBook};
+use crate::i18n::Msg;
+use crate::paths::Paths;
+
+// `--backend libp2p` の接続。証明書とTLSの代わりにlibp2pのPeerID (ed25519) とNoiseで相手を確かめ、
+// TCP・QUICの上で、NATの内側の相手にはリレーを経由してからDCUtRで直接の接続に切り替えてつなぐ
+//
+// チャットは /p2pchat/1.0.0 のストリームの上でWebSocketのフレームとしてやり取りする
+// (Envelopeとセッションの処理は wss と同じものを使う)
+
+// 接続をどちらの方式で行うか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Backend {
+    /// TLSの上のWebSocket (wss://)
+    #[default]
+    Wss,
+    /// libp2p (接続先はmultiaddrかPeerID)
+    Libp2p,
+}
+
+// チャットに使うストリームのプロトコル
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/p2pchat/1.0.0");
+// identifyで相手に伝えるプロトコルのバージョン
+const IDENTIFY_VERSION: &str = "/p2pchat/id/1.0.0";
+// ストリームの上で行うWebSocketハンドシェイクのURL (ホストは使わない)
+const STREAM_URL: &str = "ws://p2pchat/";
+// チャットのストリームがない接続を閉じるまでの時間
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// PeerIDだけで接続するときに、Kademliaで相手のアドレスを探す時間
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(60);
+// 相手への接続が確立するまでの時間 (リレーを含む)
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+// リレー経由でつながった後、DCUtRで直接の接続に切り替わるのを待つ時間
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    relay: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
+    identify: identify::Behaviour,
+    /// bootstrapの相手がいる場合だけ使う
+    kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    ping: ping::Behaviour,
+    stream: libp2p_stream::Behaviour,
+}
+
+// 保存したPeerIDの鍵を読む。なければ作って保存する
+fn keypair(paths: &Paths) -> Result<Keypair, Box<dyn Error>> {
+    let path = paths.libp2p_key_file();
+    if path.exists() {
+        return Ok(Keypair::from_protobuf_encoding(&std::fs::read(&path)?)?);
+    }
+    let keypair = Keypair::generate_ed25519();
+    let mut open = std::fs::OpenOptions::new();
+    open.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+    std::io::Write::write_all(&mut open.open(&path)?, &keypair.to_protobuf_encoding()?)?;
+    tracing::info!(path = %path.display(), "libp2pの鍵を作成しました");
+    Ok(keypair)
+}
+
+// bootstrapの相手がいなければKademliaは使わない (近くの相手を探せないため)
+fn build(keypair: Keypair, bootstrap: bool) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
+    let swarm = SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(tcp::Config::default().nodelay(true), noise::Config::new, yamux::Config::default)?
+        .with_quic()
+        .with_dns()?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay| {
+            let peer_id = key.public().to_peer_id();
+            let kad = bootstrap.then(|| kad::Behaviour::new(peer_id, kad::store::MemoryStore::new(peer_id)));
+            Behaviour {
+                relay,
+                dcutr: dcutr::Behaviour::new(peer_id),
+                identify: identify::Behaviour::new(identify::Config::new(IDENTIFY_VERSION.to_string(), key.public())),
+                kad: kad.into(),
+                ping: ping::Behaviour::default(),
+                stream: libp2p_stream::Behaviour::new(),
+            }
+        })?
+        .with_swarm_config(|config| config.with_idle_connection_timeout(IDLE_TIMEOUT))
+        .build();
+    Ok(swarm)
+}
+
+// multiaddrの最後の /p2p/<PeerID>
+fn peer_of(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer)) => Some(peer),
+        _ => None,
+    }
+}
+
+// 接続してきた相手のIPアドレス (リレー経由の場合はわからない)
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    if addr.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+        return None;
+    }
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+// [libp2p] bootstrap の相手をKademliaに加え、近くの相手を探し始める
+fn join_network(swarm: &mut Swarm<Behaviour>, config: &Libp2pConfig) -> Result<(), Box<dyn Error>> {
+    for bootstrap in &config.bootstrap {
+        let addr: Multiaddr = bootstrap.parse().map_err(|e| format!("[libp2p] bootstrap のmultiaddrが正しくありません ({}): {}", bootstrap, e))?;
+        let peer = peer_of(&addr).ok_or_else(|| format!("[libp2p] bootstrap の最後に /p2p/<PeerID> を付けてください: {}", bootstrap))?;
+        if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+            kad.add_address(&peer, addr);
+        }
+    }
+    if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+        if let Err(e) = kad.bootstrap() {
+            tracing::warn!(error = %e, "Kademliaのbootstrapを始められませんでした");
+        }
+    }
+    Ok(())
+}
+
+fn listen_on(swarm: &mut Swarm<Behaviour>, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => Protocol::Ip4(ip),
+        IpAddr::V6(ip) => Protocol::Ip6(ip),
+    };
+    swarm.listen_on(Multiaddr::empty().with(ip.clone()).with(Protocol::Tcp(addr.port())))?;
+    swarm.listen_on(Multiaddr::empty().with(ip).with(Protocol::Udp(addr.port())).with(Protocol::QuicV1))?;
+    Ok(())
+}
+
+// 接続した相手のIPアドレス (アクセス制限に使う)
+type Remotes = Arc<Mutex<HashMap<PeerId, IpAddr>>>;
+
+// スワームの出来事を処理し続ける (ストリームはlibp2p_stream::Controlで受け付け・開く)
+// announceがtrueなら、待ち受けているアドレスを相手に伝えられる形 (/p2p/<PeerID> 付き) で表示する
+async fn drive(mut swarm: Swarm<Behaviour>, config: watch::Receiver<Config>, remotes: Remotes, announce: bool) {
+    let local = *swarm.local_peer_id();
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                tracing::info!(%address, "libp2pで待ち受けています");
+                if announce {
+                    println!("{}", Msg::Libp2pListening.with(&[&address.with(Protocol::P2p(local))]));
+                }
+            }
+            SwarmEvent::ExternalAddrConfirmed { address } => {
+                tracing::info!(%address, "外部から到達できるアドレスが確認できました");
+                if announce {
+                    println!("{}", Msg::Libp2pListening.with(&[&address.with(Protocol::P2p(local))]));
+                }
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                let remote = endpoint.get_remote_address();
+                tracing::debug!(peer = %peer_id, %remote, "libp2pの接続が確立しました");
+                if let Some(ip) = ip_of(remote).filter(|_| endpoint.is_listener()) {
+                    if !config.borrow().access.permits(ip) {
+                        tracing::warn!(security = true, peer = %peer_id, %ip, "アクセス制限により接続を拒否しました");
+                        swarm.close_connection(connection_id);
+                        continue;
+                    }
+                    remotes.lock().unwrap_or_else(|e| e.into_inner()).insert(peer_id, ip);
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                tracing::debug!(peer = %peer_id, cause = ?cause, "libp2pの接続が閉じました");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                    for address in info.listen_addrs {
+                        kad.add_address(&peer_id, address);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(relay::client::Event::ReservationReqAccepted { relay_peer_id, .. })) => {
+                tracing::info!(relay = %relay_peer_id, "リレーに待受を予約しました");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => match event.result {
+                Ok(_) => tracing::info!(peer = %event.remote_peer_id, "DCUtRで直接の接続に切り替えました"),
+                Err(e) => tracing::info!(peer = %event.remote_peer_id, error = %e, "直接の接続に切り替えられませんでした (リレー経由のまま続けます)"),
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                tracing::debug!(peer = ?peer_id, error = %error, "libp2pの接続に失敗しました");
+            }
+            event => tracing::trace!(?event, "libp2pの出来事"),
+        }
+    }
+}
+
+// アドレス帳でPeerIDが一致する連絡先 (uriの最後が /p2p/<PeerID>) の設定を適用する
+fn apply_contact(peer: &PeerId, paths: &Paths, options: &mut ChatOptions) {
+    match AddressBook::load(paths.contacts_file()) {
+        Ok(book) => {
+            let found = book.iter().find(|(_, contact)| contact.uri.parse::<Multiaddr>().ok().and_then(|addr| peer_of(&addr)) == Some(*peer));
+            if let Some((name, contact)) = found {
+                tracing::info!(contact = %name, "連絡先の設定を適用します");
+                options.apply_contact(contact);
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "アドレス帳の読み込みに失敗しました"),
+    }
+}
+
+// 接続を待ち受け、最初に /p2pchat/1.0.0 のストリームを開いた相手とチャットする
+pub async fn listen(paths: &Paths, mut options: ChatOptions, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let mut history = open_history(paths)?;
+    let keypair = keypair(paths)?;
+    let local = keypair.public().to_peer_id();
+    let config = options.config.borrow().libp2p.clone();
+    let mut swarm = build(keypair, !config.bootstrap.is_empty())?;
+    listen_on(&mut swarm, addr)?;
+    join_network(&mut swarm, &config)?;
+    if let Some(relay) = &config.relay {
+        let relay: Multiaddr = relay.parse().map_err(|e| format!("[libp2p] relay のmultiaddrが正しくありません ({}): {}", relay, e))?;
+        swarm.listen_on(relay.with(Protocol::P2pCircuit))?;
+    }
+    let mut incoming = swarm.behaviour().stream.new_control().accept(PROTOCOL)?;
+    if !options.quiet {
+        println!("{}", Msg::Libp2pPeerId.with(&[&local]));
+    }
+    let remotes = Remotes::default();
+    let driver = tokio::spawn(drive(swarm, options.config.clone(), Arc::clone(&remotes), !options.quiet).in_current_span());
+    if !options.quiet {
+        println!("{}", Msg::Listening);
+    }
+
+    let (peer, stream) = loop {
+        let accepted = tokio::select! {
+            accepted = incoming.next() => accepted,
+            _ = options.shutdown.cancelled() => None,
+        };
+        let Some((peer, stream)) = accepted else {
+            driver.abort();
+            return Ok(());
+        };
+        // 接続の確立時に拒否しきれなかった相手のストリームは使わない
+        let ip = remotes.lock().unwrap_or_else(|e| e.into_inner()).get(&peer).copied();
+        if ip.is_some_and(|ip| !options.config.borrow().access.permits(ip)) {
+            continue;
+        }
+        break (peer, stream);
+    };
+    let span = tracing::info_span!("connection", peer = %peer);
+    let ws_stream = tokio_tungstenite::accept_async(stream.compat()).instrument(span.clone()).await.inspect_err(|e| {
+        tracing::error!(parent: &span, error = %e, "WebSocketハンドシェイクに失敗しました");
+    })?;
+    if !options.quiet {
+        println!("{}", Msg::Libp2pConnected.with(&[&peer]));
+    }
+    apply_contact(&peer, paths, &mut options);
+    handle_connection(ws_stream, &peer.to_string(), &mut history, Role::Listener, paths, options, None).instrument(span).await;
+    driver.abort();
+    Ok(())
+}
+
+// 接続先 (/p2p/<PeerID> で終わるmultiaddr、PeerID、またはそれを保存した連絡先の名前) を、相手とわかっているアドレスにする
+fn resolve(target: &str, paths: &Paths, options: &mut ChatOptions) -> Result<(PeerId, Option<Multiaddr>), Box<dyn Error>> {
+    if let Ok(peer) = target.parse::<PeerId>() {
+        return Ok((peer, None));
+    }
+    let uri = match target.starts_with('/') {
+        true => target.to_string(),
+        false => {
+            let target = contacts::resolve_target(target, &paths.contacts_file())?;
+            if let Some((_, contact)) = &target.contact {
+                options.apply_contact(contact);
+            }
+            target.uri
+        }
+    };
+    if let Ok(peer) = uri.parse::<PeerId>() {
+        return Ok((peer, None));
+    }
+    let addr: Multiaddr = uri.parse().map_err(|e| format!("libp2pの接続先はmultiaddrかPeerIDで指定してください ({}): {}", uri, e))?;
+    let peer = peer_of(&addr).ok_or_else(|| format!("接続先の最後に /p2p/<PeerID> を付けてください: {}", uri))?;
+    Ok((peer, Some(addr)))
+}
+
+// Kademliaで相手のアドレスを探す
+async fn locate(swarm: &mut Swarm<Behaviour>, peer: PeerId) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    let Some(kad) = swarm.behaviour_mut().kad.as_mut() else {
+        return Err("PeerIDだけで接続するには config.toml の [libp2p] bootstrap を指定してください".into());
+    };
+    kad.get_closest_peers(peer);
+    let search = async {
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                    if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
+                        for address in info.listen_addrs {
+                            kad.add_address(&peer_id, address);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { result: kad::QueryResult::GetClosestPeers(result), step, .. })) => {
+                    let found = match &result {
+                        Ok(ok) => ok.peers.iter().find(|info| info.peer_id == peer),
+                        Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers.iter().find(|info| info.peer_id == peer),
+                    };
+                    if let Some(info) = found.filter(|info| !info.addrs.is_empty()) {
+                        return Ok(info.addrs.clone());
+                    }
+                    if step.last {
+                        return Err(format!("Kademliaで相手が見つかりませんでした: {}", peer).into());
+                    }
+                }
+                event => tracing::trace!(?event, "libp2pの出来事"),
+            }
+        }
+    };
+    tokio::time::timeout(LOOKUP_TIMEOUT, search).await.map_err(|_| format!("Kademliaで相手を探す時間 ({}秒) を過ぎました: {}", LOOKUP_TIMEOUT.as_secs(), peer))?
+}
+
+// 相手に接続し、接続が確立するまでスワームを動かす
+// リレー経由でつながった場合は、DCUtRで直接の接続に切り替わるのを少し待つ (リレーは転送量と時間を制限している)
+async fn reach(swarm: &mut Swarm<Behaviour>, peer: PeerId, addrs: Vec<Multiaddr>) -> Result<(), Box<dyn Error>> {
+    swarm.dial(DialOpts::peer_id(peer).addresses(addrs).build())?;
+    let dial = async {
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } if peer_id == peer => {
+                    if !endpoint.is_relayed() {
+                        return Ok(());
+                    }
+                    tracing::info!("リレー経由で接続しました。直接の接続に切り替わるのを待ちます");
+                    break;
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } if peer_id == peer => {
+                    return Err(format!("相手に接続できませんでした: {}", error).into());
+                }
+                event => tracing::trace!(?event, "libp2pの出来事"),
+            }
+        }
+        let upgrade = async {
+            loop {
+                match swarm.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } if peer_id == peer && !endpoint.is_relayed() => return,
+                    event => tracing::trace!(?event, "libp2pの出来事"),
+                }
+            }
+        };
+        if tokio::time::timeout(UPGRADE_TIMEOUT, upgrade).await.is_err() {
+            tracing::info!("直接の接続に切り替わらなかったため、リレー経由で続けます");
+        }
+        Ok(())
+    };
+    tokio::time::timeout(CONNECT_TIMEOUT, dial).await.map_err(|_| format!("相手に接続できる時間 ({}秒) を過ぎました", CONNECT_TIMEOUT.as_secs()))?
+}
+
+// 相手に /p2pchat/1.0.0 のストリームを開いてチャットする
+pub async fn connect(paths: &Paths, mut options: ChatOptions, target: &str) -> Result<(), Box<dyn Error>> {
+    let (peer, addr) = resolve(target, paths, &mut options)?;
+    let mut history = open_history(paths)?;
+    let config = options.config.borrow().libp2p.clone();
+    let mut swarm = build(keypair(paths)?, !config.bootstrap.is_empty())?;
+    // DCUtRで穴を開けられるよう、接続する側も待ち受けておく
+    listen_on(&mut swarm, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    join_network(&mut swarm, &config)?;
+    let span = tracing::info_span!("connection", peer = %peer);
+    let connected = async {
+        let addrs = match addr {
+            Some(addr) => vec![addr],
+            None => {
+                if !options.quiet {
+                    println!("{}", Msg::Libp2pLocating.with(&[&peer]));
+                }
+                locate(&mut swarm, peer).await?
+            }
+        };
+        reach(&mut swarm, peer, addrs).await
+    };
+    let connected: Result<(), Box<dyn Error>> = tokio::select! {
+        connected = connected.instrument(span.clone()) => connected,
+        _ = options.shutdown.cancelled() => return Ok(()),
+    };
+    connected?;
+    let mut control = swarm.behaviour().stream.new_control();
+    let driver = tokio::spawn(drive(swarm, options.config.clone(), Remotes::default(), false).instrument(span.clone()));
+    let stream = match control.open_stream(peer, PROTOCOL).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            driver.abort();
+            return Err(format!("相手にチャットのストリームを開けませんでした: {}", e).into());
+        }
+    };
+    let (ws_stream, _) = tokio_tungstenite::client_async(STREAM_URL, stream.compat()).instrument(span.clone()).await.inspect_err(|e| {
+        tracing::error!(parent: &span, error = %e, "WebSocketハンドシェイクに失敗しました");
+    })?;
+    if !options.quiet {
+        println!("{}", Msg::Libp2pConnected.with(&[&peer]));
+    }
+    handle_connection(ws_stream, &peer.to_string(), &mut history, Role::Client, paths, options, None).instrument(span).await;
+    driver.abort();
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::identity;
+use crate::keystore;
+use crate::paths::Paths;
+use crate::vault::{self, Vault};
+
+// バックアップファイルの先頭に書く識別子
+const ARCHIVE_FORMAT: &str = "rust_p2p_chat-backup";
+
+// バックアップファイルの形式。ファイルの中身はすべてpayloadに暗号化して格納する
+#[derive(Serialize, Deserialize)]
+struct Archive {
+    format: String,
+    version: u32,
+    created_at: DateTime<Local>,
+    profile: String,
+    salt: String,
+    iterations: u32,
+    payload: String,
+}
+
+// 暗号化される中身。アーカイブ内の名前 → ファイルの内容 (base64)
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    files: BTreeMap<String, String>,
+}
+
+// バックアップの対象 (アーカイブ内の名前と、現在のプロファイルでの保存先)
+// 履歴は大きくなりうるため含めない
+fn backup_files(paths: &Paths) -> Vec<(&'static str, PathBuf)> {
+    vec![
+        ("identity_cert.der", paths.identity_cert_file()),
+        ("identity_key.der", paths.identity_key_file()),
+        ("libp2p_key", paths.libp2p_key_file()),
+        ("known_peers", paths.known_peers_file()),
+        ("certs.json", paths.certs_file()),
+        ("contacts.json", paths.contacts_file()),
+        ("config.toml", paths.config_file()),
+    ]
+}
+
+// 本人だけが読めるように書き戻すファイル (秘密鍵と、メールのパスフレーズを含みうるアドレス帳)
+const PRIVATE_FILES: [&str; 3] = ["identity_key.der", "libp2p_key", "contacts.json"];
+
+// 鍵・信頼済みの相手・アドレス帳・設定をまとめて暗号化し、outputに書き出す。含めたファイルの名前を返す
+pub fn create(paths: &Paths, output: &Path, passphrase: &str) -> Result<Vec<&'static str>, Box<dyn std::error::Error>> {
+    let mut files = BTreeMap::new();
+    for (name, path) in backup_files(paths) {
+        if path.exists() {
+            files.insert(name.to_string(), BASE64.encode(std::fs::read(&path)?));
+        }
+    }
+    // 秘密鍵をキーストアに預けている場合も、バックアップには含める
+    if let Some(key) = keystore::identity_key(paths)? {
+        files.insert("identity_key.der".to_string(), BASE64.encode(key));
+    }
+    if files.is_empty() {
+        return Err("バックアップするファイルがありません".into());
+    }
+    let included = backup_files(paths)
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| files.contains_key(*name))
+        .collect();
+
+    let salt = vault::random_salt()?;
+    let key = Vault::derive(passphrase, &salt, vault::PBKDF2_ITERATIONS)?;
+    let archive = Archive {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: 1,
+        created_at: Local::now(),
+        profile: paths.profile.clone(),
+        salt: BASE64.encode(salt),
+        iterations: vault::PBKDF2_ITERATIONS,
+        payload: key.seal(serde_json::to_string(&Payload { files })?.as_bytes())?,
+    };
+    std::fs::write(output, serde_json::to_string_pretty(&archive)?)?;
+    Ok(included)
+}
+
+// バックアップを復号して現在のプロファイルに書き戻す。復元したファイルの名前を返す
+// 既存の鍵と異なる鍵で上書きすると相手側のピン留めと一致しなくなるため、forceがなければ拒否する
+pub fn restore(paths: &Paths, input: &Path, passphrase: &str, force: bool) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let archive: Archive = serde_json::from_str(&std::fs::read_to_string(input)?)
+        .map_err(|e| format!("バックアップファイルの形式が正しくありません: {}", e))?;
+    if archive.format != ARCHIVE_FORMAT || archive.version != 1 {
+        return Err(format!("対応していないバックアップ形式です: {} v{}", archive.format, archive.version).into());
+    }
+    let key = Vault::derive(passphrase, &BASE64.decode(&archive.salt)?, archive.iterations)?;
+    let payload: Payload = serde_json::from_slice(&key.open(&archive.payload)?)?;
+
+    let mut targets = Vec::new();
+    for (name, path) in backup_files(paths) {
+        if let Some(encoded) = payload.files.get(name) {
+            targets.push((name, path, BASE64.decode(encoded)?));
+        }
+    }
+
+    if !force {
+        for (_, path, data) in targets.iter().filter(|(name, _, _)| *name == "identity_key.der") {
+            let current = match path.exists() {
+                true => Some(std::fs::read(path)?),
+                false => keystore::identity_key(paths)?,
+            };
+            if current.is_some_and(|current| current != *data) {
+                return Err("このプロファイルには別の鍵が既にあります。上書きする場合は --force を指定してください".into());
+            }
+        }
+    }
+
+    let mut restored = Vec::new();
+    for (name, path, data) in targets {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if PRIVATE_FILES.contains(&name) {
+            identity::write_private_key(&path, &data)?;
+        } else {
+            std::fs::write(&path, data)?;
+        }
+        restored.push(name.to_string());
+    }
+    Ok(restored)
+}
@@ -0,0 +1,42 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+// 他のチャットの仕組みとの橋渡し (`bridge <種類>`)
+// どれも実行中のデーモン (daemon) の制御用ソケットを使い、デーモンの部屋を相手の仕組みの会話として見せる
+
+#[cfg(unix)]
+pub mod irc;
+#[cfg(unix)]
+pub mod mqtt;
+#[cfg(all(unix, feature = "discovery"))]
+pub mod relay;
+#[cfg(all(unix, feature = "xmpp"))]
+pub mod xmpp;
+
+// --listen の値。ポート番号だけの場合は、同じマシンのクライアントだけが使えるようループバックアドレスで待ち受ける
+pub fn parse_listen(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = value.parse::<u16>() {
+        return Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+    value
+        .parse()
+        .map_err(|_| format!("ポート番号またはアドレス (例: 6667, 127.0.0.1:6667) を指定してください: {}", value))
+}
+
+// bridge relay の転送先のサービス (Incoming Webhookに送るJSONの形が違う)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RelayService {
+    Discord,
+    Slack,
+}
+
+impl RelayService {
+    // WebhookのURLのホストから決める
+    pub fn detect(url: &str) -> Option<Self> {
+        let url = url::Url::parse(url).ok()?;
+        match url.host_str()? {
+            "discord.com" | "discordapp.com" | "canary.discord.com" | "ptb.discord.com" => Some(Self::Discord),
+            "hooks.slack.com" => Some(Self::Slack),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Instrument;
+
+use crate::daemon::{self, Request};
+use crate::i18n::Msg;
+
+// IRCサーバーのふりをして、IRCクライアント (weechat・irssi など) にデーモンの部屋を見せる
+// 部屋はチャンネル (#部屋の名前)、部屋の相手はニックネームになる。IRCクライアントの接続ごとにデーモンの出来事を購読し、
+// メッセージ・JOIN/PART (接続・切断)・トピックを両方向に変換する
+
+// IRCクライアントから見たサーバー名
+const SERVER: &str = "p2pchat";
+
+// PRIVMSGの本文の上限 (1行512バイトから、送信元と宛先の分を除いたもの)。長い行は分けて送る
+const MAX_TEXT: usize = 400;
+
+// sentの出来事を待つ、自分が送ったメッセージの数の上限 (フックなどで加工されて一致しないものが溜まり続けないようにする)
+const MAX_PENDING: usize = 32;
+
+// IRCブリッジの設定
+#[derive(Debug, Clone)]
+pub struct IrcSettings {
+    /// デーモンの制御用ソケット
+    pub socket: PathBuf,
+    /// 指定した場合は、IRCクライアントにPASSでこのパスワードを求める
+    pub password: Option<String>,
+}
+
+// IRCクライアントの接続を受け付け続ける
+pub async fn serve(listener: TcpListener, settings: IrcSettings) -> std::io::Result<()> {
+    let settings = Arc::new(settings);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(error = %e, "IRCクライアントの接続の受け付けに失敗しました");
+                continue;
+            }
+        };
+        let settings = Arc::clone(&settings);
+        let span = tracing::info_span!("irc", client = %addr);
+        tokio::spawn(
+            async move {
+                tracing::info!("IRCクライアントが接続しました");
+                if let Err(e) = Client::run(stream, settings).await {
+                    tracing::debug!(error = %e, "IRCクライアントとの接続が終わりました");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+// IRCクライアントからの1行 (送信元とタグは使わないため読み飛ばす)
+#[derive(Debug)]
+struct Line {
+    command: String,
+    params: Vec<String>,
+}
+
+fn parse(line: &str) -> Option<Line> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1;
+    }
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1;
+    }
+    let rest = rest.trim_start_matches(' ');
+    let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    if command.is_empty() {
+        return None;
+    }
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            break;
+        }
+        if let Some(trailing) = rest.strip_prefix(':') {
+            params.push(trailing.to_string());
+            break;
+        }
+        let (param, next) = rest.split_once(' ').unwrap_or((rest, ""));
+        params.push(param.to_string());
+        rest = next;
+    }
+    Some(Line { command: command.to_ascii_uppercase(), params })
+}
+
+// 部屋の名前をチャンネル名にする (チャンネル名に使えない空白・カンマ・コロンは_にする)
+fn channel_name(room: &str) -> String {
+    let name: String = room.chars().map(|c| if matches!(c, ' ' | ',' | ':' | '\x07') { '_' } else { c }).collect();
+    format!("#{}", name)
+}
+
+// ニックネームに使えない文字を_にする。数字と-では始められないため、その場合は先頭に_を付ける
+fn nick_name(value: &str) -> String {
+    let nick: String = value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_-[]\\`^{}|".contains(c) { c } else { '_' })
+        .collect();
+    match nick.chars().next() {
+        None => "peer".to_string(),
+        Some(first) if first.is_ascii_digit() || first == '-' => format!("_{}", nick),
+        Some(_) => nick,
+    }
+}
+
+// 長い行を、文字の途中で切らずにMAX_TEXTバイト以下に分ける
+fn chunks(text: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > MAX_TEXT {
+        let mut end = MAX_TEXT;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+// IRCクライアントに見せる部屋
+struct Channel {
+    /// チャンネル名 (#から始まる)
+    name: String,
+    /// 相手のニックネーム
+    peer: String,
+    /// 相手のアドレス (WHOISで見せる)
+    address: String,
+    topic: String,
+    /// 相手が接続しているか (切断されるとPART、再接続するとJOINを見せる)
+    present: bool,
+}
+
+// 1つのIRCクライアントとの接続
+struct Client {
+    settings: Arc<IrcSettings>,
+    writer: OwnedWriteHalf,
+    nick: String,
+    /// 部屋の名前ごとのチャンネル
+    channels: BTreeMap<String, Channel>,
+    /// 部屋ごとの、自分が送ってまだsentの出来事が届いていないメッセージ
+    /// (IRCクライアントは自分の発言を自分で表示するため、デーモンからのsentでもう一度見せない)
+    pending: HashMap<String, VecDeque<String>>,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+impl Client {
+    async fn run(stream: TcpStream, settings: Arc<IrcSettings>) -> Result<()> {
+        let (reader, writer) = stream.into_split();
+        // UTF-8でない行を送るクライアントもあるため、行ごとに置き換えて読む
+        let mut lines = BufReader::new(reader).split(b'\n');
+        let mut client = Client { settings, writer, nick: "*".to_string(), channels: BTreeMap::new(), pending: HashMap::new() };
+
+        if !client.register(&mut lines).await? {
+            return Ok(());
+        }
+        let subscribed = daemon::request(&client.settings.socket, &Request::Events).await.map_err(|e| e.to_string());
+        let (_, mut events) = match subscribed {
+            Ok(subscribed) => subscribed,
+            Err(e) => {
+                client.send(&format!("ERROR :{}", e)).await?;
+                return Ok(());
+            }
+        };
+        client.welcome().await?;
+        client.refresh().await?;
+
+        loop {
+            tokio::select! {
+                line = lines.next_segment() => match line? {
+                    Some(line) => {
+                        if !client.command(&String::from_utf8_lossy(&line)).await? {
+                            return Ok(());
+                        }
+                    }
+                    None => return Ok(()),
+                },
+                event = events.next_line() => match event? {
+                    Some(event) => client.event(&event).await?,
+                    None => {
+                        client.send("ERROR :デーモンが終了しました").await?;
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    // デーモンに要求を1つ送る (エラーはIRCクライアントに見せるため文字列にする)
+    async fn request(&self, request: Request) -> std::result::Result<Value, String> {
+        daemon::request(&self.settings.socket, &request).await.map(|(response, _)| response).map_err(|e| e.to_string())
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        let line = line.replace(['\r', '\n'], " ");
+        self.writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        Ok(())
+    }
+
+    // サーバーからの応答 (番号付き)
+    async fn reply(&mut self, code: &str, text: &str) -> Result<()> {
+        let line = format!(":{} {} {} {}", SERVER, code, self.nick, text);
+        self.send(&line).await
+    }
+
+    // nickの発言・操作として見せる
+    async fn from(&mut self, nick: &str, text: &str) -> Result<()> {
+        self.send(&format!(":{0}!{0}@{1} {2}", nick, SERVER, text)).await
+    }
+
+    // NICKとUSERを受け取るまで待つ。途中で切断された・パスワードが違う場合はfalse
+    async fn register<R>(&mut self, lines: &mut tokio::io::Split<R>) -> Result<bool>
+    where
+        R: tokio::io::AsyncBufRead + Unpin,
+    {
+        let (mut nick, mut user, mut pass) = (None, false, None);
+        while let Some(line) = lines.next_segment().await? {
+            let Some(line) = parse(&String::from_utf8_lossy(&line)) else {
+                continue;
+            };
+            let first = line.params.first().cloned();
+            match line.command.as_str() {
+                "CAP" => self.cap(&line.params).await?,
+                "PASS" => pass = first,
+                "NICK" => nick = first.map(|nick| nick_name(&nick)),
+                "USER" => user = true,
+                "PING" => self.send(&format!(":{0} PONG {0} :{1}", SERVER, first.unwrap_or_default())).await?,
+                "QUIT" => return Ok(false),
+                _ => self.reply("451", ":You have not registered").await?,
+            }
+            if let (Some(nick), true) = (&nick, user) {
+                self.nick = nick.clone();
+                if self.settings.password.is_some() && pass != self.settings.password {
+                    self.reply("464", ":Password incorrect").await?;
+                    self.send("ERROR :パスワードが違います").await?;
+                    return Ok(false);
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // 追加の機能 (IRCv3) には対応していないことを伝える
+    async fn cap(&mut self, params: &[String]) -> Result<()> {
+        match params.first().map(|sub| sub.to_ascii_uppercase()).as_deref() {
+            Some("LS") => self.send(&format!(":{} CAP * LS :", SERVER)).await,
+            Some("REQ") => self.send(&format!(":{} CAP * NAK :{}", SERVER, params.get(1).map(String::as_str).unwrap_or_default())).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn welcome(&mut self) -> Result<()> {
+        let version = env!("CARGO_PKG_VERSION");
+        let nick = self.nick.clone();
+        self.reply("001", &format!(":Welcome to the p2pchat IRC bridge, {}", nick)).await?;
+        self.reply("002", &format!(":Your host is {}, running version {}", SERVER, version)).await?;
+        self.reply("004", &format!("{} {} i nt", SERVER, version)).await?;
+        self.reply("005", "CHANTYPES=# NETWORK=p2pchat :are supported by this server").await?;
+        self.reply("422", ":MOTD File is missing").await
+    }
+
+    // デーモンの部屋の一覧を取り、まだチャンネルにしていない部屋に参加する
+    async fn refresh(&mut self) -> Result<()> {
+        let rooms = match self.request(Request::Rooms).await {
+            Ok(response) => response["rooms"].as_array().cloned().unwrap_or_default(),
+            Err(e) => {
+                self.send(&format!(":{} NOTICE {} :{}", SERVER, self.nick, e)).await?;
+                return Ok(());
+            }
+        };
+        for room in rooms {
+            if room["name"].as_str().is_some_and(|name| !self.channels.contains_key(name)) {
+                self.open(&room, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // 部屋 (デーモンのroomsの1件) をチャンネルとして見せる。nameを省略した場合は部屋の名前から決める
+    async fn open(&mut self, room: &Value, name: Option<String>) -> Result<()> {
+        let room_name = room["name"].as_str().unwrap_or_default().to_string();
+        let address = room["peer"].as_str().unwrap_or_default().to_string();
+        // 日本語のニックネームなど、ニックネームに使える文字が残らない場合はアドレスを使う
+        let mut peer = room["nickname"]
+            .as_str()
+            .map(nick_name)
+            .filter(|nick| nick.chars().any(|c| c.is_ascii_alphanumeric()))
+            .unwrap_or_else(|| nick_name(&address));
+        while peer.eq_ignore_ascii_case(&self.nick) {
+            peer.push('_');
+        }
+        let channel = Channel {
+            name: name.unwrap_or_else(|| channel_name(&room_name)),
+            peer,
+            topic: format!("p2pchat: {} ({})", address, room["role"].as_str().unwrap_or("-")),
+            address,
+            present: true,
+        };
+        let (nick, name) = (self.nick.clone(), channel.name.clone());
+        self.channels.insert(room_name, channel);
+        self.from(&nick, &format!("JOIN {}", name)).await?;
+        self.topic_reply(&name).await?;
+        self.names(&name).await
+    }
+
+    fn room_of_channel(&self, name: &str) -> Option<String> {
+        self.channels.iter().find(|(_, channel)| channel.name.eq_ignore_ascii_case(name)).map(|(room, _)| room.clone())
+    }
+
+    fn room_of_nick(&self, nick: &str) -> Option<String> {
+        self.channels.iter().find(|(_, channel)| channel.peer.eq_ignore_ascii_case(nick)).map(|(room, _)| room.clone())
+    }
+
+    fn channel(&self, name: &str) -> Option<&Channel> {
+        self.channels.values().find(|channel| channel.name.eq_ignore_ascii_case(name))
+    }
+
+    async fn topic_reply(&mut self, name: &str) -> Result<()> {
+        match self.channel(name).map(|channel| channel.topic.clone()) {
+            Some(topic) => self.reply("332", &format!("{} :{}", name, topic)).await,
+            None => self.reply("331", &format!("{} :No topic is set", name)).await,
+        }
+    }
+
+    async fn names(&mut self, name: &str) -> Result<()> {
+        if let Some(channel) = self.channel(name) {
+            let mut members = vec![self.nick.clone()];
+            if channel.present {
+                members.push(channel.peer.clone());
+            }
+            let (name, members) = (channel.name.clone(), members.join(" "));
+            self.reply("353", &format!("= {} :{}", name, members)).await?;
+        }
+        self.reply("366", &format!("{} :End of /NAMES list", name)).await
+    }
+
+    // デーモンの出来事 (--format jsonl と同じ形式) をIRCのメッセージにする
+    async fn event(&mut self, line: &str) -> Result<()> {
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return Ok(());
+        };
+        let Some(room) = event["room"].as_str() else {
+            return Ok(());
+        };
+        if !self.channels.contains_key(room) {
+            self.refresh().await?;
+        }
+        let Some(channel) = self.channels.get_mut(room) else {
+            return Ok(());
+        };
+        let (name, peer) = (channel.name.clone(), channel.peer.clone());
+        match event["event"].as_str().unwrap_or_default() {
+            "message" => self.privmsg(&peer, &name, event["body"].as_str().unwrap_or_default()).await?,
+            "sent" => {
+                let body = event["body"].as_str().unwrap_or_default();
+                let pending = self.pending.entry(room.to_string()).or_default();
+                match pending.iter().position(|sent| sent == body) {
+                    Some(index) => {
+                        pending.remove(index);
+                    }
+                    // ctl send や別のIRCクライアントから送ったもの
+                    None => {
+                        let nick = self.nick.clone();
+                        self.privmsg(&nick, &name, body).await?;
+                    }
+                }
+            }
+            "connected" if !channel.present => {
+                channel.present = true;
+                self.from(&peer, &format!("JOIN {}", name)).await?;
+            }
+            "disconnected" if channel.present => {
+                channel.present = false;
+                self.from(&peer, &format!("PART {} :{}", name, Msg::BridgePeerLeft)).await?;
+            }
+            "info" | "warning" | "error" => {
+                let text = event["text"].as_str().or(event["message"].as_str()).unwrap_or_default().to_string();
+                for line in text.lines() {
+                    self.send(&format!(":{} NOTICE {} :{}", SERVER, name, line)).await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // 複数行のメッセージは1行ずつ、長い行は分けて送る
+    async fn privmsg(&mut self, from: &str, target: &str, body: &str) -> Result<()> {
+        for line in body.split('\n') {
+            for chunk in chunks(line.trim_end_matches('\r')) {
+                self.from(from, &format!("PRIVMSG {} :{}", target, chunk)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // IRCクライアントからの1行を処理する。QUITの場合はfalse
+    async fn command(&mut self, line: &str) -> Result<bool> {
+        let Some(Line { command, params }) = parse(line) else {
+            return Ok(true);
+        };
+        let param = |index: usize| params.get(index).map(String::as_str);
+        match command.as_str() {
+            "PING" => self.send(&format!(":{0} PONG {0} :{1}", SERVER, param(0).unwrap_or_default())).await?,
+            "PONG" => {}
+            "CAP" => self.cap(&params).await?,
+            "NICK" => match param(0).map(nick_name) {
+                Some(nick) if self.room_of_nick(&nick).is_some() => self.reply("433", &format!("{} :Nickname is already in use", nick)).await?,
+                Some(nick) => {
+                    let old = std::mem::replace(&mut self.nick, nick.clone());
+                    self.from(&old, &format!("NICK :{}", nick)).await?;
+                }
+                None => self.reply("431", ":No nickname given").await?,
+            },
+            "JOIN" => match param(0) {
+                Some(names) => {
+                    for name in names.split(',') {
+                        self.join(name).await?;
+                    }
+                }
+                None => self.reply("461", "JOIN :Not enough parameters").await?,
+            },
+            "PART" => match param(0) {
+                Some(names) => {
+                    for name in names.split(',') {
+                        self.part(name).await?;
+                    }
+                }
+                None => self.reply("461", "PART :Not enough parameters").await?,
+            },
+            "PRIVMSG" | "NOTICE" => match (param(0), param(1)) {
+                (Some(target), Some(text)) => self.say(target, text, command == "NOTICE").await?,
+                _ if command == "NOTICE" => {}
+                (None, _) => self.reply("411", ":No recipient given (PRIVMSG)").await?,
+                (Some(_), None) => self.reply("412", ":No text to send").await?,
+            },
+            "TOPIC" => match param(0) {
+                Some(name) => self.topic(name, param(1)).await?,
+                None => self.reply("461", "TOPIC :Not enough parameters").await?,
+            },
+            "NAMES" => {
+                let names: Vec<String> = match param(0) {
+                    Some(names) => names.split(',').map(str::to_string).collect(),
+                    None => self.channels.values().map(|channel| channel.name.clone()).collect(),
+                };
+                for name in names {
+                    self.names(&name).await?;
+                }
+            }
+            "LIST" => {
+                self.reply("321", "Channel :Users  Name").await?;
+                let list: Vec<String> = self
+                    .channels
+                    .values()
+                    .map(|channel| format!("{} {} :{}", channel.name, 1 + channel.present as usize, channel.topic))
+                    .collect();
+                for entry in list {
+                    self.reply("322", &entry).await?;
+                }
+                self.reply("323", ":End of /LIST").await?;
+            }
+            "WHO" => {
+                let target = param(0).unwrap_or("*").to_string();
+                if let Some(channel) = self.channel(&target) {
+                    let mut members = vec![self.nick.clone()];
+                    if channel.present {
+                        members.push(channel.peer.clone());
+                    }
+                    let name = channel.name.clone();
+                    for member in members {
+                        self.reply("352", &format!("{} {1} {2} {2} {1} H :0 {1}", name, member, SERVER)).await?;
+                    }
+                }
+                self.reply("315", &format!("{} :End of /WHO list", target)).await?;
+            }
+            "WHOIS" => {
+                // WHOIS [サーバー] ニックネーム
+                let target = param(1).or(param(0)).unwrap_or_default().to_string();
+                match self.room_of_nick(&target).map(|room| &self.channels[&room]) {
+                    Some(channel) => {
+                        let (address, name) = (channel.address.clone(), channel.name.clone());
+                        self.reply("311", &format!("{0} {0} {1} * :{1}", target, address)).await?;
+                        self.reply("319", &format!("{} :{}", target, name)).await?;
+                    }
+                    None if target.eq_ignore_ascii_case(&self.nick) => {}
+                    None => self.reply("401", &format!("{} :No such nick/channel", target)).await?,
+                }
+                self.reply("318", &format!("{} :End of /WHOIS list", target)).await?;
+            }
+            "ISON" => {
+                let online: Vec<String> = params
+                    .iter()
+                    .flat_map(|nicks| nicks.split(' '))
+                    .filter(|nick| self.room_of_nick(nick).is_some_and(|room| self.channels[&room].present))
+                    .map(str::to_string)
+                    .collect();
+                self.reply("303", &format!(":{}", online.join(" "))).await?;
+            }
+            "MODE" => match (param(0), param(1)) {
+                (Some(target), None) if self.channel(target).is_some() => self.reply("324", &format!("{} +nt", target)).await?,
+                (Some(target), Some("b")) => self.reply("368", &format!("{} :End of channel ban list", target)).await?,
+                (Some(target), _) if target.eq_ignore_ascii_case(&self.nick) => self.reply("221", "+i").await?,
+                _ => {}
+            },
+            "QUIT" => {
+                self.send("ERROR :Closing link").await?;
+                return Ok(false);
+            }
+            "USER" | "PASS" => self.reply("462", ":You may not reregister").await?,
+            command => self.reply("421", &format!("{} :Unknown command", command)).await?,
+        }
+        Ok(true)
+    }
+
+    // JOIN: 開いている部屋ならその部屋に、そうでなければチャンネル名 (#を除く) をURIまたは連絡先の名前として接続する
+    async fn join(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name == "0" {
+            return Ok(());
+        }
+        if self.channel(name).is_some() {
+            let nick = self.nick.clone();
+            self.from(&nick, &format!("JOIN {}", name)).await?;
+            self.topic_reply(name).await?;
+            return self.names(name).await;
+        }
+        let uri = name.trim_start_matches(['#', '&']).to_string();
+        let room = match self.request(Request::Connect { uri }).await {
+            Ok(response) => response["room"].as_str().unwrap_or_default().to_string(),
+            Err(e) => return self.reply("403", &format!("{} :{}", name, e)).await,
+        };
+        let rooms = match self.request(Request::Rooms).await {
+            Ok(response) => response["rooms"].as_array().cloned().unwrap_or_default(),
+            Err(e) => return self.reply("403", &format!("{} :{}", name, e)).await,
+        };
+        match rooms.iter().find(|info| info["name"].as_str() == Some(&room)) {
+            Some(info) => self.open(info, Some(name.to_string())).await,
+            // 接続してすぐに切断された
+            None => self.reply("403", &format!("{} :{}", name, Msg::BridgePeerLeft)).await,
+        }
+    }
+
+    // PART: 部屋の接続を閉じる
+    async fn part(&mut self, name: &str) -> Result<()> {
+        let Some(room) = self.room_of_channel(name) else {
+            return self.reply("403", &format!("{} :No such channel", name)).await;
+        };
+        // すでに閉じている部屋はチャンネルを消すだけでよい
+        let _ = self.request(Request::Close { room: room.clone() }).await;
+        let channel = self.channels.remove(&room).map(|channel| channel.name).unwrap_or_default();
+        self.pending.remove(&room);
+        let nick = self.nick.clone();
+        self.from(&nick, &format!("PART {}", channel)).await
+    }
+
+    // PRIVMSG・NOTICE: チャンネルまたは相手のニックネームに送る (NOTICEには失敗を知らせない)
+    async fn say(&mut self, target: &str, text: &str, notice: bool) -> Result<()> {
+        let room = match target.starts_with(['#', '&']) {
+            true => self.room_of_channel(target),
+            false => self.room_of_nick(target),
+        };
+        let Some(room) = room else {
+            return match notice {
+                true => Ok(()),
+                false => self.reply("401", &format!("{} :No such nick/channel", target)).await,
+            };
+        };
+        // /me はCTCPのACTIONとして届く。それ以外のCTCP (VERSIONなど) は相手に送らない
+        let body = match text.strip_prefix('\x01').map(|ctcp| ctcp.trim_end_matches('\x01')) {
+            Some(ctcp) => match ctcp.strip_prefix("ACTION ") {
+                Some(action) => format!("* {} {}", self.nick, action),
+                None => return Ok(()),
+            },
+            None => text.to_string(),
+        };
+        let pending = self.pending.entry(room.clone()).or_default();
+        if pending.len() >= MAX_PENDING {
+            pending.pop_front();
+        }
+        pending.push_back(body.clone());
+        if let Err(e) = self.request(Request::Send { room: room.clone(), body }).await {
+            if let Some(pending) = self.pending.get_mut(&room) {
+                pending.pop_back();
+            }
+            if !notice {
+                self.reply("404", &format!("{} :{}", target, e)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // TOPIC: 設定したトピックはチャンネルに残し、相手にもメッセージで知らせる
+    async fn topic(&mut self, name: &str, topic: Option<&str>) -> Result<()> {
+        let Some(topic) = topic else {
+            return self.topic_reply(name).await;
+        };
+        let Some(room) = self.room_of_channel(name) else {
+            return self.reply("403", &format!("{} :No such channel", name)).await;
+        };
+        let body = Msg::BridgeTopic.with(&[&self.nick, &topic]);
+        if let Err(e) = self.request(Request::Send { room: room.clone(), body }).await {
+            return self.reply("482", &format!("{} :{}", name, e)).await;
+        }
+        if let Some(channel) = self.channels.get_mut(&room) {
+            channel.topic = topic.to_string();
+        }
+        let nick = self.nick.clone();
+        self.from(&nick, &format!("TOPIC {} :{}", name, topic)).await
+    }
+}
@@ -0,0 +1,387 @@
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::daemon::{self, Request};
+
+// MQTTブローカー (Mosquittoなど) にクライアントとして接続し、デーモンの部屋とトピックを橋渡しする (MQTT 3.1.1)
+// 相手から受信したメッセージは部屋ごとのトピックにPUBLISHし、購読したトピックに届いたペイロードは相手へのメッセージとして送る
+// 機器やダッシュボードからの通知を、人のいるチャットに流すために使う
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// トピックの中で部屋の名前に置き換える部分
+const ROOM: &str = "{room}";
+// 受け取るパケットの大きさの上限
+const MAX_PACKET_BYTES: usize = 1024 * 1024;
+// CONNACK・SUBACKを待つ上限
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// ブローカーに伝える、パケットを送らずにいてよい時間 (この半分ごとにPINGREQを送る)
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+// MQTTブリッジの設定
+pub struct MqttSettings {
+    /// デーモンの制御用ソケット
+    pub socket: PathBuf,
+    /// ブローカーのアドレス (例: localhost:1883)
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 受信したメッセージをPUBLISHするトピック。{room} は部屋の名前になる (例: p2pchat/{room}/messages)
+    pub publish: String,
+    /// 購読するトピック。{room} を含む場合はその部分を送り先の部屋とし、含まない場合は room に送る (例: p2pchat/{room}/send)
+    pub subscribe: String,
+    /// subscribe に {room} がない場合の送り先 (部屋の名前・連絡先の名前・URI)
+    pub room: Option<String>,
+    /// 本文の代わりに、出来事のJSON (ctl events と同じ形式) をPUBLISHする
+    pub json: bool,
+}
+
+impl MqttSettings {
+    // PUBLISHするトピック
+    fn publish_topic(&self, room: &str) -> String {
+        self.publish.replace(ROOM, &escape_level(room))
+    }
+
+    // 購読するトピックフィルター ({room} は1階層のワイルドカード)
+    fn filter(&self) -> String {
+        self.subscribe.replace(ROOM, "+")
+    }
+
+    // 購読したトピックに届いたペイロードの送り先
+    fn target(&self, topic: &str) -> Option<String> {
+        let Some(index) = self.subscribe.split('/').position(|level| level == ROOM) else {
+            return self.room.clone();
+        };
+        topic.split('/').nth(index).map(unescape_level)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.publish.contains(['+', '#']) {
+            return Err("PUBLISHするトピックにワイルドカード (+・#) は使えません".into());
+        }
+        if self.subscribe.split('/').any(|level| level.contains(ROOM) && level != ROOM) {
+            return Err("購読するトピックの {room} は1つの階層全体に書いてください (例: p2pchat/{room}/send)".into());
+        }
+        if !self.subscribe.contains(ROOM) && self.room.is_none() {
+            return Err("購読するトピックに {room} がない場合は、送り先の部屋 (--room) を指定してください".into());
+        }
+        // 自分のPUBLISHを購読すると、受信したメッセージを相手に送り返してしまう
+        if topic_matches(&self.filter(), &self.publish_topic("room")) {
+            return Err("PUBLISHするトピックが購読するトピックに含まれています".into());
+        }
+        Ok(())
+    }
+}
+
+// 部屋の名前をトピックの1階層にする (/ とワイルドカードを%で符号化する)
+fn escape_level(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '%' | '/' | '+' | '#' => format!("%{:02X}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn unescape_level(level: &str) -> String {
+    let mut result = String::new();
+    let mut rest = level;
+    while let Some(index) = rest.find('%') {
+        result.push_str(&rest[..index]);
+        match rest.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if b"%/+#".contains(&byte) => {
+                result.push(byte as char);
+                rest = &rest[index + 3..];
+            }
+            _ => {
+                result.push('%');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// トピックフィルター (+ と #) にトピックが一致するか
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(actual)) if level == actual => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+// ブローカーに接続し、接続が切れるまで橋渡しを続ける
+pub async fn run(settings: MqttSettings) -> Result<()> {
+    settings.check()?;
+    let stream = TcpStream::connect(&settings.broker)
+        .await
+        .map_err(|e| format!("MQTTブローカーに接続できません ({}): {}", settings.broker, e))?;
+    let (reader, writer) = stream.into_split();
+    let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(read_packets(reader, incoming_tx));
+    let mut bridge = Bridge { settings, writer, next_id: 1 };
+
+    bridge.write(&connect_packet(&bridge.settings)).await?;
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.recv()).await {
+        Ok(Some(Packet { kind: CONNACK, body, .. })) => match body.get(1).copied().unwrap_or(u8::MAX) {
+            0 => {}
+            code => return Err(format!("MQTTブローカーに接続を拒否されました: {}", connack_reason(code)).into()),
+        },
+        Ok(Some(_)) => return Err("MQTTブローカーからCONNACKが届きませんでした".into()),
+        Ok(None) => return Err(closed(reading).await),
+        Err(_) => return Err("MQTTブローカーからの応答がありません".into()),
+    }
+    let filter = bridge.settings.filter();
+    let id = bridge.packet_id();
+    bridge.write(&subscribe_packet(id, &filter)).await?;
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.recv()).await {
+        Ok(Some(Packet { kind: SUBACK, body, .. })) if body.get(2).is_some_and(|code| *code <= 2) => {}
+        Ok(Some(_)) => return Err(format!("トピックを購読できませんでした: {}", filter).into()),
+        Ok(None) => return Err(closed(reading).await),
+        Err(_) => return Err("MQTTブローカーからの応答がありません".into()),
+    }
+    tracing::info!(broker = %bridge.settings.broker, subscribe = %filter, publish = %bridge.settings.publish, "MQTTブローカーに接続しました");
+
+    let subscribed = daemon::request(&bridge.settings.socket, &Request::Events).await.map_err(|e| e.to_string());
+    let (_, mut events) = subscribed?;
+    let mut ping = tokio::time::interval(KEEP_ALIVE / 2);
+    ping.tick().await;
+    loop {
+        tokio::select! {
+            packet = incoming.recv() => match packet {
+                Some(packet) => bridge.packet(packet).await?,
+                None => return Err(closed(reading).await),
+            },
+            event = events.next_line() => match event? {
+                Some(event) => bridge.event(&event).await?,
+                None => return Err("デーモンが終了しました".into()),
+            },
+            _ = ping.tick() => bridge.write(&[PINGREQ << 4, 0]).await?,
+        }
+    }
+}
+
+// ブローカーとの接続が終わった理由
+async fn closed(reading: tokio::task::JoinHandle<std::result::Result<(), String>>) -> Box<dyn std::error::Error + Send + Sync> {
+    match reading.await {
+        Ok(Err(e)) => format!("MQTTブローカーからの受信に失敗しました: {}", e).into(),
+        _ => "MQTTブローカーとの接続が切れました".into(),
+    }
+}
+
+fn connack_reason(code: u8) -> &'static str {
+    match code {
+        1 => "対応していないプロトコルのバージョンです",
+        2 => "クライアントIDが使えません",
+        3 => "サービスを利用できません",
+        4 => "ユーザー名またはパスワードが違います",
+        5 => "接続が許可されていません",
+        _ => "不明な応答です",
+    }
+}
+
+// パケットの種類 (固定ヘッダーの上位4ビット)
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+
+// 受け取ったパケット
+struct Packet {
+    kind: u8,
+    /// 固定ヘッダーの下位4ビット (PUBLISHのQoSなど)
+    flags: u8,
+    body: Vec<u8>,
+}
+
+// ブローカーからのパケットを読み、1つ読むたびに渡す
+async fn read_packets<R: AsyncRead + Unpin>(mut reader: R, incoming: mpsc::UnboundedSender<Packet>) -> std::result::Result<(), String> {
+    loop {
+        let header = match reader.read_u8().await {
+            Ok(header) => header,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+        // 残りの長さは7ビットずつ、最大4バイト
+        let mut length = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = reader.read_u8().await.map_err(|e| e.to_string())?;
+            length |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if length > MAX_PACKET_BYTES {
+            return Err(format!("パケットが大きすぎます ({} バイト)", length));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.map_err(|e| e.to_string())?;
+        if incoming.send(Packet { kind: header >> 4, flags: header & 0x0f, body }).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// 固定ヘッダーを付けてパケットにする
+fn encode(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        packet.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+// 長さ (2バイト) の後に続く文字列
+fn put_str(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn connect_packet(settings: &MqttSettings) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, b"MQTT");
+    body.push(4);
+    // clean session。ユーザー名・パスワードがあればそのビットを立てる
+    let mut flags = 0x02;
+    if settings.username.is_some() {
+        flags |= 0x80;
+    }
+    if settings.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put_str(&mut body, settings.client_id.as_bytes());
+    for value in [&settings.username, &settings.password].into_iter().flatten() {
+        put_str(&mut body, value.as_bytes());
+    }
+    encode(CONNECT << 4, &body)
+}
+
+fn subscribe_packet(id: u16, filter: &str) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    put_str(&mut body, filter.as_bytes());
+    // 受け取るQoSの上限は1
+    body.push(1);
+    encode((SUBSCRIBE << 4) | 0x02, &body)
+}
+
+// QoS 1でPUBLISHする (ブローカーからのPUBACKは待たない)
+fn publish_packet(id: u16, topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic.as_bytes());
+    body.extend_from_slice(&id.to_be_bytes());
+    body.extend_from_slice(payload);
+    encode((PUBLISH << 4) | 0x02, &body)
+}
+
+struct Bridge {
+    settings: MqttSettings,
+    writer: OwnedWriteHalf,
+    next_id: u16,
+}
+
+impl Bridge {
+    async fn write(&mut self, packet: &[u8]) -> Result<()> {
+        self.writer.write_all(packet).await?;
+        Ok(())
+    }
+
+    // パケットID (0は使えない)
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    async fn request(&self, request: Request) -> std::result::Result<Value, String> {
+        daemon::request(&self.settings.socket, &request).await.map(|(response, _)| response).map_err(|e| e.to_string())
+    }
+
+    // 部屋に送る。部屋が開いていなければ、送り先を連絡先の名前 (またはURI) として接続してから送る
+    async fn send(&self, target: &str, body: String) -> std::result::Result<(), String> {
+        if self.request(Request::Send { room: target.to_string(), body: body.clone() }).await.is_ok() {
+            return Ok(());
+        }
+        let response = self.request(Request::Connect { uri: target.to_string() }).await?;
+        let room = response["room"].as_str().unwrap_or_default().to_string();
+        self.request(Request::Send { room, body }).await.map(|_| ())
+    }
+
+    // ブローカーからのパケットを処理する
+    async fn packet(&mut self, packet: Packet) -> Result<()> {
+        if packet.kind != PUBLISH {
+            // PUBACK・SUBACK・PINGRESPは確かめることがない
+            return Ok(());
+        }
+        let qos = (packet.flags >> 1) & 0x03;
+        let body = &packet.body;
+        let topic_len = body.get(..2).map_or(0, |len| u16::from_be_bytes([len[0], len[1]]) as usize);
+        let Some(topic) = body.get(2..2 + topic_len).and_then(|topic| std::str::from_utf8(topic).ok()) else {
+            return Err("MQTTブローカーから不正なPUBLISHが届きました".into());
+        };
+        let mut payload = &body[2 + topic_len..];
+        if qos > 0 {
+            let Some(id) = payload.get(..2).map(|id| [id[0], id[1]]) else {
+                return Err("MQTTブローカーから不正なPUBLISHが届きました".into());
+            };
+            payload = &payload[2..];
+            self.write(&encode(PUBACK << 4, &id)).await?;
+        }
+        let Some(target) = self.settings.target(topic) else {
+            return Ok(());
+        };
+        let body = match std::str::from_utf8(payload) {
+            Ok(body) if !body.trim().is_empty() => body.to_string(),
+            Ok(_) => return Ok(()),
+            Err(_) => {
+                tracing::warn!(topic, "UTF-8ではないペイロードは送りません");
+                return Ok(());
+            }
+        };
+        if let Err(e) = self.send(&target, body).await {
+            tracing::warn!(topic, room = %target, error = %e, "MQTTのメッセージを相手に送れませんでした");
+        }
+        Ok(())
+    }
+
+    // デーモンの出来事 (--format jsonl と同じ形式) のうち、受信したメッセージをPUBLISHする
+    async fn event(&mut self, line: &str) -> Result<()> {
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return Ok(());
+        };
+        let (Some(room), Some("message")) = (event["room"].as_str(), event["event"].as_str()) else {
+            return Ok(());
+        };
+        let topic = self.settings.publish_topic(room);
+        let payload = match self.settings.json {
+            true => line.as_bytes().to_vec(),
+            false => event["body"].as_str().unwrap_or_default().as_bytes().to_vec(),
+        };
+        let id = self.packet_id();
+        self.write(&publish_packet(id, &topic, &payload)).await
+    }
+}
@@ -0,0 +1,223 @@
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use super::RelayService;
+use crate::daemon::{self, api, Request};
+use crate::http;
+use crate::i18n::Msg;
+
+// デーモンの部屋のメッセージを、DiscordまたはSlackのIncoming Webhookに転送する (チームのワークスペースに会話を映す)
+// 待ち受けるアドレスを指定した場合は、SlackのOutgoing Webhook (または {"text": ...} のJSON) を受け取り、部屋の相手に送る
+//
+//     POST /  token=<トークン>&user_name=bob&text=了解     (SlackのOutgoing Webhook)
+//     POST /  {"username": "bob", "content": "了解"}       (Authorization: Bearer <トークン>)
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Incoming Webhookへの1回の送信を待つ上限
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// 429 (送りすぎ) のときに送り直す回数と、Retry-Afterがない場合の待ち時間
+const RATE_LIMIT_RETRIES: u32 = 3;
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+// Discordの1つのメッセージに書ける文字数 (超える場合は分けて送る)
+const DISCORD_MAX_CHARS: usize = 2000;
+
+// サービスごとの、発言者の名前と本文をIncoming Webhookに送るJSON (長い本文は複数に分ける)
+fn payloads(service: RelayService, name: &str, body: &str) -> Vec<Value> {
+    match service {
+        // メンションは通知しない (相手のメッセージで @everyone が鳴らないように)
+        RelayService::Discord => chunks(body, DISCORD_MAX_CHARS)
+            .into_iter()
+            .map(|content| json!({ "username": name, "content": content, "allowed_mentions": { "parse": [] } }))
+            .collect(),
+        RelayService::Slack => vec![json!({ "username": name, "text": slack_escape(body) })],
+    }
+}
+
+// 文字数でlimitごとに分ける
+fn chunks(body: &str, limit: usize) -> Vec<String> {
+    let chars: Vec<char> = body.chars().collect();
+    chars.chunks(limit).map(|chunk| chunk.iter().collect()).collect()
+}
+
+// Slackのmrkdwnで特別な意味を持つ文字
+fn slack_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn slack_unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+// 転送の設定
+pub struct RelaySettings {
+    /// デーモンの制御用ソケット
+    pub socket: PathBuf,
+    /// 転送する部屋の名前 (ctl rooms の name)。受け取ったメッセージもこの部屋に送る
+    pub room: String,
+    /// Incoming WebhookのURL
+    pub webhook: String,
+    pub service: RelayService,
+    /// Outgoing Webhookを受け取るときに求めるトークン
+    pub token: Option<String>,
+}
+
+// Outgoing Webhookで受け取ったメッセージ
+struct Inbound {
+    user: Option<String>,
+    text: String,
+}
+
+// デーモンが終了するまで転送を続ける。listenerを渡した場合はOutgoing Webhookも受け取る
+pub async fn run(settings: RelaySettings, listener: Option<TcpListener>) -> Result<()> {
+    let (inbound_tx, mut inbound) = mpsc::unbounded_channel();
+    if let Some(listener) = listener {
+        let token: Arc<str> = settings.token.clone().ok_or("Outgoing Webhookを受け取るにはトークン (--token) を指定してください")?.into();
+        tokio::spawn(serve(listener, token, inbound_tx));
+    }
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!("p2pchat/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let subscribed = daemon::request(&settings.socket, &Request::Events).await.map_err(|e| e.to_string());
+    let (_, mut events) = subscribed?;
+    tracing::info!(room = %settings.room, service = ?settings.service, "部屋のメッセージの転送を始めます");
+    let mut relay = Relay { settings, client, pending: Vec::new() };
+    loop {
+        tokio::select! {
+            event = events.next_line() => match event? {
+                Some(event) => relay.event(&event).await,
+                None => return Err("デーモンが終了しました".into()),
+            },
+            Some(message) = inbound.recv() => relay.inbound(message).await,
+        }
+    }
+}
+
+struct Relay {
+    settings: RelaySettings,
+    client: reqwest::Client,
+    /// Outgoing Webhookから部屋に送った本文 (sentの出来事として戻ってきたときに転送し返さない)
+    pending: Vec<String>,
+}
+
+impl Relay {
+    // デーモンの出来事のうち、部屋のメッセージ (相手のもの・自分が送ったもの) を転送する
+    async fn event(&mut self, line: &str) {
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+        if event["room"].as_str() != Some(self.settings.room.as_str()) {
+            return;
+        }
+        let body = event["body"].as_str().unwrap_or_default();
+        let name = match event["event"].as_str().unwrap_or_default() {
+            "message" => event["from"].as_str().unwrap_or(&self.settings.room).to_string(),
+            "sent" => {
+                if let Some(index) = self.pending.iter().position(|sent| sent == body) {
+                    self.pending.remove(index);
+                    return;
+                }
+                Msg::RelayYou.to_string()
+            }
+            _ => return,
+        };
+        for payload in payloads(self.settings.service, &name, body) {
+            if let Err(e) = self.post(&payload).await {
+                tracing::warn!(room = %self.settings.room, error = %e, "メッセージをWebhookに転送できませんでした");
+                return;
+            }
+        }
+    }
+
+    // 送りすぎ (429) の場合は指示された時間だけ待って送り直す
+    async fn post(&self, payload: &Value) -> std::result::Result<(), String> {
+        for _ in 0..RATE_LIMIT_RETRIES {
+            let response = self.client.post(&self.settings.webhook).json(payload).send().await.map_err(|e| e.to_string())?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            if status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Err(status.to_string());
+            }
+            let delay = response
+                .headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<f64>().ok())
+                .map_or(RATE_LIMIT_DELAY, |seconds| Duration::from_secs_f64(seconds.clamp(0.0, 60.0)));
+            tokio::time::sleep(delay).await;
+        }
+        Err("送りすぎのため断られました (429)".to_string())
+    }
+
+    // 受け取ったメッセージを部屋の相手に送る。発言者が分かる場合は名前を前に付ける
+    async fn inbound(&mut self, message: Inbound) {
+        let body = match message.user {
+            Some(user) => format!("{}: {}", user, message.text),
+            None => message.text,
+        };
+        self.pending.push(body.clone());
+        let request = Request::Send { room: self.settings.room.clone(), body: body.clone() };
+        if let Err(e) = daemon::request(&self.settings.socket, &request).await {
+            tracing::warn!(room = %self.settings.room, error = %e, "Webhookで受け取ったメッセージを相手に送れませんでした");
+            self.pending.retain(|sent| sent != &body);
+        }
+    }
+}
+
+// Outgoing Webhookを受け付ける (1つの接続で1つの要求)
+async fn serve(listener: TcpListener, token: Arc<str>, inbound: mpsc::UnboundedSender<Inbound>) {
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(error = %e, "Outgoing Webhookの接続の受け付けに失敗しました");
+                continue;
+            }
+        };
+        tracing::debug!(%client, "Outgoing Webhookの接続を受け付けました");
+        tokio::spawn(respond(stream, Arc::clone(&token), inbound.clone()));
+    }
+}
+
+async fn respond(mut stream: TcpStream, token: Arc<str>, inbound: mpsc::UnboundedSender<Inbound>) {
+    let reply = match tokio::time::timeout(http::REQUEST_TIMEOUT, http::read_request(&mut stream)).await {
+        Ok(Ok(request)) => accept(&token, request, &inbound),
+        Ok(Err(error)) => error,
+        Err(_) => http::failure(408, "要求が時間内に届きませんでした"),
+    };
+    http::write_reply(&mut stream, reply).await;
+}
+
+// 要求の本文はSlackのフォーム (token・user_name・text) か、{"text" または "content", "username"} のJSON
+fn accept(token: &str, request: http::HttpRequest, inbound: &mpsc::UnboundedSender<Inbound>) -> http::Reply {
+    if request.method != "POST" {
+        return http::failure(405, "POSTで送ってください");
+    }
+    let fields: Value = match serde_json::from_slice(&request.body) {
+        Ok(value @ Value::Object(_)) => value,
+        _ => Value::Object(url::form_urlencoded::parse(&request.body).map(|(key, value)| (key.into_owned(), Value::String(value.into_owned()))).collect()),
+    };
+    let field = |key: &str| fields[key].as_str().filter(|value| !value.is_empty());
+    let authorized = api::authorized(token, request.authorization.as_deref()) || field("token").is_some_and(|given| api::same_token(token, given));
+    if !authorized {
+        tracing::warn!(security = true, "トークンのない、または一致しないOutgoing Webhookを断りました");
+        return http::failure(401, "トークンが一致しません");
+    }
+    // Incoming Webhookで転送したメッセージ (ボットの発言) を送り返さない
+    if field("bot_id").is_some() || field("subtype") == Some("bot_message") {
+        return http::success(200, json!({}));
+    }
+    let Some(text) = field("text").or(field("content")).map(slack_unescape) else {
+        return http::failure(400, "本文 (text または content) がありません");
+    };
+    let user = field("user_name").or(field("username")).map(str::to_string);
+    let _ = inbound.send(Inbound { user, text });
+    http::success(200, json!({}))
+}
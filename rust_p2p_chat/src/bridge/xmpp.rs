@@ -0,0 +1,464 @@
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufRead, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::contacts::AddressBook;
+use crate::daemon::{self, Request};
+
+// XMPPサーバーにコンポーネント (XEP-0114) として接続し、デーモンの部屋の相手をJID (<連絡先の名前>@<ドメイン>) として見せる
+// 持ち主のJIDとの間で、メッセージ (type='chat') と在席情報 (相手の接続・切断) を両方向に変換する
+// 持ち主以外のJIDからのスタンザは受け付けない
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+
+// XMPPブリッジの設定
+pub struct XmppSettings {
+    /// デーモンの制御用ソケット
+    pub socket: PathBuf,
+    /// XMPPサーバーのコンポーネント用のアドレス (例: localhost:5347)
+    pub server: String,
+    /// コンポーネントのドメイン (例: p2pchat.example.org)
+    pub domain: String,
+    /// XMPPサーバーに設定したコンポーネントのパスワード
+    pub secret: String,
+    /// メッセージをやり取りするXMPPの利用者 (ベアJID)
+    pub owner: String,
+    /// JIDのローカル部分に使う連絡先の名前を、部屋の相手のアドレスから探す
+    pub contacts: AddressBook,
+}
+
+// XMPPサーバーに接続し、接続が切れるまで変換を続ける
+pub async fn run(settings: XmppSettings) -> Result<()> {
+    let stream = TcpStream::connect(&settings.server)
+        .await
+        .map_err(|e| format!("XMPPサーバーに接続できません ({}): {}", settings.server, e))?;
+    let (reader, writer) = stream.into_split();
+    let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(read_stream(BufReader::new(reader), incoming_tx));
+    let mut bridge = Bridge { settings, writer, rooms: BTreeMap::new(), online: HashSet::new() };
+
+    // ハンドシェイク: ストリームのidとパスワードのSHA-1を送る
+    let open = format!(
+        "<?xml version='1.0'?><stream:stream xmlns='jabber:component:accept' xmlns:stream='http://etherx.jabber.org/streams' to='{}'>",
+        escape(&bridge.settings.domain)
+    );
+    bridge.write(&open).await?;
+    let id = match incoming.recv().await {
+        Some(Incoming::Opened { id }) => id,
+        _ => return Err(closed(reading).await),
+    };
+    bridge.write(&format!("<handshake>{}</handshake>", handshake(&id, &bridge.settings.secret))).await?;
+    match incoming.recv().await {
+        Some(Incoming::Stanza(element)) if element.name == "handshake" => {}
+        Some(Incoming::Stanza(element)) => return Err(format!("XMPPサーバーに接続を拒否されました: {}", element.describe()).into()),
+        _ => return Err(closed(reading).await),
+    }
+    tracing::info!(server = %bridge.settings.server, domain = %bridge.settings.domain, "XMPPサーバーにコンポーネントとして接続しました");
+
+    let subscribed = daemon::request(&bridge.settings.socket, &Request::Events).await.map_err(|e| e.to_string());
+    let (_, mut events) = subscribed?;
+    bridge.refresh().await?;
+    bridge.announce_all().await?;
+
+    loop {
+        tokio::select! {
+            stanza = incoming.recv() => match stanza {
+                Some(Incoming::Stanza(element)) => bridge.stanza(element).await?,
+                Some(Incoming::Opened { .. }) => {}
+                None => return Err(closed(reading).await),
+            },
+            event = events.next_line() => match event? {
+                Some(event) => bridge.event(&event).await?,
+                None => return Err("デーモンが終了しました".into()),
+            },
+        }
+    }
+}
+
+// XMPPサーバーとの接続が終わった理由
+async fn closed(reading: tokio::task::JoinHandle<std::result::Result<(), String>>) -> Box<dyn std::error::Error + Send + Sync> {
+    match reading.await {
+        Ok(Err(e)) => format!("XMPPサーバーからの受信に失敗しました: {}", e).into(),
+        _ => "XMPPサーバーとの接続が切れました".into(),
+    }
+}
+
+fn handshake(id: &str, secret: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", id, secret).as_bytes());
+    digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// JIDのローカル部分に使えない文字をエスケープする (XEP-0106)
+fn escape_node(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ' ' | '"' | '&' | '\'' | '/' | ':' | '<' | '>' | '@' | '\\' => format!("\\{:02x}", c as u32),
+            c => c.to_lowercase().to_string(),
+        })
+        .collect()
+}
+
+fn unescape_node(value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(index) = rest.find('\\') {
+        result.push_str(&rest[..index]);
+        let escaped = rest.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if b" \"&'/:<>@\\".contains(&byte) => {
+                result.push(byte as char);
+                rest = &rest[index + 3..];
+            }
+            _ => {
+                result.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// 受け取ったスタンザ (ストリームの直下の要素)。名前は接頭辞付きのまま (stream:error など) 扱う
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    // エラーのスタンザの中身 (ログとエラーメッセージ用)
+    fn describe(&self) -> String {
+        let conditions: Vec<&str> = self.children.iter().map(|child| child.name.as_str()).collect();
+        format!("{} {}", self.name, conditions.join(" "))
+    }
+}
+
+enum Incoming {
+    /// XMPPサーバーがストリームを開いた
+    Opened { id: String },
+    Stanza(Element),
+}
+
+// XMPPサーバーからのストリームを読み、ストリームの直下の要素ができるたびに渡す
+async fn read_stream<R: AsyncBufRead + Unpin>(reader: R, incoming: mpsc::UnboundedSender<Incoming>) -> std::result::Result<(), String> {
+    let mut reader = Reader::from_reader(reader);
+    let mut buf = Vec::new();
+    let mut open: Vec<Element> = Vec::new();
+    let mut opened = false;
+    loop {
+        buf.clear();
+        let finished = match reader.read_event_into_async(&mut buf).await.map_err(|e| e.to_string())? {
+            Event::Start(start) if !opened => {
+                opened = true;
+                let stream = element(&start)?;
+                let id = stream.attr("id").unwrap_or_default().to_string();
+                let _ = incoming.send(Incoming::Opened { id });
+                None
+            }
+            Event::Start(start) => {
+                open.push(element(&start)?);
+                None
+            }
+            Event::Empty(start) => Some(element(&start)?),
+            // 閉じる要素がない場合は </stream:stream>
+            Event::End(_) => match open.pop() {
+                Some(element) => Some(element),
+                None => return Ok(()),
+            },
+            Event::Text(text) => {
+                if let Some(element) = open.last_mut() {
+                    element.text.push_str(&text.unescape().map_err(|e| e.to_string())?);
+                }
+                None
+            }
+            Event::CData(data) => {
+                if let Some(element) = open.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&data));
+                }
+                None
+            }
+            Event::Eof => return Ok(()),
+            _ => None,
+        };
+        if let Some(element) = finished {
+            match open.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => {
+                    if incoming.send(Incoming::Stanza(element)).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn element(start: &BytesStart) -> std::result::Result<Element, String> {
+    let mut attrs = Vec::new();
+    for attr in start.attributes() {
+        let attr = attr.map_err(|e| e.to_string())?;
+        let value = attr.unescape_value().map_err(|e| e.to_string())?;
+        attrs.push((String::from_utf8_lossy(attr.key.as_ref()).into_owned(), value.into_owned()));
+    }
+    Ok(Element { name: String::from_utf8_lossy(start.name().as_ref()).into_owned(), attrs, ..Default::default() })
+}
+
+struct Bridge {
+    settings: XmppSettings,
+    writer: OwnedWriteHalf,
+    /// 部屋の名前ごとのJIDのローカル部分
+    rooms: BTreeMap<String, String>,
+    /// 相手が接続している部屋
+    online: HashSet<String>,
+}
+
+impl Bridge {
+    async fn write(&mut self, xml: &str) -> Result<()> {
+        self.writer.write_all(xml.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn request(&self, request: Request) -> std::result::Result<Value, String> {
+        daemon::request(&self.settings.socket, &request).await.map(|(response, _)| response).map_err(|e| e.to_string())
+    }
+
+    fn jid(&self, local: &str) -> String {
+        format!("{}@{}", local, self.settings.domain)
+    }
+
+    // 部屋 (デーモンのroomsの1件) の相手のJIDのローカル部分。相手のアドレスが連絡先に一致すればその名前、なければ部屋の名前
+    fn local_of(&self, room: &Value) -> String {
+        let name = room["name"].as_str().unwrap_or_default();
+        let peer = room["peer"].as_str().unwrap_or_default();
+        let host = match peer.parse::<std::net::SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => peer.rsplit_once(':').map_or(peer, |(host, _)| host).to_string(),
+        };
+        match self.settings.contacts.find_by_host(&host) {
+            Some((contact, _)) => escape_node(contact),
+            None => escape_node(name),
+        }
+    }
+
+    // デーモンの部屋の一覧を取り、まだJIDを決めていない部屋を加える
+    async fn refresh(&mut self) -> Result<()> {
+        let response = self.request(Request::Rooms).await?;
+        for room in response["rooms"].as_array().into_iter().flatten() {
+            let Some(name) = room["name"].as_str() else {
+                continue;
+            };
+            if !self.rooms.contains_key(name) {
+                let local = self.local_of(room);
+                self.rooms.insert(name.to_string(), local);
+                self.online.insert(name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // ローカル部分に対応する部屋。開いていなければ、ローカル部分を連絡先の名前 (またはURI) として接続する
+    async fn room_for(&mut self, local: &str) -> std::result::Result<String, String> {
+        if let Some((room, _)) = self.rooms.iter().find(|(_, known)| known.eq_ignore_ascii_case(local)) {
+            return Ok(room.clone());
+        }
+        // JIDのローカル部分は小文字になるため、連絡先の名前は大文字・小文字を区別せずに探す
+        let target = unescape_node(local);
+        let target = self
+            .settings
+            .contacts
+            .iter()
+            .map(|(name, _)| name)
+            .find(|name| name.eq_ignore_ascii_case(&target))
+            .cloned()
+            .unwrap_or(target);
+        let response = self.request(Request::Connect { uri: target }).await?;
+        let room = response["room"].as_str().unwrap_or_default().to_string();
+        self.rooms.insert(room.clone(), local.to_string());
+        self.online.insert(room.clone());
+        Ok(room)
+    }
+
+    async fn presence(&mut self, room: &str) -> Result<()> {
+        let Some(local) = self.rooms.get(room) else {
+            return Ok(());
+        };
+        let kind = match self.online.contains(room) {
+            true => "",
+            false => " type='unavailable'",
+        };
+        let xml = format!("<presence from='{}' to='{}'{}/>", escape(self.jid(local)), escape(&self.settings.owner), kind);
+        self.write(&xml).await
+    }
+
+    async fn announce_all(&mut self) -> Result<()> {
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        for room in rooms {
+            self.presence(&room).await?;
+        }
+        Ok(())
+    }
+
+    // 受け取ったスタンザにエラーで応える (fromとtoを入れ替える)
+    async fn error(&mut self, stanza: &Element, kind: &str, condition: &str, text: &str) -> Result<()> {
+        let xml = format!(
+            "<{0} type='error' from='{1}' to='{2}'{3}><error type='{4}'><{5} xmlns='{6}'/><text xmlns='{6}'>{7}</text></error></{0}>",
+            stanza.name,
+            escape(stanza.attr("to").unwrap_or_default()),
+            escape(stanza.attr("from").unwrap_or_default()),
+            stanza.attr("id").map(|id| format!(" id='{}'", escape(id))).unwrap_or_default(),
+            kind,
+            condition,
+            STANZAS,
+            escape(text),
+        );
+        self.write(&xml).await
+    }
+
+    // XMPPサーバーからのスタンザを処理する
+    async fn stanza(&mut self, stanza: Element) -> Result<()> {
+        let from = stanza.attr("from").unwrap_or_default();
+        let bare = from.split('/').next().unwrap_or_default();
+        let local = stanza.attr("to").and_then(|to| to.split_once('@')).map(|(local, _)| local.to_string());
+        let kind = stanza.attr("type").unwrap_or_default().to_string();
+        if !bare.eq_ignore_ascii_case(&self.settings.owner) {
+            tracing::debug!(from, "持ち主以外のJIDからのスタンザを無視しました");
+            return match (stanza.name.as_str(), kind.as_str()) {
+                ("message", "error") | ("presence", _) | ("iq", "result" | "error") => Ok(()),
+                _ => self.error(&stanza, "auth", "forbidden", "").await,
+            };
+        }
+        match stanza.name.as_str() {
+            "message" if kind != "error" => {
+                // 入力中の通知 (XEP-0085) など、本文のないメッセージは送らない
+                let Some(body) = stanza.child("body").map(|body| body.text.clone()).filter(|body| !body.is_empty()) else {
+                    return Ok(());
+                };
+                let Some(local) = local else {
+                    return self.error(&stanza, "cancel", "service-unavailable", "").await;
+                };
+                if let Err(e) = self.send(&local, body).await {
+                    self.error(&stanza, "cancel", "recipient-unavailable", &e).await?;
+                }
+            }
+            "presence" => match (kind.as_str(), local) {
+                ("subscribe", Some(local)) => {
+                    let (from, to) = (self.jid(&local), self.settings.owner.clone());
+                    self.write(&format!("<presence type='subscribed' from='{}' to='{}'/>", escape(&from), escape(&to))).await?;
+                    if let Some(room) = self.rooms.iter().find(|(_, known)| **known == local).map(|(room, _)| room.clone()) {
+                        self.presence(&room).await?;
+                    }
+                }
+                ("unsubscribe", Some(local)) => {
+                    let (from, to) = (self.jid(&local), self.settings.owner.clone());
+                    self.write(&format!("<presence type='unsubscribed' from='{}' to='{}'/>", escape(&from), escape(&to))).await?;
+                }
+                ("probe", Some(local)) => match self.rooms.iter().find(|(_, known)| **known == local).map(|(room, _)| room.clone()) {
+                    Some(room) => self.presence(&room).await?,
+                    None => {
+                        let (from, to) = (self.jid(&local), self.settings.owner.clone());
+                        self.write(&format!("<presence type='unavailable' from='{}' to='{}'/>", escape(&from), escape(&to))).await?;
+                    }
+                },
+                // 持ち主がオンラインになった
+                ("", _) => self.announce_all().await?,
+                _ => {}
+            },
+            "iq" if kind == "get" || kind == "set" => {
+                let namespace = stanza.children.first().and_then(|query| query.attr("xmlns")).unwrap_or_default();
+                let reply = match namespace {
+                    "http://jabber.org/protocol/disco#info" => Some(
+                        "<query xmlns='http://jabber.org/protocol/disco#info'><identity category='gateway' type='p2pchat' name='p2pchat'/>\
+                         <feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:ping'/></query>",
+                    ),
+                    "urn:xmpp:ping" => Some(""),
+                    _ => None,
+                };
+                match reply {
+                    Some(reply) => {
+                        let xml = format!(
+                            "<iq type='result' from='{}' to='{}' id='{}'>{}</iq>",
+                            escape(stanza.attr("to").unwrap_or_default()),
+                            escape(from),
+                            escape(stanza.attr("id").unwrap_or_default()),
+                            reply
+                        );
+                        self.write(&xml).await?;
+                    }
+                    None => self.error(&stanza, "cancel", "service-unavailable", "").await?,
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // 相手に送る。部屋がすでに閉じていた場合は接続し直して1回だけ送り直す
+    async fn send(&mut self, local: &str, body: String) -> std::result::Result<(), String> {
+        let room = self.room_for(local).await?;
+        if self.request(Request::Send { room: room.clone(), body: body.clone() }).await.is_ok() {
+            return Ok(());
+        }
+        self.rooms.remove(&room);
+        self.online.remove(&room);
+        let room = self.room_for(local).await?;
+        self.request(Request::Send { room, body }).await.map(|_| ())
+    }
+
+    // デーモンの出来事 (--format jsonl と同じ形式) をスタンザにする
+    async fn event(&mut self, line: &str) -> Result<()> {
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            return Ok(());
+        };
+        let Some(room) = event["room"].as_str() else {
+            return Ok(());
+        };
+        if !self.rooms.contains_key(room) {
+            self.refresh().await?;
+        }
+        let Some(local) = self.rooms.get(room).cloned() else {
+            return Ok(());
+        };
+        match event["event"].as_str().unwrap_or_default() {
+            "message" => {
+                let xml = format!(
+                    "<message from='{}' to='{}' type='chat'><body>{}</body></message>",
+                    escape(self.jid(&local)),
+                    escape(&self.settings.owner),
+                    escape(event["body"].as_str().unwrap_or_default())
+                );
+                self.write(&xml).await?;
+            }
+            "connected" => {
+                self.online.insert(room.to_string());
+                self.presence(room).await?;
+            }
+            "disconnected" => {
+                self.online.remove(room);
+                self.presence(room).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use crate::chat::ChatOptions;
+use crate::contacts::{self, Contact};
+use crate::health::Health;
+use crate::identity::Identity;
+use crate::keystore::{self, Keystore};
+use crate::paths::Paths;
+use crate::proxy::Proxy;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+// 待ち受け (ListenerBuilder) と接続 (ClientBuilder) の設定
+// build() は何も読み書きしないので、設定の組み立てと検証だけを確かめられる
+
+// 受け入れるTLSのバージョン
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsMode {
+    /// TLS 1.2と1.3 (既定)
+    #[default]
+    Compatible,
+    /// TLS 1.3のみ
+    Tls13Only,
+}
+
+static TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+impl TlsMode {
+    pub(crate) fn versions(self) -> &'static [&'static rustls::SupportedProtocolVersion] {
+        match self {
+            TlsMode::Compatible => rustls::ALL_VERSIONS,
+            TlsMode::Tls13Only => TLS13_ONLY,
+        }
+    }
+}
+
+// 自分の証明書と鍵の読み込み元
+#[derive(Debug, Clone, Default)]
+pub enum IdentitySource {
+    /// プロファイルの保存先 (初回のみ生成する)。`keystore store` で秘密鍵を預けていればキーストアから読む
+    #[default]
+    Profile,
+    /// 指定したファイル (なければ生成する)
+    Files { cert: PathBuf, key: PathBuf },
+    /// プロファイルの証明書と、キーストアに預けた秘密鍵 (なければ生成して預ける)
+    Keystore(Arc<dyn Keystore>),
+}
+
+impl IdentitySource {
+    pub fn load(&self, paths: &Paths) -> Result<Identity, Box<dyn std::error::Error>> {
+        match self {
+            IdentitySource::Profile => match keystore::identity_key(paths)? {
+                Some(key_der) => Ok(Identity { cert_der: std::fs::read(paths.identity_cert_file())?, key_der }),
+                None => Identity::load_or_generate(paths.identity_cert_file(), paths.identity_key_file()),
+            },
+            IdentitySource::Files { cert, key } => Identity::load_or_generate(cert.clone(), key.clone()),
+            IdentitySource::Keystore(store) => {
+                Identity::load_or_generate_in(paths.identity_cert_file(), &**store, &keystore::account(paths, keystore::IDENTITY_KEY))
+            }
+        }
+    }
+}
+
+// キーストアは同じものを指しているときに等しいとする
+impl PartialEq for IdentitySource {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (IdentitySource::Profile, IdentitySource::Profile) => true,
+            (IdentitySource::Files { cert, key }, IdentitySource::Files { cert: other_cert, key: other_key }) => cert == other_cert && key == other_key,
+            (IdentitySource::Keystore(store), IdentitySource::Keystore(other)) => Arc::ptr_eq(store, other),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for IdentitySource {}
+
+// TCP接続に失敗したときの再試行 (TLS・WebSocketのハンドシェイクや証明書の照合の失敗は再試行しない)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// 最初の1回を含む試行回数
+    pub attempts: u32,
+    /// 1回目の再試行までの待ち時間。以降は2倍ずつ延ばす
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self { attempts: 1, initial_delay: Duration::from_secs(1), max_delay: Duration::from_secs(30) }
+    }
+}
+
+impl ReconnectPolicy {
+    // retry回目 (1から数える) の再試行の前に待つ時間
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+// 1つの接続で受け取る量の上限 (Noneは制限なし)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// WebSocketの1メッセージの大きさ
+    pub max_message_bytes: Option<usize>,
+    /// 受け取るファイルの大きさ (連絡先の max_file_mb があればそちらを使う)
+    pub max_file_bytes: Option<u64>,
+}
+
+impl Limits {
+    pub(crate) fn websocket_config(&self) -> Option<WebSocketConfig> {
+        self.max_message_bytes.map(|bytes| WebSocketConfig {
+            max_message_size: Some(bytes),
+            max_frame_size: Some(bytes),
+            ..Default::default()
+        })
+    }
+}
+
+// 相手に提供する機能
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// 相手からのファイルを受け取る (連絡先の accept_files があればそちらを使う)
+    pub receive_files: bool,
+    /// 送るメッセージに署名する
+    pub sign_messages: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self { receive_files: true, sign_messages: true }
+    }
+}
+
+// 設定の誤り
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    MissingUri,
+    InvalidUri(String),
+    NoAttempts,
+    DelayOrder,
+    ZeroLimit,
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::MissingUri => f.write_str("接続先が指定されていません"),
+            BuildError::InvalidUri(reason) => write!(f, "接続先のURIが正しくありません: {}", reason),
+            BuildError::NoAttempts => f.write_str("再試行の回数は1以上にしてください"),
+            BuildError::DelayOrder => f.write_str("再試行の待ち時間の初期値が上限を超えています"),
+            BuildError::ZeroLimit => f.write_str("上限に0は指定できません"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+fn check_limits(limits: &Limits) -> Result<(), BuildError> {
+    match (limits.max_message_bytes, limits.max_file_bytes) {
+        (Some(0), _) | (_, Some(0)) => Err(BuildError::ZeroLimit),
+        _ => Ok(()),
+    }
+}
+
+fn apply_capabilities(options: &mut ChatOptions, identity: IdentitySource, capabilities: Capabilities, limits: &Limits) {
+    options.identity = identity;
+    options.sign_messages = capabilities.sign_messages;
+    options.downloads.accept = capabilities.receive_files;
+    options.downloads.max_bytes = limits.max_file_bytes;
+}
+
+// 待ち受けの設定 (run_server に渡す)
+pub struct ListenerSettings {
+    pub paths: Paths,
+    pub options: ChatOptions,
+    pub addr: SocketAddr,
+    pub tls: TlsMode,
+    pub limits: Limits,
+    /// 接続用のURLをQRコードで表示する (標準出力が端末の場合のみ)
+    pub show_qr: bool,
+    /// 登録して接続コードを表示するランデブーサーバー
+    pub rendezvous: Option<String>,
+    /// 同じポートでブラウザ用のページも配り、平文 (ws://) の接続も受け付ける
+    pub web: bool,
+    /// 同じポート番号のUDPでWebTransport (HTTP/3) のセッションも受け付ける (webtransport フィーチャー)
+    pub webtransport: bool,
+    /// TXTレコードで待受を公開するドメイン (公開するレコードを表示し、公開済みのものと照合する)
+    pub dns_name: Option<String>,
+    /// 接続を待っている間、この表示名でmDNSを使ってLANに知らせる (mdns フィーチャー)
+    pub announce: Option<String>,
+    /// 待受の状態を知らせる先 (--health の /healthz・/readyz)
+    pub health: Option<Health>,
+}
+
+pub struct ListenerBuilder {
+    paths: Paths,
+    options: ChatOptions,
+    addr: SocketAddr,
+    identity: IdentitySource,
+    tls: TlsMode,
+    limits: Limits,
+    capabilities: Capabilities,
+    show_qr: bool,
+    rendezvous: Option<String>,
+    web: bool,
+    webtransport: bool,
+    dns_name: Option<String>,
+    announce: Option<String>,
+    health: Option<Health>,
+}
+
+impl ListenerBuilder {
+    pub fn new(paths: Paths, options: ChatOptions) -> Self {
+        Self {
+            paths,
+            options,
+            addr: crate::DEFAULT_LISTEN_ADDR,
+            identity: IdentitySource::default(),
+            tls: TlsMode::default(),
+            limits: Limits::default(),
+            capabilities: Capabilities::default(),
+            show_qr: false,
+            rendezvous: None,
+            web: false,
+            webtransport: false,
+            dns_name: None,
+            announce: None,
+            health: None,
+        }
+    }
+
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn identity(mut self, identity: IdentitySource) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsMode) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn show_qr(mut self, show_qr: bool) -> Self {
+        self.show_qr = show_qr;
+        self
+    }
+
+    pub fn rendezvous(mut self, server: Option<String>) -> Self {
+        self.rendezvous = server;
+        self
+    }
+
+    pub fn web(mut self, web: bool) -> Self {
+        self.web = web;
+        self
+    }
+
+    pub fn webtransport(mut self, webtransport: bool) -> Self {
+        self.webtransport = webtransport;
+        self
+    }
+
+    pub fn dns_name(mut self, domain: Option<String>) -> Self {
+        self.dns_name = domain;
+        self
+    }
+
+    pub fn announce(mut self, name: Option<String>) -> Self {
+        self.announce = name;
+        self
+    }
+
+    pub fn health(mut self, health: Option<Health>) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn build(self) -> Result<ListenerSettings, BuildError> {
+        check_limits(&self.limits)?;
+        let mut options = self.options;
+        apply_capabilities(&mut options, self.identity, self.capabilities, &self.limits);
+        Ok(ListenerSettings {
+            paths: self.paths,
+            options,
+            addr: self.addr,
+            tls: self.tls,
+            limits: self.limits,
+            show_qr: self.show_qr,
+            rendezvous: self.rendezvous,
+            web: self.web,
+            webtransport: self.webtransport,
+            dns_name: self.dns_name,
+            announce: self.announce,
+            health: self.health,
+        })
+    }
+}
+
+// 接続の設定 (run_client・run_send に渡す)
+pub struct ClientSettings {
+    pub paths: Paths,
+    pub options: ChatOptions,
+    pub uri: String,
+    /// 接続先をアドレス帳の名前で指定した場合の連絡先 (フィンガープリントの照合に使う)
+    pub contact: Option<(String, Contact)>,
+    /// known_peersに登録されていない相手や証明書が変わった相手への接続を拒否する
+    pub strict: bool,
+    pub tls: TlsMode,
+    pub proxy: Option<Proxy>,
+    pub reconnect: ReconnectPolicy,
+    pub limits: Limits,
+}
+
+pub struct ClientBuilder {
+    paths: Paths,
+    options: ChatOptions,
+    uri: Option<String>,
+    contact: Option<(String, Contact)>,
+    strict: bool,
+    identity: IdentitySource,
+    tls: TlsMode,
+    proxy: Option<Proxy>,
+    reconnect: ReconnectPolicy,
+    limits: Limits,
+    capabilities: Capabilities,
+}
+
+impl ClientBuilder {
+    pub fn new(paths: Paths, options: ChatOptions) -> Self {
+        Self {
+            paths,
+            options,
+            uri: None,
+            contact: None,
+            strict: false,
+            identity: IdentitySource::default(),
+            tls: TlsMode::default(),
+            proxy: None,
+            reconnect: ReconnectPolicy::default(),
+            limits: Limits::default(),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    // 接続先 (URIまたは連絡先の名前) を解決して設定する。連絡先であればその設定も適用する
+    pub fn target(mut self, target: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let target = contacts::resolve_target(target, &self.paths.contacts_file())?;
+        self.uri = Some(target.uri);
+        if let Some((name, contact)) = target.contact {
+            self = self.contact(name, contact);
+        }
+        Ok(self)
+    }
+
+    pub fn uri(mut self, uri: impl Into<String>) -> Self {
+        self.uri = Some(uri.into());
+        self
+    }
+
+    // 連絡先の設定 (名前・通知・ファイルの受け取り・strict) を適用し、フィンガープリントの照合に使う
+    pub fn contact(mut self, name: String, contact: Contact) -> Self {
+        self.strict |= contact.strict;
+        self.capabilities.receive_files = contact.accept_files;
+        self.limits.max_file_bytes = contact.max_file_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+        self.options.apply_contact(&contact);
+        self.contact = Some((name, contact));
+        self
+    }
+
+    // 連絡先の strict が有効な場合は、ここでfalseにしても照合する
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict |= strict;
+        self
+    }
+
+    pub fn identity(mut self, identity: IdentitySource) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsMode) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn build(self) -> Result<ClientSettings, BuildError> {
+        let uri = self.uri.ok_or(BuildError::MissingUri)?;
+        let url = url::Url::parse(&uri).map_err(|e| BuildError::InvalidUri(e.to_string()))?;
+        match url.scheme() {
+            "wss" => {}
+            // https:// はWebTransportで接続する (UDPのため、プロキシは経由できない)
+            #[cfg(feature = "webtransport")]
+            "https" if self.proxy.is_some() => {
+                return Err(BuildError::InvalidUri("https:// (WebTransport) ではプロキシを経由できません".to_string()));
+            }
+            #[cfg(feature = "webtransport")]
+            "https" => {}
+            scheme => return Err(BuildError::InvalidUri(format!("wss:// で始めてください ({})", scheme))),
+        }
+        if url.host_str().is_none_or(|host| host.is_empty()) {
+            return Err(BuildError::InvalidUri("ホスト名がありません".to_string()));
+        }
+        if self.reconnect.attempts == 0 {
+            return Err(BuildError::NoAttempts);
+        }
+        if self.reconnect.initial_delay > self.reconnect.max_delay {
+            return Err(BuildError::DelayOrder);
+        }
+        check_limits(&self.limits)?;
+
+        let mut options = self.options;
+        apply_capabilities(&mut options, self.identity, self.capabilities, &self.limits);
+        Ok(ClientSettings {
+            paths: self.paths,
+            options,
+            uri,
+            contact: self.contact,
+            strict: self.strict,
+            tls: self.tls,
+            proxy: self.proxy,
+            reconnect: self.reconnect,
+            limits: self.limits,
+        })
+    }
+}
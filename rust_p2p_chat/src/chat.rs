@@ -0,0 +1,1166 @@
+use crate::commands::DraftsAction;
+use crate::config::{Config, WebhookEvent};
+use crate::contacts::Contact;
+use crate::builder::IdentitySource;
+use crate::drafts::Drafts;
+use crate::event::{DisconnectReason, Event, Events, SessionCommand, IDLE_CLOSE_REASON};
+use crate::frontend::Frontend;
+use crate::history::{Direction, EventKind, History, MessageSignature};
+use crate::hook::{Effects, Hooks};
+use crate::i18n::Msg;
+use crate::liveness::{Activity, Check, Liveness};
+use crate::paths::Paths;
+use crate::protocol::{BackfillMessage, Envelope};
+use crate::runtime::Runtime;
+use crate::session::{self, ResumeTokens, SessionStore};
+use crate::transfer::{self, DownloadConfig, Downloads};
+use crate::trust::KnownPeers;
+use crate::webhook::Webhooks;
+use crate::{debug, identity, keystore, latency, logging, outbox, ratelimit, stats, ui, vault};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{stream::StreamExt, SinkExt};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tracing::Instrument;
+
+// 接続後のチャット: メッセージ・ファイルの送受信、履歴への記録、画面とのやり取り
+
+// こちらから切断したときに、相手からCloseが返るのを待つ時間
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+// 送信中のファイルから先に受け取っておくEnvelopeの数 (取り消しがすぐ効くよう少なくする)
+const UPLOAD_BUFFER: usize = 8;
+
+// Deliveredを待つスパンの上限 (応答しない相手でも溜まり続けないよう古いものから閉じる)
+const MAX_ROUNDTRIP_SPANS: usize = 256;
+
+// 履歴を開く。暗号化されている場合はパスフレーズ (キーストアに預けていればそれ) で鍵を導出する
+pub fn open_history(paths: &Paths) -> Result<History, Box<dyn std::error::Error>> {
+    let key_file = paths.history_key_file();
+    let vault = if key_file.exists() {
+        let passphrase = match keystore::history_passphrase(paths)? {
+            Some(passphrase) => passphrase,
+            None => vault::read_passphrase(vault::PASSPHRASE_ENV, "履歴のパスフレーズ: ")?,
+        };
+        Some(vault::Vault::unlock(&key_file, &passphrase)?)
+    } else {
+        None
+    };
+    History::open(paths.history_file(), vault)
+}
+
+// 接続中に使う設定をまとめたもの
+#[derive(Clone)]
+pub struct ChatOptions {
+    pub downloads: DownloadConfig,
+    /// 設定ファイルが変更されると新しい内容に置き換わる
+    pub config: watch::Receiver<Config>,
+    /// 相手のメッセージを表示するときの名前 (省略時は「相手」)
+    pub nickname: Option<String>,
+    /// メッセージの受信や相手の接続・切断を通知音で知らせる (--no-alerts と連絡先のnotify)
+    pub notify: bool,
+    /// --download-dir が指定されている (連絡先ごとの保存先より優先する)
+    pub download_dir_fixed: bool,
+    /// TLSハンドシェイクで決まったパラメータ (`debug dump` 用)
+    pub negotiated: Option<debug::Negotiated>,
+    /// チャット画面の表示方法
+    pub ui: ui::UiOptions,
+    /// -q: 起動・接続時の案内を表示しない
+    pub quiet: bool,
+    /// 自分の証明書と鍵の読み込み元
+    pub identity: IdentitySource,
+    /// 送るメッセージに署名する
+    pub sign_messages: bool,
+    /// 取り消すと待受・接続・転送を止め、相手に切断を知らせて終了する (Ctrl+Cなど)
+    pub shutdown: CancellationToken,
+    /// 送受信するメッセージに処理を挟むフック
+    pub hooks: Hooks,
+    /// タスクの起動・時間待ち・TCP接続に使う実行環境 (既定はtokio)
+    pub runtime: Runtime,
+}
+
+impl ChatOptions {
+    // 組み込み先 (C・Pythonなど) で使う既定の設定。保存先の config.toml を読み、変更を監視する (tokioのランタイム内で呼ぶ)
+    pub fn embedded(paths: &Paths) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Config::load(&paths.config_file())?;
+        let options = Self {
+            downloads: DownloadConfig {
+                dir: config.downloads.dir.clone().unwrap_or_else(|| paths.downloads_dir()),
+                per_peer: config.downloads.per_peer,
+                accept: true,
+                max_bytes: None,
+            },
+            config: crate::config::watch(paths.config_file(), config.clone()),
+            nickname: None,
+            notify: false,
+            download_dir_fixed: false,
+            negotiated: None,
+            ui: ui::UiOptions {
+                mode: ui::UiMode::Plain,
+                format: ui::OutputFormat::Jsonl,
+                color: false,
+                input_history: None,
+                settings: config.ui.clone(),
+                contacts: Vec::new(),
+            },
+            quiet: true,
+            identity: IdentitySource::Profile,
+            sign_messages: true,
+            shutdown: CancellationToken::new(),
+            hooks: Hooks::default(),
+            runtime: Runtime::default(),
+        };
+        Ok(options.headless())
+    }
+
+    // 端末を使わない場合 (デーモン・ライブラリ) の設定。出来事はjsonlにして送り先に渡し、入力履歴も残さない
+    pub fn headless(mut self) -> Self {
+        self.quiet = true;
+        self.ui.format = ui::OutputFormat::Jsonl;
+        self.ui.input_history = None;
+        self
+    }
+
+    // 連絡先に保存された相手ごとの設定を適用する
+    pub fn apply_contact(&mut self, contact: &Contact) {
+        self.nickname = contact.nickname.clone();
+        self.notify &= contact.notify;
+        self.downloads.accept = contact.accept_files;
+        self.downloads.max_bytes = contact.max_file_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+        if let (false, Some(dir)) = (self.download_dir_fixed, &contact.download_dir) {
+            self.downloads.dir = dir.clone();
+        }
+    }
+}
+
+// 接続のどちら側か
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Listener,
+    Client,
+}
+
+// 接続後のメッセージ送受信をハンドルする共通関数
+// セッション (run_session) と画面 (plain・TUI) を並べて動かし、出来事とコマンドでつなぐ
+// headlessを渡した場合 (デーモン) は端末を使わず、その送り先と入力でやり取りする
+pub(crate) async fn handle_connection<S>(
+    ws_stream: tokio_tungstenite::WebSocketStream<S>,
+    peer: &str,
+    history: &mut History,
+    role: Role,
+    paths: &Paths,
+    options: ChatOptions,
+    headless: Option<ui::Headless>,
+) -> DisconnectReason
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let nickname = options.nickname.clone().unwrap_or_else(|| Msg::Peer.to_string());
+    let (ui, input) = match headless {
+        Some(headless) => ui::headless(&options.ui, headless),
+        None => ui::start(&options.ui, peer, &nickname),
+    };
+    let frontend = Frontend::new(&options, nickname);
+    let quiet = options.quiet;
+    let (event_tx, events) = mpsc::unbounded_channel();
+    let (commands, command_rx) = mpsc::unbounded_channel();
+    let (reason, ()) = tokio::join!(
+        run_session(ws_stream, peer, history, role, paths, options, event_tx, command_rx),
+        frontend.run(ui, input, events, commands),
+    );
+    // 行編集・TUIは画面の終了時に片付いているので、端末を元に戻した後に表示される
+    if !quiet {
+        println!("{}", Msg::ChatEnded);
+    }
+    reason
+}
+
+// 1つの接続のセッション。コマンドを受けて送信し、受信した内容や状態の変化を出来事としてeventsに送る
+// クライアント側は接続直後にResumeを送り、前回のセッションの再開と切断中のメッセージの再送を求める
+// 接続が終わるか、コマンドの送り元がすべてなくなると、終わった理由を返す (最後にEvent::Disconnectedを送る)
+#[allow(clippy::too_many_arguments)]
+pub async fn run_session<S>(
+    ws_stream: tokio_tungstenite::WebSocketStream<S>,
+    peer: &str,
+    history: &mut History,
+    role: Role,
+    paths: &Paths,
+    options: ChatOptions,
+    events: mpsc::UnboundedSender<Event>,
+    mut commands: mpsc::UnboundedReceiver<SessionCommand>,
+) -> DisconnectReason
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    // セッションを再開した場合、履歴上の相手の名前は前回のものに切り替わる
+    let mut peer = peer.to_string();
+
+    let ChatOptions { downloads, config, nickname, negotiated, ui: ui_options, .. } = options;
+    let runtime = options.runtime.clone();
+    let nickname = nickname.unwrap_or_else(|| Msg::Peer.to_string());
+    let events = Events::new(events);
+    events.send(Event::PeerConnected { peer: peer.clone() });
+    let webhooks = Webhooks::new(config.clone(), runtime.clone());
+    webhooks.emit(WebhookEvent::PeerConnected, &peer, json!({ "role": format!("{:?}", role).to_lowercase() }));
+
+    let entries = history.recent(&peer, ui_options.settings.scrollback).unwrap_or_else(|e| {
+        tracing::warn!(error = %e, "以前のメッセージを読み込めませんでした");
+        Vec::new()
+    });
+    events.send(Event::Earlier { entries });
+    record(history, &peer, EventKind::System { text: format!("{} と接続しました", peer) });
+    announce_drafts(&events, paths, &peer);
+
+    // 送受信したフレームを記録し、`debug dump` で集められるよう定期的に書き出す (本文は残さない)
+    let live = Arc::new(Mutex::new(debug::LiveState::new(&paths.cache_dir, &format!("{:?}", role), &peer, negotiated)));
+    let mut live_interval = runtime.interval(std::time::Duration::from_secs(5));
+
+    // WebSocketストリームを送信と受信に分割
+    let (ws_sender, ws_receiver) = ws_stream.split();
+    // 接続の生存確認 (必要なときだけPingを送り、往復時間を測り、応答しない相手を見つける)。待受側はやり取りのない時間が続くと切断する
+    let mut liveness = Liveness::new(&config.borrow().connection);
+    let activity = liveness.activity();
+    let idle_timeout = match role {
+        Role::Listener => config.borrow().listen.idle_timeout_mins.map(|mins| std::time::Duration::from_secs(mins * 60)),
+        Role::Client => None,
+    };
+    let live_out = Arc::clone(&live);
+    let activity_out = activity.clone();
+    let ws_sender = ws_sender.with(move |message: tokio_tungstenite::tungstenite::Message| {
+        lock(&live_out).frame_out(&message);
+        activity_out.sent(&message);
+        futures_util::future::ready(Ok::<_, tokio_tungstenite::tungstenite::Error>(message))
+    });
+    // 送信は順番待ちに入れ、書き込みは別のタスクで行う (受け取りの遅い相手で入力の処理が止まらないように)
+    let connection = config.borrow().connection.clone();
+    let (ws_sender, writer) = outbox::spawn(&runtime, ws_sender, connection.send_queue, connection.queue_full);
+    let dropped = events.clone();
+    let mut ws_sender = ws_sender.on_drop(move || dropped.warn(Msg::SendQueueFull.text()));
+    let live_in = Arc::clone(&live);
+    let activity_in = activity.clone();
+    let mut ws_receiver = ws_receiver.inspect(move |result| {
+        if let Ok(message) = result {
+            lock(&live_in).frame_in(message);
+            activity_in.received(message);
+        }
+    });
+
+    // この接続中に受信した相手側の通し番号 (再送分との重複表示を防ぐ)
+    let mut seen_remote: HashSet<u64> = HashSet::new();
+    // 有効なトークンで再開したセッションの、再送してよい範囲の始まり (再開していない相手には再送しない)
+    let mut backfill_cursor: Option<u64> = None;
+    let mut downloads = Downloads::new(downloads, &peer);
+    let mut next_transfer_id: u64 = 1;
+    // 送信中のファイル。読み込みと分割は別のタスクで行い、分割したEnvelopeをuploadsで受け取って送る
+    let (uploads_tx, mut uploads) = mpsc::channel(UPLOAD_BUFFER);
+    let mut sending: std::collections::HashMap<u64, Sending> = std::collections::HashMap::new();
+    let shutdown = options.shutdown.clone();
+    // フックが受信したメッセージに返信する場合は、画面からのメッセージと同じように送る
+    let hooks = options.hooks.clone();
+    let (reply_tx, mut replies) = mpsc::unbounded_channel();
+    // 送信したファイルの名前 (相手からのFileReceivedの表示に使う)
+    let mut sent_files: std::collections::HashMap<u64, String> = std::collections::HashMap::new();
+    // 保持期間を過ぎた履歴を定期的に削除する (最初のtickは即座に発火する)
+    let mut prune_interval = runtime.interval(std::time::Duration::from_secs(60 * 60));
+    let started = runtime.now();
+    // 相手から受け取る量の制限。上限を超えるとthrottledが終わるまで受信を読まない
+    let mut limiter = ratelimit::RateLimiter::new(&config.borrow().rate_limit);
+    let mut throttled: Option<futures_util::future::BoxFuture<'static, ()>> = None;
+
+    // 送信するメッセージに署名する鍵と、受信したメッセージの署名を検証するための相手の証明書
+    let identity = match options.identity.load(paths) {
+        Ok(identity) => Some(identity),
+        Err(e) => {
+            tracing::warn!(error = %e, "鍵を読み込めないため、メッセージに署名せずに続けます");
+            None
+        }
+    };
+    let sign_messages = options.sign_messages;
+    let mut peer_cert: Option<Vec<u8>> = None;
+    if let Some(identity) = &identity {
+        remember_cert(paths, &identity.cert_der);
+        let hello = Envelope::Identity { cert: BASE64.encode(&identity.cert_der) };
+        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(hello.encode())).await {
+            tracing::error!(error = %e, "証明書の送信に失敗しました");
+        }
+    }
+
+    // 再開要求からSessionの応答までのスパン (プロトコルの挨拶)
+    let mut hello: Option<tracing::Span> = None;
+    // 送信したメッセージからDeliveredの応答までのスパン (通し番号ごと)
+    let mut roundtrips: BTreeMap<u64, tracing::Span> = BTreeMap::new();
+    if role == Role::Client {
+        let token = match ResumeTokens::load(paths.resume_tokens_file()) {
+            Ok(tokens) => tokens.get(&peer).cloned(),
+            Err(e) => {
+                tracing::warn!(error = %e, "再接続用トークンの読み込みに失敗しました");
+                None
+            }
+        };
+        let since = history.last_remote_seq(&peer).unwrap_or_else(|e| {
+            tracing::error!(error = %e, "履歴の読み込みに失敗しました");
+            0
+        });
+        let request = Envelope::Resume { token, since };
+        hello = Some(tracing::info_span!("hello", since, resumed = tracing::field::Empty));
+        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(request.encode())).await {
+            tracing::error!(error = %e, "セッション再開要求の送信に失敗しました");
+        }
+    }
+
+    let reason = loop {
+        tokio::select! {
+            _ = live_interval.tick() => {
+                let mut live = lock(&live);
+                live.set_active_downloads(downloads.active());
+                live.flush();
+            }
+            _ = prune_interval.tick() => {
+                let retention = config.borrow().retention.clone();
+                match history.prune(&retention) {
+                    Ok(0) => {}
+                    Ok(removed) => tracing::info!(removed, "保持期間を過ぎた履歴を削除しました"),
+                    Err(e) => tracing::error!(error = %e, "履歴の削除に失敗しました"),
+                }
+            }
+            // 相手から何も届かない時間が続いたらPingを送り、応答のないまま続けば切断する
+            _ = runtime.sleep(liveness.next_check()) => match liveness.check() {
+                Check::Wait => {}
+                Check::Ping(data) => {
+                    if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Ping(data)).await {
+                        tracing::error!(error = %e, "Pingの送信に失敗しました");
+                        break DisconnectReason::Error(e.to_string());
+                    }
+                }
+                Check::Unreachable => {
+                    tracing::warn!("Pingへの応答が続けて届かないため、相手に届かなくなったとみなします");
+                    break DisconnectReason::Unreachable;
+                }
+            },
+            // 待っている間にやり取りがあれば、次の期限まで待ち直す
+            _ = runtime.sleep(idle_left(&activity, idle_timeout)), if idle_timeout.is_some() => {
+                if !idle_left(&activity, idle_timeout).is_zero() {
+                    continue;
+                }
+                let mins = idle_timeout.unwrap_or_default().as_secs() / 60;
+                tracing::info!(mins, "やり取りのない時間が続いたため切断します");
+                events.info(Msg::IdleClosed.with(&[&mins]));
+                send_close_with(&mut ws_sender, CloseCode::Away, IDLE_CLOSE_REASON.to_string()).await;
+                break DisconnectReason::Idle;
+            }
+            // Ctrl+Cやデーモンの終了で取り消された
+            _ = shutdown.cancelled() => {
+                tracing::info!("終了の指示によりチャットを終了します");
+                send_close(&mut ws_sender).await;
+                break DisconnectReason::Closed;
+            }
+            Some(upload) = uploads.recv() => match upload {
+                transfer::Upload::Started { id, name, size } => {
+                    events.info(Msg::SendingFile.with(&[&name, &size, &id]));
+                    if let Some(sending) = sending.get_mut(&id) {
+                        sending.name = name;
+                        sending.size = size;
+                    }
+                }
+                transfer::Upload::Envelope { id, envelope } => {
+                    let offset = match &envelope {
+                        Envelope::FileChunk { offset, .. } => Some(*offset),
+                        _ => None,
+                    };
+                    if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(envelope.encode())).await {
+                        tracing::error!(error = %e, "ファイルの送信に失敗しました");
+                        break DisconnectReason::Error(e.to_string());
+                    }
+                    if let (Some(offset), Some(upload)) = (offset, sending.get(&id)) {
+                        let bytes = (offset + transfer::CHUNK_SIZE as u64).min(upload.size);
+                        let (name, direction, total) = (upload.name.clone(), Direction::Outgoing, Some(upload.size));
+                        events.send(Event::TransferProgress { id, name, direction, bytes, total });
+                    }
+                }
+                transfer::Upload::Finished { id } => {
+                    let Some(Sending { name, size, .. }) = sending.remove(&id) else {
+                        continue;
+                    };
+                    events.info(Msg::SentFile.with(&[&name]));
+                    sent_files.insert(id, name.clone());
+                    record(history, &peer, EventKind::FileTransfer { direction: Direction::Outgoing, file_name: name, size });
+                }
+                transfer::Upload::Cancelled { id } => {
+                    let name = sending.remove(&id).map(|upload| upload.name).unwrap_or_default();
+                    // 接続を閉じる場合は、相手は受信途中のファイルを自分で破棄する
+                    if shutdown.is_cancelled() {
+                        continue;
+                    }
+                    let cancel = Envelope::FileCancel { id };
+                    if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(cancel.encode())).await {
+                        tracing::error!(error = %e, "ファイル送信の取り消しを通知できませんでした");
+                        break DisconnectReason::Error(e.to_string());
+                    }
+                    events.info(Msg::CancelledSend.with(&[&name]));
+                }
+                transfer::Upload::Unreadable { id, error } => {
+                    let started = sending.remove(&id).is_some_and(|upload| !upload.name.is_empty());
+                    events.info(Msg::CannotReadFile.with(&[&error]));
+                    // 送っている途中で読めなくなった場合は、相手に受信途中のファイルを破棄させる
+                    if started {
+                        let cancel = Envelope::FileCancel { id };
+                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(cancel.encode())).await {
+                            tracing::error!(error = %e, "ファイル送信の取り消しを通知できませんでした");
+                            break DisconnectReason::Error(e.to_string());
+                        }
+                    }
+                }
+            },
+            // 画面から届いたコマンドを処理する
+            command = next_command(&mut replies, &mut commands) => {
+                let Some(command) = command else {
+                    tracing::info!("入力が閉じられたためチャットを終了します");
+                    send_close(&mut ws_sender).await;
+                    break DisconnectReason::InputClosed;
+                };
+                match command {
+                    SessionCommand::SendText(body) => {
+                        let Some(body) = hooks.outgoing(&peer, body) else {
+                            continue;
+                        };
+                        match send_chat(&mut ws_sender, history, &peer, identity.as_ref().filter(|_| sign_messages), body.clone()).await {
+                            Ok(seq) => {
+                                lock(&live).message_out();
+                                if roundtrips.len() >= MAX_ROUNDTRIP_SPANS {
+                                    roundtrips.pop_first();
+                                }
+                                roundtrips.insert(seq, tracing::info_span!("roundtrip", seq));
+                                events.send(Event::MessageSent { seq, body });
+                            }
+                            Err(e) => {
+                                tracing::error!(error = %e, "メッセージの送信に失敗しました");
+                                save_draft(&events, paths, &peer, body);
+                                break DisconnectReason::Error(e.to_string());
+                            }
+                        }
+                    }
+                    SessionCommand::Close => {
+                        tracing::info!("チャットを終了します");
+                        send_close(&mut ws_sender).await;
+                        break DisconnectReason::Closed;
+                    }
+                    SessionCommand::Who => {
+                        let fingerprint = peer_cert.as_deref().map(identity::fingerprint).unwrap_or_else(|| Msg::NotReceived.to_string());
+                        events.info(Msg::WhoPeer.with(&[&nickname, &peer, &(runtime.now().saturating_duration_since(started).as_secs() / 60)]));
+                        events.info(Msg::WhoCert.with(&[&fingerprint]));
+                        if let Some(average) = liveness.latency().average() {
+                            events.info(Msg::WhoLatency.with(&[&latency::millis(average)]));
+                        }
+                    }
+                    SessionCommand::Stats => {
+                        let stats = lock(&live).stats();
+                        for line in stats_lines(&stats) {
+                            events.info(line);
+                        }
+                    }
+                    SessionCommand::Ping => {
+                        let ping = Envelope::Ping { sent_at: liveness.stamp() };
+                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(ping.encode())).await {
+                            tracing::error!(error = %e, "Pingの送信に失敗しました");
+                            break DisconnectReason::Error(e.to_string());
+                        }
+                    }
+                    // ファイルを分割して送信する (送り終わるまでの間も他のコマンドやメッセージを扱う)
+                    SessionCommand::SendFile(path) => {
+                        let id = next_transfer_id;
+                        next_transfer_id += 1;
+                        let token = shutdown.child_token();
+                        let (acked_tx, acked) = watch::channel(0);
+                        let window = config.borrow().connection.transfer_window();
+                        sending.insert(id, Sending { token: token.clone(), acked: acked_tx, name: String::new(), size: 0 });
+                        transfer::spawn_upload(&runtime, id, path, token, acked, window, uploads_tx.clone());
+                    }
+                    SessionCommand::CancelTransfer { id, direction: Direction::Outgoing } => match sending.get(&id) {
+                        Some(upload) => upload.token.cancel(),
+                        None => events.info(Msg::NoSuchTransfer.with(&[&id])),
+                    },
+                    SessionCommand::CancelTransfer { id, direction: Direction::Incoming } => match downloads.cancel(id) {
+                        Some(name) => events.info(Msg::CancelledReceive.with(&[&name])),
+                        None => events.info(Msg::NoSuchTransfer.with(&[&id])),
+                    },
+                    SessionCommand::Custom { name, args } => match hooks.command(&peer, &name, &args) {
+                        Some(effects) => apply_effects(effects, &events, &reply_tx),
+                        None => events.warn(Msg::UnknownCommand.with(&[&name])),
+                    },
+                    // 前回送信できなかったメッセージを扱う
+                    SessionCommand::Drafts(action) => {
+                        let mut drafts = match Drafts::load(paths.drafts_file()) {
+                            Ok(drafts) => drafts,
+                            Err(e) => {
+                                tracing::error!(error = %e, "下書きの読み込みに失敗しました");
+                                continue;
+                            }
+                        };
+                        match action {
+                            DraftsAction::List => {
+                                if drafts.get(&peer).is_empty() {
+                                    events.info(Msg::NoDrafts.text());
+                                }
+                                for draft in drafts.get(&peer) {
+                                    events.info(format!("[{}] {}", draft.saved_at.format("%m/%d %H:%M"), draft.body));
+                                }
+                            }
+                            DraftsAction::Send => {
+                                let mut pending = drafts.take(&peer).into_iter();
+                                let mut failed = None;
+                                for draft in pending.by_ref() {
+                                    if let Err(e) = send_chat(&mut ws_sender, history, &peer, identity.as_ref().filter(|_| sign_messages), draft.body.clone()).await {
+                                        tracing::error!(error = %e, "メッセージの送信に失敗しました");
+                                        drafts.push(&peer, draft.body);
+                                        failed = Some(e.to_string());
+                                        break;
+                                    }
+                                    lock(&live).message_out();
+                                    events.info(Msg::DraftSent.with(&[&draft.body]));
+                                }
+                                for draft in pending {
+                                    drafts.push(&peer, draft.body);
+                                }
+                                if let Err(e) = drafts.save() {
+                                    tracing::error!(error = %e, "下書きの保存に失敗しました");
+                                }
+                                if let Some(error) = failed {
+                                    events.info(Msg::DraftsKept.text());
+                                    break DisconnectReason::Error(error);
+                                }
+                            }
+                            DraftsAction::Discard => {
+                                let discarded = drafts.take(&peer).len();
+                                if let Err(e) = drafts.save() {
+                                    tracing::error!(error = %e, "下書きの保存に失敗しました");
+                                }
+                                events.info(Msg::DraftsDiscarded.with(&[&discarded]));
+                            }
+                        }
+                    }
+                }
+            }
+            // 受信が上限を超えている間は、相手からのフレームを読まずに待つ
+            _ = async { if let Some(throttled) = throttled.as_mut() { throttled.await } }, if throttled.is_some() => {
+                throttled = None;
+            }
+            // WebSocketからメッセージを受信して表示
+            msg_result = ws_receiver.next(), if throttled.is_none() => {
+                match msg_result {
+                    Some(Ok(msg)) => {
+                        match msg {
+                            tokio_tungstenite::tungstenite::Message::Text(text) => {
+                                let envelope = Envelope::decode(&text);
+                                // チャットのメッセージ (再送はまとめて1件) の数と、フレームの大きさを数える
+                                let messages = matches!(envelope, Envelope::Chat { .. } | Envelope::Backfill { .. }) as u64;
+                                let file = matches!(envelope, Envelope::FileChunk { .. });
+                                // 設定ファイルを書き換えた場合は、接続を切らずに新しい上限を使う
+                                limiter.reconfigure(&config.borrow().rate_limit);
+                                match limiter.check(runtime.now(), messages, text.len() as u64, file) {
+                                    ratelimit::Verdict::Allow => {}
+                                    ratelimit::Verdict::Throttle { delay, first } => {
+                                        if first {
+                                            tracing::warn!("相手からの受信が上限を超えたため、受け取りを遅らせます");
+                                            events.warn(Msg::RateLimited.text());
+                                        }
+                                        throttled = Some(runtime.sleep(delay));
+                                    }
+                                    ratelimit::Verdict::Disconnect => {
+                                        tracing::warn!("相手からの受信が上限を超え続けたため切断します");
+                                        events.warn(Msg::RateLimitDisconnect.text());
+                                        send_close_with(&mut ws_sender, CloseCode::Policy, Msg::RateLimitDisconnect.to_string()).await;
+                                        break DisconnectReason::Error(Msg::RateLimitDisconnect.to_string());
+                                    }
+                                }
+                                match envelope {
+                                    Envelope::Identity { cert } => match BASE64.decode(&cert) {
+                                        Ok(cert) => {
+                                            tracing::info!(fingerprint = %identity::fingerprint(&cert), "相手の証明書を受信しました");
+                                            remember_cert(paths, &cert);
+                                            events.send(Event::Verification(verification(paths, &cert)));
+                                            peer_cert = Some(cert);
+                                        }
+                                        Err(e) => tracing::warn!(error = %e, "相手の証明書を読み取れませんでした"),
+                                    },
+                                    Envelope::Chat { seq, body, sig } => {
+                                        if seq > 0 && !seen_remote.insert(seq) {
+                                            continue;
+                                        }
+                                        let span = tracing::info_span!("message", direction = "in", seq);
+                                        let _enter = span.enter();
+                                        let signature = check_signature(&events, peer_cert.as_ref(), seq, &body, sig);
+                                        if let Some(shown) = hook_incoming(&hooks, &peer, body.clone(), &events, &reply_tx) {
+                                            webhooks.emit(WebhookEvent::MessageReceived, &peer, json!({ "from": nickname, "seq": seq, "body": shown }));
+                                            events.send(Event::MessageReceived { from: nickname.clone(), seq, body: shown, sent_at: None });
+                                        }
+                                        lock(&live).message_in();
+                                        if logging::log_messages() {
+                                            tracing::info!(len = body.len(), "メッセージを受信しました");
+                                        }
+                                        record_remote(history, &peer, seq, chrono::Local::now(), body, signature);
+                                        if seq > 0 {
+                                            if let Err(e) = send_delivered(&mut ws_sender, seq).await {
+                                                tracing::error!(error = %e, "受信確認の送信に失敗しました");
+                                                break DisconnectReason::Error(e.to_string());
+                                            }
+                                        }
+                                    }
+                                    Envelope::Resume { token, since } => {
+                                        let (token, resumed, resumes) = match resume_session(paths, token.as_deref(), &peer, history.last_seq()) {
+                                            Ok((token, Some(session))) => {
+                                                tracing::info!(peer = %session.peer, resumes = session.resumes, "セッションを再開しました");
+                                                peer = session.peer;
+                                                downloads.set_peer(&peer);
+                                                lock(&live).set_peer(&peer);
+                                                events.send(Event::PeerRenamed { peer: peer.clone() });
+                                                announce_drafts(&events, paths, &peer);
+                                                lock(&live).set_reconnects(session.resumes);
+                                                backfill_cursor = Some(session.cursor);
+                                                (token, true, session.resumes)
+                                            }
+                                            Ok((token, None)) => (token, false, 0),
+                                            Err(e) => {
+                                                tracing::error!(error = %e, "セッションの保存に失敗しました");
+                                                continue;
+                                            }
+                                        };
+                                        let mut responses = vec![Envelope::Session { token, resumed, resumes }];
+                                        if let Some(cursor) = backfill_cursor.filter(|_| since > 0) {
+                                            tracing::info!(since, cursor, "再送要求を受信しました");
+                                            responses.push(backfill_envelope(history, &peer, since.max(cursor)));
+                                        }
+                                        let mut failed = None;
+                                        for response in responses {
+                                            if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(response.encode())).await {
+                                                tracing::error!(error = %e, "セッション情報の送信に失敗しました");
+                                                failed = Some(e.to_string());
+                                                break;
+                                            }
+                                        }
+                                        if let Some(error) = failed {
+                                            break DisconnectReason::Error(error);
+                                        }
+                                    }
+                                    Envelope::Session { token, resumed, resumes } => {
+                                        if let Some(span) = hello.take() {
+                                            span.record("resumed", resumed);
+                                        }
+                                        if resumed {
+                                            lock(&live).set_reconnects(resumes);
+                                            events.send(Event::Resumed);
+                                        }
+                                        let saved = ResumeTokens::load(paths.resume_tokens_file()).and_then(|mut tokens| {
+                                            tokens.set(&peer, &token);
+                                            tokens.save()
+                                        });
+                                        if let Err(e) = saved {
+                                            tracing::warn!(error = %e, "再接続用トークンの保存に失敗しました");
+                                        }
+                                    }
+                                    Envelope::BackfillRequest { since } => {
+                                        // 相手はIPアドレスでしか分からないため、再開したセッションの範囲だけを再送する
+                                        let response = match backfill_cursor {
+                                            Some(cursor) => {
+                                                tracing::info!(since, cursor, "再送要求を受信しました");
+                                                backfill_envelope(history, &peer, since.max(cursor))
+                                            }
+                                            None => {
+                                                tracing::warn!(since, "セッションを再開していない相手からの再送要求には何も送りません");
+                                                Envelope::Backfill { messages: Vec::new() }
+                                            }
+                                        };
+                                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(response.encode())).await {
+                                            tracing::error!(error = %e, "再送データの送信に失敗しました");
+                                            break DisconnectReason::Error(e.to_string());
+                                        }
+                                    }
+                                    Envelope::Backfill { messages } => {
+                                        tracing::info!(count = messages.len(), "再送データを受信しました");
+                                        if !messages.is_empty() {
+                                            events.info(Msg::Backfill.with(&[&messages.len()]));
+                                        }
+                                        let mut delivered = Vec::new();
+                                        for message in messages {
+                                            if !seen_remote.insert(message.seq) {
+                                                continue;
+                                            }
+                                            let signature = check_signature(&events, peer_cert.as_ref(), message.seq, &message.body, message.sig);
+                                            if let Some(body) = hook_incoming(&hooks, &peer, message.body.clone(), &events, &reply_tx) {
+                                                let (from, seq, sent_at) = (nickname.clone(), message.seq, Some(message.timestamp));
+                                                let fields = json!({ "from": from, "seq": seq, "body": body, "sent_at": message.timestamp.to_rfc3339() });
+                                                webhooks.emit(WebhookEvent::MessageReceived, &peer, fields);
+                                                events.send(Event::MessageReceived { from, seq, body, sent_at });
+                                            }
+                                            lock(&live).message_in();
+                                            record_remote(history, &peer, message.seq, message.timestamp, message.body, signature);
+                                            delivered.push(message.seq);
+                                        }
+                                        let mut failed = None;
+                                        for seq in delivered {
+                                            if let Err(e) = send_delivered(&mut ws_sender, seq).await {
+                                                tracing::error!(error = %e, "受信確認の送信に失敗しました");
+                                                failed = Some(e.to_string());
+                                                break;
+                                            }
+                                        }
+                                        if let Some(error) = failed {
+                                            break DisconnectReason::Error(error);
+                                        }
+                                    }
+                                    Envelope::FileStart { id, name, size, sha256 } => {
+                                        match downloads.start(id, &name, size, &sha256) {
+                                            Ok(true) => events.info(Msg::ReceivingFile.with(&[&name, &size, &id])),
+                                            Ok(false) => events.info(Msg::DeclinedFile.with(&[&name, &size])),
+                                            Err(e) => tracing::error!(error = %e, "受信ファイルを作成できませんでした"),
+                                        }
+                                    }
+                                    Envelope::FileChunk { id, offset, data } => {
+                                        match downloads.chunk(id, offset, &data) {
+                                            Ok(()) => {
+                                                if let Some((name, bytes, total)) = downloads.progress(id) {
+                                                    let (name, direction) = (name.to_string(), Direction::Incoming);
+                                                    events.send(Event::TransferProgress { id, name, direction, bytes, total });
+                                                }
+                                            }
+                                            Err(e) => tracing::error!(error = %e, "ファイルの受信に失敗しました"),
+                                        }
+                                        // 受け取りを断った・失敗した転送にも応答し、相手がFileEndまで送れるようにする
+                                        let ack = Envelope::FileAck { id, received: offset + data.len() as u64 };
+                                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(ack.encode())).await {
+                                            tracing::error!(error = %e, "受信確認の送信に失敗しました");
+                                            break DisconnectReason::Error(e.to_string());
+                                        }
+                                    }
+                                    Envelope::FileAck { id, received } => {
+                                        if let Some(upload) = sending.get(&id) {
+                                            upload.acked.send_modify(|acked| *acked = (*acked).max(received));
+                                        }
+                                    }
+                                    Envelope::FileEnd { id } => {
+                                        let ok = save_download(&events, &webhooks, history, &peer, downloads.finish(id));
+                                        let received = Envelope::FileReceived { id, ok };
+                                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(received.encode())).await {
+                                            tracing::error!(error = %e, "受信確認の送信に失敗しました");
+                                            break DisconnectReason::Error(e.to_string());
+                                        }
+                                    }
+                                    Envelope::StreamStart { id, name } => match downloads.start_stream(id, &name) {
+                                        Ok(true) => events.info(Msg::ReceivingStream.with(&[&name])),
+                                        Ok(false) => events.info(Msg::DeclinedStream.with(&[&name])),
+                                        Err(e) => tracing::error!(error = %e, "受信ファイルを作成できませんでした"),
+                                    },
+                                    Envelope::StreamEnd { id, size, sha256 } => {
+                                        let ok = save_download(&events, &webhooks, history, &peer, downloads.finish_stream(id, size, &sha256));
+                                        let received = Envelope::FileReceived { id, ok };
+                                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(received.encode())).await {
+                                            tracing::error!(error = %e, "受信確認の送信に失敗しました");
+                                            break DisconnectReason::Error(e.to_string());
+                                        }
+                                    }
+                                    Envelope::FileCancel { id } => {
+                                        if let Some((name, _, _)) = downloads.progress(id) {
+                                            events.info(Msg::PeerCancelledFile.with(&[&name]));
+                                        }
+                                        downloads.abort(id);
+                                    }
+                                    Envelope::FileReceived { id, ok } => {
+                                        let name = sent_files.remove(&id).unwrap_or_else(|| format!("#{}", id));
+                                        match ok {
+                                            true => {
+                                                let fields = json!({ "direction": "outgoing", "name": name });
+                                                webhooks.emit(WebhookEvent::TransferCompleted, &peer, fields);
+                                                events.info(Msg::PeerReceivedFile.with(&[&name]));
+                                            }
+                                            false => events.warn(Msg::PeerRejectedFile.with(&[&name])),
+                                        }
+                                    }
+                                    Envelope::Delivered { seq } => {
+                                        roundtrips.remove(&seq);
+                                        events.send(Event::DeliveryAck { seq })
+                                    }
+                                    Envelope::Ping { sent_at } => {
+                                        let pong = Envelope::Pong { sent_at };
+                                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(pong.encode())).await {
+                                            tracing::error!(error = %e, "Pongの送信に失敗しました");
+                                            break DisconnectReason::Error(e.to_string());
+                                        }
+                                    }
+                                    Envelope::Pong { sent_at } => {
+                                        let rtt = liveness.measured(sent_at);
+                                        let average = liveness.latency().average().unwrap_or(rtt);
+                                        events.send(Event::Latency { last: rtt, average });
+                                        events.info(Msg::PingReply.with(&[&latency::millis(rtt), &latency::millis(average)]));
+                                    }
+                                }
+                            }
+                            tokio_tungstenite::tungstenite::Message::Close(close_frame) => {
+                                if let Some(frame) = close_frame.as_ref().filter(|frame| frame.reason == IDLE_CLOSE_REASON) {
+                                    tracing::info!(code = %frame.code, "やり取りのない時間が続いたため、相手が接続を切断しました");
+                                    break DisconnectReason::PeerIdle;
+                                }
+                                if let Some(frame) = close_frame {
+                                    tracing::info!(code = %frame.code, reason = %frame.reason, "相手が接続を切断しました");
+                                    break DisconnectReason::PeerClosed { code: Some(frame.code.into()), reason: frame.reason.to_string() };
+                                } else {
+                                    tracing::info!("相手が接続を切断しました");
+                                    break DisconnectReason::PeerClosed { code: None, reason: String::new() };
+                                }
+                            }
+                            tokio_tungstenite::tungstenite::Message::Ping(data) => {
+                                if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Pong(data)).await {
+                                    tracing::error!(error = %e, "Pongの送信に失敗しました");
+                                    break DisconnectReason::Error(e.to_string());
+                                }
+                            }
+                            tokio_tungstenite::tungstenite::Message::Pong(data) => {
+                                if let Some(last) = liveness.pong(&data) {
+                                    let average = liveness.latency().average().unwrap_or(last);
+                                    events.send(Event::Latency { last, average });
+                                }
+                            }
+                            _ => {
+                                // その他のメッセージタイプは無視
+                            }
+                        }
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!(error = %e, "WebSocketエラー");
+                        break DisconnectReason::Error(e.to_string());
+                    }
+                    None => {
+                        tracing::info!("WebSocket接続が閉じられました");
+                        break DisconnectReason::StreamEnded;
+                    }
+                }
+            }
+        }
+    };
+
+    // こちらから切断した場合は、相手がCloseを返すまで (長くても数秒) 待ってから接続を閉じる
+    if matches!(reason, DisconnectReason::Closed | DisconnectReason::InputClosed | DisconnectReason::Idle) {
+        let _ = runtime.timeout(CLOSE_TIMEOUT, async {
+            while let Some(Ok(message)) = ws_receiver.next().await {
+                if message.is_close() {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+    // 順番待ちに残ったフレームと、相手から切断した場合は受け取ったCloseへの応答を送ってから閉じる
+    writer.finish(ws_sender, &runtime, CLOSE_TIMEOUT).await;
+    events.send(Event::Disconnected { peer: peer.clone(), reason: reason.clone() });
+    let mut live = lock(&live);
+    live.set_state("closed");
+    live.flush();
+    drop(live);
+    record(history, &peer, EventKind::System { text: format!("{} との接続が終了しました", peer) });
+    reason
+}
+
+// フックが返信するメッセージを、画面からのコマンドより先に取り出す
+async fn next_command(
+    replies: &mut mpsc::UnboundedReceiver<String>,
+    commands: &mut mpsc::UnboundedReceiver<SessionCommand>,
+) -> Option<SessionCommand> {
+    tokio::select! {
+        biased;
+        Some(body) = replies.recv() => Some(SessionCommand::SendText(body)),
+        command = commands.recv() => command,
+    }
+}
+
+// 受信したメッセージをフックに通す。表示する本文を返し、フックからの返信は送信の順番待ちに入れる
+// (履歴には署名と一致する元の本文を残す)
+fn hook_incoming(
+    hooks: &Hooks,
+    peer: &str,
+    body: String,
+    events: &Events,
+    replies: &mpsc::UnboundedSender<String>,
+) -> Option<String> {
+    if hooks.is_empty() {
+        return Some(body);
+    }
+    let (shown, effects) = hooks.incoming(peer, body);
+    apply_effects(effects, events, replies);
+    if shown.is_none() {
+        tracing::debug!("フックによりメッセージを表示しませんでした");
+    }
+    shown
+}
+
+// フックの案内を画面に出し、返信を送信の順番待ちに入れる
+fn apply_effects(effects: Effects, events: &Events, replies: &mpsc::UnboundedSender<String>) {
+    for note in effects.notes {
+        events.info(note);
+    }
+    for body in effects.replies {
+        let _ = replies.send(body);
+    }
+}
+
+// 送信中のファイル (名前と大きさは読み込んだ後のUpload::Startedで分かる)
+struct Sending {
+    token: CancellationToken,
+    /// 相手が受け取ったと知らせたバイト数 (FileAck)。送るタスクはこれを見て先に送りすぎないよう待つ
+    acked: watch::Sender<u64>,
+    name: String,
+    size: u64,
+}
+
+// 相手に切断を知らせる (Closeフレームに理由を付ける)
+async fn send_close<S>(ws_sender: &mut S)
+where
+    S: futures_util::Sink<tokio_tungstenite::tungstenite::Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    send_close_with(ws_sender, CloseCode::Normal, Msg::CloseReason.to_string()).await;
+}
+
+async fn send_close_with<S>(ws_sender: &mut S, code: CloseCode, reason: String)
+where
+    S: futures_util::Sink<tokio_tungstenite::tungstenite::Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    let frame = CloseFrame { code, reason: reason.into() };
+    if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Close(Some(frame))).await {
+        tracing::warn!(error = %e, "切断の通知に失敗しました");
+    }
+}
+
+// やり取りのないまま切断するまでの残り時間
+fn idle_left(activity: &Activity, idle_timeout: Option<std::time::Duration>) -> std::time::Duration {
+    idle_timeout.unwrap_or_default().saturating_sub(activity.idle_for())
+}
+
+// デバッグ用の状態は記録の途中でpanicしても読み書きできればよいので、poisonは無視する
+fn lock(live: &Mutex<debug::LiveState>) -> std::sync::MutexGuard<'_, debug::LiveState> {
+    live.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// トークンが有効なら前回のセッションを返す。無効なら新しいセッションを発行する (cursorは履歴の最後の通し番号)
+fn resume_session(
+    paths: &Paths,
+    token: Option<&str>,
+    peer: &str,
+    cursor: u64,
+) -> Result<(String, Option<session::SessionRecord>), Box<dyn std::error::Error>> {
+    let mut store = SessionStore::load(paths.sessions_file())?;
+    let resumed = token.and_then(|token| store.resume(token).map(|record| (token.to_string(), record)));
+    let result = match resumed {
+        Some((token, record)) => (token, Some(record)),
+        None => (store.issue(peer, cursor)?, None),
+    };
+    store.save()?;
+    Ok(result)
+}
+
+// 指定した通し番号より後に相手へ送ったメッセージをBackfillにまとめる
+fn backfill_envelope(history: &History, peer: &str, since: u64) -> Envelope {
+    let messages = match history.sent_since(peer, since) {
+        Ok(entries) => entries
+            .into_iter()
+            .filter_map(|entry| match entry.event {
+                EventKind::Message { body, .. } => Some(BackfillMessage {
+                    seq: entry.seq,
+                    timestamp: entry.timestamp,
+                    body,
+                    sig: entry.signature.map(|signature| signature.sig),
+                }),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            tracing::error!(error = %e, "履歴の読み込みに失敗しました");
+            Vec::new()
+        }
+    };
+    Envelope::Backfill { messages }
+}
+
+// チャットメッセージに署名し、履歴に記録してから送信する。送信したメッセージの通し番号を返す
+// 送信に失敗した場合は履歴から取り消す (再接続時の再送と下書きの送信で二重に届かないようにする)
+pub(crate) async fn send_chat<W>(
+    ws_sender: &mut W,
+    history: &mut History,
+    peer: &str,
+    identity: Option<&identity::Identity>,
+    body: String,
+) -> Result<u64, tokio_tungstenite::tungstenite::Error>
+where
+    W: futures_util::Sink<tokio_tungstenite::tungstenite::Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    let sign = |seq| {
+        identity.and_then(|identity| match identity.sign(&identity::signed_content(seq, &body)) {
+            Ok(sig) => Some(MessageSignature { signer: identity.fingerprint(), sig }),
+            Err(e) => {
+                tracing::warn!(error = %e, "メッセージに署名できませんでした");
+                None
+            }
+        })
+    };
+    // 先に履歴へ記録して通し番号を確定させる
+    let event = EventKind::Message { direction: Direction::Outgoing, body: body.clone() };
+    let (seq, sig) = match history.append_signed(peer, event, sign) {
+        Ok(entry) => (entry.seq, entry.signature.map(|signature| signature.sig)),
+        Err(e) => {
+            tracing::error!(error = %e, "履歴の保存に失敗しました");
+            (0, None)
+        }
+    };
+    let span = tracing::info_span!("message", direction = "out", seq);
+    if logging::log_messages() {
+        span.in_scope(|| tracing::info!(len = body.len(), "メッセージを送信します"));
+    }
+    let envelope = Envelope::Chat { seq, body, sig };
+    let sent = ws_sender
+        .send(tokio_tungstenite::tungstenite::Message::Text(envelope.encode()))
+        .instrument(span)
+        .await;
+    if sent.is_err() && seq > 0 {
+        if let Err(e) = history.retain(|entry| entry.seq != seq) {
+            tracing::error!(error = %e, "送信できなかったメッセージを履歴から取り消せませんでした");
+        }
+    }
+    sent.map(|()| seq)
+}
+
+// /stats で表示する行
+fn stats_lines(stats: &stats::Stats) -> Vec<String> {
+    let uptime = stats.uptime_secs;
+    let buffers = p2pchat_core::pool::CHUNKS.stats();
+    vec![
+        Msg::StatsUptime.with(&[&(uptime / 3600), &(uptime / 60 % 60), &(uptime % 60)]),
+        Msg::StatsSent.with(&[&stats::bytes(stats.bytes_sent as f64), &stats.frames_sent, &stats.messages_sent]),
+        Msg::StatsReceived.with(&[&stats::bytes(stats.bytes_received as f64), &stats.frames_received, &stats.messages_received]),
+        Msg::StatsThroughput.with(&[&stats::bytes(stats.throughput_sent), &stats::bytes(stats.throughput_received)]),
+        Msg::StatsReconnects.with(&[&stats.reconnects]),
+        // WebSocketの圧縮拡張 (permessage-deflate) は使っていないため、常に圧縮なし
+        Msg::StatsCompression.to_string(),
+        // ファイル転送のチャンクのバッファはプロセス全体で使い回している
+        Msg::StatsBuffers.with(&[&buffers.in_use, &buffers.idle, &buffers.allocated, &buffers.reused]),
+    ]
+}
+
+// 受信し終えたファイルを履歴に記録する。保存できたかを返す (相手へのFileReceivedに使う)
+fn save_download(
+    events: &Events,
+    webhooks: &Webhooks,
+    history: &mut History,
+    peer: &str,
+    finished: Result<Option<transfer::CompletedFile>, Box<dyn std::error::Error>>,
+) -> bool {
+    match finished {
+        Ok(None) => false,
+        Ok(Some(file)) => {
+            events.info(Msg::SavedFile.with(&[&file.path.display()]));
+            let fields = json!({ "direction": "incoming", "name": file.name, "size": file.size, "path": file.path });
+            webhooks.emit(WebhookEvent::TransferCompleted, peer, fields);
+            record(history, peer, EventKind::FileTransfer { direction: Direction::Incoming, file_name: file.name, size: file.size });
+            true
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "ファイルの受信に失敗しました");
+            false
+        }
+    }
+}
+
+// 相手のメッセージを受け取ったことを知らせる (相手側の配送状況の表示に使う)
+async fn send_delivered<W>(ws_sender: &mut W, seq: u64) -> Result<(), tokio_tungstenite::tungstenite::Error>
+where
+    W: futures_util::Sink<tokio_tungstenite::tungstenite::Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    ws_sender
+        .send(tokio_tungstenite::tungstenite::Message::Text(Envelope::Delivered { seq }.encode()))
+        .await
+}
+
+// 送信できなかったメッセージを、次にその相手と接続したときに送れるよう保存する
+fn save_draft(events: &Events, paths: &Paths, peer: &str, body: String) {
+    let result = Drafts::load(paths.drafts_file()).and_then(|mut drafts| {
+        drafts.push(peer, body);
+        drafts.save()
+    });
+    match result {
+        Ok(()) => events.info(Msg::DraftSaved.text()),
+        Err(e) => tracing::error!(error = %e, "下書きの保存に失敗しました"),
+    }
+}
+
+// 相手宛ての下書きがあれば知らせる
+fn announce_drafts(events: &Events, paths: &Paths, peer: &str) {
+    match Drafts::load(paths.drafts_file()) {
+        Ok(drafts) if !drafts.get(peer).is_empty() => events.info(Msg::DraftsPending.with(&[&drafts.get(peer).len()])),
+        Ok(_) => {}
+        Err(e) => tracing::warn!(error = %e, "下書きの読み込みに失敗しました"),
+    }
+}
+
+// 相手の証明書で受信したメッセージの署名を検証する。正しい署名のみ履歴に残す
+fn check_signature(events: &Events, peer_cert: Option<&Vec<u8>>, seq: u64, body: &str, sig: Option<String>) -> Option<MessageSignature> {
+    let sig = sig?;
+    let Some(cert) = peer_cert else {
+        tracing::warn!(seq, "相手の証明書を受け取っていないため署名を検証できません");
+        return None;
+    };
+    if identity::verify(cert, &identity::signed_content(seq, body), &sig) {
+        Some(MessageSignature { signer: identity::fingerprint(cert), sig })
+    } else {
+        tracing::warn!(security = true, seq, "メッセージの署名が正しくありません");
+        events.warn(Msg::BadSignature.with(&[&seq]));
+        None
+    }
+}
+
+// 相手の証明書がknown_peersに記録されていれば検証済みとする (ステータスバーに表示する)
+fn verification(paths: &Paths, cert_der: &[u8]) -> ui::Verification {
+    match KnownPeers::load(paths.known_peers_file()) {
+        Ok(known) if known.contains_fingerprint(&identity::fingerprint(cert_der)) => ui::Verification::Trusted,
+        Ok(_) => ui::Verification::Untrusted,
+        Err(e) => {
+            tracing::warn!(error = %e, "known_peersを読み込めませんでした");
+            ui::Verification::Untrusted
+        }
+    }
+}
+
+// 署名付きトランスクリプトのエクスポートで使えるよう証明書を保存しておく
+fn remember_cert(paths: &Paths, cert_der: &[u8]) {
+    let result = identity::CertStore::load(paths.certs_file()).and_then(|mut certs| {
+        if certs.get(&identity::fingerprint(cert_der)).is_none() {
+            certs.insert(cert_der);
+            certs.save()?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        tracing::warn!(error = %e, "証明書の保存に失敗しました");
+    }
+}
+
+// 履歴への書き込みに失敗してもチャット自体は継続する
+pub(crate) fn record(history: &mut History, peer: &str, event: EventKind) {
+    if let Err(e) = history.append(peer, event) {
+        tracing::error!(error = %e, "履歴の保存に失敗しました");
+    }
+}
+
+fn record_remote(
+    history: &mut History,
+    peer: &str,
+    remote_seq: u64,
+    timestamp: chrono::DateTime<chrono::Local>,
+    body: String,
+    signature: Option<MessageSignature>,
+) {
+    let event = EventKind::Message { direction: Direction::Incoming, body };
+    if let Err(e) = history.append_remote(peer, remote_seq, timestamp, event, signature) {
+        tracing::error!(error = %e, "履歴の保存に失敗しました");
+    }
+}
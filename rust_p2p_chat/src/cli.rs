@@ -0,0 +1,658 @@
+use clap::{CommandFactory, Parser, Subcommand};
+use rust_p2p_chat::config::RetentionPolicy;
+use rust_p2p_chat::contacts::Contact;
+use rust_p2p_chat::history::{self, ExportFormat};
+use rust_p2p_chat::proxy::Proxy;
+use rust_p2p_chat::{bridge, exit, i18n, logging, paths, ui};
+use std::net::SocketAddr;
+
+// コマンドライン引数の定義
+// 各オプションは P2PCHAT_* 環境変数でも指定できる (優先順位: コマンドライン > 環境変数 > config.toml)
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Commands,
+
+    #[arg(long, global = true, env = "P2PCHAT_DATA_DIR", help = "鍵・履歴・アドレス帳などの保存先 (省略時はOSごとの標準の場所)")]
+    pub data_dir: Option<std::path::PathBuf>,
+    #[arg(long, global = true, env = "P2PCHAT_PROFILE", default_value = paths::DEFAULT_PROFILE, help = "使用するプロファイル (鍵・信頼済みの相手・アドレス帳・履歴を分ける)")]
+    pub profile: String,
+    #[arg(long, global = true, env = "P2PCHAT_DOWNLOAD_DIR", help = "受信したファイルの保存先 (省略時はデータディレクトリのdownloads)")]
+    pub download_dir: Option<std::path::PathBuf>,
+    // config.toml の per_peer = true を打ち消せるよう、指定がなければconfig.tomlに従う
+    #[arg(long, global = true, env = "P2PCHAT_DOWNLOAD_PER_PEER", value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = clap::builder::BoolishValueParser::new(), help = "受信したファイルを相手ごとのサブフォルダに保存する (--download-per-peer=false でconfig.tomlの設定を打ち消す)")]
+    pub download_per_peer: Option<bool>,
+    #[arg(long, global = true, env = "P2PCHAT_LOG_FILE", help = "接続の記録やエラーをこのファイルに書き出す")]
+    pub log_file: Option<std::path::PathBuf>,
+    #[arg(long, global = true, env = "P2PCHAT_LOG_MAX_SIZE", default_value_t = 10, help = "ログファイルをローテーションするサイズ (MB)")]
+    pub log_max_size: u64,
+    #[arg(long, global = true, env = "P2PCHAT_LOG_ROTATE_DAILY", help = "日付が変わったときにもログファイルをローテーションする")]
+    pub log_rotate_daily: bool,
+    #[arg(long, global = true, env = "P2PCHAT_LOG_KEEP", default_value_t = 5, help = "残しておく古いログファイルの数")]
+    pub log_keep: usize,
+    #[arg(long, global = true, env = "P2PCHAT_LOG_MESSAGES", help = "メッセージのメタデータ (方向・長さ・通し番号) もログに記録する。本文は記録しない")]
+    pub log_messages: bool,
+    #[arg(long, global = true, env = "P2PCHAT_LOG_FORMAT", value_enum, default_value = "text", help = "診断ログの出力形式")]
+    pub log_format: logging::LogFormat,
+    #[arg(long, global = true, env = "P2PCHAT_SYSLOG", value_name = "TARGET", help = "診断ログをsyslogにも送る (local: /dev/log、journald、udp://ホスト:ポート)。アクセスの拒否などセキュリティの出来事は authpriv で送る")]
+    pub syslog: Option<logging::SyslogTarget>,
+    #[arg(long, global = true, env = "P2PCHAT_SYSLOG_FACILITY", value_enum, default_value = "daemon", help = "--syslog で送るログのfacility (セキュリティの出来事は常に authpriv)")]
+    pub syslog_facility: logging::Facility,
+    #[cfg(feature = "otel")]
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT", help = "接続の確立の各段階とメッセージの往復をトレースとして送るOTLP (gRPC) の送り先 (例: http://localhost:4317)")]
+    pub otlp_endpoint: Option<String>,
+    #[arg(long, global = true, env = "P2PCHAT_UI", value_enum, default_value = "auto", help = "チャット画面の表示方法 (autoは端末ならtui、パイプならplain)")]
+    pub ui: ui::UiMode,
+    #[cfg(feature = "libp2p")]
+    #[arg(long, global = true, env = "P2PCHAT_BACKEND", value_enum, default_value = "wss", help = "listen・connectの接続の方式 (libp2pでは connect にmultiaddrかPeerIDを渡す。設定は config.toml の [libp2p])")]
+    pub backend: rust_p2p_chat::backend::Backend,
+    #[arg(long, global = true, env = "P2PCHAT_NO_ALERTS", help = "通知音 (メッセージの受信・相手の接続と切断) をすべて止める")]
+    pub no_alerts: bool,
+    #[arg(long, global = true, env = "P2PCHAT_NO_COLOR", help = "色を付けずに表示する (環境変数 NO_COLOR が設定されている場合も同様)")]
+    pub no_color: bool,
+    #[arg(long, global = true, env = "P2PCHAT_FORMAT", value_enum, default_value = "text", help = "標準入出力の形式 (jsonlは出来事を1行1つのJSONで書き出し、標準入力からJSONのコマンドを受け付ける)")]
+    pub format: ui::OutputFormat,
+    #[arg(long, global = true, env = "P2PCHAT_ERROR_FORMAT", value_enum, default_value = "text", help = "終了するときのエラーの書き方 (jsonは種類と終了コードを含む1行のJSONを標準エラー出力に書く)")]
+    pub error_format: exit::ErrorFormat,
+    #[arg(short, long, global = true, env = "P2PCHAT_QUIET", conflicts_with = "verbose", help = "起動時の案内・IPアドレスの取得・ポート開放の説明を表示せず、診断ログもエラーのみにする")]
+    pub quiet: bool,
+    #[arg(short, long, global = true, env = "P2PCHAT_VERBOSE", action = clap::ArgAction::Count, help = "診断ログを詳しくする (-v: 送受信したフレームを含むdebugログ、-vv: TLS・WebSocketのライブラリのログも出す)")]
+    pub verbose: u8,
+    #[arg(long, global = true, env = "P2PCHAT_LANG", value_enum, help = "表示する言語 (省略時は LC_ALL・LC_MESSAGES・LANG から決める)")]
+    pub lang: Option<i18n::Lang>,
+    #[cfg(feature = "plugins")]
+    #[arg(long, global = true, env = "P2PCHAT_NO_PLUGINS", help = "設定ディレクトリのpluginsにあるWebAssemblyのプラグインを読み込まない")]
+    pub no_plugins: bool,
+    #[cfg(feature = "scripting")]
+    #[arg(long, global = true, env = "P2PCHAT_NO_SCRIPTS", help = "設定ディレクトリのscriptsにあるスクリプトを読み込まない (/script load では読み込める)")]
+    pub no_scripts: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// サーバーとして起動し、接続を待ち受けます
+    Listen {
+        #[arg(short, long, env = "P2PCHAT_ADDR", help = "待ち受けるアドレス (省略時は config.toml の [listen] addr、なければ 127.0.0.1:8080)")]
+        addr: Option<SocketAddr>,
+        #[arg(long, env = "P2PCHAT_NO_QR", help = "接続用のURLをQRコードで表示しない (標準出力が端末の場合のみ表示する)")]
+        no_qr: bool,
+        #[arg(long, env = "P2PCHAT_CODE", help = "ランデブーサーバーに登録し、相手に伝える短い接続コード (例: tidy-walrus-42) を表示する")]
+        code: bool,
+        #[arg(long, env = "P2PCHAT_RENDEZVOUS", help = "接続コードに使うランデブーサーバー (省略時は config.toml の [rendezvous] url)")]
+        rendezvous: Option<String>,
+        #[arg(long, env = "P2PCHAT_DNS_NAME", help = "このドメインのTXTレコード (_p2pchat.<ドメイン>) に登録する内容を表示し、公開済みのものが今の待受と一致するかを確かめる (相手は `connect <ドメイン>` で接続できる)")]
+        dns_name: Option<String>,
+        #[cfg(feature = "mdns")]
+        #[arg(long, env = "P2PCHAT_NO_MDNS", help = "接続を待っている間、mDNSで同じLANの相手の discover に表示されるようにしない")]
+        no_mdns: bool,
+        #[arg(long, env = "P2PCHAT_WEB", help = "同じポートでブラウザ用のページも配り、バイナリのない相手がブラウザから参加できるようにする (平文の http:// ・ ws:// も受け付ける)")]
+        web: bool,
+        #[cfg(feature = "webtransport")]
+        #[arg(long, env = "P2PCHAT_WEBTRANSPORT", help = "同じポート番号のUDPでWebTransport (HTTP/3) の接続も受け付ける (相手は connect https://<ホスト>:<ポート>/ で接続できる)")]
+        webtransport: bool,
+        #[arg(long, env = "P2PCHAT_HEALTH", value_parser = bridge::parse_listen, help = "状態の確認 (/healthz・/readyz) を待ち受けるポートまたはアドレス (ポート番号だけの場合は同じマシンからのみ、例: 8081)")]
+        health: Option<SocketAddr>,
+    },
+    /// 指定したサーバーにクライアントとして接続します
+    #[command(group(clap::ArgGroup::new("target").required(true).args(["uri", "code"])))]
+    Connect {
+        #[arg(env = "P2PCHAT_CONNECT", help = "接続先のサーバーアドレス (例: wss://127.0.0.1:8080、WebTransportなら https://127.0.0.1:8080)、連絡先の名前、またはTXTレコードを公開しているドメイン名")]
+        uri: Option<String>,
+        #[arg(long, env = "P2PCHAT_CONNECT_CODE", help = "相手の `listen --code` で表示された接続コード (例: tidy-walrus-42)")]
+        code: Option<String>,
+        #[arg(long, env = "P2PCHAT_RENDEZVOUS", help = "接続コードに使うランデブーサーバー (省略時は config.toml の [rendezvous] url)")]
+        rendezvous: Option<String>,
+        #[arg(long, env = "P2PCHAT_STRICT", help = "known_peersに登録されていない相手や証明書が変わった相手への接続を拒否する")]
+        strict: bool,
+        #[arg(long, env = "P2PCHAT_PROXY", help = "経由するプロキシ (例: socks5://127.0.0.1:9050、http://proxy:3128、ssh://user@host)")]
+        proxy: Option<Proxy>,
+        #[arg(long, env = "P2PCHAT_VIA_SSH", value_name = "USER@HOST", conflicts_with = "proxy", help = "SSHでログインできるマシンを経由して接続する (ssh -W。接続先のアドレスはそのマシンから見たもの、例: wss://localhost:8080)")]
+        via_ssh: Option<String>,
+        #[arg(long, env = "P2PCHAT_RETRIES", default_value_t = 0, help = "TCP接続に失敗したときに再試行する回数 (待ち時間は1秒から倍に延ばす)")]
+        retries: u32,
+    },
+    /// 接続してメッセージまたはファイルを1回だけ送り、相手に届いたことを確認して終了します
+    ///
+    /// 終了コード: 0 届いた、1 その他のエラー、2 接続・認証に失敗、3 受信確認が届かない、4 相手がファイルを受け取らなかった
+    #[command(group(clap::ArgGroup::new("content").required(true).args(["message", "file", "stream"])))]
+    Send {
+        #[arg(long, help = "送信先のサーバーアドレス (例: wss://127.0.0.1:8080)、連絡先の名前、またはTXTレコードを公開しているドメイン名")]
+        to: String,
+        #[arg(long, help = "送信するメッセージ")]
+        message: Option<String>,
+        #[arg(long, help = "送信するファイル")]
+        file: Option<std::path::PathBuf>,
+        #[arg(long, help = "標準入力を読み終わるまで、届いた分から送り続ける (1行ごとにメッセージとして送る)")]
+        stream: bool,
+        #[arg(long, requires = "stream", help = "--stream で行に区切らず、バイナリのまま1つのファイルとして送る")]
+        raw: bool,
+        #[arg(long, requires = "raw", default_value = "stdin", help = "--raw で送るファイルの名前")]
+        name: String,
+        #[arg(long, env = "P2PCHAT_STRICT", help = "known_peersに登録されていない相手や証明書が変わった相手への接続を拒否する")]
+        strict: bool,
+        #[arg(long, env = "P2PCHAT_PROXY", help = "経由するプロキシ (例: socks5://127.0.0.1:9050、http://proxy:3128、ssh://user@host)")]
+        proxy: Option<Proxy>,
+        #[arg(long, env = "P2PCHAT_RETRIES", default_value_t = 0, help = "TCP接続に失敗したときに再試行する回数 (待ち時間は1秒から倍に延ばす)")]
+        retries: u32,
+        #[arg(long, env = "P2PCHAT_SEND_TIMEOUT", default_value_t = 30, help = "相手からの受信確認を待つ秒数")]
+        timeout: u64,
+    },
+    /// (スクリプト向け) 接続先の指定が、どのURI・連絡先・フィンガープリントになるかを1行のJSONで出力します。接続はしません
+    Resolve {
+        #[arg(help = "接続先のサーバーアドレス、連絡先の名前、またはTXTレコードを公開しているドメイン名")]
+        target: String,
+    },
+    /// (スクリプト向け) TLSのハンドシェイクだけを行い、相手の証明書のフィンガープリントと照合の結果を1行のJSONで出力します
+    Fingerprint {
+        #[arg(help = "接続先のサーバーアドレスまたは連絡先の名前")]
+        target: String,
+        #[arg(long, env = "P2PCHAT_PROXY", help = "経由するプロキシ (例: socks5://127.0.0.1:9050)")]
+        proxy: Option<Proxy>,
+    },
+    /// (スクリプト向け) 自分の証明書のフィンガープリントと待受のURIから招待の文字列を作り、1行のJSONで出力します
+    EncodeInvite {
+        #[arg(help = "相手が接続するURI (例: wss://203.0.113.5:8080)")]
+        uri: String,
+        #[arg(long, help = "招待に含める自分の表示名")]
+        name: Option<String>,
+    },
+    /// (スクリプト向け) 招待の文字列を解釈し、接続先とフィンガープリントを1行のJSONで出力します
+    DecodeInvite {
+        #[arg(help = "encode-invite で作った文字列 (p2pchat-invite1:...)")]
+        invite: String,
+    },
+    /// (スクリプト向け) ハンドシェイクだけを行い、TLS・証明書・相手が対応していることを1行のJSONで出力します。メッセージは送りません
+    Probe {
+        #[arg(help = "接続先のサーバーアドレスまたは連絡先の名前")]
+        target: String,
+        #[arg(long, env = "P2PCHAT_PROXY", help = "経由するプロキシ (例: socks5://127.0.0.1:9050)")]
+        proxy: Option<Proxy>,
+    },
+    /// 他の実装がこのプロトコルでやり取りできるかを確かめます (ハンドシェイク・メッセージの形式・受信確認など)
+    ///
+    /// 相手の待受に接続するか、--listen で待ち受けて接続してきた実装 (ブラウザのページを含む) を確かめます。
+    /// 終了コード: 0 すべて合格 (対応していない省略できる機能を含む)、1 不合格がある
+    #[command(group(clap::ArgGroup::new("subject").required(true).args(["target", "listen", "vectors"])))]
+    Conformance {
+        #[arg(help = "確かめる相手のサーバーアドレス (例: wss://127.0.0.1:8080) または連絡先の名前")]
+        target: Option<String>,
+        #[arg(long, help = "このアドレスで待ち受け、最初に接続してきた実装を確かめる (ブラウザからの ws:// も受け付ける)")]
+        listen: Option<SocketAddr>,
+        #[arg(long, help = "他の実装のテストに使うメッセージの例 (テストベクター) を1行に1つのJSONで出力する")]
+        vectors: bool,
+        #[arg(long, env = "P2PCHAT_PROXY", help = "経由するプロキシ (例: socks5://127.0.0.1:9050)")]
+        proxy: Option<Proxy>,
+    },
+    /// 同じLANでmDNSを使って知らせている待受 (listen) を探し、接続先のURIを表示します
+    #[cfg(feature = "mdns")]
+    Discover {
+        #[arg(long, default_value_t = 3, help = "探す秒数")]
+        timeout: u64,
+    },
+    /// バックグラウンドで接続を保ち、制御用ソケットから操作できるようにします (ctl で操作します)
+    ///
+    /// 端末を閉じても (SIGHUP) 終了しません。SIGTERMまたはCtrl+Cで終了します
+    Daemon {
+        #[arg(short, long, env = "P2PCHAT_ADDR", help = "接続を待ち受けるアドレス (省略時は待ち受けず、ctl connect で接続した相手とのみやり取りする)")]
+        addr: Option<SocketAddr>,
+        #[arg(long, env = "P2PCHAT_SOCKET", help = "制御用ソケットのパス (省略時は保存先ディレクトリの control.sock)")]
+        socket: Option<std::path::PathBuf>,
+        #[arg(long, env = "P2PCHAT_API", value_parser = bridge::parse_listen, help = "REST APIを待ち受けるポートまたはアドレス (ポート番号だけの場合は同じマシンからのみ、例: 8787)")]
+        api: Option<SocketAddr>,
+        #[cfg(feature = "grpc")]
+        #[arg(long, env = "P2PCHAT_GRPC", value_parser = bridge::parse_listen, help = "gRPC (proto/p2pchat.proto) を待ち受けるポートまたはアドレス (ポート番号だけの場合は同じマシンからのみ、例: 50051)")]
+        grpc: Option<SocketAddr>,
+        #[arg(long, env = "P2PCHAT_API_TOKEN", hide_env_values = true, help = "REST API・gRPCのトークン (省略時は保存先ディレクトリの api_token、なければ作る)")]
+        api_token: Option<String>,
+        #[arg(long, env = "P2PCHAT_FEED", value_parser = bridge::parse_listen, requires = "feed_room", help = "--feed-room の部屋のAtomフィード (/feed.atom) を公開するポートまたはアドレス (トークンは求めません)")]
+        feed: Option<SocketAddr>,
+        #[arg(long, env = "P2PCHAT_FEED_ROOM", requires = "feed", help = "フィードにする部屋の名前 (ctl rooms の name)")]
+        feed_room: Option<String>,
+        #[arg(long, env = "P2PCHAT_HEALTH", value_parser = bridge::parse_listen, help = "状態の確認 (/healthz・/readyz) を待ち受けるポートまたはアドレス (ポート番号だけの場合は同じマシンからのみ、例: 8081)")]
+        health: Option<SocketAddr>,
+    },
+    /// 実行中のデーモンを操作します
+    Ctl {
+        #[arg(long, env = "P2PCHAT_SOCKET", help = "デーモンの制御用ソケットのパス (省略時は保存先ディレクトリの control.sock)")]
+        socket: Option<std::path::PathBuf>,
+        #[command(subcommand)]
+        action: CtlCommands,
+    },
+    /// 実行中のデーモンの部屋を、他のチャットのクライアントから使えるようにします
+    Bridge {
+        #[command(subcommand)]
+        action: BridgeCommands,
+    },
+    /// 接続コードを登録・解決するランデブーサーバーとして起動します
+    ///
+    /// 平文のWebSocketで待ち受けます。インターネットに公開する場合はTLSを終端するリバースプロキシの後ろに置いてください
+    Rendezvous {
+        #[arg(short, long, env = "P2PCHAT_RENDEZVOUS_ADDR", default_value = "0.0.0.0:8090", help = "待ち受けるアドレス")]
+        addr: SocketAddr,
+        #[arg(long, env = "P2PCHAT_CODE_TTL", default_value_t = 600, help = "接続コードの有効期限 (秒)")]
+        ttl: u64,
+    },
+    /// アドレス帳を操作します
+    Contacts {
+        #[command(subcommand)]
+        action: ContactsCommands,
+    },
+    /// 信頼済みの相手 (known_peers) を管理します
+    Trust {
+        #[command(subcommand)]
+        action: TrustCommands,
+    },
+    /// プロファイルを管理します
+    Profile {
+        #[command(subcommand)]
+        action: ProfileCommands,
+    },
+    /// チャット履歴を操作します
+    History {
+        #[command(subcommand)]
+        action: HistoryCommands,
+    },
+    /// 鍵・信頼済みの相手・アドレス帳・設定をバックアップ・復元します
+    Backup {
+        #[command(subcommand)]
+        action: BackupCommands,
+    },
+    /// 証明書の秘密鍵と履歴のパスフレーズをOSのキーチェーンに預け、平文のファイルを置かずに起動できるようにします
+    Keystore {
+        #[command(subcommand)]
+        action: KeystoreCommands,
+    },
+    /// 不具合の調査に使う情報を出力します
+    Debug {
+        #[command(subcommand)]
+        action: DebugCommands,
+    },
+    /// 届けられなかったメッセージをメールで送ります (config.toml の [mail] と連絡先の --email)
+    Mail {
+        #[command(subcommand)]
+        action: MailCommands,
+    },
+    /// Nostrのリレーを経由して、暗号化したダイレクトメッセージを送受信します (config.toml の [nostr] と連絡先の --nostr)
+    #[cfg(feature = "nostr")]
+    Nostr {
+        #[command(subcommand)]
+        action: NostrCommands,
+    },
+    /// 利用状況の記録 (初期状態では無効) を確認・変更します
+    Telemetry {
+        #[command(subcommand)]
+        action: TelemetryCommands,
+    },
+    /// シェルの補完スクリプトを標準出力に書き出します
+    ///
+    /// 例: rust_p2p_chat completions bash > /etc/bash_completion.d/rust_p2p_chat
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// manページ (roff形式) を標準出力に書き出します
+    Manpage {
+        #[arg(long, help = "標準出力の代わりに、サブコマンドごとのmanページをこのディレクトリに書き出す")]
+        dir: Option<std::path::PathBuf>,
+    },
+}
+
+impl Commands {
+    // 結果を1行のJSONで出力するスクリプト向けのサブコマンド
+    pub fn is_plumbing(&self) -> bool {
+        matches!(
+            self,
+            Commands::Resolve { .. }
+                | Commands::Fingerprint { .. }
+                | Commands::EncodeInvite { .. }
+                | Commands::DecodeInvite { .. }
+                | Commands::Probe { .. }
+                | Commands::Conformance { vectors: true, .. }
+        )
+    }
+}
+
+#[derive(Subcommand)]
+pub enum ContactsCommands {
+    /// 連絡先を追加します
+    Add {
+        name: String,
+        #[arg(help = "接続先のサーバーアドレス (例: wss://127.0.0.1:8080)")]
+        uri: String,
+        #[arg(long, help = "相手の証明書のフィンガープリント (sha256:...)")]
+        fingerprint: Option<String>,
+        #[arg(long, default_value = "wss")]
+        transport: String,
+        #[arg(long)]
+        notes: Option<String>,
+        #[command(flatten)]
+        defaults: ContactDefaults,
+    },
+    /// 登録済みの連絡先を一覧表示します
+    List,
+    /// 連絡先に保存する既定のオプションを変更します
+    Set {
+        name: String,
+        #[command(flatten)]
+        defaults: ContactDefaults,
+    },
+    /// 連絡先を削除します
+    Remove { name: String },
+}
+
+// 連絡先ごとのオプション。`connect <名前>` のとき、またはその相手から接続されたときに適用される
+// 指定しなかった項目は変更しない
+#[derive(clap::Args)]
+pub struct ContactDefaults {
+    #[arg(long, help = "チャット画面で相手を表示するときの名前")]
+    pub nickname: Option<String>,
+    #[arg(long, help = "known_peersに登録されていない相手や証明書が変わった相手への接続を拒否する (true/false)")]
+    pub strict: Option<bool>,
+    #[arg(long, help = "相手から送られたファイルを受け取る (true/false)")]
+    pub accept_files: Option<bool>,
+    #[arg(long, help = "自動で受け取るファイルの最大サイズ (MB)。0で制限なし")]
+    pub max_file_mb: Option<u64>,
+    #[arg(long, help = "メッセージを受信したときに端末のベルで知らせる (true/false)")]
+    pub notify: Option<bool>,
+    // グローバルの --download-dir と同じidにすると、そちらの値 (環境変数も) がこの相手の保存先として保存されてしまう
+    #[arg(long = "files-dir", id = "files_dir", help = "この相手から受信したファイルの保存先 (グローバルの--download-dirが優先)")]
+    pub files_dir: Option<std::path::PathBuf>,
+    #[arg(long, help = "届けられなかったメッセージをメールで送る先 (mail gateway)。空文字列で削除")]
+    pub email: Option<String>,
+    #[arg(long, help = "メールで送る本文を暗号化する、相手と共有したパスフレーズ。空文字列で削除 (知らせのみ送る)")]
+    pub mail_passphrase: Option<String>,
+    #[arg(long, help = "相手のNostrの公開鍵 (npub1... または16進数)。nostr send・nostr gateway で使う。空文字列で削除")]
+    pub nostr: Option<String>,
+}
+
+impl ContactDefaults {
+    pub fn apply(&self, contact: &mut Contact) {
+        if let Some(nickname) = &self.nickname {
+            contact.nickname = Some(nickname.clone());
+        }
+        if let Some(strict) = self.strict {
+            contact.strict = strict;
+        }
+        if let Some(accept_files) = self.accept_files {
+            contact.accept_files = accept_files;
+        }
+        if let Some(max_file_mb) = self.max_file_mb {
+            contact.max_file_mb = (max_file_mb > 0).then_some(max_file_mb);
+        }
+        if let Some(notify) = self.notify {
+            contact.notify = notify;
+        }
+        if let Some(dir) = &self.files_dir {
+            contact.download_dir = Some(dir.clone());
+        }
+        if let Some(email) = &self.email {
+            contact.email = (!email.is_empty()).then(|| email.clone());
+        }
+        if let Some(passphrase) = &self.mail_passphrase {
+            contact.mail_passphrase = (!passphrase.is_empty()).then(|| passphrase.clone());
+        }
+        if let Some(nostr) = &self.nostr {
+            contact.nostr = (!nostr.is_empty()).then(|| nostr.clone());
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum TrustCommands {
+    /// 相手のフィンガープリントを信頼済みとして登録します
+    Add {
+        #[arg(help = "相手のアドレス (例: 127.0.0.1:8080 または wss://127.0.0.1:8080)")]
+        host: String,
+        #[arg(help = "相手の証明書のフィンガープリント (sha256:...)")]
+        fingerprint: String,
+        #[arg(long, help = "対応付けるアドレス帳の名前")]
+        contact: Option<String>,
+    },
+    /// 信頼済みの相手を一覧表示します
+    List,
+    /// 信頼済みの相手を削除します
+    Remove { host: String },
+}
+
+#[derive(Subcommand)]
+pub enum MailCommands {
+    /// 送れなかったメッセージ (下書き) を1分ごとに確かめ、[mail] after_mins より古いものを連絡先のメールアドレスに送ります
+    Gateway {
+        #[arg(long, help = "1回だけ確かめて終了する (cronなどから実行する場合)")]
+        once: bool,
+    },
+    /// メールで届いた暗号化したメッセージを、共有しているパスフレーズで開いて表示します
+    Open {
+        #[arg(help = "メールの本文を保存したファイル (省略時は標準入力)")]
+        file: Option<std::path::PathBuf>,
+    },
+}
+
+#[cfg(feature = "nostr")]
+#[derive(Subcommand)]
+pub enum NostrCommands {
+    /// 自分のNostrの公開鍵 (証明書の鍵から導いたもの) を表示します。相手の連絡先の --nostr に登録してもらいます
+    Key,
+    /// 連絡先の相手 (または公開鍵) に、暗号化したダイレクトメッセージを送ります
+    Send {
+        #[arg(help = "送り先の連絡先の名前、npub1... または16進数の公開鍵")]
+        to: String,
+        #[arg(help = "送るメッセージ")]
+        message: String,
+    },
+    /// 前回以降に届いたダイレクトメッセージを履歴に記録して表示します
+    Inbox {
+        #[arg(long, help = "終了せず、1分ごとに確かめ続ける")]
+        follow: bool,
+    },
+    /// 送れなかったメッセージ (下書き) を1分ごとに確かめ、[nostr] after_mins より古いものを連絡先のNostrの公開鍵に送ります
+    Gateway {
+        #[arg(long, help = "1回だけ確かめて終了する (cronなどから実行する場合)")]
+        once: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TelemetryCommands {
+    /// 記録が有効かどうかと、これまでの記録を表示します
+    Status,
+    /// 使った機能とエラーの種類の回数の記録を始めます (送るのは submit を実行したときだけです)
+    Enable,
+    /// 記録をやめ、これまでの記録を削除します
+    Disable,
+    /// submit で送る内容 (JSON) をそのまま表示します
+    Report,
+    /// 記録を送り、回数を0に戻します
+    Submit {
+        #[arg(long, env = "P2PCHAT_TELEMETRY_URL", help = "送り先のURL (JSONをPOSTする)")]
+        url: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ProfileCommands {
+    /// プロファイルを一覧表示します
+    List,
+    /// 新しいプロファイルを作成し、専用の鍵を生成します
+    Create { name: String },
+    /// プロファイルと、その鍵・履歴などをすべて削除します
+    Delete {
+        name: String,
+        #[arg(long, help = "確認なしで削除する")]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CtlCommands {
+    /// デーモンの状態を表示します
+    Status,
+    /// 接続中の部屋 (相手) を一覧表示します
+    Rooms,
+    /// 部屋の相手にメッセージを送ります
+    Send {
+        #[arg(long, help = "送り先の部屋の名前 (rooms で表示される名前)")]
+        room: String,
+        message: String,
+    },
+    /// 相手に接続して部屋を開きます
+    Connect {
+        #[arg(help = "接続先のサーバーアドレス (例: wss://127.0.0.1:8080)、連絡先の名前、またはTXTレコードを公開しているドメイン名")]
+        uri: String,
+    },
+    /// 部屋の接続を閉じます
+    Close { room: String },
+    /// すべての部屋の出来事を1行に1つのJSONで表示し続けます
+    Events,
+}
+
+#[derive(Subcommand)]
+pub enum BridgeCommands {
+    /// IRCサーバーとして待ち受け、IRCクライアントにデーモンの部屋をチャンネル、相手をニックネームとして見せます
+    ///
+    /// 例: rust_p2p_chat bridge irc --listen 6667 (IRCクライアントで 127.0.0.1 の6667番に接続し、/join #alice で連絡先に接続します)
+    Irc {
+        #[arg(long, env = "P2PCHAT_IRC_LISTEN", default_value = "6667", value_parser = bridge::parse_listen, help = "待ち受けるポート番号またはアドレス (ポート番号だけの場合は127.0.0.1で待ち受ける)")]
+        listen: SocketAddr,
+        #[arg(long, env = "P2PCHAT_SOCKET", help = "デーモンの制御用ソケットのパス (省略時は保存先ディレクトリの control.sock)")]
+        socket: Option<std::path::PathBuf>,
+        #[arg(long, env = "P2PCHAT_IRC_PASSWORD", hide_env_values = true, help = "IRCクライアントに求めるパスワード (PASS)")]
+        password: Option<String>,
+    },
+    /// MQTTブローカーに接続し、相手から受信したメッセージをトピックにPUBLISHし、購読したトピックのペイロードを相手に送ります
+    ///
+    /// 例: rust_p2p_chat bridge mqtt --broker localhost:1883 (mosquitto_pub -t p2pchat/alice/send -m "玄関のドアが開きました")
+    Mqtt {
+        #[arg(long, env = "P2PCHAT_MQTT_BROKER", default_value = "localhost:1883", help = "MQTTブローカーのアドレス")]
+        broker: String,
+        #[arg(long, env = "P2PCHAT_MQTT_PUBLISH", default_value = "p2pchat/{room}/messages", help = "受信したメッセージをPUBLISHするトピック ({room} は部屋の名前になる)")]
+        publish: String,
+        #[arg(long, env = "P2PCHAT_MQTT_SUBSCRIBE", default_value = "p2pchat/{room}/send", help = "購読するトピック。ペイロードを {room} の部屋 (または連絡先) に送る")]
+        subscribe: String,
+        #[arg(long, env = "P2PCHAT_MQTT_ROOM", help = "購読するトピックに {room} がない場合の送り先 (部屋の名前・連絡先の名前・URI)")]
+        room: Option<String>,
+        #[arg(long, help = "本文の代わりに出来事のJSON (ctl events と同じ形式) をPUBLISHする")]
+        json: bool,
+        #[arg(long, env = "P2PCHAT_MQTT_CLIENT_ID", default_value = "p2pchat", help = "MQTTのクライアントID")]
+        client_id: String,
+        #[arg(long, env = "P2PCHAT_MQTT_USERNAME", help = "MQTTブローカーのユーザー名")]
+        username: Option<String>,
+        #[arg(long, env = "P2PCHAT_MQTT_PASSWORD", hide_env_values = true, help = "MQTTブローカーのパスワード")]
+        password: Option<String>,
+        #[arg(long, env = "P2PCHAT_SOCKET", help = "デーモンの制御用ソケットのパス (省略時は保存先ディレクトリの control.sock)")]
+        socket: Option<std::path::PathBuf>,
+    },
+    /// 部屋のメッセージをDiscordまたはSlackのIncoming Webhookに転送し、--listen を指定した場合はOutgoing Webhookで受け取ったメッセージを部屋に送ります
+    ///
+    /// 例: rust_p2p_chat bridge relay --room alice --webhook-url https://discord.com/api/webhooks/...
+    #[cfg(feature = "discovery")]
+    Relay {
+        #[arg(long, env = "P2PCHAT_RELAY_ROOM", help = "転送する部屋の名前 (ctl rooms の name)")]
+        room: String,
+        #[arg(long, env = "P2PCHAT_RELAY_WEBHOOK_URL", hide_env_values = true, help = "転送先のIncoming WebhookのURL")]
+        webhook_url: String,
+        #[arg(long, value_enum, help = "転送先のサービス (省略時はURLから判断する)")]
+        service: Option<bridge::RelayService>,
+        #[arg(long, env = "P2PCHAT_RELAY_LISTEN", value_parser = bridge::parse_listen, help = "Outgoing Webhookを待ち受けるポート番号またはアドレス (ポート番号だけの場合は127.0.0.1で待ち受ける)")]
+        listen: Option<SocketAddr>,
+        #[arg(long, env = "P2PCHAT_RELAY_TOKEN", hide_env_values = true, help = "Outgoing Webhookに求めるトークン (Slackのtoken、または Authorization: Bearer)")]
+        token: Option<String>,
+        #[arg(long, env = "P2PCHAT_SOCKET", help = "デーモンの制御用ソケットのパス (省略時は保存先ディレクトリの control.sock)")]
+        socket: Option<std::path::PathBuf>,
+    },
+    /// XMPPサーバーにコンポーネントとして接続し、デーモンの部屋の相手をJID (<連絡先の名前>@<ドメイン>) として見せます
+    ///
+    /// 例: rust_p2p_chat bridge xmpp --server localhost:5347 --domain p2pchat.example.org --owner bob@example.org
+    #[cfg(feature = "xmpp")]
+    Xmpp {
+        #[arg(long, env = "P2PCHAT_XMPP_SERVER", default_value = "localhost:5347", help = "XMPPサーバーのコンポーネント用のアドレス")]
+        server: String,
+        #[arg(long, env = "P2PCHAT_XMPP_DOMAIN", help = "コンポーネントのドメイン (XMPPサーバーに登録したもの)")]
+        domain: String,
+        #[arg(long, env = "P2PCHAT_XMPP_SECRET", hide_env_values = true, help = "XMPPサーバーに設定したコンポーネントのパスワード")]
+        secret: String,
+        #[arg(long, env = "P2PCHAT_XMPP_OWNER", help = "メッセージをやり取りするXMPPのアカウント (例: bob@example.org)")]
+        owner: String,
+        #[arg(long, env = "P2PCHAT_SOCKET", help = "デーモンの制御用ソケットのパス (省略時は保存先ディレクトリの control.sock)")]
+        socket: Option<std::path::PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DebugCommands {
+    /// 実行中の接続の状態 (直近のフレームの種類・サイズ、TLSのパラメータなど) と保存データの概要をファイルに書き出します
+    /// メッセージの本文・鍵・トークンは含みません
+    Dump { file: std::path::PathBuf },
+}
+
+#[derive(Subcommand)]
+pub enum BackupCommands {
+    /// 現在のプロファイルの鍵・known_peers・アドレス帳・config.tomlを暗号化して書き出します
+    Create { file: std::path::PathBuf },
+    /// バックアップを現在のプロファイルに復元します
+    Restore {
+        file: std::path::PathBuf,
+        #[arg(long, help = "既存の鍵と異なる場合も上書きする")]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeystoreCommands {
+    /// 預けている秘密と、その保存先を表示します
+    Status,
+    /// 証明書の秘密鍵 (identity_key.der) をOSのキーチェーンに預け、ファイルを削除します
+    Store {
+        #[arg(long, help = "履歴のパスフレーズも預け、起動時に尋ねないようにする")]
+        history: bool,
+    },
+    /// 預けた秘密鍵を identity_key.der に書き戻し、キーチェーンから削除します
+    Remove,
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommands {
+    /// 保持期間を過ぎた履歴をすぐに削除します (保持期間は config.toml の [retention] で設定)
+    Purge {
+        #[arg(short, long, help = "この相手の履歴のみを対象にする")]
+        peer: Option<String>,
+        #[arg(long, help = "設定の代わりにこの保持期間を使う (forever / none / 30d)")]
+        keep: Option<RetentionPolicy>,
+    },
+    /// 平文の履歴をパスフレーズで暗号化し、以降の履歴も暗号化して保存します
+    Encrypt,
+    /// 履歴をJSONまたはMarkdown形式のファイルに書き出します
+    Export {
+        #[arg(short = 'f', id = "export_format", long = "export-format", value_enum, default_value = "json", help = "書き出す形式 (--format は標準入出力の形式)")]
+        format: ExportFormat,
+        #[arg(short, long, help = "この相手とのやり取りのみを書き出す")]
+        peer: Option<String>,
+        #[arg(short, long, value_parser = history::parse_since, help = "この日時以降の履歴のみを書き出す (例: 2024-01-31)")]
+        since: Option<chrono::DateTime<chrono::Local>>,
+        #[arg(short, long, help = "出力先ファイル (省略時は chat_export_<日時>.<拡張子>)")]
+        output: Option<std::path::PathBuf>,
+        #[arg(long, help = "各メッセージの署名と検証に使う証明書を含め、書き出す内容全体に自分の鍵で署名する (JSONのみ)")]
+        signed: bool,
+    },
+    /// `export --signed` で書き出したトランスクリプトの署名を検証します
+    Verify {
+        file: std::path::PathBuf,
+        #[arg(long = "signer", value_name = "FINGERPRINT", help = "信頼する署名者のフィンガープリント (複数指定可。省略時は known_peers・アドレス帳・自分の証明書)")]
+        signers: Vec<String>,
+    },
+}
+
+// manページを書き出す。dirを指定した場合はサブコマンドごとのページ (rust_p2p_chat-listen.1 など) も作る
+pub fn write_manpages(dir: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let command = Cli::command();
+    match dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            clap_mangen::generate_to(command, dir)?;
+            println!("manページを書き出しました: {}", dir.display());
+        }
+        None => clap_mangen::Man::new(command).render(&mut std::io::stdout())?,
+    }
+    Ok(())
+}
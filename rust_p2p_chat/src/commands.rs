@@ -0,0 +1,204 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+use unicode_width::UnicodeWidthStr;
+
+use crate::history::Direction;
+use crate::hook::HookCommand;
+use crate::i18n::Msg;
+
+// チャット中に使えるコマンドの説明 (/help の表示と、不明なコマンドの判定に使う)
+pub struct Spec {
+    pub name: &'static str,
+    /// 引数の説明を含む場合は言語ごとに変わる
+    pub usage: Usage,
+    pub description: Msg,
+}
+
+pub enum Usage {
+    Fixed(&'static str),
+    Localized(Msg),
+}
+
+impl Usage {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Usage::Fixed(usage) => usage,
+            Usage::Localized(msg) => msg.text(),
+        }
+    }
+}
+
+// コマンドを追加するときは、ここに説明を追加してparse_commandで引数を解釈する
+pub const COMMANDS: &[Spec] = &[
+    Spec { name: "help", usage: Usage::Fixed("/help"), description: Msg::HelpHelp },
+    Spec { name: "quit", usage: Usage::Fixed("/quit"), description: Msg::HelpQuit },
+    Spec { name: "send", usage: Usage::Localized(Msg::UsageSend), description: Msg::HelpSend },
+    Spec { name: "cancel", usage: Usage::Localized(Msg::UsageCancel), description: Msg::HelpCancel },
+    Spec { name: "who", usage: Usage::Fixed("/who"), description: Msg::HelpWho },
+    Spec { name: "stats", usage: Usage::Fixed("/stats"), description: Msg::HelpStats },
+    Spec { name: "ping", usage: Usage::Fixed("/ping"), description: Msg::HelpPing },
+    Spec { name: "more", usage: Usage::Fixed("/more"), description: Msg::HelpMore },
+    Spec { name: "drafts", usage: Usage::Fixed("/drafts [send|discard]"), description: Msg::HelpDrafts },
+    Spec { name: "paste", usage: Usage::Fixed("/paste"), description: Msg::HelpPaste },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Quit,
+    Send { path: PathBuf },
+    /// 転送の番号と方向 (送信中か受信中か)
+    Cancel { id: u64, direction: Direction },
+    Who,
+    Ping,
+    Stats,
+    More,
+    Drafts(DraftsAction),
+    Paste,
+    /// 組み込みにないコマンド (フックが追加したものか、不明なもの)
+    Custom { name: String, args: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DraftsAction {
+    List,
+    Send,
+    Discard,
+}
+
+// 入力された1行の解釈
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Parsed {
+    /// 相手に送るメッセージ
+    Chat(String),
+    Command(Command),
+    /// コマンドとして解釈できなかった (表示する説明)
+    Invalid(String),
+}
+
+// `/` で始まる行はコマンドとして解釈する。`//` で始まる行は先頭の `/` を1つ除いてメッセージとして送る
+pub fn parse(line: &str) -> Parsed {
+    if let Some(escaped) = line.strip_prefix("//") {
+        return Parsed::Chat(format!("/{}", escaped));
+    }
+    let Some(command) = line.trim().strip_prefix('/') else {
+        return Parsed::Chat(line.to_string());
+    };
+    let (name, args) = match command.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (command, ""),
+    };
+    match parse_command(name, args) {
+        Ok(command) => Parsed::Command(command),
+        Err(message) => Parsed::Invalid(message),
+    }
+}
+
+fn parse_command(name: &str, args: &str) -> Result<Command, String> {
+    let Some(spec) = COMMANDS.iter().find(|spec| spec.name == name) else {
+        return Ok(Command::Custom { name: name.to_string(), args: args.to_string() });
+    };
+    let usage = || Msg::Usage.with(&[&spec.usage.text()]);
+    match (name, args) {
+        ("help", "") => Ok(Command::Help),
+        ("quit", "") => Ok(Command::Quit),
+        ("who", "") => Ok(Command::Who),
+        ("ping", "") => Ok(Command::Ping),
+        ("stats", "") => Ok(Command::Stats),
+        ("more", "") => Ok(Command::More),
+        ("paste", "") => Ok(Command::Paste),
+        ("send", "") => Err(usage()),
+        ("send", path) => Ok(Command::Send { path: PathBuf::from(path) }),
+        ("cancel", args) => {
+            let (direction, id) = match args.split_once(char::is_whitespace) {
+                Some(("recv", id)) => (Direction::Incoming, id.trim()),
+                Some(_) => return Err(usage()),
+                None => (Direction::Outgoing, args),
+            };
+            id.parse().map(|id| Command::Cancel { id, direction }).map_err(|_| usage())
+        }
+        ("drafts", "") => Ok(Command::Drafts(DraftsAction::List)),
+        ("drafts", "send") => Ok(Command::Drafts(DraftsAction::Send)),
+        ("drafts", "discard") => Ok(Command::Drafts(DraftsAction::Discard)),
+        _ => Err(usage()),
+    }
+}
+
+// --format jsonl で標準入力から受け付けるコマンド (1行に1つのJSON)
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonCommand {
+    /// {"type":"send","body":"..."} 本文は `/` で始まってもそのまま送る
+    Send { body: String },
+    /// {"type":"file","path":"..."}
+    File { path: PathBuf },
+    /// {"type":"command","line":"/stats"} チャット中のコマンドを実行する
+    Command { line: String },
+    /// {"type":"cancel","id":1} 受信中の転送は "direction":"incoming" を付ける
+    Cancel {
+        id: u64,
+        #[serde(default = "outgoing")]
+        direction: Direction,
+    },
+    /// {"type":"quit"}
+    Quit,
+}
+
+fn outgoing() -> Direction {
+    Direction::Outgoing
+}
+
+pub fn parse_json(line: &str) -> Parsed {
+    match serde_json::from_str::<JsonCommand>(line) {
+        Ok(JsonCommand::Send { body }) => Parsed::Chat(body),
+        Ok(JsonCommand::File { path }) => Parsed::Command(Command::Send { path }),
+        Ok(JsonCommand::Cancel { id, direction }) => Parsed::Command(Command::Cancel { id, direction }),
+        Ok(JsonCommand::Quit) => Parsed::Command(Command::Quit),
+        Ok(JsonCommand::Command { line }) => match parse(&line) {
+            Parsed::Chat(_) => Parsed::Invalid(Msg::CommandNeedsSlash.with(&[&line])),
+            parsed => parsed,
+        },
+        Err(e) => Parsed::Invalid(Msg::InvalidJsonCommand.with(&[&e])),
+    }
+}
+
+// Tabでの補完。行頭の `/` で始まる語はコマンド名を、それ以外は名前 (参加者と連絡先) を補完する
+// 補完する語の開始位置 (バイト単位) と、前方一致する候補を返す
+pub fn complete(line: &str, pos: usize, names: &[String]) -> (usize, Vec<String>) {
+    let before = &line[..pos];
+    let start = before.rfind(char::is_whitespace).map(|i| i + before[i..].chars().next().map_or(1, char::len_utf8)).unwrap_or(0);
+    let word = &before[start..];
+    let mut candidates: Vec<String> = match word.strip_prefix('/') {
+        Some(name) if start == 0 => COMMANDS
+            .iter()
+            .filter(|spec| spec.name.starts_with(name))
+            .map(|spec| format!("/{}", spec.name))
+            .collect(),
+        _ if word.is_empty() => Vec::new(),
+        _ => {
+            let lower = word.to_lowercase();
+            names.iter().filter(|name| name.to_lowercase().starts_with(&lower)).cloned().collect()
+        }
+    };
+    candidates.sort();
+    candidates.dedup();
+    (start, candidates)
+}
+
+// /help で表示する行 (フックが追加したコマンドは組み込みのコマンドの後に並べる)
+pub fn help(extra: &[HookCommand]) -> Vec<String> {
+    let entries: Vec<(&str, String)> = COMMANDS
+        .iter()
+        .map(|spec| (spec.usage.text(), spec.description.to_string()))
+        .chain(extra.iter().map(|command| (command.usage.as_str(), command.description.clone())))
+        .collect();
+    // 全角文字を含む使い方でも説明の位置が揃うよう、表示幅で埋める
+    let width = entries.iter().map(|(usage, _)| usage.width()).max().unwrap_or(0);
+    let mut lines = vec![Msg::HelpHeader.to_string()];
+    lines.extend(
+        entries
+            .iter()
+            .map(|(usage, description)| format!("  {}{}  {}", usage, " ".repeat(width - usage.width()), description)),
+    );
+    lines
+}
@@ -0,0 +1,585 @@
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
+
+use crate::theme::{ColorOverrides, ThemeName};
+
+// 設定ファイルの変更を確認する間隔
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+// config.toml の内容。実行中に書き換えると再読み込みされる
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// 診断ログの出力レベル (例: "info", "rust_p2p_chat=debug")。RUST_LOGより優先される
+    pub log_level: Option<String>,
+    pub listen: ListenConfig,
+    pub rendezvous: RendezvousConfig,
+    pub downloads: DownloadsConfig,
+    pub access: AccessConfig,
+    pub retention: RetentionConfig,
+    pub alerts: AlertsConfig,
+    pub ui: UiConfig,
+    pub discovery: DiscoveryConfig,
+    /// 出来事をJSONでPOSTする先 ([[webhooks]] を複数書ける)
+    pub webhooks: Vec<WebhookConfig>,
+    pub mail: MailConfig,
+    pub libp2p: Libp2pConfig,
+    pub nostr: NostrConfig,
+    pub push: PushConfig,
+    pub connection: ConnectionConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+impl Config {
+    // ファイルがなければ既定値を使う
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| format!("{} の読み込みに失敗しました: {}", path.display(), e).into())
+    }
+}
+
+// listenコマンドの既定値 (コマンドラインや環境変数で指定されていない場合に使う)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListenConfig {
+    pub addr: Option<SocketAddr>,
+    /// mDNSでLANに知らせる表示名 (省略時はログインしているユーザー名)
+    pub name: Option<String>,
+    /// この分数のあいだ相手とメッセージなどのやり取りがなければ、待受側から接続を閉じる (省略時は閉じない)
+    pub idle_timeout_mins: Option<u64>,
+}
+
+// 接続コードを登録・解決するランデブーサーバー (--rendezvous で指定されていない場合に使う)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RendezvousConfig {
+    /// 例: "wss://rendezvous.example.com"
+    pub url: Option<String>,
+}
+
+// 待受時に自分のIPアドレスを調べる問い合わせ先 (discovery::ip::parse_source の書き方)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// グローバルIPアドレスの問い合わせ先。上から順に試す ("https://..." はHTTPのサービス、"stun:host:port" はSTUNサーバー)
+    pub global: Vec<String>,
+    /// ローカルIPアドレスの調べ方 ("route" は外向きの経路、"interfaces" はネットワークインターフェースの一覧)
+    pub local: Vec<String>,
+    /// 調べた結果を使い回す秒数 (0で毎回問い合わせる)
+    pub cache_secs: u64,
+    /// TXTレコード (_p2pchat.<ドメイン>) を問い合わせるDNSサーバー (省略時は /etc/resolv.conf のもの)
+    pub dns_server: Option<std::net::SocketAddr>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            global: crate::discovery::ip::DEFAULT_GLOBAL_SOURCES.map(String::from).to_vec(),
+            local: crate::discovery::ip::DEFAULT_LOCAL_SOURCES.map(String::from).to_vec(),
+            cache_secs: 300,
+            dns_server: None,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_secs)
+    }
+}
+
+// --backend libp2p で参加するネットワーク (multiaddrで書く)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Libp2pConfig {
+    /// Kademliaの最初の接続先 (例: "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN")。PeerIDだけで接続する場合に必要
+    pub bootstrap: Vec<String>,
+    /// NATの内側で待ち受けるときに経由するリレー (例: "/ip4/203.0.113.7/tcp/4001/p2p/12D3KooW...")。接続後はDCUtRで直接の接続に切り替える
+    pub relay: Option<String>,
+}
+
+// mail gateway: 届けられなかったメッセージ (下書き) を、連絡先のメールアドレスにSMTPで送る
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    /// SMTPサーバー ("smtps://smtp.example.org" は接続直後からTLS、"smtp://smtp.example.org:587" はSTARTTLS)
+    pub smtp: Option<String>,
+    /// SMTPの認証に使うユーザー名 (省略時は認証しない)
+    pub username: Option<String>,
+    /// SMTPの認証に使うパスワード (環境変数 P2PCHAT_SMTP_PASSWORD が優先)
+    pub password: Option<String>,
+    /// 差出人のメールアドレス
+    pub from: Option<String>,
+    /// 送れなかったメッセージをメールで送るまでの分
+    pub after_mins: u64,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self { smtp: None, username: None, password: None, from: None, after_mins: 30 }
+    }
+}
+
+// nostr: 直接つながらない相手と、Nostrのリレー経由で暗号化したダイレクトメッセージをやり取りする
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NostrConfig {
+    /// 送受信に使うリレー (例: "wss://relay.damus.io")
+    pub relays: Vec<String>,
+    /// 送れなかったメッセージを nostr gateway で送るまでの分
+    pub after_mins: u64,
+    /// NIP-17 (ギフトラップ) の代わりに、古いクライアント向けのNIP-04で送る
+    pub nip04: bool,
+}
+
+impl Default for NostrConfig {
+    fn default() -> Self {
+        Self { relays: Vec::new(), after_mins: 30, nip04: false }
+    }
+}
+
+// daemon: 端末やアプリがデーモンにつながっていない間にメッセージが届いたら、プッシュ通知の中継サーバーに知らせる
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    /// 知らせる先 (UnifiedPushのディストリビューターが発行したエンドポイント、またはプッシュゲートウェイの .../_matrix/push/v1/notify)
+    pub endpoint: Option<String>,
+    pub kind: PushKind,
+    /// gateway: プッシュゲートウェイに登録したアプリのID (例: "org.example.p2pchat")
+    pub app_id: Option<String>,
+    /// gateway: 端末のプッシュキー (FCMの登録トークンなど)
+    pub pushkey: Option<String>,
+    /// 通知に部屋の名前 (相手) を含める。既定では未読の件数だけを送り、中継サーバーに相手を知らせない
+    pub include_room: bool,
+    /// 続けて届いたメッセージで何度も通知しないよう、次の通知まで空ける秒数
+    pub min_interval_secs: u64,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self { endpoint: None, kind: PushKind::default(), app_id: None, pushkey: None, include_room: false, min_interval_secs: 60 }
+    }
+}
+
+// プッシュ通知の送り方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushKind {
+    /// UnifiedPush: エンドポイントに本文をそのままPOSTする (ntfy・NextPush・FCMディストリビューターなど)
+    #[default]
+    UnifiedPush,
+    /// Matrixのプッシュゲートウェイ (Sygnalなど) の形式で送り、FCM・APNsに中継してもらう
+    Gateway,
+}
+
+// 接続ごとの送信の設定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// 相手へ送るのを待てるフレームの数 (接続ごと)
+    pub send_queue: usize,
+    /// 送信の順番待ちがいっぱいになったとき (相手の受け取りが遅い) の扱い
+    pub queue_full: QueuePolicy,
+    /// ファイルの送信で、相手が受け取ったと知らせた位置より先に送ってよい量 (KiB)
+    pub transfer_window_kb: u64,
+    /// 接続するとき、TCP接続 (プロキシ経由を含む) を待つ秒数 (0は時間を限らない)
+    pub connect_timeout_secs: u64,
+    /// TLSハンドシェイク (https:// ではWebTransportのセッションの確立) を待つ秒数。待受側も使う (0は時間を限らない)
+    pub tls_timeout_secs: u64,
+    /// WebSocketのハンドシェイクを待つ秒数。待受側も使う (0は時間を限らない)
+    pub handshake_timeout_secs: u64,
+    /// 相手から何も届かないままこの秒数が過ぎたらPingを送る (Pongを待つ秒数も同じ)
+    pub ping_interval_secs: u64,
+    /// Pingへの応答が続けてこの回数届かなければ、相手に届かなくなったとみなして切断する (0は切断しない)
+    pub missed_pongs: u32,
+}
+
+impl ConnectionConfig {
+    // 先に送ってよいバイト数 (少なくとも1チャンクは送れるようにする)
+    pub fn transfer_window(&self) -> u64 {
+        (self.transfer_window_kb * 1024).max(crate::transfer::CHUNK_SIZE as u64)
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            send_queue: 256,
+            queue_full: QueuePolicy::default(),
+            transfer_window_kb: 4096,
+            connect_timeout_secs: 10,
+            tls_timeout_secs: 10,
+            handshake_timeout_secs: 10,
+            ping_interval_secs: 15,
+            missed_pongs: 3,
+        }
+    }
+}
+
+// 相手から受け取る量の上限 (接続ごと)。メッセージを送り続ける相手から画面と履歴を守る
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// 1秒あたりに受け取るメッセージの数 (0は無制限)
+    pub messages_per_sec: u64,
+    /// 1秒あたりに受け取るバイト数 (ファイルも含むが、ファイルのデータだけで超えても切断はしない。0は無制限)
+    pub bytes_per_sec: u64,
+    /// 上限を超え続けたら切断するまでの秒数 (0は切断せず、受け取りを遅らせ続ける)
+    pub disconnect_after_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self { messages_per_sec: 50, bytes_per_sec: 0, disconnect_after_secs: 30 }
+    }
+}
+
+// 送信の順番待ちがいっぱいのときの扱い
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuePolicy {
+    /// 空きができるまで入力やコマンドの処理を待たせる
+    #[default]
+    Block,
+    /// 送れないチャットのメッセージを捨てて警告する (ファイルのデータや制御のフレームは空きを待つ)
+    Drop,
+    /// 接続を切る
+    Disconnect,
+}
+
+// 受信したファイルの保存先の既定値
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadsConfig {
+    pub dir: Option<PathBuf>,
+    pub per_peer: bool,
+}
+
+// リスナーに接続できる相手の制限。IPアドレスまたはCIDR (例: "192.168.0.0/24") で指定する
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// 空でなければ、ここに含まれる相手からの接続のみ受け付ける
+    pub allow: Vec<ipnet::IpNet>,
+    /// ここに含まれる相手からの接続は拒否する (allowより優先)
+    pub deny: Vec<ipnet::IpNet>,
+}
+
+impl AccessConfig {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+// チャット画面の設定
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiConfig {
+    /// さかのぼって表示できる件数 (接続時に履歴からも読み込む)
+    pub scrollback: usize,
+    /// メッセージの表示形式。{time} {nick} {body} {id} {ticks} を置き換える (省略時は表示方法ごとの既定の形式)
+    pub format: Option<String>,
+    /// 時刻を24時間表記 ("24h") と12時間表記 ("12h") のどちらで表示するか
+    pub clock: Clock,
+    /// {id} にメッセージの通し番号を表示する
+    pub show_ids: bool,
+    /// {ticks} に自分のメッセージの配送状況 (✓ 送信済み、✓✓ 相手に届いた) を表示する
+    pub ticks: bool,
+    /// 配色 (dark / light / solarized / high-contrast / mono)
+    pub theme: ThemeName,
+    /// 配色の一部を置き換える色 ([ui.colors])
+    pub colors: ColorOverrides,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            scrollback: 5000,
+            format: None,
+            clock: Clock::H24,
+            show_ids: false,
+            ticks: false,
+            theme: ThemeName::default(),
+            colors: ColorOverrides::default(),
+        }
+    }
+}
+
+// 時刻の表示形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Clock {
+    #[serde(rename = "24h")]
+    H24,
+    #[serde(rename = "12h")]
+    H12,
+}
+
+// Webhookを送るきっかけ (設定ファイルの events と、送るJSONの event に書く名前)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    /// 相手のメッセージを受信した
+    #[serde(rename = "message.received")]
+    MessageReceived,
+    /// 相手と接続した
+    #[serde(rename = "peer.connected")]
+    PeerConnected,
+    /// ファイルの送信・受信が終わった
+    #[serde(rename = "transfer.completed")]
+    TransferCompleted,
+    /// 接続先の証明書がknown_peersの記録と異なっていた
+    #[serde(rename = "fingerprint.changed")]
+    FingerprintChanged,
+}
+
+impl WebhookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            WebhookEvent::MessageReceived => "message.received",
+            WebhookEvent::PeerConnected => "peer.connected",
+            WebhookEvent::TransferCompleted => "transfer.completed",
+            WebhookEvent::FingerprintChanged => "fingerprint.changed",
+        }
+    }
+}
+
+// 出来事を知らせるWebhook。本文はJSONで、secretがあれば X-P2PChat-Signature にHMAC-SHA256の署名を付ける
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// 例: "https://example.com/hooks/p2pchat"
+    pub url: String,
+    /// 署名の鍵 (省略時は署名しない)
+    pub secret: Option<String>,
+    /// 送る出来事 (空ならすべて)
+    pub events: Vec<WebhookEvent>,
+    /// 送れなかったとき (接続できない・5xx・429) に再送する回数
+    pub retries: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self { url: String::new(), secret: None, events: Vec::new(), retries: 3 }
+    }
+}
+
+impl WebhookConfig {
+    pub fn wants(&self, event: WebhookEvent) -> bool {
+        !self.url.is_empty() && (self.events.is_empty() || self.events.contains(&event))
+    }
+}
+
+// 通知音を鳴らすきっかけ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertEvent {
+    Message,
+    Connect,
+    Disconnect,
+}
+
+// メッセージの受信や相手の接続・切断を音で知らせる設定 (相手ごとには連絡先のnotifyで止められる)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    /// falseにするとすべての通知音を止める
+    pub enabled: bool,
+    pub on_message: bool,
+    pub on_connect: bool,
+    pub on_disconnect: bool,
+    /// 端末のベルの代わりに再生する音声ファイル
+    pub sound: Option<PathBuf>,
+    /// soundを再生するコマンド (省略時はmacOSではafplay、それ以外ではpaplay)
+    pub player: Option<String>,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            on_message: true,
+            on_connect: true,
+            on_disconnect: true,
+            sound: None,
+            player: None,
+        }
+    }
+}
+
+impl AlertsConfig {
+    pub fn wants(&self, event: AlertEvent) -> bool {
+        self.enabled
+            && match event {
+                AlertEvent::Message => self.on_message,
+                AlertEvent::Connect => self.on_connect,
+                AlertEvent::Disconnect => self.on_disconnect,
+            }
+    }
+
+    pub fn player(&self) -> &str {
+        match &self.player {
+            Some(player) => player,
+            None if cfg!(target_os = "macos") => "afplay",
+            None => "paplay",
+        }
+    }
+}
+
+// 履歴の保持期間の設定
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// 相手ごとの設定がない場合に使う保持期間
+    pub default: RetentionPolicy,
+    /// 相手 (履歴上の名前) ごとの保持期間
+    pub peers: BTreeMap<String, RetentionPolicy>,
+}
+
+impl RetentionConfig {
+    pub fn policy_for(&self, peer: &str) -> RetentionPolicy {
+        self.peers.get(peer).copied().unwrap_or(self.default)
+    }
+}
+
+// 保持期間。設定ファイルでは "forever" / "none" / "30d" のように書く
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RetentionPolicy {
+    #[default]
+    Forever,
+    Days(u32),
+    Nothing,
+}
+
+impl RetentionPolicy {
+    // この時刻に記録されたエントリを残すかどうか
+    pub fn keeps(&self, timestamp: DateTime<Local>, now: DateTime<Local>) -> bool {
+        match self {
+            RetentionPolicy::Forever => true,
+            // 日時の範囲を超えるほど長い期間は、期限がないものとして扱う
+            RetentionPolicy::Days(days) => now
+                .checked_sub_signed(Duration::days(i64::from(*days)))
+                .is_none_or(|cutoff| timestamp > cutoff),
+            RetentionPolicy::Nothing => false,
+        }
+    }
+}
+
+impl std::str::FromStr for RetentionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "forever" => Ok(RetentionPolicy::Forever),
+            "none" | "nothing" => Ok(RetentionPolicy::Nothing),
+            other => other
+                .strip_suffix("days")
+                .or_else(|| other.strip_suffix('d'))
+                .and_then(|days| days.trim().parse().ok())
+                .map(RetentionPolicy::Days)
+                .ok_or_else(|| format!("保持期間の形式が正しくありません (forever / none / 30d): {}", value)),
+        }
+    }
+}
+
+impl TryFrom<String> for RetentionPolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RetentionPolicy> for String {
+    fn from(policy: RetentionPolicy) -> Self {
+        match policy {
+            RetentionPolicy::Forever => "forever".to_string(),
+            RetentionPolicy::Days(days) => format!("{}d", days),
+            RetentionPolicy::Nothing => "none".to_string(),
+        }
+    }
+}
+
+// 設定ファイルを監視し、変更されたら (またはSIGHUPを受け取ったら) 読み込み直して配信する
+// 読み込みに失敗した場合は以前の設定を使い続ける
+pub fn watch(path: PathBuf, initial: Config) -> watch::Receiver<Config> {
+    let (tx, rx) = watch::channel(initial);
+    tokio::spawn(async move {
+        let mut last_modified = modified_time(&path);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut hangup = Hangup::new();
+        loop {
+            let forced = tokio::select! {
+                _ = interval.tick() => false,
+                _ = hangup.recv() => true,
+            };
+            let modified = modified_time(&path);
+            if !forced && modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            match Config::load(&path) {
+                Ok(config) => {
+                    if *tx.borrow() == config {
+                        continue;
+                    }
+                    if config.log_level != tx.borrow().log_level {
+                        if let Err(e) = crate::logging::set_level(config.log_level.as_deref()) {
+                            tracing::error!(error = %e, "ログレベルを変更できませんでした");
+                        }
+                    }
+                    tracing::info!(path = %path.display(), "設定を再読み込みしました");
+                    if tx.send(config).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => tracing::error!(error = %e, "設定の再読み込みに失敗しました。以前の設定を使い続けます"),
+            }
+        }
+    });
+    rx
+}
+
+fn modified_time(path: &Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// SIGHUPの受信 (Unix以外では何も受信しない)
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .inspect_err(|e| tracing::warn!(error = %e, "SIGHUPのハンドラを登録できませんでした"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        {
+            Self {}
+        }
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
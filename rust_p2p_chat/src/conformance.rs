@@ -0,0 +1,265 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use p2pchat_core::vectors as protocol_vectors;
+use serde::Serialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::builder::{ClientSettings, ListenerSettings};
+use crate::identity::{self, Identity};
+use crate::protocol::{self, Envelope};
+use crate::runtime::{BoxStream, Runtime};
+use crate::{tls, transport};
+
+// conformance: 他の実装 (サーバー・ブラウザのページ) がこのプロトコルでやり取りできるかを確かめる
+//
+//     handshake   TLS (ブラウザからの ws:// では省く) とWebSocketのハンドシェイク
+//     identity    接続直後に証明書 (Identity) を送るか。署名に対応しない実装ではskip
+//     session     接続する側は Resume に Session で応えるか、待ち受ける側では相手が接続直後に Resume を送るか
+//     delivered   Chat に同じseqの Delivered で応えるか
+//     ping        Ping に同じsent_atの Pong で応えるか
+//     backfill    BackfillRequest に Backfill で応えるか
+//     close       Close に応えて接続を閉じるか
+//
+// 相手の履歴には確かめるためのメッセージが1件残る。known_peers・再開用のトークンは書き換えない
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// 相手の応答を待つ時間 (確かめることごと)
+const WAIT: Duration = Duration::from_secs(3);
+// 確かめるために送るチャットメッセージ
+const PROBE_BODY: &str = "p2pchat conformance test";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// 省略できる機能に対応していない
+    Skip,
+}
+
+// 確かめたことの1つ
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Self { name, outcome, detail: detail.into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// 確かめた相手 (URI・受け付けた相手のアドレス・vectors)
+    pub target: String,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    // failがなければ互換がある
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.outcome != Outcome::Fail)
+    }
+}
+
+// conformance --vectors: 他の実装のテストに使うメッセージの例 (1つが1行のJSONになる)
+pub fn vectors() -> Vec<serde_json::Value> {
+    let envelopes = protocol_vectors::envelopes()
+        .into_iter()
+        .map(|vector| json!({ "kind": "envelope", "name": vector.name, "text": vector.text, "envelope": vector.envelope }));
+    let signed = protocol_vectors::signed_contents()
+        .into_iter()
+        .map(|vector| json!({ "kind": "signed_content", "seq": vector.seq, "body": vector.body, "content": vector.content }));
+    envelopes.chain(signed).collect()
+}
+
+// settings の接続先 (wss:// の待受) に接続して確かめる
+pub async fn connect(settings: &ClientSettings) -> Result<Report> {
+    let url = url::Url::parse(&settings.uri)?;
+    let identity = settings.options.identity.load(&settings.paths)?;
+    let mut checks = Vec::new();
+    let started = Instant::now();
+    let (stream, negotiated, peer_cert) = transport::connect_secure(settings, &url).await?;
+    let fingerprint = identity::fingerprint(&peer_cert);
+    let (ws_stream, _) = transport::websocket_handshake(settings, &url, stream).await?;
+    let pinned = url.fragment().filter(|pinned| !pinned.is_empty());
+    checks.push(match pinned {
+        Some(pinned) if pinned != fingerprint => Check::new("handshake", Outcome::Fail, format!("URLのフィンガープリントと一致しません ({})", fingerprint)),
+        _ => Check::new(
+            "handshake",
+            Outcome::Pass,
+            format!("{} {} ({} ms)", negotiated.tls_version.unwrap_or_default(), fingerprint, started.elapsed().as_millis()),
+        ),
+    });
+
+    let mut exchange = Exchange::new(ws_stream, settings.options.runtime.clone(), &identity).await?;
+    checks.push(exchange.identity(Some(&fingerprint)).await);
+    exchange.send(Envelope::Resume { token: None, since: 0 }).await?;
+    checks.push(match exchange.expect(|envelope| matches!(envelope, Envelope::Session { .. })).await {
+        Some(Envelope::Session { token, resumed: false, .. }) if !token.is_empty() => Check::new("session", Outcome::Pass, "Resume に Session で応えました"),
+        Some(session) => Check::new("session", Outcome::Fail, format!("新しいセッションへの応答が正しくありません: {}", session.encode())),
+        None => Check::new("session", Outcome::Fail, "Resume への Session が届きません"),
+    });
+    exchange.common(&identity, &mut checks).await?;
+    Ok(Report { target: settings.uri.clone(), checks })
+}
+
+// settings のアドレスで待ち受け、最初に接続してきた相手 (ブラウザのページからの ws:// も) を確かめる
+pub async fn accept(settings: ListenerSettings) -> Result<Report> {
+    let ListenerSettings { paths, mut options, addr, tls, limits, .. } = settings;
+    let mut listener = options.runtime.bind(addr).await?;
+    let identity = options.identity.load(&paths)?;
+    let tls_acceptor = tls::acceptor(&identity, tls)?;
+    tracing::info!(addr = %listener.local_addr()?, "確かめる相手の接続を待っています");
+    let Some((incoming, peer_addr)) = transport::next_peer(&mut *listener, &tls_acceptor, limits, &options, true, None).await? else {
+        return Err("待受を中止しました".into());
+    };
+    let started = Instant::now();
+    let ws_stream = incoming.accept(peer_addr, &tls_acceptor, &limits, &paths, &mut options).await?;
+    let mut checks = vec![match &options.negotiated {
+        Some(negotiated) => Check::new(
+            "handshake",
+            Outcome::Pass,
+            format!("{} ({} ms)", negotiated.tls_version.clone().unwrap_or_default(), started.elapsed().as_millis()),
+        ),
+        None => Check::new("handshake", Outcome::Pass, "ws:// (平文)"),
+    }];
+
+    let mut exchange = Exchange::new(ws_stream, options.runtime.clone(), &identity).await?;
+    checks.push(exchange.identity(None).await);
+    checks.push(match exchange.expect(|envelope| matches!(envelope, Envelope::Resume { .. })).await {
+        Some(Envelope::Resume { .. }) => {
+            let token = format!("conformance-{}", started.elapsed().as_nanos());
+            exchange.send(Envelope::Session { token, resumed: false, resumes: 0 }).await?;
+            Check::new("session", Outcome::Pass, "接続直後に Resume を送りました")
+        }
+        _ => Check::new("session", Outcome::Fail, "接続直後に Resume が届きません"),
+    });
+    exchange.common(&identity, &mut checks).await?;
+    Ok(Report { target: peer_addr.to_string(), checks })
+}
+
+// 確かめている接続。待っているものと違うメッセージは、後で確かめるために残しておく
+struct Exchange {
+    ws_stream: tokio_tungstenite::WebSocketStream<BoxStream>,
+    runtime: Runtime,
+    pending: Vec<Envelope>,
+    started: Instant,
+    /// 相手が接続を閉じた (Closeを受け取った)
+    closed: bool,
+}
+
+impl Exchange {
+    // この実装と同じく、接続直後に自分の証明書を送る
+    async fn new(ws_stream: tokio_tungstenite::WebSocketStream<BoxStream>, runtime: Runtime, identity: &Identity) -> Result<Self> {
+        let mut exchange = Self { ws_stream, runtime, pending: Vec::new(), started: Instant::now(), closed: false };
+        exchange.send(Envelope::Identity { cert: BASE64.encode(&identity.cert_der) }).await?;
+        Ok(exchange)
+    }
+
+    async fn send(&mut self, envelope: Envelope) -> Result<()> {
+        self.ws_stream.send(Message::Text(envelope.encode())).await?;
+        Ok(())
+    }
+
+    // matchesに合うメッセージが届くまで待つ。相手からのPing・Chat・BackfillRequestにはこの実装と同じく応える
+    async fn expect(&mut self, matches: impl Fn(&Envelope) -> bool) -> Option<Envelope> {
+        if let Some(index) = self.pending.iter().position(&matches) {
+            return Some(self.pending.remove(index));
+        }
+        let runtime = self.runtime.clone();
+        runtime
+            .timeout(WAIT, async {
+                while let Some(Ok(message)) = self.ws_stream.next().await {
+                    let text = match message {
+                        Message::Text(text) => text,
+                        Message::Close(_) => {
+                            self.closed = true;
+                            return None;
+                        }
+                        _ => continue,
+                    };
+                    let envelope = Envelope::decode(&text);
+                    let reply = match &envelope {
+                        Envelope::Ping { sent_at } => Some(Envelope::Pong { sent_at: *sent_at }),
+                        Envelope::Chat { seq, .. } if *seq > 0 => Some(Envelope::Delivered { seq: *seq }),
+                        Envelope::BackfillRequest { .. } => Some(Envelope::Backfill { messages: Vec::new() }),
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        let _ = self.send(reply).await;
+                    }
+                    if matches(&envelope) {
+                        return Some(envelope);
+                    }
+                    self.pending.push(envelope);
+                }
+                None
+            })
+            .await
+            .ok()
+            .flatten()
+    }
+
+    // tls_fingerprint: 接続する側ではTLSの証明書と同じものかも確かめる
+    async fn identity(&mut self, tls_fingerprint: Option<&str>) -> Check {
+        let Some(Envelope::Identity { cert }) = self.expect(|envelope| matches!(envelope, Envelope::Identity { .. })).await else {
+            return Check::new("identity", Outcome::Skip, "証明書が届きません (署名付きのメッセージに対応しない実装)");
+        };
+        let Ok(cert) = BASE64.decode(&cert) else {
+            return Check::new("identity", Outcome::Fail, "証明書をbase64として読めません");
+        };
+        let fingerprint = identity::fingerprint(&cert);
+        match tls_fingerprint {
+            Some(expected) if expected != fingerprint => {
+                Check::new("identity", Outcome::Fail, format!("TLSの証明書 ({}) と違う証明書です ({})", expected, fingerprint))
+            }
+            _ => Check::new("identity", Outcome::Pass, fingerprint),
+        }
+    }
+
+    // どちらの側でも確かめること (delivered・ping・backfill・close)
+    async fn common(&mut self, identity: &Identity, checks: &mut Vec<Check>) -> Result<()> {
+        let sig = identity.sign(&protocol::signed_content(1, PROBE_BODY))?;
+        self.send(Envelope::Chat { seq: 1, body: PROBE_BODY.to_string(), sig: Some(sig) }).await?;
+        checks.push(match self.expect(|envelope| matches!(envelope, Envelope::Delivered { seq: 1 })).await {
+            Some(_) => Check::new("delivered", Outcome::Pass, "seq 1"),
+            None => Check::new("delivered", Outcome::Fail, "Chat (seq 1) への Delivered が届きません"),
+        });
+
+        let sent_at = self.started.elapsed().as_nanos() as u64;
+        self.send(Envelope::Ping { sent_at }).await?;
+        checks.push(match self.expect(|envelope| matches!(envelope, Envelope::Pong { sent_at: echoed } if *echoed == sent_at)).await {
+            Some(_) => Check::new("ping", Outcome::Pass, format!("{} ms", (self.started.elapsed().as_nanos() as u64 - sent_at) / 1_000_000)),
+            None => Check::new("ping", Outcome::Fail, format!("sent_at {} の Pong が届きません", sent_at)),
+        });
+
+        self.send(Envelope::BackfillRequest { since: 0 }).await?;
+        checks.push(match self.expect(|envelope| matches!(envelope, Envelope::Backfill { .. })).await {
+            Some(Envelope::Backfill { messages }) if messages.windows(2).all(|pair| pair[0].seq < pair[1].seq) => {
+                Check::new("backfill", Outcome::Pass, format!("{}件", messages.len()))
+            }
+            Some(_) => Check::new("backfill", Outcome::Fail, "再送したメッセージが通し番号の順に並んでいません"),
+            None => Check::new("backfill", Outcome::Fail, "BackfillRequest への Backfill が届きません"),
+        });
+
+        let frame = CloseFrame { code: CloseCode::Normal, reason: "conformance".into() };
+        self.ws_stream.send(Message::Close(Some(frame))).await?;
+        // Closeを送った後は、相手のCloseが届くまでに来たものを読み捨てる
+        let _ = self.expect(|_| false).await;
+        checks.push(match self.closed {
+            true => Check::new("close", Outcome::Pass, "Close に応えました"),
+            false => Check::new("close", Outcome::Fail, "Close への応答が届きません"),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// アドレス帳の1件分
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub uri: String,
+    /// 相手の証明書のフィンガープリント (sha256:...)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// 接続に使うトランスポート (wss、または --backend libp2p で接続する相手は libp2p)
+    #[serde(default = "default_transport")]
+    pub transport: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// 以下は `connect <名前>` のときに適用される既定のオプション
+    /// チャット画面で相手を表示するときの名前
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    /// known_peersに登録されていない相手や証明書が変わった相手への接続を拒否する
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict: bool,
+    /// 相手から送られたファイルを受け取る
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub accept_files: bool,
+    /// 自動で受け取るファイルの最大サイズ (MB)。これより大きいファイルは断る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_mb: Option<u64>,
+    /// メッセージを受信したときに通知する
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub notify: bool,
+    /// この相手から受信したファイルの保存先
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<PathBuf>,
+    /// 届けられなかったメッセージをメールのゲートウェイ (mail gateway) で送る先
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// メールで送る本文を暗号化する、相手と事前に共有したパスフレーズ (省略時は知らせのみ送る)
+    /// contacts.json (本人だけが読める) とバックアップ (暗号化する) に平文のまま含まれる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail_passphrase: Option<String>,
+    /// Nostrの公開鍵 (npub1... または16進数)。直接つながらないときに nostr gateway がリレー経由で届ける
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nostr: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn default_transport() -> String {
+    "wss".to_string()
+}
+
+// 名前 → 連絡先 の対応を保持するアドレス帳
+pub struct AddressBook {
+    path: PathBuf,
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl AddressBook {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let contacts = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, contacts })
+    }
+
+    // 連絡先にはメールの本文を暗号化するパスフレーズ (mail_passphrase) が平文で含まれうるため、本人だけが読めるように書く
+    // 一時ファイルを作り直してから置き換えるので、以前にumaskのまま作られたファイルも本人だけが読めるようになる
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tmp_name = self.path.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        if tmp_path.exists() {
+            std::fs::remove_file(&tmp_path)?;
+        }
+        let mut open = std::fs::OpenOptions::new();
+        open.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+        std::io::Write::write_all(&mut open.open(&tmp_path)?, serde_json::to_string_pretty(&self.contacts)?.as_bytes())?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, name: &str, contact: Contact) -> Result<(), Box<dyn std::error::Error>> {
+        if self.contacts.contains_key(name) {
+            return Err(format!("連絡先 {} は既に登録されています", name).into());
+        }
+        self.contacts.insert(name.to_string(), contact);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Contact> {
+        self.contacts.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Contact> {
+        self.contacts.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Contact> {
+        self.contacts.get_mut(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Contact)> {
+        self.contacts.iter()
+    }
+
+    // 接続してきた相手のアドレスに一致するURIを持つ連絡先を探す
+    pub fn find_by_host(&self, host: &str) -> Option<(&String, &Contact)> {
+        self.contacts.iter().find(|(_, contact)| {
+            url::Url::parse(&contact.uri)
+                .ok()
+                .and_then(|url| url.host_str().map(|h| h.trim_matches(['[', ']']) == host))
+                .unwrap_or(false)
+        })
+    }
+
+    // 履歴・下書きに記録された相手 (連絡先の名前、接続先の host:port、接続してきた相手のIPアドレス) の連絡先を探す
+    pub fn find_by_peer(&self, peer: &str) -> Option<(&String, &Contact)> {
+        if let Some(found) = self.contacts.get_key_value(peer) {
+            return Some(found);
+        }
+        self.contacts.iter().find(|(_, contact)| {
+            let Ok(url) = url::Url::parse(&contact.uri) else {
+                return false;
+            };
+            let Some(host) = url.host_str() else {
+                return false;
+            };
+            // 接続先の host:port は接続時と同じくポートの省略を8080とみなす (wss:// の既定の443ではない)
+            let address = crate::trust::host_key(&contact.uri).ok();
+            address.as_deref() == Some(peer) || host.trim_matches(['[', ']']) == peer
+        })
+    }
+}
+
+// Connectに渡された接続先を解決した結果
+pub struct Target {
+    pub uri: String,
+    /// アドレス帳経由で解決した場合の名前と連絡先
+    pub contact: Option<(String, Contact)>,
+}
+
+// 接続先の指定がURIでなければ、アドレス帳の名前として解決する
+pub fn resolve_target(target: &str, contacts_file: &Path) -> Result<Target, Box<dyn std::error::Error>> {
+    if target.contains("://") {
+        return Ok(Target { uri: target.to_string(), contact: None });
+    }
+    let book = AddressBook::load(contacts_file)?;
+    let contact = book
+        .get(target)
+        .ok_or_else(|| format!("URIでも登録済みの連絡先でもありません: {}", target))?;
+    Ok(Target {
+        uri: contact.uri.clone(),
+        contact: Some((target.to_string(), contact.clone())),
+    })
+}
@@ -0,0 +1,464 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc};
+use tracing::Instrument;
+
+use crate::builder::{ClientBuilder, Limits, TlsMode};
+use crate::discovery::dns;
+use crate::health::{self, Health, ListenerState};
+use crate::history::History;
+use crate::i18n::Msg;
+use crate::paths::Paths;
+use crate::ui;
+use crate::{ChatOptions, Role};
+
+pub mod api;
+pub mod feed;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod push;
+
+// eventsの購読者の読み取りが遅れたときに溜めておく出来事の数
+const EVENT_BUFFER: usize = 256;
+
+// 終了するときに、各部屋が相手に切断を知らせ終えるのを待つ時間
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// 制御用ソケットへの要求。1行に1つのJSONで送り、1行のJSONで応答を受け取る
+// (events は応答のあと、接続を閉じるまで各部屋の出来事を --format jsonl と同じ形式で送り続ける)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// デーモンの状態
+    Status,
+    /// 接続中の部屋の一覧
+    Rooms,
+    /// 部屋の相手にメッセージを送る
+    Send { room: String, body: String },
+    /// 相手に接続して部屋を開く (URIまたは連絡先の名前)
+    Connect { uri: String },
+    /// 部屋の接続を閉じる
+    Close { room: String },
+    /// すべての部屋の出来事を購読する
+    Events,
+}
+
+// 接続中の相手ごとの部屋
+struct Room {
+    peer: String,
+    /// 連絡先のニックネーム (連絡先に登録していない相手はNone)
+    nickname: Option<String>,
+    role: Role,
+    since: DateTime<Local>,
+    /// 部屋のチャットへの入力 (--format jsonl のコマンド)
+    input: mpsc::UnboundedSender<String>,
+}
+
+#[derive(Serialize)]
+struct RoomInfo<'a> {
+    name: &'a str,
+    peer: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<&'a str>,
+    role: &'static str,
+    since: DateTime<Local>,
+}
+
+struct Daemon {
+    paths: Paths,
+    /// 部屋ごとにcloneして連絡先の設定を適用する
+    options: ChatOptions,
+    history: History,
+    listen: Option<SocketAddr>,
+    socket: PathBuf,
+    started: DateTime<Local>,
+    rooms: Mutex<BTreeMap<String, Room>>,
+    events: broadcast::Sender<Arc<str>>,
+}
+
+// デーモンを起動し、SIGTERMを受け取るか options.shutdown が取り消される (Ctrl+C) まで接続を保つ
+// addrを指定した場合は接続を待ち受け、受け付けた相手ごとに部屋を開く。apiで指定したREST API・gRPCも待ち受ける
+pub async fn run(
+    addr: Option<SocketAddr>,
+    socket: PathBuf,
+    api: api::ApiSettings,
+    paths: Paths,
+    options: ChatOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let quiet = options.quiet;
+    // 部屋の出来事はeventsで購読する。端末には何も書き出さない
+    let options = options.headless();
+
+    // 起動してからパスフレーズを尋ねないよう、先に履歴を開いておく
+    let history = crate::open_history(&paths)?;
+    let identity = options.identity.load(&paths)?;
+    let tls_acceptor = crate::tls::acceptor(&identity, TlsMode::default())?;
+    // systemdのソケットアクティベーションで起動された場合は、渡されたソケットで待ち受ける
+    let listener = match (crate::systemd::listener()?, addr) {
+        (Some(listener), _) => Some(listener),
+        (None, Some(addr)) => Some(TcpListener::bind(addr).await?),
+        (None, None) => None,
+    };
+    let addr = listener.as_ref().map(TcpListener::local_addr).transpose()?;
+    let control = bind_control(&socket).await?;
+    // REST API・gRPCは同じトークンを使う
+    let token = match (api.token, api.rest.is_some() || api.grpc.is_some()) {
+        (_, false) => None,
+        (Some(token), true) => Some((token, "--api-token".to_string())),
+        (None, true) => Some((api::load_token(&paths.api_token_file())?, paths.api_token_file().display().to_string())),
+    };
+    let rest = match api.rest {
+        Some(addr) => Some(api::bind(addr).await?),
+        None => None,
+    };
+    #[cfg(feature = "grpc")]
+    let grpc = match api.grpc {
+        Some(addr) => Some(api::bind(addr).await?),
+        None => None,
+    };
+    // フィードは公開する前提のため、他のマシンから使えるアドレスでも警告しない
+    let feed = match api.feed {
+        Some(feed) => Some((TcpListener::bind(feed.addr).await?, feed.room)),
+        None => None,
+    };
+    // リレーは起動したときの [push] endpoint (プッシュ通知の中継サーバー) を確かめる
+    let health = match api.health {
+        Some(addr) => {
+            let relays = options.config.borrow().push.endpoint.iter().cloned().collect();
+            Some((health::bind(addr).await?, Health::new(paths.data_dir.clone(), relays)))
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "grpc"))]
+    if api.grpc.is_some() {
+        return Err("gRPCは含まれていません (grpcフィーチャー)".into());
+    }
+
+    if !quiet {
+        println!("{}", Msg::DaemonStarted.with(&[&socket.display()]));
+        if let Some(addr) = addr {
+            println!("{}", Msg::DaemonListening.with(&[&addr, &identity.fingerprint()]));
+        }
+        let source = token.as_ref().map(|(_, source)| source.as_str()).unwrap_or_default();
+        if let Some(listener) = &rest {
+            println!("{}", Msg::DaemonApi.with(&[&listener.local_addr()?, &source]));
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(listener) = &grpc {
+            println!("{}", Msg::DaemonGrpc.with(&[&listener.local_addr()?, &source]));
+        }
+        if let Some((listener, room)) = &feed {
+            println!("{}", Msg::DaemonFeed.with(&[room, &listener.local_addr()?]));
+        }
+        if let Some((listener, _)) = &health {
+            println!("{}", Msg::HealthListening.with(&[&listener.local_addr()?]));
+        }
+    }
+    tracing::info!(socket = %socket.display(), listen = ?addr, "デーモンを起動しました");
+    crate::systemd::ready("デーモンを起動しました");
+
+    let (events, _) = broadcast::channel(EVENT_BUFFER);
+    let daemon = Arc::new(Daemon {
+        paths,
+        options,
+        history,
+        listen: addr,
+        socket,
+        started: Local::now(),
+        rooms: Mutex::new(BTreeMap::new()),
+        events,
+    });
+    let token = token.map(|(token, _)| token).unwrap_or_default();
+    let mut servers = Vec::new();
+    if let Some(listener) = rest {
+        servers.push(tokio::spawn(api::serve(listener, Arc::clone(&daemon), token.clone())));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(listener) = grpc {
+        servers.push(tokio::spawn(grpc::serve(listener, Arc::clone(&daemon), token)));
+    }
+    if let Some((listener, room)) = feed {
+        servers.push(tokio::spawn(feed::serve(listener, Arc::clone(&daemon), room, identity.fingerprint())));
+    }
+    // 状態の確認は、部屋を閉じ終えるまで (終了中と分かるよう) 応答し続ける
+    let health = health.map(|(listener, health)| {
+        health.set_listener(if addr.is_some() { ListenerState::Listening } else { ListenerState::Disabled });
+        (tokio::spawn(health::serve(listener, health.clone())), health)
+    });
+    // [push] は実行中に設定しても使えるよう、常に見張っておく
+    servers.push(tokio::spawn(push::watch(Arc::clone(&daemon))));
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            accepted = accept(listener.as_ref()) => {
+                let (stream, peer_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::error!(error = %e, "接続の受け付けに失敗しました");
+                        continue;
+                    }
+                };
+                if !daemon.options.config.borrow().access.permits(peer_addr.ip()) {
+                    tracing::warn!(security = true, peer = %peer_addr, "許可されていない相手からの接続を拒否しました");
+                    continue;
+                }
+                let daemon = Arc::clone(&daemon);
+                let tls_acceptor = tls_acceptor.clone();
+                let span = tracing::info_span!("connection", peer = %peer_addr);
+                tokio::spawn(
+                    async move {
+                        let mut options = daemon.options.clone();
+                        let accepted = crate::transport::accept_peer(Box::new(stream), peer_addr, &tls_acceptor, &Limits::default(), &daemon.paths, &mut options).await;
+                        let ws_stream = match accepted {
+                            Ok(ws_stream) => ws_stream,
+                            Err(e) => {
+                                tracing::warn!(error = %e, "接続を確立できませんでした");
+                                return;
+                            }
+                        };
+                        // クライアントは再接続のたびに送信元ポートが変わるため、IPアドレスで相手を識別する
+                        daemon.spawn_chat(ws_stream, peer_addr.ip().to_string(), Role::Listener, options);
+                    }
+                    .instrument(span),
+                );
+            }
+            accepted = control.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(Arc::clone(&daemon).serve(stream));
+                }
+                Err(e) => tracing::error!(error = %e, "制御用ソケットの接続の受け付けに失敗しました"),
+            },
+            // 端末を閉じても接続を保つ (設定の再読み込みはconfig::watchが行う)
+            _ = hangup.recv() => tracing::info!("SIGHUPを受け取りました。接続を保ったまま続けます"),
+            _ = terminate.recv() => break,
+            _ = daemon.options.shutdown.cancelled() => break,
+        }
+    }
+
+    tracing::info!("デーモンを終了します");
+    if let Some((_, health)) = &health {
+        health.set_listener(ListenerState::Stopped);
+    }
+    let _ = std::fs::remove_file(&daemon.socket);
+    for server in servers {
+        server.abort();
+    }
+    // 各部屋の相手に切断を知らせ、部屋が閉じるまで (長くても数秒) 待つ
+    daemon.options.shutdown.cancel();
+    let closed = async {
+        while !lock(&daemon.rooms).is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, closed).await.is_err() {
+        tracing::warn!("切断の通知が終わらない部屋を残して終了します");
+    }
+    if let Some((server, _)) = health {
+        server.abort();
+    }
+    Ok(())
+}
+
+// 待ち受けていない場合は接続を受け付けない
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// 制御用ソケットを作る。前回異常終了したときのソケットファイルが残っていれば削除する
+async fn bind_control(socket: &Path) -> Result<UnixListener, Box<dyn std::error::Error>> {
+    if socket.exists() {
+        if UnixStream::connect(socket).await.is_ok() {
+            return Err(format!("デーモンはすでに起動しています: {}", socket.display()).into());
+        }
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    // 他のユーザーが自分としてメッセージを送れないようにする
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn lock(rooms: &Mutex<BTreeMap<String, Room>>) -> MutexGuard<'_, BTreeMap<String, Room>> {
+    rooms.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Daemon {
+    // 部屋を開いてチャットを始め、部屋の名前を返す。接続が終わると部屋を閉じる
+    fn spawn_chat<S>(
+        self: &Arc<Self>,
+        ws_stream: tokio_tungstenite::WebSocketStream<S>,
+        peer: String,
+        role: Role,
+        options: ChatOptions,
+    ) -> String
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (input_tx, input) = mpsc::unbounded_channel();
+        let room = {
+            let mut rooms = lock(&self.rooms);
+            // 同じ相手と複数の接続がある場合は番号を付けて区別する
+            let name = (1..)
+                .map(|n| if n == 1 { peer.clone() } else { format!("{}#{}", peer, n) })
+                .find(|name| !rooms.contains_key(name))
+                .expect("部屋の名前は必ず見つかる");
+            rooms.insert(name.clone(), Room { peer: peer.clone(), nickname: options.nickname.clone(), role, since: Local::now(), input: input_tx });
+            name
+        };
+        let headless = ui::Headless { room: room.clone(), events: self.events.clone(), input };
+        let daemon = Arc::clone(self);
+        let name = room.clone();
+        tokio::spawn(
+            async move {
+                let mut history = daemon.history.clone();
+                crate::chat::handle_connection(ws_stream, &peer, &mut history, role, &daemon.paths, options, Some(headless)).await;
+                lock(&daemon.rooms).remove(&name);
+            }
+            .in_current_span(),
+        );
+        room
+    }
+
+    // 制御用ソケットの1つの接続から要求を読み、応答する
+    async fn serve(self: Arc<Self>, stream: UnixStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(Request::Events) => {
+                    self.stream_events(writer).await;
+                    return;
+                }
+                Ok(request) => self.handle(request).await,
+                Err(e) => Err(format!("要求として解釈できません: {}", e)),
+            };
+            let response = match response {
+                Ok(Value::Object(mut fields)) => {
+                    fields.insert("ok".to_string(), Value::Bool(true));
+                    Value::Object(fields)
+                }
+                Ok(_) => json!({ "ok": true }),
+                Err(error) => json!({ "ok": false, "error": error }),
+            };
+            if writer.write_all(format!("{}\n", response).as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn handle(self: &Arc<Self>, request: Request) -> Result<Value, String> {
+        match request {
+            Request::Status => Ok(json!({
+                "pid": std::process::id(),
+                "started": self.started,
+                "listen": self.listen,
+                "socket": self.socket,
+                "rooms": lock(&self.rooms).len(),
+            })),
+            Request::Rooms => {
+                let rooms = lock(&self.rooms);
+                let rooms: Vec<RoomInfo> = rooms
+                    .iter()
+                    .map(|(name, room)| RoomInfo {
+                        name,
+                        peer: &room.peer,
+                        nickname: room.nickname.as_deref(),
+                        role: match room.role {
+                            Role::Listener => "listener",
+                            Role::Client => "client",
+                        },
+                        since: room.since,
+                    })
+                    .collect();
+                Ok(json!({ "rooms": rooms }))
+            }
+            Request::Send { room, body } => self.input(&room, json!({ "type": "send", "body": body })),
+            Request::Close { room } => self.input(&room, json!({ "type": "quit" })),
+            Request::Connect { uri } => {
+                let dns_server = self.options.config.borrow().discovery.dns_server;
+                let target = dns::resolve_target(&uri, &self.paths.contacts_file(), dns_server).await.map_err(|e| e.to_string())?;
+                let settings = ClientBuilder::new(self.paths.clone(), self.options.clone())
+                    .target(&target)
+                    .map_err(|e| e.to_string())?
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                let span = tracing::info_span!("connection", peer = %settings.uri);
+                let (ws_stream, peer, negotiated) = crate::transport::connect(&settings, true)
+                    .instrument(span.clone())
+                    .await
+                    .map_err(|e| format!("接続できませんでした: {}", e))?;
+                let mut options = settings.options;
+                options.negotiated = Some(negotiated);
+                let room = span.in_scope(|| self.spawn_chat(ws_stream, peer, Role::Client, options));
+                Ok(json!({ "room": room }))
+            }
+            Request::Events => Err("events は単独で送ってください".to_string()),
+        }
+    }
+
+    // 部屋のチャットにコマンドを渡す
+    fn input(&self, room: &str, command: Value) -> Result<Value, String> {
+        let rooms = lock(&self.rooms);
+        let room = rooms.get(room).ok_or_else(|| format!("部屋が見つかりません: {}", room))?;
+        room.input.send(command.to_string()).map_err(|_| "部屋の接続はすでに閉じています".to_string())?;
+        Ok(Value::Null)
+    }
+
+    // 接続が閉じられるまで各部屋の出来事を送り続ける
+    async fn stream_events(&self, mut writer: tokio::net::unix::OwnedWriteHalf) {
+        let mut events = self.events.subscribe();
+        if writer.write_all(format!("{}\n", json!({ "ok": true })).as_bytes()).await.is_err() {
+            return;
+        }
+        loop {
+            let line = match events.recv().await {
+                Ok(line) => line,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "読み取りが遅れたため、出来事の一部を送れませんでした");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if writer.write_all(format!("{}\n", line).as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// デーモンに要求を1つ送り、応答を返す。eventsの出来事は返したLinesから読み取る
+pub async fn request(
+    socket: &Path,
+    request: &Request,
+) -> Result<(Value, Lines<BufReader<OwnedReadHalf>>), Box<dyn std::error::Error>> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| format!("デーモンに接続できません ({}): {}", socket.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(format!("{}\n", serde_json::to_string(request)?).as_bytes()).await?;
+    let mut lines = BufReader::new(reader).lines();
+    let line = lines.next_line().await?.ok_or("デーモンから応答がありませんでした")?;
+    let response: Value = serde_json::from_str(&line)?;
+    if response["ok"] != Value::Bool(true) {
+        return Err(response["error"].as_str().unwrap_or("不明なエラー").into());
+    }
+    Ok((response, lines))
+}
@@ -0,0 +1,147 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Instrument;
+
+use super::{lock, Daemon, Request};
+use crate::http::{failure, read_request, success, write_reply, HttpRequest, Reply, REQUEST_TIMEOUT};
+
+// `daemon --api` のREST API。ライブラリを組み込まずに、スクリプトやホームオートメーションからメッセージを送受信する
+//
+//     POST /messages          {"room": "127.0.0.1", "body": "こんにちは"}
+//     GET  /messages?since=N  通し番号がNより後のメッセージ (すべての部屋、古い順)。次は応答のnextをsinceに渡す
+//     GET  /peers             接続中の部屋 (ctl rooms と同じ)
+//
+// どの要求にも Authorization: Bearer <トークン> が必要。応答は制御用ソケットと同じく {"ok":true,...} か {"ok":false,"error":"..."}
+
+// GET /messages で一度に返す件数 (limitの既定値と上限)
+pub(super) const DEFAULT_LIMIT: usize = 100;
+pub(super) const MAX_LIMIT: usize = 1000;
+
+// REST API・gRPCの設定 (どちらのアドレスも指定しなければ待ち受けない)
+#[derive(Debug, Clone, Default)]
+pub struct ApiSettings {
+    /// REST APIを待ち受けるアドレス
+    pub rest: Option<SocketAddr>,
+    /// gRPCを待ち受けるアドレス (grpc フィーチャー)
+    pub grpc: Option<SocketAddr>,
+    /// 認証に使うトークン (Noneの場合はデータディレクトリの api_token を使い、なければ作る)
+    pub token: Option<String>,
+    /// 部屋のAtomフィードを公開する場合の設定 (トークンは求めない)
+    pub feed: Option<super::feed::FeedSettings>,
+    /// 状態の確認 (/healthz・/readyz) を待ち受けるアドレス (トークンは求めない)
+    pub health: Option<SocketAddr>,
+}
+
+// 待ち受けを始める。どちらも平文なので、他のマシンから使えるアドレスでは警告する
+pub(super) async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        tracing::warn!(%addr, "APIを他のマシンからも使えるアドレスで待ち受けます (平文です)");
+    }
+    TcpListener::bind(addr).await
+}
+
+// トークンを読み込む。ファイルがなければ新しく作り、所有者だけが読めるようにする
+pub(super) fn load_token(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    if path.exists() {
+        let token = std::fs::read_to_string(path)?.trim().to_string();
+        if token.is_empty() {
+            return Err(format!("トークンのファイルが空です: {}", path.display()).into());
+        }
+        return Ok(token);
+    }
+    let token = crate::session::new_token()?;
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    writeln!(file, "{}", token)?;
+    Ok(token)
+}
+
+// 接続を受け付け、1つの接続で1つの要求に応答する
+pub(super) async fn serve(listener: TcpListener, daemon: Arc<Daemon>, token: String) {
+    let token: Arc<str> = token.into();
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(error = %e, "REST APIの接続の受け付けに失敗しました");
+                continue;
+            }
+        };
+        let span = tracing::info_span!("api", client = %client);
+        tokio::spawn(respond(stream, Arc::clone(&daemon), Arc::clone(&token)).instrument(span));
+    }
+}
+
+async fn respond(mut stream: TcpStream, daemon: Arc<Daemon>, token: Arc<str>) {
+    let reply = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => route(&daemon, &token, request).await,
+        Ok(Err(error)) => error,
+        Err(_) => failure(408, "要求が時間内に届きませんでした"),
+    };
+    write_reply(&mut stream, reply).await;
+}
+
+#[derive(Deserialize)]
+struct SendMessage {
+    room: String,
+    body: String,
+}
+
+async fn route(daemon: &Arc<Daemon>, token: &str, request: HttpRequest) -> Reply {
+    if !authorized(token, request.authorization.as_deref()) {
+        tracing::warn!(security = true, path = %request.path, "トークンのない、または一致しないREST APIの要求を断りました");
+        return failure(401, "トークンが一致しません (Authorization: Bearer <トークン>)");
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/peers") => match daemon.handle(Request::Rooms).await {
+            Ok(rooms) => success(200, json!({ "peers": rooms["rooms"] })),
+            Err(error) => failure(500, &error),
+        },
+        ("GET", "/messages") => {
+            let param = |name: &str| request.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+            let Ok(since) = param("since").unwrap_or("0").parse::<u64>() else {
+                return failure(400, "sinceには通し番号を指定してください");
+            };
+            let Ok(limit) = param("limit").map_or(Ok(DEFAULT_LIMIT), str::parse::<usize>) else {
+                return failure(400, "limitには件数を指定してください");
+            };
+            match daemon.history.messages_since(since, limit.min(MAX_LIMIT)) {
+                Ok(messages) => {
+                    let next = messages.last().map_or(since, |entry| entry.seq);
+                    success(200, json!({ "messages": messages, "next": next }))
+                }
+                Err(e) => failure(500, &format!("履歴を読み込めません: {}", e)),
+            }
+        }
+        ("POST", "/messages") => {
+            let message: SendMessage = match serde_json::from_slice(&request.body) {
+                Ok(message) => message,
+                Err(e) => return failure(400, &format!("本文は {{\"room\": ..., \"body\": ...}} のJSONで送ってください: {}", e)),
+            };
+            if !lock(&daemon.rooms).contains_key(&message.room) {
+                return failure(404, &format!("部屋が見つかりません: {}", message.room));
+            }
+            match daemon.handle(Request::Send { room: message.room, body: message.body }).await {
+                Ok(_) => success(202, json!({})),
+                Err(error) => failure(409, &error),
+            }
+        }
+        (_, "/peers" | "/messages") => failure(405, "このパスには対応していないメソッドです"),
+        _ => failure(404, "パスが見つかりません"),
+    }
+}
+
+pub(crate) fn authorized(token: &str, authorization: Option<&str>) -> bool {
+    authorization.and_then(|value| value.strip_prefix("Bearer ")).is_some_and(|given| same_token(token, given))
+}
+
+// トークンの比較にかかる時間から一致した長さが分からないよう、ハッシュどうしを比べる
+pub(crate) fn same_token(token: &str, given: &str) -> bool {
+    let digest = |value: &str| ring::digest::digest(&ring::digest::SHA256, value.trim().as_bytes());
+    digest(given).as_ref() == digest(token).as_ref()
+}
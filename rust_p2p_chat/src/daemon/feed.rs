@@ -0,0 +1,146 @@
+use chrono::{DateTime, Local};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Instrument;
+
+use super::Daemon;
+use crate::contacts::AddressBook;
+use crate::history::{Direction, EventKind, HistoryEntry};
+use crate::http;
+use crate::i18n::Msg;
+
+// `daemon --feed` の読み取り専用のAtomフィード。お知らせ用の部屋 (--feed-room) のメッセージを、
+// チャットに参加しない人もフィードリーダーで購読できるようにする
+//
+//     GET /feed.atom   部屋の新しいメッセージ (履歴から、新しい順)
+//
+// 公開する前提のためトークンは求めない。部屋の名前は ctl rooms の name (履歴に記録される相手の名前)
+
+// フィードに載せるメッセージの数
+const FEED_ENTRIES: usize = 50;
+// 履歴から読む件数 (ファイル転送などを除いてFEED_ENTRIESが残るよう多めに読む)
+const SCAN_ENTRIES: usize = FEED_ENTRIES * 4;
+// エントリーのタイトルにする本文の最初の文字数
+const TITLE_CHARS: usize = 80;
+// フィードリーダーが取得し直すまでの秒数
+const MAX_AGE_SECS: u64 = 60;
+
+// フィードの設定
+#[derive(Debug, Clone)]
+pub struct FeedSettings {
+    /// 待ち受けるアドレス
+    pub addr: SocketAddr,
+    /// フィードにする部屋の名前
+    pub room: String,
+}
+
+// idは自分の証明書のフィンガープリントから作る (フィードリーダーが別のホストのフィードと区別できるよう)
+pub(super) async fn serve(listener: TcpListener, daemon: Arc<Daemon>, room: String, fingerprint: String) {
+    let room: Arc<str> = room.into();
+    let id: Arc<str> = format!("tag:p2pchat,2024:{}", fingerprint).into();
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(error = %e, "フィードの接続の受け付けに失敗しました");
+                continue;
+            }
+        };
+        let span = tracing::info_span!("feed", client = %client);
+        tokio::spawn(respond(stream, Arc::clone(&daemon), Arc::clone(&room), Arc::clone(&id)).instrument(span));
+    }
+}
+
+async fn respond(mut stream: TcpStream, daemon: Arc<Daemon>, room: Arc<str>, id: Arc<str>) {
+    let request = match tokio::time::timeout(http::REQUEST_TIMEOUT, http::read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(error)) => return http::write_reply(&mut stream, error).await,
+        Err(_) => return http::write_reply(&mut stream, http::failure(408, "要求が時間内に届きませんでした")).await,
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET" | "HEAD", "/feed.atom" | "/") => {}
+        (_, "/feed.atom" | "/") => return http::write_reply(&mut stream, http::failure(405, "GETで取得してください")).await,
+        _ => return http::write_reply(&mut stream, http::failure(404, "フィードは /feed.atom です")).await,
+    }
+    let entries = daemon.history.recent(&room, SCAN_ENTRIES).map_err(|e| tracing::error!(error = %e, "フィードにする履歴を読めませんでした"));
+    let Ok(entries) = entries else {
+        return http::write_reply(&mut stream, http::failure(500, "履歴を読めませんでした")).await;
+    };
+    let author = AddressBook::load(daemon.paths.contacts_file())
+        .ok()
+        .and_then(|book| book.find_by_peer(&room).map(|(name, contact)| contact.nickname.clone().unwrap_or_else(|| name.clone())))
+        .unwrap_or_else(|| room.to_string());
+    let host = daemon.options.config.borrow().listen.name.clone().unwrap_or_else(|| Msg::Me.to_string());
+    let xml = render(&Feed { id: &id, room: &room, author: &author, host: &host }, &entries);
+    let body = if request.method == "HEAD" { Vec::new() } else { xml.into_bytes() };
+    let headers = format!("Cache-Control: public, max-age={}\r\n", MAX_AGE_SECS);
+    http::write_response(&mut stream, 200, "application/atom+xml; charset=utf-8", &headers, &body).await;
+}
+
+// フィード全体の情報
+pub struct Feed<'a> {
+    /// フィードのID (エントリーのIDはこれに /部屋/通し番号 を付ける)
+    pub id: &'a str,
+    pub room: &'a str,
+    /// 相手のメッセージの著者名
+    pub author: &'a str,
+    /// 自分のメッセージの著者名
+    pub host: &'a str,
+}
+
+// 履歴のメッセージ (古い順) から、新しい順のAtomフィードを作る
+pub fn render(feed: &Feed, entries: &[HistoryEntry]) -> String {
+    let messages: Vec<(&HistoryEntry, Direction, &str)> = entries
+        .iter()
+        .rev()
+        .filter_map(|entry| match &entry.event {
+            EventKind::Message { direction, body } => Some((entry, *direction, body.as_str())),
+            _ => None,
+        })
+        .take(FEED_ENTRIES)
+        .collect();
+    let updated = messages.first().map_or_else(Local::now, |(entry, _, _)| entry.timestamp);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <id>{}/{}</id>\n", escape(feed.id), escape(feed.room)));
+    xml.push_str(&format!("  <title>{}</title>\n", escape(&Msg::FeedTitle.with(&[&feed.author]))));
+    xml.push_str(&format!("  <updated>{}</updated>\n", timestamp(updated)));
+    xml.push_str(&format!("  <author><name>{}</name></author>\n", escape(feed.author)));
+    xml.push_str(&format!("  <generator version=\"{}\">rust_p2p_chat</generator>\n", env!("CARGO_PKG_VERSION")));
+    for (entry, direction, body) in messages {
+        let author = match direction {
+            Direction::Incoming => feed.author,
+            Direction::Outgoing => feed.host,
+        };
+        let title: String = body.lines().next().unwrap_or_default().chars().take(TITLE_CHARS).collect();
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <id>{}/{}/{}</id>\n", escape(feed.id), escape(feed.room), entry.seq));
+        xml.push_str(&format!("    <title>{}</title>\n", escape(&title)));
+        xml.push_str(&format!("    <updated>{}</updated>\n", timestamp(entry.timestamp)));
+        xml.push_str(&format!("    <author><name>{}</name></author>\n", escape(author)));
+        xml.push_str(&format!("    <content type=\"text\">{}</content>\n", escape(body)));
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn timestamp(at: DateTime<Local>) -> String {
+    at.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+// XMLで使えない制御文字は除く
+fn escape(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .fold(String::with_capacity(text.len()), |mut out, c| {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                c => out.push(c),
+            }
+            out
+        })
+}
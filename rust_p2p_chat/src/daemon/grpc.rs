@@ -0,0 +1,246 @@
+// エラーの型はtonicのトレイトが決める tonic::Status なので、大きさの警告は抑える
+#![allow(clippy::result_large_err)]
+
+use futures_util::Stream;
+use serde_json::Value;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tonic::{Response, Status};
+
+use super::{api, lock, Daemon, Request, Room};
+use crate::contacts::{AddressBook, Contact};
+use crate::history::{Direction, EventKind};
+use crate::Role;
+
+// `daemon --grpc` のgRPCサービス (proto/p2pchat.proto)。Rust以外のフロントエンドやオーケストレーションから使う
+// 部屋の操作は制御用ソケットと同じ Daemon::handle を通し、トークンはREST APIと同じものを使う
+
+// proto/p2pchat.proto から生成したメッセージとサービス (クライアントの control_client も含む)
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("p2pchat.v1");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+// 接続を受け付け、authorizationメタデータのトークンを確かめてから呼び出しに応える
+pub(super) async fn serve(listener: TcpListener, daemon: Arc<Daemon>, token: String) {
+    let check = move |request: tonic::Request<()>| {
+        let authorization = request.metadata().get("authorization").and_then(|value| value.to_str().ok());
+        if api::authorized(&token, authorization) {
+            return Ok(request);
+        }
+        tracing::warn!(security = true, "トークンのない、または一致しないgRPCの呼び出しを断りました");
+        Err(Status::unauthenticated("トークンが一致しません (authorization: Bearer <トークン>)"))
+    };
+    let incoming = futures_util::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        Some((accepted, listener))
+    });
+    let served = tonic::transport::Server::builder()
+        .add_service(ControlServer::with_interceptor(Service { daemon }, check))
+        .serve_with_incoming(incoming)
+        .await;
+    if let Err(e) = served {
+        tracing::error!(error = %e, "gRPCの待受が終了しました");
+    }
+}
+
+struct Service {
+    daemon: Arc<Daemon>,
+}
+
+impl Service {
+    // 部屋が開いていなければNotFound
+    fn ensure_room(&self, room: &str) -> Result<(), Status> {
+        match lock(&self.daemon.rooms).contains_key(room) {
+            true => Ok(()),
+            false => Err(Status::not_found(format!("部屋が見つかりません: {}", room))),
+        }
+    }
+
+    fn contacts(&self) -> Result<AddressBook, Status> {
+        AddressBook::load(self.daemon.paths.contacts_file()).map_err(|e| Status::internal(format!("アドレス帳を読み込めません: {}", e)))
+    }
+}
+
+fn room_info(name: &str, room: &Room) -> proto::Room {
+    proto::Room {
+        name: name.to_string(),
+        peer: room.peer.clone(),
+        nickname: room.nickname.clone().unwrap_or_default(),
+        role: match room.role {
+            Role::Listener => "listener",
+            Role::Client => "client",
+        }
+        .to_string(),
+        since: room.since.to_rfc3339(),
+    }
+}
+
+fn contact_info(name: &str, contact: &Contact) -> proto::Contact {
+    proto::Contact {
+        name: name.to_string(),
+        uri: contact.uri.clone(),
+        fingerprint: contact.fingerprint.clone().unwrap_or_default(),
+        nickname: contact.nickname.clone().unwrap_or_default(),
+        notes: contact.notes.clone().unwrap_or_default(),
+    }
+}
+
+// eventsの1行 (ctl events と同じJSON) をEventにする
+fn event(line: &str) -> proto::Event {
+    let value: Value = serde_json::from_str(line).unwrap_or_default();
+    let text = |key: &str| value[key].as_str().unwrap_or_default().to_string();
+    proto::Event {
+        room: text("room"),
+        kind: text("event"),
+        at: text("at"),
+        from: text("from"),
+        body: text("body"),
+        seq: value["seq"].as_u64().unwrap_or_default(),
+        text: text("text"),
+        peer: text("peer"),
+        json: line.to_string(),
+    }
+}
+
+fn empty_string_none(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+#[tonic::async_trait]
+impl Control for Service {
+    async fn get_status(&self, _: tonic::Request<proto::GetStatusRequest>) -> Result<Response<proto::DaemonStatus>, Status> {
+        let daemon = &self.daemon;
+        Ok(Response::new(proto::DaemonStatus {
+            pid: std::process::id(),
+            started: daemon.started.to_rfc3339(),
+            listen: daemon.listen.map(|addr| addr.to_string()).unwrap_or_default(),
+            socket: daemon.socket.display().to_string(),
+            rooms: lock(&daemon.rooms).len() as u32,
+        }))
+    }
+
+    async fn list_rooms(&self, _: tonic::Request<proto::ListRoomsRequest>) -> Result<Response<proto::ListRoomsResponse>, Status> {
+        let rooms = lock(&self.daemon.rooms).iter().map(|(name, room)| room_info(name, room)).collect();
+        Ok(Response::new(proto::ListRoomsResponse { rooms }))
+    }
+
+    async fn open_room(&self, request: tonic::Request<proto::OpenRoomRequest>) -> Result<Response<proto::Room>, Status> {
+        let uri = request.into_inner().target;
+        let opened = self.daemon.handle(Request::Connect { uri }).await.map_err(Status::unavailable)?;
+        let name = opened["room"].as_str().unwrap_or_default();
+        let rooms = lock(&self.daemon.rooms);
+        let room = rooms.get(name).ok_or_else(|| Status::aborted("部屋は開いた直後に閉じられました"))?;
+        Ok(Response::new(room_info(name, room)))
+    }
+
+    async fn close_room(&self, request: tonic::Request<proto::CloseRoomRequest>) -> Result<Response<proto::CloseRoomResponse>, Status> {
+        let room = request.into_inner().room;
+        self.ensure_room(&room)?;
+        self.daemon.handle(Request::Close { room }).await.map_err(Status::failed_precondition)?;
+        Ok(Response::new(proto::CloseRoomResponse {}))
+    }
+
+    async fn send_message(&self, request: tonic::Request<proto::SendMessageRequest>) -> Result<Response<proto::SendMessageResponse>, Status> {
+        let proto::SendMessageRequest { room, body } = request.into_inner();
+        self.ensure_room(&room)?;
+        self.daemon.handle(Request::Send { room, body }).await.map_err(Status::failed_precondition)?;
+        Ok(Response::new(proto::SendMessageResponse {}))
+    }
+
+    async fn list_messages(&self, request: tonic::Request<proto::ListMessagesRequest>) -> Result<Response<proto::ListMessagesResponse>, Status> {
+        let proto::ListMessagesRequest { since, limit } = request.into_inner();
+        let limit = match limit {
+            0 => api::DEFAULT_LIMIT,
+            limit => (limit as usize).min(api::MAX_LIMIT),
+        };
+        let entries = self
+            .daemon
+            .history
+            .messages_since(since, limit)
+            .map_err(|e| Status::internal(format!("履歴を読み込めません: {}", e)))?;
+        let next = entries.last().map_or(since, |entry| entry.seq);
+        let messages = entries
+            .into_iter()
+            .filter_map(|entry| match entry.event {
+                EventKind::Message { direction, body } => Some(proto::Message {
+                    seq: entry.seq,
+                    timestamp: entry.timestamp.to_rfc3339(),
+                    peer: entry.peer,
+                    direction: match direction {
+                        Direction::Incoming => "incoming",
+                        Direction::Outgoing => "outgoing",
+                    }
+                    .to_string(),
+                    body,
+                }),
+                _ => None,
+            })
+            .collect();
+        Ok(Response::new(proto::ListMessagesResponse { messages, next }))
+    }
+
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+    async fn stream_events(&self, _: tonic::Request<proto::StreamEventsRequest>) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let events = futures_util::stream::unfold(self.daemon.events.subscribe(), |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(line) => return Some((Ok(event(&line)), events)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "読み取りが遅れたため、出来事の一部を送れませんでした");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn list_contacts(&self, _: tonic::Request<proto::ListContactsRequest>) -> Result<Response<proto::ListContactsResponse>, Status> {
+        let contacts = self.contacts()?.iter().map(|(name, contact)| contact_info(name, contact)).collect();
+        Ok(Response::new(proto::ListContactsResponse { contacts }))
+    }
+
+    async fn add_contact(&self, request: tonic::Request<proto::AddContactRequest>) -> Result<Response<proto::Contact>, Status> {
+        let added = request.into_inner().contact.ok_or_else(|| Status::invalid_argument("contactを指定してください"))?;
+        if added.name.is_empty() {
+            return Err(Status::invalid_argument("連絡先の名前を指定してください"));
+        }
+        url::Url::parse(&added.uri).map_err(|e| Status::invalid_argument(format!("URIを解釈できません: {}", e)))?;
+        let contact = Contact {
+            uri: added.uri,
+            fingerprint: empty_string_none(added.fingerprint),
+            transport: "wss".to_string(),
+            notes: empty_string_none(added.notes),
+            nickname: empty_string_none(added.nickname),
+            strict: false,
+            accept_files: true,
+            max_file_mb: None,
+            notify: true,
+            download_dir: None,
+            email: None,
+            mail_passphrase: None,
+            nostr: None,
+        };
+        let mut book = self.contacts()?;
+        book.add(&added.name, contact).map_err(|e| Status::already_exists(e.to_string()))?;
+        book.save().map_err(|e| Status::internal(format!("アドレス帳を保存できません: {}", e)))?;
+        let contact = book.get(&added.name).expect("追加した連絡先は必ずある");
+        Ok(Response::new(contact_info(&added.name, contact)))
+    }
+
+    async fn remove_contact(&self, request: tonic::Request<proto::RemoveContactRequest>) -> Result<Response<proto::RemoveContactResponse>, Status> {
+        let name = request.into_inner().name;
+        let mut book = self.contacts()?;
+        if book.remove(&name).is_none() {
+            return Err(Status::not_found(format!("連絡先が見つかりません: {}", name)));
+        }
+        book.save().map_err(|e| Status::internal(format!("アドレス帳を保存できません: {}", e)))?;
+        Ok(Response::new(proto::RemoveContactResponse {}))
+    }
+}
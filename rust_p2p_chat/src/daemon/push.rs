@@ -0,0 +1,135 @@
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+use super::Daemon;
+use crate::config::{PushConfig, PushKind};
+
+// config.toml の [push]: デーモンに端末やアプリがつながっていない (ctl events・gRPCで出来事を購読していない) 間に
+// 相手のメッセージが届いたら、プッシュ通知の中継サーバーに「メッセージが届いている」ことを知らせ、スマートフォンで気付けるようにする
+//
+//     unifiedpush  {"event": "message_waiting", "unread": 3}                        をエンドポイントにPOST
+//     gateway      {"notification": {"counts": {"unread": 3}, "devices": [...], ...}} (Matrixのプッシュゲートウェイの形式)
+//
+// 本文と送り主は中継サーバーに渡さない (include_room で部屋の名前だけは含められる)
+
+// 1回の送信を待つ上限
+#[cfg(feature = "discovery")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// UnifiedPushの中継サーバーが、届けられない端末のために通知を保っておく秒数
+#[cfg(feature = "discovery")]
+const PUSH_TTL_SECS: u64 = 24 * 60 * 60;
+
+// 部屋の出来事を見張り、購読しているものがいない間に届いたメッセージを知らせる
+pub(super) async fn watch(daemon: Arc<Daemon>) {
+    let mut events = daemon.events.subscribe();
+    // 最後に購読しているものがいたとき以降に届いたメッセージの数
+    let mut unread = 0;
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        let line = match events.recv().await {
+            Ok(line) => line,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!(skipped, "読み取りが遅れたため、プッシュ通知で数えられなかったメッセージがあります");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        // このタスクの他に購読しているもの (端末やアプリ) があれば、そちらで気付ける
+        if daemon.events.receiver_count() > 1 {
+            unread = 0;
+            continue;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if event["event"] != "message" {
+            continue;
+        }
+        unread += 1;
+        let config = daemon.options.config.borrow().push.clone();
+        let Some(endpoint) = config.endpoint.clone() else {
+            continue;
+        };
+        if last_sent.is_some_and(|at| at.elapsed() < Duration::from_secs(config.min_interval_secs)) {
+            continue;
+        }
+        let room = event["room"].as_str().filter(|_| config.include_room);
+        let body = match payload(&config, unread, room) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!(error = %e, "プッシュ通知の設定が足りないため、知らせられません");
+                continue;
+            }
+        };
+        last_sent = Some(Instant::now());
+        let kind = config.kind;
+        tokio::spawn(async move {
+            match post(&endpoint, kind, body).await {
+                Ok(()) => tracing::debug!(endpoint = %endpoint, unread, "プッシュ通知の中継サーバーに知らせました"),
+                Err(e) => tracing::warn!(endpoint = %endpoint, error = %e, "プッシュ通知を送れませんでした"),
+            }
+        });
+    }
+}
+
+// 中継サーバーに送る本文
+fn payload(config: &PushConfig, unread: u64, room: Option<&str>) -> Result<String, String> {
+    let body = match config.kind {
+        PushKind::UnifiedPush => {
+            let mut body = json!({ "event": "message_waiting", "unread": unread });
+            if let Some(room) = room {
+                body["room"] = json!(room);
+            }
+            body
+        }
+        PushKind::Gateway => {
+            let (Some(app_id), Some(pushkey)) = (&config.app_id, &config.pushkey) else {
+                return Err("[push] kind = \"gateway\" では app_id と pushkey が必要です".to_string());
+            };
+            let mut notification = json!({
+                "type": "p2pchat.message_waiting",
+                "prio": "high",
+                "counts": { "unread": unread },
+                "devices": [{ "app_id": app_id, "pushkey": pushkey }],
+            });
+            if let Some(room) = room {
+                notification["room_name"] = json!(room);
+            }
+            json!({ "notification": notification })
+        }
+    };
+    Ok(body.to_string())
+}
+
+#[cfg(feature = "discovery")]
+async fn post(endpoint: &str, kind: PushKind, body: String) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!("p2pchat/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut request = client.post(endpoint).header("Content-Type", "application/json").body(body);
+    if kind == PushKind::UnifiedPush {
+        request = request.header("TTL", PUSH_TTL_SECS.to_string()).header("Urgency", "high");
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+    // プッシュゲートウェイは、登録が切れた端末のプッシュキーを rejected で返す
+    if kind == PushKind::Gateway {
+        let reply: Value = response.json().await.unwrap_or_default();
+        if reply["rejected"].as_array().is_some_and(|rejected| !rejected.is_empty()) {
+            return Err("プッシュゲートウェイにプッシュキーを断られました (端末で登録し直してください)".to_string());
+        }
+    }
+    Ok(())
+}
+
+// discovery フィーチャー (HTTPクライアント) を含めずにビルドした場合は送れない
+#[cfg(not(feature = "discovery"))]
+async fn post(_endpoint: &str, _kind: PushKind, _body: String) -> Result<(), String> {
+    Err("プッシュ通知の送信は含まれていません (discoveryフィーチャー)".to_string())
+}
@@ -0,0 +1,271 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::paths::Paths;
+use crate::protocol::Envelope;
+use crate::stats::Stats;
+
+// スナップショットに残す直近のフレーム数
+const MAX_FRAMES: usize = 200;
+// 実行中のスナップショットファイル名の接頭辞 (cache_dir/debug-<pid>-<接続の番号>.json)
+const LIVE_PREFIX: &str = "debug-";
+// プロセス内の接続の番号 (デーモンでは1つのプロセスが複数の接続を持つ)
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+// TLSハンドシェイクで決まったパラメータ
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Negotiated {
+    pub tls_version: Option<String>,
+    pub cipher_suite: Option<String>,
+    pub alpn: Option<String>,
+}
+
+impl Negotiated {
+    pub fn from_connection(conn: &tokio_rustls::rustls::CommonState) -> Self {
+        Self {
+            tls_version: conn.protocol_version().map(|v| format!("{:?}", v)),
+            cipher_suite: conn.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        }
+    }
+}
+
+// 送受信したフレームの記録。本文やファイルの中身は残さない
+#[derive(Debug, Clone, Serialize)]
+struct FrameRecord {
+    at: DateTime<Local>,
+    direction: &'static str,
+    summary: String,
+    bytes: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Snapshot {
+    pid: u32,
+    started_at: DateTime<Local>,
+    updated_at: DateTime<Local>,
+    role: String,
+    peer: String,
+    state: &'static str,
+    negotiated: Option<Negotiated>,
+    active_downloads: usize,
+    stats: Stats,
+    frames: VecDeque<FrameRecord>,
+}
+
+// 実行中の接続の状態。定期的にcache_dirへ書き出し、`debug dump` で集められるようにする
+pub struct LiveState {
+    path: PathBuf,
+    snapshot: Snapshot,
+    dirty: bool,
+}
+
+impl LiveState {
+    pub fn new(cache_dir: &Path, role: &str, peer: &str, negotiated: Option<Negotiated>) -> Self {
+        let pid = std::process::id();
+        Self {
+            path: cache_dir.join(format!("{}{}-{}.json", LIVE_PREFIX, pid, NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed))),
+            snapshot: Snapshot {
+                pid,
+                started_at: Local::now(),
+                updated_at: Local::now(),
+                role: role.to_string(),
+                peer: peer.to_string(),
+                state: "connected",
+                negotiated,
+                active_downloads: 0,
+                stats: Stats::default(),
+                frames: VecDeque::new(),
+            },
+            dirty: true,
+        }
+    }
+
+    pub fn set_peer(&mut self, peer: &str) {
+        self.snapshot.peer = peer.to_string();
+        self.dirty = true;
+    }
+
+    pub fn set_state(&mut self, state: &'static str) {
+        self.snapshot.state = state;
+        self.dirty = true;
+    }
+
+    pub fn set_active_downloads(&mut self, count: usize) {
+        if self.snapshot.active_downloads != count {
+            self.snapshot.active_downloads = count;
+            self.dirty = true;
+        }
+    }
+
+    pub fn frame_in(&mut self, message: &Message) {
+        self.snapshot.stats.frame_received(message.len());
+        self.push_frame("in", message);
+    }
+
+    pub fn frame_out(&mut self, message: &Message) {
+        self.snapshot.stats.frame_sent(message.len());
+        self.push_frame("out", message);
+    }
+
+    pub fn message_in(&mut self) {
+        self.snapshot.stats.messages_received += 1;
+        self.dirty = true;
+    }
+
+    pub fn message_out(&mut self) {
+        self.snapshot.stats.messages_sent += 1;
+        self.dirty = true;
+    }
+
+    pub fn set_reconnects(&mut self, reconnects: u32) {
+        self.snapshot.stats.reconnects = reconnects;
+        self.dirty = true;
+    }
+
+    // 接続時間と転送速度を更新した統計
+    pub fn stats(&mut self) -> Stats {
+        self.snapshot.stats.refresh();
+        self.snapshot.stats.clone()
+    }
+
+    fn push_frame(&mut self, direction: &'static str, message: &Message) {
+        if self.snapshot.frames.len() == MAX_FRAMES {
+            self.snapshot.frames.pop_front();
+        }
+        let summary = describe(message);
+        tracing::debug!(direction, bytes = message.len(), frame = %summary, "フレーム");
+        self.snapshot.frames.push_back(FrameRecord {
+            at: Local::now(),
+            direction,
+            summary,
+            bytes: message.len(),
+        });
+        self.dirty = true;
+    }
+
+    // 変更があればスナップショットを書き出す
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.snapshot.updated_at = Local::now();
+        self.snapshot.stats.refresh();
+        let result = serde_json::to_string_pretty(&self.snapshot)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&self.path, json));
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(e) => tracing::debug!(error = %e, "デバッグ用スナップショットを書き出せませんでした"),
+        }
+    }
+}
+
+impl Drop for LiveState {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// フレームの種類と番号だけを残し、本文・ファイルの中身・トークンは伏せる
+fn describe(message: &Message) -> String {
+    match message {
+        Message::Text(text) => match Envelope::decode(text) {
+            Envelope::Chat { seq, sig, .. } => format!("chat seq={} signed={}", seq, sig.is_some()),
+            Envelope::Identity { .. } => "identity".to_string(),
+            Envelope::BackfillRequest { since } => format!("backfill_request since={}", since),
+            Envelope::Resume { token, since } => format!("resume token={} since={}", token.is_some(), since),
+            Envelope::Session { resumed, .. } => format!("session resumed={}", resumed),
+            Envelope::Backfill { messages } => format!("backfill messages={}", messages.len()),
+            Envelope::FileStart { id, size, .. } => format!("file_start id={} size={}", id, size),
+            Envelope::FileChunk { id, offset, .. } => format!("file_chunk id={} offset={}", id, offset),
+            Envelope::FileEnd { id } => format!("file_end id={}", id),
+            Envelope::FileAck { id, received } => format!("file_ack id={} received={}", id, received),
+            Envelope::FileCancel { id } => format!("file_cancel id={}", id),
+            Envelope::StreamStart { id, .. } => format!("stream_start id={}", id),
+            Envelope::StreamEnd { id, size, .. } => format!("stream_end id={} size={}", id, size),
+            Envelope::FileReceived { id, ok } => format!("file_received id={} ok={}", id, ok),
+            Envelope::Delivered { seq } => format!("delivered seq={}", seq),
+            Envelope::Ping { sent_at } => format!("ping sent_at={}", sent_at),
+            Envelope::Pong { sent_at } => format!("pong sent_at={}", sent_at),
+        },
+        Message::Binary(_) => "binary".to_string(),
+        Message::Ping(_) => "ping".to_string(),
+        Message::Pong(_) => "pong".to_string(),
+        Message::Close(frame) => match frame {
+            Some(frame) => format!("close code={}", frame.code),
+            None => "close".to_string(),
+        },
+        Message::Frame(_) => "frame".to_string(),
+    }
+}
+
+// 保存データの概要 (件数とサイズのみ。鍵・トークン・本文は含めない)
+#[derive(Serialize)]
+struct StoredState {
+    profile: String,
+    data_dir: PathBuf,
+    config_dir: PathBuf,
+    schema: Option<serde_json::Value>,
+    history_bytes: Option<u64>,
+    history_encrypted: bool,
+    contacts: usize,
+    known_peers: usize,
+    drafts: usize,
+}
+
+#[derive(Serialize)]
+struct Dump {
+    created_at: DateTime<Local>,
+    version: &'static str,
+    os: &'static str,
+    stored: StoredState,
+    config: serde_json::Value,
+    connections: Vec<serde_json::Value>,
+}
+
+// 保存データの概要と、実行中の接続のスナップショットをまとめてoutputに書き出す
+pub fn dump(paths: &Paths, config: &crate::config::Config, output: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let schema = std::fs::read_to_string(paths.schema_file())
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok());
+    let contacts = crate::contacts::AddressBook::load(paths.contacts_file())?.iter().count();
+    let known_peers = crate::trust::KnownPeers::load(paths.known_peers_file())?.iter().count();
+    let drafts = crate::drafts::Drafts::load(paths.drafts_file())?.count();
+
+    let mut connections = Vec::new();
+    for entry in std::fs::read_dir(&paths.cache_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(LIVE_PREFIX) && name.ends_with(".json") {
+            if let Ok(snapshot) = serde_json::from_str(&std::fs::read_to_string(entry.path())?) {
+                connections.push(snapshot);
+            }
+        }
+    }
+
+    let dump = Dump {
+        created_at: Local::now(),
+        version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        stored: StoredState {
+            profile: paths.profile.clone(),
+            data_dir: paths.data_dir.clone(),
+            config_dir: paths.config_dir.clone(),
+            schema,
+            history_bytes: std::fs::metadata(paths.history_file()).ok().map(|m| m.len()),
+            history_encrypted: paths.history_key_file().exists(),
+            contacts,
+            known_peers,
+            drafts,
+        },
+        config: serde_json::to_value(config)?,
+        connections,
+    };
+    std::fs::write(output, serde_json::to_string_pretty(&dump)?)?;
+    Ok(dump.connections.len())
+}
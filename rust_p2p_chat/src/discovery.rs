@@ -0,0 +1,9 @@
+// 自分のIPアドレスを調べる (接続を待ち受けるときに、相手に伝えるURLを表示するため)
+// 調べ方は ip::IpSource で、どれを使うかは config.toml の [discovery] で決める
+// dns は、待受の情報をDNSのTXTレコードで公開し、ドメイン名から接続先を調べる
+// mdns は、待受を同じLANにmDNS/DNS-SDで知らせ、LANの待受を探す (discover)
+
+pub mod dns;
+pub mod ip;
+#[cfg(feature = "mdns")]
+pub mod mdns;
@@ -0,0 +1,237 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::contacts::AddressBook;
+
+// DNSのTXTレコード (_p2pchat.<ドメイン>) で、待受のアドレス・ポート・フィンガープリントを公開する
+//
+//     _p2pchat.alice.example.org. 300 IN TXT "v=p2pchat1 addr=203.0.113.5 port=8080 fp=sha256:..."
+//
+// 接続する側は `connect alice.example.org` だけで wss://203.0.113.5:8080#sha256:... に接続する
+// DNSSECは検証しないため、フィンガープリントは改ざんされていないDNSの応答を前提にした照合になる
+
+// レコード名の先頭に付けるラベル
+pub const LABEL: &str = "_p2pchat";
+// TXTの先頭に書く形式の版
+const VERSION: &str = "v=p2pchat1";
+// 1回の問い合わせを待つ上限と、UDPで送り直す回数
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const QUERY_ATTEMPTS: usize = 2;
+// レコードの種類とクラス
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+// TXTレコードに書く待受の情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    /// 接続先のホスト (IPアドレスまたはホスト名)
+    pub host: String,
+    pub port: u16,
+    /// 証明書のフィンガープリント (sha256:...)
+    pub fingerprint: String,
+}
+
+impl Advertisement {
+    // TXTレコードの値
+    pub fn txt(&self) -> String {
+        format!("{} addr={} port={} fp={}", VERSION, self.host, self.port, self.fingerprint)
+    }
+
+    // ゾーンファイルに書く1行
+    pub fn zone_line(&self, domain: &str) -> String {
+        format!("{}. 300 IN TXT \"{}\"", record_name(domain), self.txt())
+    }
+
+    // 接続先のURI。フィンガープリントはURIの#以降として照合される
+    pub fn uri(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("wss://[{}]:{}#{}", self.host, self.port, self.fingerprint),
+            false => format!("wss://{}:{}#{}", self.host, self.port, self.fingerprint),
+        }
+    }
+
+    pub fn parse(txt: &str) -> Option<Self> {
+        let mut fields = txt.split_whitespace();
+        if fields.next() != Some(VERSION) {
+            return None;
+        }
+        let (mut host, mut port, mut fingerprint) = (None, None, None);
+        for field in fields {
+            match field.split_once('=') {
+                Some(("addr", value)) => host = Some(value.trim_start_matches('[').trim_end_matches(']').to_string()),
+                Some(("port", value)) => port = value.parse().ok(),
+                Some(("fp", value)) if value.starts_with("sha256:") => fingerprint = Some(value.to_string()),
+                // 知らない項目は後の版で増えたものとして無視する
+                _ => {}
+            }
+        }
+        Some(Self { host: host?, port: port?, fingerprint: fingerprint? })
+    }
+}
+
+// ドメインに対応するレコード名 (末尾の . は付けない)
+pub fn record_name(domain: &str) -> String {
+    format!("{}.{}", LABEL, domain.trim_end_matches('.'))
+}
+
+// 接続先の指定がURIでも連絡先の名前でもなく、ドメイン名に見える場合はTXTレコードから接続先のURIを調べる
+// それ以外はそのまま返す。serverを省略した場合は /etc/resolv.conf のDNSサーバーに問い合わせる
+pub async fn resolve_target(target: &str, contacts_file: &Path, server: Option<SocketAddr>) -> Result<String, Box<dyn std::error::Error>> {
+    let looks_like_domain = target.contains('.') && !target.contains(['/', ':', ' ']) && target.parse::<IpAddr>().is_err();
+    if target.contains("://") || !looks_like_domain || AddressBook::load(contacts_file)?.get(target).is_some() {
+        return Ok(target.to_string());
+    }
+    let server = server.or_else(system_server).ok_or("DNSサーバーが分かりません (config.toml の [discovery] dns_server で指定してください)")?;
+    let advertisement = lookup(server, target).await?;
+    tracing::info!(domain = target, uri = %advertisement.uri(), "TXTレコードから接続先を調べました");
+    Ok(advertisement.uri())
+}
+
+// /etc/resolv.conf の最初のnameserver
+pub fn system_server() -> Option<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines().find_map(|line| {
+        let address = line.trim().strip_prefix("nameserver")?.trim();
+        // リンクローカルのIPv6アドレスに付くゾーン (%eth0) は使わない
+        let ip: IpAddr = address.split('%').next()?.parse().ok()?;
+        Some(SocketAddr::new(ip, 53))
+    })
+}
+
+// _p2pchat.<domain> のTXTレコードを問い合わせ、最初に解釈できたものを返す
+pub async fn lookup(server: SocketAddr, domain: &str) -> Result<Advertisement, Box<dyn std::error::Error>> {
+    let name = record_name(domain);
+    let records = query_txt(server, &name).await.map_err(|e| format!("{} のTXTレコードを問い合わせられません: {}", name, e))?;
+    records
+        .iter()
+        .find_map(|txt| Advertisement::parse(txt))
+        .ok_or_else(|| format!("{} に {} のTXTレコードがありません", name, VERSION).into())
+}
+
+// UDPで問い合わせ、応答が切り詰められていた場合はTCPで問い合わせ直す
+async fn query_txt(server: SocketAddr, name: &str) -> Result<Vec<String>, String> {
+    let mut id = [0u8; 2];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id).map_err(|_| "乱数を生成できません".to_string())?;
+    let query = encode_query(u16::from_be_bytes(id), name)?;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|e| e.to_string())?;
+    socket.connect(server).await.map_err(|e| e.to_string())?;
+    let mut buf = vec![0u8; 4096];
+    for _ in 0..QUERY_ATTEMPTS {
+        socket.send(&query).await.map_err(|e| e.to_string())?;
+        let deadline = tokio::time::Instant::now() + QUERY_TIMEOUT;
+        // 別の問い合わせへの応答 (IDが違うもの) は読み捨てる
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let n = received.map_err(|e| e.to_string())?;
+            if buf[..n].starts_with(&query[..2]) {
+                return match parse_response(&buf[..n])? {
+                    Response::Records(records) => Ok(records),
+                    Response::Truncated => query_tcp(server, &query).await,
+                };
+            }
+        }
+    }
+    Err(format!("DNSサーバー ({}) から応答がありません", server))
+}
+
+async fn query_tcp(server: SocketAddr, query: &[u8]) -> Result<Vec<String>, String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(query);
+        stream.write_all(&framed).await?;
+        let length = stream.read_u16().await?;
+        let mut response = vec![0u8; length as usize];
+        stream.read_exact(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(QUERY_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("DNSサーバー ({}) から応答がありません", server))?
+        .map_err(|e| e.to_string())?;
+    match parse_response(&response)? {
+        Response::Records(records) => Ok(records),
+        Response::Truncated => Err("DNSの応答が切り詰められています".to_string()),
+    }
+}
+
+// 再帰的な問い合わせ (RD) で、nameのTXTレコードを1つ尋ねる
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("ドメイン名として使えません: {}", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+enum Response {
+    /// 回答のTXTレコード (1つのレコードの複数の文字列はつなげる)
+    Records(Vec<String>),
+    /// TCで切り詰められた
+    Truncated,
+}
+
+fn parse_response(message: &[u8]) -> Result<Response, String> {
+    let malformed = || "DNSの応答を解釈できません".to_string();
+    let word = |offset: usize| message.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or_else(malformed);
+    let flags = word(2)?;
+    if flags & 0x0200 != 0 {
+        return Ok(Response::Truncated);
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err("ドメインが存在しません (NXDOMAIN)".to_string()),
+        rcode => return Err(format!("DNSサーバーがエラーを返しました (RCODE {})", rcode)),
+    }
+    let (questions, answers) = (word(4)?, word(6)?);
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(message, offset).ok_or_else(malformed)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(message, offset).ok_or_else(malformed)?;
+        let (kind, length) = (word(offset)?, word(offset + 8)? as usize);
+        let data = message.get(offset + 10..offset + 10 + length).ok_or_else(malformed)?;
+        offset += 10 + length;
+        // CNAMEなどはリゾルバーがたどった途中の記録なので飛ばす
+        if kind != TYPE_TXT {
+            continue;
+        }
+        let mut text = Vec::new();
+        let mut rest = data;
+        while let Some((&len, tail)) = rest.split_first() {
+            let chunk = tail.get(..len as usize).ok_or_else(malformed)?;
+            text.extend_from_slice(chunk);
+            rest = &tail[len as usize..];
+        }
+        records.push(String::from_utf8_lossy(&text).into_owned());
+    }
+    Ok(Response::Records(records))
+}
+
+// 名前 (ラベルの並びか圧縮のポインター) の次の位置
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
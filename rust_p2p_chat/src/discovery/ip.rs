@@ -0,0 +1,368 @@
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::DiscoveryConfig;
+
+// 自分のIPアドレスの調べ方 (HTTPのサービス・STUN・経路・ネットワークインターフェース)
+// 設定ファイルでは1つを1つの文字列で書く: "https://api.ipify.org"、"stun:stun.l.google.com:19302"、"route"、"interfaces"
+
+// グローバルIPアドレスの既定の問い合わせ先。すべてに同時に問い合わせ、答えが食い違えば上のものを優先する
+pub const DEFAULT_GLOBAL_SOURCES: [&str; 4] = [
+    "https://api.ipify.org",
+    "https://httpbin.org/ip",
+    "https://icanhazip.com",
+    "stun:stun.l.google.com:19302",
+];
+// ローカルIPアドレスの既定の調べ方
+pub const DEFAULT_LOCAL_SOURCES: [&str; 2] = ["route", "interfaces"];
+
+pub type LookupError = Box<dyn std::error::Error + Send + Sync>;
+
+pub trait IpSource: Send + Sync {
+    /// ログに出す名前 (設定ファイルでの書き方と同じ)。結果を使い回すときの見分けにも使う
+    fn name(&self) -> &str;
+    fn lookup(&self) -> BoxFuture<'_, Result<IpAddr, LookupError>>;
+}
+
+// 設定ファイルの1つの問い合わせ先を解釈する
+pub fn parse_source(spec: &str) -> Result<Box<dyn IpSource>, String> {
+    match spec {
+        "route" => return Ok(Box::new(RouteSource)),
+        "interfaces" => return Ok(Box::new(InterfaceSource)),
+        _ => {}
+    }
+    if spec.starts_with("http://") || spec.starts_with("https://") {
+        #[cfg(feature = "discovery")]
+        return Ok(Box::new(HttpSource { url: spec.to_string() }));
+        #[cfg(not(feature = "discovery"))]
+        return Err(format!("{} は使えません (discoveryフィーチャーを含めずにビルドされています)", spec));
+    }
+    if let Some(server) = spec.strip_prefix("stun:") {
+        #[cfg(feature = "discovery")]
+        return Ok(Box::new(StunSource::new(server)));
+        #[cfg(not(feature = "discovery"))]
+        return Err(format!("{} は使えません (discoveryフィーチャーを含めずにビルドされています)", server));
+    }
+    Err(format!("不明な問い合わせ先です: {}", spec))
+}
+
+// 最初の答えが届いてから、ほかの問い合わせ先の答えと照らし合わせるために待つ時間
+pub const CONFIRM_WAIT: Duration = Duration::from_secs(1);
+
+// 問い合わせ先すべてに同時に問い合わせ、最初に分かったアドレスを返す (応答のない問い合わせ先で待たされないため)
+// 最初の答えからCONFIRM_WAITの間に届いた答えと照らし合わせ、食い違えば多くの問い合わせ先が返したアドレス (同数なら並びの先のもの) を使う
+// 結果はttlの間プロセスの中で使い回す (デーモンで待ち受け直すたびに、失敗する問い合わせ先から試し直さないため)
+pub struct Resolver {
+    sources: Vec<Box<dyn IpSource>>,
+    ttl: Duration,
+}
+
+impl Resolver {
+    pub fn new(sources: Vec<Box<dyn IpSource>>, ttl: Duration) -> Self {
+        Self { sources, ttl }
+    }
+
+    // [discovery] のglobalから作る。解釈できない問い合わせ先は警告して飛ばす
+    pub fn global(config: &DiscoveryConfig) -> Self {
+        Self::from_specs(&config.global, config.cache_ttl())
+    }
+
+    pub fn local(config: &DiscoveryConfig) -> Self {
+        Self::from_specs(&config.local, config.cache_ttl())
+    }
+
+    fn from_specs(specs: &[String], ttl: Duration) -> Self {
+        let sources = specs
+            .iter()
+            .filter_map(|spec| {
+                parse_source(spec)
+                    .inspect_err(|e| tracing::warn!(error = %e, "IPアドレスの問い合わせ先を飛ばします"))
+                    .ok()
+            })
+            .collect();
+        Self::new(sources, ttl)
+    }
+
+    // cancelが取り消されたら、答えを待つのをやめる
+    pub async fn lookup(&self, cancel: &CancellationToken) -> Result<IpAddr, Box<dyn std::error::Error>> {
+        if self.sources.is_empty() {
+            return Err("IPアドレスの問い合わせ先がありません".into());
+        }
+        // 同じ問い合わせ先の並びなら、前回の結果を使い回す
+        let key = self.sources.iter().map(|source| source.name()).collect::<Vec<_>>().join(" ");
+        if let Some(ip) = cached(&key, self.ttl) {
+            tracing::debug!(%ip, "前回調べたIPアドレスを使います");
+            return Ok(ip);
+        }
+        let mut pending: FuturesUnordered<_> = self
+            .sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| async move { (index, source.lookup().await) })
+            .collect();
+        // 届いたアドレスと、それを返した問い合わせ先の並びの位置
+        let mut answers: Vec<(usize, IpAddr)> = Vec::new();
+        let mut deadline = None;
+        loop {
+            let confirm = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let next = tokio::select! {
+                next = pending.next() => next,
+                _ = confirm => None,
+                _ = cancel.cancelled() => return Err("IPアドレスの取得を中止しました".into()),
+            };
+            let Some((index, result)) = next else {
+                break;
+            };
+            match result {
+                Ok(ip) => {
+                    let confirmed = answers.iter().any(|(_, seen)| *seen == ip);
+                    answers.push((index, ip));
+                    // 2つの問い合わせ先の答えが一致すれば、残りは待たない
+                    if confirmed {
+                        break;
+                    }
+                    deadline.get_or_insert_with(|| tokio::time::Instant::now() + CONFIRM_WAIT);
+                }
+                Err(e) => tracing::warn!(source = self.sources[index].name(), error = %e, "IP取得に失敗"),
+            }
+        }
+        let ip = choose(&answers).ok_or("すべての問い合わせ先からの取得に失敗しました")?;
+        if answers.iter().any(|(_, seen)| *seen != ip) {
+            let answers: Vec<String> = answers.iter().map(|(index, seen)| format!("{}={}", self.sources[*index].name(), seen)).collect();
+            tracing::warn!(%ip, answers = %answers.join(" "), "問い合わせ先によってIPアドレスが食い違います");
+        }
+        store(&key, ip);
+        Ok(ip)
+    }
+}
+
+// 多くの問い合わせ先が返したアドレス。同数なら並びの先の問い合わせ先が返したもの
+fn choose(answers: &[(usize, IpAddr)]) -> Option<IpAddr> {
+    let votes = |ip: IpAddr| answers.iter().filter(|(_, seen)| *seen == ip).count();
+    answers
+        .iter()
+        .max_by_key(|(index, ip)| (votes(*ip), std::cmp::Reverse(*index)))
+        .map(|(_, ip)| *ip)
+}
+
+type Cache = Mutex<HashMap<String, (Instant, IpAddr)>>;
+
+fn cache() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(Cache::default)
+}
+
+fn cached(name: &str, ttl: Duration) -> Option<IpAddr> {
+    let cache = cache().lock().unwrap_or_else(|e| e.into_inner());
+    cache.get(name).filter(|(at, _)| at.elapsed() < ttl).map(|(_, ip)| *ip)
+}
+
+fn store(name: &str, ip: IpAddr) {
+    cache().lock().unwrap_or_else(|e| e.into_inner()).insert(name.to_string(), (Instant::now(), ip));
+}
+
+// 外向きの経路で使われるアドレス。ダミーの外部アドレスにUDPソケットをつなぐだけで、パケットは送らない
+struct RouteSource;
+
+impl IpSource for RouteSource {
+    fn name(&self) -> &str {
+        "route"
+    }
+
+    fn lookup(&self) -> BoxFuture<'_, Result<IpAddr, LookupError>> {
+        Box::pin(async {
+            let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect("8.8.8.8:80")?;
+            Ok(socket.local_addr()?.ip())
+        })
+    }
+}
+
+// ネットワークインターフェースの一覧から、ループバックとリンクローカル以外の最初のアドレス (IPv4を優先する)
+struct InterfaceSource;
+
+impl IpSource for InterfaceSource {
+    fn name(&self) -> &str {
+        "interfaces"
+    }
+
+    fn lookup(&self) -> BoxFuture<'_, Result<IpAddr, LookupError>> {
+        Box::pin(async {
+            let interfaces = if_addrs::get_if_addrs()?;
+            let candidates: Vec<IpAddr> = interfaces
+                .iter()
+                .filter(|interface| interface.is_oper_up() && !interface.is_loopback() && !interface.is_link_local())
+                .map(|interface| interface.ip())
+                .collect();
+            candidates
+                .iter()
+                .find(|ip| ip.is_ipv4())
+                .or_else(|| candidates.first())
+                .copied()
+                .ok_or_else(|| "使えるネットワークインターフェースがありません".into())
+        })
+    }
+}
+
+// 自分のアドレスをテキストかJSON ({"origin": "x.x.x.x"} など) で返すHTTPのサービス
+#[cfg(feature = "discovery")]
+struct HttpSource {
+    url: String,
+}
+
+#[cfg(feature = "discovery")]
+impl IpSource for HttpSource {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn lookup(&self) -> BoxFuture<'_, Result<IpAddr, LookupError>> {
+        Box::pin(async {
+            let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+            let text = client.get(&self.url).send().await?.error_for_status()?.text().await?;
+            let text = match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(json) => ["origin", "ip"].iter().find_map(|key| json[key].as_str()).unwrap_or_default().to_string(),
+                Err(_) => text,
+            };
+            // プロキシを経由すると "x.x.x.x, y.y.y.y" のように複数返るので、最初のものを使う
+            let ip = text.split(',').next().unwrap_or_default().trim();
+            ip.parse().map_err(|_| format!("無効なIPアドレス形式: {}", ip).into())
+        })
+    }
+}
+
+// STUNサーバーに尋ねた、NATの外から見えるアドレス (RFC 5389のBinding)
+#[cfg(feature = "discovery")]
+struct StunSource {
+    name: String,
+    server: String,
+}
+
+// 応答を待つ時間と、届かなかった場合に送り直す回数 (UDPなので要求か応答が失われることがある)
+#[cfg(feature = "discovery")]
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(feature = "discovery")]
+const STUN_ATTEMPTS: usize = 3;
+#[cfg(feature = "discovery")]
+const STUN_DEFAULT_PORT: u16 = 3478;
+#[cfg(feature = "discovery")]
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+
+#[cfg(feature = "discovery")]
+impl StunSource {
+    // ポートを省略した場合はSTUNの既定のポートを使う
+    fn new(server: &str) -> Self {
+        let server = match server.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+            _ => format!("{}:{}", server, STUN_DEFAULT_PORT),
+        };
+        Self { name: format!("stun:{}", server), server }
+    }
+}
+
+#[cfg(feature = "discovery")]
+impl IpSource for StunSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn lookup(&self) -> BoxFuture<'_, Result<IpAddr, LookupError>> {
+        Box::pin(async {
+            use ring::rand::{SecureRandom, SystemRandom};
+
+            let server = tokio::net::lookup_host(&self.server).await?.next().ok_or("STUNサーバーの名前を解決できません")?;
+            let socket = tokio::net::UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+            socket.connect(server).await?;
+            let mut transaction = [0u8; 12];
+            SystemRandom::new().fill(&mut transaction).map_err(|_| "乱数の生成に失敗しました")?;
+            let request = stun_binding_request(&transaction);
+            let mut buf = [0u8; 512];
+            for _ in 0..STUN_ATTEMPTS {
+                socket.send(&request).await?;
+                let Ok(received) = tokio::time::timeout(STUN_TIMEOUT, socket.recv(&mut buf)).await else {
+                    continue;
+                };
+                if let Some(ip) = stun_mapped_address(&buf[..received?], &transaction) {
+                    return Ok(ip);
+                }
+            }
+            Err("STUNサーバーから応答がありません".into())
+        })
+    }
+}
+
+#[cfg(feature = "discovery")]
+fn stun_binding_request(transaction: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&0x0001u16.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction);
+    request
+}
+
+// Bindingの成功応答からアドレスを取り出す。XOR-MAPPED-ADDRESSを優先し、古いサーバー向けにMAPPED-ADDRESSも読む
+#[cfg(feature = "discovery")]
+fn stun_mapped_address(packet: &[u8], transaction: &[u8; 12]) -> Option<IpAddr> {
+    const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    const MAPPED_ADDRESS: u16 = 0x0001;
+
+    let header = packet.get(..20)?;
+    if u16::from_be_bytes([header[0], header[1]]) != 0x0101
+        || header[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || header[8..20] != transaction[..]
+    {
+        return None;
+    }
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut attributes = packet.get(20..20 + length)?;
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let size = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + size)?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return stun_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = stun_address(value, None),
+            _ => {}
+        }
+        // 属性は4バイト境界に揃えられている
+        attributes = attributes.get((4 + size).next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped
+}
+
+// アドレスの属性 (予約1バイト・種類・ポート・アドレス) を読む。transactionがあればXORを戻す
+#[cfg(feature = "discovery")]
+fn stun_address(value: &[u8], transaction: Option<&[u8; 12]>) -> Option<IpAddr> {
+    let xor = |bytes: &[u8]| -> Vec<u8> {
+        match transaction {
+            Some(transaction) => {
+                let key = STUN_MAGIC_COOKIE.to_be_bytes().into_iter().chain(transaction.iter().copied());
+                bytes.iter().zip(key).map(|(b, k)| b ^ k).collect()
+            }
+            None => bytes.to_vec(),
+        }
+    };
+    match value.get(1)? {
+        0x01 => {
+            let octets: [u8; 4] = xor(value.get(4..8)?).try_into().ok()?;
+            Some(IpAddr::from(octets))
+        }
+        0x02 => {
+            let octets: [u8; 16] = xor(value.get(4..20)?).try_into().ok()?;
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
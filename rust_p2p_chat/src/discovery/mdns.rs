@@ -0,0 +1,110 @@
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+// 同じLANの相手に、待受をmDNS/DNS-SD (_p2pchat._tcp.local) で知らせる・探す
+// TXTには表示名 (nick) と証明書のフィンガープリント (fp) を入れ、discover では wss://<アドレス>:<ポート>#<fp> として一覧する
+
+pub const SERVICE_TYPE: &str = "_p2pchat._tcp.local.";
+
+// 知らせている間の登録。落とすと取り消す (相手の一覧からも消える)
+pub struct Announcement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Announcement {
+    // addrで待ち受けている待受を知らせる。ループバックアドレスではLANから接続できないため知らせない
+    pub fn register(addr: SocketAddr, name: &str, fingerprint: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if addr.ip().is_loopback() {
+            tracing::debug!(%addr, "ループバックアドレスで待ち受けているため、LANには知らせません");
+            return Ok(None);
+        }
+        let daemon = ServiceDaemon::new()?;
+        // 同じ表示名の待受がLANに複数あっても区別できるよう、フィンガープリントの先頭を付ける
+        let short = fingerprint.trim_start_matches("sha256:").get(..8).unwrap_or_default();
+        let instance = format!("{} ({})", name, short);
+        let host = format!("p2pchat-{}.local.", short);
+        let properties = [("nick", name), ("fp", fingerprint)];
+        let info = match addr.ip().is_unspecified() {
+            // すべてのインターフェースで待ち受けている場合は、インターフェースのアドレスをすべて知らせる
+            true => ServiceInfo::new(SERVICE_TYPE, &instance, &host, "", addr.port(), &properties[..])?.enable_addr_auto(),
+            false => ServiceInfo::new(SERVICE_TYPE, &instance, &host, addr.ip(), addr.port(), &properties[..])?,
+        };
+        let fullname = info.get_fullname().to_string();
+        daemon.register(info)?;
+        tracing::info!(instance = %instance, port = addr.port(), "mDNSで待受をLANに知らせています");
+        Ok(Some(Self { daemon, fullname }))
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+// LANで見つかった待受
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanPeer {
+    /// DNS-SDのインスタンス名 (例: alice (069346da))
+    pub instance: String,
+    pub nickname: Option<String>,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub fingerprint: Option<String>,
+}
+
+impl LanPeer {
+    // 接続先のURI。フィンガープリントが分かれば#以降に付けて照合させる
+    // LANの別のマシンから届くアドレスを選ぶ (IPv4、リンクローカル以外のIPv6、ループバックの順)
+    pub fn uri(&self) -> Option<String> {
+        let rank = |ip: &&IpAddr| match ip {
+            ip if ip.is_loopback() => 3,
+            IpAddr::V4(_) => 0,
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => 2,
+            IpAddr::V6(_) => 1,
+        };
+        let address = self.addresses.iter().min_by_key(rank)?;
+        let host = match address {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        let pin = self.fingerprint.as_ref().map(|fingerprint| format!("#{}", fingerprint)).unwrap_or_default();
+        Some(format!("wss://{}:{}{}", host, self.port, pin))
+    }
+}
+
+// durationの間LANを探し、見つかった待受を名前の順に返す (途中で消えたものは含めない)
+pub async fn browse(duration: Duration) -> Result<Vec<LanPeer>, Box<dyn std::error::Error>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let mut found = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + duration;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                let mut addresses: Vec<IpAddr> = service.get_addresses().iter().map(|ip| ip.to_ip_addr()).collect();
+                addresses.sort();
+                let instance = service.get_fullname().trim_end_matches(SERVICE_TYPE).trim_end_matches('.').to_string();
+                let peer = LanPeer {
+                    instance,
+                    nickname: service.get_property_val_str("nick").map(str::to_string),
+                    addresses,
+                    port: service.get_port(),
+                    fingerprint: service.get_property_val_str("fp").filter(|fp| fp.starts_with("sha256:")).map(str::to_string),
+                };
+                found.insert(service.get_fullname().to_string(), peer);
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                found.remove(&fullname);
+            }
+            _ => {}
+        }
+    }
+    let _ = daemon.stop_browse(SERVICE_TYPE);
+    let _ = daemon.shutdown();
+    Ok(found.into_values().collect())
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// 送信できなかったメッセージ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Draft {
+    pub saved_at: DateTime<Local>,
+    pub body: String,
+    /// mail gatewayで相手に知らせた日時 (同じ下書きを何度も知らせない)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mailed_at: Option<DateTime<Local>>,
+}
+
+// 相手 → 送信できなかったメッセージ の一覧。次にその相手と接続したときに送信するか選べる
+pub struct Drafts {
+    path: PathBuf,
+    drafts: BTreeMap<String, Vec<Draft>>,
+}
+
+impl Drafts {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let drafts = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, drafts })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.drafts.is_empty() && !self.path.exists() {
+            return Ok(());
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.drafts)?)?;
+        Ok(())
+    }
+
+    pub fn push(&mut self, peer: &str, body: String) {
+        self.drafts.entry(peer.to_string()).or_default().push(Draft {
+            saved_at: Local::now(),
+            body,
+            mailed_at: None,
+        });
+    }
+
+    pub fn get(&self, peer: &str) -> &[Draft] {
+        self.drafts.get(peer).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    // すべての相手の下書きの件数
+    pub fn count(&self) -> usize {
+        self.drafts.values().map(Vec::len).sum()
+    }
+
+    // 下書きのある相手の名前
+    pub fn peers(&self) -> Vec<String> {
+        self.drafts.keys().cloned().collect()
+    }
+
+    // 相手の下書きを書き換える (空にした相手は一覧から消す)
+    pub fn replace(&mut self, peer: &str, drafts: Vec<Draft>) {
+        match drafts.is_empty() {
+            true => self.drafts.remove(peer),
+            false => self.drafts.insert(peer.to_string(), drafts),
+        };
+    }
+
+    // 相手の下書きをすべて取り出す (取り出した分は削除される)
+    pub fn take(&mut self, peer: &str) -> Vec<Draft> {
+        self.drafts.remove(peer).unwrap_or_default()
+    }
+}
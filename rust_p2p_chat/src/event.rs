@@ -0,0 +1,102 @@
+use crate::commands::DraftsAction;
+use crate::history::{Direction, HistoryEntry};
+use crate::ui::Verification;
+use chrono::{DateTime, Local};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// チャットのセッションと画面 (plain・TUI・デーモンの部屋・組み込み先) の間でやり取りする出来事とコマンド
+// セッションは出来事を送るだけで、どう表示するかは受け取る側が決める
+
+// セッションから画面への出来事
+#[derive(Debug, Clone)]
+pub enum Event {
+    PeerConnected { peer: String },
+    /// 履歴に残っている以前のメッセージ (接続した直後に1回だけ送る)
+    Earlier { entries: Vec<HistoryEntry> },
+    /// 相手のメッセージ。seqは相手側の通し番号 (0は不明)、sent_atは再送されたメッセージの元の送信日時
+    MessageReceived { from: String, seq: u64, body: String, sent_at: Option<DateTime<Local>> },
+    /// 自分が送信したメッセージ
+    MessageSent { seq: u64, body: String },
+    /// 自分のメッセージが相手に届いた
+    DeliveryAck { seq: u64 },
+    /// ファイルの送受信の進み具合。totalはストリームの場合は送り終わるまで分からない
+    TransferProgress { id: u64, name: String, direction: Direction, bytes: u64, total: Option<u64> },
+    Info { text: String },
+    Warning { text: String },
+    /// セッションを再開し、履歴上の相手の名前が前回のものに切り替わった
+    PeerRenamed { peer: String },
+    /// 相手が前回のセッションを引き継いだ
+    Resumed,
+    /// 相手の証明書がknown_peersに記録されているか
+    Verification(Verification),
+    /// 直近の往復時間と平均
+    Latency { last: Duration, average: Duration },
+    /// 接続が終わった。これが最後の出来事になる
+    Disconnected { peer: String, reason: DisconnectReason },
+}
+
+// 接続が終わった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// 相手が切断した (Closeフレームに理由があればcodeとreason)
+    PeerClosed { code: Option<u16>, reason: String },
+    /// Closeフレームなしに接続が閉じられた
+    StreamEnded,
+    /// 送受信に失敗した
+    Error(String),
+    /// こちらから切断した (SessionCommand::Close)
+    Closed,
+    /// コマンドの送り元がなくなった (入力の終端など)
+    InputClosed,
+    /// やり取りのない時間が [listen] idle_timeout_mins を超えたため、こちら (待受側) から切断した
+    Idle,
+    /// やり取りのない時間が続いたため、相手 (待受側) が切断した。ネットワークの障害ではない
+    PeerIdle,
+    /// Pingへの応答が [connection] missed_pongs 回続けて届かなかった (相手に届かなくなったとみなした)
+    Unreachable,
+}
+
+// やり取りのない時間が続いたために切断するときのCloseフレームの理由 (受け取った側はPeerIdleとして扱う)
+pub const IDLE_CLOSE_REASON: &str = "idle";
+
+// 画面からセッションへのコマンド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionCommand {
+    SendText(String),
+    SendFile(PathBuf),
+    /// ファイルの転送を取り消す。idは送信ならこちらの、受信なら相手の転送の番号
+    CancelTransfer { id: u64, direction: Direction },
+    /// 相手・証明書・往復時間の案内を出す (/who)
+    Who,
+    Stats,
+    Ping,
+    Drafts(DraftsAction),
+    /// フックが追加したコマンド (/<name> <args>)
+    Custom { name: String, args: String },
+    /// 相手に切断を知らせて終了する
+    Close,
+}
+
+// セッションが出来事を送る口。受け取る側がいなくなっても (画面を閉じても) 処理は続ける
+#[derive(Clone)]
+pub(crate) struct Events(mpsc::UnboundedSender<Event>);
+
+impl Events {
+    pub(crate) fn new(sender: mpsc::UnboundedSender<Event>) -> Self {
+        Self(sender)
+    }
+
+    pub(crate) fn send(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn info(&self, text: impl Into<String>) {
+        self.send(Event::Info { text: text.into() });
+    }
+
+    pub(crate) fn warn(&self, text: impl Into<String>) {
+        self.send(Event::Warning { text: text.into() });
+    }
+}
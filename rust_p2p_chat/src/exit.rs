@@ -0,0 +1,73 @@
+use crate::transport::ConnectError;
+use std::error::Error;
+
+// コマンドラインの終了コード (READMEの一覧と同じ)。ラッパーやスクリプトが失敗の原因で処理を分けられるようにする
+// 1〜4は以前からのもの (2〜4はsendだけが使う)、10以降は接続の失敗の種類
+
+pub const FAILURE: i32 = 1;
+/// send: 接続できなかった (原因が分からない場合。分かる場合は10以降)
+pub const SEND_CONNECT: i32 = 2;
+/// send: 受信確認が届かなかった
+pub const SEND_NOT_DELIVERED: i32 = 3;
+/// send: 相手がファイルを受け取らなかった
+pub const SEND_REFUSED: i32 = 4;
+/// 接続先の名前を解決できなかった
+pub const DNS: i32 = 10;
+/// 接続を拒否された (相手が待ち受けていない・ファイアウォール)
+pub const CONNECTION_REFUSED: i32 = 11;
+/// TLSハンドシェイクに失敗した
+pub const TLS: i32 = 12;
+/// 相手の証明書が連絡先・known_peers・URLのフィンガープリントと一致しない、または相手に接続を断られた
+pub const REJECTED: i32 = 13;
+/// その他の理由でTCP接続できなかった (経路がない・時間切れ・プロキシの失敗)
+pub const UNREACHABLE: i32 = 14;
+/// TCP接続・TLSハンドシェイク・WebSocketハンドシェイクのいずれかが設定した時間内に終わらなかった
+pub const TIMEOUT: i32 = 15;
+/// Ctrl+Cなどで中止した
+pub const CANCELLED: i32 = 130;
+
+// --error-format: 終了する前のエラーの書き方 (標準エラー出力)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// 「<場面>: <メッセージ>」の1行
+    #[default]
+    Text,
+    /// {"error": {"kind", "code", "context", "message"}} の1行のJSON
+    Json,
+}
+
+// 終了の原因になったエラーの種類 (kindはJSONに書く名前)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failure {
+    pub code: i32,
+    pub kind: &'static str,
+}
+
+impl Failure {
+    pub const OTHER: Failure = Failure { code: FAILURE, kind: "other" };
+
+    // エラーとその原因 (source) をたどり、種類の分かる最初のエラーから決める
+    pub fn of(error: &(dyn Error + 'static)) -> Failure {
+        let mut current = Some(error);
+        while let Some(error) = current {
+            if let Some(connect) = error.downcast_ref::<ConnectError>() {
+                return connect.failure();
+            }
+            current = error.source();
+        }
+        Failure::OTHER
+    }
+}
+
+// エラーをformatに従って標準エラー出力に書く (終了コードは呼び出し側で使う)
+pub fn report(format: ErrorFormat, context: &str, message: &str, failure: Failure) {
+    match format {
+        ErrorFormat::Text => eprintln!("{}: {}", context, message),
+        ErrorFormat::Json => {
+            let json = serde_json::json!({
+                "error": { "kind": failure.kind, "code": failure.code, "context": context, "message": message }
+            });
+            eprintln!("{}", json);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use crate::event::{DisconnectReason, Event, SessionCommand};
+use crate::history::Direction;
+use crate::paths::{Paths, DEFAULT_PROFILE};
+use crate::{ChatOptions, ChatSession, Peer};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// C言語から使うための入口 (宣言は include/p2pchat.h)。GUIなどの組み込み先が接続・送信し、出来事をコールバックで受け取る
+// 関数は呼び出したスレッドで完了するまで待つ。コールバックはライブラリ内部のスレッドから呼ぶので、コールバックの中でこれらの関数を呼ばない
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+thread_local! {
+    // 直前に失敗した関数のエラー (p2pchat_last_error)
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// 出来事の種類
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum P2pChatEventKind {
+    /// 相手と接続した (peer)
+    Connected,
+    /// 相手のメッセージ (peer・text・seq)
+    MessageReceived,
+    /// 自分のメッセージを送った (text・seq)
+    MessageSent,
+    /// 自分のメッセージが相手に届いた (seq)
+    DeliveryAck,
+    /// ファイルの送受信の進み具合 (transfer_id・text にファイル名・incoming・bytes・total)
+    TransferProgress,
+    /// 案内 (text)
+    Info,
+    /// 警告 (text)
+    Warning,
+    /// 接続が終わった (peer・text に理由)。これが最後の出来事になる
+    Disconnected,
+}
+
+/// コールバックに渡す出来事。文字列はコールバックの間だけ有効 (残す場合は複製する)
+#[repr(C)]
+pub struct P2pChatEvent {
+    pub kind: P2pChatEventKind,
+    /// 相手の名前 (使わない出来事では空文字列)
+    pub peer: *const c_char,
+    /// 本文・案内・ファイル名・切断の理由 (使わない出来事では空文字列)
+    pub text: *const c_char,
+    pub seq: u64,
+    pub transfer_id: u64,
+    /// 受信中の転送ならtrue
+    pub incoming: bool,
+    pub bytes: u64,
+    /// ファイルの大きさ。分からない場合は -1
+    pub total: i64,
+}
+
+/// 出来事を受け取るコールバック。user_dataには接続時に渡したものをそのまま渡す
+pub type P2pChatEventCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, event: *const P2pChatEvent)>;
+
+/// 1つの接続 (中身は公開しない)
+pub struct P2pChatSession {
+    commands: mpsc::UnboundedSender<SessionCommand>,
+    /// 出来事をコールバックに渡すタスク。セッションが終わると終了する
+    events: JoinHandle<()>,
+}
+
+// コールバックに渡す組み込み先のポインタ。スレッドをまたいで使えるかは組み込み先が保証する
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// 相手 (URIまたは連絡先の名前) に接続する。data_dirがNULLの場合はOSごとの標準の保存先を使う
+/// 失敗した場合はNULLを返す (理由は p2pchat_last_error)
+///
+/// # Safety
+/// data_dir・targetはNULL終端の文字列 (data_dirはNULLも可)。callbackはp2pchat_closeまで呼ばれ得る
+#[no_mangle]
+pub unsafe extern "C" fn p2pchat_connect(
+    data_dir: *const c_char,
+    target: *const c_char,
+    callback: P2pChatEventCallback,
+    user_data: *mut c_void,
+) -> *mut P2pChatSession {
+    let result = (|| {
+        let data_dir = optional_str(data_dir)?.map(PathBuf::from);
+        let target = required_str(target, "target")?;
+        runtime().block_on(async {
+            let peer = peer(data_dir)?;
+            peer.connect(target).await
+        })
+    })();
+    start(result, callback, user_data)
+}
+
+/// addr (例: 127.0.0.1:8080) で待ち受け、最初に受け付けた相手とのセッションを返す。接続されるまで戻らない
+/// 失敗した場合はNULLを返す (理由は p2pchat_last_error)
+///
+/// # Safety
+/// p2pchat_connectと同じ
+#[no_mangle]
+pub unsafe extern "C" fn p2pchat_listen(
+    data_dir: *const c_char,
+    addr: *const c_char,
+    callback: P2pChatEventCallback,
+    user_data: *mut c_void,
+) -> *mut P2pChatSession {
+    let result = (|| {
+        let data_dir = optional_str(data_dir)?.map(PathBuf::from);
+        let addr = required_str(addr, "addr")?.parse()?;
+        runtime().block_on(async {
+            let peer = peer(data_dir)?;
+            peer.listen(addr).await
+        })
+    })();
+    start(result, callback, user_data)
+}
+
+/// メッセージを送る。成功すれば0、セッションが終わっていれば-1
+///
+/// # Safety
+/// sessionはp2pchat_connect・p2pchat_listenが返したもので、p2pchat_closeの前であること。textはNULL終端の文字列
+#[no_mangle]
+pub unsafe extern "C" fn p2pchat_send(session: *mut P2pChatSession, text: *const c_char) -> c_int {
+    let text = match required_str(text, "text") {
+        Ok(text) => text.to_string(),
+        Err(e) => return fail(e),
+    };
+    command(session, SessionCommand::SendText(text))
+}
+
+/// ファイルを送る。進み具合は TransferProgress の出来事で届く。成功すれば0、セッションが終わっていれば-1
+///
+/// # Safety
+/// p2pchat_sendと同じ (pathはNULL終端の文字列)
+#[no_mangle]
+pub unsafe extern "C" fn p2pchat_send_file(session: *mut P2pChatSession, path: *const c_char) -> c_int {
+    let path = match required_str(path, "path") {
+        Ok(path) => PathBuf::from(path),
+        Err(e) => return fail(e),
+    };
+    command(session, SessionCommand::SendFile(path))
+}
+
+/// 相手に切断を知らせ、最後の出来事 (Disconnected) をコールバックに渡し終えるまで待ってからセッションを解放する
+///
+/// # Safety
+/// sessionはp2pchat_connect・p2pchat_listenが返したもの (NULLも可)。この後は使わない
+#[no_mangle]
+pub unsafe extern "C" fn p2pchat_close(session: *mut P2pChatSession) {
+    if session.is_null() {
+        return;
+    }
+    let session = Box::from_raw(session);
+    let _ = session.commands.send(SessionCommand::Close);
+    drop(session.commands);
+    let _ = runtime().block_on(session.events);
+}
+
+/// このスレッドで直前に失敗した関数のエラー。次にこのスレッドで関数を呼ぶまで有効。なければNULL
+#[no_mangle]
+pub extern "C" fn p2pchat_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ref().map_or(std::ptr::null(), |error| error.as_ptr()))
+}
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("tokioのランタイムを作成できません")
+    })
+}
+
+fn peer(data_dir: Option<PathBuf>) -> Result<Peer, Box<dyn std::error::Error>> {
+    let paths = Paths::resolve(data_dir.as_deref(), DEFAULT_PROFILE, true)?;
+    let options = ChatOptions::embedded(&paths)?;
+    Ok(Peer::new(paths, options))
+}
+
+// 接続できたら出来事をコールバックに渡すタスクを動かし、セッションを組み込み先に渡す
+fn start(
+    result: Result<ChatSession, Box<dyn std::error::Error>>,
+    callback: P2pChatEventCallback,
+    user_data: *mut c_void,
+) -> *mut P2pChatSession {
+    let session = match result {
+        Ok(session) => session,
+        Err(e) => {
+            set_error(e.to_string());
+            return std::ptr::null_mut();
+        }
+    };
+    let (mut events, commands) = session.split();
+    let user_data = UserData(user_data);
+    let events = runtime().spawn(async move {
+        let user_data = user_data;
+        while let Some(event) = events.recv().await {
+            if let Some(callback) = callback {
+                deliver(callback, user_data.0, event);
+            }
+        }
+    });
+    Box::into_raw(Box::new(P2pChatSession { commands, events }))
+}
+
+fn deliver(callback: unsafe extern "C" fn(*mut c_void, *const P2pChatEvent), user_data: *mut c_void, event: Event) {
+    let mut peer = String::new();
+    let mut text = String::new();
+    let (mut seq, mut transfer_id, mut incoming, mut bytes, mut total) = (0, 0, false, 0, -1);
+    let kind = match event {
+        Event::PeerConnected { peer: name } => {
+            peer = name;
+            P2pChatEventKind::Connected
+        }
+        Event::MessageReceived { from, seq: number, body, .. } => {
+            (peer, text, seq) = (from, body, number);
+            P2pChatEventKind::MessageReceived
+        }
+        Event::MessageSent { seq: number, body } => {
+            (text, seq) = (body, number);
+            P2pChatEventKind::MessageSent
+        }
+        Event::DeliveryAck { seq: number } => {
+            seq = number;
+            P2pChatEventKind::DeliveryAck
+        }
+        Event::TransferProgress { id, name, direction, bytes: sent, total: size } => {
+            (transfer_id, text, incoming, bytes) = (id, name, direction == Direction::Incoming, sent);
+            total = size.map_or(-1, |size| i64::try_from(size).unwrap_or(i64::MAX));
+            P2pChatEventKind::TransferProgress
+        }
+        Event::Info { text: info } => {
+            text = info;
+            P2pChatEventKind::Info
+        }
+        Event::Warning { text: warning } => {
+            text = warning;
+            P2pChatEventKind::Warning
+        }
+        Event::Disconnected { peer: name, reason } => {
+            peer = name;
+            text = match reason {
+                DisconnectReason::PeerClosed { reason, .. } => reason,
+                DisconnectReason::Error(error) => error,
+                other => format!("{:?}", other),
+            };
+            P2pChatEventKind::Disconnected
+        }
+        // 画面の表示にだけ使う出来事は渡さない
+        Event::Earlier { .. }
+        | Event::PeerRenamed { .. }
+        | Event::Resumed
+        | Event::Verification(_)
+        | Event::Latency { .. } => return,
+    };
+    let peer = c_string(peer);
+    let text = c_string(text);
+    let event = P2pChatEvent { kind, peer: peer.as_ptr(), text: text.as_ptr(), seq, transfer_id, incoming, bytes, total };
+    unsafe { callback(user_data, &event) };
+}
+
+unsafe fn command(session: *mut P2pChatSession, command: SessionCommand) -> c_int {
+    let Some(session) = session.as_ref() else {
+        return fail("sessionがNULLです".into());
+    };
+    match session.commands.send(command) {
+        Ok(()) => 0,
+        Err(_) => fail("セッションは終了しています".into()),
+    }
+}
+
+unsafe fn optional_str<'a>(text: *const c_char) -> Result<Option<&'a str>, Box<dyn std::error::Error>> {
+    if text.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(CStr::from_ptr(text).to_str()?))
+}
+
+unsafe fn required_str<'a>(text: *const c_char, name: &str) -> Result<&'a str, Box<dyn std::error::Error>> {
+    optional_str(text)?.ok_or_else(|| format!("{}がNULLです", name).into())
+}
+
+fn fail(error: Box<dyn std::error::Error>) -> c_int {
+    set_error(error.to_string());
+    -1
+}
+
+fn set_error(error: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(c_string(error)));
+}
+
+// C文字列には途中のNULを含められないので取り除く
+fn c_string(text: String) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
@@ -0,0 +1,199 @@
+use crate::chat::ChatOptions;
+use crate::commands::{self, Command};
+use crate::config::{AlertEvent, Config};
+use crate::event::{DisconnectReason, Event, SessionCommand};
+use crate::hook::Hooks;
+use crate::i18n::Msg;
+use crate::ui::{self, Input, Ui};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+// plain・TUI・デーモンの部屋の画面。セッションの出来事を表示し、入力した行をコマンドにしてセッションに送る
+// 画面だけで済むコマンド (/help・/more・/paste) はセッションに送らない
+pub(crate) struct Frontend {
+    /// 相手のメッセージを表示するときの名前
+    nickname: String,
+    format: ui::OutputFormat,
+    /// 設定ファイルの [alerts] は実行中に変更できるため、鳴らすたびに読む
+    config: watch::Receiver<Config>,
+    notify: bool,
+    quiet: bool,
+    /// /paste で入力中の複数行のメッセージ
+    paste: Option<Vec<String>>,
+    /// 取り消されたら相手に切断を知らせて終了する (Ctrl+C・デーモンの終了)
+    shutdown: CancellationToken,
+    /// フックが追加したコマンドを /help に載せ、不明なコマンドと区別する
+    hooks: Hooks,
+}
+
+impl Frontend {
+    pub(crate) fn new(options: &ChatOptions, nickname: String) -> Self {
+        Self {
+            nickname,
+            format: options.ui.format,
+            config: options.config.clone(),
+            notify: options.notify,
+            quiet: options.quiet,
+            paste: None,
+            shutdown: options.shutdown.clone(),
+            hooks: options.hooks.clone(),
+        }
+    }
+
+    // セッションが出来事を送り終える (Event::Disconnected の後に閉じる) まで続ける
+    pub(crate) async fn run(
+        mut self,
+        ui: Ui,
+        mut input: Input,
+        mut events: mpsc::UnboundedReceiver<Event>,
+        commands: mpsc::UnboundedSender<SessionCommand>,
+    ) {
+        // 入力が閉じられたら送り口を捨て、セッションに終了を知らせる
+        let mut commands = Some(commands);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => self.show(&ui, event),
+                    None => break,
+                },
+                line = input.next_line(), if commands.is_some() => match line {
+                    Some(line) => {
+                        let Some(command) = self.parse(&ui, line) else {
+                            continue;
+                        };
+                        let close = command == SessionCommand::Close;
+                        if let Some(sender) = &commands {
+                            let _ = sender.send(command);
+                        }
+                        if close {
+                            commands = None;
+                        }
+                    }
+                    None => {
+                        ui.info(Msg::InputClosed.text());
+                        commands = None;
+                    }
+                },
+                _ = self.shutdown.cancelled(), if commands.is_some() => {
+                    ui.info(Msg::Interrupted.text());
+                    if let Some(sender) = commands.take() {
+                        let _ = sender.send(SessionCommand::Close);
+                    }
+                }
+            }
+        }
+    }
+
+    fn show(&self, ui: &Ui, event: Event) {
+        match event {
+            Event::PeerConnected { peer } => {
+                ui.connected(&peer);
+                self.alert(ui, AlertEvent::Connect);
+            }
+            Event::Earlier { entries } => {
+                ui.load_earlier(&self.nickname, entries);
+                if !self.quiet {
+                    ui.info(Msg::ChatStart.text());
+                }
+            }
+            Event::MessageReceived { from, seq, body, sent_at } => {
+                ui.remote(&from, &body, seq, sent_at);
+                // 再送されたメッセージでは鳴らさない
+                if sent_at.is_none() {
+                    self.alert(ui, AlertEvent::Message);
+                }
+            }
+            Event::MessageSent { seq, body } => ui.own(&body, seq),
+            Event::DeliveryAck { seq } => ui.delivered(seq),
+            Event::TransferProgress { id, name, direction, bytes, total } => ui.progress(id, &name, direction, bytes, total),
+            Event::Info { text } => ui.info(text),
+            Event::Warning { text } => ui.warn(text),
+            Event::PeerRenamed { peer } => ui.set_peer(&peer),
+            Event::Resumed => {
+                ui.set_state(Msg::StateResumed.text());
+                ui.info(Msg::SessionResumed.text());
+            }
+            Event::Verification(verification) => ui.set_verification(verification),
+            Event::Latency { last, average } => ui.set_latency(last, average),
+            Event::Disconnected { peer, reason } => {
+                match reason {
+                    DisconnectReason::PeerClosed { code: Some(code), reason } => ui.info(Msg::PeerClosedWithReason.with(&[&code, &reason])),
+                    DisconnectReason::PeerClosed { code: None, .. } => ui.info(Msg::PeerClosed.text()),
+                    DisconnectReason::StreamEnded => ui.info(Msg::WebSocketClosed.text()),
+                    DisconnectReason::PeerIdle => ui.info(Msg::PeerIdleClosed.text()),
+                    DisconnectReason::Unreachable => ui.info(Msg::PeerUnreachable.text()),
+                    DisconnectReason::Error(_) | DisconnectReason::Closed | DisconnectReason::InputClosed | DisconnectReason::Idle => {}
+                }
+                ui.disconnected(&peer);
+                self.alert(ui, AlertEvent::Disconnect);
+            }
+        }
+    }
+
+    // 入力した行をセッションへのコマンドにする。/paste の途中の行や、画面だけで済むコマンドはNone
+    fn parse(&mut self, ui: &Ui, line: String) -> Option<SessionCommand> {
+        // /paste の後は、単独の . の行までを1つのメッセージにまとめる (空行もそのまま含める)
+        let parsed = match self.paste.as_mut() {
+            Some(lines) if line != "." => {
+                lines.push(line);
+                return None;
+            }
+            Some(_) => match self.paste.take().unwrap_or_default().join("\n") {
+                body if body.trim().is_empty() => return None,
+                body => commands::Parsed::Chat(body),
+            },
+            None if line.trim().is_empty() => return None,
+            None => match self.format {
+                ui::OutputFormat::Text => commands::parse(&line),
+                ui::OutputFormat::Jsonl => commands::parse_json(&line),
+            },
+        };
+        let command = match parsed {
+            commands::Parsed::Chat(body) => return Some(SessionCommand::SendText(body)),
+            commands::Parsed::Invalid(message) => {
+                ui.error(message);
+                return None;
+            }
+            commands::Parsed::Command(command) => command,
+        };
+        match command {
+            Command::Help => {
+                for line in commands::help(&self.hooks.commands()) {
+                    ui.info(line);
+                }
+                None
+            }
+            Command::More => {
+                ui.more();
+                None
+            }
+            Command::Paste => {
+                self.paste = Some(Vec::new());
+                ui.info(Msg::PasteStarted.text());
+                None
+            }
+            Command::Quit => {
+                tracing::info!("/quit によりチャットを終了します");
+                Some(SessionCommand::Close)
+            }
+            Command::Send { path } => Some(SessionCommand::SendFile(path)),
+            Command::Cancel { id, direction } => Some(SessionCommand::CancelTransfer { id, direction }),
+            Command::Who => Some(SessionCommand::Who),
+            Command::Stats => Some(SessionCommand::Stats),
+            Command::Ping => Some(SessionCommand::Ping),
+            Command::Drafts(action) => Some(SessionCommand::Drafts(action)),
+            Command::Custom { name, args } if self.hooks.has_command(&name) => Some(SessionCommand::Custom { name, args }),
+            Command::Custom { name, .. } => {
+                ui.error(Msg::UnknownCommand.with(&[&name]));
+                None
+            }
+        }
+    }
+
+    fn alert(&self, ui: &Ui, event: AlertEvent) {
+        let config = self.config.borrow();
+        if self.notify && config.alerts.wants(event) {
+            ui.alert(&config.alerts);
+        }
+    }
+}
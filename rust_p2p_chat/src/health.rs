@@ -0,0 +1,177 @@
+use serde::Serialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Instrument;
+
+use crate::http::{self, failure, Reply};
+
+// listen・daemon の --health: DockerのHEALTHCHECKやKubernetesのprobeが使う状態の確認
+//
+//     GET /healthz   プロセスが応答できるか (liveness)。待受を終えた後は503
+//     GET /readyz    新しい接続を受け付けられるか (readiness)。待受・リレー・保存先のどれかに問題があれば503
+//
+// 応答は {"ok":true,"status":"ready","checks":{...}}。チャットの内容は返さないため、トークンは求めない
+
+// 要求を受け取り終えるまでの時間 (probeのtimeoutより短くする)
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// リレーに接続できるかを確かめるときの待ち時間 (probeのtimeoutより短くする)
+const RELAY_TIMEOUT: Duration = Duration::from_secs(2);
+// 保存先に書き込めるかを確かめるファイルの名前の始まり (後ろにプロセスIDと通し番号を付ける)
+const PROBE_FILE: &str = ".health";
+// 同時に届いた確認が同じファイルを書いて消し合わないよう、確認ごとに別の名前にする
+static PROBE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// 待受の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerState {
+    /// 証明書や履歴を準備している
+    Starting,
+    /// 接続を受け付けられる
+    Listening,
+    /// 相手と接続していて、切断されるまで次の接続は受け付けない (listen は同時に1つの接続だけ)
+    Connected,
+    /// 待ち受けない設定 (addrを指定しないdaemon)
+    Disabled,
+    /// 待受を終えた
+    Stopped,
+}
+
+impl ListenerState {
+    fn ready(self) -> bool {
+        matches!(self, ListenerState::Listening | ListenerState::Disabled)
+    }
+}
+
+// 状態の確認に使うもの。複製したものは同じ待受の状態を共有する
+#[derive(Debug, Clone)]
+pub struct Health {
+    data_dir: PathBuf,
+    /// 接続できることを確かめるリレー (listen --code のランデブーサーバー・daemon の [push] endpoint)
+    relays: Vec<String>,
+    listener: Arc<Mutex<ListenerState>>,
+}
+
+impl Health {
+    pub fn new(data_dir: PathBuf, relays: Vec<String>) -> Self {
+        Self { data_dir, relays, listener: Arc::new(Mutex::new(ListenerState::Starting)) }
+    }
+
+    pub fn set_listener(&self, state: ListenerState) {
+        *self.listener.lock().unwrap() = state;
+    }
+
+    pub fn listener(&self) -> ListenerState {
+        *self.listener.lock().unwrap()
+    }
+
+    // /healthz: 待受を終えていなければ応答できる
+    pub fn live(&self) -> bool {
+        self.listener() != ListenerState::Stopped
+    }
+
+    // /readyz: 待受・リレー・保存先を確かめた結果 (すべて問題なければok)
+    pub async fn ready(&self) -> Readiness {
+        let listener = self.listener();
+        let relays = futures_util::future::join_all(self.relays.iter().map(|url| check_relay(url))).await;
+        let storage = check_storage(&self.data_dir);
+        let ok = listener.ready() && relays.iter().all(|relay| relay.ok) && storage.is_none();
+        Readiness { ok, listener, relays, storage }
+    }
+}
+
+// /readyz の結果
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ok: bool,
+    pub listener: ListenerState,
+    pub relays: Vec<RelayCheck>,
+    /// 保存先に書き込めない場合の理由
+    pub storage: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayCheck {
+    pub url: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// リレーのホストにTCPで接続できるか (TLSやHTTPの応答までは確かめない)
+async fn check_relay(url: &str) -> RelayCheck {
+    let reachable = async {
+        let parsed = url::Url::parse(url).map_err(|e| e.to_string())?;
+        let host = parsed.host_str().ok_or("ホストがありません")?.to_string();
+        let port = parsed.port_or_known_default().ok_or("ポート番号が分かりません")?;
+        match tokio::time::timeout(RELAY_TIMEOUT, TcpStream::connect((host.as_str(), port))).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("{}秒以内に接続できません", RELAY_TIMEOUT.as_secs())),
+        }
+    };
+    let error = reachable.await.err();
+    RelayCheck { url: url.to_string(), ok: error.is_none(), error }
+}
+
+// 保存先にファイルを書いて消せるか (ディスクの空きがない・読み取り専用になった場合に失敗する)
+fn check_storage(data_dir: &std::path::Path) -> Option<String> {
+    let probe = data_dir.join(format!("{}.{}.{}", PROBE_FILE, std::process::id(), PROBE_SEQ.fetch_add(1, Ordering::Relaxed)));
+    std::fs::write(&probe, b"ok").and_then(|_| std::fs::remove_file(&probe)).err().map(|e| format!("{}: {}", data_dir.display(), e))
+}
+
+// 待ち受けを始める。平文で状態を返すため、他のマシンから使えるアドレスでは警告する
+pub async fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    if !addr.ip().is_loopback() {
+        tracing::warn!(%addr, "状態の確認を他のマシンからも使えるアドレスで待ち受けます (平文です)");
+    }
+    TcpListener::bind(addr).await
+}
+
+pub async fn serve(listener: TcpListener, health: Health) {
+    loop {
+        let (stream, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!(error = %e, "状態の確認の接続の受け付けに失敗しました");
+                continue;
+            }
+        };
+        let span = tracing::debug_span!("health", client = %client);
+        tokio::spawn(respond(stream, health.clone()).instrument(span));
+    }
+}
+
+async fn respond(mut stream: TcpStream, health: Health) {
+    let reply = match tokio::time::timeout(REQUEST_TIMEOUT, http::read_request(&mut stream)).await {
+        Ok(Ok(request)) => route(&health, &request.method, &request.path).await,
+        Ok(Err(error)) => error,
+        Err(_) => failure(408, "要求が時間内に届きませんでした"),
+    };
+    http::write_reply(&mut stream, reply).await;
+}
+
+async fn route(health: &Health, method: &str, path: &str) -> Reply {
+    if method != "GET" {
+        return failure(405, "GETで取得してください");
+    }
+    match path {
+        "/healthz" => {
+            let ok = health.live();
+            let status = if ok { "alive" } else { "stopped" };
+            (if ok { 200 } else { 503 }, json!({ "ok": ok, "status": status, "listener": health.listener() }))
+        }
+        "/readyz" => {
+            let readiness = health.ready().await;
+            let ok = readiness.ok;
+            let status = if ok { "ready" } else { "not_ready" };
+            (if ok { 200 } else { 503 }, json!({ "ok": ok, "status": status, "checks": readiness }))
+        }
+        _ => failure(404, "/healthz か /readyz を指定してください"),
+    }
+}
@@ -0,0 +1,605 @@
+use chrono::{DateTime, Local, NaiveDate, SecondsFormat, TimeZone, Utc};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::config::RetentionConfig;
+use crate::identity::{self, CertStore, Identity};
+use crate::vault::{self, Vault};
+
+// メッセージの方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+// 履歴に記録されるイベントの種類
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    /// チャットメッセージ
+    Message { direction: Direction, body: String },
+    /// ファイル転送の記録
+    FileTransfer {
+        direction: Direction,
+        file_name: String,
+        size: u64,
+    },
+    /// 接続・切断などのシステムイベント
+    System { text: String },
+    /// 届けられなかったメッセージをメールで送った記録 (encryptedでなければ本文は送らず知らせのみ)
+    Mailed { address: String, body: String, encrypted: bool },
+    /// Nostrのリレーを経由して送受信した、暗号化したダイレクトメッセージ
+    Nostr { direction: Direction, body: String },
+}
+
+// メッセージの署名。signerは署名した側の証明書のフィンガープリント
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageSignature {
+    pub signer: String,
+    pub sig: String,
+}
+
+// 履歴の1エントリ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub timestamp: DateTime<Local>,
+    pub peer: String,
+    /// 相手側の履歴での通し番号 (相手から受信したメッセージのみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<MessageSignature>,
+    #[serde(flatten)]
+    pub event: EventKind,
+}
+
+// JSON Lines形式の追記専用履歴ストア
+// vaultが設定されている場合、各行を暗号化して保存する
+// cloneしたものは同じ通し番号を共有する (デーモンで複数の接続から同時に追記する)
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+    /// 次に追記するエントリの通し番号。追記と書き換えの間はこのロックを持つ
+    next_seq: Arc<Mutex<u64>>,
+    vault: Option<Arc<Vault>>,
+}
+
+impl History {
+    pub fn open(path: impl AsRef<Path>, vault: Option<Vault>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let next_seq = Self::load_from(&path, vault.as_ref())?
+            .last()
+            .map(|entry| entry.seq + 1)
+            .unwrap_or(1)
+            .max(Self::load_seq_mark(&path)?);
+        Ok(Self { path, next_seq: Arc::new(Mutex::new(next_seq)), vault: vault.map(Arc::new) })
+    }
+
+    // 削除で末尾のエントリが消えても通し番号を使い回さないよう、書き換えの前に次の通し番号を記録するファイル
+    pub fn seq_mark_file(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".seq");
+        PathBuf::from(name)
+    }
+
+    fn load_seq_mark(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+        let mark_file = Self::seq_mark_file(path);
+        if !mark_file.exists() {
+            return Ok(1);
+        }
+        let mark = std::fs::read_to_string(&mark_file)?;
+        Ok(mark.trim().parse().map_err(|e| format!("{} を読み込めません: {}", mark_file.display(), e))?)
+    }
+
+    fn save_seq_mark(&self, next_seq: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let mark_file = Self::seq_mark_file(&self.path);
+        let mut tmp_name = mark_file.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        {
+            let mut file = File::create(&tmp_path)?;
+            writeln!(file, "{}", next_seq)?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &mark_file)?;
+        Ok(())
+    }
+
+    // 最後に追記したエントリの通し番号 (まだなければ0)
+    pub fn last_seq(&self) -> u64 {
+        *self.lock() - 1
+    }
+
+    fn lock(&self) -> MutexGuard<'_, u64> {
+        self.next_seq.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // イベントを1件追記する
+    pub fn append(&mut self, peer: &str, event: EventKind) -> Result<HistoryEntry, Box<dyn std::error::Error>> {
+        self.append_entry(peer, None, Local::now(), event, None)
+    }
+
+    // 署名付きのメッセージを追記する。署名は確定した通し番号を受け取って作る
+    // (他の接続の追記と通し番号が入れ替わらないよう、署名から追記までロックを持つ)
+    pub fn append_signed(
+        &mut self,
+        peer: &str,
+        event: EventKind,
+        sign: impl FnOnce(u64) -> Option<MessageSignature>,
+    ) -> Result<HistoryEntry, Box<dyn std::error::Error>> {
+        let mut next_seq = self.lock();
+        let signature = sign(*next_seq);
+        self.write_entry(&mut next_seq, peer, None, Local::now(), event, signature)
+    }
+
+    // 相手から受け取ったメッセージを相手側の通し番号付きで追記する
+    pub fn append_remote(
+        &mut self,
+        peer: &str,
+        remote_seq: u64,
+        timestamp: DateTime<Local>,
+        event: EventKind,
+        signature: Option<MessageSignature>,
+    ) -> Result<HistoryEntry, Box<dyn std::error::Error>> {
+        let remote_seq = (remote_seq > 0).then_some(remote_seq);
+        self.append_entry(peer, remote_seq, timestamp, event, signature)
+    }
+
+    fn append_entry(
+        &mut self,
+        peer: &str,
+        remote_seq: Option<u64>,
+        timestamp: DateTime<Local>,
+        event: EventKind,
+        signature: Option<MessageSignature>,
+    ) -> Result<HistoryEntry, Box<dyn std::error::Error>> {
+        let mut next_seq = self.lock();
+        self.write_entry(&mut next_seq, peer, remote_seq, timestamp, event, signature)
+    }
+
+    fn write_entry(
+        &self,
+        next_seq: &mut u64,
+        peer: &str,
+        remote_seq: Option<u64>,
+        timestamp: DateTime<Local>,
+        event: EventKind,
+        signature: Option<MessageSignature>,
+    ) -> Result<HistoryEntry, Box<dyn std::error::Error>> {
+        let entry = HistoryEntry {
+            seq: *next_seq,
+            timestamp,
+            peer: peer.to_string(),
+            remote_seq,
+            signature,
+            event,
+        };
+        let line = self.encode_line(&entry)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        *next_seq += 1;
+        Ok(entry)
+    }
+
+    pub fn load(&self) -> Result<Vec<HistoryEntry>, Box<dyn std::error::Error>> {
+        Self::load_from(&self.path, self.vault.as_deref())
+    }
+
+    fn encode_line(&self, entry: &HistoryEntry) -> Result<String, Box<dyn std::error::Error>> {
+        let json = serde_json::to_string(entry)?;
+        match &self.vault {
+            Some(vault) => vault.seal(json.as_bytes()),
+            None => Ok(json),
+        }
+    }
+
+    // 平文で保存されている既存の履歴を新しいパスフレーズですべて暗号化し直し、鍵ファイルを作る
+    // 鍵ファイルは一時ファイルに書いておき、暗号化した履歴に置き換えてから移す
+    // (途中で失敗しても、平文の履歴のそばに鍵ファイルが残って「既に暗号化されています」とならないように)
+    pub fn encrypt_all(&mut self, key_file: &Path, passphrase: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let entries = Self::load_from(&self.path, None)?;
+        let mut tmp_name = key_file.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_key_file = PathBuf::from(tmp_name);
+        let vault = Vault::create(&tmp_key_file, passphrase)?;
+        let previous = self.vault.replace(Arc::new(vault));
+        if let Err(e) = self.rewrite(&entries) {
+            self.vault = previous;
+            let _ = std::fs::remove_file(&tmp_key_file);
+            return Err(e);
+        }
+        std::fs::rename(&tmp_key_file, key_file)?;
+        Ok(entries.len())
+    }
+
+    // 条件に合うエントリだけを残し、削除した件数を返す
+    pub fn retain(&mut self, mut keep: impl FnMut(&HistoryEntry) -> bool) -> Result<usize, Box<dyn std::error::Error>> {
+        let next_seq = self.lock();
+        let entries = self.load()?;
+        let before = entries.len();
+        let kept: Vec<HistoryEntry> = entries.into_iter().filter(|entry| keep(entry)).collect();
+        let removed = before - kept.len();
+        if removed > 0 {
+            self.save_seq_mark(*next_seq)?;
+            self.rewrite(&kept)?;
+        }
+        Ok(removed)
+    }
+
+    // 保持期間を過ぎたエントリを削除する
+    pub fn prune(&mut self, retention: &RetentionConfig) -> Result<usize, Box<dyn std::error::Error>> {
+        let now = Local::now();
+        self.retain(|entry| retention.policy_for(&entry.peer).keeps(entry.timestamp, now))
+    }
+
+    // 一時ファイルに書いてから置き換え、途中で失敗しても元の履歴を壊さない
+    fn rewrite(&self, entries: &[HistoryEntry]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tmp_name = self.path.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        {
+            let mut file = File::create(&tmp_path)?;
+            for entry in entries {
+                writeln!(file, "{}", self.encode_line(entry)?)?;
+            }
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    // 相手から受信済みのメッセージのうち、最も新しい相手側の通し番号
+    pub fn last_remote_seq(&self, peer: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(self
+            .load()?
+            .iter()
+            .filter(|entry| entry.peer == peer)
+            .filter_map(|entry| entry.remote_seq)
+            .max()
+            .unwrap_or(0))
+    }
+
+    // 相手とのメッセージとファイル転送のうち、新しいものからlimit件 (古い順に並べて返す)
+    pub fn recent(&self, peer: &str, limit: usize) -> Result<Vec<HistoryEntry>, Box<dyn std::error::Error>> {
+        let mut entries: Vec<HistoryEntry> = self
+            .load()?
+            .into_iter()
+            .filter(|entry| entry.peer == peer && !matches!(entry.event, EventKind::System { .. }))
+            .collect();
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+        Ok(entries)
+    }
+
+    // 指定した通し番号より後に相手へ送信したメッセージ
+    pub fn sent_since(&self, peer: &str, since: u64) -> Result<Vec<HistoryEntry>, Box<dyn std::error::Error>> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|entry| entry.peer == peer && entry.seq > since)
+            .filter(|entry| {
+                matches!(
+                    entry.event,
+                    EventKind::Message { direction: Direction::Outgoing, .. }
+                )
+            })
+            .collect())
+    }
+
+    // 指定した通し番号より後のメッセージ (すべての相手、古い順にlimit件まで)
+    pub fn messages_since(&self, since: u64, limit: usize) -> Result<Vec<HistoryEntry>, Box<dyn std::error::Error>> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|entry| entry.seq > since && matches!(entry.event, EventKind::Message { .. }))
+            .take(limit)
+            .collect())
+    }
+
+    fn load_from(path: &Path, vault: Option<&Vault>) -> Result<Vec<HistoryEntry>, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // 移行途中の履歴には平文の行と暗号化された行が混在しうる
+            if vault::is_sealed(&line) {
+                let vault = vault.ok_or("履歴は暗号化されています。パスフレーズが必要です")?;
+                entries.push(serde_json::from_slice(&vault.open(&line)?)?);
+            } else {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// エクスポート形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+// エクスポート対象を絞り込む条件
+pub struct ExportFilter {
+    pub peer: Option<String>,
+    pub since: Option<DateTime<Local>>,
+}
+
+impl ExportFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        if let Some(peer) = &self.peer {
+            if &entry.peer != peer {
+                return false;
+            }
+        }
+        if let Some(since) = &self.since {
+            if entry.timestamp < *since {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// `2024-01-31` 形式またはRFC 3339形式の日時を解釈する
+pub fn parse_since(value: &str) -> Result<DateTime<Local>, String> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Ok(datetime.with_timezone(&Local));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("日付の形式が正しくありません (例: 2024-01-31): {}", value))?;
+    let midnight = date.and_hms_opt(0, 0, 0).ok_or("日付の変換に失敗しました")?;
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .ok_or_else(|| format!("ローカル時刻に変換できません: {}", value))
+}
+
+#[derive(Serialize)]
+struct JsonTranscript<'a> {
+    exported_at: DateTime<Local>,
+    peer: Option<&'a str>,
+    since: Option<DateTime<Local>>,
+    entries: Vec<&'a HistoryEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manifest: Option<Manifest>,
+}
+
+// 署名付きトランスクリプトの検証に必要な情報
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub algorithm: String,
+    /// 署名の対象の形式 (メッセージごとの署名と、エクスポートした側によるトランスクリプト全体の署名)
+    pub signed_content: String,
+    /// 署名した側の証明書のフィンガープリント → 証明書 (DER、base64)
+    pub signers: BTreeMap<String, String>,
+    /// エクスポートした側の証明書のフィンガープリント
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exporter: Option<String>,
+    /// エクスポートした側によるトランスクリプト全体の署名
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<String>,
+}
+
+impl Manifest {
+    // エントリの署名者の証明書を集め、書き出すエントリ全体にexporterの鍵で署名する
+    // 証明書が見つからない署名者がいればエラーにする
+    pub fn collect(
+        entries: &[HistoryEntry],
+        filter: &ExportFilter,
+        certs: &CertStore,
+        exporter: &Identity,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let selected: Vec<&HistoryEntry> = entries.iter().filter(|e| filter.matches(e)).collect();
+        let mut signers = BTreeMap::new();
+        for signature in selected.iter().filter_map(|e| e.signature.as_ref()) {
+            if !signers.contains_key(&signature.signer) {
+                let cert = certs
+                    .get(&signature.signer)
+                    .ok_or_else(|| format!("署名者の証明書が見つかりません: {}", signature.signer))?;
+                signers.insert(signature.signer.clone(), cert.clone());
+            }
+        }
+        signers.insert(exporter.fingerprint(), BASE64.encode(&exporter.cert_der));
+        Ok(Self {
+            algorithm: identity::SIGNATURE_ALGORITHM.to_string(),
+            signed_content: "message: rust_p2p_chat message v1\\n<seq>\\n<body>; transcript (seal): rust_p2p_chat transcript v1 + \\n[seq, timestamp (UTC), peer, remote_seq, signature, event] per entry".to_string(),
+            signers,
+            exporter: Some(exporter.fingerprint()),
+            seal: Some(exporter.sign(&transcript_content(&selected)?)?),
+        })
+    }
+}
+
+// トランスクリプト全体の署名の対象。エントリの並び・件数と、各エントリの時刻・相手・向き・本文・署名をすべて含める
+fn transcript_content(entries: &[&HistoryEntry]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut content = String::from("rust_p2p_chat transcript v1");
+    for entry in entries {
+        // 検証する側のタイムゾーンに左右されないようにUTCで表す
+        let timestamp = entry.timestamp.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Nanos, true);
+        content.push('\n');
+        content.push_str(&serde_json::to_string(&(
+            entry.seq,
+            timestamp,
+            &entry.peer,
+            entry.remote_seq,
+            &entry.signature,
+            &entry.event,
+        ))?);
+    }
+    Ok(content.into_bytes())
+}
+
+// 署名の検証結果
+#[derive(Default)]
+pub struct Verification {
+    pub valid: usize,
+    pub unsigned: usize,
+    /// 署名が正しくないエントリの通し番号
+    pub invalid: Vec<u64>,
+    /// メッセージに署名した側のフィンガープリント
+    pub signers: BTreeSet<String>,
+    /// エクスポートした側のフィンガープリント
+    pub exporter: Option<String>,
+    /// トランスクリプト全体の署名が正しいか (エントリの削除・並べ替えや時刻・相手・向きの書き換えがないか)
+    pub sealed: bool,
+}
+
+#[derive(Deserialize)]
+struct SignedTranscript {
+    entries: Vec<HistoryEntry>,
+    manifest: Option<Manifest>,
+}
+
+// 検証情報にある証明書のうち、フィンガープリントが合うもの
+fn signer_cert(manifest: &Manifest, fingerprint: &str) -> Option<Vec<u8>> {
+    manifest
+        .signers
+        .get(fingerprint)
+        .and_then(|cert| BASE64.decode(cert).ok())
+        .filter(|cert| identity::fingerprint(cert) == fingerprint)
+}
+
+// `history export --signed` で書き出したトランスクリプトの各メッセージの署名と、トランスクリプト全体の署名を検証する
+// 署名者が信頼できる相手かどうかは呼び出し側で確かめる
+pub fn verify_transcript(json: &str) -> Result<Verification, Box<dyn std::error::Error>> {
+    let transcript: SignedTranscript = serde_json::from_str(json)?;
+    let manifest = transcript
+        .manifest
+        .ok_or("検証情報 (manifest) がありません。--signed を付けてエクスポートしてください")?;
+    if manifest.algorithm != identity::SIGNATURE_ALGORITHM {
+        return Err(format!("対応していない署名アルゴリズムです: {}", manifest.algorithm).into());
+    }
+
+    let mut result = Verification::default();
+    for entry in &transcript.entries {
+        let EventKind::Message { direction, body } = &entry.event else {
+            continue;
+        };
+        let Some(signature) = &entry.signature else {
+            result.unsigned += 1;
+            continue;
+        };
+        result.signers.insert(signature.signer.clone());
+        let signed_seq = match direction {
+            Direction::Incoming => entry.remote_seq.unwrap_or(0),
+            Direction::Outgoing => entry.seq,
+        };
+        let valid = signer_cert(&manifest, &signature.signer)
+            .is_some_and(|cert| identity::verify(&cert, &identity::signed_content(signed_seq, body), &signature.sig));
+        if valid {
+            result.valid += 1;
+        } else {
+            result.invalid.push(entry.seq);
+        }
+    }
+
+    let entries: Vec<&HistoryEntry> = transcript.entries.iter().collect();
+    let content = transcript_content(&entries)?;
+    result.sealed = match (&manifest.exporter, &manifest.seal) {
+        (Some(exporter), Some(seal)) => signer_cert(&manifest, exporter).is_some_and(|cert| identity::verify(&cert, &content, seal)),
+        _ => false,
+    };
+    result.exporter = manifest.exporter;
+    Ok(result)
+}
+
+// 履歴をトランスクリプト形式の文字列に変換する
+// manifestを渡した場合 (JSONのみ) は署名を検証するための情報を含める
+pub fn render_transcript(
+    entries: &[HistoryEntry],
+    filter: &ExportFilter,
+    format: ExportFormat,
+    manifest: Option<Manifest>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let selected: Vec<&HistoryEntry> = entries.iter().filter(|e| filter.matches(e)).collect();
+
+    match format {
+        ExportFormat::Json => {
+            let transcript = JsonTranscript {
+                exported_at: Local::now(),
+                peer: filter.peer.as_deref(),
+                since: filter.since,
+                entries: selected,
+                manifest,
+            };
+            Ok(serde_json::to_string_pretty(&transcript)?)
+        }
+        ExportFormat::Markdown => {
+            let mut out = String::new();
+            out.push_str("# チャット履歴\n\n");
+            out.push_str(&format!("- エクスポート日時: {}\n", Local::now().format("%Y-%m-%d %H:%M:%S")));
+            if let Some(peer) = &filter.peer {
+                out.push_str(&format!("- 相手: {}\n", peer));
+            }
+            if let Some(since) = &filter.since {
+                out.push_str(&format!("- 期間: {} 以降\n", since.format("%Y-%m-%d %H:%M:%S")));
+            }
+            out.push_str(&format!("- 件数: {}\n\n", selected.len()));
+
+            let mut current_date = None;
+            for entry in selected {
+                let date = entry.timestamp.date_naive();
+                if current_date != Some(date) {
+                    out.push_str(&format!("## {}\n\n", date.format("%Y-%m-%d")));
+                    current_date = Some(date);
+                }
+                let time = entry.timestamp.format("%H:%M:%S");
+                let line = match &entry.event {
+                    EventKind::Message { direction, body } => {
+                        let who = match direction {
+                            Direction::Incoming => entry.peer.as_str(),
+                            Direction::Outgoing => "自分",
+                        };
+                        format!("- `{}` **{}**: {}", time, who, body)
+                    }
+                    EventKind::FileTransfer { direction, file_name, size } => {
+                        let action = match direction {
+                            Direction::Incoming => "受信",
+                            Direction::Outgoing => "送信",
+                        };
+                        format!("- `{}` [ファイル{}] {} ({} bytes)", time, action, file_name, size)
+                    }
+                    EventKind::System { text } => format!("- `{}` _{}_", time, text),
+                    EventKind::Mailed { address, body, encrypted: true } => format!("- `{}` **自分** (メールで {} に送信): {}", time, address, body),
+                    EventKind::Mailed { address, body, encrypted: false } => format!("- `{}` _{} にメールで知らせました (未配送)_: {}", time, address, body),
+                    EventKind::Nostr { direction, body } => {
+                        let who = match direction {
+                            Direction::Incoming => entry.peer.as_str(),
+                            Direction::Outgoing => "自分",
+                        };
+                        format!("- `{}` **{}** (Nostr経由): {}", time, who, body)
+                    }
+                };
+                out.push_str(&line);
+                out.push('\n');
+            }
+            Ok(out)
+        }
+    }
+}
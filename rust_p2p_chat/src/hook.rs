@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+// メッセージの送受信に処理を挟む仕組み (ログ・フィルター・翻訳・ボットなど)
+// フックは登録した順に呼ばれ、前のフックが書き換えた本文を次のフックが受け取る
+
+// フックの判断
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// この本文で続ける (書き換えない場合は受け取った本文をそのまま返す)
+    Keep(String),
+    /// 表示・送信しない。受信したメッセージの受信確認は相手に送る
+    Drop,
+}
+
+// フックが追加するチャット中のコマンド (/help に表示する)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookCommand {
+    /// 先頭の `/` を除いた名前
+    pub name: String,
+    pub usage: String,
+    pub description: String,
+}
+
+// 受信したメッセージ・実行されたコマンドについてフックに渡す情報
+pub struct HookContext<'a> {
+    peer: &'a str,
+    replies: Vec<String>,
+    notes: Vec<String>,
+}
+
+impl HookContext<'_> {
+    // 履歴上で相手を識別する名前
+    pub fn peer(&self) -> &str {
+        self.peer
+    }
+
+    // 相手に返信する。受信したメッセージの後に、自分のメッセージとして (送信のフックを通して) 送る
+    pub fn reply(&mut self, body: impl Into<String>) {
+        self.replies.push(body.into());
+    }
+
+    // 自分の画面にだけ案内を表示する (相手には送らない)
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.notes.push(text.into());
+    }
+}
+
+// フックが返信・案内として残したもの
+#[derive(Debug, Default)]
+pub(crate) struct Effects {
+    pub(crate) replies: Vec<String>,
+    pub(crate) notes: Vec<String>,
+}
+
+pub trait MessageHook: Send + Sync {
+    // 相手のメッセージを受け取ったとき (署名の検証の後、表示の前)。履歴には書き換える前の本文を残す
+    fn on_incoming(&self, ctx: &mut HookContext<'_>, body: String) -> Verdict {
+        let _ = ctx;
+        Verdict::Keep(body)
+    }
+
+    // 自分のメッセージを送る前 (署名・履歴への記録の前)
+    fn on_outgoing(&self, peer: &str, body: String) -> Verdict {
+        let _ = peer;
+        Verdict::Keep(body)
+    }
+
+    // 追加するコマンド。組み込みのコマンドと同じ名前は使えない
+    fn commands(&self) -> Vec<HookCommand> {
+        Vec::new()
+    }
+
+    // commandsで追加したコマンドが実行されたとき。argsは名前の後の引数 (前後の空白を除く)。処理した場合はtrue
+    fn on_command(&self, ctx: &mut HookContext<'_>, name: &str, args: &str) -> bool {
+        let _ = (ctx, name, args);
+        false
+    }
+}
+
+// 登録されたフックの一覧。ChatOptionsに持たせ、接続ごとのセッションで使う
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn MessageHook>>);
+
+impl Hooks {
+    pub fn register(&mut self, hook: impl MessageHook + 'static) {
+        self.0.push(Arc::new(hook));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // フックが追加したコマンドの一覧 (登録した順)
+    pub fn commands(&self) -> Vec<HookCommand> {
+        self.0.iter().flat_map(|hook| hook.commands()).collect()
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.0.iter().any(|hook| hook.commands().iter().any(|command| command.name == name))
+    }
+
+    // 受信したメッセージをフックに通す。表示する本文 (Noneは表示しない) と、フックからの返信・案内を返す
+    pub(crate) fn incoming(&self, peer: &str, body: String) -> (Option<String>, Effects) {
+        let mut ctx = HookContext { peer, replies: Vec::new(), notes: Vec::new() };
+        let body = self.0.iter().try_fold(body, |body, hook| match hook.on_incoming(&mut ctx, body) {
+            Verdict::Keep(body) => Some(body),
+            Verdict::Drop => None,
+        });
+        (body, Effects { replies: ctx.replies, notes: ctx.notes })
+    }
+
+    // コマンドを、それを追加したフックに実行させる。どのフックも処理しなかった場合はNone
+    pub(crate) fn command(&self, peer: &str, name: &str, args: &str) -> Option<Effects> {
+        let mut ctx = HookContext { peer, replies: Vec::new(), notes: Vec::new() };
+        let handled = self
+            .0
+            .iter()
+            .filter(|hook| hook.commands().iter().any(|command| command.name == name))
+            .any(|hook| hook.on_command(&mut ctx, name, args));
+        handled.then_some(Effects { replies: ctx.replies, notes: ctx.notes })
+    }
+
+    // 送るメッセージをフックに通す。Noneは送らない
+    pub(crate) fn outgoing(&self, peer: &str, body: String) -> Option<String> {
+        self.0.iter().try_fold(body, |body, hook| match hook.on_outgoing(peer, body) {
+            Verdict::Keep(body) => Some(body),
+            Verdict::Drop => None,
+        })
+    }
+}
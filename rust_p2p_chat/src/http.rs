@@ -0,0 +1,129 @@
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// REST API・フィード・bridge relay の待受・状態の確認 (--health) で使う、最小限のHTTP/1.1
+// 1つの接続で1つの要求を読み、応答を書いたら閉じる (Keep-Aliveやchunkedには対応しない)
+
+// 要求のヘッダーと本文の大きさの上限
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+// 要求を受け取り終えるまでの時間
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 応答を決めるのに使う部分
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) query: Vec<(String, String)>,
+    pub(crate) authorization: Option<String>,
+    pub(crate) body: Vec<u8>,
+}
+
+// 応答のステータスと本文
+pub(crate) type Reply = (u16, Value);
+
+// ヘッダーの終わりまでと、Content-Lengthの分の本文を読む
+// (読み残したまま閉じると、応答を読む前の相手に接続のリセットが届くことがある)
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, Reply> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err(failure(431, "要求のヘッダーが大きすぎます"));
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err(failure(400, "要求の途中で接続が閉じられました")),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = std::str::from_utf8(&buf[..head_end]).map_err(|_| failure(400, "要求を解釈できません"))?;
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(failure(400, "要求を解釈できません"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        authorization: None,
+        body: Vec::new(),
+    };
+    let mut length = 0;
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            request.authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse().map_err(|_| failure(400, "Content-Lengthを解釈できません"))?;
+        }
+    }
+    if length > MAX_BODY_BYTES {
+        return Err(failure(413, "本文が大きすぎます"));
+    }
+    request.body = buf.split_off(head_end + 4);
+    while request.body.len() < length {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return Err(failure(400, "本文の途中で接続が閉じられました")),
+            Ok(n) => request.body.extend_from_slice(&chunk[..n]),
+        }
+    }
+    request.body.truncate(length);
+    Ok(request)
+}
+
+// JSONの応答を書き込んで接続を閉じる
+pub(crate) async fn write_reply(stream: &mut TcpStream, (status, body): Reply) {
+    let headers = match status {
+        401 => "Cache-Control: no-store\r\nWWW-Authenticate: Bearer\r\n",
+        _ => "Cache-Control: no-store\r\n",
+    };
+    write_response(stream, status, "application/json", headers, body.to_string().as_bytes()).await;
+}
+
+// JSON以外の応答 (フィードなど) も書き込む。headersは "名前: 値\r\n" を並べたもの
+pub(crate) async fn write_response(stream: &mut TcpStream, status: u16, content_type: &str, headers: &str, body: &[u8]) {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        headers
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.write_all(body).await;
+    let _ = stream.shutdown().await;
+}
+
+pub(crate) fn success(status: u16, mut body: Value) -> Reply {
+    body["ok"] = Value::Bool(true);
+    (status, body)
+}
+
+pub(crate) fn failure(status: u16, error: &str) -> Reply {
+    (status, json!({ "ok": false, "error": error }))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
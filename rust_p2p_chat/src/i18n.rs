@@ -0,0 +1,272 @@
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+// 表示する言語
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Lang {
+    Ja,
+    En,
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+// 表示する言語を決める。--lang がなければ LC_ALL・LC_MESSAGES・LANG の順に見る
+pub fn init(lang: Option<Lang>) {
+    let _ = LANG.set(lang.unwrap_or_else(detect));
+}
+
+pub fn lang() -> Lang {
+    LANG.get().copied().unwrap_or(Lang::Ja)
+}
+
+// 未設定やC・POSIXの場合はこれまでどおり日本語にする
+fn detect() -> Lang {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty());
+    match locale.as_deref() {
+        None | Some("C") | Some("POSIX") => Lang::Ja,
+        Some(locale) if locale.starts_with("C.") || locale.starts_with("ja") => Lang::Ja,
+        Some(_) => Lang::En,
+    }
+}
+
+// 表示文字列の一覧。{} には Msg::with で渡した引数が順に入る
+// 文字列を追加するときは、すべての言語の訳を並べて書く
+macro_rules! catalog {
+    ($($(#[$attr:meta])* $key:ident => $ja:literal, $en:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Msg {
+            $($(#[$attr])* $key,)*
+        }
+
+        impl Msg {
+            pub fn text(self) -> &'static str {
+                match (self, lang()) {
+                    $(
+                        $(#[$attr])*
+                        (Msg::$key, Lang::Ja) => $ja,
+                        $(#[$attr])*
+                        (Msg::$key, Lang::En) => $en,
+                    )*
+                }
+            }
+        }
+    };
+}
+
+impl Msg {
+    pub fn with(self, args: &[&dyn std::fmt::Display]) -> String {
+        let mut parts = self.text().split("{}");
+        let mut out = parts.next().unwrap_or_default().to_string();
+        let mut args = args.iter();
+        for part in parts {
+            if let Some(arg) = args.next() {
+                let _ = write!(out, "{}", arg);
+            }
+            out.push_str(part);
+        }
+        out
+    }
+}
+
+impl std::fmt::Display for Msg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.text())
+    }
+}
+
+catalog! {
+    // 起動と接続
+    Fingerprint => "証明書のフィンガープリント: {}", "Certificate fingerprint: {}";
+    Listening => "接続待受中... Ctrl+Cで終了", "Waiting for connections... press Ctrl+C to quit";
+    ClientConnected => "クライアントが接続しました: {}", "Client connected: {}";
+    WebUrl => "ブラウザからも参加できます: https://{}:{}/ (証明書の警告が出たら、フィンガープリントを確かめてから進んでください)", "Peers can also join from a browser: https://{}:{}/ (if the browser warns about the certificate, check the fingerprint before continuing)";
+    WebTransportUrl => "WebTransport (HTTP/3) でも接続できます: https://{}:{}/#{}", "Peers can also connect over WebTransport (HTTP/3): https://{}:{}/#{}";
+    WebSocketEstablished => "WebSocket接続が確立しました。", "WebSocket connection established.";
+    #[cfg(feature = "libp2p")]
+    Libp2pPeerId => "PeerID: {}", "Peer ID: {}";
+    #[cfg(feature = "libp2p")]
+    Libp2pListening => "待受アドレス (相手は connect --backend libp2p <アドレス> で接続できます): {}", "Listening address (peers can run connect --backend libp2p <address>): {}";
+    #[cfg(feature = "libp2p")]
+    Libp2pLocating => "Kademliaで {} のアドレスを探しています...", "Looking up the addresses of {} in Kademlia...";
+    #[cfg(feature = "libp2p")]
+    Libp2pConnected => "{} とlibp2pで接続しました。", "Connected to {} over libp2p.";
+    ServerStarting => "サーバーを起動します: {}", "Starting server: {}";
+    LocalIp => "ローカルIPアドレス: {}", "Local IP address: {}";
+    LocalUrl => "ローカルネットワーク内からの接続用URL: wss://{}:{}", "URL for connections from the local network: wss://{}:{}";
+    FetchingGlobalIp => "グローバルIPアドレスを取得中...", "Looking up the global IP address...";
+    GlobalIp => "グローバルIPアドレス: {}", "Global IP address: {}";
+    ExternalUrl => "外部からの接続用URL: wss://{}:{}", "URL for connections from outside: wss://{}:{}";
+    PortForwardNote => "注意: 以下の設定が必要です:", "Note: the following setup is required:";
+    PortForwardFirewall => "  1. Windowsファイアウォールでポート{}を開放", "  1. Open port {} in Windows Firewall";
+    PortForwardRouter => "  2. ルーターでポートフォワーディング設定 (外部{}→内部{}:{})", "  2. Forward the port on your router (external {} -> internal {}:{})";
+    PortForwardIsp => "  3. ISPがポート{}をブロックしていないことを確認", "  3. Make sure your ISP does not block port {}";
+    LocalOnly => "ローカルアドレスでのみ接続を受け付けます", "Accepting connections on local addresses only";
+    Connecting => "サーバーに接続します: {}", "Connecting to server: {}";
+    FirstContact => "初めて接続する相手です。フィンガープリントを記録します: {}", "First connection to this peer. Recording its fingerprint: {}";
+    FingerprintChanged => "警告: 相手の証明書が記録と異なります (記録: {}, 実際: {})", "Warning: the peer's certificate differs from the recorded one (recorded: {}, actual: {})";
+    SpoofingHint => "なりすましの可能性があります。--strict を指定すると接続を拒否します", "Someone may be impersonating the peer. Use --strict to refuse such connections";
+    UnknownPeerStrict => "未登録の相手です ({})。信頼する場合は `trust add {} {}` を実行してください", "Unknown peer ({}). To trust it, run `trust add {} {}`";
+    ChangedPeerStrict => "相手の証明書が変わっています (記録: {}, 実際: {})。信頼する場合は `trust add {} {}` を実行してください", "The peer's certificate has changed (recorded: {}, actual: {}). To trust it, run `trust add {} {}`";
+    ConnectCode => "接続コード: {} ({}分間有効、1回だけ使えます。相手は `connect --code {}` で接続できます)", "Connect code: {} (valid for {} minutes, single use; the other side can run `connect --code {}`)";
+    CodeRegisterFailed => "接続コードの登録に失敗しました: {}", "Failed to register the connect code: {}";
+    ResolvingCode => "接続コードを問い合わせています: {}", "Looking up connect code: {}";
+    DnsRecord => "DNSに次のTXTレコードを登録すると、相手は `connect {}` で接続できます: {}", "Add this TXT record to DNS and the other side can run `connect {}`: {}";
+    DnsRecordPublished => "DNSのTXTレコードは公開済みです。相手は `connect {}` で接続できます", "The DNS TXT record is published; the other side can run `connect {}`";
+    DnsRecordOutdated => "DNSのTXTレコード ({}) が今の待受と異なります。次の内容に更新してください: {}", "The DNS TXT record ({}) does not match this listener; update it to: {}";
+    NoRendezvous => "ランデブーサーバーが指定されていません (--rendezvous または config.toml の [rendezvous] url)", "No rendezvous server given (use --rendezvous or [rendezvous] url in config.toml)";
+    ConnectQr => "接続用のURL: {}", "URL for connecting: {}";
+    PinnedMismatch => "URLで指定されたフィンガープリントと一致しません (期待値: {}, 実際: {})", "The fingerprint does not match the one in the URL (expected: {}, actual: {})";
+    DaemonStarted => "デーモンを起動しました (制御用ソケット: {})", "Daemon started (control socket: {})";
+    DaemonListening => "接続待受中: {} (証明書のフィンガープリント: {})", "Listening on {} (certificate fingerprint: {})";
+    DaemonApi => "REST APIを待ち受けています: http://{}/ (トークン: {})", "REST API listening on http://{}/ (token: {})";
+    #[cfg(feature = "grpc")]
+    DaemonGrpc => "gRPCを待ち受けています: {} (トークン: {})", "gRPC listening on {} (token: {})";
+    #[cfg(unix)]
+    BridgeListening => "IRCクライアントの接続を待ち受けています: {} (デーモンの制御用ソケット: {})", "Waiting for IRC clients on {} (daemon control socket: {})";
+    #[cfg(unix)]
+    BridgeTopic => "* {} がトピックを「{}」に変更しました", "* {} changed the topic to \"{}\"";
+    #[cfg(unix)]
+    BridgePeerLeft => "相手との接続が切れました", "The connection to the peer was closed";
+    #[cfg(all(unix, feature = "discovery"))]
+    RelayListening => "Outgoing Webhookを待ち受けています: http://{}/ (部屋: {})", "Waiting for outgoing webhooks on http://{}/ (room: {})";
+    #[cfg(all(unix, feature = "discovery"))]
+    RelayYou => "自分", "me";
+    FeedTitle => "{} の部屋 (p2pchat)", "{} room (p2pchat)";
+    DaemonFeed => "部屋 {} のAtomフィードを http://{}/feed.atom で公開しています", "Serving an Atom feed of room {} at http://{}/feed.atom";
+    HealthListening => "状態の確認 (/healthz・/readyz) を待ち受けています: http://{}/", "Health checks (/healthz, /readyz) on http://{}/";
+    #[cfg(all(unix, feature = "discovery"))]
+    RelayUnknownService => "WebhookのURLからDiscordかSlackかを判断できません。--service を指定してください", "Cannot tell Discord or Slack from the webhook URL; specify --service";
+
+    // チャット中の表示
+    Peer => "相手", "peer";
+    Me => "自分", "me";
+    ChatStart => "チャットを開始します。メッセージを入力してEnterキーを押してください (/help でコマンドの一覧を表示します)。", "Chat started. Type a message and press Enter (/help lists the commands).";
+    WhoPeer => "{} ({}) 接続してから{}分", "{} ({}) connected for {} min";
+    WhoCert => "  証明書: {}", "  Certificate: {}";
+    WhoLatency => "  遅延: 平均 {}", "  Latency: {} on average";
+    NotReceived => "(未受信)", "(not received)";
+    SendingFile => "ファイルを送信します: {} ({} bytes)。/cancel {} で取り消せます", "Sending file: {} ({} bytes). Cancel with /cancel {}";
+    CancelledSend => "ファイルの送信を取り消しました: {}", "Cancelled sending the file: {}";
+    CancelledReceive => "ファイルの受信を取り消しました: {}", "Cancelled receiving the file: {}";
+    PeerCancelledFile => "相手がファイルの送信を取り消しました: {}", "The peer cancelled sending the file: {}";
+    NoSuchTransfer => "転送中のファイルはありません: {}", "No transfer in progress: {}";
+    SentFile => "ファイルを送信しました: {}", "File sent: {}";
+    CannotReadFile => "ファイルを読み込めません: {}", "Cannot read the file: {}";
+    NoDrafts => "下書きはありません。", "There are no drafts.";
+    DraftSent => "送信しました: {}", "Sent: {}";
+    DraftsKept => "送信できなかったメッセージは下書きに残しました。", "Messages that could not be sent were kept as drafts.";
+    DraftsDiscarded => "{}件の下書きを破棄しました。", "Discarded {} draft(s).";
+    MailSubject => "p2pchatで届けられなかったメッセージ ({}件)", "Undelivered p2pchat messages ({})";
+    MailNotice => "p2pchatで送ったメッセージ{}件 ({}から) を届けられませんでした。接続すると受け取れます。", "{} p2pchat message(s) sent since {} could not be delivered. Connect to receive them.";
+    MailEncrypted => "p2pchatで届けられなかったメッセージ{}件を暗号化して添えます。このメールの本文を `rust_p2p_chat mail open` に渡し、共有しているパスフレーズで開いてください。", "{} undelivered p2pchat message(s) are attached encrypted. Pipe this mail to `rust_p2p_chat mail open` and enter the shared passphrase.";
+    DraftSaved => "送信できなかったメッセージを下書きに保存しました。次回の接続時に /drafts send で送信できます。", "The message could not be sent and was saved as a draft. Send it with /drafts send next time you connect.";
+    SendQueueFull => "相手の受け取りが追いつかないため、送信の順番待ちに入らないメッセージを捨てました (config.toml の [connection] queue_full)", "The peer is not keeping up, so messages that did not fit in the send queue were dropped ([connection] queue_full in config.toml)";
+    RateLimited => "相手からの受信が多すぎるため、受け取りを遅らせています (config.toml の [rate_limit])", "The peer is sending too much, so receiving is being slowed down ([rate_limit] in config.toml)";
+    RateLimitDisconnect => "相手からの受信が上限を超え続けたため切断しました。", "Disconnected because the peer kept exceeding the receive limits.";
+    DraftsPending => "前回送信できなかったメッセージが{}件あります。/drafts で表示、/drafts send で送信、/drafts discard で破棄します。", "{} message(s) could not be sent last time. /drafts shows them, /drafts send sends them, /drafts discard discards them.";
+    InputClosed => "入力が閉じられました。", "Input closed.";
+    CloseReason => "チャットを終了しました", "left the chat";
+    Interrupted => "終了します。相手に切断を知らせています。", "Shutting down. Notifying the peer.";
+    SessionResumed => "前回のセッションを再開しました。", "Resumed the previous session.";
+    StateConnected => "接続中", "connected";
+    StateResumed => "再開済み", "resumed";
+    StateOffline => "切断", "offline";
+    VerificationPending => "証明書未受信", "no certificate yet";
+    VerificationTrusted => "検証済み", "verified";
+    VerificationUntrusted => "未検証", "unverified";
+    Backfill => "--- 切断中に届かなかったメッセージ ({}件) ---", "--- {} message(s) missed while disconnected ---";
+    ReceivingFile => "ファイルを受信しています: {} ({} bytes)。/cancel recv {} で取り消せます", "Receiving file: {} ({} bytes). Cancel with /cancel recv {}";
+    DeclinedFile => "ファイルの受け取りを断りました: {} ({} bytes)", "Declined file: {} ({} bytes)";
+    ReceivingStream => "ストリームを受信しています: {}", "Receiving stream: {}";
+    DeclinedStream => "ストリームの受け取りを断りました: {}", "Declined stream: {}";
+    PeerReceivedFile => "相手がファイルを受け取りました: {}", "The peer received the file: {}";
+    PeerRejectedFile => "相手がファイルを受け取りませんでした: {}", "The peer did not accept the file: {}";
+    SavedFile => "ファイルを保存しました: {}", "File saved: {}";
+    PingReply => "応答がありました: {} (直近の平均 {})", "Reply received: {} (recent average {})";
+    PeerClosedWithReason => "相手が接続を切断しました: {} - {}", "The peer closed the connection: {} - {}";
+    PeerClosed => "相手が接続を切断しました。", "The peer closed the connection.";
+    PeerIdleClosed => "やり取りのない時間が続いたため、相手が接続を閉じました。", "The peer closed the connection because it was idle.";
+    PeerUnreachable => "相手からの応答がないため、接続が切れたものとみなしました。", "The peer stopped responding; treating the connection as lost.";
+    IdleClosed => "{}分間やり取りがなかったため、接続を閉じます。", "Closing the connection after {} minute(s) without activity.";
+    WebSocketClosed => "WebSocket接続が閉じられました。", "The WebSocket connection was closed.";
+    ChatEnded => "チャット終了。", "Chat ended.";
+    BadSignature => "次のメッセージの署名が正しくありません (seq {})", "The signature of the next message is invalid (seq {})";
+    StatsUptime => "接続時間: {}時間{}分{}秒", "Connected for: {}h {}m {}s";
+    StatsSent => "送信: {} ({}フレーム、メッセージ{}件)", "Sent: {} ({} frames, {} messages)";
+    StatsReceived => "受信: {} ({}フレーム、メッセージ{}件)", "Received: {} ({} frames, {} messages)";
+    StatsThroughput => "転送速度 (直近10秒): 送信 {}/s、受信 {}/s", "Throughput (last 10 s): sent {}/s, received {}/s";
+    StatsReconnects => "再接続: {}回", "Reconnects: {}";
+    StatsCompression => "圧縮率: 1.00 (圧縮なし)", "Compression ratio: 1.00 (uncompressed)";
+    StatsBuffers => "チャンクのバッファ: 使用中 {}、待機 {} (新規確保 {}回、再利用 {}回)", "Chunk buffers: {} in use, {} idle ({} allocated, {} reused)";
+
+    // 画面
+    PressEnterToQuit => "Enterキーを押すと終了します。", "Press Enter to quit.";
+    EarlierReceivedFile => "ファイルを受信しました: {} ({} bytes)", "Received file: {} ({} bytes)";
+    EarlierSentFile => "ファイルを送信しました: {} ({} bytes)", "Sent file: {} ({} bytes)";
+    EarlierMailed => "メールで送りました ({}): {}", "Sent by email ({}): {}";
+    EarlierMailNotice => "メールで知らせました ({}): {}", "Notified by email ({}): {}";
+    ViaNostr => "{} (Nostr経由)", "{} (via Nostr)";
+    EarlierEnd => "--- ここまでが以前のメッセージです ({}件) ---", "--- end of {} earlier message(s) ---";
+    EarlierAvailable => "以前のメッセージが{}件あります。/more で表示します。", "{} earlier message(s) available. Use /more to show them.";
+    NoEarlier => "これより前のメッセージはありません。", "There are no earlier messages.";
+    MorePage => "--- {}件目から{}件目 (全{}件) ---", "--- messages {} to {} of {} ---";
+    WarningLine => "警告: {}", "Warning: {}";
+    Quitting => "終了しています...", "Quitting...";
+    TitleMessages => " メッセージ ", " Messages ";
+    TitleMessagesScrolled => " メッセージ (↑{}行) ", " Messages (↑{} lines) ";
+    TitleParticipants => " 参加者 ", " Participants ";
+    TitleInput => " 入力 ", " Input ";
+    TitleSearch => " 入力履歴を検索: {} (Ctrl-R: さらに前 / Esc: 取り消し) ", " Search input history: {} (Ctrl-R: older / Esc: cancel) ";
+    StatusBar => " {} | {} | {} | 未読: {} | 遅延: {} | PgUp/PgDn: スクロール  Ctrl+C: 終了", " {} | {} | {} | Unread: {} | Latency: {} | PgUp/PgDn: scroll  Ctrl+C: quit";
+    LatencyValue => "{} (平均 {})", "{} (avg {})";
+
+    // チャット中のコマンド
+    HelpHeader => "使えるコマンド (`//` で始めると `/` から始まるメッセージを送れます):", "Commands (start with `//` to send a message that begins with `/`):";
+    HelpHelp => "使えるコマンドの一覧を表示します", "List the available commands";
+    HelpQuit => "接続を切断して終了します", "Disconnect and quit";
+    HelpSend => "ファイルを送信します", "Send a file";
+    HelpWho => "接続中の相手を表示します", "Show the connected peer";
+    HelpStats => "この接続の送受信の統計を表示します", "Show traffic statistics for this connection";
+    HelpPing => "相手との往復時間を測定します", "Measure the round-trip time to the peer";
+    HelpMore => "以前のメッセージをさかのぼって表示します", "Show earlier messages";
+    HelpDrafts => "前回送信できなかったメッセージを表示・送信・破棄します", "Show, send or discard messages that could not be sent";
+    HelpPaste => "単独の `.` の行までを1つのメッセージとして送ります (TUIではShift+EnterかAlt+Enterで改行できます)", "Send everything up to a line with a lone `.` as one message (in the TUI, Shift+Enter or Alt+Enter inserts a line break)";
+    PasteStarted => "複数行のメッセージを入力してください。単独の `.` の行で送信します", "Enter a multi-line message. A line with a lone `.` sends it";
+    UsageSend => "/send <パス>", "/send <path>";
+    UsageCancel => "/cancel [recv] <番号>", "/cancel [recv] <id>";
+    HelpCancel => "送信中 (recvでは受信中) のファイルの転送を取り消します", "Cancel a file being sent (or received, with recv)";
+    #[cfg(feature = "scripting")]
+    UsageScript => "/script [list|load <パス>|unload <名前>]", "/script [list|load <path>|unload <name>]";
+    #[cfg(feature = "scripting")]
+    HelpScript => "読み込んだスクリプトを表示・追加・削除します", "List, load or unload scripts";
+    #[cfg(feature = "scripting")]
+    ScriptLoaded => "スクリプトを読み込みました: {}", "Loaded script: {}";
+    #[cfg(feature = "scripting")]
+    ScriptUnloaded => "スクリプトを削除しました: {}", "Unloaded script: {}";
+    #[cfg(feature = "scripting")]
+    ScriptLoadFailed => "スクリプトを読み込めませんでした: {}", "Could not load the script: {}";
+    #[cfg(feature = "scripting")]
+    NoSuchScript => "読み込まれていないスクリプトです: {}", "No such script: {}";
+    #[cfg(feature = "scripting")]
+    NoScripts => "読み込まれているスクリプトはありません", "No scripts are loaded";
+    UnknownCommand => "不明なコマンドです: /{} (/help で一覧を表示します)", "Unknown command: /{} (/help lists the commands)";
+    Usage => "使い方: {}", "Usage: {}";
+    CommandNeedsSlash => "コマンドは / で始めてください: {}", "Commands must start with /: {}";
+    InvalidJsonCommand => "コマンドとして解釈できません: {}", "Not a valid command: {}";
+
+    // エラー
+    ServerError => "サーバーエラー", "Server error";
+    ClientError => "クライアントエラー", "Client error";
+    SendFailed => "送信エラー", "Send error";
+    SendConnect => "接続できませんでした: {}", "Could not connect: {}";
+    SendNotDelivered => "相手に届いたことを確認できませんでした: {}", "Could not confirm delivery: {}";
+    SendRefused => "相手がファイルを受け取りませんでした: {}", "The peer did not accept the file: {}";
+    DaemonError => "デーモンエラー", "Daemon error";
+    CtlFailed => "デーモンの操作に失敗しました", "Failed to control the daemon";
+    BridgeError => "ブリッジエラー", "Bridge error";
+    #[cfg(not(unix))]
+    DaemonUnsupported => "この環境ではデーモンに対応していません", "The daemon is not supported on this platform";
+}
@@ -0,0 +1,158 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rcgen::generate_simple_self_signed;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::keystore::Keystore;
+
+// メッセージの署名に使うアルゴリズム (rcgenが生成する鍵と同じ)
+pub const SIGNATURE_ALGORITHM: &str = "ECDSA_P256_SHA256";
+
+// 秘密鍵を本人だけが読めるファイルに書く (umaskに任せると他のユーザーにも読めることが多い)
+// 証明書がなく作り直す場合は、残っていた古い鍵のファイルを消してから作る
+pub(crate) fn write_private_key(path: &Path, key_der: &[u8]) -> std::io::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let mut open = std::fs::OpenOptions::new();
+    open.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open, 0o600);
+    std::io::Write::write_all(&mut open.open(path)?, key_der)
+}
+
+// サーバーの証明書と秘密鍵。起動のたびに作り直すとフィンガープリントが変わってしまうため保存して再利用する
+pub struct Identity {
+    pub cert_der: Vec<u8>,
+    pub key_der: Vec<u8>,
+}
+
+impl Identity {
+    pub fn load_or_generate(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+        if cert_path.exists() && key_path.exists() {
+            return Ok(Self {
+                cert_der: std::fs::read(cert_path)?,
+                key_der: std::fs::read(key_path)?,
+            });
+        }
+
+        let cert = generate_simple_self_signed(vec!["localhost".into()])?;
+        let identity = Self {
+            cert_der: cert.cert.der().to_vec(),
+            key_der: cert.key_pair.serialize_der(),
+        };
+        std::fs::write(cert_path, &identity.cert_der)?;
+        write_private_key(key_path, &identity.key_der)?;
+        Ok(identity)
+    }
+
+    // 秘密鍵をキーストアに預ける場合。証明書はファイルから、秘密鍵はキーストアから読む
+    // どちらもなければ生成し、秘密鍵はファイルに書かずにキーストアに預ける
+    pub fn load_or_generate_in(
+        cert_path: impl AsRef<Path>,
+        store: &dyn Keystore,
+        account: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let cert_path = cert_path.as_ref();
+        match (cert_path.exists(), store.get(account)?) {
+            (true, Some(key_der)) => Ok(Self { cert_der: std::fs::read(cert_path)?, key_der }),
+            (false, None) => {
+                let cert = generate_simple_self_signed(vec!["localhost".into()])?;
+                let identity = Self {
+                    cert_der: cert.cert.der().to_vec(),
+                    key_der: cert.key_pair.serialize_der(),
+                };
+                store.set(account, &identity.key_der)?;
+                std::fs::write(cert_path, &identity.cert_der)?;
+                Ok(identity)
+            }
+            (true, None) => Err(format!("キーストア {} に証明書の秘密鍵がありません", store.name()).into()),
+            (false, Some(_)) => Err(format!("キーストア {} の秘密鍵に対応する証明書 ({}) がありません", store.name(), cert_path.display()).into()),
+        }
+    }
+
+    pub fn cert_chain(&self) -> Vec<CertificateDer<'static>> {
+        vec![CertificateDer::from(self.cert_der.clone())]
+    }
+
+    pub fn private_key(&self) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key_der.clone()))
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert_der)
+    }
+
+    // 証明書の秘密鍵でメッセージに署名し、base64で返す
+    pub fn sign(&self, message: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &self.key_der, &rng)
+            .map_err(|_| "秘密鍵を署名に使用できません")?;
+        let signature = key.sign(&rng, message).map_err(|_| "署名に失敗しました")?;
+        Ok(BASE64.encode(signature.as_ref()))
+    }
+}
+
+// 署名の対象 (通信の形式の一部なので p2pchat-core にある)
+pub use p2pchat_core::protocol::signed_content;
+
+// 証明書の公開鍵でbase64の署名を検証する
+pub fn verify(cert_der: &[u8], message: &[u8], signature: &str) -> bool {
+    let Ok(signature) = BASE64.decode(signature) else {
+        return false;
+    };
+    let cert = CertificateDer::from(cert_der);
+    webpki::EndEntityCert::try_from(&cert).is_ok_and(|cert| {
+        cert.verify_signature(webpki::ring::ECDSA_P256_SHA256, message, &signature)
+            .is_ok()
+    })
+}
+
+// 署名の検証に使う証明書 (フィンガープリント → DERのbase64)。相手から受け取ったものと自分のものを保存する
+pub struct CertStore {
+    path: PathBuf,
+    certs: BTreeMap<String, String>,
+}
+
+impl CertStore {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let certs = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, certs })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.certs)?)?;
+        Ok(())
+    }
+
+    // 証明書を追加し、そのフィンガープリントを返す
+    pub fn insert(&mut self, cert_der: &[u8]) -> String {
+        let fingerprint = fingerprint(cert_der);
+        self.certs.insert(fingerprint.clone(), BASE64.encode(cert_der));
+        fingerprint
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&String> {
+        self.certs.get(fingerprint)
+    }
+}
+
+// 証明書(DER)のSHA-256フィンガープリントを `sha256:<hex>` 形式で返す
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert_der);
+    let hex: String = digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::identity;
+use crate::paths::Paths;
+
+// keystore store: 証明書の秘密鍵と履歴のパスフレーズを、平文のファイルの代わりにOSのキーチェーンに預ける
+// (macOSのキーチェーン・Windowsの資格情報マネージャー・Linuxのlibsecret)。デーモンを無人で起動しても、ディスクに平文の鍵が残らない
+//
+//     identity_key        証明書の秘密鍵 (PKCS#8のDER)。預けると identity_key.der を消す
+//     history_passphrase  履歴のパスフレーズ。預けると起動時に尋ねない
+//
+// どれをどこに預けたかはプロファイルの keystore.json に記録する (秘密そのものは書かない)
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// 預ける秘密の名前
+pub const IDENTITY_KEY: &str = "identity_key";
+pub const HISTORY_PASSPHRASE: &str = "history_passphrase";
+// OSのキーチェーンに登録するときのサービス名
+#[cfg(feature = "keychain")]
+const SERVICE: &str = "rust_p2p_chat";
+
+// 秘密の保存先。名前 (アカウント) ごとにバイト列を1つ預かる
+pub trait Keystore: Send + Sync {
+    // keystore.json と表示に使う名前
+    fn name(&self) -> &'static str;
+
+    fn get(&self, account: &str) -> Result<Option<Vec<u8>>>;
+
+    fn set(&self, account: &str, secret: &[u8]) -> Result<()>;
+
+    // 預かっていなければ何もしない
+    fn delete(&self, account: &str) -> Result<()>;
+}
+
+impl std::fmt::Debug for dyn Keystore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Keystore({})", self.name())
+    }
+}
+
+// メモリ上の保存先 (テスト用。組み込み先が鍵を自分で保管する場合は Keystore を実装する)
+// 複製したものは同じ中身を共有する
+#[derive(Clone, Default)]
+pub struct MemoryKeystore {
+    secrets: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl Keystore for MemoryKeystore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get(&self, account: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.secrets.lock().unwrap().get(account).cloned())
+    }
+
+    fn set(&self, account: &str, secret: &[u8]) -> Result<()> {
+        self.secrets.lock().unwrap().insert(account.to_string(), secret.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        self.secrets.lock().unwrap().remove(account);
+        Ok(())
+    }
+}
+
+// OSのキーチェーン (keychain フィーチャー)
+#[cfg(feature = "keychain")]
+pub struct OsKeystore;
+
+#[cfg(feature = "keychain")]
+impl Keystore for OsKeystore {
+    fn name(&self) -> &'static str {
+        "os"
+    }
+
+    fn get(&self, account: &str) -> Result<Option<Vec<u8>>> {
+        match keyring::Entry::new(SERVICE, account)?.get_secret() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(format!("OSのキーチェーンから読めません: {}", e).into()),
+        }
+    }
+
+    fn set(&self, account: &str, secret: &[u8]) -> Result<()> {
+        keyring::Entry::new(SERVICE, account)?
+            .set_secret(secret)
+            .map_err(|e| format!("OSのキーチェーンに保存できません: {}", e).into())
+    }
+
+    fn delete(&self, account: &str) -> Result<()> {
+        match keyring::Entry::new(SERVICE, account)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(format!("OSのキーチェーンから削除できません: {}", e).into()),
+        }
+    }
+}
+
+// OSのキーチェーンを開く
+pub fn os() -> Result<Box<dyn Keystore>> {
+    #[cfg(feature = "keychain")]
+    return Ok(Box::new(OsKeystore));
+    #[cfg(not(feature = "keychain"))]
+    Err("OSのキーチェーンへの保存は含まれていません (keychainフィーチャー)".into())
+}
+
+// keystore.json に記録した保存先を開く。何も預けていなければNone
+pub fn configured(paths: &Paths) -> Result<Option<Box<dyn Keystore>>> {
+    Deposits::load(paths)?.map(|deposits| open(&deposits.keystore)).transpose()
+}
+
+fn open(name: &str) -> Result<Box<dyn Keystore>> {
+    match name {
+        "os" => os(),
+        name => Err(format!("キーストア {} を開けません (keystore.json)", name).into()),
+    }
+}
+
+// プロファイルごとに分けるため、保存先のディレクトリを含めたアカウント名にする
+pub fn account(paths: &Paths, secret: &str) -> String {
+    format!("{}:{}", paths.data_dir.display(), secret)
+}
+
+// keystore.json: どの秘密をどこに預けたか
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deposits {
+    /// 保存先の名前 (Keystore::name)
+    pub keystore: String,
+    #[serde(default)]
+    pub identity_key: bool,
+    #[serde(default)]
+    pub history_passphrase: bool,
+}
+
+impl Deposits {
+    pub fn load(paths: &Paths) -> Result<Option<Self>> {
+        let path = paths.keystore_file();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    // 何も預けていなければ記録を消す
+    fn save(&self, paths: &Paths) -> Result<()> {
+        if self.identity_key || self.history_passphrase {
+            std::fs::write(paths.keystore_file(), serde_json::to_string_pretty(self)?)?;
+        } else if paths.keystore_file().exists() {
+            std::fs::remove_file(paths.keystore_file())?;
+        }
+        Ok(())
+    }
+
+    // 同じプロファイルの秘密を別々の保存先に分けない
+    fn for_store(paths: &Paths, store: &dyn Keystore) -> Result<Self> {
+        match Self::load(paths)? {
+            Some(deposits) if deposits.keystore != store.name() => {
+                Err(format!("既にキーストア {} に預けています。`keystore remove` で戻してから預け直してください", deposits.keystore).into())
+            }
+            Some(deposits) => Ok(deposits),
+            None => Ok(Self { keystore: store.name().to_string(), ..Self::default() }),
+        }
+    }
+}
+
+// 預けた秘密を読む。預けていなければNone
+fn deposited(paths: &Paths, secret: &str, deposited: impl Fn(&Deposits) -> bool) -> Result<Option<Vec<u8>>> {
+    let Some(deposits) = Deposits::load(paths)?.filter(|deposits| deposited(deposits)) else {
+        return Ok(None);
+    };
+    let store = open(&deposits.keystore)?;
+    match store.get(&account(paths, secret))? {
+        Some(value) => Ok(Some(value)),
+        None => Err(format!("キーストア {} に {} が見つかりません", store.name(), secret).into()),
+    }
+}
+
+// keystore.json に記録した保存先から、預けた証明書の秘密鍵を読む
+// (identity_key.der があればそちらを使うため、ファイルを置き直した場合はNone)
+pub fn identity_key(paths: &Paths) -> Result<Option<Vec<u8>>> {
+    if paths.identity_key_file().exists() {
+        return Ok(None);
+    }
+    deposited(paths, IDENTITY_KEY, |deposits| deposits.identity_key)
+}
+
+// 預けた履歴のパスフレーズ
+pub fn history_passphrase(paths: &Paths) -> Result<Option<String>> {
+    Ok(deposited(paths, HISTORY_PASSPHRASE, |deposits| deposits.history_passphrase)?
+        .map(|passphrase| String::from_utf8_lossy(&passphrase).into_owned()))
+}
+
+// identity_key.der を保存先に預け、読み戻せることを確かめてからファイルを消す
+pub fn store_identity_key(paths: &Paths, store: &dyn Keystore) -> Result<()> {
+    let mut deposits = Deposits::for_store(paths, store)?;
+    let key_file = paths.identity_key_file();
+    if !key_file.exists() {
+        return Err("預ける秘密鍵 (identity_key.der) がありません".into());
+    }
+    let key = std::fs::read(&key_file)?;
+    let account = account(paths, IDENTITY_KEY);
+    store.set(&account, &key)?;
+    if store.get(&account)?.as_deref() != Some(key.as_slice()) {
+        return Err(format!("キーストア {} から秘密鍵を読み戻せませんでした。identity_key.der は残しています", store.name()).into());
+    }
+    deposits.identity_key = true;
+    deposits.save(paths)?;
+    std::fs::remove_file(key_file)?;
+    Ok(())
+}
+
+// 履歴のパスフレーズを保存先に預ける (正しいかどうかは呼び出し側で確かめる)
+pub fn store_history_passphrase(paths: &Paths, store: &dyn Keystore, passphrase: &str) -> Result<()> {
+    let mut deposits = Deposits::for_store(paths, store)?;
+    store.set(&account(paths, HISTORY_PASSPHRASE), passphrase.as_bytes())?;
+    deposits.history_passphrase = true;
+    deposits.save(paths)
+}
+
+// 預けた秘密鍵を identity_key.der に書き戻し、預けたものをすべて保存先から消す。戻した秘密の名前を返す
+pub fn remove(paths: &Paths, store: &dyn Keystore) -> Result<Vec<&'static str>> {
+    let Some(deposits) = Deposits::load(paths)? else {
+        return Ok(Vec::new());
+    };
+    let mut removed = Vec::new();
+    if deposits.identity_key {
+        let account = account(paths, IDENTITY_KEY);
+        if !paths.identity_key_file().exists() {
+            let key = store.get(&account)?.ok_or_else(|| format!("キーストア {} に {} が見つかりません", store.name(), IDENTITY_KEY))?;
+            identity::write_private_key(&paths.identity_key_file(), &key)?;
+        }
+        store.delete(&account)?;
+        removed.push(IDENTITY_KEY);
+    }
+    if deposits.history_passphrase {
+        store.delete(&account(paths, HISTORY_PASSPHRASE))?;
+        removed.push(HISTORY_PASSPHRASE);
+    }
+    Deposits::default().save(paths)?;
+    Ok(removed)
+}
+
+// プロファイルを削除するときに、預けた秘密も保存先から消す (書き戻さない)
+pub fn forget(paths: &Paths, store: &dyn Keystore) -> Result<()> {
+    store.delete(&account(paths, IDENTITY_KEY))?;
+    store.delete(&account(paths, HISTORY_PASSPHRASE))
+}
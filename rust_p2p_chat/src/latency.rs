@@ -0,0 +1,35 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// 平均を取る直近の測定数
+const WINDOW: usize = 10;
+
+// 往復時間の直近の測定結果 (WebSocketのPingと /ping の両方を記録する)
+#[derive(Debug, Default)]
+pub struct Latency {
+    samples: VecDeque<Duration>,
+}
+
+impl Latency {
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.samples.back().copied()
+    }
+
+    // 直近WINDOW回の平均
+    pub fn average(&self) -> Option<Duration> {
+        let count = self.samples.len() as u32;
+        (count > 0).then(|| self.samples.iter().sum::<Duration>() / count)
+    }
+}
+
+// 1ms未満でも0と表示しないよう、小数点以下1桁まで表示する
+pub fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
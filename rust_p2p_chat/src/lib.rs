@@ -0,0 +1,290 @@
+//! P2Pチャットのライブラリ。`Peer::listen` / `Peer::connect` で接続し、`ChatSession` で出来事 (`Event`) を受け取りコマンド (`SessionCommand`) を送る
+//! (コマンドラインの `rust_p2p_chat` もこのライブラリを使っている)
+
+#[cfg(feature = "libp2p")]
+pub mod backend;
+pub mod backup;
+pub mod bridge;
+pub mod builder;
+pub mod chat;
+pub mod commands;
+pub mod config;
+pub mod conformance;
+pub mod debug;
+pub mod contacts;
+#[cfg(unix)]
+pub mod daemon;
+pub mod discovery;
+pub mod drafts;
+pub mod event;
+pub mod exit;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod frontend;
+pub mod health;
+pub mod history;
+pub mod hook;
+mod http;
+pub mod i18n;
+pub mod identity;
+pub mod keystore;
+pub mod latency;
+pub mod liveness;
+pub mod logging;
+pub mod mail;
+pub mod migrate;
+#[cfg(feature = "nostr")]
+pub mod nostr;
+pub mod outbox;
+pub mod paths;
+pub mod plumbing;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub use p2pchat_core::protocol;
+#[cfg(feature = "python")]
+mod python;
+pub mod proxy;
+pub mod ratelimit;
+pub mod rendezvous;
+pub mod runtime;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod send;
+pub mod session;
+pub mod stats;
+pub mod systemd;
+pub mod telemetry;
+pub mod theme;
+pub mod tls;
+pub mod transfer;
+pub mod transport;
+pub mod trust;
+pub mod ui;
+pub mod vault;
+mod webhook;
+#[cfg(feature = "webtransport")]
+mod webtransport;
+mod webui;
+
+pub use builder::{ClientBuilder, ClientSettings, ListenerBuilder, ListenerSettings};
+pub use chat::{open_history, ChatOptions, Role};
+pub use event::{DisconnectReason, Event, SessionCommand};
+pub use hook::{HookCommand, HookContext, MessageHook, Verdict};
+pub use runtime::Runtime;
+pub use send::{run_send, Outgoing, SendError, SEND_TRANSFER_ID};
+pub use transport::{run_client, run_server};
+
+use futures_util::{Sink, Stream};
+use health::ListenerState;
+use history::History;
+use paths::Paths;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
+use tracing::Instrument;
+
+// listen の --addr も config.toml の [listen] addr も指定されていない場合の待受アドレス
+pub const DEFAULT_LISTEN_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 8080);
+
+// 他のプログラムに組み込むときの入口。保存先と設定を持ち、接続ごとにChatSessionを返す
+// 端末には何も表示せず、出来事はChatSessionから受け取る
+pub struct Peer {
+    paths: Paths,
+    options: ChatOptions,
+}
+
+impl Peer {
+    pub fn new(paths: Paths, options: ChatOptions) -> Self {
+        // 組み込み先がすでに暗号化プロバイダーを設定していればそれを使う
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        Self { paths, options: options.headless() }
+    }
+
+    // 送受信するメッセージに処理を挟むフックを登録する (以降のlisten・connectのセッションで使う)
+    pub fn add_hook(&mut self, hook: impl hook::MessageHook + 'static) {
+        self.options.hooks.register(hook);
+    }
+
+    // 接続を待ち受け、最初に受け付けた (config.toml の [access] で許可された) 相手とのセッションを返す
+    pub async fn listen(&self, addr: SocketAddr) -> Result<ChatSession, Box<dyn std::error::Error>> {
+        // 設定の組み立てのエラーをawaitの前に返し、Futureを別スレッドに渡せるようにする
+        let settings = self.listener().addr(addr).build()?;
+        self.listen_with(settings).await
+    }
+
+    // 相手に接続する (URIまたは連絡先の名前)。連絡先の設定とフィンガープリントの照合は `connect` コマンドと同じ
+    pub async fn connect(&self, target: &str) -> Result<ChatSession, Box<dyn std::error::Error>> {
+        let settings = self.client().target(target)?.build()?;
+        self.connect_with(settings).await
+    }
+
+    // このPeerの保存先と設定から始める待ち受けの設定 (TLS・上限などを変える場合)
+    pub fn listener(&self) -> ListenerBuilder {
+        ListenerBuilder::new(self.paths.clone(), self.options.clone())
+    }
+
+    // このPeerの保存先と設定から始める接続の設定 (プロキシ・再試行などを変える場合)
+    pub fn client(&self) -> ClientBuilder {
+        ClientBuilder::new(self.paths.clone(), self.options.clone())
+    }
+
+    pub async fn listen_with(&self, settings: ListenerSettings) -> Result<ChatSession, Box<dyn std::error::Error>> {
+        let ListenerSettings { paths, mut options, addr, tls, limits, web, webtransport, health, .. } = settings;
+        let mut listener = options.runtime.bind(addr).await?;
+        let identity = options.identity.load(&paths)?;
+        let tls_acceptor = tls::acceptor(&identity, tls)?;
+        let mut sessions = match webtransport {
+            true => Some(transport::listen_webtransport(listener.local_addr()?, &identity, limits, &options)?),
+            false => None,
+        };
+        let history = open_history(&paths)?;
+        let set_health = |state| {
+            if let Some(health) = &health {
+                health.set_listener(state);
+            }
+        };
+        set_health(ListenerState::Listening);
+        let Some((incoming, peer_addr)) = transport::next_peer(&mut *listener, &tls_acceptor, limits, &options, web, sessions.as_mut()).await? else {
+            set_health(ListenerState::Stopped);
+            return Err("待受を中止しました".into());
+        };
+        set_health(ListenerState::Connected);
+        let ws_stream = incoming.accept(peer_addr, &tls_acceptor, &limits, &paths, &mut options).await?;
+        Ok(ChatSession::spawn(ws_stream, peer_addr.ip().to_string(), Role::Listener, history, paths, options))
+    }
+
+    pub async fn connect_with(&self, settings: ClientSettings) -> Result<ChatSession, Box<dyn std::error::Error>> {
+        let history = open_history(&settings.paths)?;
+        let (ws_stream, peer, negotiated) = transport::connect(&settings, true).await?;
+        let ClientSettings { paths, mut options, .. } = settings;
+        options.negotiated = Some(negotiated);
+        Ok(ChatSession::spawn(ws_stream, peer, Role::Client, history, paths, options))
+    }
+}
+
+// 1つの接続。出来事 (Event) を受け取り、コマンド (SessionCommand) を送る
+// 接続が終わると、Event::Disconnected の後に next_event がNoneを返す
+// Stream<Item = Event> と Sink<SessionCommand> でもあるので、filter・forward・StreamExt::split などのコンビネーターで扱える
+pub struct ChatSession {
+    peer: String,
+    events: mpsc::UnboundedReceiver<Event>,
+    commands: mpsc::UnboundedSender<SessionCommand>,
+    task: runtime::Task,
+}
+
+impl ChatSession {
+    fn spawn<S>(
+        ws_stream: tokio_tungstenite::WebSocketStream<S>,
+        peer: String,
+        role: Role,
+        mut history: History,
+        paths: Paths,
+        options: ChatOptions,
+    ) -> Self
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (event_tx, events) = mpsc::unbounded_channel();
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let name = peer.clone();
+        let span = tracing::info_span!("connection", peer = %peer);
+        let runtime = options.runtime.clone();
+        let task = runtime.spawn(
+            async move {
+                chat::run_session(ws_stream, &name, &mut history, role, &paths, options, event_tx, command_rx).await;
+            }
+            .instrument(span),
+        );
+        Self { peer, events, commands, task }
+    }
+
+    // 履歴上で相手を識別する名前
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    pub fn send_text(&self, body: &str) -> Result<(), SessionClosed> {
+        self.command(SessionCommand::SendText(body.to_string()))
+    }
+
+    pub fn send_file(&self, path: &std::path::Path) -> Result<(), SessionClosed> {
+        self.command(SessionCommand::SendFile(path.to_path_buf()))
+    }
+
+    // 送信中 (Outgoing) または受信中 (Incoming) のファイルの転送を取り消す (idはEvent::TransferProgressのもの)
+    pub fn cancel_transfer(&self, id: u64, direction: history::Direction) -> Result<(), SessionClosed> {
+        self.command(SessionCommand::CancelTransfer { id, direction })
+    }
+
+    // 相手に切断を知らせて接続を閉じる
+    pub fn close(&self) -> Result<(), SessionClosed> {
+        self.command(SessionCommand::Close)
+    }
+
+    pub fn command(&self, command: SessionCommand) -> Result<(), SessionClosed> {
+        self.commands.send(command).map_err(|_| SessionClosed)
+    }
+
+    // 出来事の受け口とコマンドの送り口に分ける (別々のタスクで扱う場合)
+    // 送り口をすべて捨てると、セッションは相手に知らせずに終了する
+    // (StreamとSinkのまま分けるにはStreamExt::split(session)を使う)
+    pub fn split(self) -> (mpsc::UnboundedReceiver<Event>, mpsc::UnboundedSender<SessionCommand>) {
+        (self.events, self.commands)
+    }
+
+    // 接続が終わるまで待つ
+    pub async fn closed(self) {
+        self.task.join().await;
+    }
+}
+
+// 接続が終わったセッションに送ろうとした
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionClosed;
+
+// next_eventと同じ出来事を順に返し、接続が終わると終端になる
+impl Stream for ChatSession {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+// コマンドは溜めておけるので、送る前に待つことはない
+// 閉じる (SinkExt::close、forwardの終わり) と相手に切断を知らせる
+impl Sink<SessionCommand> for ChatSession {
+    type Error = SessionClosed;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SessionClosed>> {
+        Poll::Ready(if self.commands.is_closed() { Err(SessionClosed) } else { Ok(()) })
+    }
+
+    fn start_send(self: Pin<&mut Self>, command: SessionCommand) -> Result<(), SessionClosed> {
+        self.command(command)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SessionClosed>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // すでに接続が終わっている場合も成功にする
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SessionClosed>> {
+        let _ = self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl std::fmt::Display for SessionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("接続は終了しています")
+    }
+}
+
+impl std::error::Error for SessionClosed {}
@@ -0,0 +1,154 @@
+use crate::config::ConnectionConfig;
+use crate::latency::Latency;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+// 接続ごとの生存確認: 相手とのやり取りの記録、必要なときだけ送るPing、往復時間の測定、応答しない相手の検出
+// 相手から何か届いている間はPingを送らない。intervalの間なにも届かなければPingを送り、
+// Pongもほかのフレームも届かないままintervalが過ぎることがmax_missed回続いたら、相手に届かなくなったとみなす
+
+// 送受信したフレームの時刻。送信・受信の途中 (書き込むタスクなど) から記録するので複製して渡す
+#[derive(Clone)]
+pub struct Activity(Arc<Mutex<Times>>);
+
+struct Times {
+    /// 相手から最後に何か (Ping・Pongも含む) 届いた時刻
+    received: Instant,
+    /// 最後にメッセージなどのフレーム (Ping・Pongを除く) をやり取りした時刻
+    data: Instant,
+}
+
+impl Activity {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self(Arc::new(Mutex::new(Times { received: now, data: now })))
+    }
+
+    fn times(&self) -> std::sync::MutexGuard<'_, Times> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn received(&self, message: &Message) {
+        let now = Instant::now();
+        let mut times = self.times();
+        times.received = now;
+        if message.is_text() || message.is_binary() {
+            times.data = now;
+        }
+    }
+
+    pub fn sent(&self, message: &Message) {
+        if message.is_text() || message.is_binary() {
+            self.times().data = Instant::now();
+        }
+    }
+
+    // メッセージなどのやり取りがないまま過ぎた時間 (自動で送り合うPing・Pongはやり取りに数えない)
+    pub fn idle_for(&self) -> Duration {
+        self.times().data.elapsed()
+    }
+
+    fn last_received(&self) -> Instant {
+        self.times().received
+    }
+}
+
+// checkの結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    /// next_checkの後にもう一度確かめる
+    Wait,
+    /// このデータでPingを送る
+    Ping(Vec<u8>),
+    /// Pingへの応答がmax_missed回続けて届かなかった
+    Unreachable,
+}
+
+pub struct Liveness {
+    activity: Activity,
+    /// Pingのデータ (接続してからの経過時間) の起点
+    started: Instant,
+    interval: Duration,
+    max_missed: u32,
+    /// 応答を待っているPingを送った時刻
+    pending: Option<Instant>,
+    missed: u32,
+    latency: Latency,
+}
+
+impl Liveness {
+    pub fn new(config: &ConnectionConfig) -> Self {
+        Self {
+            activity: Activity::new(),
+            started: Instant::now(),
+            interval: Duration::from_secs(config.ping_interval_secs.max(1)),
+            max_missed: config.missed_pongs,
+            pending: None,
+            missed: 0,
+            latency: Latency::default(),
+        }
+    }
+
+    pub fn activity(&self) -> Activity {
+        self.activity.clone()
+    }
+
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    // 次にcheckするまでの時間
+    pub fn next_check(&self) -> Duration {
+        let from = self.pending.unwrap_or_else(|| self.activity.last_received());
+        (from + self.interval).saturating_duration_since(Instant::now())
+    }
+
+    // Pingを送るか、相手に届かなくなったかを決める
+    pub fn check(&mut self) -> Check {
+        let now = Instant::now();
+        let received = self.activity.last_received();
+        if let Some(sent) = self.pending {
+            if received >= sent {
+                // Pingを送ってから何か届いた
+                self.pending = None;
+                self.missed = 0;
+            } else if now.saturating_duration_since(sent) < self.interval {
+                return Check::Wait;
+            } else {
+                self.pending = None;
+                self.missed += 1;
+                tracing::debug!(missed = self.missed, "Pingへの応答が届きませんでした");
+                if self.max_missed > 0 && self.missed >= self.max_missed {
+                    return Check::Unreachable;
+                }
+            }
+        }
+        if self.pending.is_none() && now.saturating_duration_since(self.activity.last_received()) >= self.interval {
+            self.pending = Some(now);
+            return Check::Ping(self.stamp().to_be_bytes().to_vec());
+        }
+        Check::Wait
+    }
+
+    // Pong (相手がPingのデータをそのまま返したもの) から往復時間を測る
+    pub fn pong(&mut self, data: &[u8]) -> Option<Duration> {
+        let sent_at = u64::from_be_bytes(<[u8; 8]>::try_from(data).ok()?);
+        self.pending = None;
+        self.missed = 0;
+        Some(self.measured(sent_at))
+    }
+
+    // /ping で送るPingのデータ (接続してからの経過時間、ナノ秒)
+    pub fn stamp(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    // stampで作ったsent_atが返ってきた: 往復時間を記録して返す
+    pub fn measured(&mut self, sent_at: u64) -> Duration {
+        let rtt = Duration::from_nanos(self.stamp().saturating_sub(sent_at));
+        self.latency.record(rtt);
+        rtt
+    }
+}
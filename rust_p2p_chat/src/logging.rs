@@ -0,0 +1,317 @@
+use chrono::{Local, NaiveDate};
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer};
+
+mod syslog;
+
+pub use syslog::{Facility, SyslogTarget};
+
+// ログファイルの設定
+pub struct LogConfig {
+    pub path: PathBuf,
+    /// このサイズを超えたらローテーションする (バイト)
+    pub max_bytes: u64,
+    /// 日付が変わったらローテーションする
+    pub daily: bool,
+    /// 残しておく古いログファイルの数
+    pub keep: usize,
+}
+
+// syslog・journaldへの送り先 (--syslog)
+pub struct SyslogConfig {
+    pub target: SyslogTarget,
+    /// security の出来事以外のfacility
+    pub facility: Facility,
+}
+
+// ログの出力形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// -q・-v で指定する出力の詳しさ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// エラーのみ表示する
+    Quiet,
+    Normal,
+    /// このアプリのdebugログ (送受信したフレームの概要を含む)
+    Verbose,
+    /// TLS・WebSocketのライブラリを含む詳細なログ
+    Trace,
+}
+
+impl Verbosity {
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Trace,
+        }
+    }
+
+    // コマンドラインで指定されたレベル (Normalの場合は設定ファイルやRUST_LOGに従う)
+    fn level(self) -> Option<&'static str> {
+        match self {
+            Verbosity::Quiet => Some("error"),
+            Verbosity::Normal => None,
+            Verbosity::Verbose => Some("rust_p2p_chat=debug,info"),
+            Verbosity::Trace => Some("rust_p2p_chat=trace,tokio_tungstenite=trace,tungstenite=trace,rustls=debug,debug"),
+        }
+    }
+}
+
+// サイズと日付でローテーションするログファイル
+pub struct RotatingFile {
+    config: LogConfig,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    pub fn open(config: LogConfig) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file,
+            size,
+            opened_on: Local::now().date_naive(),
+        })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        let too_large = self.size > 0 && self.size + incoming as u64 > self.config.max_bytes;
+        let new_day = self.config.daily && Local::now().date_naive() != self.opened_on;
+        too_large || new_day
+    }
+
+    // app.log → app.log.1 → app.log.2 ... と名前をずらし、keepを超えた分は削除する
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            let _ = std::fs::remove_file(numbered(path, self.config.keep));
+            for n in (1..self.config.keep).rev() {
+                let from = numbered(path, n);
+                if from.exists() {
+                    std::fs::rename(&from, numbered(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, numbered(path, 1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+static LOG_MESSAGES: AtomicBool = AtomicBool::new(false);
+// TUIの表示中はstderrへの出力を止める (画面が崩れるため。ログファイルには出力を続ける)
+static STDERR_MUTED: AtomicBool = AtomicBool::new(false);
+
+type ReloadFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+// 出力先ごとのフィルタと、RUST_LOGも設定もないときの既定のレベル
+// 設定の再読み込み時にログレベルを変更するために保持しておく
+static FILTERS: OnceLock<Vec<(ReloadFilter, &'static str)>> = OnceLock::new();
+
+// tracingのsubscriberを設定する。チャットの表示(stdout)と分けるため、診断ログはstderrとログファイルに出す
+// 出力レベルは RUST_LOG で変更できる (stderrの既定はwarn、ログファイルの既定はinfo)
+// 設定ファイルの log_level はRUST_LOGより優先され、実行中に変更できる (set_level)
+// -q・-v はさらに優先され、指定した場合は設定ファイルを書き換えても変わらない (-qはstderrのみに効く)
+// syslogにはログファイルと同じレベル (既定はinfo) で送る
+#[allow(clippy::too_many_arguments)]
+pub fn init(
+    file: Option<LogConfig>,
+    syslog: Option<SyslogConfig>,
+    format: LogFormat,
+    messages: bool,
+    level: Option<&str>,
+    color: bool,
+    verbosity: Verbosity,
+    otlp: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    LOG_MESSAGES.store(messages, Ordering::Relaxed);
+
+    let mut filters: Vec<(ReloadFilter, &'static str)> = Vec::new();
+
+    let stderr_filter = match verbosity.level() {
+        Some(fixed) => reload::Layer::new(EnvFilter::try_new(fixed)?).0,
+        None => {
+            let (filter, handle) = reload::Layer::new(env_filter(level, "warn")?);
+            filters.push((Box::new(move |filter| handle.reload(filter)), "warn"));
+            filter
+        }
+    };
+    let stderr_layer = match format {
+        LogFormat::Text => fmt::layer()
+            .with_ansi(color && std::io::stderr().is_terminal())
+            .with_writer(stderr_writer)
+            .boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(stderr_writer).boxed(),
+    }
+    .with_filter(stderr_filter);
+
+    let file_layer = match file {
+        Some(config) => {
+            let writer = Mutex::new(RotatingFile::open(config)?);
+            let file_filter = match verbosity.level().filter(|_| verbosity > Verbosity::Normal) {
+                Some(fixed) => reload::Layer::new(EnvFilter::try_new(fixed)?).0,
+                None => {
+                    let (filter, handle) = reload::Layer::new(env_filter(level, "info")?);
+                    filters.push((Box::new(move |filter| handle.reload(filter)), "info"));
+                    filter
+                }
+            };
+            let layer = match format {
+                LogFormat::Text => fmt::layer().with_ansi(false).with_writer(writer).boxed(),
+                LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+            };
+            Some(layer.with_filter(file_filter))
+        }
+        None => None,
+    };
+
+    let syslog_layer = match syslog {
+        Some(config) => {
+            let layer = syslog::SyslogLayer::connect(&config.target, config.facility)
+                .map_err(|e| format!("syslogに接続できません ({:?}): {}", config.target, e))?;
+            let syslog_filter = match verbosity.level().filter(|_| verbosity > Verbosity::Normal) {
+                Some(fixed) => reload::Layer::new(EnvFilter::try_new(fixed)?).0,
+                None => {
+                    let (filter, handle) = reload::Layer::new(env_filter(level, "info")?);
+                    filters.push((Box::new(move |filter| handle.reload(filter)), "info"));
+                    filter
+                }
+            };
+            Some(layer.with_filter(syslog_filter))
+        }
+        None => None,
+    };
+
+    let otel_layer = match otlp {
+        Some(endpoint) => Some(otel_layer(endpoint)?),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(otel_layer)
+        .with(stderr_layer)
+        .with(file_layer)
+        .with(syslog_layer)
+        .try_init()?;
+    let _ = FILTERS.set(filters);
+    Ok(())
+}
+
+// 送り出すトレースの提供元 (終了時に残りを送るため)
+#[cfg(feature = "otel")]
+static TRACER_PROVIDER: OnceLock<opentelemetry_sdk::trace::TracerProvider> = OnceLock::new();
+
+type OtelLayer = Box<dyn Layer<tracing_subscriber::Registry> + Send + Sync>;
+
+// このアプリのスパン (connection・dns・tcp・tls・websocket・hello・roundtrip など) をOTLP (gRPC) でendpointに送る
+// ログのレベルとは別に、常にinfo以上のスパンを送る
+#[cfg(feature = "otel")]
+fn otel_layer(endpoint: &str) -> Result<OtelLayer, Box<dyn std::error::Error>> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder().with_tonic().with_endpoint(endpoint).build()?;
+    let resource = opentelemetry_sdk::Resource::new([
+        opentelemetry::KeyValue::new("service.name", "p2pchat"),
+        opentelemetry::KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ]);
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(resource)
+        .build();
+    let tracer = provider.tracer("rust_p2p_chat");
+    let _ = TRACER_PROVIDER.set(provider);
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer).with_filter(EnvFilter::try_new("rust_p2p_chat=info")?);
+    Ok(layer.boxed())
+}
+
+// otel フィーチャーを含めずにビルドした場合は送れない
+#[cfg(not(feature = "otel"))]
+fn otel_layer(_endpoint: &str) -> Result<OtelLayer, Box<dyn std::error::Error>> {
+    Err("トレースの送信は含まれていません (otelフィーチャー)".into())
+}
+
+// 終了する前に、まだ送っていないトレースを送る
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("トレースを送り切れませんでした: {}", e);
+        }
+    }
+}
+
+// 実行中にログレベルを変更する。Noneの場合はRUST_LOGまたは既定のレベルに戻す
+pub fn set_level(level: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    for (reload, default) in FILTERS.get().into_iter().flatten() {
+        reload(env_filter(level, default)?)?;
+    }
+    Ok(())
+}
+
+// stderrへのログ出力を一時的に止める・再開する
+pub fn mute_stderr(muted: bool) {
+    STDERR_MUTED.store(muted, Ordering::Relaxed);
+}
+
+fn stderr_writer() -> Box<dyn Write> {
+    if STDERR_MUTED.load(Ordering::Relaxed) {
+        Box::new(std::io::sink())
+    } else {
+        Box::new(std::io::stderr())
+    }
+}
+
+fn env_filter(level: Option<&str>, default: &str) -> Result<EnvFilter, Box<dyn std::error::Error>> {
+    match level {
+        Some(level) => Ok(EnvFilter::try_new(level)?),
+        None => Ok(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default))),
+    }
+}
+
+// メッセージのメタデータを記録する設定かどうか
+pub fn log_messages() -> bool {
+    LOG_MESSAGES.load(Ordering::Relaxed)
+}
@@ -0,0 +1,280 @@
+use chrono::Local;
+use std::fmt::Write as _;
+use std::io;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// --syslog: 診断ログをsyslog・journaldにも送り、サーバーとして動かすときに他のデーモンと同じ場所で接続の出来事を見られるようにする
+//
+//     local     /dev/log (macOSは /var/run/syslog) に、syslog(3) と同じ形式で送る
+//     journald  journaldのソケットに、出来事の項目 (peer など) を P2PCHAT_* のフィールドとして送る
+//     udp://ホスト:ポート  RFC 5424の形式で別のマシンのsyslogに送る
+//
+// 出来事のレベルをseverityにし、security = true の出来事 (アクセス制限による拒否・トークンの不一致・証明書の変化など) は
+// --syslog-facility の代わりに authpriv で送る (認証のログとして、権限のある管理者だけが読めるファイルに分けられる)
+
+// syslogのプログラム名
+const IDENTIFIER: &str = "p2pchat";
+// セキュリティの出来事のfacility (authpriv)
+const AUTHPRIV: u8 = 10;
+#[cfg(unix)]
+const LOCAL_SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog"];
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// ログの送り先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTarget {
+    Local,
+    Journald,
+    /// host:port
+    Udp(String),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "local" => Ok(SyslogTarget::Local),
+            "journald" => Ok(SyslogTarget::Journald),
+            value => match value.strip_prefix("udp://") {
+                Some(addr) if !addr.is_empty() => Ok(SyslogTarget::Udp(if addr.contains(':') { addr.to_string() } else { format!("{}:514", addr) })),
+                _ => Err(format!("local・journald・udp://ホスト:ポート のいずれかを指定してください ({})", value)),
+            },
+        }
+    }
+}
+
+// 接続やエラーのログのfacility
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Facility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+enum Socket {
+    #[cfg(unix)]
+    Local(UnixDatagram, PathBuf),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+pub(super) struct SyslogLayer {
+    socket: Socket,
+    facility: Facility,
+    hostname: String,
+}
+
+impl SyslogLayer {
+    pub(super) fn connect(target: &SyslogTarget, facility: Facility) -> io::Result<Self> {
+        let socket = match target {
+            #[cfg(unix)]
+            SyslogTarget::Local => {
+                let path = LOCAL_SOCKETS.iter().map(Path::new).find(|path| path.exists()).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "syslogのソケット (/dev/log) が見つかりません")
+                })?;
+                Socket::Local(UnixDatagram::unbound()?, path.to_path_buf())
+            }
+            #[cfg(unix)]
+            SyslogTarget::Journald => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(JOURNALD_SOCKET)?;
+                Socket::Journald(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Local | SyslogTarget::Journald => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "このOSではudp://ホスト:ポート に送ってください"));
+            }
+            SyslogTarget::Udp(addr) => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.connect(addr)?;
+                Socket::Udp(socket)
+            }
+        };
+        Ok(Self { socket, facility, hostname: hostname() })
+    }
+
+    fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        match &self.socket {
+            // syslogデーモンが再起動してもそのまま送れるよう、毎回宛先を指定する
+            #[cfg(unix)]
+            Socket::Local(socket, path) => socket.send_to(datagram, path).map(drop),
+            #[cfg(unix)]
+            Socket::Journald(socket) => socket.send(datagram).map(drop),
+            Socket::Udp(socket) => socket.send(datagram).map(drop),
+        }
+    }
+
+    fn encode(&self, priority: u8, severity: u8, line: &str, fields: &[(String, String)]) -> Vec<u8> {
+        let pid = std::process::id();
+        match &self.socket {
+            #[cfg(unix)]
+            Socket::Local(..) => format!("<{}>{} {}[{}]: {}", priority, Local::now().format("%b %e %H:%M:%S"), IDENTIFIER, pid, line).into_bytes(),
+            #[cfg(unix)]
+            Socket::Journald(_) => {
+                let mut datagram = Vec::new();
+                journal_field(&mut datagram, "MESSAGE", line);
+                journal_field(&mut datagram, "PRIORITY", &severity.to_string());
+                journal_field(&mut datagram, "SYSLOG_FACILITY", &(priority >> 3).to_string());
+                journal_field(&mut datagram, "SYSLOG_IDENTIFIER", IDENTIFIER);
+                journal_field(&mut datagram, "SYSLOG_PID", &pid.to_string());
+                for (name, value) in fields {
+                    journal_field(&mut datagram, &journal_name(name), value);
+                }
+                datagram
+            }
+            Socket::Udp(_) => {
+                let timestamp = Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+                format!("<{}>1 {} {} {} {} - - {}", priority, timestamp, self.hostname, IDENTIFIER, pid, line).into_bytes()
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // 出来事の親のスパン (connection の peer など) の項目も載せるため、スパンの項目を覚えておく
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        // 外側のスパンから順に並べ、出来事の項目を最後にする
+        let mut all = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(span_fields)) = span.extensions().get::<SpanFields>() {
+                    all.extend(span_fields.iter().cloned());
+                }
+            }
+        }
+        all.extend(fields.fields);
+
+        let mut line = fields.message;
+        for (name, value) in &all {
+            let _ = write!(line, " {}={}", name, value);
+        }
+        let severity = severity(*event.metadata().level());
+        let facility = if fields.security { AUTHPRIV } else { self.facility.code() };
+        // ログを送れなくても、チャットは続ける
+        let _ = self.send(&self.encode(facility << 3 | severity, severity, &line, &all));
+    }
+}
+
+struct SpanFields(Vec<(String, String)>);
+
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Vec<(String, String)>,
+    security: bool,
+}
+
+impl Visit for Fields {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        match field.name() {
+            "security" => self.security = value,
+            name => self.fields.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.fields.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => self.fields.push((name.to_string(), format!("{:?}", value))),
+        }
+    }
+}
+
+// tracingのレベルをsyslogのseverityにする (err・warning・info・debug)
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+// 改行を含む値は長さを前に付けるバイナリの形式で書く (journaldのネイティブプロトコル)
+#[cfg(unix)]
+fn journal_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+// journaldのフィールド名は英大文字・数字・_ だけ
+#[cfg(unix)]
+fn journal_name(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+    format!("P2PCHAT_{}", name)
+}
+
+// RFC 5424のHOSTNAME (分からなければ "-")
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+        .unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,300 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Local;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, pki_types::ServerName, ClientConfig};
+
+use crate::chat::record;
+use crate::config::{Config, MailConfig};
+use crate::contacts::AddressBook;
+use crate::drafts::{Draft, Drafts};
+use crate::history::{EventKind, History};
+use crate::i18n::Msg;
+use crate::paths::Paths;
+use crate::runtime::{BoxStream, Stream};
+use crate::vault::{self, Vault};
+
+// mail gateway: 相手に届けられなかったメッセージ (下書き) が [mail] after_mins より古くなったら、
+// 連絡先のメールアドレスに相手ごと1通にまとめてSMTPで送る
+// 連絡先に mail_passphrase があれば本文を暗号化して添え (相手は `mail open` で開く)、なければ届いていないことだけを知らせる
+//
+//     -----BEGIN P2PCHAT MAIL-----
+//     salt=<base64> iterations=200000
+//     enc1:<base64>
+//     -----END P2PCHAT MAIL-----
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// SMTPのパスワードと、`mail open` のパスフレーズを渡す環境変数
+pub const PASSWORD_ENV: &str = "P2PCHAT_SMTP_PASSWORD";
+pub const PASSPHRASE_ENV: &str = "P2PCHAT_MAIL_PASSPHRASE";
+// 下書きを確かめる間隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// SMTPサーバーとの1通分のやり取りを待つ上限
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+const BEGIN: &str = "-----BEGIN P2PCHAT MAIL-----";
+const END: &str = "-----END P2PCHAT MAIL-----";
+
+// 送るメール
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub subject: String,
+    /// 本文の行
+    pub lines: Vec<String>,
+}
+
+// 相手宛ての下書きをまとめたメール。passphraseがあれば本文を暗号化して添える
+pub fn compose(drafts: &[Draft], passphrase: Option<&str>) -> Result<Mail> {
+    let subject = Msg::MailSubject.with(&[&drafts.len()]);
+    let Some(passphrase) = passphrase else {
+        let since = drafts.iter().map(|draft| draft.saved_at).min().unwrap_or_else(Local::now);
+        let lines = vec![Msg::MailNotice.with(&[&drafts.len(), &since.format("%Y-%m-%d %H:%M")])];
+        return Ok(Mail { subject, lines });
+    };
+    let mut lines = vec![Msg::MailEncrypted.with(&[&drafts.len()]), String::new()];
+    lines.extend(seal(drafts, passphrase)?.lines().map(str::to_string));
+    Ok(Mail { subject, lines })
+}
+
+// 下書きを暗号化したブロック
+pub fn seal(drafts: &[Draft], passphrase: &str) -> Result<String> {
+    let salt = vault::random_salt()?;
+    let sealed = Vault::derive(passphrase, &salt, vault::PBKDF2_ITERATIONS)?.seal(&serde_json::to_vec(drafts)?)?;
+    Ok(format!("{}\nsalt={} iterations={}\n{}\n{}", BEGIN, BASE64.encode(salt), vault::PBKDF2_ITERATIONS, sealed, END))
+}
+
+// メールの本文から暗号化したブロックを探して開く (引用の "> " は取り除く)
+pub fn open(text: &str, passphrase: &str) -> Result<Vec<Draft>> {
+    let mut lines = text
+        .lines()
+        .map(|line| line.trim_start_matches(['>', ' ']).trim())
+        .skip_while(|line| *line != BEGIN)
+        .skip(1);
+    let parameters = lines.next().ok_or("暗号化したブロック (-----BEGIN P2PCHAT MAIL-----) が見つかりません")?;
+    let (mut salt, mut iterations) = (None, None);
+    for field in parameters.split_whitespace() {
+        match field.split_once('=') {
+            Some(("salt", value)) => salt = BASE64.decode(value).ok(),
+            Some(("iterations", value)) => iterations = value.parse().ok(),
+            _ => {}
+        }
+    }
+    let (salt, iterations) = salt.zip(iterations).ok_or("暗号化したブロックのパラメータ (salt・iterations) を読めません")?;
+    let sealed: String = lines.take_while(|line| *line != END).collect();
+    let plaintext = Vault::derive(passphrase, &salt, iterations)?.open(&sealed)?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+// [mail] の設定に従い、止められるまで下書きを確かめてメールで送る。onceなら1回だけ確かめる
+pub async fn run_gateway(paths: &Paths, history: &mut History, once: bool) -> Result<()> {
+    loop {
+        // 設定の変更は次に確かめるときに反映する
+        let config = Config::load(&paths.config_file())?.mail;
+        match deliver_due(paths, &config, history).await {
+            Ok(0) => tracing::debug!("メールで送る下書きはありません"),
+            Ok(mailed) => println!("{}件のメッセージをメールで送りました。", mailed),
+            Err(e) if once => return Err(e),
+            Err(e) => tracing::error!(error = %e, "下書きをメールで送れませんでした"),
+        }
+        if once {
+            return Ok(());
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+// after_minsより古く、まだ送っていない下書きを相手ごとにメールで送り、送ったメッセージの数を返す
+// 暗号化した本文を送った下書きは届けたものとして消し、知らせのみの場合は次に接続したときに送れるよう残す
+pub async fn deliver_due(paths: &Paths, config: &MailConfig, history: &mut History) -> Result<usize> {
+    let smtp = Smtp::from_config(config)?;
+    let book = AddressBook::load(paths.contacts_file())?;
+    let mut drafts = Drafts::load(paths.drafts_file())?;
+    let deadline = Local::now() - chrono::Duration::minutes(config.after_mins as i64);
+    let mut mailed = 0;
+    for peer in drafts.peers() {
+        let Some((name, contact)) = book.find_by_peer(&peer) else {
+            continue;
+        };
+        let Some(address) = &contact.email else {
+            continue;
+        };
+        let (due, mut kept): (Vec<Draft>, Vec<Draft>) =
+            drafts.get(&peer).iter().cloned().partition(|draft| draft.saved_at <= deadline && draft.mailed_at.is_none());
+        if due.is_empty() {
+            continue;
+        }
+        let mail = compose(&due, contact.mail_passphrase.as_deref())?;
+        if let Err(e) = smtp.send(address, &mail).await {
+            tracing::warn!(contact = %name, error = %e, "下書きをメールで送れませんでした");
+            continue;
+        }
+        tracing::info!(contact = %name, count = due.len(), "届けられなかったメッセージをメールで送りました");
+        let encrypted = contact.mail_passphrase.is_some();
+        mailed += due.len();
+        for draft in &due {
+            record(history, &peer, EventKind::Mailed { address: address.clone(), body: draft.body.clone(), encrypted });
+        }
+        if !encrypted {
+            let now = Local::now();
+            kept.extend(due.into_iter().map(|draft| Draft { mailed_at: Some(now), ..draft }));
+            kept.sort_by_key(|draft| draft.saved_at);
+        }
+        drafts.replace(&peer, kept);
+        // 後の相手で失敗しても同じメールを送り直さないよう、1通ごとに保存する
+        drafts.save()?;
+    }
+    Ok(mailed)
+}
+
+// SMTPサーバーへの送信
+struct Smtp {
+    host: String,
+    port: u16,
+    /// 接続直後からTLS (smtps)。falseならSTARTTLSを使う
+    implicit_tls: bool,
+    credentials: Option<(String, String)>,
+    from: String,
+}
+
+impl Smtp {
+    fn from_config(config: &MailConfig) -> Result<Self> {
+        let smtp = config.smtp.as_deref().ok_or("config.toml の [mail] smtp でSMTPサーバーを指定してください")?;
+        let url = url::Url::parse(smtp).map_err(|e| format!("SMTPサーバーのURLを解釈できません ({}): {}", smtp, e))?;
+        let implicit_tls = match url.scheme() {
+            "smtps" => true,
+            "smtp" => false,
+            scheme => return Err(format!("SMTPサーバーのURLは smtps:// か smtp:// で指定してください: {}", scheme).into()),
+        };
+        let host = url.host_str().ok_or("SMTPサーバーのURLにホストがありません")?.trim_matches(['[', ']']).to_string();
+        let port = url.port().unwrap_or(if implicit_tls { 465 } else { 587 });
+        let password = std::env::var(PASSWORD_ENV).ok().or_else(|| config.password.clone());
+        let credentials = match (&config.username, password) {
+            (Some(username), Some(password)) => Some((username.clone(), password)),
+            (Some(_), None) => return Err(format!("SMTPのパスワードを [mail] password か環境変数 {} で指定してください", PASSWORD_ENV).into()),
+            (None, _) => None,
+        };
+        let from = config.from.clone().ok_or("config.toml の [mail] from で差出人のメールアドレスを指定してください")?;
+        Ok(Self { host, port, implicit_tls, credentials, from })
+    }
+
+    async fn send(&self, to: &str, mail: &Mail) -> Result<()> {
+        tokio::time::timeout(SMTP_TIMEOUT, self.exchange(to, mail))
+            .await
+            .map_err(|_| format!("SMTPサーバー ({}) から{}秒以内に応答がありません", self.host, SMTP_TIMEOUT.as_secs()))?
+    }
+
+    async fn exchange(&self, to: &str, mail: &Mail) -> Result<()> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let stream: BoxStream = match self.implicit_tls {
+            true => Box::new(self.tls(tcp).await?),
+            false => Box::new(tcp),
+        };
+        let mut session = BufReader::new(stream);
+        reply(&mut session, 220).await?;
+        let mut extensions = ehlo(&mut session).await?;
+        if !self.implicit_tls {
+            if extensions.iter().any(|line| line.eq_ignore_ascii_case("STARTTLS")) {
+                command(&mut session, "STARTTLS", 220).await?;
+                let stream: BoxStream = Box::new(self.tls(session.into_inner()).await?);
+                session = BufReader::new(stream);
+                extensions = ehlo(&mut session).await?;
+            } else if self.credentials.is_some() {
+                return Err("SMTPサーバーがSTARTTLSに対応していないため、パスワードを送れません".into());
+            }
+        }
+        if let Some((username, password)) = &self.credentials {
+            let mechanisms: Vec<String> = extensions
+                .iter()
+                .filter_map(|line| line.strip_prefix("AUTH ").or(line.strip_prefix("AUTH=")))
+                .flat_map(|list| list.split_whitespace().map(str::to_ascii_uppercase))
+                .collect();
+            if mechanisms.iter().any(|mechanism| mechanism == "PLAIN") || mechanisms.is_empty() {
+                let token = BASE64.encode(format!("\0{}\0{}", username, password));
+                command(&mut session, &format!("AUTH PLAIN {}", token), 235).await?;
+            } else {
+                command(&mut session, "AUTH LOGIN", 334).await?;
+                command(&mut session, &BASE64.encode(username), 334).await?;
+                command(&mut session, &BASE64.encode(password), 235).await?;
+            }
+        }
+        command(&mut session, &format!("MAIL FROM:<{}>", mailbox(&self.from)), 250).await?;
+        command(&mut session, &format!("RCPT TO:<{}>", mailbox(to)), 250).await?;
+        command(&mut session, "DATA", 354).await?;
+        let message = format!("{}\r\n.\r\n", self.message(to, mail));
+        session.get_mut().write_all(message.as_bytes()).await?;
+        reply(&mut session, 250).await?;
+        command(&mut session, "QUIT", 221).await?;
+        Ok(())
+    }
+
+    async fn tls<S: Stream>(&self, tcp: S) -> Result<tokio_rustls::client::TlsStream<S>> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs()?);
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let name = ServerName::try_from(self.host.clone())?;
+        Ok(tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, tcp).await?)
+    }
+
+    // ヘッダーと、base64で76文字ごとに折り返した本文 (行頭の . をエスケープせずに済む)
+    fn message(&self, to: &str, mail: &Mail) -> String {
+        let body = BASE64.encode(mail.lines.join("\r\n"));
+        let wrapped: Vec<&str> = body.as_bytes().chunks(76).map(|chunk| std::str::from_utf8(chunk).unwrap_or_default()).collect();
+        let domain = mailbox(&self.from).rsplit_once('@').map_or("localhost", |(_, domain)| domain).to_string();
+        let mut id = [0u8; 12];
+        let _ = ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id);
+        [
+            format!("From: {}", self.from),
+            format!("To: {}", to),
+            format!("Subject: =?UTF-8?B?{}?=", BASE64.encode(&mail.subject)),
+            format!("Date: {}", Local::now().to_rfc2822()),
+            format!("Message-ID: <{}@{}>", id.iter().map(|b| format!("{:02x}", b)).collect::<String>(), domain),
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: text/plain; charset=UTF-8".to_string(),
+            "Content-Transfer-Encoding: base64".to_string(),
+            String::new(),
+            wrapped.join("\r\n"),
+        ]
+        .join("\r\n")
+    }
+}
+
+// "名前 <alice@example.org>" のような書き方からメールアドレスだけを取り出す
+fn mailbox(address: &str) -> &str {
+    match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address.trim(),
+    }
+}
+
+async fn ehlo(session: &mut BufReader<BoxStream>) -> Result<Vec<String>> {
+    let lines = command(session, "EHLO localhost", 250).await?;
+    Ok(lines.into_iter().skip(1).collect())
+}
+
+async fn command(session: &mut BufReader<BoxStream>, line: &str, expected: u16) -> Result<Vec<String>> {
+    session.get_mut().write_all(format!("{}\r\n", line).as_bytes()).await?;
+    reply(session, expected).await
+}
+
+// 応答 (複数行の場合は "250-..." が続き "250 ..." で終わる) を読み、コードがexpectedでなければエラーにする
+async fn reply(session: &mut BufReader<BoxStream>, expected: u16) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if session.read_line(&mut line).await? == 0 {
+            return Err("SMTPサーバーが接続を切断しました".into());
+        }
+        let line = line.trim_end();
+        let code: u16 = line.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| format!("SMTPの応答を解釈できません: {}", line))?;
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            if code != expected {
+                return Err(format!("SMTPサーバーが断りました: {}", line).into());
+            }
+            return Ok(lines);
+        }
+    }
+}
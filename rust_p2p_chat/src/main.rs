@@ -1,318 +1,720 @@
-use clap::{Parser, Subcommand};
-use futures_util::{stream::StreamExt, SinkExt};
-use rcgen::generate_simple_self_signed;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{stdin, AsyncBufReadExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::rustls::{self, pki_types::CertificateDer, ClientConfig, ServerConfig};
-use tokio_rustls::TlsConnector;
-
-// コマンドライン引数の定義
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    #[command(subcommand)]
-    command: Commands,
+mod cli;
+
+use clap::{CommandFactory, FromArgMatches};
+use cli::{write_manpages, BackupCommands, BridgeCommands, Cli, Commands, ContactsCommands, CtlCommands, DebugCommands, HistoryCommands, KeystoreCommands, MailCommands, ProfileCommands, TelemetryCommands, TrustCommands};
+#[cfg(unix)]
+use rust_p2p_chat::daemon;
+#[cfg(feature = "libp2p")]
+use rust_p2p_chat::backend;
+#[cfg(feature = "nostr")]
+use cli::NostrCommands;
+#[cfg(feature = "nostr")]
+use rust_p2p_chat::nostr;
+use rust_p2p_chat::config::{self, Config, RetentionPolicy};
+use rust_p2p_chat::contacts::{AddressBook, Contact};
+use rust_p2p_chat::history::{self, ExportFilter, ExportFormat, History};
+use rust_p2p_chat::i18n::{self, Msg};
+use rust_p2p_chat::paths::Paths;
+use rust_p2p_chat::transfer::{self, DownloadConfig};
+use rust_p2p_chat::trust::{self, KnownPeers};
+use rust_p2p_chat::exit::{self, ErrorFormat, Failure};
+use rust_p2p_chat::telemetry::{self, Telemetry};
+use rust_p2p_chat::{backup, conformance, debug, health, identity, keystore, logging, mail, migrate, plumbing, rendezvous, ui, vault};
+use rust_p2p_chat::builder::{IdentitySource, ReconnectPolicy};
+use rust_p2p_chat::proxy::Proxy;
+use rust_p2p_chat::{open_history, run_client, run_send, run_server, ChatOptions, Runtime, ClientBuilder, ClientSettings, ListenerBuilder, Outgoing, SEND_TRANSFER_ID};
+use rust_p2p_chat::DEFAULT_LISTEN_ADDR;
+use tokio_util::sync::CancellationToken;
+
+
+// 接続コードに使うランデブーサーバー (コマンドライン・環境変数 > config.toml)
+fn rendezvous_server(flag: &Option<String>, config: &Config) -> Result<String, Msg> {
+    flag.clone().or_else(|| config.rendezvous.url.clone()).ok_or(Msg::NoRendezvous)
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// サーバーとして起動し、接続を待ち受けます
-    Listen {
-        #[arg(short, long, default_value = "127.0.0.1:8080")]
-        addr: SocketAddr,
-    },
-    /// 指定したサーバーにクライアントとして接続します
-    Connect {
-        #[arg(help = "接続先のサーバーアドレス (例: wss://127.0.0.1:8080)")]
-        uri: String,
-    },
+// known_peersの操作
+fn run_trust(action: &TrustCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    let mut known = KnownPeers::load(paths.known_peers_file())?;
+
+    match action {
+        TrustCommands::Add { host, fingerprint, contact } => {
+            let host = trust::host_key(host)?;
+            // アドレス帳側にもフィンガープリントを反映する
+            if let Some(name) = contact {
+                let mut book = AddressBook::load(paths.contacts_file())?;
+                let entry = book
+                    .get_mut(name)
+                    .ok_or_else(|| format!("連絡先 {} は登録されていません", name))?;
+                entry.fingerprint = Some(fingerprint.clone());
+                book.save()?;
+            }
+            known.insert(&host, fingerprint, contact.clone());
+            known.save()?;
+            println!("信頼済みとして登録しました: {} {}", host, fingerprint);
+        }
+        TrustCommands::List => {
+            if known.iter().next().is_none() {
+                println!("信頼済みの相手はいません。");
+            }
+            for (host, peer) in known.iter() {
+                match &peer.contact {
+                    Some(contact) => println!("{} {} ({})", host, peer.fingerprint, contact),
+                    None => println!("{} {}", host, peer.fingerprint),
+                }
+            }
+        }
+        TrustCommands::Remove { host } => {
+            let host = trust::host_key(host)?;
+            if known.remove(&host).is_none() {
+                return Err(format!("{} は登録されていません", host).into());
+            }
+            known.save()?;
+            println!("削除しました: {}", host);
+        }
+    }
+
+    Ok(())
 }
 
-// グローバルIPアドレスを取得する関数
-async fn get_global_ip() -> Result<String, Box<dyn std::error::Error>> {
-    // 複数のサービスを試行して、より確実にIPを取得
-    let services = [
-        "https://api.ipify.org",
-        "https://httpbin.org/ip",
-        "https://icanhazip.com",
-    ];
+// アドレス帳の操作
+fn run_contacts(action: &ContactsCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    let mut book = AddressBook::load(paths.contacts_file())?;
 
-    for service in &services {
-        match try_get_ip_from_service(service).await {
-            Ok(ip) => return Ok(ip),
-            Err(e) => {
-                eprintln!("{}からのIP取得に失敗: {}", service, e);
-                continue;
+    match action {
+        ContactsCommands::Add { name, uri, fingerprint, transport, notes, defaults } => {
+            url::Url::parse(uri)?;
+            let mut contact = Contact {
+                uri: uri.clone(),
+                fingerprint: fingerprint.clone(),
+                transport: transport.clone(),
+                notes: notes.clone(),
+                nickname: None,
+                strict: false,
+                accept_files: true,
+                max_file_mb: None,
+                notify: true,
+                download_dir: None,
+                email: None,
+                mail_passphrase: None,
+                nostr: None,
+            };
+            defaults.apply(&mut contact);
+            book.add(name, contact)?;
+            book.save()?;
+            // フィンガープリントが分かっていれば known_peers にも登録する
+            if let Some(fingerprint) = fingerprint {
+                let mut known = KnownPeers::load(paths.known_peers_file())?;
+                known.insert(&trust::host_key(uri)?, fingerprint, Some(name.clone()));
+                known.save()?;
+            }
+            println!("連絡先を追加しました: {}", name);
+        }
+        ContactsCommands::List => {
+            if book.iter().next().is_none() {
+                println!("登録済みの連絡先はありません。");
+            }
+            for (name, contact) in book.iter() {
+                println!("{} ({}, {})", name, contact.uri, contact.transport);
+                if let Some(fingerprint) = &contact.fingerprint {
+                    println!("  フィンガープリント: {}", fingerprint);
+                }
+                if let Some(notes) = &contact.notes {
+                    println!("  メモ: {}", notes);
+                }
+                if let Some(nickname) = &contact.nickname {
+                    println!("  表示名: {}", nickname);
+                }
+                if contact.strict {
+                    println!("  strict: 未登録・証明書が変わった相手には接続しない");
+                }
+                if !contact.accept_files {
+                    println!("  ファイル: 受け取らない");
+                } else if let Some(max) = contact.max_file_mb {
+                    println!("  ファイル: {} MBまで受け取る", max);
+                }
+                if !contact.notify {
+                    println!("  通知: オフ");
+                }
+                if let Some(dir) = &contact.download_dir {
+                    println!("  ファイルの保存先: {}", dir.display());
+                }
+                if let Some(email) = &contact.email {
+                    let mode = if contact.mail_passphrase.is_some() { "暗号化した本文" } else { "知らせのみ" };
+                    println!("  メール: {} ({})", email, mode);
+                }
+                if let Some(nostr) = &contact.nostr {
+                    println!("  Nostr: {}", nostr);
+                }
+            }
+        }
+        ContactsCommands::Set { name, defaults } => {
+            let contact = book
+                .get_mut(name)
+                .ok_or_else(|| format!("連絡先 {} は登録されていません", name))?;
+            defaults.apply(contact);
+            book.save()?;
+            println!("連絡先を更新しました: {}", name);
+        }
+        ContactsCommands::Remove { name } => {
+            if book.remove(name).is_none() {
+                return Err(format!("連絡先 {} は登録されていません", name).into());
             }
+            book.save()?;
+            println!("連絡先を削除しました: {}", name);
         }
     }
 
-    Err("すべてのIPサービスからの取得に失敗しました".into())
+    Ok(())
 }
 
-async fn try_get_ip_from_service(url: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(10))
-        .build()?;
-    
-    let response = client.get(url).send().await?;
-    let text = response.text().await?;
-    
-    // サービスによってレスポンス形式が異なるため、IPアドレスを抽出
-    let ip = if url.contains("httpbin.org") {
-        // httpbin.orgはJSON形式: {"origin": "x.x.x.x"}
-        let json: serde_json::Value = serde_json::from_str(&text)?;
-        json["origin"].as_str().unwrap_or("").to_string()
-    } else {
-        // その他のサービスはプレーンテキスト
-        text.trim().to_string()
-    };
-    
-    // IPアドレスの簡単な検証
-    if ip.is_empty() || !ip.chars().any(|c| c.is_ascii_digit()) {
-        return Err("無効なIPアドレス形式".into());
+// プロファイルの操作
+fn run_profile(action: &ProfileCommands, data_dir: Option<&std::path::Path>, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        ProfileCommands::List => {
+            for profile in paths.list_profiles()? {
+                let marker = if profile == paths.profile { "*" } else { " " };
+                println!("{} {}", marker, profile);
+            }
+        }
+        ProfileCommands::Create { name } => {
+            if paths.list_profiles()?.contains(name) {
+                return Err(format!("プロファイル {} は既に存在します", name).into());
+            }
+            let created = Paths::resolve(data_dir, name, true)?;
+            let identity = identity::Identity::load_or_generate(created.identity_cert_file(), created.identity_key_file())?;
+            println!("プロファイルを作成しました: {}", name);
+            println!("証明書のフィンガープリント: {}", identity.fingerprint());
+        }
+        ProfileCommands::Delete { name, yes } => {
+            if !yes {
+                return Err(format!(
+                    "プロファイル {} の鍵・履歴・アドレス帳がすべて削除されます。実行するには --yes を指定してください",
+                    name
+                )
+                .into());
+            }
+            // キーチェーンの秘密を消す前に、削除できるプロファイルか確かめる
+            paths.deletable_profile(name)?;
+            // キーチェーンに預けた秘密も残さない
+            let deleted = Paths::resolve(data_dir, name, false)?;
+            if let Some(store) = keystore::configured(&deleted)? {
+                keystore::forget(&deleted, &*store)?;
+            }
+            paths.delete_profile(name)?;
+            println!("プロファイルを削除しました: {}", name);
+        }
     }
-    
-    Ok(ip)
+
+    Ok(())
 }
 
-// ローカルIPアドレスを取得する関数
-async fn get_local_ip() -> Result<String, Box<dyn std::error::Error>> {
-    use std::net::UdpSocket;
-    
-    // ダミーの外部アドレスに接続して、使用されるローカルIPを取得
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect("8.8.8.8:80")?;
-    let local_addr = socket.local_addr()?;
-    Ok(local_addr.ip().to_string())
+// バックアップの作成と復元
+fn run_backup(action: &BackupCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        BackupCommands::Create { file } => {
+            let passphrase = vault::read_new_passphrase(vault::BACKUP_PASSPHRASE_ENV, "バックアップのパスフレーズ: ")?;
+            let included = backup::create(paths, file, &passphrase)?;
+            println!("バックアップを作成しました: {} ({})", file.display(), included.join(", "));
+        }
+        BackupCommands::Restore { file, force } => {
+            let passphrase = vault::read_passphrase(vault::BACKUP_PASSPHRASE_ENV, "バックアップのパスフレーズ: ")?;
+            let restored = backup::restore(paths, file, &passphrase, *force)?;
+            println!("バックアップを復元しました: {}", restored.join(", "));
+            if restored.iter().any(|name| name == "identity_key.der") {
+                let identity = IdentitySource::Profile.load(paths)?;
+                println!("証明書のフィンガープリント: {}", identity.fingerprint());
+            }
+        }
+    }
+
+    Ok(())
 }
 
-// サーバー側の処理
-async fn run_server(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
-    println!("サーバーを起動します: {}", addr);
-    
-    // ローカルIPアドレスを取得して表示
-    if let Ok(local_ip) = get_local_ip().await {
-        println!("ローカルIPアドレス: {}", local_ip);
-        println!("ローカルネットワーク内からの接続用URL: wss://{}:{}", local_ip, addr.port());
+// キーチェーンへの秘密の預け入れと書き戻し
+fn run_keystore(action: &KeystoreCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        KeystoreCommands::Status => match keystore::Deposits::load(paths)? {
+            Some(deposits) => {
+                println!("保存先: {}", deposits.keystore);
+                let mark = |deposited: bool| if deposited { "預けています" } else { "ファイル・入力" };
+                println!("証明書の秘密鍵: {}", mark(deposits.identity_key && !paths.identity_key_file().exists()));
+                println!("履歴のパスフレーズ: {}", mark(deposits.history_passphrase));
+            }
+            None => println!("キーストアには何も預けていません"),
+        },
+        KeystoreCommands::Store { history } => {
+            let store = keystore::os()?;
+            // 先に鍵を作っておく (初めて使うプロファイルでも預けられるように)
+            let identity = IdentitySource::Profile.load(paths)?;
+            if paths.identity_key_file().exists() {
+                keystore::store_identity_key(paths, &*store)?;
+                println!("証明書の秘密鍵をキーストア {} に預け、identity_key.der を削除しました", store.name());
+            }
+            println!("証明書のフィンガープリント: {}", identity.fingerprint());
+            if *history {
+                let key_file = paths.history_key_file();
+                if !key_file.exists() {
+                    return Err("履歴は暗号化されていません。先に `history encrypt` を実行してください".into());
+                }
+                let passphrase = vault::read_passphrase(vault::PASSPHRASE_ENV, "履歴のパスフレーズ: ")?;
+                vault::Vault::unlock(&key_file, &passphrase)?;
+                keystore::store_history_passphrase(paths, &*store, &passphrase)?;
+                println!("履歴のパスフレーズをキーストア {} に預けました", store.name());
+            }
+        }
+        KeystoreCommands::Remove => {
+            let Some(store) = keystore::configured(paths)? else {
+                println!("キーストアには何も預けていません");
+                return Ok(());
+            };
+            let removed = keystore::remove(paths, &*store)?;
+            println!("キーストア {} から削除しました: {}", store.name(), removed.join(", "));
+            if removed.contains(&keystore::IDENTITY_KEY) {
+                println!("証明書の秘密鍵を書き戻しました: {}", paths.identity_key_file().display());
+            }
+        }
     }
-    
-    // グローバルIPアドレスを取得して表示
-    println!("グローバルIPアドレスを取得中...");
-    match get_global_ip().await {
-        Ok(global_ip) => {
-            println!("グローバルIPアドレス: {}", global_ip);
-            let port = addr.port();
-            println!("外部からの接続用URL: wss://{}:{}", global_ip, port);
-            println!("注意: 以下の設定が必要です:");
-            println!("  1. Windowsファイアウォールでポート{}を開放", port);
-            println!("  2. ルーターでポートフォワーディング設定 (外部{}→内部{}:{})", port, 
-                    get_local_ip().await.unwrap_or_else(|_| "LOCAL_IP".to_string()), port);
-            println!("  3. ISPがポート{}をブロックしていないことを確認", port);
-        }
-        Err(e) => {
-            eprintln!("グローバルIPアドレスの取得に失敗しました: {}", e);
-            println!("ローカルアドレスでのみ接続を受け付けます");
+
+    Ok(())
+}
+
+// デーモンに要求を送り、応答を表示する。jsonlでは応答のJSONをそのまま表示する
+#[cfg(unix)]
+async fn run_ctl(socket: &std::path::Path, action: &CtlCommands, format: ui::OutputFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let request = match action {
+        CtlCommands::Status => daemon::Request::Status,
+        CtlCommands::Rooms => daemon::Request::Rooms,
+        CtlCommands::Send { room, message } => daemon::Request::Send { room: room.clone(), body: message.clone() },
+        CtlCommands::Connect { uri } => daemon::Request::Connect { uri: uri.clone() },
+        CtlCommands::Close { room } => daemon::Request::Close { room: room.clone() },
+        CtlCommands::Events => daemon::Request::Events,
+    };
+    let (response, mut lines) = daemon::request(socket, &request).await?;
+    if let CtlCommands::Events = action {
+        // 出来事は形式によらずJSONのまま表示する
+        while let Some(line) = lines.next_line().await? {
+            println!("{}", line);
         }
+        return Ok(());
     }
+    if format == ui::OutputFormat::Jsonl {
+        println!("{}", response);
+        return Ok(());
+    }
+    match action {
+        CtlCommands::Status => {
+            println!("PID: {}", response["pid"]);
+            println!("起動日時: {}", response["started"].as_str().unwrap_or("-"));
+            println!("待受アドレス: {}", response["listen"].as_str().unwrap_or("なし"));
+            println!("接続中の部屋: {}件", response["rooms"]);
+        }
+        CtlCommands::Rooms => {
+            let rooms = response["rooms"].as_array().cloned().unwrap_or_default();
+            if rooms.is_empty() {
+                println!("接続中の部屋はありません");
+            }
+            for room in rooms {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    room["name"].as_str().unwrap_or("-"),
+                    room["peer"].as_str().unwrap_or("-"),
+                    room["role"].as_str().unwrap_or("-"),
+                    room["since"].as_str().unwrap_or("-")
+                );
+            }
+        }
+        CtlCommands::Connect { .. } => println!("部屋を開きました: {}", response["room"].as_str().unwrap_or("-")),
+        CtlCommands::Send { .. } | CtlCommands::Close { .. } | CtlCommands::Events => {}
+    }
+    Ok(())
+}
 
-    // 1. 自己署名証明書の生成
-    let cert = generate_simple_self_signed(vec!["localhost".into()])?;
-    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
-    let cert_chain = vec![cert.cert.der().clone()];
+// 設定ディレクトリのプラグイン・スクリプトをフックとして登録する (有効にしたフィーチャーのもののみ)
+#[cfg_attr(not(any(feature = "plugins", feature = "scripting")), allow(unused_variables, unused_mut))]
+fn load_hooks(paths: &Paths, cli: &Cli) -> rust_p2p_chat::hook::Hooks {
+    let mut hooks = rust_p2p_chat::hook::Hooks::default();
+    #[cfg(feature = "plugins")]
+    if !cli.no_plugins {
+        match rust_p2p_chat::plugin::load_dir(&paths.plugins_dir()) {
+            Ok(plugins) => plugins.into_iter().for_each(|plugin| hooks.register(plugin)),
+            Err(e) => tracing::warn!(error = %e, "プラグインを読み込めませんでした"),
+        }
+    }
+    #[cfg(feature = "scripting")]
+    {
+        // /script load で後から読み込めるよう、スクリプトがなくても登録する
+        let scripts = rust_p2p_chat::script::Scripts::new(&paths.scripts_dir());
+        if !cli.no_scripts {
+            if let Err(e) = scripts.load_dir(&paths.scripts_dir()) {
+                tracing::warn!(error = %e, "スクリプトを読み込めませんでした");
+            }
+        }
+        hooks.register(scripts);
+    }
+    hooks
+}
 
-    // 2. TLSサーバー設定
-    let mut config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key)?;
-    config.alpn_protocols = vec![b"http/1.1".to_vec()];
-    let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+// Ctrl+C (SIGINT) で待受・接続・転送を取り消し、相手に切断を知らせてから終了する
+// (他のサブコマンドではこれまでどおりCtrl+Cでそのまま終了する)
+fn cancel_on_ctrl_c(shutdown: &CancellationToken) {
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("Ctrl+Cを受け取りました");
+            shutdown.cancel();
+        }
+    });
+}
 
-    // 3. TCPリスナーの起動
-    let listener = TcpListener::bind(&addr).await?;
-    println!("接続待受中... Ctrl+Cで終了");
+// connect・send の接続先 (URIまたは連絡先の名前) とオプションから接続の設定を組み立てる
+async fn client_settings(
+    paths: Paths,
+    options: ChatOptions,
+    target: &str,
+    strict: bool,
+    proxy: &Option<Proxy>,
+    retries: u32,
+) -> Result<ClientSettings, Box<dyn std::error::Error>> {
+    let reconnect = ReconnectPolicy { attempts: retries.saturating_add(1), ..Default::default() };
+    // URIでも連絡先の名前でもないドメイン名は、TXTレコード (_p2pchat.<ドメイン>) から接続先を調べる
+    let dns_server = options.config.borrow().discovery.dns_server;
+    let target = rust_p2p_chat::discovery::dns::resolve_target(target, &paths.contacts_file(), dns_server).await?;
+    let builder = ClientBuilder::new(paths, options).target(&target)?;
+    Ok(builder.strict(strict).proxy(proxy.clone()).reconnect(reconnect).build()?)
+}
 
-    // 4. 接続を受け付け、処理する
-    let (stream, peer_addr) = listener.accept().await?;
-    println!("クライアントが接続しました: {}", peer_addr);
+// スクリプト向けのサブコマンド。結果は1行のJSONで標準出力に書く (項目は rust_p2p_chat::plumbing の構造体)
+async fn run_plumbing(command: &Commands, paths: Paths, options: ChatOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let output = match command {
+        Commands::Resolve { target } => {
+            let settings = client_settings(paths, options, target, false, &None, 0).await?;
+            serde_json::to_value(plumbing::resolve(target, &settings)?)?
+        }
+        Commands::Fingerprint { target, proxy } => {
+            let settings = client_settings(paths, options, target, false, proxy, 0).await?;
+            serde_json::to_value(plumbing::fingerprint(&settings).await?)?
+        }
+        Commands::Probe { target, proxy } => {
+            let settings = client_settings(paths, options, target, false, proxy, 0).await?;
+            serde_json::to_value(plumbing::probe(&settings).await?)?
+        }
+        Commands::EncodeInvite { uri, name } => {
+            let invite = plumbing::Invite::new(&paths, uri, name.clone())?;
+            let mut output = serde_json::to_value(&invite)?;
+            output["invite"] = invite.encode().into();
+            output
+        }
+        Commands::DecodeInvite { invite } => {
+            let invite = plumbing::Invite::decode(invite)?;
+            let mut output = serde_json::to_value(&invite)?;
+            output["connect_uri"] = invite.connect_uri().into();
+            output
+        }
+        _ => unreachable!("スクリプト向けのサブコマンドだけを渡す"),
+    };
+    println!("{}", output);
+    Ok(())
+}
 
-    let tls_stream = tls_acceptor.accept(stream).await?;
+// 他の実装との互換性を確かめて結果を表示する。不合格がなければtrue
+async fn run_conformance(
+    paths: Paths,
+    options: ChatOptions,
+    target: Option<&str>,
+    listen: Option<std::net::SocketAddr>,
+    vectors: bool,
+    proxy: &Option<Proxy>,
+    format: ui::OutputFormat,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if vectors {
+        for vector in conformance::vectors() {
+            println!("{}", vector);
+        }
+        return Ok(true);
+    }
+    let report = match (target, listen) {
+        (Some(target), _) => {
+            let settings = client_settings(paths, options, target, false, proxy, 0).await?;
+            conformance::connect(&settings).await?
+        }
+        (None, Some(addr)) => {
+            cancel_on_ctrl_c(&options.shutdown);
+            if !options.quiet {
+                println!("{} で確かめる実装の接続を待っています (wss:// または ws://)", addr);
+            }
+            conformance::accept(ListenerBuilder::new(paths, options).addr(addr).build()?).await?
+        }
+        (None, None) => unreachable!("clapで接続先か待受のどちらかを必須にしている"),
+    };
+    match format {
+        ui::OutputFormat::Jsonl => println!("{}", serde_json::to_string(&report)?),
+        ui::OutputFormat::Text => {
+            println!("{}", report.target);
+            for check in &report.checks {
+                let outcome = match check.outcome {
+                    conformance::Outcome::Pass => "PASS",
+                    conformance::Outcome::Fail => "FAIL",
+                    conformance::Outcome::Skip => "SKIP",
+                };
+                println!("  {} {:<10} {}", outcome, check.name, check.detail);
+            }
+            let count = |outcome| report.checks.iter().filter(|check| check.outcome == outcome).count();
+            let (passed, failed, skipped) = (count(conformance::Outcome::Pass), count(conformance::Outcome::Fail), count(conformance::Outcome::Skip));
+            println!("合格 {}、不合格 {}、省略 {}", passed, failed, skipped);
+        }
+    }
+    Ok(report.passed())
+}
 
-    // 5. WebSocketハンドシェイク
-    let ws_stream = tokio_tungstenite::accept_async(tls_stream).await?;
-    println!("WebSocket接続が確立しました。");
+// LANでmDNSを使って知らせている待受を一覧する
+#[cfg(feature = "mdns")]
+async fn run_discover(timeout: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let peers = rust_p2p_chat::discovery::mdns::browse(timeout).await?;
+    if peers.is_empty() {
+        println!("LANに待受は見つかりませんでした。");
+    }
+    for peer in peers {
+        let uri = peer.uri().unwrap_or_default();
+        match &peer.nickname {
+            Some(nickname) => println!("{}  {}", nickname, uri),
+            None => println!("{}  {}", peer.instance, uri),
+        }
+        if peer.addresses.len() > 1 {
+            let addresses: Vec<String> = peer.addresses.iter().map(|ip| ip.to_string()).collect();
+            println!("  アドレス: {}", addresses.join(", "));
+        }
+    }
+    Ok(())
+}
 
-    handle_connection(ws_stream).await;
+// 保持期間を過ぎた履歴を削除する
+fn run_history_purge(
+    paths: &Paths,
+    config: &Config,
+    peer: Option<&str>,
+    keep: Option<RetentionPolicy>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut history = open_history(paths)?;
+    let now = chrono::Local::now();
+    let removed = history.retain(|entry| {
+        if peer.is_some_and(|peer| entry.peer != peer) {
+            return true;
+        }
+        let policy = keep.unwrap_or_else(|| config.retention.policy_for(&entry.peer));
+        policy.keeps(entry.timestamp, now)
+    })?;
+    println!("{}件の履歴を削除しました。", removed);
 
     Ok(())
 }
 
-// クライアント側の処理
-async fn run_client(uri: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("サーバーに接続します: {}", uri);
-
-    // 1. TLSクライアント設定（サーバー証明書を検証しない）
-    let root_cert_store = rustls::RootCertStore::empty();
-    let mut config = ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
-    
-    // サーバー証明書の検証をスキップするカスタム検証ロジック
-    config.dangerous().set_certificate_verifier(Arc::new(NoopServerCertVerifier));
-    config.alpn_protocols = vec![b"http/1.1".to_vec()];
-
-    let connector = TlsConnector::from(Arc::new(config));
-    let url = url::Url::parse(uri)?;
-    let host = url.host_str().ok_or("URIにホスト名がありません")?;
-    let port = url.port().unwrap_or(8080);
-
-    // 2. TCP接続とTLSハンドシェイク
-    let addr = format!("{}:{}", host, port);
-    let stream = TcpStream::connect(&addr).await?;
-    let domain = rustls::pki_types::ServerName::try_from(host)?.to_owned();
-    let tls_stream = connector.connect(domain, stream).await?;
-
-    // 3. WebSocketハンドシェイク
-    let (ws_stream, _) = tokio_tungstenite::client_async(uri, tls_stream).await?;
-    println!("WebSocket接続が確立しました。");
-
-    handle_connection(ws_stream).await;
+// 既存の平文の履歴を暗号化する
+fn run_history_encrypt(paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    let key_file = paths.history_key_file();
+    if key_file.exists() {
+        return Err("履歴は既に暗号化されています".into());
+    }
+    let mut history = History::open(paths.history_file(), None)?;
+
+    let passphrase = vault::read_new_passphrase(vault::PASSPHRASE_ENV, "新しいパスフレーズ: ")?;
+
+    // 暗号化する前の平文の履歴を残しておく (パスフレーズを忘れても読めるように。不要になったら削除する)
+    let plaintext = migrate::backup(paths, migrate::Store::History, "plaintext")?;
+    let count = history.encrypt_all(&key_file, &passphrase)?;
+    println!("履歴を暗号化しました ({}件)。鍵の導出パラメータ: {}", count, key_file.display());
+    if let Some(dir) = plaintext {
+        println!("暗号化する前の履歴を {} に残しました。確かめたら削除してください", dir.display());
+    }
 
     Ok(())
 }
 
-// サーバー証明書を検証しないためのダミー構造体
-#[derive(Debug)]
-struct NoopServerCertVerifier;
-
-impl rustls::client::danger::ServerCertVerifier for NoopServerCertVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &CertificateDer<'_>,
-        _intermediates: &[CertificateDer<'_>],
-        _server_name: &rustls::pki_types::ServerName<'_>,
-        _ocsp_response: &[u8],
-        _now: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
+// 履歴のエクスポート処理
+fn run_history_export(
+    paths: &Paths,
+    format: ExportFormat,
+    peer: Option<String>,
+    since: Option<chrono::DateTime<chrono::Local>>,
+    output: Option<std::path::PathBuf>,
+    signed: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if signed && format != ExportFormat::Json {
+        return Err("--signed はJSON形式でのみ使用できます".into());
     }
+    let history = open_history(paths)?;
+    let entries = history.load()?;
+    let filter = ExportFilter { peer, since };
+    let manifest = if signed {
+        let mut certs = identity::CertStore::load(paths.certs_file())?;
+        if paths.identity_cert_file().exists() {
+            certs.insert(&std::fs::read(paths.identity_cert_file())?);
+        }
+        let exporter = IdentitySource::Profile.load(paths)?;
+        Some(history::Manifest::collect(&entries, &filter, &certs, &exporter)?)
+    } else {
+        None
+    };
+    let transcript = history::render_transcript(&entries, &filter, format, manifest)?;
+
+    let output = output.unwrap_or_else(|| {
+        let stamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
+        format!("chat_export_{}.{}", stamp, format.extension()).into()
+    });
+    std::fs::write(&output, transcript)?;
+    println!("履歴を書き出しました: {}", output.display());
 
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    Ok(())
+}
+
+// 署名付きトランスクリプトを検証する。署名が正しくないメッセージ・署名のないメッセージ・
+// 信頼していない署名者があるか、トランスクリプト全体の署名が合わなければエラーにする
+fn run_history_verify(paths: &Paths, file: &std::path::Path, pinned: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let result = history::verify_transcript(&std::fs::read_to_string(file)?)?;
+
+    // --signer の指定がなければ、known_peers・アドレス帳に記録した相手と自分の証明書を信頼する
+    let trusted: std::collections::BTreeSet<String> = if pinned.is_empty() {
+        let mut trusted: std::collections::BTreeSet<String> =
+            KnownPeers::load(paths.known_peers_file())?.iter().map(|(_, peer)| peer.fingerprint.clone()).collect();
+        trusted.extend(AddressBook::load(paths.contacts_file())?.iter().filter_map(|(_, contact)| contact.fingerprint.clone()));
+        if paths.identity_cert_file().exists() {
+            trusted.insert(identity::fingerprint(&std::fs::read(paths.identity_cert_file())?));
+        }
+        trusted
+    } else {
+        pinned.iter().cloned().collect()
+    };
+    let mut unknown = Vec::new();
+    for signer in result.signers.iter().chain(result.exporter.as_ref()) {
+        let known = trusted.contains(signer);
+        let role = if result.exporter.as_ref() == Some(signer) { "エクスポート" } else { "署名者" };
+        println!("{}: {}{}", role, signer, if known { "" } else { " (信頼していない鍵)" });
+        if !known && !unknown.contains(signer) {
+            unknown.push(signer.clone());
+        }
     }
+    println!("署名が正しいメッセージ: {}件", result.valid);
+    println!("署名のないメッセージ: {}件", result.unsigned);
 
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    if !result.invalid.is_empty() {
+        let seqs: Vec<String> = result.invalid.iter().map(u64::to_string).collect();
+        return Err(format!("署名が正しくないメッセージがあります (seq: {})", seqs.join(", ")).into());
     }
+    if !result.sealed {
+        return Err("トランスクリプト全体の署名がないか正しくありません (エントリの削除・並べ替えや、時刻・相手・向きの書き換え)".into());
+    }
+    if !unknown.is_empty() {
+        return Err(format!(
+            "信頼していない鍵で署名されています: {} (--signer で信頼するフィンガープリントを指定できます)",
+            unknown.join(", ")
+        )
+        .into());
+    }
+    if result.unsigned > 0 {
+        return Err(format!("署名のないメッセージが{}件あり、改ざんされていないことを確認できません", result.unsigned).into());
+    }
+    println!("トランスクリプトは改ざんされていません。");
+    Ok(())
+}
 
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        // すべての一般的な署名スキームをサポート
-        vec![
-            rustls::SignatureScheme::RSA_PKCS1_SHA1,
-            rustls::SignatureScheme::ECDSA_SHA1_Legacy,
-            rustls::SignatureScheme::RSA_PKCS1_SHA256,
-            rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
-            rustls::SignatureScheme::RSA_PKCS1_SHA384,
-            rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
-            rustls::SignatureScheme::RSA_PKCS1_SHA512,
-            rustls::SignatureScheme::ECDSA_NISTP521_SHA512,
-            rustls::SignatureScheme::RSA_PSS_SHA256,
-            rustls::SignatureScheme::RSA_PSS_SHA384,
-            rustls::SignatureScheme::RSA_PSS_SHA512,
-            rustls::SignatureScheme::ED25519,
-            rustls::SignatureScheme::ED448,
-        ]
+// 届けられなかった下書きのメール送信 (gateway) と、暗号化して送られたメールを開く (open)
+async fn run_mail(action: &MailCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        MailCommands::Gateway { once } => {
+            let mut history = open_history(paths)?;
+            mail::run_gateway(paths, &mut history, *once).await
+        }
+        MailCommands::Open { file } => {
+            let text = match file {
+                Some(file) => std::fs::read_to_string(file)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let passphrase = vault::read_passphrase(mail::PASSPHRASE_ENV, "共有しているパスフレーズ: ")?;
+            for draft in mail::open(&text, &passphrase)? {
+                println!("[{}] {}", draft.saved_at.format("%Y-%m-%d %H:%M"), draft.body);
+            }
+            Ok(())
+        }
     }
 }
 
-// 接続後のメッセージ送受信をハンドルする共通関数
-async fn handle_connection<S>(ws_stream: tokio_tungstenite::WebSocketStream<S>)
-where
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-{
-    println!("チャットを開始します。メッセージを入力してEnterキーを押してください。");
-
-    // WebSocketストリームを送信と受信に分割
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    let mut stdin = BufReader::new(stdin()).lines();
-
-    loop {
-        tokio::select! {
-            // 標準入力からメッセージを読み取って送信
-            line_result = stdin.next_line() => {
-                match line_result {
-                    Ok(Some(line)) => {
-                        if line.trim().is_empty() {
-                            continue;
-                        }
-                        if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Text(line)).await {
-                            println!("メッセージ送信エラー: {}", e);
-                            break;
-                        }
-                    }
-                    Ok(None) => {
-                        println!("標準入力が閉じられました。");
-                        break;
-                    }
-                    Err(e) => {
-                        println!("標準入力読み取りエラー: {}", e);
-                        break;
-                    }
-                }
+// Nostrの鍵の表示と、リレーを経由したダイレクトメッセージの送信・受信・下書きの送信
+#[cfg(feature = "nostr")]
+async fn run_nostr(action: &NostrCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        NostrCommands::Key => {
+            let keys = nostr::keys(paths)?;
+            println!("{}", nostr::npub(&keys.public_key()));
+            Ok(())
+        }
+        NostrCommands::Send { to, message } => {
+            let config = Config::load(&paths.config_file())?.nostr;
+            let mut history = open_history(paths)?;
+            let accepted = nostr::send(paths, &config, &mut history, to, message).await?;
+            println!("{}件のリレーが受け付けました。", accepted);
+            Ok(())
+        }
+        NostrCommands::Inbox { follow } => {
+            let mut history = open_history(paths)?;
+            nostr::run_inbox(paths, &mut history, *follow).await
+        }
+        NostrCommands::Gateway { once } => {
+            let mut history = open_history(paths)?;
+            nostr::run_gateway(paths, &mut history, *once).await
+        }
+    }
+}
+
+// 利用状況の記録の確認・変更・送信
+async fn run_telemetry(action: &TelemetryCommands, paths: &Paths) -> Result<(), Box<dyn std::error::Error>> {
+    let path = paths.telemetry_file();
+    let mut state = Telemetry::load(&path)?;
+    match action {
+        TelemetryCommands::Status => {
+            if !state.enabled {
+                println!("利用状況の記録は無効です (有効にするには: telemetry enable)");
+                return Ok(());
             }
-            // WebSocketからメッセージを受信して表示
-            msg_result = ws_receiver.next() => {
-                match msg_result {
-                    Some(Ok(msg)) => {
-                        match msg {
-                            tokio_tungstenite::tungstenite::Message::Text(text) => {
-                                println!("相手: {}", text);
-                            }
-                            tokio_tungstenite::tungstenite::Message::Close(close_frame) => {
-                                if let Some(frame) = close_frame {
-                                    println!("相手が接続を切断しました: {} - {}", frame.code, frame.reason);
-                                } else {
-                                    println!("相手が接続を切断しました。");
-                                }
-                                break;
-                            }
-                            tokio_tungstenite::tungstenite::Message::Ping(data) => {
-                                if let Err(e) = ws_sender.send(tokio_tungstenite::tungstenite::Message::Pong(data)).await {
-                                    println!("Pong送信エラー: {}", e);
-                                    break;
-                                }
-                            }
-                            _ => {
-                                // その他のメッセージタイプは無視
-                            }
-                        }
-                    }
-                    Some(Err(e)) => {
-                        println!("WebSocketエラー: {}", e);
-                        break;
-                    }
-                    None => {
-                        println!("WebSocket接続が閉じられました。");
-                        break;
-                    }
-                }
+            let since = state.since.map(|since| since.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_else(|| "-".to_string());
+            println!("利用状況の記録は有効です ({} から)", since);
+            for (name, count) in &state.features {
+                println!("  機能 {:<24} {}回", name, count);
             }
+            for (kind, count) in &state.errors {
+                println!("  エラー {:<22} {}回", kind, count);
+            }
+        }
+        TelemetryCommands::Enable => {
+            state.enable();
+            state.save(&path)?;
+            println!("利用状況の記録を有効にしました。使った機能とエラーの種類の回数だけを記録し、送るのは telemetry submit を実行したときだけです。");
+        }
+        TelemetryCommands::Disable => {
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            println!("利用状況の記録を無効にし、これまでの記録を削除しました。");
+        }
+        TelemetryCommands::Report => println!("{}", serde_json::to_string_pretty(&state.report())?),
+        TelemetryCommands::Submit { url } => {
+            if !state.enabled {
+                return Err("利用状況の記録が無効です (telemetry enable で有効にしてください)".into());
+            }
+            telemetry::submit(url, &state.report()).await?;
+            state.reset();
+            state.save(&path)?;
+            println!("利用状況を送りました: {}", url);
         }
     }
+    Ok(())
+}
 
-    println!("チャット終了。");
+// --error-format に従ってエラーを書き、原因に応じた終了コード (rust_p2p_chat::exit) で終了する
+fn exit_with(format: ErrorFormat, context: &str, error: &(dyn std::error::Error + 'static)) -> ! {
+    let failure = Failure::of(error);
+    telemetry::record_error(failure.kind);
+    exit::report(format, context, &error.to_string(), failure);
+    logging::shutdown();
+    std::process::exit(failure.code);
 }
 
 #[tokio::main]
@@ -322,22 +724,485 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .install_default()
         .map_err(|_| "暗号化プロバイダーの初期化に失敗しました")?;
 
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    i18n::init(cli.lang);
+
+    // 補完スクリプトとmanページはclapの定義だけから作るため、保存先ディレクトリや設定を読み込まない
+    match &cli.command {
+        Commands::Completions { shell } => {
+            // clap_completeは書き込みに失敗するとpanicするため、一度バッファに書いてから出力する (`| head` などで途中で閉じられる場合)
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            let mut script = Vec::new();
+            clap_complete::generate(*shell, &mut command, name, &mut script);
+            if let Err(e) = std::io::Write::write_all(&mut std::io::stdout(), &script) {
+                exit_with(cli.error_format, "補完スクリプトの出力エラー", &e);
+            }
+            return Ok(());
+        }
+        Commands::Manpage { dir } => {
+            if let Err(e) = write_manpages(dir.as_deref()) {
+                exit_with(cli.error_format, "manページの出力エラー", &*e);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let paths = match Paths::resolve(cli.data_dir.as_deref(), &cli.profile, false) {
+        Ok(paths) => paths,
+        Err(e) => exit_with(cli.error_format, "保存先ディレクトリの準備に失敗しました", &*e),
+    };
+
+    // 有効にしている場合だけ、使ったサブコマンド (trust.add など) と表示方法を記録する
+    telemetry::init(paths.telemetry_file());
+    if let Some((name, sub)) = matches.subcommand().filter(|(name, _)| *name != "telemetry") {
+        match sub.subcommand_name() {
+            Some(action) => telemetry::record_feature(&format!("{}.{}", name, action)),
+            None => telemetry::record_feature(name),
+        }
+        if matches!(cli.command, Commands::Listen { .. } | Commands::Connect { .. }) {
+            if let Some(mode) = clap::ValueEnum::to_possible_value(&cli.ui) {
+                telemetry::record_feature(&format!("ui.{}", mode.get_name()));
+            }
+        }
+    }
+
+    match migrate::run(&paths) {
+        Ok(applied) => {
+            for description in applied {
+                // スクリプト向けのサブコマンドは標準出力にJSONだけを書く
+                match cli.format {
+                    ui::OutputFormat::Text if !cli.command.is_plumbing() => println!("保存データを移行しました: {}", description),
+                    _ => eprintln!("保存データを移行しました: {}", description),
+                }
+            }
+        }
+        Err(e) => exit_with(cli.error_format, "保存データの移行に失敗しました", &*e),
+    }
+
+    let config = match Config::load(&paths.config_file()) {
+        Ok(config) => config,
+        Err(e) => exit_with(cli.error_format, "設定ファイルエラー", &*e),
+    };
+
+    let log_file = cli.log_file.as_ref().map(|path| logging::LogConfig {
+        path: path.clone(),
+        max_bytes: cli.log_max_size.saturating_mul(1024 * 1024),
+        daily: cli.log_rotate_daily,
+        keep: cli.log_keep,
+    });
+    // https://no-color.org/ に従い、NO_COLORが空でなければ色を付けない
+    let color = !cli.no_color && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    let verbosity = logging::Verbosity::from_flags(cli.quiet, cli.verbose);
+    #[cfg(feature = "otel")]
+    let otlp = cli.otlp_endpoint.as_deref();
+    #[cfg(not(feature = "otel"))]
+    let otlp = None;
+    let syslog = cli.syslog.clone().map(|target| logging::SyslogConfig { target, facility: cli.syslog_facility });
+    if let Err(e) = logging::init(log_file, syslog, cli.log_format, cli.log_messages, config.log_level.as_deref(), color, verbosity, otlp) {
+        exit_with(cli.error_format, "ログの初期化に失敗しました", &*e);
+    }
+
+    let options = ChatOptions {
+        downloads: DownloadConfig {
+            dir: cli
+                .download_dir
+                .clone()
+                .or_else(|| config.downloads.dir.clone())
+                .unwrap_or_else(|| paths.downloads_dir()),
+            per_peer: cli.download_per_peer.unwrap_or(config.downloads.per_peer),
+            accept: true,
+            max_bytes: None,
+        },
+        config: config::watch(paths.config_file(), config.clone()),
+        nickname: None,
+        notify: !cli.no_alerts,
+        download_dir_fixed: cli.download_dir.is_some(),
+        negotiated: None,
+        ui: ui::UiOptions {
+            mode: cli.ui,
+            format: cli.format,
+            color,
+            // 履歴を暗号化している場合は、入力した内容を平文で残さない
+            input_history: (!paths.history_key_file().exists()).then(|| paths.input_history_file()),
+            settings: config.ui.clone(),
+            contacts: AddressBook::load(paths.contacts_file())
+                .map(|book| {
+                    book.iter()
+                        .flat_map(|(name, contact)| std::iter::once(name.clone()).chain(contact.nickname.clone()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        },
+        // jsonlでは標準出力をJSONだけにする
+        quiet: cli.quiet || cli.format == ui::OutputFormat::Jsonl,
+        identity: IdentitySource::Profile,
+        sign_messages: true,
+        shutdown: CancellationToken::new(),
+        hooks: load_hooks(&paths, &cli),
+        runtime: Runtime::tokio(),
+    };
+    let fail = |context: &str, e: Box<dyn std::error::Error>| -> ! {
+        if cli.format == ui::OutputFormat::Jsonl {
+            ui::print_json_error(&e.to_string());
+        }
+        exit_with(cli.error_format, context, &*e)
+    };
 
     match &cli.command {
-        Commands::Listen { addr } => {
-            if let Err(e) = run_server(*addr).await {
-                eprintln!("サーバーエラー: {}", e);
-                std::process::exit(1);
+        #[cfg(feature = "libp2p")]
+        Commands::Listen { addr, code, dns_name, web, health, .. } if cli.backend == backend::Backend::Libp2p => {
+            if *code || dns_name.is_some() || *web || health.is_some() {
+                fail(Msg::ServerError.text(), "--backend libp2p では --code・--dns-name・--web・--health は使えません".into());
+            }
+            let addr = addr.or(config.listen.addr).unwrap_or(DEFAULT_LISTEN_ADDR);
+            cancel_on_ctrl_c(&options.shutdown);
+            if let Err(e) = backend::listen(&paths, options, addr).await {
+                fail(Msg::ServerError.text(), e);
+            }
+        }
+        #[cfg(feature = "libp2p")]
+        Commands::Connect { uri: Some(uri), proxy, via_ssh, .. } if cli.backend == backend::Backend::Libp2p => {
+            if proxy.is_some() || via_ssh.is_some() {
+                fail(Msg::ClientError.text(), "--backend libp2p では --proxy・--via-ssh は使えません".into());
+            }
+            cancel_on_ctrl_c(&options.shutdown);
+            if let Err(e) = backend::connect(&paths, options, uri).await {
+                fail(Msg::ClientError.text(), e);
+            }
+        }
+        Commands::Listen { addr, no_qr, code, rendezvous, dns_name, web, #[cfg(feature = "mdns")] no_mdns, #[cfg(feature = "webtransport")] webtransport, health } => {
+            let addr = addr.or(config.listen.addr).unwrap_or(DEFAULT_LISTEN_ADDR);
+            // mDNSで知らせる表示名は、config.toml の [listen] name か、ログインしているユーザー名
+            #[cfg(feature = "mdns")]
+            let announce = (!no_mdns).then(|| {
+                config.listen.name.clone().or_else(|| std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok()).unwrap_or_else(|| "p2pchat".to_string())
+            });
+            #[cfg(not(feature = "mdns"))]
+            let announce = None;
+            #[cfg(feature = "webtransport")]
+            let webtransport = *webtransport;
+            #[cfg(not(feature = "webtransport"))]
+            let webtransport = false;
+            let rendezvous = match code {
+                true => Some(rendezvous_server(rendezvous, &config).unwrap_or_else(|e| fail(Msg::ServerError.text(), e.to_string().into()))),
+                false => None,
+            };
+            // 待受の準備 (パスフレーズの入力など) の間も、/healthz には応答する
+            let health = match health {
+                Some(health_addr) => {
+                    let listener = health::bind(*health_addr).await.unwrap_or_else(|e| fail(Msg::ServerError.text(), e.into()));
+                    let health = health::Health::new(paths.data_dir.clone(), rendezvous.iter().cloned().collect());
+                    if !options.quiet {
+                        println!("{}", Msg::HealthListening.with(&[&listener.local_addr().unwrap_or(*health_addr)]));
+                    }
+                    tokio::spawn(health::serve(listener, health.clone()));
+                    Some(health)
+                }
+                None => None,
+            };
+            cancel_on_ctrl_c(&options.shutdown);
+            let settings = ListenerBuilder::new(paths, options)
+                .addr(addr)
+                .show_qr(!no_qr)
+                .rendezvous(rendezvous)
+                .web(*web)
+                .webtransport(webtransport)
+                .dns_name(dns_name.clone())
+                .announce(announce)
+                .health(health)
+                .build()
+                .unwrap_or_else(|e| fail(Msg::ServerError.text(), e.into()));
+            if let Err(e) = run_server(settings).await {
+                fail(Msg::ServerError.text(), e);
+            }
+        }
+        Commands::Resolve { .. } | Commands::Fingerprint { .. } | Commands::EncodeInvite { .. } | Commands::DecodeInvite { .. } | Commands::Probe { .. } => {
+            if let Err(e) = run_plumbing(&cli.command, paths, options).await {
+                exit_with(cli.error_format, "スクリプト向けのコマンドのエラー", &*e);
+            }
+        }
+        Commands::Conformance { target, listen, vectors, proxy } => {
+            match run_conformance(paths, options, target.as_deref(), *listen, *vectors, proxy, cli.format).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    logging::shutdown();
+                    std::process::exit(1);
+                }
+                Err(e) => exit_with(cli.error_format, "互換性を確かめられませんでした", &*e),
+            }
+        }
+        #[cfg(feature = "mdns")]
+        Commands::Discover { timeout } => {
+            if let Err(e) = run_discover(std::time::Duration::from_secs(*timeout)).await {
+                exit_with(cli.error_format, "LANの待受を探せませんでした", &*e);
+            }
+        }
+        Commands::Connect { uri, code, rendezvous, strict, proxy, via_ssh, retries } => {
+            let uri = match (uri, code) {
+                (Some(uri), _) => uri.clone(),
+                (None, Some(code)) => {
+                    let server = rendezvous_server(rendezvous, &config).unwrap_or_else(|e| fail(Msg::ClientError.text(), e.to_string().into()));
+                    if !options.quiet {
+                        println!("{}", Msg::ResolvingCode.with(&[code]));
+                    }
+                    match rendezvous::resolve(&server, code).await {
+                        Ok(uri) => uri,
+                        Err(e) => fail(Msg::ClientError.text(), e),
+                    }
+                }
+                (None, None) => unreachable!("clapでどちらかを必須にしている"),
+            };
+            cancel_on_ctrl_c(&options.shutdown);
+            let proxy = via_ssh.clone().map(Proxy::Ssh).or_else(|| proxy.clone());
+            let settings = client_settings(paths, options, &uri, *strict, &proxy, *retries)
+                .await
+                .unwrap_or_else(|e| fail(Msg::ClientError.text(), e));
+            if let Err(e) = run_client(settings).await {
+                fail(Msg::ClientError.text(), e);
+            }
+        }
+        Commands::Send { to, message, file, stream, raw, name, strict, timeout, proxy, retries } => {
+            let settings = client_settings(paths, options, to, *strict, proxy, *retries)
+                .await
+                .unwrap_or_else(|e| fail(Msg::SendFailed.text(), e));
+            let outgoing = match (message, file) {
+                _ if *stream && *raw => Outgoing::Raw { name: name.clone() },
+                _ if *stream => Outgoing::Lines,
+                (Some(message), _) => Outgoing::Message(message.clone()),
+                (None, Some(file)) => match transfer::OutgoingFile::open(SEND_TRANSFER_ID, file).await {
+                    Ok(file) => Outgoing::File(file),
+                    Err(e) => fail(Msg::SendFailed.text(), e),
+                },
+                (None, None) => unreachable!("clapでどちらかを必須にしている"),
+            };
+            let timeout = std::time::Duration::from_secs(*timeout);
+            if let Err(e) = run_send(&settings, outgoing, timeout).await {
+                if cli.format == ui::OutputFormat::Jsonl {
+                    ui::print_json_error(&e.to_string());
+                }
+                let failure = e.failure();
+                telemetry::record_error(failure.kind);
+                exit::report(cli.error_format, Msg::SendFailed.text(), &e.to_string(), failure);
+                logging::shutdown();
+                std::process::exit(failure.code);
+            }
+        }
+        Commands::Daemon { addr, socket, api, #[cfg(feature = "grpc")] grpc, api_token, feed, feed_room, health } => {
+            #[cfg(unix)]
+            {
+                let socket = socket.clone().unwrap_or_else(|| paths.control_socket());
+                let api = daemon::api::ApiSettings {
+                    rest: *api,
+                    #[cfg(feature = "grpc")]
+                    grpc: *grpc,
+                    #[cfg(not(feature = "grpc"))]
+                    grpc: None,
+                    token: api_token.clone(),
+                    feed: feed.zip(feed_room.clone()).map(|(addr, room)| daemon::feed::FeedSettings { addr, room }),
+                    health: *health,
+                };
+                cancel_on_ctrl_c(&options.shutdown);
+                if let Err(e) = daemon::run(*addr, socket, api, paths, options).await {
+                    fail(Msg::DaemonError.text(), e);
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (addr, socket, api, api_token, feed, feed_room, health);
+                #[cfg(feature = "grpc")]
+                let _ = grpc;
+                fail(Msg::DaemonError.text(), Msg::DaemonUnsupported.to_string().into());
+            }
+        }
+        Commands::Ctl { socket, action } => {
+            #[cfg(unix)]
+            {
+                let socket = socket.clone().unwrap_or_else(|| paths.control_socket());
+                if let Err(e) = run_ctl(&socket, action, cli.format).await {
+                    fail(Msg::CtlFailed.text(), e);
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (socket, action);
+                fail(Msg::CtlFailed.text(), Msg::DaemonUnsupported.to_string().into());
+            }
+        }
+        Commands::Bridge { action: BridgeCommands::Irc { listen, socket, password } } => {
+            #[cfg(unix)]
+            {
+                let socket = socket.clone().unwrap_or_else(|| paths.control_socket());
+                let listener = tokio::net::TcpListener::bind(listen).await.unwrap_or_else(|e| fail(Msg::BridgeError.text(), e.into()));
+                if !options.quiet {
+                    println!("{}", Msg::BridgeListening.with(&[listen, &socket.display()]));
+                }
+                let settings = rust_p2p_chat::bridge::irc::IrcSettings { socket, password: password.clone() };
+                if let Err(e) = rust_p2p_chat::bridge::irc::serve(listener, settings).await {
+                    fail(Msg::BridgeError.text(), e.into());
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (listen, socket, password);
+                fail(Msg::BridgeError.text(), Msg::DaemonUnsupported.to_string().into());
+            }
+        }
+        Commands::Bridge { action: BridgeCommands::Mqtt { broker, publish, subscribe, room, json, client_id, username, password, socket } } => {
+            #[cfg(unix)]
+            {
+                let settings = rust_p2p_chat::bridge::mqtt::MqttSettings {
+                    socket: socket.clone().unwrap_or_else(|| paths.control_socket()),
+                    broker: broker.clone(),
+                    client_id: client_id.clone(),
+                    username: username.clone(),
+                    password: password.clone(),
+                    publish: publish.clone(),
+                    subscribe: subscribe.clone(),
+                    room: room.clone(),
+                    json: *json,
+                };
+                if let Err(e) = rust_p2p_chat::bridge::mqtt::run(settings).await {
+                    fail(Msg::BridgeError.text(), e.to_string().into());
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (broker, publish, subscribe, room, json, client_id, username, password, socket);
+                fail(Msg::BridgeError.text(), Msg::DaemonUnsupported.to_string().into());
+            }
+        }
+        #[cfg(feature = "discovery")]
+        Commands::Bridge { action: BridgeCommands::Relay { room, webhook_url, service, listen, token, socket } } => {
+            #[cfg(unix)]
+            {
+                let service = service
+                    .or_else(|| rust_p2p_chat::bridge::RelayService::detect(webhook_url))
+                    .unwrap_or_else(|| fail(Msg::BridgeError.text(), Msg::RelayUnknownService.to_string().into()));
+                let listener = match listen {
+                    Some(listen) => Some(tokio::net::TcpListener::bind(listen).await.unwrap_or_else(|e| fail(Msg::BridgeError.text(), e.into()))),
+                    None => None,
+                };
+                if let (Some(listen), false) = (listen, options.quiet) {
+                    println!("{}", Msg::RelayListening.with(&[listen, room]));
+                }
+                let settings = rust_p2p_chat::bridge::relay::RelaySettings {
+                    socket: socket.clone().unwrap_or_else(|| paths.control_socket()),
+                    room: room.clone(),
+                    webhook: webhook_url.clone(),
+                    service,
+                    token: token.clone(),
+                };
+                if let Err(e) = rust_p2p_chat::bridge::relay::run(settings, listener).await {
+                    fail(Msg::BridgeError.text(), e.to_string().into());
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (room, webhook_url, service, listen, token, socket);
+                fail(Msg::BridgeError.text(), Msg::DaemonUnsupported.to_string().into());
+            }
+        }
+        #[cfg(feature = "xmpp")]
+        Commands::Bridge { action: BridgeCommands::Xmpp { server, domain, secret, owner, socket } } => {
+            #[cfg(unix)]
+            {
+                let contacts = AddressBook::load(paths.contacts_file()).unwrap_or_else(|e| fail(Msg::BridgeError.text(), e));
+                let settings = rust_p2p_chat::bridge::xmpp::XmppSettings {
+                    socket: socket.clone().unwrap_or_else(|| paths.control_socket()),
+                    server: server.clone(),
+                    domain: domain.clone(),
+                    secret: secret.clone(),
+                    owner: owner.clone(),
+                    contacts,
+                };
+                if let Err(e) = rust_p2p_chat::bridge::xmpp::run(settings).await {
+                    fail(Msg::BridgeError.text(), e.to_string().into());
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (server, domain, secret, owner, socket);
+                fail(Msg::BridgeError.text(), Msg::DaemonUnsupported.to_string().into());
+            }
+        }
+        Commands::Rendezvous { addr, ttl } => {
+            if let Err(e) = rendezvous::serve(*addr, std::time::Duration::from_secs(*ttl)).await {
+                exit_with(cli.error_format, "ランデブーサーバーエラー", &*e);
+            }
+        }
+        Commands::Contacts { action } => {
+            if let Err(e) = run_contacts(action, &paths) {
+                exit_with(cli.error_format, "アドレス帳エラー", &*e);
+            }
+        }
+        Commands::Trust { action } => {
+            if let Err(e) = run_trust(action, &paths) {
+                exit_with(cli.error_format, "known_peersエラー", &*e);
+            }
+        }
+        Commands::Profile { action } => {
+            if let Err(e) = run_profile(action, cli.data_dir.as_deref(), &paths) {
+                exit_with(cli.error_format, "プロファイルエラー", &*e);
+            }
+        }
+        Commands::History { action } => match action {
+            HistoryCommands::Purge { peer, keep } => {
+                if let Err(e) = run_history_purge(&paths, &config, peer.as_deref(), *keep) {
+                    exit_with(cli.error_format, "履歴削除エラー", &*e);
+                }
+            }
+            HistoryCommands::Encrypt => {
+                if let Err(e) = run_history_encrypt(&paths) {
+                    exit_with(cli.error_format, "履歴暗号化エラー", &*e);
+                }
+            }
+            HistoryCommands::Export { format, peer, since, output, signed } => {
+                if let Err(e) = run_history_export(&paths, *format, peer.clone(), *since, output.clone(), *signed) {
+                    exit_with(cli.error_format, "履歴エクスポートエラー", &*e);
+                }
+            }
+            HistoryCommands::Verify { file, signers } => {
+                if let Err(e) = run_history_verify(&paths, file, signers) {
+                    exit_with(cli.error_format, "履歴検証エラー", &*e);
+                }
+            }
+        },
+        Commands::Backup { action } => {
+            if let Err(e) = run_backup(action, &paths) {
+                exit_with(cli.error_format, "バックアップエラー", &*e);
+            }
+        }
+        Commands::Keystore { action } => {
+            if let Err(e) = run_keystore(action, &paths) {
+                exit_with(cli.error_format, "キーストアのエラー", &*e);
+            }
+        }
+        Commands::Mail { action } => {
+            if let Err(e) = run_mail(action, &paths).await {
+                exit_with(cli.error_format, "メールのゲートウェイのエラー", &*e);
+            }
+        }
+        #[cfg(feature = "nostr")]
+        Commands::Nostr { action } => {
+            if let Err(e) = run_nostr(action, &paths).await {
+                exit_with(cli.error_format, "Nostrのエラー", &*e);
             }
         }
-        Commands::Connect { uri } => {
-            if let Err(e) = run_client(uri).await {
-                eprintln!("クライアントエラー: {}", e);
-                std::process::exit(1);
+        Commands::Telemetry { action } => {
+            if let Err(e) = run_telemetry(action, &paths).await {
+                exit_with(cli.error_format, "利用状況の記録エラー", &*e);
             }
         }
+        Commands::Completions { .. } | Commands::Manpage { .. } => unreachable!("保存先ディレクトリの準備の前に処理している"),
+        Commands::Debug { action: DebugCommands::Dump { file } } => match debug::dump(&paths, &config, file) {
+            Ok(connections) => println!("デバッグ情報を書き出しました: {} (実行中の接続: {}件)", file.display(), connections),
+            Err(e) => exit_with(cli.error_format, "デバッグ情報の出力エラー", &*e),
+        },
     }
 
+    logging::shutdown();
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::history::History;
+use crate::paths::Paths;
+
+// 保存形式のバージョンを管理するデータの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Store {
+    History,
+    KnownPeers,
+    Config,
+}
+
+impl Store {
+    const ALL: [Store; 3] = [Store::History, Store::KnownPeers, Store::Config];
+
+    // このバージョンのプログラムが読み書きする形式のバージョン
+    fn current_version(self) -> u32 {
+        match self {
+            Store::History => 1,
+            Store::KnownPeers => 1,
+            Store::Config => 1,
+        }
+    }
+
+    // 移行前にバックアップするファイル
+    fn files(self, paths: &Paths) -> Vec<PathBuf> {
+        match self {
+            Store::History => vec![
+                paths.history_file(),
+                History::seq_mark_file(&paths.history_file()),
+                paths.history_key_file(),
+            ],
+            Store::KnownPeers => vec![paths.known_peers_file()],
+            Store::Config => vec![paths.config_file()],
+        }
+    }
+}
+
+// 1つのバージョンから次のバージョンへの移行処理
+struct Migration {
+    store: Store,
+    from: u32,
+    description: &'static str,
+    run: fn(&Paths) -> Result<(), Box<dyn std::error::Error>>,
+}
+
+// 保存形式を変更するときは、Store::current_versionを上げてここに移行処理を追加する
+const MIGRATIONS: &[Migration] = &[];
+
+// schema.json の内容。データの種類 → 保存形式のバージョン
+#[derive(Default, Serialize, Deserialize)]
+struct Schema {
+    versions: BTreeMap<Store, u32>,
+}
+
+// 保存済みのデータを現在の形式に移行する。移行したものの説明を返す
+// 設定ファイルも対象にするため、ログの初期化より前に呼ばれる
+// バージョンの記録がないデータ (この仕組みより前に作られたもの) はバージョン1として扱う
+pub fn run(paths: &Paths) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let schema_file = paths.schema_file();
+    let existed = schema_file.exists();
+    let mut schema: Schema = if existed {
+        serde_json::from_str(&std::fs::read_to_string(&schema_file)?)?
+    } else {
+        Schema::default()
+    };
+
+    let mut applied = Vec::new();
+    for store in Store::ALL {
+        let current = store.current_version();
+        let mut version = schema.versions.get(&store).copied().unwrap_or(1);
+        if version > current {
+            return Err(format!(
+                "{:?} の保存形式 (v{}) はこのバージョンが対応する形式 (v{}) より新しいため読み込めません。新しいバージョンを使用してください",
+                store, version, current
+            )
+            .into());
+        }
+        if version < current {
+            backup(paths, store, &format!("v{}", version))?;
+        }
+        while version < current {
+            let migration = MIGRATIONS
+                .iter()
+                .find(|m| m.store == store && m.from == version)
+                .ok_or_else(|| format!("{:?} をv{}から移行する処理がありません", store, version))?;
+            (migration.run)(paths)?;
+            version += 1;
+            // 途中で失敗しても、完了した段階からやり直せるよう1段階ごとに記録する
+            schema.versions.insert(store, version);
+            std::fs::write(&schema_file, serde_json::to_string_pretty(&schema)?)?;
+            applied.push(format!("{:?} v{}: {}", store, version, migration.description));
+        }
+        schema.versions.insert(store, version);
+    }
+
+    if !existed {
+        std::fs::write(&schema_file, serde_json::to_string_pretty(&schema)?)?;
+    }
+    Ok(applied)
+}
+
+// 移行前のファイルを backups/<日時>-<種類>-<label>/ にコピーしておく (labelは移行前のバージョンなど)。コピーしたファイルがあればその場所を返す
+// 保存形式のバージョンを変えずに中身を書き換える処理 (history encrypt など) も、書き換える前に呼ぶ
+pub fn backup(paths: &Paths, store: Store, label: &str) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let dir = paths.data_dir.join("backups").join(format!(
+        "{}-{:?}-{}",
+        chrono::Local::now().format("%Y%m%d%H%M%S"),
+        store,
+        label
+    ));
+    let mut copied = false;
+    for file in store.files(paths) {
+        if let Some(name) = file.file_name().filter(|_| file.exists()) {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::copy(&file, dir.join(name))?;
+            copied = true;
+        }
+    }
+    Ok(copied.then_some(dir))
+}
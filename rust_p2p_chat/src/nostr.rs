@@ -0,0 +1,415 @@
+use ::nostr::nips::nip04;
+use ::nostr::nips::nip59::UnwrappedGift;
+use ::nostr::{ClientMessage, Event, EventBuilder, Filter, JsonUtil, Keys, Kind, PublicKey, RelayMessage, SecretKey, SubscriptionId, Tag, Timestamp, ToBech32};
+use chrono::{DateTime, Local, TimeZone};
+use futures_util::{SinkExt, StreamExt};
+use ring::hkdf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::builder::IdentitySource;
+use crate::chat::record;
+use crate::config::{Config, NostrConfig};
+use crate::contacts::{AddressBook, Contact};
+use crate::drafts::{Draft, Drafts};
+use crate::history::{Direction, EventKind, History};
+use crate::paths::Paths;
+
+// Nostrのリレーを経由した、暗号化したダイレクトメッセージ。直接もカスタムのリレーでもつながらない相手に、非同期で届ける
+//
+// 自分のNostrの鍵 (secp256k1) は、証明書の秘密鍵からHKDFで導く (新しく保存する鍵はなく、証明書を作り直すと変わる)
+// 送るときは NIP-17 (NIP-44で暗号化してNIP-59のギフトラップで包む)、[nostr] nip04 = true なら古いクライアント向けのNIP-04を使う
+// 受け取るときはどちらにも対応する
+//
+//     nostr key       自分の公開鍵 (npub) を表示する (相手の連絡先の --nostr に登録してもらう)
+//     nostr send      連絡先またはnpubの相手に送る
+//     nostr inbox     届いたメッセージを履歴に記録して表示する
+//     nostr gateway   [nostr] after_mins より古い下書きを、連絡先の --nostr の相手に送る
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// 証明書の鍵からNostrの鍵を導くときのsaltとinfo (変えると鍵が変わる)
+const KEY_SALT: &[u8] = b"p2pchat nostr key";
+const KEY_INFO: &[u8] = b"secp256k1";
+// リレーとの1回のやり取り (接続・送信の確認・保存済みのイベントの受信) を待つ上限
+const RELAY_TIMEOUT: Duration = Duration::from_secs(15);
+// 下書き・届いたメッセージを確かめる間隔 (gateway・inbox --follow)
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// ギフトラップの作成時刻は、やり取りの時刻を隠すため最大2日前までずらされる (NIP-59)。その分さかのぼって問い合わせる
+const WRAP_SKEW_SECS: u64 = 2 * 24 * 60 * 60;
+// 初めて受け取るときにさかのぼる秒数
+const FIRST_LOOKBACK_SECS: u64 = 7 * 24 * 60 * 60;
+// 問い合わせるときのサブスクリプションID
+const SUBSCRIPTION: &str = "p2pchat-inbox";
+
+// 自分のNostrの鍵 (証明書と鍵がなければ作る)
+pub fn keys(paths: &Paths) -> Result<Keys> {
+    let identity = IdentitySource::Profile.load(paths)?;
+    derive_keys(&identity.key_der)
+}
+
+// 証明書の秘密鍵 (PKCS#8のDER) からNostrの鍵を導く。secp256k1の秘密鍵として使えない値 (ごくまれ) なら数え上げて導き直す
+pub fn derive_keys(key_der: &[u8]) -> Result<Keys> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, KEY_SALT).extract(key_der);
+    for counter in 0..=u8::MAX {
+        let mut secret = [0u8; 32];
+        prk.expand(&[KEY_INFO, &[counter]], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut secret))
+            .map_err(|_| "Nostrの鍵を導けませんでした")?;
+        if let Ok(secret) = SecretKey::from_slice(&secret) {
+            return Ok(Keys::new(secret));
+        }
+    }
+    Err("Nostrの鍵を導けませんでした".into())
+}
+
+// npub1... または16進数の公開鍵
+pub fn parse_public_key(text: &str) -> Result<PublicKey> {
+    PublicKey::parse(text.trim()).map_err(|e| format!("Nostrの公開鍵として読めません ({}): {}", text, e).into())
+}
+
+pub fn npub(key: &PublicKey) -> String {
+    key.to_bech32().unwrap_or_else(|_| key.to_hex())
+}
+
+// 相手宛ての暗号化したダイレクトメッセージのイベントを作る
+pub async fn seal(keys: &Keys, receiver: &PublicKey, body: &str, nip04: bool) -> Result<Event> {
+    if nip04 {
+        let content = nip04::encrypt(keys.secret_key(), receiver, body)?;
+        let event = EventBuilder::new(Kind::EncryptedDirectMessage, content).tag(Tag::public_key(*receiver)).sign_with_keys(keys)?;
+        return Ok(event);
+    }
+    Ok(EventBuilder::private_msg(keys, *receiver, body, []).await?)
+}
+
+// 受け取ったダイレクトメッセージ
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub sender: PublicKey,
+    pub body: String,
+    /// 送った側がメッセージを書いた時刻 (ギフトラップのずらした時刻ではない)
+    pub at: DateTime<Local>,
+}
+
+// 自分宛てのイベントを検証して開く。ダイレクトメッセージでなければNone
+pub async fn open(keys: &Keys, event: &Event) -> Result<Option<Received>> {
+    event.verify()?;
+    match event.kind {
+        Kind::GiftWrap => {
+            let unwrapped = UnwrappedGift::from_gift_wrap(keys, event).await?;
+            if unwrapped.rumor.kind != Kind::PrivateDirectMessage {
+                return Ok(None);
+            }
+            let at = local_time(unwrapped.rumor.created_at);
+            Ok(Some(Received { sender: unwrapped.sender, body: unwrapped.rumor.content, at }))
+        }
+        Kind::EncryptedDirectMessage => {
+            let body = nip04::decrypt(keys.secret_key(), &event.pubkey, &event.content)?;
+            Ok(Some(Received { sender: event.pubkey, body, at: local_time(event.created_at) }))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn local_time(at: Timestamp) -> DateTime<Local> {
+    Local.timestamp_opt(at.as_secs() as i64, 0).single().unwrap_or_else(Local::now)
+}
+
+// [nostr] relays のすべてにイベントを送り、受け付けたリレーの数を返す (1つも受け付けなければエラー)
+pub async fn publish(relays: &[String], event: &Event) -> Result<usize> {
+    let relays = required(relays)?;
+    let results = futures_util::future::join_all(relays.iter().map(|relay| publish_to(relay, event))).await;
+    let mut accepted = 0;
+    let mut last_error = None;
+    for (relay, result) in relays.iter().zip(results) {
+        match result {
+            Ok(()) => accepted += 1,
+            Err(e) => {
+                tracing::warn!(relay = %relay, error = %e, "リレーにイベントを送れませんでした");
+                last_error = Some(e);
+            }
+        }
+    }
+    match (accepted, last_error) {
+        (0, Some(e)) => Err(format!("どのリレーにも送れませんでした: {}", e).into()),
+        (accepted, _) => Ok(accepted),
+    }
+}
+
+// [nostr] relays から自分宛てのダイレクトメッセージのイベントを集める (重複は除く)
+pub async fn fetch(relays: &[String], receiver: &PublicKey, since: Timestamp) -> Result<Vec<Event>> {
+    let relays = required(relays)?;
+    let filter = Filter::new().kinds([Kind::GiftWrap, Kind::EncryptedDirectMessage]).pubkey(*receiver).since(since);
+    let results = futures_util::future::join_all(relays.iter().map(|relay| fetch_from(relay, filter.clone()))).await;
+    let mut events = BTreeMap::new();
+    let mut reached = false;
+    for (relay, result) in relays.iter().zip(results) {
+        match result {
+            Ok(fetched) => {
+                reached = true;
+                events.extend(fetched.into_iter().map(|event| (event.id, event)));
+            }
+            Err(e) => tracing::warn!(relay = %relay, error = %e, "リレーからイベントを受け取れませんでした"),
+        }
+    }
+    if !reached {
+        return Err("どのリレーにも接続できませんでした".into());
+    }
+    Ok(events.into_values().collect())
+}
+
+fn required(relays: &[String]) -> Result<&[String]> {
+    if relays.is_empty() {
+        return Err("config.toml の [nostr] relays に使うリレーを指定してください".into());
+    }
+    Ok(relays)
+}
+
+type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn connect(relay: &str) -> Result<Socket> {
+    let (socket, _) = tokio::time::timeout(RELAY_TIMEOUT, tokio_tungstenite::connect_async(relay))
+        .await
+        .map_err(|_| format!("リレーに接続できませんでした (時間切れ): {}", relay))??;
+    Ok(socket)
+}
+
+// 次のリレーのメッセージ (テキスト以外と読めないものは読み飛ばす)
+async fn next_message(socket: &mut Socket) -> Result<RelayMessage<'static>> {
+    loop {
+        let message = socket.next().await.ok_or("リレーが接続を閉じました")??;
+        let Message::Text(text) = message else {
+            continue;
+        };
+        match RelayMessage::from_json(&text) {
+            Ok(message) => return Ok(message),
+            Err(e) => tracing::debug!(error = ?e, "リレーのメッセージを読めませんでした"),
+        }
+    }
+}
+
+async fn publish_to(relay: &str, event: &Event) -> Result<()> {
+    let mut socket = connect(relay).await?;
+    socket.send(Message::Text(ClientMessage::event(event.clone()).as_json())).await?;
+    let accepted = async {
+        loop {
+            match next_message(&mut socket).await? {
+                RelayMessage::Ok { event_id, status: true, .. } if event_id == event.id => return Ok(()),
+                RelayMessage::Ok { event_id, message, .. } if event_id == event.id => return Err(format!("リレーが受け付けませんでした: {}", message).into()),
+                RelayMessage::Notice(notice) => tracing::info!(relay = %relay, %notice, "リレーからのお知らせ"),
+                _ => {}
+            }
+        }
+    };
+    let result: Result<()> = tokio::time::timeout(RELAY_TIMEOUT, accepted).await.map_err(|_| "リレーから受け付けの確認が届きませんでした")?;
+    let _ = socket.close(None).await;
+    result
+}
+
+async fn fetch_from(relay: &str, filter: Filter) -> Result<Vec<Event>> {
+    let mut socket = connect(relay).await?;
+    let subscription = SubscriptionId::new(SUBSCRIPTION);
+    socket.send(Message::Text(ClientMessage::req(subscription.clone(), vec![filter]).as_json())).await?;
+    let mut events = Vec::new();
+    let stored = async {
+        loop {
+            match next_message(&mut socket).await? {
+                RelayMessage::Event { subscription_id, event } if *subscription_id == subscription => events.push(event.into_owned()),
+                RelayMessage::EndOfStoredEvents(subscription_id) if *subscription_id == subscription => return Ok(()),
+                RelayMessage::Closed { subscription_id, message } if *subscription_id == subscription => {
+                    return Err(format!("リレーが問い合わせを断りました: {}", message).into());
+                }
+                RelayMessage::Notice(notice) => tracing::info!(relay = %relay, %notice, "リレーからのお知らせ"),
+                _ => {}
+            }
+        }
+    };
+    let result: Result<()> = tokio::time::timeout(RELAY_TIMEOUT, stored).await.map_err(|_| "リレーから保存済みのイベントが届き終わりませんでした")?;
+    result?;
+    let _ = socket.send(Message::Text(ClientMessage::close(subscription).as_json())).await;
+    let _ = socket.close(None).await;
+    Ok(events)
+}
+
+// 連絡先の相手を履歴・下書きに記録する名前 (connect <名前> のときと同じ host:port。URIでなければ連絡先の名前)
+fn history_peer(name: &str, contact: &Contact) -> String {
+    if !contact.uri.contains("://") {
+        return name.to_string();
+    }
+    crate::trust::host_key(&contact.uri).unwrap_or_else(|_| name.to_string())
+}
+
+// 公開鍵が一致する連絡先
+fn find_contact<'a>(book: &'a AddressBook, key: &PublicKey) -> Option<(&'a String, &'a Contact)> {
+    book.iter().find(|(_, contact)| contact.nostr.as_deref().and_then(|text| PublicKey::parse(text.trim()).ok()) == Some(*key))
+}
+
+// 送り先 (連絡先の名前、npub、16進数の公開鍵) を、公開鍵と履歴に記録する名前にする
+pub fn resolve(paths: &Paths, to: &str) -> Result<(PublicKey, String)> {
+    let book = AddressBook::load(paths.contacts_file())?;
+    if let Some(contact) = book.get(to) {
+        let key = contact.nostr.as_deref().ok_or_else(|| format!("連絡先 {} にNostrの公開鍵がありません (contacts set {} --nostr <npub>)", to, to))?;
+        return Ok((parse_public_key(key)?, history_peer(to, contact)));
+    }
+    let key = parse_public_key(to)?;
+    let peer = find_contact(&book, &key).map_or_else(|| npub(&key), |(name, contact)| history_peer(name, contact));
+    Ok((key, peer))
+}
+
+// 1通送って履歴に記録し、受け付けたリレーの数を返す
+pub async fn send(paths: &Paths, config: &NostrConfig, history: &mut History, to: &str, body: &str) -> Result<usize> {
+    let (receiver, peer) = resolve(paths, to)?;
+    let keys = keys(paths)?;
+    let event = seal(&keys, &receiver, body, config.nip04).await?;
+    let accepted = publish(&config.relays, &event).await?;
+    record(history, &peer, EventKind::Nostr { direction: Direction::Outgoing, body: body.to_string() });
+    Ok(accepted)
+}
+
+// 最後に確かめた時刻と、受け取ったギフトラップのID (ずらした時刻の分、同じものが何度か届くため)
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct InboxState {
+    since: Option<u64>,
+    /// イベントのID → 作成時刻
+    seen: BTreeMap<String, u64>,
+}
+
+impl InboxState {
+    fn load(paths: &Paths) -> Result<Self> {
+        let path = paths.nostr_state_file();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, paths: &Paths) -> Result<()> {
+        std::fs::write(paths.nostr_state_file(), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+// 届いたメッセージを受け取り、履歴に記録して (履歴に記録した名前, メッセージ) を古い順に返す
+// 前回の受け取り以降のものだけを返す (知らない相手からのものは名前をnpubにする)
+pub async fn receive(paths: &Paths, config: &NostrConfig, history: &mut History) -> Result<Vec<(String, Received)>> {
+    let keys = keys(paths)?;
+    let mut state = InboxState::load(paths)?;
+    let now = Timestamp::now().as_secs();
+    let since = state.since.map_or(now.saturating_sub(FIRST_LOOKBACK_SECS), |since| since.saturating_sub(WRAP_SKEW_SECS));
+    let events = fetch(&config.relays, &keys.public_key(), Timestamp::from(since)).await?;
+    let book = AddressBook::load(paths.contacts_file())?;
+    let mut received = Vec::new();
+    for event in events {
+        let id = event.id.to_hex();
+        if state.seen.contains_key(&id) {
+            continue;
+        }
+        state.seen.insert(id, event.created_at.as_secs());
+        match open(&keys, &event).await {
+            Ok(Some(message)) => {
+                let peer = find_contact(&book, &message.sender).map_or_else(|| npub(&message.sender), |(name, contact)| history_peer(name, contact));
+                received.push((peer, message));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!(event = %event.id, error = %e, "届いたイベントを開けませんでした"),
+        }
+    }
+    received.sort_by_key(|(_, message)| message.at);
+    for (peer, message) in &received {
+        record(history, peer, EventKind::Nostr { direction: Direction::Incoming, body: message.body.clone() });
+    }
+    // 次に問い合わせる範囲より古いIDは、もう届かないので忘れる
+    state.seen.retain(|_, created_at| *created_at + WRAP_SKEW_SECS >= now);
+    state.since = Some(now);
+    state.save(paths)?;
+    Ok(received)
+}
+
+// 届いたメッセージを表示する。followなら止められるまで1分ごとに確かめる
+pub async fn run_inbox(paths: &Paths, history: &mut History, follow: bool) -> Result<()> {
+    loop {
+        let config = Config::load(&paths.config_file())?.nostr;
+        match receive(paths, &config, history).await {
+            Ok(received) => {
+                for (peer, message) in received {
+                    println!("[{}] {}: {}", message.at.format("%Y-%m-%d %H:%M"), peer, message.body);
+                }
+            }
+            Err(e) if !follow => return Err(e),
+            Err(e) => tracing::error!(error = %e, "Nostrのメッセージを受け取れませんでした"),
+        }
+        if !follow {
+            return Ok(());
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+// [nostr] の設定に従い、止められるまで下書きを確かめてNostrで送る。onceなら1回だけ確かめる
+pub async fn run_gateway(paths: &Paths, history: &mut History, once: bool) -> Result<()> {
+    loop {
+        // 設定の変更は次に確かめるときに反映する
+        let config = Config::load(&paths.config_file())?.nostr;
+        match deliver_due(paths, &config, history).await {
+            Ok(0) => tracing::debug!("Nostrで送る下書きはありません"),
+            Ok(sent) => println!("{}件のメッセージをNostrで送りました。", sent),
+            Err(e) if once => return Err(e),
+            Err(e) => tracing::error!(error = %e, "下書きをNostrで送れませんでした"),
+        }
+        if once {
+            return Ok(());
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+// after_minsより古い下書きを、連絡先にNostrの公開鍵がある相手に1通ずつ送り、送ったメッセージの数を返す
+// リレーが受け付けた下書きは届けたものとして消す
+pub async fn deliver_due(paths: &Paths, config: &NostrConfig, history: &mut History) -> Result<usize> {
+    required(&config.relays)?;
+    let keys = keys(paths)?;
+    let book = AddressBook::load(paths.contacts_file())?;
+    let mut drafts = Drafts::load(paths.drafts_file())?;
+    let deadline = Local::now() - chrono::Duration::minutes(config.after_mins as i64);
+    let mut sent = 0;
+    for peer in drafts.peers() {
+        let Some((name, contact)) = book.find_by_peer(&peer) else {
+            continue;
+        };
+        let Some(key) = &contact.nostr else {
+            continue;
+        };
+        let receiver = match parse_public_key(key) {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                tracing::warn!(contact = %name, error = %e, "連絡先のNostrの公開鍵を読めません");
+                continue;
+            }
+        };
+        let mut kept: Vec<Draft> = Vec::new();
+        for draft in drafts.get(&peer).to_vec() {
+            // 順番を保つため、1通でも送れなければ以降の下書きも残す
+            if draft.saved_at > deadline || !kept.is_empty() {
+                kept.push(draft);
+                continue;
+            }
+            let event = seal(&keys, &receiver, &draft.body, config.nip04).await?;
+            if let Err(e) = publish(&config.relays, &event).await {
+                tracing::warn!(contact = %name, error = %e, "下書きをNostrで送れませんでした");
+                kept.push(draft);
+                continue;
+            }
+            sent += 1;
+            record(history, &peer, EventKind::Nostr { direction: Direction::Outgoing, body: draft.body });
+        }
+        if kept.len() != drafts.get(&peer).len() {
+            tracing::info!(contact = %name, "届けられなかったメッセージをNostrで送りました");
+            drafts.replace(&peer, kept);
+            // 後の相手で失敗しても同じメッセージを送り直さないよう、相手ごとに保存する
+            drafts.save()?;
+        }
+    }
+    Ok(sent)
+}
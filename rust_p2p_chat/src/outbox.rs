@@ -0,0 +1,204 @@
+use crate::config::QueuePolicy;
+use crate::protocol::Envelope;
+use crate::runtime::{Runtime, Task};
+use futures_util::{Sink, SinkExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_util::sync::{CancellationToken, PollSender};
+
+// 相手へ送るフレームの順番待ち (接続ごと)
+// セッションは順番待ちに入れるだけで、WebSocketへの書き込みは専用のタスクが行う
+// そのため受け取りの遅い相手がいても、順番待ちに空きがあるうちは入力やコマンドの処理が止まらない
+
+// 順番待ちに入れる口。SinkとしてWebSocketの送信側の代わりに使う
+pub struct Outbox {
+    sender: PollSender<Message>,
+    policy: QueuePolicy,
+    /// 書き込みに失敗した理由 (書き込むタスクが終わった後の送信はこのエラーになる)
+    failure: Arc<Mutex<Option<String>>>,
+    /// Dropで捨てたフレームの数
+    dropped: u64,
+    /// Dropでも捨てられないフレームのうち、空きを待っているもの (空きができしだい順に入れる)
+    held: VecDeque<Message>,
+    /// 捨て始めてから、まだ順番待ちに入れられたフレームがない (警告を繰り返さない)
+    dropping: bool,
+    on_drop: Option<Box<dyn Fn() + Send>>,
+}
+
+// 順番待ちからWebSocketに書き込むタスク
+pub struct Writer {
+    task: Task,
+    stop: CancellationToken,
+}
+
+// 書き込むタスクを起動し、順番待ちに入れる口を返す
+pub fn spawn<W>(runtime: &Runtime, sink: W, capacity: usize, policy: QueuePolicy) -> (Outbox, Writer)
+where
+    W: Sink<Message, Error = Error> + Unpin + Send + 'static,
+{
+    let (sender, queue) = mpsc::channel(capacity.max(1));
+    let failure = Arc::new(Mutex::new(None));
+    let stop = CancellationToken::new();
+    let writing = write(sink, queue, Arc::clone(&failure));
+    let cancelled = stop.clone();
+    let task = runtime.spawn(async move {
+        tokio::select! {
+            _ = cancelled.cancelled() => tracing::debug!("送信を待っていたフレームを捨てて接続を閉じます"),
+            _ = writing => {}
+        }
+    });
+    let outbox = Outbox { sender: PollSender::new(sender), policy, failure, dropped: 0, held: VecDeque::new(), dropping: false, on_drop: None };
+    (outbox, Writer { task, stop })
+}
+
+// 順番待ちから取り出して書き込む。続けて届いたフレームは順番待ちが空になってからまとめて書き出す
+async fn write<W>(mut sink: W, mut queue: mpsc::Receiver<Message>, failure: Arc<Mutex<Option<String>>>)
+where
+    W: Sink<Message, Error = Error> + Unpin,
+{
+    while let Some(message) = queue.recv().await {
+        let mut result = sink.feed(message).await;
+        if result.is_ok() && queue.is_empty() {
+            result = sink.flush().await;
+        }
+        if let Err(e) = result {
+            tracing::error!(error = %e, "WebSocketへの書き込みに失敗しました");
+            *failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(e.to_string());
+            return;
+        }
+    }
+    // セッションが終わった: 受け取ったCloseへの応答も含めて書き出し、接続を閉じる
+    let _ = sink.close().await;
+}
+
+impl Outbox {
+    // Dropでフレームを捨て始めたときに呼ぶ (続けて捨てる間は1回だけ)
+    pub fn on_drop(mut self, notify: impl Fn() + Send + 'static) -> Self {
+        self.on_drop = Some(Box::new(notify));
+        self
+    }
+
+    // Dropで捨てたフレームの数
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // 空きを待っているフレームをすべて順番待ちに入れるまで待つ
+    fn poll_held(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.held.is_empty() {
+            if std::task::ready!(self.sender.poll_reserve(cx)).is_err() {
+                return Poll::Ready(Err(self.closed()));
+            }
+            let message = self.held.pop_front().expect("空きを待っているフレームがありません");
+            self.sender.send_item(message).map_err(|_| self.closed())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Dropで捨てる
+    fn discard(&mut self) {
+        self.dropped += 1;
+        tracing::warn!(dropped = self.dropped, "送信の順番待ちがいっぱいのため、メッセージを捨てました");
+        if !std::mem::replace(&mut self.dropping, true) {
+            if let Some(notify) = &self.on_drop {
+                notify();
+            }
+        }
+    }
+
+    // 書き込むタスクが終わった後の送信のエラー
+    fn closed(&self) -> Error {
+        match self.failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone() {
+            Some(failure) => Error::Io(std::io::Error::other(failure)),
+            None => Error::AlreadyClosed,
+        }
+    }
+}
+
+impl Sink<Message> for Outbox {
+    type Error = Error;
+
+    // Blockでは空きができるまで待つ。Drop・Disconnectでは待たずに、入れられない場合の扱いをstart_sendで決める
+    // (Dropで空きを待っているフレームがあれば、それを入れ終えてから)
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        std::task::ready!(self.poll_held(cx))?;
+        let ready = match self.policy {
+            QueuePolicy::Block => std::task::ready!(self.sender.poll_reserve(cx)).is_ok(),
+            _ => !self.sender.is_closed(),
+        };
+        Poll::Ready(if ready { Ok(()) } else { Err(self.closed()) })
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), Error> {
+        if self.policy == QueuePolicy::Block {
+            return self.sender.send_item(message).map_err(|_| self.closed());
+        }
+        if !self.held.is_empty() {
+            // 空きを待っているフレームを追い越さない
+            if droppable(&message) {
+                self.discard();
+            } else {
+                self.held.push_back(message);
+            }
+            return Ok(());
+        }
+        let Some(sender) = self.sender.get_ref() else {
+            return Err(self.closed());
+        };
+        match sender.try_send(message) {
+            Ok(()) => {
+                self.dropping = false;
+                Ok(())
+            }
+            // 順番待ちがいっぱいなら、方針に従って捨てるか接続を切る
+            // Dropで捨てるのはチャットのメッセージだけ。ファイルのデータ・受信確認・Pong・セッションの制御・Closeは
+            // 捨てると転送や接続が壊れるため、空きを待って順に入れる
+            Err(mpsc::error::TrySendError::Full(message)) if self.policy == QueuePolicy::Drop => {
+                if droppable(&message) {
+                    self.discard();
+                } else {
+                    self.held.push_back(message);
+                }
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Full(message)) => {
+                tracing::warn!("送信の順番待ちがいっぱいのため、接続を切ります");
+                Err(Error::WriteBufferFull(message))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(self.closed()),
+        }
+    }
+
+    // 順番待ちに入れた時点で送ったものとする (書き込みは書き込むタスクが順に行う)
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_held(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        std::task::ready!(self.poll_held(cx))?;
+        self.sender.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Dropで捨ててよいフレーム (チャットのメッセージ)
+fn droppable(message: &Message) -> bool {
+    matches!(message, Message::Text(text) if matches!(Envelope::decode(text), Envelope::Chat { .. }))
+}
+
+impl Writer {
+    // 順番待ちを閉じ、残りのフレームを書き込んで接続を閉じるまで長くてもtimeoutだけ待つ
+    // 間に合わなければ残りを捨てる (応答しない相手のために接続を開いたままにしない)
+    pub async fn finish(self, outbox: Outbox, runtime: &Runtime, timeout: Duration) {
+        drop(outbox);
+        if runtime.timeout(timeout, self.task.join()).await.is_err() {
+            tracing::warn!("送信を待っているフレームを書き込めないまま接続を閉じます");
+            self.stop.cancel();
+        }
+    }
+}
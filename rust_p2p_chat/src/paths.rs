@@ -0,0 +1,216 @@
+use directories::ProjectDirs;
+use std::path::{Path, PathBuf};
+
+// プロファイルを指定しなかったときに使うプロファイル名
+pub const DEFAULT_PROFILE: &str = "default";
+
+// 設定・データ・キャッシュの保存先
+// Linux: XDG (~/.config, ~/.local/share, ~/.cache)、Windows: AppData、macOS: ~/Library
+// default以外のプロファイルは profiles/<名前> 以下に鍵・履歴・アドレス帳などを分けて保存する
+#[derive(Clone)]
+pub struct Paths {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    pub profile: String,
+    base_config_dir: PathBuf,
+    base_data_dir: PathBuf,
+}
+
+impl Paths {
+    // --data-dir が指定された場合はその下にすべてをまとめる
+    // createがfalseのとき、存在しないプロファイルはエラーにする
+    pub fn resolve(data_dir: Option<&Path>, profile: &str, create: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        validate_profile_name(profile)?;
+
+        let (base_config_dir, base_data_dir, cache_dir) = match data_dir {
+            Some(dir) => (dir.to_path_buf(), dir.to_path_buf(), dir.join("cache")),
+            None => {
+                let dirs = ProjectDirs::from("", "", "rust_p2p_chat")
+                    .ok_or("ホームディレクトリが見つかりません。--data-dir で保存先を指定してください")?;
+                (
+                    dirs.config_dir().to_path_buf(),
+                    dirs.data_dir().to_path_buf(),
+                    dirs.cache_dir().to_path_buf(),
+                )
+            }
+        };
+
+        let (config_dir, data_dir) = if profile == DEFAULT_PROFILE {
+            (base_config_dir.clone(), base_data_dir.clone())
+        } else {
+            let data_dir = base_data_dir.join("profiles").join(profile);
+            if !create && !data_dir.exists() {
+                return Err(format!(
+                    "プロファイル {} は存在しません。`profile create {}` で作成してください",
+                    profile, profile
+                )
+                .into());
+            }
+            (base_config_dir.join("profiles").join(profile), data_dir)
+        };
+
+        let paths = Self {
+            config_dir,
+            data_dir,
+            cache_dir,
+            profile: profile.to_string(),
+            base_config_dir,
+            base_data_dir,
+        };
+        for dir in [&paths.config_dir, &paths.data_dir, &paths.cache_dir] {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(paths)
+    }
+
+    // 作成済みのプロファイル名の一覧 (defaultを含む)
+    pub fn list_profiles(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut profiles = vec![DEFAULT_PROFILE.to_string()];
+        let root = self.base_data_dir.join("profiles");
+        if root.exists() {
+            for entry in std::fs::read_dir(root)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    profiles.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        profiles[1..].sort();
+        Ok(profiles)
+    }
+
+    // 削除できるプロファイルか確かめ、そのデータディレクトリを返す。defaultと使用中のプロファイルは削除できない
+    pub fn deletable_profile(&self, profile: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        validate_profile_name(profile)?;
+        if profile == DEFAULT_PROFILE {
+            return Err("defaultプロファイルは削除できません".into());
+        }
+        if profile == self.profile {
+            return Err(format!("使用中のプロファイル {} は削除できません。別のプロファイル (--profile) から削除してください", profile).into());
+        }
+        let data_dir = self.base_data_dir.join("profiles").join(profile);
+        if !data_dir.exists() {
+            return Err(format!("プロファイル {} は存在しません", profile).into());
+        }
+        Ok(data_dir)
+    }
+
+    // プロファイルのディレクトリを削除する
+    pub fn delete_profile(&self, profile: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data_dir = self.deletable_profile(profile)?;
+        std::fs::remove_dir_all(data_dir)?;
+        let config_dir = self.base_config_dir.join("profiles").join(profile);
+        if config_dir.exists() {
+            std::fs::remove_dir_all(config_dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.config_dir.join("config.toml")
+    }
+
+    pub fn history_file(&self) -> PathBuf {
+        self.data_dir.join("history.jsonl")
+    }
+
+    pub fn history_key_file(&self) -> PathBuf {
+        self.data_dir.join("history.key")
+    }
+
+    pub fn contacts_file(&self) -> PathBuf {
+        self.data_dir.join("contacts.json")
+    }
+
+    pub fn known_peers_file(&self) -> PathBuf {
+        self.data_dir.join("known_peers")
+    }
+
+    pub fn identity_cert_file(&self) -> PathBuf {
+        self.data_dir.join("identity_cert.der")
+    }
+
+    pub fn identity_key_file(&self) -> PathBuf {
+        self.data_dir.join("identity_key.der")
+    }
+
+    // キーストアに預けた秘密の記録 (`keystore store` で作る。秘密そのものは含まない)
+    pub fn keystore_file(&self) -> PathBuf {
+        self.data_dir.join("keystore.json")
+    }
+
+    // nostr inbox が最後に確かめた時刻と受け取ったイベント
+    pub fn nostr_state_file(&self) -> PathBuf {
+        self.data_dir.join("nostr.json")
+    }
+
+    // --backend libp2p のPeerIDの鍵 (初回に作る)
+    pub fn libp2p_key_file(&self) -> PathBuf {
+        self.data_dir.join("libp2p_key")
+    }
+
+    pub fn certs_file(&self) -> PathBuf {
+        self.data_dir.join("certs.json")
+    }
+
+    pub fn sessions_file(&self) -> PathBuf {
+        self.data_dir.join("sessions.json")
+    }
+
+    pub fn resume_tokens_file(&self) -> PathBuf {
+        self.data_dir.join("resume_tokens.json")
+    }
+
+    pub fn schema_file(&self) -> PathBuf {
+        self.data_dir.join("schema.json")
+    }
+
+    pub fn drafts_file(&self) -> PathBuf {
+        self.data_dir.join("drafts.json")
+    }
+
+    pub fn input_history_file(&self) -> PathBuf {
+        self.data_dir.join("input_history")
+    }
+
+    // 利用状況の記録 (`telemetry enable` で作る)
+    pub fn telemetry_file(&self) -> PathBuf {
+        self.data_dir.join("telemetry.json")
+    }
+
+    // デーモンの制御用ソケット
+    pub fn control_socket(&self) -> PathBuf {
+        self.data_dir.join("control.sock")
+    }
+
+    // デーモンのREST APIの認証に使うトークン (`daemon --api` の初回に作る)
+    pub fn api_token_file(&self) -> PathBuf {
+        self.data_dir.join("api_token")
+    }
+
+    // WebAssemblyのプラグイン (*.wasm) を置くディレクトリ
+    pub fn plugins_dir(&self) -> PathBuf {
+        self.config_dir.join("plugins")
+    }
+
+    // Rhaiのスクリプト (*.rhai) を置くディレクトリ
+    pub fn scripts_dir(&self) -> PathBuf {
+        self.config_dir.join("scripts")
+    }
+
+    pub fn downloads_dir(&self) -> PathBuf {
+        self.data_dir.join("downloads")
+    }
+}
+
+// ディレクトリ名として安全に使える名前だけを受け付ける
+fn validate_profile_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!("プロファイル名には英数字・'-'・'_' のみ使用できます: {}", name).into());
+    }
+    Ok(())
+}
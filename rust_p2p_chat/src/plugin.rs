@@ -0,0 +1,173 @@
+use crate::commands::COMMANDS;
+use crate::hook::{HookCommand, HookContext, MessageHook, Verdict};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use wasmtime::component::{Component, HasSelf, Linker};
+use wasmtime::{Config, Engine, Store, StoreLimits, StoreLimitsBuilder};
+
+// WebAssemblyのプラグイン (wit/plugin.wit の chat-plugin ワールドを実装したコンポーネント)
+// プラグインにはWASIを渡さないため、ファイル・ネットワーク・環境変数には触れられない。渡すのはログへの書き出しだけ
+
+mod bindings {
+    wasmtime::component::bindgen!({ path: "wit", world: "chat-plugin" });
+}
+
+use bindings::p2pchat::plugin::types;
+
+// 1回の呼び出しで使える計算量 (無限ループでチャットが止まらないようにする)
+const FUEL_PER_CALL: u64 = 100_000_000;
+// プラグインが使えるメモリ
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+struct State {
+    plugin: String,
+    limits: StoreLimits,
+}
+
+impl bindings::p2pchat::plugin::host::Host for State {
+    fn log(&mut self, message: String) {
+        tracing::info!(plugin = %self.plugin, "{}", message);
+    }
+}
+
+impl types::Host for State {}
+
+pub struct Plugin {
+    name: String,
+    commands: Vec<HookCommand>,
+    instance: Mutex<(Store<State>, bindings::ChatPlugin)>,
+    /// 一度失敗 (計算量・メモリの上限、トラップ) したら以後は呼ばない
+    failed: AtomicBool,
+}
+
+impl Plugin {
+    // .wasm のコンポーネントを読み込んでインスタンスを作り、追加するコマンドを問い合わせる
+    pub fn load(engine: &Engine, path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let component = Component::from_file(engine, path)?;
+        let mut linker = Linker::new(engine);
+        bindings::ChatPlugin::add_to_linker::<State, HasSelf<State>>(&mut linker, |state| state)?;
+
+        let limits = StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build();
+        let mut store = Store::new(engine, State { plugin: name.clone(), limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = bindings::ChatPlugin::instantiate(&mut store, &component, &linker)?;
+
+        store.set_fuel(FUEL_PER_CALL)?;
+        let mut commands = Vec::new();
+        for command in instance.call_commands(&mut store)? {
+            if COMMANDS.iter().any(|spec| spec.name == command.name) {
+                tracing::warn!(plugin = %name, command = %command.name, "組み込みのコマンドと同じ名前のため、プラグインのコマンドを無視します");
+                continue;
+            }
+            commands.push(HookCommand { name: command.name, usage: command.usage, description: command.description });
+        }
+        Ok(Self { name, commands, instance: Mutex::new((store, instance)), failed: AtomicBool::new(false) })
+    }
+
+    // ファイル名から拡張子を除いたもの
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // プラグインの関数を呼ぶ。失敗したらプラグインを止め、Noneを返す (呼び出し元はプラグインがないものとして続ける)
+    fn call<R>(&self, f: impl FnOnce(&mut Store<State>, &bindings::ChatPlugin) -> wasmtime::Result<R>) -> Option<R> {
+        if self.failed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut guard = self.instance.lock().unwrap_or_else(|e| e.into_inner());
+        let (store, instance) = &mut *guard;
+        let result = store.set_fuel(FUEL_PER_CALL).and_then(|_| f(store, instance));
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                tracing::error!(plugin = %self.name, error = %e, "プラグインの呼び出しに失敗したため、このプラグインを止めます");
+                self.failed.store(true, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+
+impl MessageHook for Plugin {
+    fn on_incoming(&self, ctx: &mut HookContext<'_>, body: String) -> Verdict {
+        let peer = ctx.peer().to_string();
+        match self.call(|store, instance| instance.call_on_incoming(store, &peer, &body)) {
+            Some(incoming) => {
+                apply(ctx, incoming.effects);
+                verdict(incoming.verdict)
+            }
+            None => Verdict::Keep(body),
+        }
+    }
+
+    fn on_outgoing(&self, peer: &str, body: String) -> Verdict {
+        match self.call(|store, instance| instance.call_on_outgoing(store, peer, &body)) {
+            Some(result) => verdict(result),
+            None => Verdict::Keep(body),
+        }
+    }
+
+    fn commands(&self) -> Vec<HookCommand> {
+        self.commands.clone()
+    }
+
+    fn on_command(&self, ctx: &mut HookContext<'_>, name: &str, args: &str) -> bool {
+        let peer = ctx.peer().to_string();
+        match self.call(|store, instance| instance.call_on_command(store, &peer, name, args)) {
+            Some(result) => {
+                apply(ctx, result.effects);
+                result.handled
+            }
+            None => false,
+        }
+    }
+}
+
+fn verdict(verdict: types::Verdict) -> Verdict {
+    match verdict {
+        types::Verdict::Keep(body) => Verdict::Keep(body),
+        types::Verdict::Drop => Verdict::Drop,
+    }
+}
+
+fn apply(ctx: &mut HookContext<'_>, effects: types::Effects) {
+    for body in effects.replies {
+        ctx.reply(body);
+    }
+    for note in effects.notes {
+        ctx.info(note);
+    }
+}
+
+// ディレクトリ内の *.wasm を名前順に読み込む。読み込めなかったプラグインは警告して飛ばす
+pub fn load_dir(dir: &Path) -> Result<Vec<Plugin>, Box<dyn std::error::Error>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+        .collect();
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+    files.sort();
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    let mut plugins = Vec::new();
+    for path in files {
+        match Plugin::load(&engine, &path) {
+            Ok(plugin) => {
+                tracing::info!(plugin = %plugin.name(), commands = plugin.commands.len(), "プラグインを読み込みました");
+                plugins.push(plugin);
+            }
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "プラグインを読み込めませんでした"),
+        }
+    }
+    Ok(plugins)
+}
@@ -0,0 +1,221 @@
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD};
+use base64::Engine;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::builder::ClientSettings;
+use crate::debug::Negotiated;
+use crate::paths::Paths;
+use crate::protocol::Envelope;
+use crate::trust::{self, KnownPeers, TrustStatus};
+use crate::{identity, transport};
+
+// スクリプト向けのサブコマンド (resolve・fingerprint・encode-invite・decode-invite・probe) の処理と出力
+// 出力は1行のJSONで、項目の名前と意味は変えない (項目を増やすことはある)。対話向けのコマンドの表示が変わってもスクリプトが壊れないようにする
+// known_peers・履歴・再開用のトークンは読むだけで書き換えない
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// 招待の文字列の先頭 (形式を変えるときは番号を上げる)
+const INVITE_PREFIX: &str = "p2pchat-invite1:";
+// probe で、相手が接続直後に送るもの (証明書) を待つ時間
+const PROBE_WAIT: Duration = Duration::from_secs(2);
+
+// resolve: 接続先の指定 (URI・連絡先の名前・TXTレコードのドメイン名) をどこにつなぐかに直したもの
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Resolved {
+    pub target: String,
+    /// 接続するURI (フィンガープリントの部分を除く)
+    pub uri: String,
+    /// known_peersのキー (host:port)
+    pub peer: String,
+    pub transport: String,
+    /// 連絡先の名前で指定した場合
+    pub contact: Option<String>,
+    /// 接続したときに照合するフィンガープリント (URLの末尾 → 連絡先 → known_peers の順)
+    pub fingerprint: Option<String>,
+    pub strict: bool,
+}
+
+// settings の接続先を解決する (相手には接続しない)
+pub fn resolve(target: &str, settings: &ClientSettings) -> Result<Resolved> {
+    let mut url = url::Url::parse(&settings.uri)?;
+    let pinned = url.fragment().filter(|pinned| !pinned.is_empty()).map(str::to_string);
+    url.set_fragment(None);
+    let peer = trust::host_key(url.as_str())?;
+    let contact = settings.contact.as_ref();
+    let known = KnownPeers::load(settings.paths.known_peers_file())?;
+    let fingerprint = pinned
+        .or_else(|| contact.and_then(|(_, contact)| contact.fingerprint.clone()))
+        .or_else(|| known.iter().find(|(host, _)| **host == peer).map(|(_, known)| known.fingerprint.clone()));
+    Ok(Resolved {
+        target: target.to_string(),
+        transport: contact.map_or_else(|| transport_of(&url), |(_, contact)| contact.transport.clone()),
+        uri: url.to_string(),
+        peer,
+        contact: contact.map(|(name, _)| name.clone()),
+        fingerprint,
+        strict: settings.strict,
+    })
+}
+
+fn transport_of(url: &url::Url) -> String {
+    match url.scheme() {
+        "https" => "webtransport".to_string(),
+        scheme => scheme.to_string(),
+    }
+}
+
+// fingerprint: 相手の証明書と、それを照合した結果
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerCertificate {
+    pub uri: String,
+    pub peer: String,
+    pub fingerprint: String,
+    /// URLの末尾のフィンガープリントと一致したか (指定がなければnull)
+    pub pinned: Option<bool>,
+    /// known_peersとの照合: trusted・unknown・changed
+    pub trust: &'static str,
+    /// changed の場合に known_peers に記録されているフィンガープリント
+    pub expected: Option<String>,
+    pub tls: Negotiated,
+}
+
+// TLSのハンドシェイク (https:// ではWebTransportのセッション) だけを行い、相手の証明書を照合した結果を返す
+// 一致しなくても失敗にはしない (照合した結果を返す)
+pub async fn fingerprint(settings: &ClientSettings) -> Result<PeerCertificate> {
+    let url = url::Url::parse(&settings.uri)?;
+    let (_, negotiated, peer_cert) = transport::connect_secure(settings, &url).await?;
+    certificate(settings, &url, &peer_cert, negotiated)
+}
+
+fn certificate(settings: &ClientSettings, url: &url::Url, peer_cert: &[u8], tls: Negotiated) -> Result<PeerCertificate> {
+    let fingerprint = identity::fingerprint(peer_cert);
+    let peer = trust::host_key(&settings.uri)?;
+    let (trust, expected) = match KnownPeers::load(settings.paths.known_peers_file())?.check(&peer, &fingerprint) {
+        TrustStatus::Trusted => ("trusted", None),
+        TrustStatus::Unknown => ("unknown", None),
+        TrustStatus::Changed { expected } => ("changed", Some(expected)),
+    };
+    let mut uri = url.clone();
+    uri.set_fragment(None);
+    Ok(PeerCertificate {
+        uri: uri.to_string(),
+        pinned: url.fragment().filter(|pinned| !pinned.is_empty()).map(|pinned| pinned == fingerprint),
+        peer,
+        fingerprint,
+        trust,
+        expected,
+        tls,
+    })
+}
+
+// probe: ハンドシェイクの結果と、相手が対応していること
+#[derive(Debug, Clone, Serialize)]
+pub struct Probe {
+    #[serde(flatten)]
+    pub certificate: PeerCertificate,
+    /// WebSocketのハンドシェイクの応答
+    pub websocket: WebSocketInfo,
+    /// 相手が接続直後に送ってきた、メッセージの署名に使う証明書のフィンガープリント
+    pub identity: Option<String>,
+    /// signatures (署名付きのメッセージを検証できる)・webtransport (https:// で接続した)
+    pub capabilities: Vec<&'static str>,
+    /// 接続を始めてからWebSocketのハンドシェイクを終えるまで (ミリ秒)
+    pub handshake_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WebSocketInfo {
+    pub protocol: Option<String>,
+    pub extensions: Option<String>,
+    pub server: Option<String>,
+}
+
+// TLSとWebSocketのハンドシェイクまで行い、相手が最初に送るものを少し待ってから閉じる
+// 再開の要求 (Resume) は送らないため、相手の履歴やセッションには何も残らない
+pub async fn probe(settings: &ClientSettings) -> Result<Probe> {
+    let url = url::Url::parse(&settings.uri)?;
+    let started = Instant::now();
+    let (stream, negotiated, peer_cert) = transport::connect_secure(settings, &url).await?;
+    let certificate = certificate(settings, &url, &peer_cert, negotiated)?;
+    let (mut ws_stream, response) = transport::websocket_handshake(settings, &url, stream).await?;
+    let handshake_ms = started.elapsed().as_millis() as u64;
+    let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let websocket = WebSocketInfo {
+        protocol: header("sec-websocket-protocol"),
+        extensions: header("sec-websocket-extensions"),
+        server: header("server"),
+    };
+
+    let mut identity = None;
+    let _ = settings
+        .options
+        .runtime
+        .timeout(PROBE_WAIT, async {
+            while let Some(Ok(message)) = ws_stream.next().await {
+                if let Message::Text(text) = message {
+                    if let Envelope::Identity { cert } = Envelope::decode(&text) {
+                        identity = BASE64.decode(cert).ok().map(|cert| identity::fingerprint(&cert));
+                        return;
+                    }
+                }
+            }
+        })
+        .await;
+    let _ = ws_stream.close(None).await;
+
+    let mut capabilities = Vec::new();
+    if identity.is_some() {
+        capabilities.push("signatures");
+    }
+    if url.scheme() == "https" {
+        capabilities.push("webtransport");
+    }
+    Ok(Probe { certificate, websocket, identity, capabilities, handshake_ms })
+}
+
+// 招待: 接続先のURIと、そこで待ち受ける相手の証明書のフィンガープリント。QRコード・メール・チャットで渡す1行の文字列にする
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invite {
+    /// フィンガープリントの部分を除いたURI
+    pub uri: String,
+    pub fingerprint: String,
+    /// 招待した人の表示名 (受け取った側の連絡先の名前の候補)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Invite {
+    // 自分の証明書で、uri で待ち受けていることを知らせる招待を作る
+    pub fn new(paths: &Paths, uri: &str, name: Option<String>) -> Result<Self> {
+        let mut url = url::Url::parse(uri)?;
+        if !matches!(url.scheme(), "wss" | "https") || url.host_str().is_none_or(str::is_empty) {
+            return Err(format!("wss://ホスト:ポート の形式で指定してください ({})", uri).into());
+        }
+        url.set_fragment(None);
+        let identity = crate::builder::IdentitySource::Profile.load(paths)?;
+        Ok(Self { uri: url.to_string(), fingerprint: identity.fingerprint(), name })
+    }
+
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("招待のシリアライズに失敗しました");
+        format!("{}{}", INVITE_PREFIX, URL_SAFE_NO_PAD.encode(json))
+    }
+
+    pub fn decode(text: &str) -> Result<Self> {
+        let encoded = text.trim().strip_prefix(INVITE_PREFIX).ok_or("招待の文字列ではありません (p2pchat-invite1: で始まります)")?;
+        let invite: Self = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(encoded)?)?;
+        if !invite.fingerprint.starts_with("sha256:") {
+            return Err("招待のフィンガープリントの形式が正しくありません".into());
+        }
+        Ok(invite)
+    }
+
+    // 接続に使うURI (フィンガープリントを末尾に付けたもの)
+    pub fn connect_uri(&self) -> String {
+        format!("{}#{}", self.uri, self.fingerprint)
+    }
+}
@@ -0,0 +1,164 @@
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Join, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStdin, ChildStdout};
+
+use crate::runtime::BoxStream;
+
+// 相手へのTCP接続を中継するプロキシ (socks5://host:port、http://host:port または ssh://user@host)
+// TLSとWebSocketはプロキシの先の相手と直接やり取りするため、プロキシからは中身が見えない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proxy {
+    /// SOCKS5 (認証なし)。ホスト名はプロキシ側で解決する
+    Socks5(String),
+    /// HTTPのCONNECTメソッド
+    Http(String),
+    /// SSHでログインできるマシン (user@host または ssh://user@host:port)。そのマシンから相手に接続する (ssh -W)
+    Ssh(String),
+}
+
+impl std::str::FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let url = url::Url::parse(text).map_err(|e| format!("プロキシのURLを解釈できません: {} ({})", text, e))?;
+        if url.scheme() == "ssh" {
+            return Ok(Proxy::Ssh(text.to_string()));
+        }
+        let host = url.host_str().ok_or_else(|| format!("プロキシのURLにホスト名がありません: {}", text))?;
+        let port = url.port_or_known_default().unwrap_or(1080);
+        let addr = format!("{}:{}", host, port);
+        match url.scheme() {
+            "socks5" | "socks5h" => Ok(Proxy::Socks5(addr)),
+            "http" => Ok(Proxy::Http(addr)),
+            scheme => Err(format!("対応していないプロキシの種類です: {} (socks5、http または ssh)", scheme)),
+        }
+    }
+}
+
+impl Proxy {
+    // プロキシを経由してhost:portに接続する
+    pub async fn connect(&self, host: &str, port: u16) -> Result<BoxStream, Box<dyn std::error::Error>> {
+        match self {
+            Proxy::Socks5(proxy) => Ok(Box::new(socks5(proxy, host, port).await?)),
+            Proxy::Http(proxy) => Ok(Box::new(http_connect(proxy, host, port).await?)),
+            Proxy::Ssh(destination) => ssh(destination, host, port),
+        }
+    }
+}
+
+// RFC 1928。認証なしでCONNECTを要求する
+async fn socks5(proxy: &str, host: &str, port: u16) -> Result<TcpStream, Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, 0] {
+        return Err("SOCKS5プロキシが認証なしの接続を受け付けませんでした".into());
+    }
+
+    let host_len = u8::try_from(host.len()).map_err(|_| "ホスト名が長すぎます")?;
+    let mut request = vec![5, 1, 0, 3, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0 {
+        return Err(format!("SOCKS5プロキシが接続を拒否しました (応答コード: {})", header[1]).into());
+    }
+    // 応答に含まれるプロキシ側のアドレスは使わないので読み捨てる
+    let addr_len = match header[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        kind => return Err(format!("SOCKS5プロキシの応答を解釈できません (アドレスの種類: {})", kind).into()),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+async fn http_connect(proxy: &str, host: &str, port: u16) -> Result<TcpStream, Box<dyn std::error::Error>> {
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    let request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n");
+    stream.get_mut().write_all(request.as_bytes()).await?;
+
+    let mut status = String::new();
+    stream.read_line(&mut status).await?;
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+    if code != "200" {
+        return Err(format!("HTTPプロキシが接続を拒否しました: {}", status.trim()).into());
+    }
+    // 空行までのヘッダーを読み捨てる
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    // CONNECTの応答の後はプロキシが何も送らないので、読み込み済みのデータは残っていない
+    Ok(stream.into_inner())
+}
+
+// sshコマンドを起動し、SSHサーバーからhost:portへ転送させた標準入出力を通信路にする
+// 鍵・known_hosts・ProxyJumpなどは ~/.ssh/config に従う。チャットの画面と重ならないよう、パスワードは尋ねない (鍵かssh-agentで認証する)
+fn ssh(destination: &str, host: &str, port: u16) -> Result<BoxStream, Box<dyn std::error::Error>> {
+    let target = match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    let mut child = tokio::process::Command::new("ssh")
+        .args(["-W", &target, "-o", "BatchMode=yes", "--", destination])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("sshを起動できません: {}", e))?;
+    let (Some(stdin), Some(stdout), Some(stderr)) = (child.stdin.take(), child.stdout.take(), child.stderr.take()) else {
+        return Err("sshの標準入出力を開けません".into());
+    };
+    // 接続できなかった理由などはsshが標準エラーに書くので、ログに残す
+    let destination = destination.to_string();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            tracing::warn!(ssh = %destination, "{}", line);
+        }
+    });
+    Ok(Box::new(SshStream { io: tokio::io::join(stdout, stdin), _child: child }))
+}
+
+// sshの標準出力から読み、標準入力に書く。落とすとsshを終了させる
+struct SshStream {
+    io: Join<ChildStdout, ChildStdin>,
+    _child: Child,
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
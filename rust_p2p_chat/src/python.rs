@@ -0,0 +1,255 @@
+use crate::event::{DisconnectReason, SessionCommand};
+use crate::history::Direction;
+use crate::paths::{Paths, DEFAULT_PROFILE};
+use crate::{ChatOptions, Peer};
+use pyo3::exceptions::{PyConnectionError, PyRuntimeError, PyStopAsyncIteration, PyValueError};
+use pyo3::prelude::*;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// Pythonのモジュール p2pchat (maturinでビルドする。型は p2pchat.pyi)
+//
+//     async with p2pchat.connect("alice") as session:
+//         session.send("こんにちは")
+//         async for event in session:
+//             print(event.kind, event.text)
+//
+// セッションはpyo3-async-runtimesのtokioランタイムで動かし、出来事はasyncioから待つ
+
+// 接続先
+#[derive(Clone)]
+enum Target {
+    Connect(String),
+    Listen(SocketAddr),
+}
+
+// 接続中のセッションの出来事の受け口とコマンドの送り口
+#[derive(Default)]
+struct Channels {
+    peer: Option<String>,
+    commands: Option<mpsc::UnboundedSender<SessionCommand>>,
+}
+
+/// 1つの接続。`async with` で接続し、抜けるときに切断する。`async for` で出来事を受け取る
+#[pyclass(module = "p2pchat")]
+struct Session {
+    target: Target,
+    data_dir: Option<PathBuf>,
+    channels: Arc<Mutex<Channels>>,
+    events: Arc<tokio::sync::Mutex<Option<mpsc::UnboundedReceiver<crate::Event>>>>,
+}
+
+#[pymethods]
+impl Session {
+    // 接続する (listenの場合は最初の相手を受け付けるまで待つ)
+    fn __aenter__<'py>(slf: Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        let py = slf.py();
+        let (target, data_dir, channels, events) = {
+            let this = slf.borrow();
+            (this.target.clone(), this.data_dir.clone(), this.channels.clone(), this.events.clone())
+        };
+        let session: Py<Self> = slf.unbind();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let peer = peer(data_dir).map_err(runtime_error)?;
+            let opened = match target {
+                Target::Connect(target) => peer.connect(&target).await,
+                Target::Listen(addr) => peer.listen(addr).await,
+            }
+            .map_err(|e| PyConnectionError::new_err(e.to_string()))?;
+            let name = opened.peer().to_string();
+            let (receiver, commands) = opened.split();
+            *lock(&channels) = Channels { peer: Some(name), commands: Some(commands) };
+            *events.lock().await = Some(receiver);
+            Ok(session)
+        })
+    }
+
+    // 相手に切断を知らせ、セッションが終わるまで待つ (例外は抑えない)
+    #[pyo3(signature = (*_args))]
+    fn __aexit__<'py>(&self, py: Python<'py>, _args: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        if let Some(commands) = lock(&self.channels).commands.take() {
+            let _ = commands.send(SessionCommand::Close);
+        }
+        let events = self.events.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            if let Some(mut receiver) = events.lock().await.take() {
+                while receiver.recv().await.is_some() {}
+            }
+            Ok(false)
+        })
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    // 次の出来事。接続が終わると (Disconnected の後に) StopAsyncIteration
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let events = self.events.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let mut events = events.lock().await;
+            match events.as_mut() {
+                Some(receiver) => match receiver.recv().await {
+                    Some(event) => Ok(Event::from(event)),
+                    None => Err(PyStopAsyncIteration::new_err(())),
+                },
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+
+    /// 履歴上で相手を識別する名前 (接続する前はNone)
+    #[getter]
+    fn peer(&self) -> Option<String> {
+        lock(&self.channels).peer.clone()
+    }
+
+    /// メッセージを送る。送ったことは "sent" の出来事で分かる
+    fn send(&self, text: String) -> PyResult<()> {
+        self.command(SessionCommand::SendText(text))
+    }
+
+    /// ファイルを送る。進み具合は "progress" の出来事で届く
+    fn send_file(&self, path: PathBuf) -> PyResult<()> {
+        self.command(SessionCommand::SendFile(path))
+    }
+
+    /// 相手に切断を知らせる (残りの出来事は引き続き受け取れる)
+    fn close(&self) -> PyResult<()> {
+        self.command(SessionCommand::Close)
+    }
+}
+
+impl Session {
+    fn new(target: Target, data_dir: Option<PathBuf>) -> Self {
+        Self { target, data_dir, channels: Arc::default(), events: Arc::default() }
+    }
+
+    fn command(&self, command: SessionCommand) -> PyResult<()> {
+        let channels = lock(&self.channels);
+        let Some(commands) = &channels.commands else {
+            let reason = if channels.peer.is_some() { "セッションは終了しています" } else { "接続していません" };
+            return Err(PyConnectionError::new_err(reason));
+        };
+        commands.send(command).map_err(|_| PyConnectionError::new_err("セッションは終了しています"))
+    }
+}
+
+/// セッションの出来事。kindによって使う属性が変わり、使わない属性はNone
+///   connected (peer) / message (peer・text・seq) / sent (text・seq) / delivered (seq)
+///   progress (transfer_id・text にファイル名・incoming・bytes・total) / info (text) / warning (text)
+///   disconnected (peer・text に理由)
+#[pyclass(module = "p2pchat", frozen, get_all)]
+struct Event {
+    kind: &'static str,
+    peer: Option<String>,
+    text: Option<String>,
+    seq: Option<u64>,
+    transfer_id: Option<u64>,
+    incoming: Option<bool>,
+    bytes: Option<u64>,
+    total: Option<u64>,
+}
+
+#[pymethods]
+impl Event {
+    fn __repr__(&self) -> String {
+        let mut fields = vec![format!("kind={:?}", self.kind)];
+        if let Some(peer) = &self.peer {
+            fields.push(format!("peer={:?}", peer));
+        }
+        if let Some(text) = &self.text {
+            fields.push(format!("text={:?}", text));
+        }
+        if let Some(seq) = self.seq {
+            fields.push(format!("seq={}", seq));
+        }
+        if let (Some(id), Some(bytes)) = (self.transfer_id, self.bytes) {
+            fields.push(format!("transfer_id={} bytes={}", id, bytes));
+        }
+        format!("Event({})", fields.join(", "))
+    }
+}
+
+impl Event {
+    fn new(kind: &'static str) -> Self {
+        Self { kind, peer: None, text: None, seq: None, transfer_id: None, incoming: None, bytes: None, total: None }
+    }
+}
+
+impl From<crate::Event> for Event {
+    fn from(event: crate::Event) -> Self {
+        use crate::Event as E;
+        match event {
+            E::PeerConnected { peer } => Self { peer: Some(peer), ..Self::new("connected") },
+            E::MessageReceived { from, seq, body, .. } => Self { peer: Some(from), text: Some(body), seq: Some(seq), ..Self::new("message") },
+            E::MessageSent { seq, body } => Self { text: Some(body), seq: Some(seq), ..Self::new("sent") },
+            E::DeliveryAck { seq } => Self { seq: Some(seq), ..Self::new("delivered") },
+            E::TransferProgress { id, name, direction, bytes, total } => Self {
+                transfer_id: Some(id),
+                text: Some(name),
+                incoming: Some(direction == Direction::Incoming),
+                bytes: Some(bytes),
+                total,
+                ..Self::new("progress")
+            },
+            E::Info { text } => Self { text: Some(text), ..Self::new("info") },
+            E::Warning { text } => Self { text: Some(text), ..Self::new("warning") },
+            E::Disconnected { peer, reason } => {
+                let reason = match reason {
+                    DisconnectReason::PeerClosed { reason, .. } => reason,
+                    DisconnectReason::Error(error) => error,
+                    other => format!("{:?}", other),
+                };
+                Self { peer: Some(peer), text: Some(reason), ..Self::new("disconnected") }
+            }
+            // 画面の表示に使う出来事は名前だけ渡す
+            E::Earlier { .. } => Self::new("earlier"),
+            E::PeerRenamed { peer } => Self { peer: Some(peer), ..Self::new("renamed") },
+            E::Resumed => Self::new("resumed"),
+            E::Verification(_) => Self::new("verification"),
+            E::Latency { .. } => Self::new("latency"),
+        }
+    }
+}
+
+/// 相手 (URIまたは連絡先の名前) に接続するセッション。`async with` で接続する
+#[pyfunction]
+#[pyo3(signature = (target, data_dir=None))]
+fn connect(target: String, data_dir: Option<PathBuf>) -> Session {
+    Session::new(Target::Connect(target), data_dir)
+}
+
+/// addr (例: "127.0.0.1:8080") で待ち受けるセッション。`async with` で最初の相手を受け付ける
+#[pyfunction]
+#[pyo3(signature = (addr, data_dir=None))]
+fn listen(addr: &str, data_dir: Option<PathBuf>) -> PyResult<Session> {
+    let addr = addr.parse().map_err(|e| PyValueError::new_err(format!("待受アドレスを解釈できません: {} ({})", addr, e)))?;
+    Ok(Session::new(Target::Listen(addr), data_dir))
+}
+
+#[pymodule]
+#[pyo3(name = "p2pchat")]
+fn p2pchat_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Session>()?;
+    m.add_class::<Event>()?;
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_function(wrap_pyfunction!(listen, m)?)?;
+    Ok(())
+}
+
+fn peer(data_dir: Option<PathBuf>) -> Result<Peer, Box<dyn std::error::Error>> {
+    let paths = Paths::resolve(data_dir.as_deref(), DEFAULT_PROFILE, true)?;
+    let options = ChatOptions::embedded(&paths)?;
+    Ok(Peer::new(paths, options))
+}
+
+fn runtime_error(error: Box<dyn std::error::Error>) -> PyErr {
+    PyRuntimeError::new_err(error.to_string())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
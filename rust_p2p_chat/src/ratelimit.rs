@@ -0,0 +1,101 @@
+use crate::config::RateLimitConfig;
+use std::time::{Duration, Instant};
+
+// 相手から受け取るメッセージの数とバイト数の制限 (接続ごと)
+// 上限を超えたら相手からの受信を読むのを遅らせ (相手の送信はTCPで詰まる)、超え続けたら切断する
+// ファイルのデータは送信側がFileAckで送る量を抑えているため、遅らせるだけで切断の判定には数えない
+
+// 短い間ならこの秒数分までまとめて受け取れる (貼り付けた複数行や再送などで制限にかからないように)
+const BURST_SECS: f64 = 2.0;
+
+// 受け取ったものに対する扱い
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// 上限の内
+    Allow,
+    /// delayだけ次の受信を読まずに待つ。firstは上限を超え始めたとき (案内を出すのは1回だけにする)
+    Throttle { delay: Duration, first: bool },
+    /// disconnect_after_secsの間、上限を超え続けた
+    Disconnect,
+}
+
+// 1秒あたりrateまで受け取れる量 (使った分は時間とともに戻る)
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Option<Self> {
+        (rate > 0).then_some(Self { rate: rate as f64, tokens: rate as f64 * BURST_SECS })
+    }
+
+    // elapsedの間に戻った分を足してからcostを使い、足りない分が戻るまでの時間を返す
+    fn take(&mut self, elapsed: Duration, cost: u64) -> Duration {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate * BURST_SECS) - cost as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+    /// ファイルのデータを除いたバイト数 (切断するかの判定に使う)
+    other_bytes: Option<Bucket>,
+    disconnect_after: Option<Duration>,
+    last: Option<Instant>,
+    /// 上限を超え始めた時刻 (上限の内に戻ると消える)
+    exceeded_since: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            messages: Bucket::new(config.messages_per_sec),
+            bytes: Bucket::new(config.bytes_per_sec),
+            other_bytes: Bucket::new(config.bytes_per_sec),
+            disconnect_after: (config.disconnect_after_secs > 0).then(|| Duration::from_secs(config.disconnect_after_secs)),
+            last: None,
+            exceeded_since: None,
+        }
+    }
+
+    // 設定が変わっていれば新しい上限で数え直す (上限を超え始めた時刻は引き継ぐ)
+    pub fn reconfigure(&mut self, config: &RateLimitConfig) {
+        if *config != self.config {
+            *self = Self { last: self.last, exceeded_since: self.exceeded_since, ..Self::new(config) };
+        }
+    }
+
+    // nowに受け取ったmessages件・bytesバイトを数え、扱いを決める。fileはファイルのデータのフレーム
+    pub fn check(&mut self, now: Instant, messages: u64, bytes: u64, file: bool) -> Verdict {
+        let elapsed = self.last.map(|last| now.saturating_duration_since(last)).unwrap_or_default();
+        self.last = Some(now);
+        let take = |bucket: &mut Option<Bucket>, cost| bucket.as_mut().map(|bucket| bucket.take(elapsed, cost)).unwrap_or_default();
+        let message_delay = take(&mut self.messages, messages);
+        let bytes_delay = take(&mut self.bytes, bytes);
+        let other_bytes_delay = take(&mut self.other_bytes, if file { 0 } else { bytes });
+        let delay = message_delay.max(bytes_delay);
+        if delay.is_zero() {
+            self.exceeded_since = None;
+            return Verdict::Allow;
+        }
+        // ファイルのデータの分だけで上限を超えている間は遅らせるだけにする
+        if message_delay.is_zero() && other_bytes_delay.is_zero() {
+            self.exceeded_since = None;
+            return Verdict::Throttle { delay, first: false };
+        }
+        let first = self.exceeded_since.is_none();
+        let since = *self.exceeded_since.get_or_insert(now);
+        if self.disconnect_after.is_some_and(|after| now.saturating_duration_since(since) >= after) {
+            return Verdict::Disconnect;
+        }
+        Verdict::Throttle { delay, first }
+    }
+}
@@ -0,0 +1,210 @@
+use futures_util::{SinkExt, StreamExt};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
+
+// 短い接続コード (例: tidy-walrus-42) を、リスナーのアドレスと証明書のフィンガープリントに対応付けるランデブーサーバー
+// リスナーは登録した接続を開いたままにし、閉じるか有効期限が切れるとコードは消える
+// コードは一度解決すると使えなくなる
+
+// 存在しないコードを問い合わせたときに応答を遅らせる時間 (総当たりで探されにくくする)
+const RESOLVE_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+// コードの1語目と2語目 (64語ずつ)
+const ADJECTIVES: [&str; 64] = [
+    "amber", "bold", "brave", "brisk", "calm", "clever", "cosy", "crisp", "curly", "dapper", "eager", "early", "fancy", "fluffy",
+    "gentle", "giddy", "glad", "golden", "grand", "happy", "hasty", "humble", "jolly", "keen", "kind", "lively", "lucky", "mellow",
+    "merry", "mighty", "misty", "modest", "noble", "quick", "quiet", "rapid", "rosy", "rusty", "shiny", "silent", "silver", "sleepy",
+    "smooth", "snowy", "sturdy", "sunny", "swift", "tidy", "tiny", "proud", "vivid", "warm", "wavy", "wild", "wise", "witty",
+    "young", "zesty", "bright", "cheery", "dusty", "frosty", "lucid", "plucky",
+];
+const ANIMALS: [&str; 64] = [
+    "alpaca", "badger", "beaver", "bison", "camel", "cheetah", "cobra", "coyote", "crane", "dingo", "dolphin", "donkey", "eagle",
+    "falcon", "ferret", "finch", "gecko", "gibbon", "gopher", "heron", "hippo", "husky", "ibis", "iguana", "jackal", "koala",
+    "lemur", "leopard", "llama", "lobster", "lynx", "magpie", "marmot", "meerkat", "mole", "moose", "newt", "ocelot", "otter",
+    "owl", "panda", "parrot", "pelican", "penguin", "puffin", "python", "quail", "rabbit", "raccoon", "raven", "salmon", "seal",
+    "shark", "sloth", "squid", "stork", "tapir", "tiger", "toucan", "turtle", "walrus", "weasel", "wombat", "zebra",
+];
+
+// ランデブーサーバーへの要求。WebSocketのテキストフレーム1つに1つのJSONで送る
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// リスナーのアドレスを登録してコードを受け取る (hostを省略するとサーバーから見えた送信元のアドレスを使う)
+    Register { host: Option<String>, port: u16, fingerprint: String },
+    /// コードを接続用のURL (wss://host:port#sha256:...) に変換する
+    Resolve { code: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Registered { code: String, expires_in: u64 },
+    Resolved { uri: String },
+    Error { message: String },
+}
+
+// 登録されたコード
+struct Entry {
+    uri: String,
+    expires: Instant,
+}
+
+type Codes = Arc<Mutex<HashMap<String, Entry>>>;
+
+// ランデブーサーバーとして待ち受ける (TLSは前段のリバースプロキシで終端する想定で、平文のWebSocketを使う)
+pub async fn serve(addr: SocketAddr, ttl: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    let codes: Codes = Arc::default();
+    println!("ランデブーサーバーを起動しました: {} (コードの有効期限: {}秒)", listener.local_addr()?, ttl.as_secs());
+    tracing::info!(addr = %listener.local_addr()?, "ランデブーサーバーを起動しました");
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let codes = codes.clone();
+        let span = tracing::info_span!("rendezvous", peer = %peer_addr);
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle(stream, peer_addr, codes, ttl).await {
+                    tracing::warn!(error = %e, "ランデブーの要求の処理に失敗しました");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn handle(stream: TcpStream, peer_addr: SocketAddr, codes: Codes, ttl: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    let request = match read_json::<Request, _>(&mut ws).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    match request {
+        Request::Register { host, port, fingerprint } => {
+            let host = host.unwrap_or_else(|| peer_addr.ip().to_string());
+            let uri = format!("wss://{}:{}#{}", host, port, fingerprint);
+            let code = {
+                let mut codes = lock(&codes);
+                codes.retain(|_, entry| entry.expires > Instant::now());
+                let code = loop {
+                    let code = generate_code()?;
+                    if !codes.contains_key(&code) {
+                        break code;
+                    }
+                };
+                codes.insert(code.clone(), Entry { uri, expires: Instant::now() + ttl });
+                code
+            };
+            tracing::info!(%code, "接続コードを登録しました");
+            send_json(&mut ws, &Response::Registered { code: code.clone(), expires_in: ttl.as_secs() }).await?;
+
+            // リスナーが接続を閉じるか有効期限が切れるまで待ち、コードを消す
+            let _ = tokio::time::timeout(ttl, async { while let Some(Ok(_)) = ws.next().await {} }).await;
+            lock(&codes).remove(&code);
+            tracing::info!(%code, "接続コードを削除しました");
+        }
+        Request::Resolve { code } => {
+            let code = normalize(&code);
+            let entry = lock(&codes).remove(&code).filter(|entry| entry.expires > Instant::now());
+            let response = match entry {
+                Some(entry) => {
+                    tracing::info!(%code, "接続コードを解決しました");
+                    Response::Resolved { uri: entry.uri }
+                }
+                None => {
+                    tracing::warn!(%code, "存在しない接続コードが問い合わせられました");
+                    tokio::time::sleep(RESOLVE_FAILURE_DELAY).await;
+                    Response::Error { message: format!("接続コードが見つからないか、有効期限が切れています: {}", code) }
+                }
+            };
+            send_json(&mut ws, &response).await?;
+        }
+    }
+    let _ = ws.close(None).await;
+    Ok(())
+}
+
+// リスナーのアドレスを登録し、コードと有効期限 (秒) を返す
+// コードを有効にしておくため、サーバーとの接続はバックグラウンドで開いたままにする
+pub async fn register(server: &str, host: Option<String>, port: u16, fingerprint: String) -> Result<(String, u64), Box<dyn std::error::Error>> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(server).await?;
+    send_json(&mut ws, &Request::Register { host, port, fingerprint }).await?;
+    let registered = match read_json::<Response, _>(&mut ws).await? {
+        Some(Response::Registered { code, expires_in }) => (code, expires_in),
+        Some(Response::Error { message }) => return Err(message.into()),
+        _ => return Err("ランデブーサーバーから予期しない応答がありました".into()),
+    };
+    tokio::spawn(async move {
+        while let Some(Ok(_)) = ws.next().await {}
+        tracing::debug!("ランデブーサーバーとの接続が閉じられました");
+    });
+    Ok(registered)
+}
+
+// コードを接続用のURL (フィンガープリント付き) に変換する
+pub async fn resolve(server: &str, code: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(server).await?;
+    send_json(&mut ws, &Request::Resolve { code: code.to_string() }).await?;
+    let resolved = read_json::<Response, _>(&mut ws).await?;
+    let _ = ws.close(None).await;
+    match resolved {
+        Some(Response::Resolved { uri }) => Ok(uri),
+        Some(Response::Error { message }) => Err(message.into()),
+        _ => Err("ランデブーサーバーから予期しない応答がありました".into()),
+    }
+}
+
+// 例: tidy-walrus-42
+fn generate_code() -> Result<String, Box<dyn std::error::Error>> {
+    let mut bytes = [0u8; 3];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| "乱数の生成に失敗しました")?;
+    Ok(format!(
+        "{}-{}-{}",
+        ADJECTIVES[bytes[0] as usize % ADJECTIVES.len()],
+        ANIMALS[bytes[1] as usize % ANIMALS.len()],
+        bytes[2] % 100
+    ))
+}
+
+// 大文字や空白で区切って入力されても同じコードとして扱う
+fn normalize(code: &str) -> String {
+    code.split(|c: char| c.is_whitespace() || c == '-').filter(|word| !word.is_empty()).collect::<Vec<_>>().join("-").to_lowercase()
+}
+
+async fn send_json<S, T>(ws: &mut WebSocketStream<S>, value: &T) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    T: Serialize,
+{
+    ws.send(Message::Text(serde_json::to_string(value)?)).await?;
+    Ok(())
+}
+
+// 最初のテキストフレームを読む。接続が閉じられた場合はNone
+async fn read_json<T, S>(ws: &mut WebSocketStream<S>) -> Result<Option<T>, Box<dyn std::error::Error>>
+where
+    T: serde::de::DeserializeOwned,
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    while let Some(message) = ws.next().await {
+        match message? {
+            Message::Text(text) => return Ok(Some(serde_json::from_str(&text)?)),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+fn lock(codes: &Mutex<HashMap<String, Entry>>) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+    codes.lock().unwrap_or_else(|e| e.into_inner())
+}
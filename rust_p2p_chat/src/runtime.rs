@@ -0,0 +1,428 @@
+use futures_util::future::{self, BoxFuture, Either};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+// セッションが使う実行環境 (タスクの起動・時間待ち・TCP接続)。既定はtokioで、組み込み先は自分のランタイムや通信路に差し替えられる
+// 時間を自分で進める実行環境を渡せば、決まった順序で動かすシミュレーションのテストにも使える
+
+// バイト列をやり取りする通信路 (TCP・プロキシ経由・メモリ上のパイプなど)。この上でTLSとWebSocketを動かす
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxStream = Box<dyn Stream>;
+
+// タスクを起動し、時間を待つ
+pub trait Executor: Send + Sync {
+    /// 終了を待たずに動かす
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+    /// 今の時刻。時間を自分で進める実行環境は、その時計での時刻を返す
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// 相手への接続と待受
+pub trait Transport: Send + Sync {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<BoxStream>>;
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>>;
+}
+
+pub trait Listener: Send {
+    /// 次の接続を受け付け、相手のアドレスと合わせて返す
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+// セッションに渡す実行環境 (ChatOptions::runtime)
+#[derive(Clone)]
+pub struct Runtime {
+    executor: Arc<dyn Executor>,
+    transport: Arc<dyn Transport>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::tokio()
+    }
+}
+
+impl std::fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Runtime")
+    }
+}
+
+impl Runtime {
+    // tokioのタスクとタイマー、TCP
+    pub fn tokio() -> Self {
+        Self::new(TokioExecutor, TcpTransport)
+    }
+
+    pub fn new(executor: impl Executor + 'static, transport: impl Transport + 'static) -> Self {
+        Self { executor: Arc::new(executor), transport: Arc::new(transport) }
+    }
+
+    // 実行環境はそのままで、通信路だけを差し替える
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    // タスクを起動する。返り値で終了を待てる (捨てても止まらない)
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) -> Task {
+        let (done_tx, done) = oneshot::channel();
+        self.executor.spawn(Box::pin(async move {
+            task.await;
+            let _ = done_tx.send(());
+        }));
+        Task { done }
+    }
+
+    pub fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.executor.sleep(duration)
+    }
+
+    pub fn now(&self) -> Instant {
+        self.executor.now()
+    }
+
+    // durationまでにfutureが終わらなければ、futureを捨ててErr(Elapsed)を返す
+    pub async fn timeout<F: Future>(&self, duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+        match future::select(std::pin::pin!(future), self.sleep(duration)).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(((), _)) => Err(Elapsed),
+        }
+    }
+
+    // 最初のtickはすぐに、以降はperiodごとに終わる
+    pub fn interval(&self, period: Duration) -> Interval {
+        Interval { runtime: self.clone(), period, next: None }
+    }
+
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<BoxStream> {
+        self.transport.connect(host, port).await
+    }
+
+    pub async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        self.transport.bind(addr).await
+    }
+}
+
+// 起動したタスクの終了を待つ
+pub struct Task {
+    done: oneshot::Receiver<()>,
+}
+
+impl Task {
+    // タスクがpanicなどで途中で捨てられた場合も戻る
+    pub async fn join(self) {
+        let _ = self.done.await;
+    }
+}
+
+// 一定の間隔で繰り返す待ち。select!の中で途中で捨てても、次のtickまでの残り時間は引き継ぐ
+pub struct Interval {
+    runtime: Runtime,
+    period: Duration,
+    next: Option<BoxFuture<'static, ()>>,
+}
+
+impl Interval {
+    pub async fn tick(&mut self) {
+        if let Some(sleep) = self.next.as_mut() {
+            sleep.await;
+        }
+        self.next = Some(self.runtime.sleep(self.period));
+    }
+}
+
+// Transport::connect で接続先の名前を解決できなかった (io::Errorに包んで返す。終了コードの判別に使う)
+#[derive(Debug)]
+pub struct ResolveError(pub String);
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl ResolveError {
+    pub fn into_io(self) -> io::Error {
+        io::Error::other(self)
+    }
+}
+
+// Runtime::timeout で時間切れになった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("時間切れになりました")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+struct TokioExecutor;
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::spawn(task);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    // tokioの時計 (テストで止めて進めた時間も反映する)
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let resolved = tokio::net::lookup_host((host, port)).instrument(tracing::info_span!("dns", host)).await;
+            let addrs: Vec<SocketAddr> = resolved.map_err(|e| ResolveError(format!("{} の名前を解決できません: {}", host, e)).into_io())?.collect();
+            if addrs.is_empty() {
+                return Err(ResolveError(format!("{} のアドレスが見つかりません", host)).into_io());
+            }
+            Ok(Box::new(TcpStream::connect(&addrs[..]).await?) as BoxStream)
+        })
+    }
+
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move { Ok(Box::new(TcpListener::bind(addr).await?) as Box<dyn Listener>) })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as BoxStream, addr))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+// MemoryTransportの1つの接続が溜めておけるバイト数
+const MEMORY_BUFFER: usize = 64 * 1024;
+// MemoryTransportで接続元やポート0の待受に割り当てるポートの始まり
+const MEMORY_FIRST_PORT: u16 = 40000;
+
+type Incoming = mpsc::UnboundedSender<(BoxStream, SocketAddr)>;
+
+// MemoryTransportの通信路の状態 (シミュレーション用)。これから張る接続に効く
+// 通信路はTCPと同じく順序と内容を保つので、パケットの損失や入れ替わりは遅れと切断 (cut_links)、不通 (stall_links) で表す
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkConditions {
+    /// 書き込んだデータが相手に届くまでの遅れ (片方向)
+    pub latency: Duration,
+    /// これからの接続のうち、拒否する回数 (相手がまだ起動していない・経路が不通の場合)
+    pub refuse_connects: u32,
+    /// これからの接続のうち、応答のないまま待たせる回数 (経路の途中でパケットが捨てられる場合)
+    pub drop_connects: u32,
+}
+
+// メモリ上の通信路 (tokio::io::duplex)。ソケットを使わずに、1つのプロセスの中で待ち受けと接続をつなぐ (テスト用)
+// 複製したものは同じ待受を共有するので、両方のセッションのRuntimeに同じものを渡す
+// tokioの時間を止めたテスト (#[tokio::test(start_paused = true)]) では、遅れも含めて毎回同じ順序で動く
+#[derive(Clone)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Incoming>>>,
+    next_port: Arc<AtomicU16>,
+    conditions: Arc<Mutex<LinkConditions>>,
+    /// 張った接続をまとめて切るためのトークン (切ると新しいものに替える)
+    cut: Arc<Mutex<CancellationToken>>,
+    /// 張った接続をまとめて不通にするためのトークン
+    stall: Arc<Mutex<CancellationToken>>,
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        Self {
+            listeners: Arc::default(),
+            next_port: Arc::new(AtomicU16::new(MEMORY_FIRST_PORT)),
+            conditions: Arc::default(),
+            cut: Arc::default(),
+            stall: Arc::default(),
+        }
+    }
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.conditions.lock().unwrap_or_else(|e| e.into_inner()) = conditions;
+    }
+
+    // 張られているすべての接続を、Closeフレームもなしに切る (送っている途中のデータは相手に届かない)
+    // 待受はそのまま残るので、同じアドレスに接続し直せる
+    pub fn cut_links(&self) {
+        let mut cut = self.cut.lock().unwrap_or_else(|e| e.into_inner());
+        cut.cancel();
+        *cut = CancellationToken::new();
+    }
+
+    // 張られているすべての接続を、閉じないまま不通にする (経路の途中でパケットが捨てられ続ける場合)
+    // どちらの端も終端やエラーを受け取らず、書き込んだデータはどこにも届かない
+    pub fn stall_links(&self) {
+        let mut stall = self.stall.lock().unwrap_or_else(|e| e.into_inner());
+        stall.cancel();
+        *stall = CancellationToken::new();
+    }
+
+    fn allocate(&self, ip: IpAddr) -> SocketAddr {
+        SocketAddr::new(ip, self.next_port.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // 拒否する (応答しない) 回数が残っていれば1つ減らしてtrueを返す
+    fn refuse(&self) -> bool {
+        self.take(|conditions| &mut conditions.refuse_connects)
+    }
+
+    fn drop_connect(&self) -> bool {
+        self.take(|conditions| &mut conditions.drop_connects)
+    }
+
+    fn take(&self, count: impl FnOnce(&mut LinkConditions) -> &mut u32) -> bool {
+        let mut conditions = self.conditions.lock().unwrap_or_else(|e| e.into_inner());
+        let count = count(&mut conditions);
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    // 接続元と待受側の端を、遅れと切断を加える中継でつなぐ
+    fn link(&self) -> (DuplexStream, DuplexStream) {
+        let latency = self.conditions.lock().unwrap_or_else(|e| e.into_inner()).latency;
+        let cut = self.cut.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let stall = self.stall.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let (client, client_end) = tokio::io::duplex(MEMORY_BUFFER);
+        let (server, server_end) = tokio::io::duplex(MEMORY_BUFFER);
+        let (client_read, client_write) = tokio::io::split(client_end);
+        let (server_read, server_write) = tokio::io::split(server_end);
+        tokio::spawn(relay(client_read, server_write, latency, cut.clone(), stall.clone()));
+        tokio::spawn(relay(server_read, client_write, latency, cut, stall));
+        (client, server)
+    }
+}
+
+// 片方向の中継。読んだデータをlatencyだけ遅らせて書き込む
+// 切られると両方の端を捨てるので、相手は読み込みで終端を、書き込みでエラーを受け取る
+// 不通になると、両方の端を持ったまま読んだデータを捨て続ける
+async fn relay(mut from: ReadHalf<DuplexStream>, mut to: WriteHalf<DuplexStream>, latency: Duration, cut: CancellationToken, stall: CancellationToken) {
+    let mut buf = vec![0; MEMORY_BUFFER];
+    loop {
+        let n = tokio::select! {
+            read = from.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            },
+            _ = cut.cancelled() => return,
+        };
+        if stall.is_cancelled() {
+            continue;
+        }
+        let forwarded = async {
+            if !latency.is_zero() {
+                tokio::time::sleep(latency).await;
+            }
+            to.write_all(&buf[..n]).await
+        };
+        tokio::select! {
+            written = forwarded => if written.is_err() {
+                return;
+            },
+            _ = cut.cancelled() => return,
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+impl Transport for MemoryTransport {
+    fn connect<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<BoxStream>> {
+        Box::pin(async move {
+            // 名前解決はしないので、localhost以外はIPアドレスで指定する
+            let ip = match host {
+                "localhost" => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                host => host.parse().map_err(|_| ResolveError(format!("IPアドレスではありません: {}", host)).into_io())?,
+            };
+            let addr = SocketAddr::new(ip, port);
+            if self.refuse() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("{} への接続が拒否されました", addr)));
+            }
+            if self.drop_connect() {
+                std::future::pending::<()>().await;
+            }
+            let (client, server) = self.link();
+            let from = self.allocate(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            let listeners = self.listeners.lock().unwrap_or_else(|e| e.into_inner());
+            match listeners.get(&addr).map(|incoming| incoming.send((Box::new(server), from))) {
+                Some(Ok(())) => Ok(Box::new(client) as BoxStream),
+                _ => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("{} で待ち受けていません", addr))),
+            }
+        })
+    }
+
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        Box::pin(async move {
+            let addr = match addr.port() {
+                0 => self.allocate(addr.ip()),
+                _ => addr,
+            };
+            let mut listeners = self.listeners.lock().unwrap_or_else(|e| e.into_inner());
+            if listeners.get(&addr).is_some_and(|incoming| !incoming.is_closed()) {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} はすでに使われています", addr)));
+            }
+            let (incoming_tx, incoming) = mpsc::unbounded_channel();
+            listeners.insert(addr, incoming_tx);
+            Ok(Box::new(MemoryListener { addr, incoming }) as Box<dyn Listener>)
+        })
+    }
+}
+
+// 捨てると受信側が閉じ、以降の接続は拒否される (同じアドレスで待ち受け直せる)
+struct MemoryListener {
+    addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<(BoxStream, SocketAddr)>,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>> {
+        Box::pin(async move {
+            self.incoming.recv().await.ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "待受が閉じられました"))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
@@ -0,0 +1,241 @@
+use crate::commands::COMMANDS;
+use crate::hook::{HookCommand, HookContext, MessageHook, Verdict};
+use crate::i18n::Msg;
+use rhai::{CallFnOptions, Dynamic, Engine, Scope, AST};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Rhaiのスクリプトによる自動化 (不在時の自動返信・リンクの保存・通知など)
+// スクリプトは次の関数を定義でき、定義されたものだけを呼ぶ
+//   on_incoming(peer, body) / on_outgoing(peer, body): 文字列を返すと本文を置き換え、falseを返すと表示・送信しない
+//   on_command(peer, name, args): command(...) で追加したコマンドの処理。falseを返すと不明なコマンドとして扱う
+// スクリプトの最上位の文は読み込んだときに1回だけ実行する (command(...) でコマンドを追加する)
+// プラグインと違い、スクリプトは利用者自身が書くものとしてファイルへの書き込みを許す
+
+// 1回の呼び出しで実行できる処理の数 (無限ループでチャットが止まらないようにする)
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+// スクリプトから呼べる関数が書き込む先。呼び出しの後に取り出す
+#[derive(Default)]
+struct Output {
+    replies: Vec<String>,
+    notes: Vec<String>,
+    commands: Vec<HookCommand>,
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    commands: Vec<HookCommand>,
+}
+
+// 読み込んだスクリプトをまとめて1つのフックとして登録する。/script で実行中に追加・削除できる
+pub struct Scripts {
+    engine: Engine,
+    /// 呼び出しは1つずつ行う (出力の取り出しが混ざらないように)
+    scripts: Mutex<Vec<Script>>,
+    output: Arc<Mutex<Output>>,
+}
+
+impl Scripts {
+    // dirはappend_fileの相対パスの基準にする
+    pub fn new(dir: &Path) -> Self {
+        let output = Arc::new(Mutex::new(Output::default()));
+        let values: Arc<Mutex<HashMap<String, Dynamic>>> = Arc::default();
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| tracing::info!(script = true, "{}", text));
+        engine.on_debug(|text, source, pos| tracing::debug!(source = source.unwrap_or_default(), %pos, "{}", text));
+
+        let out = output.clone();
+        engine.register_fn("reply", move |body: &str| lock(&out).replies.push(body.to_string()));
+        let out = output.clone();
+        engine.register_fn("info", move |text: &str| lock(&out).notes.push(text.to_string()));
+        let out = output.clone();
+        engine.register_fn("command", move |name: &str, usage: &str, description: &str| {
+            lock(&out).commands.push(HookCommand { name: name.to_string(), usage: usage.to_string(), description: description.to_string() })
+        });
+        // スクリプトの関数は外側の変数を参照できないため、呼び出しをまたぐ値はここに残す
+        let store = values.clone();
+        engine.register_fn("remember", move |key: &str, value: Dynamic| {
+            lock(&store).insert(key.to_string(), value);
+        });
+        let store = values;
+        engine.register_fn("recall", move |key: &str| lock(&store).get(key).cloned().unwrap_or(Dynamic::UNIT));
+        engine.register_fn("now", || chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+        let base = dir.to_path_buf();
+        engine.register_fn("append_file", move |path: &str, line: &str| -> Result<(), Box<rhai::EvalAltResult>> {
+            append_line(&base.join(path), line).map_err(|e| e.to_string().into())
+        });
+
+        Self { engine, scripts: Mutex::new(Vec::new()), output }
+    }
+
+    // ディレクトリ内の *.rhai を名前順に読み込む。読み込めなかったスクリプトは警告して飛ばす
+    pub fn load_dir(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !dir.exists() {
+            return Ok(());
+        }
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+            .collect();
+        files.sort();
+        for path in files {
+            if let Err(e) = self.load(&path) {
+                tracing::warn!(path = %path.display(), error = %e, "スクリプトを読み込めませんでした");
+            }
+        }
+        Ok(())
+    }
+
+    // スクリプトを読み込み、最上位の文を実行する。同じ名前 (ファイル名から拡張子を除いたもの) のスクリプトは置き換える
+    pub fn load(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut scripts = lock(&self.scripts);
+        let ast = self.engine.compile_file(path.to_path_buf())?;
+        let run = self.engine.run_ast_with_scope(&mut Scope::new(), &ast);
+        let output = std::mem::take(&mut *lock(&self.output));
+        run?;
+
+        let mut commands = Vec::new();
+        for command in output.commands {
+            if COMMANDS.iter().any(|spec| spec.name == command.name) {
+                tracing::warn!(script = %name, command = %command.name, "組み込みのコマンドと同じ名前のため、スクリプトのコマンドを無視します");
+                continue;
+            }
+            commands.push(command);
+        }
+        scripts.retain(|script| script.name != name);
+        tracing::info!(script = %name, commands = commands.len(), "スクリプトを読み込みました");
+        scripts.push(Script { name: name.clone(), ast, commands });
+        Ok(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        lock(&self.scripts).iter().map(|script| script.name.clone()).collect()
+    }
+
+    // 各スクリプトの関数を順に呼ぶ。返り値を次のスクリプトに渡す本文・判断に変え、返信と案内はctxに移す
+    fn run(&self, ctx: Option<&mut HookContext<'_>>, function: &str, peer: &str, body: String) -> Verdict {
+        let scripts = lock(&self.scripts);
+        let mut body = body;
+        for script in scripts.iter().filter(|script| defines(&script.ast, function, 2)) {
+            match self.call(script, function, (peer.to_string(), body.clone())) {
+                Ok(value) if value.as_bool() == Ok(false) => {
+                    self.flush(ctx);
+                    return Verdict::Drop;
+                }
+                Ok(value) if value.is_string() => body = value.into_string().unwrap_or(body),
+                Ok(_) => {}
+                Err(e) => tracing::warn!(script = %script.name, function, error = %e, "スクリプトの実行に失敗しました"),
+            }
+        }
+        self.flush(ctx);
+        Verdict::Keep(body)
+    }
+
+    fn call(&self, script: &Script, function: &str, args: impl rhai::FuncArgs) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
+        // 最上位の文は読み込んだときにだけ実行する
+        let options = CallFnOptions::new().eval_ast(false);
+        self.engine.call_fn_with_options(options, &mut Scope::new(), &script.ast, function, args)
+    }
+
+    // スクリプトが残した返信と案内をctxに移す (送信のフックには返信の口がないので捨てる)
+    fn flush(&self, ctx: Option<&mut HookContext<'_>>) {
+        let output = std::mem::take(&mut *lock(&self.output));
+        if let Some(ctx) = ctx {
+            output.replies.into_iter().for_each(|body| ctx.reply(body));
+            output.notes.into_iter().for_each(|note| ctx.info(note));
+        }
+    }
+
+    // /script の処理
+    fn script_command(&self, ctx: &mut HookContext<'_>, args: &str) {
+        match args.split_once(char::is_whitespace).map(|(action, rest)| (action, rest.trim())).unwrap_or((args, "")) {
+            ("" | "list", "") => {
+                let names = self.names();
+                if names.is_empty() {
+                    ctx.info(Msg::NoScripts.text());
+                }
+                names.into_iter().for_each(|name| ctx.info(name));
+            }
+            ("load", path) if !path.is_empty() => match self.load(Path::new(path)) {
+                Ok(name) => ctx.info(Msg::ScriptLoaded.with(&[&name])),
+                Err(e) => ctx.info(Msg::ScriptLoadFailed.with(&[&e])),
+            },
+            ("unload", name) if !name.is_empty() => {
+                let mut scripts = lock(&self.scripts);
+                let before = scripts.len();
+                scripts.retain(|script| script.name != name);
+                if scripts.len() < before {
+                    ctx.info(Msg::ScriptUnloaded.with(&[&name]));
+                } else {
+                    ctx.info(Msg::NoSuchScript.with(&[&name]));
+                }
+            }
+            _ => ctx.info(Msg::Usage.with(&[&Msg::UsageScript.text()])),
+        }
+    }
+}
+
+impl MessageHook for Scripts {
+    fn on_incoming(&self, ctx: &mut HookContext<'_>, body: String) -> Verdict {
+        let peer = ctx.peer().to_string();
+        self.run(Some(ctx), "on_incoming", &peer, body)
+    }
+
+    fn on_outgoing(&self, peer: &str, body: String) -> Verdict {
+        self.run(None, "on_outgoing", peer, body)
+    }
+
+    fn commands(&self) -> Vec<HookCommand> {
+        let script = HookCommand {
+            name: "script".to_string(),
+            usage: Msg::UsageScript.to_string(),
+            description: Msg::HelpScript.to_string(),
+        };
+        std::iter::once(script)
+            .chain(lock(&self.scripts).iter().flat_map(|script| script.commands.clone()))
+            .collect()
+    }
+
+    fn on_command(&self, ctx: &mut HookContext<'_>, name: &str, args: &str) -> bool {
+        if name == "script" {
+            self.script_command(ctx, args);
+            return true;
+        }
+        let peer = ctx.peer().to_string();
+        let scripts = lock(&self.scripts);
+        let Some(script) = scripts
+            .iter()
+            .find(|script| script.commands.iter().any(|command| command.name == name) && defines(&script.ast, "on_command", 3))
+        else {
+            return false;
+        };
+        let handled = match self.call(script, "on_command", (peer, name.to_string(), args.to_string())) {
+            Ok(value) => value.as_bool() != Ok(false),
+            Err(e) => {
+                tracing::warn!(script = %script.name, command = name, error = %e, "スクリプトの実行に失敗しました");
+                false
+            }
+        };
+        self.flush(Some(ctx));
+        handled
+    }
+}
+
+fn defines(ast: &AST, function: &str, params: usize) -> bool {
+    ast.iter_functions().any(|f| f.name == function && f.params.len() == params)
+}
+
+fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
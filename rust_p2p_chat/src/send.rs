@@ -0,0 +1,275 @@
+use crate::builder::ClientSettings;
+use crate::chat::{open_history, record, send_chat};
+use crate::exit::{self, Failure};
+use crate::history::{Direction, EventKind, History};
+use crate::i18n::Msg;
+use crate::identity;
+use crate::protocol::Envelope;
+use crate::transfer;
+use crate::transport::{connect, ClientStream};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{stream::StreamExt, SinkExt};
+use p2pchat_core::pool;
+use std::collections::HashSet;
+use tracing::Instrument;
+
+// send サブコマンド: 接続して1つ送り、受信確認を待って切断する
+
+// send でファイルを送るときの転送ID (1回の接続で1つしか送らない)
+pub const SEND_TRANSFER_ID: u64 = 1;
+
+// send で送る内容。ファイルは接続してから読み込みに失敗しないよう、先に読み込んでおく
+pub enum Outgoing {
+    Message(String),
+    File(transfer::OutgoingFile),
+    /// 標準入力の各行をメッセージとして送る
+    Lines,
+    /// 標準入力をそのまま1つのファイルとして送る
+    Raw { name: String },
+}
+
+// send の失敗。終了コードで原因を区別できるようにする
+pub enum SendError {
+    Connect(Box<dyn std::error::Error>),
+    NotDelivered(String),
+    Refused(String),
+    Other(Box<dyn std::error::Error>),
+}
+
+impl SendError {
+    pub fn exit_code(&self) -> i32 {
+        self.failure().code
+    }
+
+    // 接続の失敗は原因が分かればその種類 (exit::DNS など) にする
+    pub fn failure(&self) -> Failure {
+        match self {
+            SendError::Other(e) => Failure::of(e.as_ref()),
+            SendError::Connect(e) => match Failure::of(e.as_ref()) {
+                Failure::OTHER => Failure { code: exit::SEND_CONNECT, kind: "connect" },
+                failure => failure,
+            },
+            SendError::NotDelivered(_) => Failure { code: exit::SEND_NOT_DELIVERED, kind: "not_delivered" },
+            SendError::Refused(_) => Failure { code: exit::SEND_REFUSED, kind: "file_refused" },
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Connect(e) => write!(f, "{}", Msg::SendConnect.with(&[e])),
+            SendError::NotDelivered(reason) => write!(f, "{}", Msg::SendNotDelivered.with(&[reason])),
+            SendError::Refused(name) => write!(f, "{}", Msg::SendRefused.with(&[name])),
+            SendError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// 接続してメッセージまたはファイルを1つ送り、相手からの受信確認 (DeliveredまたはFileReceived) を待って切断する
+pub async fn run_send(
+    settings: &ClientSettings,
+    outgoing: Outgoing,
+    timeout: std::time::Duration,
+) -> Result<(), SendError> {
+    let paths = &settings.paths;
+    let mut history = open_history(paths).map_err(SendError::Other)?;
+    let span = tracing::info_span!("connection", peer = %settings.uri);
+    let (mut ws_stream, peer, _) = connect(settings, true).instrument(span).await.map_err(SendError::Connect)?;
+
+    // 相手が署名を検証できるよう、先に証明書を送る
+    let identity = settings.options.identity.load(paths).map_err(SendError::Other)?;
+    let hello = Envelope::Identity { cert: BASE64.encode(&identity.cert_der) };
+    let signer = Some(&identity).filter(|_| settings.options.sign_messages);
+    let sent = ws_stream.send(tokio_tungstenite::tungstenite::Message::Text(hello.encode())).await;
+    sent.map_err(|e| SendError::Connect(e.into()))?;
+
+    let transfer_window = settings.options.config.borrow().connection.transfer_window();
+    let mut window = Window { window: transfer_window, acked: 0, timeout };
+    let (awaited, item) = match outgoing {
+        Outgoing::Lines => {
+            let result = stream_lines(&mut ws_stream, &mut history, &peer, signer, timeout).await;
+            let _ = ws_stream.close(None).await;
+            return result;
+        }
+        Outgoing::Raw { name } => {
+            let size = stream_raw(&mut ws_stream, &name, &mut window).await?;
+            record(&mut history, &peer, EventKind::FileTransfer { direction: Direction::Outgoing, file_name: name.clone(), size });
+            (Envelope::FileReceived { id: SEND_TRANSFER_ID, ok: true }, name)
+        }
+        Outgoing::Message(body) => {
+            let seq = send_chat(&mut ws_stream, &mut history, &peer, signer, body.clone())
+                .await
+                .map_err(|e| SendError::NotDelivered(e.to_string()))?;
+            (Envelope::Delivered { seq }, body)
+        }
+        Outgoing::File(mut file) => {
+            let send = |envelope: Envelope| tokio_tungstenite::tungstenite::Message::Text(envelope.encode());
+            ws_stream.send(send(file.start())).await.map_err(|e| SendError::NotDelivered(e.to_string()))?;
+            // チャンクは送る直前に読み、送り終えたバッファはプールに戻って次のチャンクで使い回す
+            while let Some(chunk) = file.next_chunk().await.map_err(|e| SendError::Other(e.into()))? {
+                if let Envelope::FileChunk { offset, data, .. } = &chunk {
+                    window.reserve(&mut ws_stream, offset + data.len() as u64).await?;
+                }
+                ws_stream.send(send(chunk)).await.map_err(|e| SendError::NotDelivered(e.to_string()))?;
+            }
+            ws_stream.send(send(file.end())).await.map_err(|e| SendError::NotDelivered(e.to_string()))?;
+            let (name, size) = (file.name, file.size);
+            record(&mut history, &peer, EventKind::FileTransfer { direction: Direction::Outgoing, file_name: name.clone(), size });
+            (Envelope::FileReceived { id: SEND_TRANSFER_ID, ok: true }, name)
+        }
+    };
+
+    let result = match tokio::time::timeout(timeout, wait_for_ack(&mut ws_stream, &awaited)).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Ok(false)) => Err(SendError::Refused(item)),
+        Ok(Err(reason)) => Err(SendError::NotDelivered(reason)),
+        Err(_) => Err(SendError::NotDelivered(format!("{}秒以内に応答がありません", timeout.as_secs()))),
+    };
+    let _ = ws_stream.close(None).await;
+    result
+}
+
+// 標準入力を1行ずつメッセージとして送る。読み終えたら、送ったすべての受信確認を待つ
+async fn stream_lines(
+    ws_stream: &mut ClientStream,
+    history: &mut History,
+    peer: &str,
+    signer: Option<&identity::Identity>,
+    timeout: std::time::Duration,
+) -> Result<(), SendError> {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut pending: HashSet<u64> = HashSet::new();
+    loop {
+        // 送っている間も受信確認を読み進め、相手側の送信が詰まらないようにする
+        tokio::select! {
+            line = lines.next_line() => match line.map_err(|e| SendError::Other(e.into()))? {
+                Some(line) => {
+                    let seq = send_chat(ws_stream, history, peer, signer, line)
+                        .await
+                        .map_err(|e| SendError::NotDelivered(e.to_string()))?;
+                    pending.insert(seq);
+                }
+                None => break,
+            },
+            message = ws_stream.next() => match message {
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
+                    if let Envelope::Delivered { seq } = Envelope::decode(&text) {
+                        pending.remove(&seq);
+                    }
+                }
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) | None => {
+                    return Err(SendError::NotDelivered("相手が接続を切断しました".to_string()));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(SendError::NotDelivered(e.to_string())),
+            },
+        }
+    }
+    let wait = async {
+        while !pending.is_empty() {
+            match ws_stream.next().await {
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
+                    if let Envelope::Delivered { seq } = Envelope::decode(&text) {
+                        pending.remove(&seq);
+                    }
+                }
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.to_string()),
+            }
+        }
+        Ok(pending.len())
+    };
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(Ok(0)) => Ok(()),
+        Ok(Ok(missing)) => Err(SendError::NotDelivered(format!("{}件のメッセージの受信確認が届く前に切断されました", missing))),
+        Ok(Err(reason)) => Err(SendError::NotDelivered(reason)),
+        Err(_) => Err(SendError::NotDelivered(format!("{}秒以内に応答がありません", timeout.as_secs()))),
+    }
+}
+
+// 標準入力を読みながらFileChunkで送り、最後にサイズとハッシュをStreamEndで送る。送ったバイト数を返す
+async fn stream_raw(ws_stream: &mut ClientStream, name: &str, window: &mut Window) -> Result<u64, SendError> {
+    use tokio::io::AsyncReadExt;
+
+    let send = |envelope: Envelope| tokio_tungstenite::tungstenite::Message::Text(envelope.encode());
+    let id = SEND_TRANSFER_ID;
+    let sent = ws_stream.send(send(Envelope::StreamStart { id, name: name.to_string() })).await;
+    sent.map_err(|e| SendError::NotDelivered(e.to_string()))?;
+
+    let mut stdin = tokio::io::stdin();
+    let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut offset: u64 = 0;
+    loop {
+        // 読んだ分をそのままFileChunkに渡す。バッファは送り終えるとプールに戻り、次のチャンクで使い回す
+        let mut buffer = pool::CHUNKS.take(transfer::CHUNK_SIZE);
+        let read = (&mut stdin).take(transfer::CHUNK_SIZE as u64).read_buf(&mut *buffer).await.map_err(|e| SendError::Other(e.into()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer);
+        window.reserve(ws_stream, offset + read as u64).await?;
+        let chunk = Envelope::FileChunk { id, offset, data: buffer.freeze() };
+        ws_stream.send(send(chunk)).await.map_err(|e| SendError::NotDelivered(e.to_string()))?;
+        offset += read as u64;
+    }
+    let sha256 = transfer::hex(hasher.finish().as_ref());
+    let end = Envelope::StreamEnd { id, size: offset, sha256 };
+    ws_stream.send(send(end)).await.map_err(|e| SendError::NotDelivered(e.to_string()))?;
+    Ok(offset)
+}
+
+// ファイルの送信で、相手が受け取ったと知らせた位置 (FileAck) よりwindowバイト以上先に送らない
+struct Window {
+    window: u64,
+    acked: u64,
+    /// FileAckを待つ時間の上限
+    timeout: std::time::Duration,
+}
+
+impl Window {
+    // endバイト目まで送ってよくなるまで、相手からのFileAckを読んで待つ
+    async fn reserve(&mut self, ws_stream: &mut ClientStream, end: u64) -> Result<(), SendError> {
+        let wait = async {
+            while end > self.acked + self.window {
+                match ws_stream.next().await {
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
+                        if let Envelope::FileAck { id: SEND_TRANSFER_ID, received } = Envelope::decode(&text) {
+                            self.acked = self.acked.max(received);
+                        }
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) | None => return Err("相手が接続を切断しました".to_string()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.to_string()),
+                }
+            }
+            Ok(())
+        };
+        match tokio::time::timeout(self.timeout, wait).await {
+            Ok(result) => result.map_err(SendError::NotDelivered),
+            Err(_) => Err(SendError::NotDelivered(format!("{}秒以内に応答がありません", self.timeout.as_secs()))),
+        }
+    }
+}
+
+// 受信確認が届くまで待つ。FileReceivedの場合は相手が保存できたかを返す
+async fn wait_for_ack(ws_stream: &mut ClientStream, awaited: &Envelope) -> Result<bool, String> {
+    while let Some(message) = ws_stream.next().await {
+        match message.map_err(|e| e.to_string())? {
+            tokio_tungstenite::tungstenite::Message::Text(text) => match (Envelope::decode(&text), awaited) {
+                (Envelope::Delivered { seq }, Envelope::Delivered { seq: expected }) if seq == *expected => return Ok(true),
+                (Envelope::FileReceived { id, ok }, Envelope::FileReceived { id: expected, .. }) if id == *expected => {
+                    return Ok(ok)
+                }
+                _ => {}
+            },
+            tokio_tungstenite::tungstenite::Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Err("相手が接続を切断しました".to_string())
+}
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Duration, Local};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// 使われないまま放置されたセッションを破棄するまでの日数
+const SESSION_TTL_DAYS: i64 = 30;
+
+// リスナー側で保持するセッションの情報
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// 履歴上で相手を識別する名前
+    pub peer: String,
+    pub last_seen: DateTime<Local>,
+    /// 再開された回数
+    #[serde(default)]
+    pub resumes: u32,
+    /// 発行したときの履歴の最後の通し番号。再送するのはこれより後に送ったメッセージに限る
+    #[serde(default)]
+    pub cursor: u64,
+}
+
+// リスナー側: トークン → セッション
+pub struct SessionStore {
+    path: PathBuf,
+    sessions: BTreeMap<String, SessionRecord>,
+}
+
+impl SessionStore {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let mut sessions: BTreeMap<String, SessionRecord> = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        let cutoff = Local::now() - Duration::days(SESSION_TTL_DAYS);
+        sessions.retain(|_, record| record.last_seen > cutoff);
+        Ok(Self { path, sessions })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.sessions)?)?;
+        Ok(())
+    }
+
+    // 新しいセッションを発行する。cursorは発行した時点の履歴の最後の通し番号
+    pub fn issue(&mut self, peer: &str, cursor: u64) -> Result<String, Box<dyn std::error::Error>> {
+        let token = new_token()?;
+        self.sessions.insert(
+            token.clone(),
+            SessionRecord {
+                peer: peer.to_string(),
+                last_seen: Local::now(),
+                resumes: 0,
+                cursor,
+            },
+        );
+        Ok(token)
+    }
+
+    // 既存のセッションを再開する。トークンが不明または期限切れならNone
+    pub fn resume(&mut self, token: &str) -> Option<SessionRecord> {
+        let record = self.sessions.get_mut(token)?;
+        record.last_seen = Local::now();
+        record.resumes += 1;
+        Some(record.clone())
+    }
+}
+
+// クライアント側: 接続先(host:port) → トークン
+pub struct ResumeTokens {
+    path: PathBuf,
+    tokens: BTreeMap<String, String>,
+}
+
+impl ResumeTokens {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let tokens = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, tokens })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.tokens)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, host: &str) -> Option<&String> {
+        self.tokens.get(host)
+    }
+
+    pub fn set(&mut self, host: &str, token: &str) {
+        self.tokens.insert(host.to_string(), token.to_string());
+    }
+}
+
+pub(crate) fn new_token() -> Result<String, Box<dyn std::error::Error>> {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "乱数の生成に失敗しました")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
@@ -0,0 +1,98 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// 現在の転送速度を計算する期間
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+// 1つの接続の送受信の統計 (/stats と `debug dump` のスナップショットに使う)
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    /// WebSocketのフレームの中身のバイト数
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// チャットメッセージの数 (再送分を含む)
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// このセッションを再開した回数
+    pub reconnects: u32,
+    /// refreshした時点の接続時間と直近の転送速度 (バイト/秒)
+    pub uptime_secs: u64,
+    pub throughput_sent: f64,
+    pub throughput_received: f64,
+    #[serde(skip)]
+    started: Instant,
+    /// 直近THROUGHPUT_WINDOWのフレーム (時刻, 送信バイト数, 受信バイト数)
+    #[serde(skip)]
+    recent: VecDeque<(Instant, u64, u64)>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            frames_sent: 0,
+            frames_received: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            reconnects: 0,
+            uptime_secs: 0,
+            throughput_sent: 0.0,
+            throughput_received: 0.0,
+            started: Instant::now(),
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn frame_sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        self.frames_sent += 1;
+        self.push_recent(bytes as u64, 0);
+    }
+
+    pub fn frame_received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+        self.frames_received += 1;
+        self.push_recent(0, bytes as u64);
+    }
+
+    fn push_recent(&mut self, sent: u64, received: u64) {
+        let now = Instant::now();
+        self.recent.push_back((now, sent, received));
+        while self.recent.front().is_some_and(|(at, _, _)| now.duration_since(*at) > THROUGHPUT_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+
+    // 接続時間と転送速度を現在の値に更新する
+    pub fn refresh(&mut self) {
+        let now = Instant::now();
+        self.recent.retain(|(at, _, _)| now.duration_since(*at) <= THROUGHPUT_WINDOW);
+        // 接続直後は経過時間で割る
+        let window = THROUGHPUT_WINDOW.min(now.duration_since(self.started)).as_secs_f64().max(1.0);
+        let (sent, received) = self.recent.iter().fold((0, 0), |(s, r), (_, sent, received)| (s + sent, r + received));
+        self.uptime_secs = now.duration_since(self.started).as_secs();
+        self.throughput_sent = sent as f64 / window;
+        self.throughput_received = received as f64 / window;
+    }
+}
+
+// 1024単位で読みやすく表示する
+pub fn bytes(count: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = count;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", value as u64, UNITS[0]),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+// systemdのソケットアクティベーション (sd_listen_fds) と状態の通知 (sd_notify)
+// どちらもsystemdから起動されていない場合 (環境変数がない場合) は何もしない
+
+// systemdから渡される最初のファイルディスクリプタ
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+// systemdが待受用のソケットを渡していれば、そのリスナーを返す
+#[cfg(unix)]
+pub fn listener() -> Result<Option<TcpListener>, Box<dyn std::error::Error>> {
+    use std::os::unix::io::FromRawFd;
+
+    // LISTEN_PIDが自分でなければ、親プロセスに渡されたものが引き継がれているだけ
+    if env_number::<u32>("LISTEN_PID") != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count = env_number::<u32>("LISTEN_FDS").unwrap_or(0);
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        tracing::warn!(count, "複数のソケットが渡されました。最初のソケットだけを使います");
+    }
+    // SAFETY: LISTEN_PIDが自分のPIDと一致するので、fd 3はsystemdが渡したソケットで、他では使われていない
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+#[cfg(not(unix))]
+pub fn listener() -> Result<Option<TcpListener>, Box<dyn std::error::Error>> {
+    Ok(None)
+}
+
+// 起動が完了したことを知らせ、ウォッチドッグが有効ならその間隔の半分ごとに生存を知らせ続ける
+pub fn ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+
+    let Some(usec) = env_number::<u64>("WATCHDOG_USEC") else {
+        return;
+    };
+    if env_number::<u32>("WATCHDOG_PID").is_some_and(|pid| pid != std::process::id()) {
+        return;
+    }
+    let period = Duration::from_micros(usec / 2);
+    tracing::debug!(?period, "ウォッチドッグへの通知を開始します");
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+// systemctl status に表示する状態
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+// NOTIFY_SOCKETに状態を送る (先頭が@の場合はLinuxの抽象名前空間のソケット)
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy().into_owned();
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        tracing::warn!(error = %e, "systemdへの通知に失敗しました");
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// 使われた機能とエラーの種類の回数 (`telemetry enable` で有効にした場合だけ記録する)
+// 記録するのはサブコマンド・表示方法の名前とエラーの種類 (exit::Failure のkind) の回数だけで、
+// 相手のアドレス・名前・メッセージ・ファイル名・識別子は含めない。送るのは `telemetry submit` を実行したときだけ
+
+// 記録の内容。有効にしていなければファイル自体を作らない (無効にするとファイルごと消す)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Telemetry {
+    pub enabled: bool,
+    /// 集計を始めた日時 (有効にしたとき・送ったときに更新する)
+    pub since: Option<DateTime<Local>>,
+    pub features: BTreeMap<String, u64>,
+    pub errors: BTreeMap<String, u64>,
+}
+
+// 送る内容。集計に加えて、バージョンとOSの種類だけを含める
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    /// 時刻とタイムゾーンは含めない
+    pub since: Option<NaiveDate>,
+    pub features: &'a BTreeMap<String, u64>,
+    pub errors: &'a BTreeMap<String, u64>,
+}
+
+impl Telemetry {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn enable(&mut self) {
+        if !self.enabled {
+            self.enabled = true;
+            self.since = Some(Local::now());
+        }
+    }
+
+    pub fn report(&self) -> Report<'_> {
+        Report {
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            since: self.since.map(|since| since.date_naive()),
+            features: &self.features,
+            errors: &self.errors,
+        }
+    }
+
+    // 送った後に集計をやり直す
+    pub fn reset(&mut self) {
+        self.features.clear();
+        self.errors.clear();
+        self.since = Some(Local::now());
+    }
+}
+
+// コマンドラインが記録に使うファイル (init で決める。組み込み先では記録しない)
+static FILE: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn init(path: PathBuf) {
+    let _ = FILE.set(path);
+}
+
+// 機能を1回使った (有効にしていなければ何もしない)
+pub fn record_feature(name: &str) {
+    update(|telemetry| *telemetry.features.entry(name.to_string()).or_default() += 1);
+}
+
+// 種類がkindのエラーで終了した
+pub fn record_error(kind: &str) {
+    update(|telemetry| *telemetry.errors.entry(kind.to_string()).or_default() += 1);
+}
+
+// 記録に失敗しても本来の処理は続ける
+fn update(change: impl FnOnce(&mut Telemetry)) {
+    let Some(path) = FILE.get().filter(|path| path.exists()) else {
+        return;
+    };
+    let mut telemetry = match Telemetry::load(path) {
+        Ok(telemetry) if telemetry.enabled => telemetry,
+        Ok(_) => return,
+        Err(e) => {
+            tracing::debug!(error = %e, "利用状況の記録を読み込めません");
+            return;
+        }
+    };
+    change(&mut telemetry);
+    if let Err(e) = telemetry.save(path) {
+        tracing::debug!(error = %e, "利用状況を記録できません");
+    }
+}
+
+// urlにJSONでPOSTする
+#[cfg(feature = "discovery")]
+pub async fn submit(url: &str, report: &Report<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::builder().timeout(std::time::Duration::from_secs(30)).build()?;
+    client.post(url).json(report).send().await?.error_for_status()?;
+    Ok(())
+}
+
+// discovery フィーチャー (HTTPクライアント) を含めずにビルドした場合は送れない
+#[cfg(not(feature = "discovery"))]
+pub async fn submit(_url: &str, _report: &Report<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    Err("利用状況の送信は含まれていません (discoveryフィーチャー)".into())
+}
@@ -0,0 +1,164 @@
+use ratatui::style::Color;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+
+// TUIとplainの色。config.toml の [ui] theme で選び、[ui.colors] で一部を置き換えられる
+
+// 組み込みの配色
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeName {
+    /// 暗い背景向け (既定)
+    #[default]
+    Dark,
+    /// 明るい背景向け (黄色など、白地で読みにくい色を使わない)
+    Light,
+    Solarized,
+    /// 暗い色を使わず、明るい色と太字だけで表示する
+    HighContrast,
+    /// 色を付けず、太字・斜体だけで区別する
+    Mono,
+}
+
+// 色の指定。名前 ("cyan"・"light-red")、"#rrggbb"、0〜255の番号のいずれか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThemeColor(pub Color);
+
+impl<'de> Deserialize<'de> for ThemeColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Color::from_str(&text)
+            .map(ThemeColor)
+            .map_err(|_| serde::de::Error::custom(format!("色として解釈できません: {}", text)))
+    }
+}
+
+impl Serialize for ThemeColor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+// config.toml の [ui.colors]。指定した項目だけ組み込みの配色を置き換える
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColorOverrides {
+    /// 相手の名前に使う色 (名前ごとにこの中から1つ選ぶ)
+    pub nicks: Option<Vec<ThemeColor>>,
+    /// 自分の名前
+    pub own: Option<ThemeColor>,
+    /// 時刻・通し番号・配送状況などの補足
+    pub meta: Option<ThemeColor>,
+    /// 案内の表示
+    pub info: Option<ThemeColor>,
+    pub warning: Option<ThemeColor>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    nicks: Vec<Color>,
+    pub own: Color,
+    pub meta: Color,
+    pub info: Color,
+    pub warning: Color,
+}
+
+impl Theme {
+    pub fn new(name: ThemeName, overrides: &ColorOverrides) -> Self {
+        let mut theme = Self::builtin(name);
+        if let Some(nicks) = overrides.nicks.as_ref().filter(|nicks| !nicks.is_empty()) {
+            theme.nicks = nicks.iter().map(|color| color.0).collect();
+        }
+        let fields = [
+            (&mut theme.own, overrides.own),
+            (&mut theme.meta, overrides.meta),
+            (&mut theme.info, overrides.info),
+            (&mut theme.warning, overrides.warning),
+        ];
+        for (field, color) in fields {
+            if let Some(color) = color {
+                *field = color.0;
+            }
+        }
+        theme
+    }
+
+    fn builtin(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Dark => Self {
+                nicks: vec![Color::Green, Color::Yellow, Color::Blue, Color::Magenta, Color::LightRed, Color::LightGreen],
+                own: Color::Cyan,
+                meta: Color::DarkGray,
+                info: Color::Reset,
+                warning: Color::Red,
+            },
+            ThemeName::Light => Self {
+                nicks: vec![Color::Blue, Color::Magenta, Color::Red, Color::Green, Color::Indexed(94), Color::Indexed(24)],
+                own: Color::Indexed(30),
+                meta: Color::Indexed(244),
+                info: Color::Reset,
+                warning: Color::Red,
+            },
+            ThemeName::Solarized => Self {
+                nicks: vec![
+                    Color::Rgb(0xb5, 0x89, 0x00),
+                    Color::Rgb(0xcb, 0x4b, 0x16),
+                    Color::Rgb(0xd3, 0x36, 0x82),
+                    Color::Rgb(0x6c, 0x71, 0xc4),
+                    Color::Rgb(0x26, 0x8b, 0xd2),
+                    Color::Rgb(0x85, 0x99, 0x00),
+                ],
+                own: Color::Rgb(0x2a, 0xa1, 0x98),
+                meta: Color::Rgb(0x58, 0x6e, 0x75),
+                info: Color::Rgb(0x93, 0xa1, 0xa1),
+                warning: Color::Rgb(0xdc, 0x32, 0x2f),
+            },
+            ThemeName::HighContrast => Self {
+                nicks: vec![Color::LightYellow, Color::LightGreen, Color::LightMagenta, Color::LightBlue, Color::White],
+                own: Color::LightCyan,
+                meta: Color::White,
+                info: Color::White,
+                warning: Color::LightRed,
+            },
+            ThemeName::Mono => Self {
+                nicks: vec![Color::Reset],
+                own: Color::Reset,
+                meta: Color::Reset,
+                info: Color::Reset,
+                warning: Color::Reset,
+            },
+        }
+    }
+
+    // 名前から色を選ぶ (実行ごとに変わらないよう、単純なハッシュを使う)
+    pub fn nick(&self, name: &str) -> Color {
+        let hash = name.bytes().fold(2166136261u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(16777619));
+        self.nicks[hash as usize % self.nicks.len()]
+    }
+}
+
+// plainの出力に使うSGRの番号。attrs (太字の "1"、斜体の "3" など) に文字色を加える
+pub fn sgr(attrs: &str, color: Color) -> String {
+    let color = match color {
+        Color::Reset => String::new(),
+        Color::Black => "30".to_string(),
+        Color::Red => "31".to_string(),
+        Color::Green => "32".to_string(),
+        Color::Yellow => "33".to_string(),
+        Color::Blue => "34".to_string(),
+        Color::Magenta => "35".to_string(),
+        Color::Cyan => "36".to_string(),
+        Color::Gray => "37".to_string(),
+        Color::DarkGray => "90".to_string(),
+        Color::LightRed => "91".to_string(),
+        Color::LightGreen => "92".to_string(),
+        Color::LightYellow => "93".to_string(),
+        Color::LightBlue => "94".to_string(),
+        Color::LightMagenta => "95".to_string(),
+        Color::LightCyan => "96".to_string(),
+        Color::White => "97".to_string(),
+        Color::Rgb(r, g, b) => format!("38;2;{};{};{}", r, g, b),
+        Color::Indexed(index) => format!("38;5;{}", index),
+    };
+    [attrs, color.as_str()].iter().filter(|part| !part.is_empty()).copied().collect::<Vec<_>>().join(";")
+}
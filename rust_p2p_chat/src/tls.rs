@@ -0,0 +1,158 @@
+use crate::builder::TlsMode;
+use crate::config::WebhookEvent;
+use crate::contacts::Contact;
+use crate::i18n::Msg;
+use crate::identity;
+use crate::transport::ConnectError;
+use crate::trust::{KnownPeers, TrustStatus};
+use crate::webhook::Webhooks;
+use std::sync::Arc;
+use tokio_rustls::rustls::{self, pki_types::CertificateDer, ClientConfig, ServerConfig};
+use tokio_rustls::TlsConnector;
+
+// TLSの設定と、相手の証明書の照合
+// 証明書は自己署名のため、CAによる検証の代わりにフィンガープリントをknown_peersとアドレス帳で照合する
+
+// 自分の証明書で接続を受け付けるTLSの設定
+pub fn acceptor(identity: &identity::Identity, mode: TlsMode) -> Result<tokio_rustls::TlsAcceptor, Box<dyn std::error::Error>> {
+    let mut config = server_config(identity, mode)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+// ALPNを決める前の、受け付ける側の設定 (WebTransportでも使う)
+pub(crate) fn server_config(identity: &identity::Identity, mode: TlsMode) -> Result<ServerConfig, rustls::Error> {
+    ServerConfig::builder_with_protocol_versions(mode.versions())
+        .with_no_client_auth()
+        .with_single_cert(identity.cert_chain(), identity.private_key())
+}
+
+// 接続する側のTLSの設定 (サーバー証明書はハンドシェイク後に verify_peer_fingerprint で照合する)
+pub fn connector(mode: TlsMode) -> TlsConnector {
+    let mut config = client_config(mode);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    TlsConnector::from(Arc::new(config))
+}
+
+// ALPNを決める前の、接続する側の設定 (WebTransportでも使う)
+pub(crate) fn client_config(mode: TlsMode) -> ClientConfig {
+    let root_cert_store = rustls::RootCertStore::empty();
+    let mut config = ClientConfig::builder_with_protocol_versions(mode.versions())
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+    
+    // 自己署名証明書のため、CAによる検証はスキップしてハンドシェイク後にフィンガープリントを照合する
+    config.dangerous().set_certificate_verifier(Arc::new(NoopServerCertVerifier));
+    config
+}
+
+// サーバー証明書のフィンガープリントをknown_peersおよびアドレス帳と照合する
+pub(crate) fn verify_peer_fingerprint(
+    host: &str,
+    fingerprint: &str,
+    strict: bool,
+    contact: Option<(String, Contact)>,
+    known_peers_file: &std::path::Path,
+    webhooks: &Webhooks,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // アドレス帳にフィンガープリントが登録されていれば、それと一致しなければならない
+    if let Some(expected) = contact.as_ref().and_then(|(_, c)| c.fingerprint.as_ref()) {
+        if expected != fingerprint {
+            return Err(ConnectError::Rejected(format!(
+                "連絡先に登録されたフィンガープリントと一致しません (期待値: {}, 実際: {})",
+                expected, fingerprint
+            ))
+            .into());
+        }
+    }
+
+    let mut known = KnownPeers::load(known_peers_file)?;
+    let status = known.check(host, fingerprint);
+    match &status {
+        TrustStatus::Trusted => tracing::info!(host, fingerprint, "信頼済みの相手です"),
+        TrustStatus::Unknown => tracing::info!(host, fingerprint, "未登録の相手です"),
+        TrustStatus::Changed { expected } => {
+            tracing::warn!(security = true, host, fingerprint, expected = %expected, "相手の証明書が記録と異なります");
+            let fields = serde_json::json!({ "expected": expected, "actual": fingerprint, "rejected": strict });
+            webhooks.emit(WebhookEvent::FingerprintChanged, host, fields);
+        }
+    }
+    match status {
+        TrustStatus::Trusted => {}
+        TrustStatus::Unknown if strict => {
+            tracing::warn!(security = true, host, fingerprint, "known_peersに登録されていない相手への接続を拒否しました");
+            return Err(ConnectError::Rejected(Msg::UnknownPeerStrict.with(&[&fingerprint, &host, &fingerprint])).into());
+        }
+        TrustStatus::Unknown => {
+            // --format jsonl の出力に混ざらないようstderrに出す
+            eprintln!("{}", Msg::FirstContact.with(&[&fingerprint]));
+            known.insert(host, fingerprint, contact.map(|(name, _)| name));
+            known.save()?;
+        }
+        TrustStatus::Changed { expected } if strict => {
+            tracing::warn!(security = true, host, fingerprint, "証明書が変わった相手への接続を拒否しました");
+            return Err(ConnectError::Rejected(Msg::ChangedPeerStrict.with(&[&expected, &fingerprint, &host, &fingerprint])).into());
+        }
+        TrustStatus::Changed { expected } => {
+            eprintln!("{}", Msg::FingerprintChanged.with(&[&expected, &fingerprint]));
+            eprintln!("{}", Msg::SpoofingHint);
+        }
+    }
+
+    Ok(())
+}
+
+// サーバー証明書を検証しないためのダミー構造体
+#[derive(Debug)]
+struct NoopServerCertVerifier;
+
+impl rustls::client::danger::ServerCertVerifier for NoopServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        // すべての一般的な署名スキームをサポート
+        vec![
+            rustls::SignatureScheme::RSA_PKCS1_SHA1,
+            rustls::SignatureScheme::ECDSA_SHA1_Legacy,
+            rustls::SignatureScheme::RSA_PKCS1_SHA256,
+            rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+            rustls::SignatureScheme::RSA_PKCS1_SHA384,
+            rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+            rustls::SignatureScheme::RSA_PKCS1_SHA512,
+            rustls::SignatureScheme::ECDSA_NISTP521_SHA512,
+            rustls::SignatureScheme::RSA_PSS_SHA256,
+            rustls::SignatureScheme::RSA_PSS_SHA384,
+            rustls::SignatureScheme::RSA_PSS_SHA512,
+            rustls::SignatureScheme::ED25519,
+            rustls::SignatureScheme::ED448,
+        ]
+    }
+}
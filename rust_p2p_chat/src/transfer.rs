@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::protocol::Envelope;
+use crate::runtime::Runtime;
+use p2pchat_core::pool;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+// 1回のFileChunkで送るバイト数
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// 受信したファイルの保存先の設定
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    pub dir: PathBuf,
+    /// 相手ごとにサブフォルダを作る
+    pub per_peer: bool,
+    /// falseの場合、相手から送られたファイルを受け取らない
+    pub accept: bool,
+    /// これより大きいファイルは受け取らない (バイト)
+    pub max_bytes: Option<u64>,
+}
+
+// 受信途中のファイル
+struct IncomingFile {
+    name: String,
+    /// ストリームの場合は送り終わるまで分からない
+    size: Option<u64>,
+    expected_sha256: Option<String>,
+    dir: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    hasher: ring::digest::Context,
+    received: u64,
+}
+
+// 受信が完了したファイル
+pub struct CompletedFile {
+    pub name: String,
+    pub size: u64,
+    pub path: PathBuf,
+}
+
+// 1つの接続で受信中のファイルを管理する
+pub struct Downloads {
+    config: DownloadConfig,
+    peer: String,
+    active: HashMap<u64, IncomingFile>,
+    /// 受け取りを断ったファイル転送 (続くFileChunk・FileEndを読み捨てる)
+    refused: HashSet<u64>,
+}
+
+impl Downloads {
+    pub fn new(config: DownloadConfig, peer: &str) -> Self {
+        Self {
+            config,
+            peer: peer.to_string(),
+            active: HashMap::new(),
+            refused: HashSet::new(),
+        }
+    }
+
+    // セッションの再開で相手の名前が変わったときに呼ぶ
+    pub fn set_peer(&mut self, peer: &str) {
+        self.peer = peer.to_string();
+    }
+
+    // 一時ファイル (.<名前>.part) を作って受信を始める。受け取りを断った場合はfalseを返す
+    pub fn start(&mut self, id: u64, name: &str, size: u64, sha256: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.config.max_bytes.is_some_and(|max| size > max) {
+            self.refused.insert(id);
+            return Ok(false);
+        }
+        self.open(id, name, Some(size), Some(sha256.to_string()))
+    }
+
+    // サイズとハッシュが最後に届くストリーム (send --stream --raw) の受信を始める
+    pub fn start_stream(&mut self, id: u64, name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        self.open(id, name, None, None)
+    }
+
+    fn open(&mut self, id: u64, name: &str, size: Option<u64>, sha256: Option<String>) -> Result<bool, Box<dyn std::error::Error>> {
+        if !self.config.accept {
+            self.refused.insert(id);
+            return Ok(false);
+        }
+        let name = sanitize_file_name(name);
+        let dir = if self.config.per_peer {
+            self.config.dir.join(sanitize_file_name(&self.peer))
+        } else {
+            self.config.dir.clone()
+        };
+        std::fs::create_dir_all(&dir)?;
+        let part_path = dir.join(format!(".{}.{}.part", name, id));
+        let file = File::create(&part_path)?;
+        self.active.insert(
+            id,
+            IncomingFile {
+                name,
+                size,
+                expected_sha256: sha256,
+                dir,
+                part_path,
+                file,
+                hasher: ring::digest::Context::new(&ring::digest::SHA256),
+                received: 0,
+            },
+        );
+        Ok(true)
+    }
+
+    pub fn chunk(&mut self, id: u64, offset: u64, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if self.refused.contains(&id) {
+            return Ok(());
+        }
+        let incoming = self.active.get_mut(&id).ok_or("不明なファイル転送です")?;
+        if offset != incoming.received {
+            let name = incoming.name.clone();
+            self.abort(id);
+            return Err(format!("{} の受信データの順序が不正です", name).into());
+        }
+        let received = incoming.received + bytes.len() as u64;
+        if incoming.size.is_some_and(|size| received > size) {
+            let name = incoming.name.clone();
+            self.abort(id);
+            return Err(format!("{} の受信データが宣言されたサイズを超えました", name).into());
+        }
+        if incoming.size.is_none() && self.config.max_bytes.is_some_and(|max| received > max) {
+            let name = incoming.name.clone();
+            self.abort(id);
+            return Err(format!("{} の受信データが受け取れるサイズを超えました", name).into());
+        }
+        incoming.file.write_all(bytes)?;
+        incoming.hasher.update(bytes);
+        incoming.received += bytes.len() as u64;
+        Ok(())
+    }
+
+    // ハッシュを検証してから、衝突しない名前に変更して保存する。受け取りを断った転送の場合はNoneを返す
+    pub fn finish(&mut self, id: u64) -> Result<Option<CompletedFile>, Box<dyn std::error::Error>> {
+        if self.refused.remove(&id) {
+            return Ok(None);
+        }
+        let mut incoming = self.active.remove(&id).ok_or("不明なファイル転送です")?;
+        incoming.file.flush()?;
+        incoming.file.sync_all()?;
+
+        let actual = hex(incoming.hasher.finish().as_ref());
+        let expected = incoming.expected_sha256.unwrap_or_default();
+        if incoming.size != Some(incoming.received) || actual != expected {
+            let _ = std::fs::remove_file(&incoming.part_path);
+            return Err(format!(
+                "{} のハッシュが一致しません (期待値: {}, 実際: {})。ファイルは破棄しました",
+                incoming.name, expected, actual
+            )
+            .into());
+        }
+
+        let path = move_to_unique_path(&incoming.part_path, &incoming.dir, &incoming.name)?;
+        Ok(Some(CompletedFile {
+            name: incoming.name,
+            size: incoming.received,
+            path,
+        }))
+    }
+
+    // ストリームの最後に届いたサイズとハッシュで検証して保存する
+    pub fn finish_stream(&mut self, id: u64, size: u64, sha256: &str) -> Result<Option<CompletedFile>, Box<dyn std::error::Error>> {
+        if let Some(incoming) = self.active.get_mut(&id) {
+            incoming.size = Some(size);
+            incoming.expected_sha256 = Some(sha256.to_string());
+        }
+        self.finish(id)
+    }
+
+    // 受信中のファイルの名前・受け取ったバイト数・全体のサイズ (受け取りを断った転送はNone)
+    pub fn progress(&self, id: u64) -> Option<(&str, u64, Option<u64>)> {
+        self.active.get(&id).map(|incoming| (incoming.name.as_str(), incoming.received, incoming.size))
+    }
+
+    // 受信途中のファイルの数
+    pub fn active(&self) -> usize {
+        self.active.len()
+    }
+
+    // 受信を取り消す。続けて届くFileChunk・FileEndは読み捨て、FileEndには受け取らなかったと応答する
+    pub fn cancel(&mut self, id: u64) -> Option<String> {
+        let name = self.active.get(&id).map(|incoming| incoming.name.clone())?;
+        self.abort(id);
+        self.refused.insert(id);
+        Some(name)
+    }
+
+    pub fn abort(&mut self, id: u64) {
+        if let Some(incoming) = self.active.remove(&id) {
+            let _ = std::fs::remove_file(incoming.part_path);
+        }
+    }
+}
+
+impl Drop for Downloads {
+    // 接続が切れたときに受信途中の一時ファイルを残さない
+    fn drop(&mut self) {
+        let ids: Vec<u64> = self.active.keys().copied().collect();
+        for id in ids {
+            self.abort(id);
+        }
+    }
+}
+
+// 相手から送られた名前からパス区切りなどを取り除く (ディレクトリトラバーサル対策)
+pub fn sanitize_file_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    // 先頭の点と空白はまとめて取り除く (". ." が "." になって保存先そのものを指さないように)
+    let cleaned = cleaned.trim_start_matches(|c: char| c == '.' || c.is_whitespace()).trim_end();
+    if cleaned.is_empty() {
+        "download".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+// 同名のファイルがあれば `名前 (1).拡張子` のように番号を付ける
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    candidates(dir, name).find(|path| !path.exists()).expect("空いているファイル名が見つかりません")
+}
+
+// fromを、dirの中の衝突しない名前 (unique_pathと同じ付け方) に移す
+// 空いている名前を確かめてから移すと、同じ名前のファイルを同時に受け取ったときに上書きしてしまうため、
+// ハードリンクで名前を取ってから元の名前を消す (ハードリンクを作れないファイルシステムでは空のファイルで名前を取ってから置き換える)
+pub fn move_to_unique_path(from: &Path, dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    for candidate in candidates(dir, name) {
+        let claimed = match std::fs::hard_link(from, &candidate) {
+            Ok(()) => std::fs::remove_file(from),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(_) => match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+                Ok(_) => std::fs::rename(from, &candidate),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => Err(e),
+            },
+        };
+        return claimed.map(|()| candidate);
+    }
+    unreachable!("空いているファイル名が見つかりません")
+}
+
+// name、`名前 (1).拡張子`、`名前 (2).拡張子`... の順の候補
+fn candidates<'a>(dir: &'a Path, name: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (name, None),
+    };
+    std::iter::once(dir.join(name)).chain((1..).map(move |n| match ext {
+        Some(ext) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+        None => dir.join(format!("{} ({})", stem, n)),
+    }))
+}
+
+// 送信するファイル。FileStartに載せるサイズとハッシュは開くときに読み通して求め、
+// FileChunkは送る直前に1つずつプールのバッファへ読む (数GBのファイルでもメモリに全体を読み込まない)
+pub struct OutgoingFile {
+    id: u64,
+    pub name: String,
+    pub size: u64,
+    sha256: String,
+    file: tokio::fs::File,
+    offset: u64,
+}
+
+impl OutgoingFile {
+    pub async fn open(id: u64, path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .ok_or("ファイル名がありません")?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut hasher = ring::digest::Context::new(&ring::digest::SHA256);
+        let mut size: u64 = 0;
+        let mut buffer = pool::CHUNKS.take(CHUNK_SIZE);
+        loop {
+            buffer.clear();
+            let read = (&mut file).take(CHUNK_SIZE as u64).read_buf(&mut *buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer);
+            size += read as u64;
+        }
+        file.rewind().await?;
+        Ok(Self { id, name, size, sha256: hex(hasher.finish().as_ref()), file, offset: 0 })
+    }
+
+    pub fn start(&self) -> Envelope {
+        Envelope::FileStart { id: self.id, name: self.name.clone(), size: self.size, sha256: self.sha256.clone() }
+    }
+
+    // 次のFileChunk。FileStartで知らせたサイズまで読んだらNone (その間にファイルが伸びても先は送らない)
+    pub async fn next_chunk(&mut self) -> std::io::Result<Option<Envelope>> {
+        use tokio::io::AsyncReadExt;
+
+        let left = (self.size - self.offset).min(CHUNK_SIZE as u64);
+        if left == 0 {
+            return Ok(None);
+        }
+        let mut buffer = pool::CHUNKS.take(left as usize);
+        let read = (&mut self.file).take(left).read_buf(&mut *buffer).await?;
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "送信中にファイルが短くなりました"));
+        }
+        let offset = self.offset;
+        self.offset += read as u64;
+        Ok(Some(Envelope::FileChunk { id: self.id, offset, data: buffer.freeze() }))
+    }
+
+    pub fn end(&self) -> Envelope {
+        Envelope::FileEnd { id: self.id }
+    }
+}
+
+// 送信中のファイルからセッションへの知らせ (Envelopeはセッションが相手に送る)
+pub(crate) enum Upload {
+    Started { id: u64, name: String, size: u64 },
+    Envelope { id: u64, envelope: Envelope },
+    Finished { id: u64 },
+    /// tokenが取り消されたため、FileEndを送らずに止めた
+    Cancelled { id: u64 },
+    Unreadable { id: u64, error: String },
+}
+
+// ファイルを読み込んで分割し、順にセッションへ渡すタスクを起動する
+// 送っている間もセッションはメッセージやコマンドを扱え、tokenを取り消せば次のFileChunkの前で止まる
+// 相手が受け取ったバイト数 (FileAck) はackedで届き、それよりwindowバイト以上先のFileChunkは渡さずに待つ
+// (遅い回線でもファイルのデータが送信の順番待ちに溜まらず、チャットのメッセージが後ろで待たされない)
+pub(crate) fn spawn_upload(
+    runtime: &Runtime,
+    id: u64,
+    path: PathBuf,
+    token: CancellationToken,
+    mut acked: watch::Receiver<u64>,
+    window: u64,
+    uploads: mpsc::Sender<Upload>,
+) {
+    runtime.spawn(async move {
+        let mut file = match OutgoingFile::open(id, &path).await.map_err(|e| e.to_string()) {
+            Ok(file) => file,
+            Err(error) => {
+                let _ = uploads.send(Upload::Unreadable { id, error }).await;
+                return;
+            }
+        };
+        if uploads.send(Upload::Started { id, name: file.name.clone(), size: file.size }).await.is_err() {
+            return;
+        }
+        let mut envelope = Some(file.start());
+        while let Some(next) = envelope.take() {
+            if let Envelope::FileChunk { offset, data, .. } = &next {
+                let end = offset + data.len() as u64;
+                tokio::select! {
+                    biased;
+                    _ = token.cancelled() => {
+                        let _ = uploads.send(Upload::Cancelled { id }).await;
+                        return;
+                    }
+                    credit = async { acked.wait_for(|acked| end <= acked + window).await.is_ok() } => {
+                        // セッションが終わった
+                        if !credit {
+                            return;
+                        }
+                    }
+                }
+            }
+            let finished = matches!(next, Envelope::FileEnd { .. });
+            tokio::select! {
+                biased;
+                _ = token.cancelled() => {
+                    let _ = uploads.send(Upload::Cancelled { id }).await;
+                    return;
+                }
+                sent = uploads.send(Upload::Envelope { id, envelope: next }) => {
+                    if sent.is_err() {
+                        return;
+                    }
+                }
+            }
+            if finished {
+                break;
+            }
+            envelope = match file.next_chunk().await {
+                Ok(Some(chunk)) => Some(chunk),
+                Ok(None) => Some(file.end()),
+                Err(e) => {
+                    let _ = uploads.send(Upload::Unreadable { id, error: e.to_string() }).await;
+                    return;
+                }
+            };
+        }
+        let _ = uploads.send(Upload::Finished { id }).await;
+    });
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
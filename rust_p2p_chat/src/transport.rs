@@ -0,0 +1,662 @@
+use crate::builder::{ClientSettings, Limits, ListenerSettings};
+use crate::chat::{handle_connection, open_history, ChatOptions, Role};
+use crate::config::{ConnectionConfig, DiscoveryConfig};
+use crate::contacts::AddressBook;
+use crate::event::DisconnectReason;
+use crate::health::ListenerState;
+use crate::i18n::Msg;
+use crate::paths::Paths;
+use crate::runtime::{BoxStream, Listener, ResolveError};
+use crate::webhook::Webhooks;
+use crate::discovery::dns;
+use crate::discovery::ip::Resolver;
+use crate::{debug, exit, identity, rendezvous, systemd, tls, ui, webui};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_rustls::rustls;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+// TLS上のWebSocket接続を確立する (待ち受け・接続のどちらも)
+
+// サーバー側の処理
+pub async fn run_server(settings: ListenerSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let ListenerSettings { paths, options, addr, tls, limits, show_qr, rendezvous, web, webtransport, dns_name, announce, health } = settings;
+    let paths = &paths;
+    // systemdのソケットアクティベーションで起動された場合は、渡されたソケットで待ち受ける (--addr は使わない)
+    let mut listener: Box<dyn Listener> = match systemd::listener()? {
+        Some(listener) => Box::new(listener),
+        None => options.runtime.bind(addr).await?,
+    };
+    let addr = listener.local_addr()?;
+
+    // -q ではIPアドレスの表示のための問い合わせ自体を行わない
+    let public_host = match options.quiet {
+        true => None,
+        false => {
+            let discovery = options.config.borrow().discovery.clone();
+            print_server_banner(addr, &discovery, &options.shutdown).await
+        }
+    };
+
+    // 接続を受け付けてからパスフレーズを尋ねないよう、先に履歴を開いておく
+    let mut history = open_history(paths)?;
+
+    // 1. 自己署名証明書の読み込み (初回のみ生成)
+    let identity = options.identity.load(paths)?;
+    if !options.quiet {
+        println!("{}", Msg::Fingerprint.with(&[&identity.fingerprint()]));
+    }
+    if let (Some(host), true) = (&public_host, show_qr && std::io::stdout().is_terminal()) {
+        print_connect_qr(&format!("wss://{}:{}#{}", host, addr.port(), identity.fingerprint()));
+    }
+    // --code ではランデブーサーバーに登録し、URLとフィンガープリントの代わりに伝える短いコードを表示する
+    // (-q でホストが分からない場合は、ランデブーサーバーから見えた送信元のアドレスが使われる)
+    if let Some(server) = &rendezvous {
+        let (code, expires_in) = rendezvous::register(server, public_host.clone(), addr.port(), identity.fingerprint())
+            .await
+            .map_err(|e| Msg::CodeRegisterFailed.with(&[&e]))?;
+        let line = Msg::ConnectCode.with(&[&code, &expires_in.div_ceil(60), &code]);
+        match options.ui.format {
+            ui::OutputFormat::Text => println!("{}", line),
+            ui::OutputFormat::Jsonl => eprintln!("{}", line),
+        }
+    }
+    if let Some(domain) = &dns_name {
+        // -q でホストが分からない場合は、待ち受けているアドレスを使う
+        let host = public_host.clone().or_else(|| (!addr.ip().is_unspecified()).then(|| addr.ip().to_string()));
+        match host {
+            Some(host) => {
+                let advertisement = dns::Advertisement { host, port: addr.port(), fingerprint: identity.fingerprint() };
+                let server = options.config.borrow().discovery.dns_server;
+                let line = dns_announcement(domain, &advertisement, server).await;
+                match options.ui.format {
+                    ui::OutputFormat::Text => println!("{}", line),
+                    ui::OutputFormat::Jsonl => eprintln!("{}", line),
+                }
+            }
+            None => tracing::warn!(domain = %domain, "公開するアドレスが分からないため、TXTレコードを表示できません"),
+        }
+    }
+
+    // 2. TLSサーバー設定
+    let tls_acceptor = tls::acceptor(&identity, tls)?;
+    let mut sessions = match webtransport {
+        true => Some(listen_webtransport(addr, &identity, limits, &options)?),
+        false => None,
+    };
+
+    // 3. 接続の待受を開始
+    if !options.quiet {
+        let host = public_host.clone().unwrap_or_else(|| addr.ip().to_string());
+        if web {
+            println!("{}", Msg::WebUrl.with(&[&host, &addr.port()]));
+        }
+        if webtransport {
+            println!("{}", Msg::WebTransportUrl.with(&[&host, &addr.port(), &identity.fingerprint()]));
+        }
+        println!("{}", Msg::Listening);
+    }
+    tracing::info!(%addr, fingerprint = %identity.fingerprint(), "接続待受を開始しました");
+    systemd::ready(&format!("{} で接続を待ち受けています", addr));
+    let set_health = |state| {
+        if let Some(health) = &health {
+            health.set_listener(state);
+        }
+    };
+    set_health(ListenerState::Listening);
+
+    #[cfg(not(feature = "mdns"))]
+    let _ = &announce;
+
+    // 4. 接続を受け付け、処理する。相手が切断しても、同じ証明書のまま次の接続を待ち受ける
+    loop {
+        // 接続を待っている間だけ、LANの相手の discover に表示されるようにする
+        #[cfg(feature = "mdns")]
+        let announcement = announce.as_ref().and_then(|name| {
+            crate::discovery::mdns::Announcement::register(addr, name, &identity.fingerprint())
+                .inspect_err(|e| tracing::warn!(error = %e, "mDNSで待受を知らせられませんでした"))
+                .ok()
+                .flatten()
+        });
+        let Some((incoming, peer_addr)) = next_peer(&mut *listener, &tls_acceptor, limits, &options, web, sessions.as_mut()).await? else {
+            // 接続を待っている間に取り消された (Ctrl+C) 場合は、そのまま正常に終了する
+            tracing::info!("待受を終了します");
+            set_health(ListenerState::Stopped);
+            return Ok(());
+        };
+        // 同時に受け付けるのは1つの接続だけなので、切断されるまで次の接続を受け付けられない状態にする
+        set_health(ListenerState::Connected);
+        #[cfg(feature = "mdns")]
+        drop(announcement);
+        if !options.quiet {
+            println!("{}", Msg::ClientConnected.with(&[&peer_addr]));
+        }
+        systemd::status(&format!("{} と接続しています", peer_addr));
+
+        // 連絡先の設定は接続ごとに適用するため、待受の設定は複製して渡す
+        let mut connection = options.clone();
+        let span = tracing::info_span!("connection", peer = %peer_addr);
+        let reason = async {
+            let ws_stream = match incoming.accept(peer_addr, &tls_acceptor, &limits, paths, &mut connection).await {
+                Ok(ws_stream) => ws_stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, "接続を確立できませんでした");
+                    return None;
+                }
+            };
+            // クライアントは再接続のたびに送信元ポートが変わるため、IPアドレスで相手を識別する
+            let reason = handle_connection(ws_stream, &peer_addr.ip().to_string(), &mut history, Role::Listener, paths, connection, None).await;
+            tracing::info!(?reason, "接続が終了しました");
+            Some(reason)
+        }
+        .instrument(span)
+        .await;
+
+        // こちらから終了した (/quit・入力の終わり・Ctrl+C) 場合は待受も終える
+        if options.shutdown.is_cancelled() || matches!(reason, Some(DisconnectReason::Closed | DisconnectReason::InputClosed)) {
+            tracing::info!("待受を終了します");
+            set_health(ListenerState::Stopped);
+            return Ok(());
+        }
+        set_health(ListenerState::Listening);
+        tracing::info!(%addr, "次の接続を待ち受けます");
+        if !options.quiet {
+            println!("{}", Msg::Listening);
+        }
+        systemd::status(&format!("{} で接続を待ち受けています", addr));
+    }
+}
+
+// 待受で受け付けた接続
+pub(crate) enum Incoming {
+    /// TLSとWebSocketのハンドシェイクはこれから行う
+    Tcp(BoxStream),
+    /// --web・--webtransport でハンドシェイクを済ませた接続 (平文のws://ならTLSの内容はNone)
+    Web(Box<ServerStream>, Option<debug::Negotiated>),
+}
+
+// --web・--webtransport でハンドシェイクを済ませた接続と、相手のアドレス
+pub(crate) type Upgraded = (ServerStream, Option<debug::Negotiated>, SocketAddr);
+
+// --webtransport: 待受と同じポートのUDPでWebTransportのセッションを受け付け始める
+#[cfg(feature = "webtransport")]
+pub(crate) fn listen_webtransport(
+    addr: SocketAddr,
+    identity: &identity::Identity,
+    limits: Limits,
+    options: &ChatOptions,
+) -> Result<tokio::sync::mpsc::UnboundedReceiver<Upgraded>, Box<dyn std::error::Error>> {
+    crate::webtransport::listen(addr, identity, limits, options)
+}
+
+// webtransport フィーチャーを含めずにビルドした場合は待ち受けられない
+#[cfg(not(feature = "webtransport"))]
+pub(crate) fn listen_webtransport(
+    _addr: SocketAddr,
+    _identity: &identity::Identity,
+    _limits: Limits,
+    _options: &ChatOptions,
+) -> Result<tokio::sync::mpsc::UnboundedReceiver<Upgraded>, Box<dyn std::error::Error>> {
+    Err("webtransport フィーチャーを含めずにビルドされているため、WebTransportでは待ち受けられません".into())
+}
+
+impl Incoming {
+    // ハンドシェイクを済ませ (Tcp)、連絡先の設定をoptionsに適用する
+    pub(crate) async fn accept(
+        self,
+        peer_addr: SocketAddr,
+        tls_acceptor: &tokio_rustls::TlsAcceptor,
+        limits: &Limits,
+        paths: &Paths,
+        options: &mut ChatOptions,
+    ) -> Result<ServerStream, Box<dyn std::error::Error>> {
+        match self {
+            Incoming::Tcp(stream) => accept_peer(stream, peer_addr, tls_acceptor, limits, paths, options).await,
+            Incoming::Web(ws_stream, negotiated) => {
+                options.negotiated = negotiated;
+                established(peer_addr, paths, options);
+                Ok(*ws_stream)
+            }
+        }
+    }
+}
+
+// 許可された相手からの接続を待つ (設定で許可されていない相手からの接続は閉じて待受を続ける)。取り消された場合はNone
+// webでは接続ごとにページの応答とハンドシェイクを並行して行い、最初にチャットを始めた (WebSocketに切り替えた) 接続を返す
+// sessions (--webtransport) からハンドシェイクを済ませた接続が先に届けば、それを返す
+pub(crate) async fn next_peer(
+    listener: &mut dyn Listener,
+    tls_acceptor: &tokio_rustls::TlsAcceptor,
+    limits: Limits,
+    options: &ChatOptions,
+    web: bool,
+    mut sessions: Option<&mut tokio::sync::mpsc::UnboundedReceiver<Upgraded>>,
+) -> std::io::Result<Option<(Incoming, SocketAddr)>> {
+    let (upgraded_tx, mut upgraded) = tokio::sync::mpsc::unbounded_channel::<Upgraded>();
+    loop {
+        let session = async {
+            match sessions.as_deref_mut() {
+                Some(sessions) => sessions.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            Some((ws_stream, negotiated, peer_addr)) = upgraded.recv() => return Ok(Some((Incoming::Web(Box::new(ws_stream), negotiated), peer_addr))),
+            Some((ws_stream, negotiated, peer_addr)) = session => return Ok(Some((Incoming::Web(Box::new(ws_stream), negotiated), peer_addr))),
+            _ = options.shutdown.cancelled() => return Ok(None),
+        };
+        if !options.config.borrow().access.permits(peer_addr.ip()) {
+            tracing::warn!(security = true, peer = %peer_addr, "許可されていない相手からの接続を拒否しました");
+            continue;
+        }
+        if !web {
+            return Ok(Some((Incoming::Tcp(stream), peer_addr)));
+        }
+        let (tls_acceptor, runtime, upgraded_tx) = (tls_acceptor.clone(), options.runtime.clone(), upgraded_tx.clone());
+        let span = tracing::info_span!("connection", peer = %peer_addr);
+        options.runtime.spawn(
+            async move {
+                match webui::accept(stream, &tls_acceptor, &limits, &runtime).await {
+                    Ok(Some((ws_stream, negotiated))) => {
+                        let _ = upgraded_tx.send((ws_stream, negotiated, peer_addr));
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!(error = %e, "ブラウザからの接続を処理できませんでした"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+pub type ServerStream = tokio_tungstenite::WebSocketStream<BoxStream>;
+
+// 受け付けた接続でTLSとWebSocketのハンドシェイクを行う
+// アドレス帳にこの相手のアドレスがあれば、その連絡先の設定をoptionsに適用する
+pub(crate) async fn accept_peer(
+    stream: BoxStream,
+    peer_addr: SocketAddr,
+    tls_acceptor: &tokio_rustls::TlsAcceptor,
+    limits: &Limits,
+    paths: &Paths,
+    options: &mut ChatOptions,
+) -> Result<ServerStream, Box<dyn std::error::Error>> {
+    tracing::info!("TCP接続を受け付けました");
+
+    // 接続だけして何も送らない相手で待受が止まらないよう、接続する側と同じ時間で打ち切る
+    let tls_stream = staged(options, ConnectStage::Tls, tls_acceptor.accept(stream).instrument(tracing::info_span!("tls"))).await?.inspect_err(|e| {
+        tracing::error!(error = %e, "TLSハンドシェイクに失敗しました");
+    })?;
+    options.negotiated = Some(debug::Negotiated::from_connection(tls_stream.get_ref().1));
+    let tls_stream: BoxStream = Box::new(tls_stream);
+
+    // 5. WebSocketハンドシェイク
+    let handshake = tokio_tungstenite::accept_async_with_config(tls_stream, limits.websocket_config());
+    let ws_stream = staged(options, ConnectStage::WebSocket, handshake.instrument(tracing::info_span!("websocket"))).await?.inspect_err(|e| {
+        tracing::error!(error = %e, "WebSocketハンドシェイクに失敗しました");
+    })?;
+    established(peer_addr, paths, options);
+    Ok(ws_stream)
+}
+
+// WebSocket接続が確立したことを知らせ、アドレス帳にこの相手のアドレスがあればその連絡先の設定をoptionsに適用する
+fn established(peer_addr: SocketAddr, paths: &Paths, options: &mut ChatOptions) {
+    if !options.quiet {
+        println!("{}", Msg::WebSocketEstablished);
+    }
+    tracing::info!("WebSocket接続が確立しました");
+
+    match AddressBook::load(paths.contacts_file()) {
+        Ok(book) => {
+            if let Some((name, contact)) = book.find_by_host(&peer_addr.ip().to_string()) {
+                tracing::info!(contact = %name, "連絡先の設定を適用します");
+                options.apply_contact(contact);
+            }
+        }
+        Err(e) => tracing::warn!(error = %e, "アドレス帳の読み込みに失敗しました"),
+    }
+}
+
+// 待受アドレスと、外部から接続してもらうためのURL・ポート開放の手順を表示する
+// 相手に伝えるホスト (ループバックで待ち受けていればそのアドレス、なければグローバル、ローカルの順) を返す
+async fn print_server_banner(addr: SocketAddr, discovery: &DiscoveryConfig, shutdown: &CancellationToken) -> Option<String> {
+    println!("{}", Msg::ServerStarting.with(&[&addr]));
+
+    // ローカルIPアドレスを取得して表示
+    let local_ip = Resolver::local(discovery).lookup(shutdown).await.ok().map(|ip| ip.to_string());
+    if let Some(local_ip) = &local_ip {
+        println!("{}", Msg::LocalIp.with(&[local_ip]));
+        println!("{}", Msg::LocalUrl.with(&[local_ip, &addr.port()]));
+    }
+
+    // グローバルIPアドレスを取得して表示
+    println!("{}", Msg::FetchingGlobalIp);
+    match Resolver::global(discovery).lookup(shutdown).await {
+        Ok(global_ip) => {
+            let global_ip = global_ip.to_string();
+            println!("{}", Msg::GlobalIp.with(&[&global_ip]));
+            let port = addr.port();
+            println!("{}", Msg::ExternalUrl.with(&[&global_ip, &port]));
+            println!("{}", Msg::PortForwardNote);
+            println!("{}", Msg::PortForwardFirewall.with(&[&port]));
+            let router_ip = local_ip.as_deref().unwrap_or("LOCAL_IP");
+            println!("{}", Msg::PortForwardRouter.with(&[&port, &router_ip, &port]));
+            println!("{}", Msg::PortForwardIsp.with(&[&port]));
+            if !addr.ip().is_loopback() {
+                return Some(global_ip);
+            }
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "グローバルIPアドレスの取得に失敗しました");
+            println!("{}", Msg::LocalOnly);
+        }
+    }
+    match addr.ip() {
+        ip if ip.is_loopback() => Some(ip.to_string()),
+        _ => local_ip,
+    }
+}
+
+// 接続用のURL (証明書のフィンガープリント付き) をQRコードで表示し、別の端末から打ち間違えずに読み取れるようにする
+#[cfg(feature = "qr")]
+fn print_connect_qr(uri: &str) {
+    let code = match qrcode::QrCode::new(uri.as_bytes()) {
+        Ok(code) => code,
+        Err(e) => {
+            tracing::warn!(error = %e, "QRコードを作成できませんでした");
+            return;
+        }
+    };
+    // 暗い背景の端末でも読み取れるよう、明暗を反転して描く
+    let image = code
+        .render::<qrcode::render::unicode::Dense1x2>()
+        .dark_color(qrcode::render::unicode::Dense1x2::Light)
+        .light_color(qrcode::render::unicode::Dense1x2::Dark)
+        .build();
+    println!("{}", Msg::ConnectQr.with(&[&uri]));
+    println!("{}", image);
+}
+
+// qr フィーチャーを含めずにビルドした場合は表示しない
+#[cfg(not(feature = "qr"))]
+fn print_connect_qr(_uri: &str) {}
+
+// --dns-name の案内。公開済みのTXTレコードが今の待受と一致すればそう伝え、なければ登録する内容を示す
+async fn dns_announcement(domain: &str, advertisement: &dns::Advertisement, server: Option<SocketAddr>) -> String {
+    let published = match server.or_else(dns::system_server) {
+        Some(server) => dns::lookup(server, domain).await.map_err(|e| tracing::info!(domain, error = %e, "公開済みのTXTレコードはありません")).ok(),
+        None => None,
+    };
+    match published {
+        Some(published) if &published == advertisement => Msg::DnsRecordPublished.with(&[&domain]),
+        Some(_) => Msg::DnsRecordOutdated.with(&[&dns::record_name(domain), &advertisement.zone_line(domain)]),
+        None => Msg::DnsRecord.with(&[&domain, &advertisement.zone_line(domain)]),
+    }
+}
+
+// クライアント側の処理
+pub async fn run_client(settings: ClientSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let span = tracing::info_span!("connection", peer = %settings.uri);
+    connect_and_chat(settings).instrument(span).await
+}
+
+async fn connect_and_chat(settings: ClientSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let mut history = open_history(&settings.paths)?;
+    let (ws_stream, addr, negotiated) = connect(&settings, settings.options.quiet).await?;
+    let ClientSettings { paths, mut options, .. } = settings;
+    options.negotiated = Some(negotiated);
+
+    handle_connection(ws_stream, &addr, &mut history, Role::Client, &paths, options, None).await;
+
+    Ok(())
+}
+
+// 接続の失敗の種類 (exit::Failure で終了コードとJSONのkindになる)
+#[derive(Debug)]
+pub enum ConnectError {
+    /// 接続先の名前を解決できなかった
+    Dns(String),
+    /// 相手が待ち受けていない・ファイアウォールで拒否された
+    Refused(String),
+    /// その他のTCP接続の失敗 (経路がない・時間切れ・プロキシの失敗)
+    Unreachable(String),
+    Tls(String),
+    /// 相手の証明書が連絡先・known_peers・URLのフィンガープリントと一致しない、または相手に接続を断られた
+    Rejected(String),
+    /// stageが設定した時間 (after) のうちに終わらなかった (相手に届かないアドレスなど)
+    Timeout { stage: ConnectStage, after: Duration },
+    Cancelled,
+}
+
+// 接続の段階 (時間切れになった場所を示す)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectStage {
+    Tcp,
+    Tls,
+    WebSocket,
+}
+
+impl ConnectStage {
+    // この段階を待つ時間を決める設定 (config.toml の [connection])
+    fn setting(self) -> &'static str {
+        match self {
+            ConnectStage::Tcp => "connect_timeout_secs",
+            ConnectStage::Tls => "tls_timeout_secs",
+            ConnectStage::WebSocket => "handshake_timeout_secs",
+        }
+    }
+
+    // 0秒の設定は時間切れにしない
+    fn timeout(self, config: &ConnectionConfig) -> Option<Duration> {
+        let secs = match self {
+            ConnectStage::Tcp => config.connect_timeout_secs,
+            ConnectStage::Tls => config.tls_timeout_secs,
+            ConnectStage::WebSocket => config.handshake_timeout_secs,
+        };
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+}
+
+impl std::fmt::Display for ConnectStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConnectStage::Tcp => "TCP接続",
+            ConnectStage::Tls => "TLSハンドシェイク",
+            ConnectStage::WebSocket => "WebSocketハンドシェイク",
+        })
+    }
+}
+
+impl ConnectError {
+    // TCP接続 (プロキシ経由を含む) の失敗を種類に分ける
+    fn from_tcp(error: Box<dyn std::error::Error>) -> Self {
+        let Some(io) = error.downcast_ref::<std::io::Error>() else {
+            return ConnectError::Unreachable(error.to_string());
+        };
+        if io.get_ref().is_some_and(|inner| inner.is::<ResolveError>()) {
+            return ConnectError::Dns(error.to_string());
+        }
+        match io.kind() {
+            std::io::ErrorKind::ConnectionRefused => ConnectError::Refused(error.to_string()),
+            _ => ConnectError::Unreachable(error.to_string()),
+        }
+    }
+
+    pub fn failure(&self) -> exit::Failure {
+        let (code, kind) = match self {
+            ConnectError::Dns(_) => (exit::DNS, "dns"),
+            ConnectError::Refused(_) => (exit::CONNECTION_REFUSED, "connection_refused"),
+            ConnectError::Unreachable(_) => (exit::UNREACHABLE, "unreachable"),
+            ConnectError::Tls(_) => (exit::TLS, "tls"),
+            ConnectError::Rejected(_) => (exit::REJECTED, "rejected"),
+            ConnectError::Timeout { .. } => (exit::TIMEOUT, "timeout"),
+            ConnectError::Cancelled => (exit::CANCELLED, "cancelled"),
+        };
+        exit::Failure { code, kind }
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Dns(reason) | ConnectError::Refused(reason) | ConnectError::Unreachable(reason) | ConnectError::Rejected(reason) => {
+                f.write_str(reason)
+            }
+            ConnectError::Tls(reason) => write!(f, "TLSハンドシェイクに失敗しました: {}", reason),
+            ConnectError::Timeout { stage, after } => {
+                write!(f, "{}が{}秒以内に終わりませんでした (config.toml の [connection] {})", stage, after.as_secs(), stage.setting())
+            }
+            ConnectError::Cancelled => f.write_str("接続を中止しました"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+pub type ClientStream = tokio_tungstenite::WebSocketStream<BoxStream>;
+
+// サーバーに接続し、証明書を照合してWebSocketのハンドシェイクまで行う。履歴上で相手を識別する名前 (host:port) も返す
+pub(crate) async fn connect(
+    settings: &ClientSettings,
+    quiet: bool,
+) -> Result<(ClientStream, String, debug::Negotiated), Box<dyn std::error::Error>> {
+    let uri = settings.uri.as_str();
+    if !quiet {
+        println!("{}", Msg::Connecting.with(&[&uri]));
+    }
+
+    let url = url::Url::parse(uri)?;
+    let host = url.host_str().ok_or("URIにホスト名がありません")?;
+    let port = url.port().unwrap_or(8080);
+    let addr = format!("{}:{}", host, port);
+
+    // 1. TCP接続とTLSハンドシェイク (https:// ではWebTransportのセッションと最初のストリーム)
+    let (stream, negotiated, peer_cert) = connect_secure(settings, &url).await?;
+
+    // 2. QRコードなどで渡されたURLの末尾 (#sha256:...) のフィンガープリントは、一致しなければ接続しない
+    let fingerprint = identity::fingerprint(&peer_cert);
+    if let Some(pinned) = url.fragment().filter(|pinned| !pinned.is_empty()) {
+        if pinned != fingerprint {
+            tracing::warn!(security = true, pinned, fingerprint, "URLのフィンガープリントと一致しない相手への接続を拒否しました");
+            return Err(ConnectError::Rejected(Msg::PinnedMismatch.with(&[&pinned, &fingerprint])).into());
+        }
+    }
+    let webhooks = Webhooks::new(settings.options.config.clone(), settings.options.runtime.clone());
+    tls::verify_peer_fingerprint(&addr, &fingerprint, settings.strict, settings.contact.clone(), &settings.paths.known_peers_file(), &webhooks)?;
+
+    // 3. WebSocketハンドシェイク
+    let (ws_stream, _) = websocket_handshake(settings, &url, stream).await?;
+    if !quiet {
+        println!("{}", Msg::WebSocketEstablished);
+    }
+    tracing::info!("WebSocket接続が確立しました");
+
+    Ok((ws_stream, addr, negotiated))
+}
+
+// TCP接続とTLSのハンドシェイク、https:// ではWebTransportのセッションを確立する
+// 通信路と、TLSの内容・相手の証明書を返す (サーバー証明書はここでは検証しない)
+pub(crate) async fn connect_secure(
+    settings: &ClientSettings,
+    url: &url::Url,
+) -> Result<(BoxStream, debug::Negotiated, Vec<u8>), Box<dyn std::error::Error>> {
+    let host = url.host_str().ok_or("URIにホスト名がありません")?;
+    let port = url.port().unwrap_or(8080);
+    Ok(match url.scheme() {
+        #[cfg(feature = "webtransport")]
+        "https" => staged(&settings.options, ConnectStage::Tls, crate::webtransport::connect(host, port).instrument(tracing::info_span!("webtransport"))).await??,
+        _ => connect_tls(settings, host, port).await?,
+    })
+}
+
+// WebSocketのハンドシェイク (フィンガープリントの部分は送らない。WebTransportのストリームの上でもwss://として行う)
+pub(crate) async fn websocket_handshake(
+    settings: &ClientSettings,
+    url: &url::Url,
+    stream: BoxStream,
+) -> Result<(ClientStream, tokio_tungstenite::tungstenite::handshake::client::Response), Box<dyn std::error::Error>> {
+    let mut request_url = url.clone();
+    request_url.set_fragment(None);
+    let _ = request_url.set_scheme("wss");
+    let handshake = tokio_tungstenite::client_async_with_config(request_url.as_str(), stream, settings.limits.websocket_config());
+    staged(&settings.options, ConnectStage::WebSocket, handshake.instrument(tracing::info_span!("websocket"))).await?.map_err(|e| {
+        tracing::error!(error = %e, "WebSocketハンドシェイクに失敗しました");
+        // HTTPの応答で断られた場合 (101以外)
+        match e {
+            tokio_tungstenite::tungstenite::Error::Http(response) => {
+                Box::new(ConnectError::Rejected(format!("相手に接続を断られました (HTTP {})", response.status()))) as Box<dyn std::error::Error>
+            }
+            e => e.into(),
+        }
+    })
+}
+
+// TCPで接続してTLSのハンドシェイクを行い、TLSの内容と相手の証明書も返す (サーバー証明書はここでは検証しない)
+async fn connect_tls(settings: &ClientSettings, host: &str, port: u16) -> Result<(BoxStream, debug::Negotiated, Vec<u8>), Box<dyn std::error::Error>> {
+    let connector = tls::connector(settings.tls);
+    let stream = connect_tcp(settings, host, port).await?;
+    let domain = rustls::pki_types::ServerName::try_from(host)?.to_owned();
+    let tls_stream = staged(&settings.options, ConnectStage::Tls, connector.connect(domain, stream).instrument(tracing::info_span!("tls"))).await?.map_err(|e| {
+        tracing::error!(error = %e, "TLSハンドシェイクに失敗しました");
+        ConnectError::Tls(e.to_string())
+    })?;
+    let negotiated = debug::Negotiated::from_connection(tls_stream.get_ref().1);
+
+    let peer_cert = tls_stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or("サーバーから証明書が提示されませんでした")?
+        .to_vec();
+    Ok((Box::new(tls_stream), negotiated, peer_cert))
+}
+
+// TCP接続 (プロキシがあれば経由する)。失敗したら再試行の設定に従って待ってから繰り返す
+async fn connect_tcp(settings: &ClientSettings, host: &str, port: u16) -> Result<BoxStream, ConnectError> {
+    let runtime = &settings.options.runtime;
+    let mut attempt = 1;
+    loop {
+        tracing::info!(host, port, attempt, proxy = ?settings.proxy, "TCP接続を開始します");
+        // 名前解決 (dns) はこのスパンの中に含まれる
+        let span = tracing::info_span!("tcp", attempt);
+        let connecting = async {
+            match &settings.proxy {
+                Some(proxy) => proxy.connect(host, port).instrument(span).await.map_err(ConnectError::from_tcp),
+                None => runtime.connect(host, port).instrument(span).await.map_err(|e| ConnectError::from_tcp(e.into())),
+            }
+        };
+        // 時間切れも他の失敗と同じく再試行する
+        let connected = staged(&settings.options, ConnectStage::Tcp, connecting).await.and_then(|connected| connected);
+        let delay = match connected {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt < settings.reconnect.attempts => {
+                let delay = settings.reconnect.delay(attempt);
+                tracing::warn!(error = %e, ?delay, "TCP接続に失敗したため、待ってから再試行します");
+                delay
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "TCP接続に失敗しました");
+                return Err(e);
+            }
+        };
+        tokio::select! {
+            _ = runtime.sleep(delay) => attempt += 1,
+            _ = settings.options.shutdown.cancelled() => return Err(ConnectError::Cancelled),
+        }
+    }
+}
+
+// 接続の1つの段階を、設定した時間だけ待つ。間に合わなければConnectError::Timeoutにする
+async fn staged<F: std::future::Future>(options: &ChatOptions, stage: ConnectStage, future: F) -> Result<F::Output, ConnectError> {
+    let Some(after) = stage.timeout(&options.config.borrow().connection) else {
+        return Ok(future.await);
+    };
+    options.runtime.timeout(after, future).await.map_err(|_| {
+        tracing::error!(%stage, ?after, "接続の途中で時間切れになりました");
+        ConnectError::Timeout { stage, after }
+    })
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// known_peersの1行分
+#[derive(Debug, Clone)]
+pub struct KnownPeer {
+    pub fingerprint: String,
+    /// 対応するアドレス帳の名前
+    pub contact: Option<String>,
+}
+
+// 照合結果
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrustStatus {
+    Trusted,
+    Unknown,
+    Changed { expected: String },
+}
+
+// `host:port フィンガープリント [連絡先名]` を1行ずつ記録するテキストファイル
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: BTreeMap<String, KnownPeer>,
+}
+
+impl KnownPeers {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        let mut peers = BTreeMap::new();
+        if path.exists() {
+            for (index, line) in std::fs::read_to_string(&path)?.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split_whitespace();
+                let (Some(host), Some(fingerprint)) = (fields.next(), fields.next()) else {
+                    return Err(format!("{}:{} の形式が正しくありません", path.display(), index + 1).into());
+                };
+                peers.insert(
+                    host.to_string(),
+                    KnownPeer {
+                        fingerprint: fingerprint.to_string(),
+                        contact: fields.next().map(str::to_string),
+                    },
+                );
+            }
+        }
+        Ok(Self { path, peers })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut out = String::new();
+        for (host, peer) in &self.peers {
+            out.push_str(host);
+            out.push(' ');
+            out.push_str(&peer.fingerprint);
+            if let Some(contact) = &peer.contact {
+                out.push(' ');
+                out.push_str(contact);
+            }
+            out.push('\n');
+        }
+        std::fs::write(&self.path, out)?;
+        Ok(())
+    }
+
+    pub fn check(&self, host: &str, fingerprint: &str) -> TrustStatus {
+        match self.peers.get(host) {
+            None => TrustStatus::Unknown,
+            Some(peer) if peer.fingerprint == fingerprint => TrustStatus::Trusted,
+            Some(peer) => TrustStatus::Changed {
+                expected: peer.fingerprint.clone(),
+            },
+        }
+    }
+
+    pub fn insert(&mut self, host: &str, fingerprint: &str, contact: Option<String>) {
+        self.peers.insert(
+            host.to_string(),
+            KnownPeer {
+                fingerprint: fingerprint.to_string(),
+                contact,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, host: &str) -> Option<KnownPeer> {
+        self.peers.remove(host)
+    }
+
+    // いずれかの相手のフィンガープリントとして記録されているか
+    pub fn contains_fingerprint(&self, fingerprint: &str) -> bool {
+        self.peers.values().any(|peer| peer.fingerprint == fingerprint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &KnownPeer)> {
+        self.peers.iter()
+    }
+}
+
+// wss://host:port 形式のURIから known_peers のキー (host:port) を作る
+pub fn host_key(uri: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if !uri.contains("://") {
+        return Ok(uri.to_string());
+    }
+    let url = url::Url::parse(uri)?;
+    let host = url.host_str().ok_or("URIにホスト名がありません")?;
+    Ok(format!("{}:{}", host, url.port().unwrap_or(8080)))
+}
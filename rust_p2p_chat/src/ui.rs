@@ -0,0 +1,1537 @@
+use chrono::{DateTime, Local};
+#[cfg(feature = "tui")]
+use ratatui::crossterm::event::{
+    self, DisableBracketedPaste, DisableFocusChange, EnableBracketedPaste, EnableFocusChange, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+    KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+#[cfg(feature = "tui")]
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::Color;
+#[cfg(feature = "tui")]
+use ratatui::style::{Modifier, Style};
+#[cfg(feature = "tui")]
+use ratatui::text::{Line, Span};
+#[cfg(feature = "tui")]
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+#[cfg(feature = "tui")]
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use rustyline::error::ReadlineError;
+use rustyline::history::{FileHistory, History};
+#[cfg(feature = "tui")]
+use rustyline::history::SearchDirection;
+use rustyline::ExternalPrinter;
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::mpsc as std_mpsc;
+#[cfg(feature = "tui")]
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+#[cfg(feature = "tui")]
+use unicode_width::UnicodeWidthChar;
+
+use crate::commands;
+use crate::config::{AlertsConfig, Clock, UiConfig};
+#[cfg(feature = "tui")]
+use crate::latency;
+use crate::history::{Direction, EventKind, HistoryEntry};
+use crate::i18n::Msg;
+use crate::theme::{self, Theme};
+
+// plainの /more で一度に表示する件数
+const MORE_PAGE: usize = 20;
+// 入力履歴に残す行数
+const INPUT_HISTORY_SIZE: usize = 1000;
+// plainで端末から入力するときのプロンプト
+const PROMPT: &str = "> ";
+// 入力欄で改行の代わりに表示する記号
+#[cfg(feature = "tui")]
+const NEWLINE_MARK: char = '↵';
+// キー入力を待つ間隔 (この間隔で受信したメッセージを画面に反映する)
+#[cfg(feature = "tui")]
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// チャット画面の表示方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UiMode {
+    /// 標準入力と標準出力が端末ならTUI、そうでなければplain
+    Auto,
+    /// メッセージ欄・入力欄・参加者一覧・ステータスバーを持つ全画面表示
+    Tui,
+    /// 1行ずつ標準出力に書き出す (パイプやスクリプトから使う場合)
+    Plain,
+}
+
+// 標準入出力の形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// 人が読むための表示 (--ui に従う)
+    Text,
+    /// 出来事を1行に1つのJSONで書き出し、標準入力からもJSONのコマンドを受け付ける (他のプログラムから使う場合)
+    Jsonl,
+}
+
+// --format jsonl で1行ずつ書き出す出来事
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    at: DateTime<Local>,
+    /// デーモンでの部屋の名前
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<&'a str>,
+    #[serde(flatten)]
+    event: JsonEvent<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    /// 相手のメッセージ。seqは相手側の通し番号
+    Message {
+        from: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        body: &'a str,
+    },
+    /// 自分が送信したメッセージ
+    Sent {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        body: &'a str,
+    },
+    /// 自分のメッセージが相手に届いた
+    Delivered { seq: u64 },
+    /// ファイルの送受信の進み具合 (bytesまで送った・受け取った)
+    Progress {
+        id: u64,
+        name: &'a str,
+        direction: Direction,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total: Option<u64>,
+    },
+    Connected { peer: &'a str },
+    Disconnected { peer: &'a str },
+    Info { text: &'a str },
+    Warning { text: &'a str },
+    /// 入力を処理できなかった、または接続に失敗した
+    Error { message: &'a str },
+}
+
+impl JsonEvent<'_> {
+    fn encode(self, at: DateTime<Local>, room: Option<&str>) -> String {
+        // JsonLineは常にJSONに変換できる
+        serde_json::to_string(&JsonLine { at, room, event: self }).expect("JSONへの変換に失敗しました")
+    }
+
+    fn print(self, at: DateTime<Local>) {
+        let line = self.encode(at, None);
+        let mut stdout = std::io::stdout().lock();
+        // 読み取る側が1行ずつ処理できるよう、毎回flushする
+        let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+    }
+}
+
+// --format jsonl で、チャットを始める前に起きたエラーを書き出す
+pub fn print_json_error(message: &str) {
+    JsonEvent::Error { message }.print(Local::now());
+}
+
+// デーモンで使う、端末を持たないチャット画面。出来事はJSONにしてeventsに送り、入力はinputから受け取る
+pub struct Headless {
+    pub room: String,
+    /// 出来事の1行は購読しているもの (ctl events・gRPC・プッシュ通知) の間で共有し、それぞれに写さない
+    pub events: broadcast::Sender<Arc<str>>,
+    pub input: mpsc::UnboundedReceiver<String>,
+}
+
+// チャット画面の設定
+#[derive(Debug, Clone)]
+pub struct UiOptions {
+    pub mode: UiMode,
+    pub format: OutputFormat,
+    /// 色を付ける (--no-color と NO_COLOR を反映済み)
+    pub color: bool,
+    /// 入力した行の履歴の保存先 (Noneの場合は保存しない)
+    pub input_history: Option<PathBuf>,
+    /// 表示件数と表示形式 (config.toml の [ui])
+    pub settings: UiConfig,
+    /// Tabで補完する連絡先の名前とニックネーム
+    pub contacts: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum RecordKind {
+    Remote { from: String },
+    Own,
+    Info,
+    Warning,
+}
+
+// メッセージ欄の1件
+#[derive(Debug, Clone)]
+struct Record {
+    at: DateTime<Local>,
+    kind: RecordKind,
+    text: String,
+    /// メッセージの通し番号 (相手のメッセージは相手側の番号)
+    id: Option<u64>,
+    /// 自分のメッセージの配送状況 (Some(true)は相手に届いた。以前のメッセージはNone)
+    delivery: Option<bool>,
+}
+
+impl Record {
+    fn new(kind: RecordKind, text: String) -> Self {
+        Self { at: Local::now(), kind, text, id: None, delivery: None }
+    }
+}
+
+// 表示形式のテンプレートの要素
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Text(String),
+    Time,
+    Nick,
+    Body,
+    Id,
+    Ticks,
+}
+
+// 表示する1行の部分ごとの種類 (plainとTUIで装飾を付けるために使う)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Text,
+    Time,
+    Nick,
+    Body,
+    Meta,
+}
+
+// 表示形式が設定されていない場合の形式
+#[cfg(feature = "tui")]
+const TUI_FORMAT: &str = "{time} {id}{nick}: {body}{ticks}";
+const PLAIN_FORMAT: &str = "{id}{nick}: {body}{ticks}";
+// plainで再送されたメッセージや /more のように、時刻を付けて表示する場合の形式
+const PLAIN_TIMED_FORMAT: &str = "[{time}] {id}{nick}: {body}{ticks}";
+
+// config.toml の [ui] に従ってメッセージを表示する形に変換する (plainとTUIで共通)
+#[derive(Debug, Clone)]
+struct Renderer {
+    format: Option<Vec<Token>>,
+    clock: Clock,
+    show_ids: bool,
+    ticks: bool,
+}
+
+impl Renderer {
+    fn new(settings: &UiConfig) -> Self {
+        Self {
+            format: settings.format.as_deref().map(parse_format),
+            clock: settings.clock,
+            show_ids: settings.show_ids,
+            ticks: settings.ticks,
+        }
+    }
+
+    // 今日のものは時刻のみ、それ以前は日付も付ける
+    fn time(&self, at: DateTime<Local>) -> String {
+        let time = match self.clock {
+            Clock::H24 => "%H:%M",
+            Clock::H12 => "%I:%M %p",
+        };
+        if at.date_naive() == Local::now().date_naive() {
+            at.format(time).to_string()
+        } else {
+            at.format(&format!("%m/%d {}", time)).to_string()
+        }
+    }
+
+    fn message(&self, record: &Record, nick: &str, default: &str) -> Vec<(Part, String)> {
+        let parsed;
+        let tokens = match &self.format {
+            Some(tokens) => tokens,
+            None => {
+                parsed = parse_format(default);
+                &parsed
+            }
+        };
+        tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Text(text) => Some((Part::Text, text.clone())),
+                Token::Time => Some((Part::Time, self.time(record.at))),
+                Token::Nick => Some((Part::Nick, nick.to_string())),
+                Token::Body => Some((Part::Body, record.text.clone())),
+                Token::Id => match (self.show_ids, record.id) {
+                    (true, Some(id)) => Some((Part::Meta, format!("#{} ", id))),
+                    _ => None,
+                },
+                Token::Ticks => match (self.ticks, record.delivery) {
+                    (true, Some(true)) => Some((Part::Meta, " ✓✓".to_string())),
+                    (true, Some(false)) => Some((Part::Meta, " ✓".to_string())),
+                    _ => None,
+                },
+            })
+            .collect()
+    }
+}
+
+// {time} などの置き換える部分とそれ以外に分ける。知らない {...} はそのまま表示する
+fn parse_format(format: &str) -> Vec<Token> {
+    const PLACEHOLDERS: [&str; 5] = ["{time}", "{nick}", "{body}", "{id}", "{ticks}"];
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = format;
+    while let Some(open) = rest.find('{') {
+        text.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let Some(placeholder) = PLACEHOLDERS.into_iter().find(|p| rest.starts_with(p)) else {
+            text.push('{');
+            rest = &rest[1..];
+            continue;
+        };
+        if !text.is_empty() {
+            tokens.push(Token::Text(std::mem::take(&mut text)));
+        }
+        tokens.push(match placeholder {
+            "{time}" => Token::Time,
+            "{nick}" => Token::Nick,
+            "{body}" => Token::Body,
+            "{id}" => Token::Id,
+            _ => Token::Ticks,
+        });
+        rest = &rest[placeholder.len()..];
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+// 相手の証明書の確認状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// 相手の証明書をまだ受け取っていない
+    Pending,
+    /// known_peersに記録されている証明書
+    Trusted,
+    Untrusted,
+}
+
+impl Verification {
+    fn label(self) -> &'static str {
+        match self {
+            Verification::Pending => Msg::VerificationPending.text(),
+            Verification::Trusted => Msg::VerificationTrusted.text(),
+            Verification::Untrusted => Msg::VerificationUntrusted.text(),
+        }
+    }
+}
+
+// plainの行編集でプロンプトに表示する状態 (次の行を読み取るときに反映する)
+struct StatusLine {
+    peer: String,
+    state: &'static str,
+    verification: Verification,
+}
+
+impl StatusLine {
+    fn prompt(&self) -> String {
+        format!("[{} | {} | {}] {}", self.peer, self.state, self.verification.label(), PROMPT)
+    }
+}
+
+// tui フィーチャーを含めない場合は描画スレッドがなく、送るだけで読まれない
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+enum UiEvent {
+    Record(Record),
+    Peer(String),
+    State(&'static str),
+    Verification(Verification),
+    /// 直近の往復時間と平均
+    Latency(Duration, Duration),
+    Delivered(u64),
+    PageUp,
+    Bell,
+}
+
+// チャット画面への出力。TUIの場合は描画用のスレッドに送り、plainの場合は標準出力に書き出す
+pub struct Ui {
+    tui: Option<Tui>,
+    /// plainで色を付けるか (TUIの場合は描画スレッド側で判断する)
+    color: bool,
+    /// plainで行編集を使っている場合は、入力中の行を崩さないようここから出力する
+    printer: Option<Printer>,
+    /// plainで /more に使う直近の表示内容
+    pager: Mutex<Pager>,
+    renderer: Renderer,
+    theme: Theme,
+    /// --format jsonl (plainと同じく標準出力に書き出すが、1行ずつJSONにする)
+    jsonl: bool,
+    /// デーモンでは標準出力の代わりにここへ書き出す (部屋の名前と送り先)
+    sink: Option<(String, broadcast::Sender<Arc<str>>)>,
+    /// plainの行編集のプロンプトに表示する状態
+    status: Arc<Mutex<StatusLine>>,
+}
+
+struct Pager {
+    records: Scrollback,
+    /// /more で最後からさかのぼって表示した件数 (新しい表示があると0に戻る)
+    shown: usize,
+}
+
+// 直近の表示内容。上限を超えると古いものから捨てる
+struct Scrollback {
+    records: VecDeque<Record>,
+    capacity: usize,
+}
+
+impl Scrollback {
+    fn new(capacity: usize) -> Self {
+        Self { records: VecDeque::new(), capacity: capacity.max(1) }
+    }
+
+    fn push(&mut self, record: Record) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    // 自分のメッセージが相手に届いたことを記録する
+    fn delivered(&mut self, seq: u64) {
+        let own = self
+            .records
+            .iter_mut()
+            .rev()
+            .find(|record| matches!(record.kind, RecordKind::Own) && record.id == Some(seq));
+        if let Some(record) = own {
+            record.delivery = Some(true);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &Record> {
+        self.records.iter()
+    }
+}
+
+fn lock(pager: &Mutex<Pager>) -> std::sync::MutexGuard<'_, Pager> {
+    pager.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn lock_status(status: &Mutex<StatusLine>) -> std::sync::MutexGuard<'_, StatusLine> {
+    status.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+type Printer = Arc<Mutex<Box<dyn ExternalPrinter + Send>>>;
+
+struct Tui {
+    events: Option<std_mpsc::Sender<UiEvent>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// 入力された行。TUIでは入力欄、plainでは端末なら行編集、それ以外は標準入力から読み取る
+pub enum Input {
+    Lines(mpsc::UnboundedReceiver<String>),
+    Editor(LineEditor),
+}
+
+impl Input {
+    // 入力が閉じられた (標準入力の終端、またはCtrl+C・Ctrl+D) 場合はNone
+    // select!の中で呼ばれるため、途中で中断されても入力された行を失わない
+    pub async fn next_line(&mut self) -> Option<String> {
+        match self {
+            Input::Lines(lines) => lines.recv().await,
+            Input::Editor(editor) => {
+                if !editor.pending {
+                    editor.requests.as_ref()?.send(()).ok()?;
+                    editor.pending = true;
+                }
+                let line = editor.results.recv().await.flatten();
+                editor.pending = false;
+                line
+            }
+        }
+    }
+}
+
+// TUIで括弧付き貼り付け (bracketed paste) と端末のフォーカスの通知 (未読数に使う) を有効にし、キーボードの拡張が使えればそれも有効にする
+// 拡張を有効にした場合はtrueを返す (終了時に元に戻す)
+#[cfg(feature = "tui")]
+fn enable_multiline_input() -> bool {
+    use ratatui::crossterm::{execute, terminal};
+    let mut stdout = std::io::stdout();
+    if let Err(e) = execute!(stdout, EnableBracketedPaste, EnableFocusChange) {
+        tracing::debug!(error = %e, "括弧付き貼り付けを有効にできませんでした");
+    }
+    terminal::supports_keyboard_enhancement().unwrap_or(false)
+        && execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)).is_ok()
+}
+
+#[cfg(feature = "tui")]
+fn disable_multiline_input(enhanced: bool) {
+    use ratatui::crossterm::execute;
+    let mut stdout = std::io::stdout();
+    if enhanced {
+        let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+    }
+    let _ = execute!(stdout, DisableBracketedPaste, DisableFocusChange);
+}
+
+// rustylineによる行編集 (矢印キーでの編集、入力履歴、Ctrl-Rでの検索)
+// 読み取り中は端末がrawモードになるため、要求されたときだけ1行読み取る
+// 行編集でのTabによる補完
+struct ChatHelper {
+    names: Vec<String>,
+}
+
+impl rustyline::completion::Completer for ChatHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(commands::complete(line, pos, &self.names))
+    }
+}
+
+impl rustyline::hint::Hinter for ChatHelper {
+    type Hint = String;
+}
+
+impl rustyline::highlight::Highlighter for ChatHelper {}
+
+impl rustyline::validate::Validator for ChatHelper {}
+
+impl rustyline::Helper for ChatHelper {}
+
+pub struct LineEditor {
+    status: Arc<Mutex<StatusLine>>,
+    requests: Option<std_mpsc::Sender<()>>,
+    results: mpsc::UnboundedReceiver<Option<String>>,
+    pending: bool,
+    thread: Option<JoinHandle<()>>,
+    printer: Printer,
+}
+
+impl LineEditor {
+    fn start(history: Option<PathBuf>, names: Vec<String>, status: Arc<Mutex<StatusLine>>) -> rustyline::Result<Self> {
+        let mut editor =
+            rustyline::Editor::<ChatHelper, FileHistory>::with_history(history_config()?, load_history(history.as_deref())?)?;
+        editor.set_helper(Some(ChatHelper { names }));
+        let printer: Printer = Arc::new(Mutex::new(Box::new(editor.create_external_printer()?)));
+        let (requests, request_rx) = std_mpsc::channel::<()>();
+        let (result_tx, results) = mpsc::unbounded_channel();
+        let prompt_status = Arc::clone(&status);
+        let thread = std::thread::spawn(move || {
+            while request_rx.recv().is_ok() {
+                let prompt = lock_status(&prompt_status).prompt();
+                let line = match editor.readline(&prompt) {
+                    Ok(line) => {
+                        if let Some(path) = &history {
+                            if let Err(e) = editor.append_history(path) {
+                                tracing::warn!(error = %e, "入力履歴を保存できませんでした");
+                            }
+                        }
+                        Some(line)
+                    }
+                    Err(ReadlineError::Interrupted | ReadlineError::Eof) => None,
+                    Err(e) => {
+                        tracing::error!(error = %e, "入力の読み取りに失敗しました");
+                        None
+                    }
+                };
+                let closed = line.is_none();
+                if result_tx.send(line).is_err() || closed {
+                    break;
+                }
+            }
+        });
+        Ok(Self { status, requests: Some(requests), results, pending: false, thread: Some(thread), printer })
+    }
+}
+
+// 読み取りの途中で終わった場合は、端末を元に戻すため入力が終わるのを待つ
+impl Drop for LineEditor {
+    fn drop(&mut self) {
+        self.requests.take();
+        if self.pending && self.results.is_empty() {
+            print(&self.printer, Msg::PressEnterToQuit.to_string());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn history_config() -> rustyline::Result<rustyline::Config> {
+    Ok(rustyline::Config::builder()
+        .max_history_size(INPUT_HISTORY_SIZE)?
+        .auto_add_history(true)
+        .completion_type(rustyline::CompletionType::List)
+        .build())
+}
+
+// 行編集とTUIで共通の入力履歴を読み込む
+fn load_history(path: Option<&Path>) -> rustyline::Result<FileHistory> {
+    let mut history = FileHistory::with_config(history_config()?);
+    if let Some(path) = path.filter(|path| path.exists()) {
+        if let Err(e) = history.load(path) {
+            tracing::warn!(error = %e, "入力履歴を読み込めませんでした");
+        }
+    }
+    Ok(history)
+}
+
+fn print(printer: &Printer, line: String) {
+    if let Ok(mut printer) = printer.lock() {
+        let _ = printer.print(format!("{}\n", line));
+    }
+}
+
+// チャット画面を開始する。TUIを開始できない場合はplainで続ける
+// colorがfalseの場合 (--no-color または NO_COLOR) は色を付けない。plainでは出力が端末でない場合も付けない
+pub fn start(options: &UiOptions, peer: &str, nickname: &str) -> (Ui, Input) {
+    let (line_tx, lines) = mpsc::unbounded_channel();
+    let color = options.color;
+    let jsonl = options.format == OutputFormat::Jsonl;
+    let use_tui = match options.mode {
+        _ if jsonl => false,
+        UiMode::Tui => true,
+        UiMode::Plain => false,
+        UiMode::Auto => std::io::stdin().is_terminal() && std::io::stdout().is_terminal(),
+    };
+    #[cfg(feature = "tui")]
+    if use_tui {
+        match ratatui::try_init() {
+            Ok(mut terminal) => {
+                // 貼り付けた複数行を1つの入力として受け取り、対応している端末ではShift+Enterを区別できるようにする
+                let enhanced = enable_multiline_input();
+                let (events, event_rx) = std_mpsc::channel();
+                let history = load_history(options.input_history.as_deref()).unwrap_or_default();
+                let mut app = App::new(peer, nickname, color, &options.settings, history, options.input_history.clone());
+                app.contacts = options.contacts.clone();
+                let thread = std::thread::spawn(move || {
+                    let result = app.run(&mut terminal, event_rx, line_tx);
+                    disable_multiline_input(enhanced);
+                    ratatui::restore();
+                    if let Err(e) = result {
+                        tracing::error!(error = %e, "画面の描画に失敗しました");
+                    }
+                });
+                crate::logging::mute_stderr(true);
+                let tui = Tui { events: Some(events), thread: Some(thread) };
+                return (Ui::new(Some(tui), color, None, options), Input::Lines(lines));
+            }
+            Err(e) => tracing::warn!(error = %e, "TUIを開始できないため、1行ずつの表示で続けます"),
+        }
+    }
+    #[cfg(not(feature = "tui"))]
+    if use_tui && options.mode == UiMode::Tui {
+        tracing::warn!("TUIを含めずにビルドされているため、1行ずつの表示で続けます");
+    }
+
+    let color = color && !jsonl && std::io::stdout().is_terminal();
+    if !jsonl && std::io::stdin().is_terminal() {
+        let names = [nickname, peer].iter().map(|name| name.to_string()).chain(options.contacts.iter().cloned()).collect();
+        let status = Arc::new(Mutex::new(StatusLine {
+            peer: peer.to_string(),
+            state: Msg::StateConnected.text(),
+            verification: Verification::Pending,
+        }));
+        match LineEditor::start(options.input_history.clone(), names, status) {
+            Ok(editor) => {
+                let printer = Some(Arc::clone(&editor.printer));
+                let mut ui = Ui::new(None, color, printer, options);
+                ui.status = Arc::clone(&editor.status);
+                return (ui, Input::Editor(editor));
+            }
+            Err(e) => tracing::warn!(error = %e, "行編集を開始できないため、標準入力から読み取ります"),
+        }
+    }
+
+    tokio::spawn(async move {
+        let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            match stdin.next_line().await {
+                Ok(Some(line)) => {
+                    if line_tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::error!(error = %e, "標準入力の読み取りに失敗しました");
+                    break;
+                }
+            }
+        }
+    });
+    (Ui::new(None, color, None, options), Input::Lines(lines))
+}
+
+// デーモンの部屋のチャット画面を開始する。常にjsonlとして扱う
+pub fn headless(options: &UiOptions, headless: Headless) -> (Ui, Input) {
+    let mut ui = Ui::new(None, false, None, options);
+    ui.jsonl = true;
+    ui.sink = Some((headless.room, headless.events));
+    (ui, Input::Lines(headless.input))
+}
+
+impl Ui {
+    fn new(tui: Option<Tui>, color: bool, printer: Option<Printer>, options: &UiOptions) -> Self {
+        // TUIでは描画スレッド側で保持するため、plainの場合のみ使う
+        let capacity = if tui.is_some() { 1 } else { options.settings.scrollback };
+        Self {
+            tui,
+            color,
+            printer,
+            pager: Mutex::new(Pager { records: Scrollback::new(capacity), shown: 0 }),
+            renderer: Renderer::new(&options.settings),
+            theme: Theme::new(options.settings.theme, &options.settings.colors),
+            jsonl: options.format == OutputFormat::Jsonl,
+            sink: None,
+            status: Arc::new(Mutex::new(StatusLine {
+                peer: String::new(),
+                state: Msg::StateConnected.text(),
+                verification: Verification::Pending,
+            })),
+        }
+    }
+
+    // jsonlの出来事を1行書き出す
+    fn emit(&self, event: JsonEvent, at: DateTime<Local>) {
+        match &self.sink {
+            // 購読している相手がいなければ捨てる
+            Some((room, events)) => {
+                let _ = events.send(event.encode(at, Some(room)).into());
+            }
+            None => event.print(at),
+        }
+    }
+
+    // 相手と接続した・接続が終わった (jsonlでのみ書き出す。plainとTUIでは接続処理の表示で分かる)
+    pub fn connected(&self, peer: &str) {
+        if self.jsonl {
+            self.emit(JsonEvent::Connected { peer }, Local::now());
+        }
+    }
+
+    pub fn disconnected(&self, peer: &str) {
+        self.set_state(Msg::StateOffline.text());
+        if self.jsonl {
+            self.emit(JsonEvent::Disconnected { peer }, Local::now());
+        }
+    }
+
+    // 相手のメッセージ。seqは相手側の通し番号 (0は不明)、sent_atは再送されたメッセージの元の送信日時
+    pub fn remote(&self, from: &str, body: &str, seq: u64, sent_at: Option<DateTime<Local>>) {
+        let record = Record {
+            at: sent_at.unwrap_or_else(Local::now),
+            kind: RecordKind::Remote { from: from.to_string() },
+            text: body.to_string(),
+            id: (seq > 0).then_some(seq),
+            delivery: None,
+        };
+        self.show(record, sent_at.is_some());
+    }
+
+    // 自分が送信したメッセージ (plainでは入力した行がそのまま見えているので /more でのみ表示する)
+    pub fn own(&self, body: &str, seq: u64) {
+        let mut record = Record::new(RecordKind::Own, body.to_string());
+        record.id = (seq > 0).then_some(seq);
+        record.delivery = Some(false);
+        self.show(record, false);
+    }
+
+    // 自分のメッセージが相手に届いた
+    pub fn delivered(&self, seq: u64) {
+        match &self.tui {
+            Some(tui) => tui.send(UiEvent::Delivered(seq)),
+            None => lock(&self.pager).records.delivered(seq),
+        }
+        if self.jsonl {
+            self.emit(JsonEvent::Delivered { seq }, Local::now());
+        }
+    }
+
+    // ファイルの送受信の進み具合 (plainとTUIでは開始と完了の案内だけを表示する)
+    pub fn progress(&self, id: u64, name: &str, direction: Direction, bytes: u64, total: Option<u64>) {
+        if self.jsonl {
+            self.emit(JsonEvent::Progress { id, name, direction, bytes, total }, Local::now());
+        }
+    }
+
+    pub fn info(&self, text: impl Into<String>) {
+        self.show(Record::new(RecordKind::Info, text.into()), false);
+    }
+
+    // 入力を処理できなかった (plainとTUIでは案内と同じ表示)
+    pub fn error(&self, message: impl Into<String>) {
+        let message = message.into();
+        match self.jsonl {
+            true => self.emit(JsonEvent::Error { message: &message }, Local::now()),
+            false => self.info(message),
+        }
+    }
+
+    pub fn warn(&self, text: impl Into<String>) {
+        self.show(Record::new(RecordKind::Warning, text.into()), false);
+    }
+
+    // 履歴に残っている以前のメッセージを読み込む。TUIではそのまま表示し、plainでは /more で表示する
+    pub fn load_earlier(&self, nickname: &str, entries: Vec<HistoryEntry>) {
+        let records: Vec<Record> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let (kind, text) = match entry.event {
+                    EventKind::Message { direction: Direction::Incoming, body } => {
+                        (RecordKind::Remote { from: nickname.to_string() }, body)
+                    }
+                    EventKind::Message { direction: Direction::Outgoing, body } => (RecordKind::Own, body),
+                    EventKind::FileTransfer { direction, file_name, size } => {
+                        let msg = if direction == Direction::Incoming { Msg::EarlierReceivedFile } else { Msg::EarlierSentFile };
+                        (RecordKind::Info, msg.with(&[&file_name, &size]))
+                    }
+                    EventKind::Mailed { address, body, encrypted } => {
+                        let msg = if encrypted { Msg::EarlierMailed } else { Msg::EarlierMailNotice };
+                        (RecordKind::Info, msg.with(&[&address, &body]))
+                    }
+                    EventKind::Nostr { direction: Direction::Incoming, body } => {
+                        (RecordKind::Remote { from: nickname.to_string() }, Msg::ViaNostr.with(&[&body]))
+                    }
+                    EventKind::Nostr { direction: Direction::Outgoing, body } => (RecordKind::Own, Msg::ViaNostr.with(&[&body])),
+                    EventKind::System { .. } => return None,
+                };
+                let id = entry.remote_seq.unwrap_or(entry.seq);
+                Some(Record { at: entry.timestamp, kind, text, id: Some(id), delivery: None })
+            })
+            .collect();
+        if records.is_empty() {
+            return;
+        }
+        let count = records.len();
+        match &self.tui {
+            Some(tui) => {
+                for record in records {
+                    tui.send(UiEvent::Record(record));
+                }
+                tui.send(UiEvent::Record(Record::new(
+                    RecordKind::Info,
+                    Msg::EarlierEnd.with(&[&count]),
+                )));
+            }
+            None => {
+                let mut pager = lock(&self.pager);
+                for record in records {
+                    pager.records.push(record);
+                }
+                drop(pager);
+                self.info(Msg::EarlierAvailable.with(&[&count]));
+            }
+        }
+    }
+
+    // 以前の表示をさかのぼる。TUIではメッセージ欄を1ページ戻し、plainでは1ページ分を表示し直す
+    pub fn more(&self) {
+        if let Some(tui) = &self.tui {
+            tui.send(UiEvent::PageUp);
+            return;
+        }
+        let mut pager = lock(&self.pager);
+        let total = pager.records.len();
+        let end = total - pager.shown.min(total);
+        let start = end.saturating_sub(MORE_PAGE);
+        if start == end {
+            drop(pager);
+            self.note(Msg::NoEarlier.text(), "3");
+            return;
+        }
+        let records: Vec<Record> = pager.records.iter().skip(start).take(end - start).cloned().collect();
+        pager.shown = total - start;
+        drop(pager);
+        self.note(&Msg::MorePage.with(&[&(start + 1), &end, &total]), "2");
+        for record in &records {
+            self.print_record(record, true);
+        }
+    }
+
+    // /more の見出しなど、記録に残さない案内
+    fn note(&self, text: &str, sgr: &str) {
+        match self.jsonl {
+            true => self.emit(JsonEvent::Info { text }, Local::now()),
+            false => self.println(self.paint(text, sgr)),
+        }
+    }
+
+    fn print_record(&self, record: &Record, with_time: bool) {
+        if !self.jsonl {
+            self.println(self.plain(record, with_time));
+            return;
+        }
+        let event = match &record.kind {
+            RecordKind::Remote { from } => JsonEvent::Message { from, seq: record.id, body: &record.text },
+            RecordKind::Own => JsonEvent::Sent { seq: record.id, body: &record.text },
+            RecordKind::Info => JsonEvent::Info { text: &record.text },
+            RecordKind::Warning => JsonEvent::Warning { text: &record.text },
+        };
+        self.emit(event, record.at);
+    }
+
+    fn show(&self, record: Record, with_time: bool) {
+        if let Some(tui) = &self.tui {
+            tui.send(UiEvent::Record(record));
+            return;
+        }
+        // jsonlでは送信したことも出来事として書き出す
+        if self.jsonl || !matches!(record.kind, RecordKind::Own) {
+            self.print_record(&record, with_time);
+        }
+        let mut pager = lock(&self.pager);
+        pager.records.push(record);
+        pager.shown = 0;
+    }
+
+    // plainで表示する1行
+    fn plain(&self, record: &Record, with_time: bool) -> String {
+        let (nick, nick_sgr) = match &record.kind {
+            RecordKind::Remote { from } => (from.as_str(), theme::sgr("1", self.theme.nick(from))),
+            RecordKind::Own => (Msg::Me.text(), theme::sgr("1", self.theme.own)),
+            RecordKind::Info | RecordKind::Warning => {
+                let time = match with_time {
+                    true => format!("{} ", self.paint(&format!("[{}]", self.renderer.time(record.at)), &self.meta_sgr())),
+                    false => String::new(),
+                };
+                return match record.kind {
+                    RecordKind::Info => format!("{}{}", time, self.paint(&record.text, &theme::sgr("3", self.theme.info))),
+                    _ => format!("{}{}", time, self.paint(&Msg::WarningLine.with(&[&record.text]), &theme::sgr("", self.theme.warning))),
+                };
+            }
+        };
+        let default = if with_time { PLAIN_TIMED_FORMAT } else { PLAIN_FORMAT };
+        self.renderer
+            .message(record, nick, default)
+            .into_iter()
+            .map(|(part, text)| match part {
+                Part::Nick => self.paint(&text, &nick_sgr),
+                Part::Time | Part::Meta => self.paint(&text, &self.meta_sgr()),
+                Part::Text | Part::Body => text,
+            })
+            .collect()
+    }
+
+    // 以下はステータスバーと参加者一覧の表示 (plainでは何もしない)
+    pub fn set_peer(&self, peer: &str) {
+        lock_status(&self.status).peer = peer.to_string();
+        if let Some(tui) = &self.tui {
+            tui.send(UiEvent::Peer(peer.to_string()));
+        }
+    }
+
+    pub fn set_state(&self, state: &'static str) {
+        lock_status(&self.status).state = state;
+        if let Some(tui) = &self.tui {
+            tui.send(UiEvent::State(state));
+        }
+    }
+
+    pub fn set_verification(&self, verification: Verification) {
+        lock_status(&self.status).verification = verification;
+        if let Some(tui) = &self.tui {
+            tui.send(UiEvent::Verification(verification));
+        }
+    }
+
+    pub fn set_latency(&self, last: Duration, average: Duration) {
+        if let Some(tui) = &self.tui {
+            tui.send(UiEvent::Latency(last, average));
+        }
+    }
+
+    fn println(&self, line: String) {
+        match &self.printer {
+            Some(printer) => print(printer, line),
+            None => println!("{}", line),
+        }
+    }
+
+    // 補足の表示は、配色で色を決めていなければ薄く表示する
+    fn meta_sgr(&self) -> String {
+        match self.theme.meta {
+            Color::Reset => "2".to_string(),
+            color => theme::sgr("", color),
+        }
+    }
+
+    // plainの出力にSGRの属性を付ける
+    fn paint(&self, text: &str, sgr: &str) -> String {
+        if self.color && !sgr.is_empty() {
+            format!("\x1b[{}m{}\x1b[0m", sgr, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    // 通知音を鳴らす。音声ファイルが設定されていれば再生し、なければ端末のベルを鳴らす
+    // (ベルは出力がパイプやファイルの場合は鳴らさない)
+    pub fn alert(&self, alerts: &AlertsConfig) {
+        match (&alerts.sound, &self.tui) {
+            (Some(sound), _) => play(alerts.player(), sound),
+            (None, Some(tui)) => tui.send(UiEvent::Bell),
+            // jsonlでは標準出力にJSON以外を書かない
+            (None, None) if self.jsonl => {}
+            (None, None) => ring_bell(),
+        }
+    }
+}
+
+impl Tui {
+    fn send(&self, event: UiEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+// 描画スレッドを終了させ、端末を元の状態に戻してから返る
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.events.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        crate::logging::mute_stderr(false);
+    }
+}
+
+// 再生の終了は待たない (終了したプロセスはtokioが回収する)
+fn play(player: &str, sound: &Path) {
+    let spawned = tokio::process::Command::new(player)
+        .arg(sound)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(e) = spawned {
+        tracing::warn!(error = %e, player, "通知音を再生できませんでした");
+    }
+}
+
+fn ring_bell() {
+    let mut stdout = std::io::stdout();
+    if stdout.is_terminal() {
+        let _ = stdout.write_all(b"\x07").and_then(|_| stdout.flush());
+    }
+}
+
+// TUIの状態
+#[cfg(feature = "tui")]
+struct App {
+    records: Scrollback,
+    peer: String,
+    nickname: String,
+    state: &'static str,
+    verification: Verification,
+    /// 最新のメッセージを表示していない間 (さかのぼっている、または端末が非アクティブ) に届いたメッセージの数
+    unread: usize,
+    focused: bool,
+    /// 直近の往復時間と平均
+    latency: Option<(Duration, Duration)>,
+    input: Vec<char>,
+    cursor: usize,
+    /// 最下部から何行さかのぼって表示しているか
+    scroll: usize,
+    /// 直前に描画したメッセージ欄の高さ (PageUp/PageDownの移動量に使う)
+    page: usize,
+    color: bool,
+    history: FileHistory,
+    history_path: Option<PathBuf>,
+    /// 上下キーで表示している入力履歴の位置
+    browsing: Option<usize>,
+    /// 入力履歴を表示する前に入力していた内容
+    stash: Vec<char>,
+    /// Ctrl-Rで検索中の文字列と、見つかった入力履歴の位置
+    search: Option<(String, Option<usize>)>,
+    theme: Theme,
+    /// Tabで補完する連絡先の名前 (参加者の名前は補完のたびに加える)
+    contacts: Vec<String>,
+    /// Tabで補完中の語の開始位置と候補、いま入力欄に入れている候補の位置 (続けてTabを押すと次の候補に替える)
+    completion: Option<(usize, Vec<String>, usize)>,
+    renderer: Renderer,
+}
+
+#[cfg(feature = "tui")]
+impl App {
+    fn new(
+        peer: &str,
+        nickname: &str,
+        color: bool,
+        settings: &UiConfig,
+        history: FileHistory,
+        history_path: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            records: Scrollback::new(settings.scrollback),
+            peer: peer.to_string(),
+            nickname: nickname.to_string(),
+            state: Msg::StateConnected.text(),
+            verification: Verification::Pending,
+            unread: 0,
+            focused: true,
+            latency: None,
+            input: Vec::new(),
+            cursor: 0,
+            scroll: 0,
+            page: 1,
+            color,
+            history,
+            history_path,
+            browsing: None,
+            stash: Vec::new(),
+            search: None,
+            theme: Theme::new(settings.theme, &settings.colors),
+            contacts: Vec::new(),
+            completion: None,
+            renderer: Renderer::new(settings),
+        }
+    }
+
+    fn run(
+        mut self,
+        terminal: &mut DefaultTerminal,
+        events: std_mpsc::Receiver<UiEvent>,
+        lines: mpsc::UnboundedSender<String>,
+    ) -> std::io::Result<()> {
+        let mut lines = Some(lines);
+        // 表示が変わったときだけ描画し直す
+        let mut dirty = true;
+        loop {
+            loop {
+                match events.try_recv() {
+                    Ok(UiEvent::Bell) => ring_bell(),
+                    Ok(event) => {
+                        self.apply(event);
+                        dirty = true;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            if dirty {
+                if self.focused && self.scroll == 0 {
+                    self.unread = 0;
+                }
+                terminal.draw(|frame| self.draw(frame))?;
+                dirty = false;
+            }
+            if event::poll(POLL_INTERVAL)? {
+                match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => {
+                        self.key(key, &mut lines);
+                        dirty = true;
+                    }
+                    Event::Paste(text) => {
+                        self.paste(&text);
+                        dirty = true;
+                    }
+                    Event::FocusGained => {
+                        self.focused = true;
+                        dirty = true;
+                    }
+                    Event::FocusLost => self.focused = false,
+                    Event::Resize(..) => dirty = true,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, event: UiEvent) {
+        match event {
+            UiEvent::Record(record) => {
+                if matches!(record.kind, RecordKind::Remote { .. }) {
+                    self.unread += 1;
+                }
+                self.records.push(record);
+            }
+            UiEvent::Peer(peer) => self.peer = peer,
+            UiEvent::State(state) => self.state = state,
+            UiEvent::Verification(verification) => self.verification = verification,
+            UiEvent::Latency(last, average) => self.latency = Some((last, average)),
+            UiEvent::Delivered(seq) => self.records.delivered(seq),
+            UiEvent::PageUp => self.scroll += self.page.max(1),
+            UiEvent::Bell => {}
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent, lines: &mut Option<mpsc::UnboundedSender<String>>) {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        // 入力を閉じるとチャットが終了し、描画スレッドも止まる
+        if ctrl && matches!(key.code, KeyCode::Char('c') | KeyCode::Char('d')) {
+            if lines.take().is_some() {
+                self.records.push(Record::new(RecordKind::Info, Msg::Quitting.to_string()));
+            }
+            return;
+        }
+        if self.search.is_some() {
+            self.search_key(key, ctrl);
+            return;
+        }
+        if key.code == KeyCode::Tab {
+            self.complete();
+            return;
+        }
+        self.completion = None;
+        match key.code {
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.input.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+                self.browsing = None;
+            }
+            KeyCode::Char('r') if ctrl => {
+                self.stash = self.input.clone();
+                self.search = Some((String::new(), None));
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+                self.browsing = None;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+                self.browsing = None;
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+                self.browsing = None;
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => {
+                let index = match self.browsing {
+                    None if self.history.is_empty() => return,
+                    None => {
+                        self.stash = self.input.clone();
+                        self.history.len() - 1
+                    }
+                    Some(index) => index.saturating_sub(1),
+                };
+                self.show_history(index);
+            }
+            KeyCode::Down => match self.browsing {
+                Some(index) if index + 1 < self.history.len() => self.show_history(index + 1),
+                Some(_) => {
+                    self.browsing = None;
+                    let stash = std::mem::take(&mut self.stash);
+                    self.set_input(stash);
+                }
+                None => {}
+            },
+            // Shift+Enterは端末によってはEnterと区別できないため、Alt+Enterでも改行する
+            KeyCode::Enter if key.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) => {
+                self.input.insert(self.cursor, '\n');
+                self.cursor += 1;
+                self.browsing = None;
+            }
+            KeyCode::PageUp => self.scroll += (self.page / 2).max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub((self.page / 2).max(1)),
+            KeyCode::Enter => {
+                if let Some(lines) = lines {
+                    let line: String = self.input.iter().collect();
+                    if !line.trim().is_empty() {
+                        self.remember(&line);
+                        let _ = lines.send(line);
+                        self.set_input(Vec::new());
+                        self.browsing = None;
+                        self.scroll = 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Ctrl-Rでの検索中のキー操作。Ctrl-Rでさらに前を検索し、Enterで確定、Escで取り消す
+    fn search_key(&mut self, key: KeyEvent, ctrl: bool) {
+        let Some((query, found)) = self.search.as_mut() else {
+            return;
+        };
+        let start = match key.code {
+            KeyCode::Char('r') if ctrl => match found {
+                Some(0) | None => return,
+                Some(index) => *index - 1,
+            },
+            KeyCode::Char(c) if !ctrl => {
+                query.push(c);
+                self.history.len().saturating_sub(1)
+            }
+            KeyCode::Backspace => {
+                query.pop();
+                self.history.len().saturating_sub(1)
+            }
+            KeyCode::Esc => {
+                self.search = None;
+                let stash = std::mem::take(&mut self.stash);
+                self.set_input(stash);
+                return;
+            }
+            _ => {
+                if let Some((_, Some(index))) = self.search.take() {
+                    self.show_history(index);
+                    self.browsing = None;
+                }
+                return;
+            }
+        };
+        let query = query.clone();
+        let result = match query.is_empty() {
+            true => None,
+            false => self.history.search(&query, start, SearchDirection::Reverse).ok().flatten().map(|result| result.idx),
+        };
+        // 見つからない場合は直前に見つかったものを表示したままにする
+        if let (Some((_, found)), Some(index)) = (self.search.as_mut(), result) {
+            *found = Some(index);
+        }
+    }
+
+    // カーソルの前の語を補完する。候補が複数ある場合は、Tabを押すたびに順に入れ替える
+    fn complete(&mut self) {
+        let (start, candidates, index) = match self.completion.take() {
+            Some((start, candidates, index)) => {
+                let next = (index + 1) % candidates.len();
+                (start, candidates, next)
+            }
+            None => {
+                let line: String = self.input[..self.cursor].iter().collect();
+                let names: Vec<String> = [&self.nickname, &self.peer].into_iter().chain(&self.contacts).cloned().collect();
+                let (start, candidates) = commands::complete(&line, line.len(), &names);
+                if candidates.is_empty() {
+                    return;
+                }
+                (line[..start].chars().count(), candidates, 0)
+            }
+        };
+        let candidate: Vec<char> = candidates[index].chars().collect();
+        let count = candidate.len();
+        self.input.splice(start..self.cursor, candidate);
+        self.cursor = start + count;
+        self.browsing = None;
+        if candidates.len() > 1 {
+            self.completion = Some((start, candidates, index));
+        }
+    }
+
+    // 貼り付けた文字列は改行を含めてそのまま入力欄に入れ、Enterで1つのメッセージとして送る
+    fn paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let pasted: Vec<char> = text.trim_end_matches('\n').chars().collect();
+        let count = pasted.len();
+        self.input.splice(self.cursor..self.cursor, pasted);
+        self.cursor += count;
+        self.browsing = None;
+    }
+
+    fn show_history(&mut self, index: usize) {
+        if let Ok(Some(entry)) = self.history.get(index, SearchDirection::Forward) {
+            let input = entry.entry.chars().collect();
+            self.set_input(input);
+            self.browsing = Some(index);
+        }
+    }
+
+    fn set_input(&mut self, input: Vec<char>) {
+        self.input = input;
+        self.cursor = self.input.len();
+    }
+
+    // 送信した行を入力履歴に追加し、保存先があれば書き出す
+    fn remember(&mut self, line: &str) {
+        if let Err(e) = self.history.add(line) {
+            tracing::warn!(error = %e, "入力履歴に追加できませんでした");
+            return;
+        }
+        if let Some(path) = &self.history_path {
+            if let Err(e) = self.history.append(path) {
+                tracing::warn!(error = %e, "入力履歴を保存できませんでした");
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(3)]).areas(frame.area());
+        let [messages, roster] = Layout::horizontal([Constraint::Min(20), Constraint::Length(24)]).areas(main);
+        self.draw_messages(frame, messages);
+        self.draw_roster(frame, roster);
+        self.draw_status(frame, status);
+        self.draw_input(frame, input);
+    }
+
+    fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+        let inner = Block::bordered().inner(area);
+        let width = inner.width as usize;
+        let height = inner.height as usize;
+        self.page = height;
+
+        // 表示に必要な分だけ、新しいものから折り返す (rowsは下の行から順に並ぶ)
+        let needed = height + self.scroll;
+        let mut rows: Vec<Line> = Vec::new();
+        let mut complete = true;
+        for record in self.records.iter().rev() {
+            rows.extend(wrap(self.spans(record), width).into_iter().rev());
+            if rows.len() >= needed {
+                complete = false;
+                break;
+            }
+        }
+        // すべて折り返した場合は、最も古い行より上にはさかのぼれない
+        if complete {
+            self.scroll = self.scroll.min(rows.len().saturating_sub(height));
+        }
+        let visible: Vec<Line> = rows.into_iter().skip(self.scroll).take(height).rev().collect();
+
+        let title = if self.scroll > 0 {
+            Msg::TitleMessagesScrolled.with(&[&self.scroll])
+        } else {
+            Msg::TitleMessages.to_string()
+        };
+        let paragraph = Paragraph::new(visible).block(Block::bordered().title(title));
+        frame.render_widget(paragraph, area);
+    }
+
+    fn spans(&self, record: &Record) -> Vec<Span<'static>> {
+        let (nick, nick_color) = match &record.kind {
+            RecordKind::Remote { from } => (from.as_str(), self.theme.nick(from)),
+            RecordKind::Own => (Msg::Me.text(), self.theme.own),
+            RecordKind::Info | RecordKind::Warning => {
+                let time = Span::styled(format!("{} ", self.renderer.time(record.at)), self.fg(self.theme.meta));
+                let text = match record.kind {
+                    RecordKind::Info => Span::styled(record.text.clone(), self.fg(self.theme.info).add_modifier(Modifier::ITALIC)),
+                    _ => Span::styled(Msg::WarningLine.with(&[&record.text]), self.fg(self.theme.warning)),
+                };
+                return vec![time, text];
+            }
+        };
+        self.renderer
+            .message(record, nick, TUI_FORMAT)
+            .into_iter()
+            .map(|(part, text)| match part {
+                Part::Nick => Span::styled(text, self.fg(nick_color).add_modifier(Modifier::BOLD)),
+                Part::Time | Part::Meta => Span::styled(text, self.fg(self.theme.meta)),
+                Part::Text | Part::Body => Span::raw(text),
+            })
+            .collect()
+    }
+
+    fn draw_roster(&self, frame: &mut Frame, area: Rect) {
+        let items = vec![
+            ListItem::new(Line::from(vec![
+                Span::styled("● ", self.fg(self.theme.nick(&self.nickname))),
+                Span::raw(self.nickname.clone()),
+            ])),
+            ListItem::new(Span::styled(format!("  {}", self.peer), self.fg(self.theme.meta))),
+            ListItem::new(Line::from(vec![Span::styled("● ", self.fg(self.theme.own)), Span::raw(Msg::Me.text())])),
+        ];
+        frame.render_widget(List::new(items).block(Block::bordered().title(Msg::TitleParticipants.text())), area);
+    }
+
+    // 色を付けない設定の場合は文字色を変えない (太字などの属性は残す)
+    fn fg(&self, color: Color) -> Style {
+        if self.color {
+            Style::default().fg(color)
+        } else {
+            Style::default()
+        }
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let latency = match self.latency {
+            Some((last, average)) => Msg::LatencyValue.with(&[&latency::millis(last), &latency::millis(average)]),
+            None => "-".to_string(),
+        };
+        let text = Msg::StatusBar.with(&[&self.state, &self.peer, &self.verification.label(), &self.unread, &latency]);
+        let style = Style::default().add_modifier(Modifier::REVERSED);
+        frame.render_widget(Paragraph::new(text).style(style), area);
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        // 検索中は見つかった入力履歴を表示する
+        let (title, input, cursor) = match &self.search {
+            Some((query, found)) => {
+                let entry = found.and_then(|index| self.history.get(index, SearchDirection::Forward).ok().flatten());
+                let input: Vec<char> = entry.map(|entry| entry.entry.chars().collect()).unwrap_or_default();
+                let cursor = input.len();
+                (Msg::TitleSearch.with(&[&query]), input, cursor)
+            }
+            None => (Msg::TitleInput.to_string(), self.input.clone(), self.cursor),
+        };
+        let block = Block::bordered().title(title);
+        let inner = block.inner(area);
+        let width = inner.width as usize;
+
+        // 改行は入力欄に1行で収まるよう記号で表示する
+        let input: Vec<char> = input.iter().map(|&c| if c == '\n' { NEWLINE_MARK } else { c }).collect();
+        // カーソルが入力欄に収まるよう、はみ出した分は先頭から隠す
+        let char_width = |c: &char| c.width().unwrap_or(0);
+        let mut start = 0;
+        while start < cursor && input[start..cursor].iter().map(char_width).sum::<usize>() >= width {
+            start += 1;
+        }
+        let visible: String = input[start..].iter().collect();
+        let cursor_x = input[start..cursor].iter().map(char_width).sum::<usize>();
+
+        frame.render_widget(Paragraph::new(visible).block(block), area);
+        frame.set_cursor_position(Position::new(inner.x + cursor_x as u16, inner.y));
+    }
+}
+
+// 表示幅に合わせて折り返す (全角文字は2桁として数える)
+#[cfg(feature = "tui")]
+fn wrap(spans: Vec<Span<'static>>, width: usize) -> Vec<Line<'static>> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut used = 0;
+    for span in spans {
+        let mut current = String::new();
+        for c in span.content.chars() {
+            // 複数行のメッセージは改行の位置でも折り返す
+            if c == '\n' {
+                if !current.is_empty() {
+                    row.push(Span::styled(std::mem::take(&mut current), span.style));
+                }
+                rows.push(Line::from(std::mem::take(&mut row)));
+                used = 0;
+                continue;
+            }
+            let w = c.width().unwrap_or(0);
+            if used + w > width && used > 0 {
+                if !current.is_empty() {
+                    row.push(Span::styled(std::mem::take(&mut current), span.style));
+                }
+                rows.push(Line::from(std::mem::take(&mut row)));
+                used = 0;
+            }
+            current.push(c);
+            used += w;
+        }
+        if !current.is_empty() {
+            row.push(Span::styled(current, span.style));
+        }
+    }
+    rows.push(Line::from(row));
+    rows
+}
@@ -0,0 +1,142 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+// 暗号化した行の先頭に付ける印
+const ENCRYPTED_PREFIX: &str = "enc1:";
+// パスフレーズの照合に使う既知の平文
+const CHECK_PLAINTEXT: &[u8] = b"rust_p2p_chat vault";
+pub const PBKDF2_ITERATIONS: u32 = 200_000;
+// パスフレーズを渡すための環境変数
+pub const PASSPHRASE_ENV: &str = "P2PCHAT_HISTORY_PASSPHRASE";
+pub const BACKUP_PASSPHRASE_ENV: &str = "P2PCHAT_BACKUP_PASSPHRASE";
+
+// パスフレーズから鍵を導出するためのパラメータ (鍵そのものは保存しない)
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    salt: String,
+    iterations: u32,
+    check: String,
+}
+
+// パスフレーズから導出したAES-256-GCM鍵
+pub struct Vault {
+    key: LessSafeKey,
+}
+
+impl Vault {
+    // 新しいソルトで鍵を作り、鍵ファイルに保存する
+    pub fn create(key_file: &Path, passphrase: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let salt = random_salt()?;
+        let vault = Self::derive(passphrase, &salt, PBKDF2_ITERATIONS)?;
+        let file = KeyFile {
+            version: 1,
+            salt: BASE64.encode(salt),
+            iterations: PBKDF2_ITERATIONS,
+            check: vault.seal(CHECK_PLAINTEXT)?,
+        };
+        std::fs::write(key_file, serde_json::to_string_pretty(&file)?)?;
+        Ok(vault)
+    }
+
+    // 鍵ファイルのパラメータで鍵を導出し、パスフレーズが正しいか確認する
+    pub fn unlock(key_file: &Path, passphrase: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: KeyFile = serde_json::from_str(&std::fs::read_to_string(key_file)?)?;
+        let salt = BASE64.decode(&file.salt)?;
+        let vault = Self::derive(passphrase, &salt, file.iterations)?;
+        match vault.open(&file.check) {
+            Ok(check) if check == CHECK_PLAINTEXT => Ok(vault),
+            _ => Err("パスフレーズが正しくありません".into()),
+        }
+    }
+
+    // 鍵ファイルを使わずに鍵を導出する。ソルトと反復回数は呼び出し側で暗号文と一緒に保存する
+    pub fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        let iterations = NonZeroU32::new(iterations).ok_or("鍵の導出パラメータ (反復回数) が不正です")?;
+        let mut key_bytes = [0u8; 32];
+        ring::pbkdf2::derive(
+            ring::pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            salt,
+            passphrase.as_bytes(),
+            &mut key_bytes,
+        );
+        let key = UnboundKey::new(&AES_256_GCM, &key_bytes).map_err(|_| "鍵の作成に失敗しました")?;
+        Ok(Self {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    // 平文を暗号化し、`enc1:<base64(nonce || 暗号文)>` 形式の文字列にする
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_bytes)
+            .map_err(|_| "乱数の生成に失敗しました")?;
+        let mut buffer = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce_bytes), Aad::empty(), &mut buffer)
+            .map_err(|_| "暗号化に失敗しました")?;
+        let mut out = nonce_bytes.to_vec();
+        out.extend_from_slice(&buffer);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(out)))
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let encoded = sealed
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or("暗号化されたデータではありません")?;
+        let data = BASE64.decode(encoded)?;
+        if data.len() < NONCE_LEN {
+            return Err("暗号化されたデータが短すぎます".into());
+        }
+        let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| "nonceが不正です")?;
+        let mut buffer = ciphertext.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut buffer)
+            .map_err(|_| "復号に失敗しました (パスフレーズが違うか、データが壊れています)")?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+pub fn is_sealed(line: &str) -> bool {
+    line.starts_with(ENCRYPTED_PREFIX)
+}
+
+pub fn random_salt() -> Result<[u8; 16], Box<dyn std::error::Error>> {
+    let mut salt = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| "乱数の生成に失敗しました")?;
+    Ok(salt)
+}
+
+// 環境変数があればそれを、なければ端末から入力してもらう
+pub fn read_passphrase(env: &str, prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+// 新しく設定するパスフレーズを読み取る。端末から入力する場合は確認のためもう一度入力してもらう
+pub fn read_new_passphrase(env: &str, prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let passphrase = read_passphrase(env, prompt)?;
+    if std::env::var(env).is_err() {
+        let confirm = rpassword::prompt_password("もう一度入力してください: ")?;
+        if confirm != passphrase {
+            return Err("パスフレーズが一致しません".into());
+        }
+    }
+    if passphrase.is_empty() {
+        return Err("空のパスフレーズは使用できません".into());
+    }
+    Ok(passphrase)
+}
@@ -0,0 +1,117 @@
+use crate::config::{Config, WebhookConfig, WebhookEvent};
+use crate::runtime::Runtime;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::watch;
+
+// 設定ファイルの [[webhooks]] に出来事をJSONでPOSTする (外部の自動化のきっかけにする)
+//
+//     {"event": "message.received", "id": "...", "at": "2024-01-01T12:00:00+09:00", "peer": "127.0.0.1:8080", ...}
+//
+// 出来事ごとに別のタスクで送るため、送り先が遅くてもチャットは止まらない
+
+// 1回の送信を待つ上限
+#[cfg(feature = "discovery")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// 再送までの待ち時間 (再送のたびに2倍にする)
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// 出来事を送る窓口。設定ファイルは実行中に変更できるため、送るたびに読む
+#[derive(Clone)]
+pub(crate) struct Webhooks {
+    config: watch::Receiver<Config>,
+    runtime: Runtime,
+}
+
+impl Webhooks {
+    pub(crate) fn new(config: watch::Receiver<Config>, runtime: Runtime) -> Self {
+        Self { config, runtime }
+    }
+
+    // eventを送る設定のWebhookすべてに送る。fieldsは出来事ごとの項目 (JSONのオブジェクト)
+    pub(crate) fn emit(&self, event: WebhookEvent, peer: &str, fields: Value) {
+        let targets: Vec<WebhookConfig> = self.config.borrow().webhooks.iter().filter(|hook| hook.wants(event)).cloned().collect();
+        if targets.is_empty() {
+            return;
+        }
+        let id = crate::session::new_token().unwrap_or_default();
+        let mut payload = json!({ "event": event.name(), "id": id, "at": chrono::Local::now().to_rfc3339(), "peer": peer });
+        if let (Value::Object(payload), Value::Object(fields)) = (&mut payload, fields) {
+            payload.extend(fields);
+        }
+        let body = payload.to_string();
+        for hook in targets {
+            let (runtime, body, id) = (self.runtime.clone(), body.clone(), id.clone());
+            self.runtime.spawn(async move { deliver(&runtime, &hook, event, &id, &body).await });
+        }
+    }
+}
+
+// 届くか、再送の回数を使い切るまで送る
+async fn deliver(runtime: &Runtime, hook: &WebhookConfig, event: WebhookEvent, id: &str, body: &str) {
+    let signature = hook.secret.as_deref().map(|secret| sign(secret, body));
+    let mut delay = RETRY_DELAY;
+    for attempt in 0..=hook.retries {
+        if attempt > 0 {
+            runtime.sleep(delay).await;
+            delay *= 2;
+        }
+        match post(&hook.url, event, id, signature.as_deref(), body).await {
+            Ok(()) => {
+                tracing::debug!(url = %hook.url, event = event.name(), attempt, "Webhookを送りました");
+                return;
+            }
+            Err(Failure::Permanent(e)) => {
+                tracing::warn!(url = %hook.url, event = event.name(), error = %e, "Webhookの送り先に断られました");
+                return;
+            }
+            Err(Failure::Retry(e)) => tracing::warn!(url = %hook.url, event = event.name(), attempt, error = %e, "Webhookを送れませんでした"),
+        }
+    }
+    tracing::error!(url = %hook.url, event = event.name(), "再送の回数を使い切ったため、Webhookを送るのをやめます");
+}
+
+// 本文のHMAC-SHA256 (受け取る側は同じ鍵で計算して X-P2PChat-Signature と比べる)
+fn sign(secret: &str, body: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", crate::transfer::hex(ring::hmac::sign(&key, body.as_bytes()).as_ref()))
+}
+
+#[cfg_attr(not(feature = "discovery"), allow(dead_code))]
+enum Failure {
+    /// 接続できない・タイムアウト・5xx・429。しばらく待って再送する
+    Retry(String),
+    /// それ以外の4xxなど。再送しても変わらない
+    Permanent(String),
+}
+
+#[cfg(feature = "discovery")]
+async fn post(url: &str, event: WebhookEvent, id: &str, signature: Option<&str>, body: &str) -> Result<(), Failure> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!("p2pchat/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| Failure::Permanent(e.to_string()))?;
+    let mut request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-P2PChat-Event", event.name())
+        .header("X-P2PChat-Delivery", id)
+        .body(body.to_string());
+    if let Some(signature) = signature {
+        request = request.header("X-P2PChat-Signature", signature);
+    }
+    let response = request.send().await.map_err(|e| Failure::Retry(e.to_string()))?;
+    let status = response.status();
+    match status {
+        status if status.is_success() => Ok(()),
+        status if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS => Err(Failure::Retry(status.to_string())),
+        status => Err(Failure::Permanent(status.to_string())),
+    }
+}
+
+// discovery フィーチャー (HTTPクライアント) を含めずにビルドした場合は送れない
+#[cfg(not(feature = "discovery"))]
+async fn post(_url: &str, _event: WebhookEvent, _id: &str, _signature: Option<&str>, _body: &str) -> Result<(), Failure> {
+    Err(Failure::Permanent("Webhookの送信は含まれていません (discoveryフィーチャー)".to_string()))
+}
@@ -0,0 +1,168 @@
+use crate::builder::{Limits, TlsMode};
+use crate::chat::ChatOptions;
+use crate::debug::Negotiated;
+use crate::identity::Identity;
+use crate::runtime::{BoxStream, Runtime};
+use crate::tls;
+use crate::transport::{ConnectError, ServerStream, Upgraded};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use wtransport::endpoint::{endpoint_side, IncomingSession};
+use wtransport::error::ConnectingError;
+use wtransport::stream::BiStream;
+use wtransport::tls::WEBTRANSPORT_ALPN;
+use wtransport::{ClientConfig, Connection, Endpoint, ServerConfig};
+
+// `listen --webtransport` と `connect https://...`: WebTransport (HTTP/3・QUIC) のセッションでチャットの接続を運ぶ
+// セッションの最初の双方向ストリームをTCPの代わりの通信路とし、その上で今までと同じWebSocketのハンドシェイクとフレームをやり取りする
+// (TLSはQUICが行うため、相手の証明書の照合はTCPの場合と同じくハンドシェイクの後で行う)
+
+// 通信のない接続はQUICが閉じてしまうため、チャットが黙っている間も保つ
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+// セッションの確立とWebSocketのハンドシェイクを終えるまでの時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// 待受と同じポート番号のUDPでWebTransportのセッションを受け付け始める
+// 許可された相手とハンドシェイクを済ませた接続が、受け取った順に届く。受け取る側を閉じると受け付けをやめる
+pub(crate) fn listen(
+    addr: SocketAddr,
+    identity: &Identity,
+    limits: Limits,
+    options: &ChatOptions,
+) -> Result<mpsc::UnboundedReceiver<Upgraded>, Box<dyn std::error::Error>> {
+    // QUICではTLS 1.3しか使えない
+    let mut tls_config = tls::server_config(identity, TlsMode::Tls13Only)?;
+    tls_config.alpn_protocols = vec![WEBTRANSPORT_ALPN.to_vec()];
+    let config = ServerConfig::builder()
+        .with_bind_address(addr)
+        .with_custom_tls(tls_config)
+        .keep_alive_interval(Some(KEEP_ALIVE))
+        .build();
+    let endpoint = Endpoint::server(config)?;
+
+    let (upgraded_tx, upgraded) = mpsc::unbounded_channel();
+    let (config, runtime) = (options.config.clone(), options.runtime.clone());
+    options.runtime.spawn(async move {
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => incoming,
+                _ = upgraded_tx.closed() => break,
+            };
+            let peer_addr = incoming.remote_address();
+            if !config.borrow().access.permits(peer_addr.ip()) {
+                tracing::warn!(security = true, peer = %peer_addr, "許可されていない相手からのWebTransportのセッションを拒否しました");
+                incoming.refuse();
+                continue;
+            }
+            let (runtime, upgraded_tx) = (runtime.clone(), upgraded_tx.clone());
+            let span = tracing::info_span!("connection", peer = %peer_addr);
+            runtime.clone().spawn(
+                async move {
+                    match accept(incoming, &limits, &runtime).await {
+                        Ok((ws_stream, negotiated)) => {
+                            let _ = upgraded_tx.send((ws_stream, Some(negotiated), peer_addr));
+                        }
+                        Err(e) => tracing::warn!(error = %e, "WebTransportのセッションを処理できませんでした"),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    });
+    Ok(upgraded)
+}
+
+// 要求に応えてセッションを確立し、相手が開いた最初の双方向ストリームでWebSocketのハンドシェイクを行う
+async fn accept(incoming: IncomingSession, limits: &Limits, runtime: &Runtime) -> Result<(ServerStream, Negotiated), Error> {
+    let handshake = async {
+        let request = incoming.await?;
+        tracing::debug!(authority = request.authority(), path = request.path(), "WebTransportのセッションを受け付けます");
+        let connection = request.accept().await?;
+        let negotiated = negotiated(&connection);
+        let stream = BiStream::join(connection.accept_bi().await?);
+        let stream: BoxStream = Box::new(SessionStream { stream, _connection: connection, _endpoint: None });
+        let ws_stream = tokio_tungstenite::accept_async_with_config(stream, limits.websocket_config()).await?;
+        Ok::<_, Error>((ws_stream, negotiated))
+    };
+    runtime
+        .timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| "WebTransportのハンドシェイクが時間内に終わりませんでした")?
+}
+
+// WebTransportで接続し、最初の双方向ストリームと、TLSの内容・相手の証明書を返す
+pub(crate) async fn connect(host: &str, port: u16) -> Result<(BoxStream, Negotiated, Vec<u8>), ConnectError> {
+    // サーバー証明書はTCPの場合と同じく、ハンドシェイクの後でフィンガープリントを照合する
+    let mut tls_config = tls::client_config(TlsMode::Tls13Only);
+    tls_config.alpn_protocols = vec![WEBTRANSPORT_ALPN.to_vec()];
+    let config = ClientConfig::builder()
+        .with_bind_default()
+        .with_custom_tls(tls_config)
+        .keep_alive_interval(Some(KEEP_ALIVE))
+        .build();
+    let endpoint = Endpoint::client(config).map_err(|e| ConnectError::Unreachable(e.to_string()))?;
+
+    tracing::info!(host, port, "WebTransportのセッションを開始します");
+    let url = format!("https://{}:{}/", host, port);
+    let connection = endpoint.connect(&url).await.map_err(|e| {
+        tracing::error!(error = %e, "WebTransportのセッションを確立できませんでした");
+        match e {
+            ConnectingError::DnsLookup(_) | ConnectingError::DnsNotFound => ConnectError::Dns(e.to_string()),
+            ConnectingError::SessionRejected => ConnectError::Rejected("相手にWebTransportのセッションを断られました".to_string()),
+            e => ConnectError::Unreachable(e.to_string()),
+        }
+    })?;
+    let peer_cert = connection
+        .peer_identity()
+        .and_then(|chain| chain.as_slice().first().map(|cert| cert.der().to_vec()))
+        .ok_or_else(|| ConnectError::Tls("サーバーから証明書が提示されませんでした".to_string()))?;
+    let negotiated = negotiated(&connection);
+
+    let opening = connection.open_bi().await.map_err(|e| ConnectError::Unreachable(e.to_string()))?;
+    let stream = BiStream::join(opening.await.map_err(|e| ConnectError::Unreachable(e.to_string()))?);
+    Ok((Box::new(SessionStream { stream, _connection: connection, _endpoint: Some(endpoint) }), negotiated, peer_cert))
+}
+
+// QUICのTLSは常に1.3。暗号スイートはwtransportから取り出せない
+fn negotiated(connection: &Connection) -> Negotiated {
+    Negotiated {
+        tls_version: Some("TLSv1_3".to_string()),
+        cipher_suite: None,
+        alpn: connection.handshake_data().alpn().map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+    }
+}
+
+// 最初の双方向ストリーム。使い終わるまでセッション (接続側ではエンドポイントも) を閉じないよう、一緒に持っておく
+struct SessionStream {
+    stream: BiStream,
+    _connection: Connection,
+    _endpoint: Option<Endpoint<endpoint_side::Client>>,
+}
+
+impl AsyncRead for SessionStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SessionStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
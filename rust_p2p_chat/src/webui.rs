@@ -0,0 +1,173 @@
+use crate::builder::Limits;
+use crate::debug::Negotiated;
+use crate::runtime::{BoxStream, Runtime};
+use crate::transport::ServerStream;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_rustls::TlsAcceptor;
+
+// `listen --web` で待受と同じポートから配る、ブラウザ用のページ
+// 最初のバイトでTLSかどうかを見分け、HTTPの要求がWebSocketへの切り替えならチャットの接続として、そうでなければページを返す
+// ページは Conversation (p2pchat-core) と同じ範囲のEnvelopeでやり取りする小さなクライアントで、ファイルは受け取らない
+
+const PAGE: &str = include_str!("webui/index.html");
+
+// ページが使うのは埋め込みのスクリプト・スタイルと、同じホストへのWebSocketだけ
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src ws: wss:";
+
+// 要求のヘッダーの大きさの上限
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+// TLSのハンドシェイクと要求のヘッダーを受け取り終えるまでの時間
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// 受け付けた接続を処理する。チャットの接続なら、ハンドシェイクを済ませたWebSocketとTLSの内容 (TLSでなければNone) を返す
+// ページを返した (またはページ以外の要求を断った) 場合はNone
+pub(crate) async fn accept(
+    stream: BoxStream,
+    tls_acceptor: &TlsAcceptor,
+    limits: &Limits,
+    runtime: &Runtime,
+) -> Result<Option<(ServerStream, Option<Negotiated>)>, Error> {
+    let (stream, negotiated, head) = runtime
+        .timeout(HEAD_TIMEOUT, read_request(stream, tls_acceptor))
+        .await
+        .map_err(|_| "HTTPの要求が時間内に届きませんでした")??;
+    let request = Request::parse(&head).ok_or("HTTPの要求を解釈できません")?;
+    if request.websocket {
+        // 読んだヘッダーはWebSocketのハンドシェイクでもう一度読む
+        let stream: BoxStream = Box::new(Prefixed::new(head, stream));
+        let ws_stream = tokio_tungstenite::accept_async_with_config(stream, limits.websocket_config()).await?;
+        return Ok(Some((ws_stream, negotiated)));
+    }
+    respond(stream, &request).await?;
+    tracing::debug!(method = %request.method, path = %request.path, "ブラウザにページを返しました");
+    Ok(None)
+}
+
+// TLSで始まっていればハンドシェイクを行い、要求のヘッダーの終わりまで読む
+async fn read_request(mut stream: BoxStream, tls_acceptor: &TlsAcceptor) -> Result<(BoxStream, Option<Negotiated>, Vec<u8>), Error> {
+    let mut first = [0; 1];
+    if stream.read(&mut first).await? == 0 {
+        return Err("要求の前に接続が閉じられました".into());
+    }
+    // TLSのレコードはハンドシェイク (0x16) から始まる。それ以外は平文のHTTP (ws://、http://)
+    let (mut stream, negotiated): (BoxStream, _) = match first[0] {
+        0x16 => {
+            let tls_stream = tls_acceptor.accept(Prefixed::new(first.to_vec(), stream)).await?;
+            let negotiated = Negotiated::from_connection(tls_stream.get_ref().1);
+            (Box::new(tls_stream), Some(negotiated))
+        }
+        _ => (Box::new(Prefixed::new(first.to_vec(), stream)), None),
+    };
+
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_BYTES {
+            return Err("HTTPの要求のヘッダーが大きすぎます".into());
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err("HTTPの要求の途中で接続が閉じられました".into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok((stream, negotiated, head))
+}
+
+// 要求のうち、応答を決めるのに使う部分
+struct Request {
+    method: String,
+    /// クエリを除いたパス
+    path: String,
+    /// Upgrade: websocket がある
+    websocket: bool,
+}
+
+impl Request {
+    fn parse(head: &[u8]) -> Option<Self> {
+        let head = std::str::from_utf8(head).ok()?;
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split(' ');
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let path = target.split('?').next().unwrap_or(target).to_string();
+        let websocket = lines.take_while(|line| !line.is_empty()).any(|line| {
+            line.split_once(':').is_some_and(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("upgrade") && value.trim().eq_ignore_ascii_case("websocket")
+            })
+        });
+        Some(Self { method, path, websocket })
+    }
+}
+
+// ページを返して接続を閉じる (それ以外のパスは404、GET・HEAD以外は405)
+async fn respond(mut stream: BoxStream, request: &Request) -> io::Result<()> {
+    let (status, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET" | "HEAD", "/" | "/index.html") => ("200 OK", PAGE),
+        ("GET" | "HEAD", _) => ("404 Not Found", "Not Found\n"),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n"),
+    };
+    let content_type = match status {
+        "200 OK" => "text/html; charset=utf-8",
+        _ => "text/plain; charset=utf-8",
+    };
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nContent-Security-Policy: {}\r\n\
+         X-Content-Type-Options: nosniff\r\nReferrer-Policy: no-referrer\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+        CONTENT_SECURITY_POLICY
+    );
+    stream.write_all(header.as_bytes()).await?;
+    if request.method != "HEAD" {
+        stream.write_all(body.as_bytes()).await?;
+    }
+    stream.shutdown().await
+}
+
+// 先に読んだバイトを返してから、元のストリームを読む
+struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix, pos: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
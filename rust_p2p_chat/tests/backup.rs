@@ -0,0 +1,37 @@
+// 鍵などをまとめたバックアップを別のプロファイルに復元でき、秘密鍵とアドレス帳のファイルは本人だけが読めることを確かめる
+
+mod harness;
+
+use harness::TempDir;
+use rust_p2p_chat::backup;
+use rust_p2p_chat::identity::Identity;
+use rust_p2p_chat::paths::{Paths, DEFAULT_PROFILE};
+use std::path::Path;
+
+#[cfg(unix)]
+fn assert_private(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[cfg(not(unix))]
+fn assert_private(_: &Path) {}
+
+#[test]
+fn restored_keys_are_private() {
+    let dir = TempDir::new("backup");
+    let source = Paths::resolve(Some(&dir.join("source")), DEFAULT_PROFILE, true).unwrap();
+    let target = Paths::resolve(Some(&dir.join("target")), DEFAULT_PROFILE, true).unwrap();
+    let identity = Identity::load_or_generate(source.identity_cert_file(), source.identity_key_file()).unwrap();
+    std::fs::write(source.libp2p_key_file(), b"libp2p key").unwrap();
+    std::fs::write(source.contacts_file(), "{}").unwrap();
+
+    let archive = dir.join("backup.json");
+    backup::create(&source, &archive, "correct horse").unwrap();
+    let restored = backup::restore(&target, &archive, "correct horse", false).unwrap();
+    assert!(restored.iter().any(|name| name == "identity_key.der"), "{:?}", restored);
+    assert_eq!(std::fs::read(target.identity_key_file()).unwrap(), identity.key_der);
+    assert_private(&target.identity_key_file());
+    assert_private(&target.libp2p_key_file());
+    assert_private(&target.contacts_file());
+}
@@ -0,0 +1,370 @@
+// IRC・XMPP・MQTT・Webhookのブリッジが、デーモンの部屋と出来事をそれぞれの形に、発言を送信の要求に変換することを確かめる
+// デーモンの代わりに、制御用ソケットの要求に決まった応答を返す偽物を使う
+
+#![cfg(unix)]
+
+mod harness;
+
+use harness::TempDir;
+use rust_p2p_chat::bridge::irc::{self, IrcSettings};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::{broadcast, mpsc};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// 部屋を1つ持つ偽のデーモン。eventsの購読者にはeventsに送った行を流し、sendの要求はsentに渡す
+fn fake_daemon(socket: &PathBuf, events: broadcast::Sender<String>, sent: mpsc::UnboundedSender<Value>) {
+    let listener = UnixListener::bind(socket).expect("制御用ソケットを作れません");
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (events, sent) = (events.clone(), sent.clone());
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let request: Value = serde_json::from_str(&line).unwrap();
+                    let response = match request["cmd"].as_str().unwrap() {
+                        "rooms" => json!({ "ok": true, "rooms": [{
+                            "name": "127.0.0.1", "peer": "127.0.0.1:8080", "nickname": "alice", "role": "client", "since": "2024-01-01T00:00:00+09:00"
+                        }] }),
+                        "events" => {
+                            let mut events = events.subscribe();
+                            writer.write_all(b"{\"ok\":true}\n").await.unwrap();
+                            while let Ok(event) = events.recv().await {
+                                if writer.write_all(format!("{}\n", event).as_bytes()).await.is_err() {
+                                    return;
+                                }
+                            }
+                            return;
+                        }
+                        "send" => {
+                            sent.send(request).unwrap();
+                            json!({ "ok": true })
+                        }
+                        _ => json!({ "ok": false, "error": "対応していない要求です" }),
+                    };
+                    writer.write_all(format!("{}\n", response).as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+}
+
+// prefixから始まる行が届くまで読み、その行を返す
+async fn expect(lines: &mut Lines<BufReader<OwnedReadHalf>>, prefix: &str) -> String {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let line = lines.next_line().await.unwrap().expect("接続が閉じられました");
+            if line.starts_with(prefix) {
+                return line;
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} が届きません", prefix))
+}
+
+#[tokio::test]
+async fn rooms_become_channels() {
+    let dir = TempDir::new("bridge");
+    let socket = dir.join("bridge.sock");
+    let (events, _) = broadcast::channel(16);
+    let (sent_tx, mut sent) = mpsc::unbounded_channel();
+    fake_daemon(&socket, events.clone(), sent_tx);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(irc::serve(listener, IrcSettings { socket: socket.clone(), password: None }));
+
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b"NICK me\r\nUSER me 0 * :me\r\n").await.unwrap();
+    expect(&mut lines, ":p2pchat 001 me ").await;
+    // 開いている部屋に参加し、連絡先のニックネームが相手として見える
+    expect(&mut lines, ":me!me@p2pchat JOIN #127.0.0.1").await;
+    assert_eq!(expect(&mut lines, ":p2pchat 353 ").await, ":p2pchat 353 me = #127.0.0.1 :me alice");
+    expect(&mut lines, ":p2pchat 366 ").await;
+
+    // 複数行のメッセージは1行ずつ届く
+    let message = json!({ "room": "127.0.0.1", "event": "message", "from": "alice", "body": "こんにちは\n元気?" });
+    events.send(message.to_string()).unwrap();
+    assert_eq!(expect(&mut lines, ":alice").await, ":alice!alice@p2pchat PRIVMSG #127.0.0.1 :こんにちは");
+    assert_eq!(expect(&mut lines, ":alice").await, ":alice!alice@p2pchat PRIVMSG #127.0.0.1 :元気?");
+
+    // チャンネルへの発言は部屋への送信になり、/me は * 付きの本文になる
+    writer.write_all(b"PRIVMSG #127.0.0.1 :hi\r\nPRIVMSG alice :\x01ACTION waves\x01\r\n").await.unwrap();
+    let request = tokio::time::timeout(TIMEOUT, sent.recv()).await.unwrap().unwrap();
+    assert_eq!(request, json!({ "cmd": "send", "room": "127.0.0.1", "body": "hi" }));
+    let request = tokio::time::timeout(TIMEOUT, sent.recv()).await.unwrap().unwrap();
+    assert_eq!(request["body"], "* me waves");
+
+    // 自分が送ったメッセージのsentは見せず (IRCクライアントが表示済み)、切断は相手のPARTになる
+    for body in ["hi", "* me waves"] {
+        events.send(json!({ "room": "127.0.0.1", "event": "sent", "seq": 1, "body": body }).to_string()).unwrap();
+    }
+    events.send(json!({ "room": "127.0.0.1", "event": "disconnected", "peer": "127.0.0.1" }).to_string()).unwrap();
+    assert_eq!(expect(&mut lines, ":").await, ":alice!alice@p2pchat PART #127.0.0.1 :相手との接続が切れました");
+}
+
+// XMPPサーバーから届いたものを、containsを含むまで読む
+#[cfg(feature = "xmpp")]
+async fn expect_xml(reader: &mut OwnedReadHalf, received: &mut String, contains: &str) {
+    use tokio::io::AsyncReadExt;
+    tokio::time::timeout(TIMEOUT, async {
+        while !received.contains(contains) {
+            let mut buf = [0; 4096];
+            let n = reader.read(&mut buf).await.unwrap();
+            assert!(n > 0, "接続が閉じられました");
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{} が届きません: {}", contains, received));
+    received.clear();
+}
+
+#[cfg(feature = "xmpp")]
+#[tokio::test]
+async fn contacts_become_jids() {
+    use rust_p2p_chat::bridge::xmpp::{self, XmppSettings};
+    use rust_p2p_chat::contacts::AddressBook;
+
+    let dir = TempDir::new("xmpp");
+    let socket = dir.join("xmpp.sock");
+    let contacts_file = dir.join("contacts.json");
+    std::fs::write(&contacts_file, r#"{"alice": {"uri": "wss://127.0.0.1:8080"}}"#).unwrap();
+    let (events, _) = broadcast::channel(16);
+    let (sent_tx, mut sent) = mpsc::unbounded_channel();
+    fake_daemon(&socket, events.clone(), sent_tx);
+
+    // XMPPサーバーの代わり
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let settings = XmppSettings {
+        socket: socket.clone(),
+        server: server.local_addr().unwrap().to_string(),
+        domain: "p2p.test".to_string(),
+        secret: "secret".to_string(),
+        owner: "bob@example.org".to_string(),
+        contacts: AddressBook::load(&contacts_file).unwrap(),
+    };
+    let bridge = tokio::spawn(xmpp::run(settings));
+    let (stream, _) = server.accept().await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    let mut received = String::new();
+
+    // ハンドシェイクはストリームのidとパスワードのSHA-1 (16進数)
+    expect_xml(&mut reader, &mut received, "to='p2p.test'>").await;
+    writer
+        .write_all(b"<?xml version='1.0'?><stream:stream xmlns:stream='http://etherx.jabber.org/streams' xmlns='jabber:component:accept' from='p2p.test' id='3BF96D32'>")
+        .await
+        .unwrap();
+    expect_xml(&mut reader, &mut received, "<handshake>b09ea9b3b7f586be8a08d0a3dd7466f110aeb136</handshake>").await;
+    writer.write_all(b"<handshake/>").await.unwrap();
+
+    // 開いている部屋の相手は、アドレスが一致する連絡先の名前のJIDとしてオンラインになる
+    expect_xml(&mut reader, &mut received, "<presence from='alice@p2p.test' to='bob@example.org'/>").await;
+
+    // 持ち主からのメッセージは部屋への送信になる
+    writer
+        .write_all(b"<message from='bob@example.org/phone' to='alice@p2p.test' type='chat' id='m1'><body>hi &amp; bye</body></message>")
+        .await
+        .unwrap();
+    let request = tokio::time::timeout(TIMEOUT, sent.recv()).await.unwrap().unwrap();
+    assert_eq!(request, json!({ "cmd": "send", "room": "127.0.0.1", "body": "hi & bye" }));
+
+    // 持ち主以外からのメッセージは断る
+    writer
+        .write_all(b"<message from='eve@example.org' to='alice@p2p.test' type='chat' id='m2'><body>x</body></message>")
+        .await
+        .unwrap();
+    expect_xml(&mut reader, &mut received, "<forbidden xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>").await;
+
+    // 相手のメッセージと切断
+    events.send(json!({ "room": "127.0.0.1", "event": "message", "from": "alice", "body": "<hello>" }).to_string()).unwrap();
+    expect_xml(&mut reader, &mut received, "<message from='alice@p2p.test' to='bob@example.org' type='chat'><body>&lt;hello&gt;</body></message>").await;
+    events.send(json!({ "room": "127.0.0.1", "event": "disconnected", "peer": "127.0.0.1" }).to_string()).unwrap();
+    expect_xml(&mut reader, &mut received, "<presence from='alice@p2p.test' to='bob@example.org' type='unavailable'/>").await;
+
+    bridge.abort();
+}
+
+// MQTTのパケットを1つ読み、(固定ヘッダー, 本文) を返す
+async fn read_packet(reader: &mut OwnedReadHalf) -> (u8, Vec<u8>) {
+    use tokio::io::AsyncReadExt;
+    tokio::time::timeout(TIMEOUT, async {
+        let header = reader.read_u8().await.unwrap();
+        let (mut length, mut shift) = (0usize, 0);
+        loop {
+            let byte = reader.read_u8().await.unwrap();
+            length |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        (header, body)
+    })
+    .await
+    .expect("MQTTのパケットが届きません")
+}
+
+// 長さ (2バイト) の後に続く文字列
+fn mqtt_str(value: &str) -> Vec<u8> {
+    let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
+#[tokio::test]
+async fn topics_become_rooms() {
+    use rust_p2p_chat::bridge::mqtt::{self, MqttSettings};
+
+    let dir = TempDir::new("mqtt");
+    let socket = dir.join("mqtt.sock");
+    let (events, _) = broadcast::channel(16);
+    let (sent_tx, mut sent) = mpsc::unbounded_channel();
+    fake_daemon(&socket, events.clone(), sent_tx);
+
+    // MQTTブローカーの代わり
+    let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let settings = MqttSettings {
+        socket: socket.clone(),
+        broker: broker.local_addr().unwrap().to_string(),
+        client_id: "p2pchat".to_string(),
+        username: Some("bob".to_string()),
+        password: Some("secret".to_string()),
+        publish: "p2pchat/{room}/messages".to_string(),
+        subscribe: "p2pchat/{room}/send".to_string(),
+        room: None,
+        json: false,
+    };
+    let bridge = tokio::spawn(mqtt::run(settings));
+    let (stream, _) = broker.accept().await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+
+    // CONNECT (MQTT 3.1.1、ユーザー名とパスワード付き) と、{room} をワイルドカードにしたSUBSCRIBE
+    let (header, body) = read_packet(&mut reader).await;
+    assert_eq!(header, 0x10);
+    assert_eq!(&body[..8], b"\x00\x04MQTT\x04\xc2");
+    assert!(body.ends_with(&[mqtt_str("p2pchat"), mqtt_str("bob"), mqtt_str("secret")].concat()));
+    writer.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+    let (header, body) = read_packet(&mut reader).await;
+    assert_eq!(header, 0x82);
+    assert_eq!(&body[2..], [mqtt_str("p2pchat/+/send"), vec![1]].concat());
+    writer.write_all(&[0x90, 3, body[0], body[1], 1]).await.unwrap();
+
+    // 購読したトピックのペイロードは、トピックの {room} の部屋への送信になる (QoS 1にはPUBACKを返す)
+    let mut publish = [mqtt_str("p2pchat/127.0.0.1/send"), vec![0, 7], "ドアが開きました".as_bytes().to_vec()].concat();
+    publish.splice(0..0, [0x32, publish.len() as u8]);
+    writer.write_all(&publish).await.unwrap();
+    assert_eq!(read_packet(&mut reader).await, (0x40, vec![0, 7]));
+    let request = tokio::time::timeout(TIMEOUT, sent.recv()).await.unwrap().unwrap();
+    assert_eq!(request, json!({ "cmd": "send", "room": "127.0.0.1", "body": "ドアが開きました" }));
+
+    // 相手のメッセージは部屋のトピックにPUBLISHされる (部屋の名前の # は符号化する)
+    events.send(json!({ "room": "127.0.0.1#2", "event": "message", "from": "alice", "body": "了解" }).to_string()).unwrap();
+    let (header, body) = read_packet(&mut reader).await;
+    assert_eq!(header, 0x32);
+    let topic = mqtt_str("p2pchat/127.0.0.1%232/messages");
+    assert_eq!(&body[..topic.len()], topic);
+    assert_eq!(&body[topic.len() + 2..], "了解".as_bytes());
+
+    bridge.abort();
+}
+
+// HTTPの要求を1つ読み、本文を返す
+#[cfg(feature = "discovery")]
+async fn read_http_body(stream: &mut TcpStream) -> String {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+        let text = String::from_utf8_lossy(&buf).to_string();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+                .unwrap_or(0);
+            if body.len() >= length {
+                return body.to_string();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "discovery")]
+#[tokio::test]
+async fn rooms_mirror_to_webhooks() {
+    use rust_p2p_chat::bridge::relay::{self, RelaySettings};
+    use rust_p2p_chat::bridge::RelayService;
+    use tokio::io::AsyncReadExt;
+
+    let dir = TempDir::new("relay");
+    let socket = dir.join("relay.sock");
+    let (events, _) = broadcast::channel(16);
+    let (sent_tx, mut sent) = mpsc::unbounded_channel();
+    fake_daemon(&socket, events.clone(), sent_tx);
+
+    // SlackのIncoming Webhookの代わり。受け取った本文を送る
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook = format!("http://{}/services/T0/B0/x", server.local_addr().unwrap());
+    let (posted_tx, mut posted) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            let body = read_http_body(&mut stream).await;
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap();
+            let _ = posted_tx.send(serde_json::from_str::<Value>(&body).unwrap());
+        }
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let settings = RelaySettings {
+        socket: socket.clone(),
+        room: "127.0.0.1".to_string(),
+        webhook,
+        service: RelayService::Slack,
+        token: Some("t0ken".to_string()),
+    };
+    let bridge = tokio::spawn(relay::run(settings, Some(listener)));
+    assert_eq!(RelayService::detect("https://hooks.slack.com/services/T0/B0/x"), Some(RelayService::Slack));
+    assert_eq!(RelayService::detect("https://discord.com/api/webhooks/1/x"), Some(RelayService::Discord));
+
+    let post = |form: &'static str| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}", addr, form.len(), form);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    // トークンが違うOutgoing Webhookは断る
+    assert!(post("token=wrong&user_name=bob&text=hi").await.starts_with("HTTP/1.1 401 "));
+    // SlackのOutgoing Webhookは、発言者の名前を付けて部屋に送る
+    assert!(post("token=t0ken&user_name=bob&text=1%20%26lt%3B%202").await.starts_with("HTTP/1.1 200 "));
+    let request = tokio::time::timeout(TIMEOUT, sent.recv()).await.unwrap().unwrap();
+    assert_eq!(request, json!({ "cmd": "send", "room": "127.0.0.1", "body": "bob: 1 < 2" }));
+
+    // 送ったメッセージのsentは転送し返さず、相手のメッセージと、ctl sendなどで送ったメッセージは転送する
+    events.send(json!({ "room": "127.0.0.1", "event": "sent", "seq": 1, "body": "bob: 1 < 2" }).to_string()).unwrap();
+    events.send(json!({ "room": "other", "event": "message", "from": "carol", "body": "別の部屋" }).to_string()).unwrap();
+    events.send(json!({ "room": "127.0.0.1", "event": "message", "from": "alice", "body": "<了解>" }).to_string()).unwrap();
+    events.send(json!({ "room": "127.0.0.1", "event": "sent", "seq": 2, "body": "手元から" }).to_string()).unwrap();
+    let payload = tokio::time::timeout(TIMEOUT, posted.recv()).await.unwrap().unwrap();
+    assert_eq!(payload, json!({ "username": "alice", "text": "&lt;了解&gt;" }));
+    let payload = tokio::time::timeout(TIMEOUT, posted.recv()).await.unwrap().unwrap();
+    assert_eq!(payload["text"], "手元から");
+
+    bridge.abort();
+}
@@ -0,0 +1,175 @@
+// コマンドラインの解釈と保存したデータの扱いを、ビルドしたプログラムを実際に動かして確かめる
+#![cfg(feature = "cli")]
+
+mod harness;
+
+use harness::TempDir;
+use rust_p2p_chat::contacts::AddressBook;
+use rust_p2p_chat::history::{Direction, EventKind, History, MessageSignature};
+use rust_p2p_chat::identity::{self, Identity};
+use rust_p2p_chat::vault::{self, Vault};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(data_dir: &Path, args: &[&str], envs: &[(&str, &str)]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_p2p_chat"))
+        .arg("--data-dir")
+        .arg(data_dir)
+        .args(args)
+        .env_remove("P2PCHAT_DOWNLOAD_DIR")
+        .env_remove(vault::PASSPHRASE_ENV)
+        .envs(envs.iter().copied())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// 失敗するはずのコマンドを実行し、標準エラー出力を返す
+fn run_failing(data_dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_p2p_chat")).arg("--data-dir").arg(data_dir).args(args).output().unwrap();
+    assert!(!output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn files_dir(data_dir: &Path, name: &str) -> Option<PathBuf> {
+    let book = AddressBook::load(data_dir.join("contacts.json")).unwrap();
+    book.get(name).expect("連絡先がありません").download_dir.clone()
+}
+
+// グローバルの --download-dir・P2PCHAT_DOWNLOAD_DIR は連絡先の保存先 (--files-dir) にならない
+#[test]
+fn global_download_dir_is_not_saved_to_contacts() {
+    let dir = TempDir::new("cli-download-dir");
+    run(&dir, &["contacts", "add", "alice", "wss://127.0.0.1:8080"], &[("P2PCHAT_DOWNLOAD_DIR", "/tmp/envdl")]);
+    run(&dir, &["--download-dir", "/tmp/globaldl", "contacts", "add", "bob", "wss://127.0.0.1:8081"], &[]);
+    run(&dir, &["contacts", "add", "carol", "wss://127.0.0.1:8082", "--files-dir", "/tmp/caroldl"], &[]);
+    assert_eq!(files_dir(&dir, "alice"), None);
+    assert_eq!(files_dir(&dir, "bob"), None);
+    assert_eq!(files_dir(&dir, "carol"), Some(PathBuf::from("/tmp/caroldl")));
+}
+
+// メールのパスフレーズを含むアドレス帳は本人だけが読める
+#[cfg(unix)]
+#[test]
+fn contacts_with_mail_passphrase_are_private() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = TempDir::new("cli-contacts-private");
+    run(&dir, &["contacts", "add", "alice", "wss://127.0.0.1:8080", "--mail-passphrase", "correct horse"], &[]);
+    let mode = std::fs::metadata(dir.join("contacts.json")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let book = AddressBook::load(dir.join("contacts.json")).unwrap();
+    assert_eq!(book.get("alice").unwrap().mail_passphrase.as_deref(), Some("correct horse"));
+}
+
+// 使用中のプロファイルは削除できず、別のプロファイルからなら削除できる
+#[test]
+fn active_profile_cannot_be_deleted() {
+    let dir = TempDir::new("cli-profile-delete");
+    run(&dir, &["profile", "create", "work"], &[]);
+    run(&dir, &["--profile", "work", "contacts", "add", "alice", "wss://127.0.0.1:8080"], &[]);
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_p2p_chat"))
+        .arg("--data-dir")
+        .arg(dir.as_os_str())
+        .args(["--profile", "work", "profile", "delete", "work", "--yes"])
+        .env_remove("P2PCHAT_PROFILE")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("使用中のプロファイル"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(dir.join("profiles").join("work").join("contacts.json").exists());
+    run(&dir, &["profile", "delete", "work", "--yes"], &[]);
+    assert!(!dir.join("profiles").join("work").exists());
+}
+
+// 平文の履歴を history encrypt で暗号化すると、パスフレーズで開け、暗号化する前の履歴がそのままバックアップに残る
+#[test]
+fn history_encrypt_keeps_a_plaintext_backup() {
+    const FIXTURE: &str = concat!(
+        r#"{"seq":1,"timestamp":"2024-05-01T10:00:00+09:00","peer":"alice","kind":"message","direction":"incoming","body":"こんにちは"}"#,
+        "\n",
+        r#"{"seq":2,"timestamp":"2024-05-01T10:01:00+09:00","peer":"alice","kind":"message","direction":"outgoing","body":"やあ"}"#,
+        "\n",
+    );
+    let dir = TempDir::new("cli-encrypt");
+    std::fs::write(dir.join("history.jsonl"), FIXTURE).unwrap();
+
+    run(&dir, &["history", "encrypt"], &[(vault::PASSPHRASE_ENV, "correct horse")]);
+
+    let encrypted = std::fs::read_to_string(dir.join("history.jsonl")).unwrap();
+    assert!(encrypted.lines().all(vault::is_sealed), "{}", encrypted);
+    let key_file = dir.join("history.key");
+    assert!(Vault::unlock(&key_file, "wrong horse").is_err());
+    let history = History::open(dir.join("history.jsonl"), Some(Vault::unlock(&key_file, "correct horse").unwrap())).unwrap();
+    let bodies: Vec<String> = history
+        .load()
+        .unwrap()
+        .into_iter()
+        .map(|entry| match entry.event {
+            EventKind::Message { body, .. } => body,
+            other => panic!("メッセージではありません: {:?}", other),
+        })
+        .collect();
+    assert_eq!(bodies, ["こんにちは", "やあ"]);
+
+    let backups: Vec<PathBuf> = std::fs::read_dir(dir.join("backups")).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(backups.len(), 1, "{:?}", backups);
+    assert!(backups[0].to_string_lossy().ends_with("-History-plaintext"), "{:?}", backups[0]);
+    assert_eq!(std::fs::read_to_string(backups[0].join("history.jsonl")).unwrap(), FIXTURE);
+}
+
+// history export の形式の指定がグローバルの --format (標準入出力の形式) とぶつからない
+#[test]
+fn history_export_format_is_separate_from_output_format() {
+    let dir = TempDir::new("cli-export-format");
+    let mut history = History::open(dir.join("history.jsonl"), None).unwrap();
+    history.append("alice", EventKind::System { text: "接続しました".to_string() }).unwrap();
+    let markdown = dir.join("export.md");
+    run(&dir, &["--format", "jsonl", "history", "export", "--export-format", "markdown", "--output", markdown.to_str().unwrap()], &[]);
+    assert!(std::fs::read_to_string(&markdown).unwrap().starts_with("# チャット履歴"));
+}
+
+// 署名付きトランスクリプトは、署名者を信頼しているデータディレクトリか --signer で指定したときだけ検証に通る
+#[test]
+fn transcript_from_an_unknown_signer_is_rejected() {
+    let dir = TempDir::new("cli-transcript-signer");
+    let other = TempDir::new("cli-transcript-signer-other");
+    let alice = Identity::load_or_generate(dir.join("identity_cert.der"), dir.join("identity_key.der")).unwrap();
+    let mut history = History::open(dir.join("history.jsonl"), None).unwrap();
+    let sent = EventKind::Message { direction: Direction::Outgoing, body: "会議は3時から".to_string() };
+    history
+        .append_signed("bob", sent, |seq| {
+            let sig = alice.sign(&identity::signed_content(seq, "会議は3時から")).unwrap();
+            Some(MessageSignature { signer: alice.fingerprint(), sig })
+        })
+        .unwrap();
+    let transcript = dir.join("transcript.json");
+    let transcript = transcript.to_str().unwrap();
+    run(&dir, &["history", "export", "--signed", "--output", transcript], &[]);
+
+    run(&dir, &["history", "verify", transcript], &[]);
+    let stderr = run_failing(&other, &["history", "verify", transcript]);
+    assert!(stderr.contains("信頼していない鍵"), "{}", stderr);
+    run(&other, &["history", "verify", transcript, "--signer", &alice.fingerprint()], &[]);
+}
+
+fn help(subcommand: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_p2p_chat")).args([subcommand, "--help"]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// listen・connect のオプションは環境変数でも指定できる (--help に [env: ...] が表示される)
+#[test]
+fn listen_and_connect_options_have_env_vars() {
+    let listen = help("listen");
+    assert!(listen.contains("[env: P2PCHAT_CODE="), "{}", listen);
+    let connect = help("connect");
+    for name in ["P2PCHAT_CONNECT_CODE", "P2PCHAT_VIA_SSH", "P2PCHAT_DOWNLOAD_PER_PEER"] {
+        assert!(connect.contains(&format!("[env: {}=", name)), "{}", connect);
+    }
+    // config.toml の per_peer = true はコマンドラインと環境変数で打ち消せる
+    let dir = TempDir::new("cli-download-per-peer");
+    run(&dir, &["--download-per-peer", "contacts", "list"], &[]);
+    run(&dir, &["--download-per-peer=false", "contacts", "list"], &[]);
+    run(&dir, &["contacts", "list"], &[("P2PCHAT_DOWNLOAD_PER_PEER", "false")]);
+}
@@ -0,0 +1,70 @@
+// conformance が、この実装どうし (接続する側・待ち受ける側のどちらを確かめる場合も) ですべて合格することを確かめる
+
+mod harness;
+
+use harness::{Node, LISTEN_ADDR};
+use rust_p2p_chat::builder::IdentitySource;
+use rust_p2p_chat::conformance::{self, Outcome};
+use rust_p2p_chat::runtime::MemoryTransport;
+use std::time::Duration;
+
+fn outcomes(report: &conformance::Report) -> Vec<(&'static str, Outcome)> {
+    report.checks.iter().map(|check| (check.name, check.outcome)).collect()
+}
+
+const ALL_PASS: [(&str, Outcome); 7] = [
+    ("handshake", Outcome::Pass),
+    ("identity", Outcome::Pass),
+    ("session", Outcome::Pass),
+    ("delivered", Outcome::Pass),
+    ("ping", Outcome::Pass),
+    ("backfill", Outcome::Pass),
+    ("close", Outcome::Pass),
+];
+
+#[tokio::test]
+async fn conformance_against_listener() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let fingerprint = IdentitySource::Profile.load(&alice.paths).unwrap().fingerprint();
+
+    // 受け付けたセッションを捨てると応答しなくなるため、テストの終わりまで持っておく
+    let listening = tokio::spawn(async move {
+        let session = alice.peer.listen(LISTEN_ADDR.parse().unwrap()).await.ok();
+        std::future::pending::<()>().await;
+        drop(session);
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let settings = bob.peer.client().uri(format!("wss://{}#{}", LISTEN_ADDR, fingerprint)).build().unwrap();
+    let report = conformance::connect(&settings).await.expect("確かめられません");
+    assert_eq!(outcomes(&report), ALL_PASS, "{:?}", report);
+    assert!(report.passed());
+    listening.abort();
+}
+
+#[tokio::test]
+async fn conformance_of_connecting_client() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let settings = alice.peer.listener().addr(LISTEN_ADDR.parse().unwrap()).build().unwrap();
+    let connecting = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let session = bob.peer.connect(&format!("wss://{}", LISTEN_ADDR)).await.expect("接続できません");
+        // 確かめ終えるまで接続を保つ
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        session
+    };
+    let (report, _session) = tokio::join!(conformance::accept(settings), connecting);
+    let report = report.expect("確かめられません");
+    assert_eq!(outcomes(&report), ALL_PASS, "{:?}", report);
+}
+
+#[test]
+fn vectors_cover_every_kind() {
+    let vectors = conformance::vectors();
+    assert!(vectors.iter().any(|vector| vector["kind"] == "signed_content"));
+    let names: Vec<_> = vectors.iter().filter(|vector| vector["kind"] == "envelope").map(|vector| vector["envelope"]["type"].as_str().unwrap()).collect();
+    for kind in ["chat", "identity", "resume", "session", "backfill_request", "backfill", "delivered", "ping", "pong", "file_start", "file_chunk", "file_end"] {
+        assert!(names.contains(&kind), "{}", kind);
+    }
+}
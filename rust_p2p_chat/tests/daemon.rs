@@ -0,0 +1,343 @@
+// デーモンのREST API・gRPCで、接続中の部屋の一覧・メッセージの送信・履歴の読み取りができることを確かめる
+// デーモンと相手はそれぞれの一時ディレクトリを使い、ループバックのTCPでつなぐ
+
+#![cfg(unix)]
+
+mod harness;
+
+use harness::{wait_for, TempDir};
+use rust_p2p_chat::daemon::{self, api::ApiSettings};
+use rust_p2p_chat::paths::{Paths, DEFAULT_PROFILE};
+use rust_p2p_chat::{ChatOptions, Event, Peer};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// 空いているループバックのポート
+async fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+}
+
+// 要求を1つ送り、ステータスとJSONの本文を返す
+async fn http(addr: SocketAddr, method: &str, path: &str, token: &str, body: Option<Value>) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        token,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[tokio::test]
+async fn rest_api() {
+    let dir = TempDir::new("daemon");
+    let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap();
+    let options = ChatOptions::embedded(&paths).unwrap();
+    let shutdown = options.shutdown.clone();
+    let (listen, api) = (free_addr().await, free_addr().await);
+    let settings = ApiSettings { rest: Some(api), token: Some("secret".to_string()), ..Default::default() };
+    let running = daemon::run(Some(listen), paths.control_socket(), settings, paths, options);
+
+    let client_dir = TempDir::new("daemon-client");
+    let client_paths = Paths::resolve(Some(&client_dir), DEFAULT_PROFILE, true).unwrap();
+    let client = Peer::new(client_paths.clone(), ChatOptions::embedded(&client_paths).unwrap());
+
+    let scenario = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // トークンがなければ断る
+        let (status, body) = http(api, "GET", "/peers", "wrong", None).await;
+        assert_eq!((status, body["ok"].clone()), (401, json!(false)));
+
+        let mut session = client.connect(&format!("wss://{}", listen)).await.expect("デーモンに接続できません");
+        wait_for(&mut session, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+        let (status, body) = http(api, "GET", "/peers", "secret", None).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["peers"][0]["name"], "127.0.0.1");
+
+        // POST /messages は部屋の相手に届く
+        let (status, _) = http(api, "POST", "/messages", "secret", Some(json!({ "room": "127.0.0.1", "body": "APIから" }))).await;
+        assert_eq!(status, 202);
+        let received = wait_for(&mut session, |event| match event {
+            Event::MessageReceived { body, .. } => Some(body),
+            _ => None,
+        })
+        .await;
+        assert_eq!(received, "APIから");
+        let (status, _) = http(api, "POST", "/messages", "secret", Some(json!({ "room": "192.0.2.1", "body": "x" }))).await;
+        assert_eq!(status, 404);
+
+        // GET /messages は送受信したメッセージを古い順に返し、nextから続きを読める
+        session.send_text("返事").unwrap();
+        let messages = tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+            loop {
+                let (_, body) = http(api, "GET", "/messages?since=0", "secret", None).await;
+                if body["messages"].as_array().unwrap().len() == 2 {
+                    return body;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("メッセージが履歴に記録されません");
+        let bodies: Vec<&str> = messages["messages"].as_array().unwrap().iter().map(|m| m["body"].as_str().unwrap()).collect();
+        assert_eq!(bodies, ["APIから", "返事"]);
+        assert_eq!(messages["messages"][1]["direction"], "incoming");
+        let next = messages["next"].as_u64().unwrap();
+        let (_, body) = http(api, "GET", &format!("/messages?since={}", next), "secret", None).await;
+        assert_eq!(body["messages"], json!([]));
+        assert_eq!(body["next"], next);
+
+        shutdown.cancel();
+    };
+    let (result, ()) = tokio::join!(running, scenario);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn atom_feed() {
+    use rust_p2p_chat::daemon::feed::FeedSettings;
+
+    let dir = TempDir::new("feed");
+    let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap();
+    let options = ChatOptions::embedded(&paths).unwrap();
+    let shutdown = options.shutdown.clone();
+    let (listen, feed) = (free_addr().await, free_addr().await);
+    let settings = ApiSettings { feed: Some(FeedSettings { addr: feed, room: "127.0.0.1".to_string() }), ..Default::default() };
+    let running = daemon::run(Some(listen), paths.control_socket(), settings, paths, options);
+
+    let client_dir = TempDir::new("feed-client");
+    let client_paths = Paths::resolve(Some(&client_dir), DEFAULT_PROFILE, true).unwrap();
+    let client = Peer::new(client_paths.clone(), ChatOptions::embedded(&client_paths).unwrap());
+
+    // トークンなしで取得でき、部屋のメッセージが新しい順に並ぶ
+    let fetch = |path: &'static str| async move {
+        let mut stream = TcpStream::connect(feed).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, feed).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+    let scenario = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut session = client.connect(&format!("wss://{}", listen)).await.expect("デーモンに接続できません");
+        wait_for(&mut session, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+        session.send_text("メンテナンスは <土曜> です").unwrap();
+        session.send_text("終わりました").unwrap();
+        let feed = tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+            loop {
+                let response = fetch("/feed.atom").await;
+                if response.matches("<entry>").count() == 2 {
+                    return response;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("メッセージがフィードに載りません");
+        assert!(feed.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(feed.contains("Content-Type: application/atom+xml"));
+        assert!(feed.contains("<feed xmlns=\"http://www.w3.org/2005/Atom\">"));
+        let newest = feed.find("終わりました").unwrap();
+        let older = feed.find("メンテナンスは &lt;土曜&gt; です").unwrap();
+        assert!(newest < older);
+        assert!(fetch("/messages").await.starts_with("HTTP/1.1 404"));
+
+        shutdown.cancel();
+    };
+    let (result, ()) = tokio::join!(running, scenario);
+    result.unwrap();
+}
+
+// 受け取った要求の本文を送る、プッシュ通知の中継サーバーの代わり
+#[cfg(feature = "discovery")]
+async fn push_server() -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<Value>) {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let (bodies_tx, bodies) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            let mut request = Vec::new();
+            let mut chunk = [0; 4096];
+            let body = loop {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(|n| n.trim().parse().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                }
+            };
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}").await.unwrap();
+            let _ = bodies_tx.send(serde_json::from_str(&body).unwrap());
+        }
+    });
+    (addr, bodies)
+}
+
+#[cfg(feature = "discovery")]
+#[tokio::test]
+async fn push_while_no_client_is_attached() {
+    let dir = TempDir::new("push");
+    let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap();
+    let (push, mut pushed) = push_server().await;
+    std::fs::write(paths.config_file(), format!("[push]\nendpoint = \"http://{}/up\"\nmin_interval_secs = 0\n", push)).unwrap();
+    let options = ChatOptions::embedded(&paths).unwrap();
+    let shutdown = options.shutdown.clone();
+    let socket = paths.control_socket();
+    let listen = free_addr().await;
+    let running = daemon::run(Some(listen), socket.clone(), ApiSettings::default(), paths, options);
+
+    let client_dir = TempDir::new("push-client");
+    let client_paths = Paths::resolve(Some(&client_dir), DEFAULT_PROFILE, true).unwrap();
+    let client = Peer::new(client_paths.clone(), ChatOptions::embedded(&client_paths).unwrap());
+
+    let scenario = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut session = client.connect(&format!("wss://{}", listen)).await.expect("デーモンに接続できません");
+        wait_for(&mut session, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+        // 誰も購読していなければ、件数だけを知らせる (本文・相手は送らない)
+        session.send_text("内緒の話").unwrap();
+        let body = tokio::time::timeout(harness::EVENT_TIMEOUT, pushed.recv()).await.expect("プッシュ通知が届きません").unwrap();
+        assert_eq!(body, json!({ "event": "message_waiting", "unread": 1 }));
+
+        // ctl events で購読している間は知らせない
+        let (_, events) = daemon::request(&socket, &daemon::Request::Events).await.unwrap();
+        session.send_text("見ています").unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(500), pushed.recv()).await.is_err());
+        drop(events);
+
+        // 購読をやめた後に届いたメッセージは、また知らせる
+        let body = tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+            loop {
+                session.send_text("戻ってきて").unwrap();
+                tokio::select! {
+                    body = pushed.recv() => return body.unwrap(),
+                    _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+                }
+            }
+        })
+        .await
+        .expect("購読をやめた後にプッシュ通知が届きません");
+        assert_eq!(body["event"], "message_waiting");
+
+        shutdown.cancel();
+    };
+    let (result, ()) = tokio::join!(running, scenario);
+    result.unwrap();
+}
+
+// gRPCの呼び出しにトークンを付ける
+#[cfg(feature = "grpc")]
+fn authorized<T>(message: T, token: &str) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+    request
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_api() {
+    use rust_p2p_chat::daemon::grpc::proto::{self, control_client::ControlClient};
+
+    let dir = TempDir::new("grpc");
+    let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap();
+    let options = ChatOptions::embedded(&paths).unwrap();
+    let shutdown = options.shutdown.clone();
+    let (listen, grpc) = (free_addr().await, free_addr().await);
+    let settings = ApiSettings { grpc: Some(grpc), token: Some("secret".to_string()), ..Default::default() };
+    let running = daemon::run(Some(listen), paths.control_socket(), settings, paths, options);
+
+    let client_dir = TempDir::new("grpc-client");
+    let client_paths = Paths::resolve(Some(&client_dir), DEFAULT_PROFILE, true).unwrap();
+    let client = Peer::new(client_paths.clone(), ChatOptions::embedded(&client_paths).unwrap());
+
+    let scenario = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut control = ControlClient::connect(format!("http://{}", grpc)).await.expect("gRPCに接続できません");
+        // トークンが一致しなければ断る
+        let refused = control.get_status(authorized(proto::GetStatusRequest {}, "wrong")).await.unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::Unauthenticated);
+        let status = control.get_status(authorized(proto::GetStatusRequest {}, "secret")).await.unwrap().into_inner();
+        assert_eq!((status.listen, status.rooms), (listen.to_string(), 0));
+
+        // 連絡先の追加・一覧・削除
+        let contact = proto::Contact { name: "alice".into(), uri: "wss://192.0.2.1:8080".into(), ..Default::default() };
+        control.add_contact(authorized(proto::AddContactRequest { contact: Some(contact.clone()) }, "secret")).await.unwrap();
+        let duplicate = control.add_contact(authorized(proto::AddContactRequest { contact: Some(contact) }, "secret")).await.unwrap_err();
+        assert_eq!(duplicate.code(), tonic::Code::AlreadyExists);
+        let contacts = control.list_contacts(authorized(proto::ListContactsRequest {}, "secret")).await.unwrap().into_inner().contacts;
+        assert_eq!(contacts.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["alice"]);
+        control.remove_contact(authorized(proto::RemoveContactRequest { name: "alice".into() }, "secret")).await.unwrap();
+        let missing = control.remove_contact(authorized(proto::RemoveContactRequest { name: "alice".into() }, "secret")).await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        // 出来事を受け取りながら相手とやりとりする
+        let mut events = control.stream_events(authorized(proto::StreamEventsRequest {}, "secret")).await.unwrap().into_inner();
+        let mut session = client.connect(&format!("wss://{}", listen)).await.expect("デーモンに接続できません");
+        wait_for(&mut session, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+        let rooms = control.list_rooms(authorized(proto::ListRoomsRequest {}, "secret")).await.unwrap().into_inner().rooms;
+        assert_eq!((rooms[0].name.as_str(), rooms[0].role.as_str()), ("127.0.0.1", "listener"));
+
+        let send = proto::SendMessageRequest { room: "127.0.0.1".into(), body: "gRPCから".into() };
+        control.send_message(authorized(send, "secret")).await.unwrap();
+        let received = wait_for(&mut session, |event| match event {
+            Event::MessageReceived { body, .. } => Some(body),
+            _ => None,
+        })
+        .await;
+        assert_eq!(received, "gRPCから");
+        let unknown = proto::SendMessageRequest { room: "192.0.2.1".into(), body: "x".into() };
+        assert_eq!(control.send_message(authorized(unknown, "secret")).await.unwrap_err().code(), tonic::Code::NotFound);
+
+        session.send_text("返事").unwrap();
+        let message = tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+            loop {
+                let event = events.message().await.unwrap().expect("出来事の流れが途切れました");
+                if event.kind == "message" {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("メッセージの出来事が届きません");
+        assert_eq!((message.room.as_str(), message.body.as_str()), ("127.0.0.1", "返事"));
+
+        let listed = tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+            loop {
+                let request = authorized(proto::ListMessagesRequest { since: 0, limit: 0 }, "secret");
+                let listed = control.list_messages(request).await.unwrap().into_inner();
+                if listed.messages.len() == 2 {
+                    return listed;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("メッセージが履歴に記録されません");
+        let bodies: Vec<&str> = listed.messages.iter().map(|m| m.body.as_str()).collect();
+        assert_eq!(bodies, ["gRPCから", "返事"]);
+
+        shutdown.cancel();
+    };
+    let (result, ()) = tokio::join!(running, scenario);
+    result.unwrap();
+}
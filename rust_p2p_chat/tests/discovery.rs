@@ -0,0 +1,225 @@
+// IPアドレスの問い合わせ先の解釈、同時に問い合わせて答えを照らし合わせ、結果を使い回すこと、STUNの応答とDNSのTXTレコードの読み取りを確かめる
+
+mod harness;
+
+use futures_util::future::BoxFuture;
+use harness::TempDir;
+use rust_p2p_chat::discovery::ip::{parse_source, IpSource, LookupError, Resolver, CONFIRM_WAIT};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// 決まった結果をdelayの後に返し、問い合わせられた回数を数える
+struct Fixed {
+    name: String,
+    ip: Option<IpAddr>,
+    delay: Duration,
+    calls: Arc<AtomicUsize>,
+}
+
+impl Fixed {
+    fn boxed(name: &str, ip: Option<IpAddr>) -> (Box<dyn IpSource>, Arc<AtomicUsize>) {
+        Self::delayed(name, ip, Duration::ZERO)
+    }
+
+    fn delayed(name: &str, ip: Option<IpAddr>, delay: Duration) -> (Box<dyn IpSource>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (Box::new(Self { name: name.to_string(), ip, delay, calls: calls.clone() }), calls)
+    }
+}
+
+impl IpSource for Fixed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn lookup(&self) -> BoxFuture<'_, Result<IpAddr, LookupError>> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            self.ip.ok_or_else(|| "応答がありません".into())
+        })
+    }
+}
+
+const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9));
+
+#[tokio::test]
+async fn falls_through_and_caches() {
+    let (failing, failing_calls) = Fixed::boxed("test:cached-failing", None);
+    let (working, working_calls) = Fixed::boxed("test:cached-working", Some(ADDRESS));
+    let resolver = Resolver::new(vec![failing, working], Duration::from_secs(60));
+    let cancel = CancellationToken::new();
+
+    assert_eq!(resolver.lookup(&cancel).await.unwrap(), ADDRESS);
+    assert_eq!(resolver.lookup(&cancel).await.unwrap(), ADDRESS);
+    // 2回目は前回の結果を使い、どちらにも問い合わせない
+    assert_eq!((failing_calls.load(Ordering::Relaxed), working_calls.load(Ordering::Relaxed)), (1, 1));
+}
+
+// 応答のない問い合わせ先が先にあっても、その時間切れを待たない
+#[tokio::test(start_paused = true)]
+async fn blocked_source_does_not_delay() {
+    let (blocked, _) = Fixed::delayed("test:race-blocked", Some(OTHER), Duration::from_secs(10));
+    let (working, _) = Fixed::delayed("test:race-working", Some(ADDRESS), Duration::from_millis(200));
+    let resolver = Resolver::new(vec![blocked, working], Duration::ZERO);
+    let started = tokio::time::Instant::now();
+    assert_eq!(resolver.lookup(&CancellationToken::new()).await.unwrap(), ADDRESS);
+    assert_eq!(started.elapsed(), Duration::from_millis(200) + CONFIRM_WAIT);
+}
+
+// 2つの問い合わせ先の答えが一致すれば、残りを待たずにそれを使う
+#[tokio::test(start_paused = true)]
+async fn agreeing_answers_outvote_the_first() {
+    let (wrong, _) = Fixed::delayed("test:vote-wrong", Some(OTHER), Duration::from_millis(100));
+    let (first, _) = Fixed::delayed("test:vote-first", Some(ADDRESS), Duration::from_millis(200));
+    let (second, _) = Fixed::delayed("test:vote-second", Some(ADDRESS), Duration::from_millis(300));
+    let (slow, _) = Fixed::delayed("test:vote-slow", Some(OTHER), Duration::from_secs(10));
+    let resolver = Resolver::new(vec![wrong, first, second, slow], Duration::ZERO);
+    let started = tokio::time::Instant::now();
+    assert_eq!(resolver.lookup(&CancellationToken::new()).await.unwrap(), ADDRESS);
+    assert_eq!(started.elapsed(), Duration::from_millis(300));
+}
+
+// 食い違ったまま同数なら、並びの先の問い合わせ先を優先する
+#[tokio::test(start_paused = true)]
+async fn ties_prefer_earlier_sources() {
+    let (preferred, _) = Fixed::delayed("test:tie-preferred", Some(ADDRESS), Duration::from_millis(500));
+    let (faster, _) = Fixed::delayed("test:tie-faster", Some(OTHER), Duration::from_millis(100));
+    let resolver = Resolver::new(vec![preferred, faster], Duration::ZERO);
+    assert_eq!(resolver.lookup(&CancellationToken::new()).await.unwrap(), ADDRESS);
+}
+
+#[tokio::test]
+async fn zero_ttl_asks_again() {
+    let (working, calls) = Fixed::boxed("test:uncached", Some(ADDRESS));
+    let resolver = Resolver::new(vec![working], Duration::ZERO);
+    let cancel = CancellationToken::new();
+    resolver.lookup(&cancel).await.unwrap();
+    resolver.lookup(&cancel).await.unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn all_failing() {
+    let (failing, _) = Fixed::boxed("test:all-failing", None);
+    assert!(Resolver::new(vec![failing], Duration::ZERO).lookup(&CancellationToken::new()).await.is_err());
+    assert!(Resolver::new(Vec::new(), Duration::ZERO).lookup(&CancellationToken::new()).await.is_err());
+}
+
+#[test]
+fn parses_sources() {
+    assert_eq!(parse_source("route").unwrap().name(), "route");
+    assert_eq!(parse_source("interfaces").unwrap().name(), "interfaces");
+    assert!(parse_source("ftp://example.com").is_err());
+    assert!(parse_source("").is_err());
+}
+
+// 送り元のアドレスをXOR-MAPPED-ADDRESSで返すSTUNサーバー (前に未知の属性を置き、4バイト境界の読み飛ばしも確かめる)
+#[cfg(feature = "discovery")]
+#[tokio::test]
+async fn stun_binding() {
+    const COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+
+    let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!((len, &buf[..2], &buf[4..8]), (20, &[0x00, 0x01][..], &COOKIE[..]));
+        let IpAddr::V4(ip) = from.ip() else { unreachable!() };
+        let mut attributes = vec![0x80, 0x22, 0x00, 0x05, b't', b'e', b's', b't', b'!', 0, 0, 0];
+        attributes.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+        attributes.extend_from_slice(&(from.port() ^ 0x2112).to_be_bytes());
+        attributes.extend(ip.octets().iter().zip(COOKIE).map(|(b, k)| b ^ k));
+        let mut response = vec![0x01, 0x01];
+        response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        response.extend_from_slice(&buf[4..20]);
+        response.extend_from_slice(&attributes);
+        server.send_to(&response, from).await.unwrap();
+    });
+
+    let source = parse_source(&format!("stun:127.0.0.1:{}", port)).unwrap();
+    assert_eq!(source.lookup().await.unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+}
+
+// 偽のDNSサーバー。_p2pchat.alice.example.org にはCNAMEと、2つの文字列に分けたTXTレコードを返し、それ以外はNXDOMAINにする
+async fn fake_dns_server(txt: &'static [&'static str]) -> std::net::SocketAddr {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((n, client)) = socket.recv_from(&mut buf).await {
+            let query = &buf[..n];
+            let question = &query[12..];
+            let found = question.starts_with(b"\x08_p2pchat\x05alice\x07example\x03org\x00\x00\x10\x00\x01");
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(if found { &[0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0] } else { &[0x81, 0x83, 0, 1, 0, 0, 0, 0, 0, 0] });
+            response.extend_from_slice(question);
+            if found {
+                // 名前は質問の名前への圧縮ポインター
+                response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 44, 0, 2, 0xc0, 12]);
+                let data: Vec<u8> = txt.iter().flat_map(|part| [&[part.len() as u8][..], part.as_bytes()].concat()).collect();
+                response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 1, 44, 0, data.len() as u8]);
+                response.extend_from_slice(&data);
+            }
+            socket.send_to(&response, client).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn txt_records_advertise_listeners() {
+    use rust_p2p_chat::discovery::dns::{self, Advertisement};
+
+    let server = fake_dns_server(&["v=p2pchat1 addr=2001:db8::1", " port=8443 fp=sha256:abcd future=1"]).await;
+    let advertisement = dns::lookup(server, "alice.example.org.").await.expect("TXTレコードを読めません");
+    assert_eq!(advertisement, Advertisement { host: "2001:db8::1".to_string(), port: 8443, fingerprint: "sha256:abcd".to_string() });
+    assert_eq!(advertisement.uri(), "wss://[2001:db8::1]:8443#sha256:abcd");
+    assert_eq!(Advertisement::parse(&advertisement.txt()), Some(advertisement.clone()));
+    assert_eq!(
+        advertisement.zone_line("alice.example.org"),
+        "_p2pchat.alice.example.org. 300 IN TXT \"v=p2pchat1 addr=2001:db8::1 port=8443 fp=sha256:abcd\""
+    );
+
+    // ドメイン名はTXTレコードのURIに、URI・IPアドレスはそのままにする
+    let dir = TempDir::new("dns");
+    let contacts = dir.join("contacts.json");
+    let target = dns::resolve_target("alice.example.org", &contacts, Some(server)).await.unwrap();
+    assert_eq!(target, "wss://[2001:db8::1]:8443#sha256:abcd");
+    assert_eq!(dns::resolve_target("wss://127.0.0.1:8080", &contacts, Some(server)).await.unwrap(), "wss://127.0.0.1:8080");
+    assert!(dns::lookup(server, "bob.example.org").await.unwrap_err().to_string().contains("NXDOMAIN"));
+}
+
+#[tokio::test]
+async fn txt_records_need_every_field() {
+    use rust_p2p_chat::discovery::dns;
+
+    let server = fake_dns_server(&["v=p2pchat1 addr=203.0.113.5 fp=sha256:abcd"]).await;
+    assert!(dns::lookup(server, "alice.example.org").await.is_err());
+    assert_eq!(dns::Advertisement::parse("v=spf1 include:example.org ~all"), None);
+}
+
+#[cfg(feature = "mdns")]
+#[test]
+fn lan_peers_prefer_reachable_addresses() {
+    use rust_p2p_chat::discovery::mdns::LanPeer;
+
+    let mut peer = LanPeer {
+        instance: "alice (069346da)".to_string(),
+        nickname: Some("alice".to_string()),
+        addresses: ["127.0.0.1", "fe80::1", "2001:db8::5", "192.168.1.20"].iter().map(|ip| ip.parse().unwrap()).collect(),
+        port: 8443,
+        fingerprint: Some("sha256:abcd".to_string()),
+    };
+    assert_eq!(peer.uri().as_deref(), Some("wss://192.168.1.20:8443#sha256:abcd"));
+    peer.addresses.retain(|ip| !ip.is_ipv4());
+    assert_eq!(peer.uri().as_deref(), Some("wss://[2001:db8::5]:8443#sha256:abcd"));
+    peer.fingerprint = None;
+    peer.addresses.clear();
+    assert_eq!(peer.uri(), None);
+}
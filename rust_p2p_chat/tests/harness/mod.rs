@@ -0,0 +1,143 @@
+// 1つのプロセスの中で2つのセッションを動かすための道具 (ソケットを使わずにMemoryTransportでつなぐ)
+// 証明書と鍵はそれぞれの一時ディレクトリに初回の接続時に作られる
+
+#![allow(dead_code)]
+
+use rust_p2p_chat::paths::{Paths, DEFAULT_PROFILE};
+use rust_p2p_chat::runtime::MemoryTransport;
+use rust_p2p_chat::{ChatOptions, ChatSession, ClientBuilder, Event, Peer, Runtime};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// 待受側が使うアドレス (MemoryTransportの中だけで使う)
+pub const LISTEN_ADDR: &str = "127.0.0.1:8080";
+// 1つの出来事を待つ上限
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// テストで使う一時ディレクトリ。作るたびに別の名前になり、捨てると中身ごと削除する
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("p2pchat-test-{}-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("一時ディレクトリを作成できません");
+        Self(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// 片方の端。保存先は一時ディレクトリで、捨てると削除する
+pub struct Node {
+    pub dir: TempDir,
+    pub paths: Paths,
+    pub peer: Peer,
+}
+
+impl Node {
+    pub fn new(network: &MemoryTransport, name: &str) -> Self {
+        Self::with_config(network, name, "")
+    }
+
+    // config.toml にconfigを書いてから設定を読み込む
+    pub fn with_config(network: &MemoryTransport, name: &str, config: &str) -> Self {
+        let dir = TempDir::new(name);
+        let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).expect("保存先を作成できません");
+        if !config.is_empty() {
+            std::fs::write(paths.config_file(), config).expect("設定ファイルを書き込めません");
+        }
+        let mut options = ChatOptions::embedded(&paths).expect("設定を読み込めません");
+        options.runtime = Runtime::tokio().with_transport(network.clone());
+        let peer = Peer::new(paths.clone(), options);
+        Self { dir, paths, peer }
+    }
+}
+
+// listenerで待ち受け、clientから接続する。両方のセッションを (待受側, 接続側) の順に返す
+pub async fn connect(listener: &Node, client: &Node) -> (ChatSession, ChatSession) {
+    connect_with(listener, client, |builder| builder).await
+}
+
+// connectと同じだが、接続側の設定 (再試行など) をconfigureで変えられる
+pub async fn connect_with(listener: &Node, client: &Node, configure: impl FnOnce(ClientBuilder) -> ClientBuilder) -> (ChatSession, ChatSession) {
+    let addr: SocketAddr = LISTEN_ADDR.parse().unwrap();
+    let target = format!("wss://{}", LISTEN_ADDR);
+    let accepting = listener.peer.listen(addr);
+    // 待受を始めてから接続する
+    let connecting = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let builder = client.peer.client().target(&target).expect("接続先を解釈できません");
+        client.peer.connect_with(configure(builder).build().expect("接続の設定が不正です")).await
+    };
+    let (accepted, connected) = tokio::join!(accepting, connecting);
+    (accepted.expect("接続を受け付けられません"), connected.expect("接続できません"))
+}
+
+// 条件に合う出来事が届くまで待ち、それを返す (それまでの出来事は捨てる)
+pub async fn wait_for<T>(session: &mut ChatSession, mut matches: impl FnMut(Event) -> Option<T>) -> T {
+    let found = tokio::time::timeout(EVENT_TIMEOUT, async {
+        while let Some(event) = session.next_event().await {
+            if let Some(found) = matches(event) {
+                return Some(found);
+            }
+        }
+        None
+    })
+    .await;
+    match found {
+        Ok(Some(found)) => found,
+        Ok(None) => panic!("待っている出来事の前にセッションが終了しました"),
+        Err(_) => panic!("待っている出来事が届きませんでした"),
+    }
+}
+
+// dirの中 (サブディレクトリを含む) にnameのファイルができるまで待ち、そのパスを返す
+pub async fn wait_for_file(dir: &Path, name: &str) -> PathBuf {
+    let found = tokio::time::timeout(EVENT_TIMEOUT, async {
+        loop {
+            if let Some(path) = find_file(dir, name) {
+                return path;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    found.unwrap_or_else(|_| panic!("{} が保存されませんでした", name))
+}
+
+fn find_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    for entry in std::fs::read_dir(dir).ok()?.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if let Some(found) = find_file(&path, name) {
+                return Some(found);
+            }
+        } else if path.file_name().is_some_and(|file| file == name) {
+            return Some(path);
+        }
+    }
+    None
+}
@@ -0,0 +1,105 @@
+// --health の /healthz・/readyz が、待受の状態・リレー・保存先に合わせて200と503を返すことを確かめる
+
+mod harness;
+
+use harness::{Node, LISTEN_ADDR};
+use rust_p2p_chat::health::{self, Health, ListenerState};
+use rust_p2p_chat::runtime::MemoryTransport;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// 状態の確認を待ち受け、そのアドレスを返す
+async fn serve(health: &Health) -> SocketAddr {
+    let listener = health::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(health::serve(listener, health.clone()));
+    addr
+}
+
+async fn get(addr: SocketAddr, path: &str) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[tokio::test]
+async fn readiness_follows_listener() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let health = Health::new(alice.paths.data_dir.clone(), Vec::new());
+    let addr = serve(&health).await;
+
+    // 待受の準備が終わるまでは生きているが、接続は受け付けられない
+    assert_eq!(get(addr, "/healthz").await.0, 200);
+    let (status, body) = get(addr, "/readyz").await;
+    assert_eq!((status, body["status"].as_str()), (503, Some("not_ready")));
+    assert_eq!(body["checks"]["listener"], "starting");
+
+    let settings = alice.peer.listener().addr(LISTEN_ADDR.parse().unwrap()).health(Some(health.clone())).build().unwrap();
+    let accepting = tokio::spawn(async move { alice.peer.listen_with(settings).await.ok() });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (status, body) = get(addr, "/readyz").await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["checks"]["listener"], "listening");
+    assert_eq!(body["checks"]["storage"], Value::Null);
+
+    // 同時に受け付けるのは1つの接続だけのため、接続している間は準備ができていない
+    let _client = bob.peer.connect(&format!("wss://{}", LISTEN_ADDR)).await.expect("接続できません");
+    let _session = accepting.await.unwrap().expect("接続を受け付けられません");
+    let (status, body) = get(addr, "/readyz").await;
+    assert_eq!((status, body["checks"]["listener"].as_str()), (503, Some("connected")));
+    assert_eq!(get(addr, "/healthz").await.0, 200);
+
+    health.set_listener(ListenerState::Stopped);
+    assert_eq!(get(addr, "/healthz").await, (503, serde_json::json!({ "ok": false, "status": "stopped", "listener": "stopped" })));
+    assert_eq!(get(addr, "/metrics").await.0, 404);
+}
+
+#[tokio::test]
+async fn unreachable_relay_is_not_ready() {
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    // 一度待ち受けてから閉じたポートには接続できない
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let health = Health::new(alice.paths.data_dir.clone(), vec![format!("https://{}/", closed)]);
+    health.set_listener(ListenerState::Disabled);
+    let addr = serve(&health).await;
+
+    let (status, body) = get(addr, "/readyz").await;
+    assert_eq!(status, 503);
+    let relay = &body["checks"]["relays"][0];
+    assert_eq!((relay["ok"].as_bool(), relay["error"].is_string()), (Some(false), true));
+    // リレーを確かめなければ、待ち受けないデーモンでも準備ができている
+    let health = Health::new(alice.paths.data_dir.clone(), Vec::new());
+    health.set_listener(ListenerState::Disabled);
+    assert!(health.ready().await.ok);
+}
+
+// 同時に届いた確認が保存先を確かめるファイルを消し合わず、どれも準備ができていると答え、ファイルも残さない
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_probes_do_not_race_on_storage() {
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    let health = Health::new(alice.paths.data_dir.clone(), Vec::new());
+    health.set_listener(ListenerState::Disabled);
+    let addr = serve(&health).await;
+
+    let probes: Vec<_> = (0..32).map(|_| tokio::spawn(get(addr, "/readyz"))).collect();
+    for probe in probes {
+        let (status, body) = probe.await.unwrap();
+        assert_eq!(status, 200, "{}", body);
+    }
+    let leftovers: Vec<_> = std::fs::read_dir(&alice.paths.data_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().starts_with(".health"))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}
@@ -0,0 +1,45 @@
+// 履歴の通し番号: 削除で末尾のエントリが消えて開き直しても、使った通し番号を再び使わないことを確かめる
+
+mod harness;
+
+use harness::TempDir;
+use rust_p2p_chat::history::{Direction, EventKind, History};
+
+fn sent(body: &str) -> EventKind {
+    EventKind::Message { direction: Direction::Outgoing, body: body.to_string() }
+}
+
+#[test]
+fn seq_is_not_reused_after_purging_the_newest_entries() {
+    let dir = TempDir::new("history-seq");
+    let path = dir.join("history.jsonl");
+
+    let mut history = History::open(&path, None).unwrap();
+    for body in ["1", "2", "3"] {
+        history.append("bob", sent(body)).unwrap();
+    }
+    // 相手はここまで受け取っている
+    let since = 3;
+    assert_eq!(history.retain(|_| false).unwrap(), 3);
+
+    let mut history = History::open(&path, None).unwrap();
+    assert_eq!(history.append("bob", sent("4")).unwrap().seq, 4);
+    let backfill: Vec<u64> = history.sent_since("bob", since).unwrap().iter().map(|entry| entry.seq).collect();
+    assert_eq!(backfill, [4]);
+
+    // 一部だけ残した場合も、残った最後のエントリではなく使った最大の通し番号から続ける
+    history.append("bob", sent("5")).unwrap();
+    history.retain(|entry| entry.seq == 4).unwrap();
+    let mut history = History::open(&path, None).unwrap();
+    assert_eq!(history.append("bob", sent("6")).unwrap().seq, 6);
+}
+
+// 日時の範囲を超えるほど長い保持期間でも落ちずに、すべて残す
+#[test]
+fn very_long_retention_keeps_everything() {
+    use rust_p2p_chat::config::RetentionPolicy;
+    let policy: RetentionPolicy = format!("{}d", u32::MAX).parse().unwrap();
+    let now = chrono::Local::now();
+    assert!(policy.keeps(now - chrono::Duration::days(365 * 100), now));
+    assert!(!"30d".parse::<RetentionPolicy>().unwrap().keeps(now - chrono::Duration::days(31), now));
+}
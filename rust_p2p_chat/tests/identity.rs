@@ -0,0 +1,39 @@
+// 証明書と秘密鍵をファイルに保存して使い回すこと、秘密鍵のファイルを本人だけが読めることを確かめる
+
+mod harness;
+
+use harness::TempDir;
+use rust_p2p_chat::identity::Identity;
+use std::path::Path;
+
+#[cfg(unix)]
+fn assert_private(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[cfg(not(unix))]
+fn assert_private(_: &Path) {}
+
+#[test]
+fn identity_is_saved_and_reused() {
+    let dir = TempDir::new("identity");
+    let (cert, key) = (dir.join("cert.der"), dir.join("key.der"));
+
+    let created = Identity::load_or_generate(&cert, &key).unwrap();
+    assert_private(&key);
+    let loaded = Identity::load_or_generate(&cert, &key).unwrap();
+    assert_eq!(loaded.fingerprint(), created.fingerprint());
+    assert_eq!(loaded.key_der, created.key_der);
+
+    // 証明書だけが消えた場合は作り直し、古い鍵のファイルも本人だけが読めるものに置き換える
+    std::fs::remove_file(&cert).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+    }
+    let regenerated = Identity::load_or_generate(&cert, &key).unwrap();
+    assert_ne!(regenerated.fingerprint(), created.fingerprint());
+    assert_private(&key);
+}
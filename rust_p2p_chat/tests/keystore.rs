@@ -0,0 +1,72 @@
+// 秘密鍵をキーストアに預けても同じ証明書で待ち受けられ、ディスクに秘密鍵が残らないことを確かめる
+// (OSのキーチェーンはテストの環境にないため、同じトレイトのメモリ上の保存先を使う)
+
+mod harness;
+
+use harness::{wait_for, Node};
+use rust_p2p_chat::builder::IdentitySource;
+use rust_p2p_chat::keystore::{self, Deposits, Keystore, MemoryKeystore};
+use rust_p2p_chat::runtime::MemoryTransport;
+use rust_p2p_chat::Event;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn listen_with_key_in_keystore() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let store = MemoryKeystore::default();
+    let source = IdentitySource::Keystore(Arc::new(store.clone()));
+
+    // 初めて使うときは生成し、秘密鍵はファイルに書かずに預ける
+    let fingerprint = source.load(&alice.paths).unwrap().fingerprint();
+    assert!(alice.paths.identity_cert_file().exists());
+    assert!(!alice.paths.identity_key_file().exists());
+    assert!(store.get(&keystore::account(&alice.paths, keystore::IDENTITY_KEY)).unwrap().is_some());
+
+    let addr: SocketAddr = "127.0.0.1:9443".parse().unwrap();
+    let settings = alice.peer.listener().addr(addr).identity(source).build().unwrap();
+    let connecting = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let settings = bob.peer.client().uri(format!("wss://{}/#{}", addr, fingerprint)).build().unwrap();
+        bob.peer.connect_with(settings).await
+    };
+    let (listener, client) = tokio::join!(alice.peer.listen_with(settings), connecting);
+    let (mut listener, client) = (listener.expect("接続を受け付けられません"), client.expect("接続できません"));
+    client.send_text("鍵はキーチェーンに").unwrap();
+    let body = wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { body, .. } => Some(body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "鍵はキーチェーンに");
+}
+
+#[tokio::test]
+async fn store_and_remove_identity_key() {
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    let store = MemoryKeystore::default();
+    let identity = IdentitySource::Profile.load(&alice.paths).unwrap();
+    let key_der = std::fs::read(alice.paths.identity_key_file()).unwrap();
+
+    keystore::store_identity_key(&alice.paths, &store).unwrap();
+    assert!(!alice.paths.identity_key_file().exists());
+    let deposits = Deposits::load(&alice.paths).unwrap().unwrap();
+    assert_eq!((deposits.keystore.as_str(), deposits.identity_key, deposits.history_passphrase), ("memory", true, false));
+    let loaded = IdentitySource::Keystore(Arc::new(store.clone())).load(&alice.paths).unwrap();
+    assert_eq!(loaded.fingerprint(), identity.fingerprint());
+
+    keystore::store_history_passphrase(&alice.paths, &store, "correct horse").unwrap();
+    assert_eq!(keystore::remove(&alice.paths, &store).unwrap(), vec![keystore::IDENTITY_KEY, keystore::HISTORY_PASSPHRASE]);
+    assert_eq!(std::fs::read(alice.paths.identity_key_file()).unwrap(), key_der);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(alice.paths.identity_key_file()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600, "書き戻した秘密鍵は本人だけが読める");
+    }
+    assert!(store.get(&keystore::account(&alice.paths, keystore::IDENTITY_KEY)).unwrap().is_none());
+    assert!(!alice.paths.keystore_file().exists());
+}
@@ -0,0 +1,143 @@
+// mail gateway が、期限を過ぎた下書きを連絡先のメールアドレスに送り、下書きと履歴に記録することを確かめる
+// SMTPサーバーの代わりに、受け取ったメールを渡す偽物を使う
+
+mod harness;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{Duration as Minutes, Local};
+use harness::TempDir;
+use rust_p2p_chat::config::MailConfig;
+use rust_p2p_chat::contacts::{AddressBook, Contact};
+use rust_p2p_chat::drafts::Drafts;
+use rust_p2p_chat::history::{EventKind, History};
+use rust_p2p_chat::mail;
+use rust_p2p_chat::paths::{Paths, DEFAULT_PROFILE};
+use serde_json::json;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+// 受け取ったメール (宛先と、ヘッダーを除いてbase64を戻した本文)
+struct Received {
+    to: String,
+    body: String,
+}
+
+// STARTTLSに対応しない偽のSMTPサーバー
+async fn fake_smtp_server(received: mpsc::UnboundedSender<Received>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 fake ESMTP\r\n").await.unwrap();
+            let mut to = String::new();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response: &[u8] = match line.split_once(':').map_or(line.as_str(), |(verb, _)| verb) {
+                    command if command.starts_with("EHLO") => b"250-fake\r\n250 8BITMIME\r\n",
+                    "MAIL FROM" => b"250 ok\r\n",
+                    "RCPT TO" => {
+                        to = line["RCPT TO:".len()..].trim_matches(['<', '>']).to_string();
+                        b"250 ok\r\n"
+                    }
+                    "DATA" => {
+                        writer.write_all(b"354 go ahead\r\n").await.unwrap();
+                        let mut data = Vec::new();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if line == "." {
+                                break;
+                            }
+                            data.push(line);
+                        }
+                        let encoded: String = data.iter().skip_while(|line| !line.is_empty()).map(|line| line.trim()).collect();
+                        let body = String::from_utf8(BASE64.decode(encoded).unwrap()).unwrap();
+                        received.send(Received { to: to.clone(), body }).unwrap();
+                        b"250 queued\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"502 unknown\r\n",
+                };
+                writer.write_all(response).await.unwrap();
+            }
+        }
+    });
+    addr
+}
+
+fn contact(uri: &str, email: Option<&str>, passphrase: Option<&str>) -> Contact {
+    serde_json::from_value(json!({ "uri": uri, "email": email, "mail_passphrase": passphrase })).unwrap()
+}
+
+#[tokio::test]
+async fn gateway_mails_undelivered_drafts() {
+    let dir = TempDir::new("mail");
+    let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap();
+    let mut book = AddressBook::load(paths.contacts_file()).unwrap();
+    // ポートを省略したURIは、接続時と同じく8080に接続した相手として見つける
+    book.add("alice", contact("wss://192.0.2.1", Some("alice@example.org"), Some("shared secret"))).unwrap();
+    book.add("bob", contact("wss://192.0.2.2:8080", Some("bob@example.org"), None)).unwrap();
+    book.add("carol", contact("wss://192.0.2.3:8080", None, None)).unwrap();
+    book.save().unwrap();
+    // 下書きは接続先の host:port (接続してきた相手はIPアドレス) ごとに記録される
+    let (old, recent) = (Local::now() - Minutes::hours(2), Local::now());
+    let drafts = json!({
+        "192.0.2.1:8080": [{ "saved_at": old, "body": "会議は3時から" }, { "saved_at": recent, "body": "まだ早い" }],
+        "192.0.2.2": [{ "saved_at": old, "body": "お知らせだけ" }],
+        "192.0.2.3:8080": [{ "saved_at": old, "body": "メールアドレスなし" }],
+    });
+    std::fs::write(paths.drafts_file(), drafts.to_string()).unwrap();
+
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let server = fake_smtp_server(received_tx).await;
+    let config = MailConfig { smtp: Some(format!("smtp://{}", server)), from: Some("me <me@example.org>".to_string()), ..Default::default() };
+    let mut history = History::open(paths.history_file(), None).unwrap();
+    assert_eq!(mail::deliver_due(&paths, &config, &mut history).await.unwrap(), 2);
+
+    // 暗号化した本文は共有したパスフレーズでだけ開ける
+    let alice = received.recv().await.unwrap();
+    assert_eq!(alice.to, "alice@example.org");
+    assert!(!alice.body.contains("会議は3時から"));
+    let opened = mail::open(&alice.body, "shared secret").unwrap();
+    assert_eq!(opened.iter().map(|draft| draft.body.as_str()).collect::<Vec<_>>(), ["会議は3時から"]);
+    assert!(mail::open(&alice.body, "wrong").is_err());
+    // 知らせのみのメールには本文を含めない
+    let bob = received.recv().await.unwrap();
+    assert_eq!(bob.to, "bob@example.org");
+    assert!(!bob.body.contains("お知らせだけ"));
+    assert!(received.try_recv().is_err());
+
+    // 暗号化して送った下書きは消え、知らせた下書きは次の接続のために残る (同じ下書きは2度知らせない)
+    let drafts = Drafts::load(paths.drafts_file()).unwrap();
+    assert_eq!(drafts.get("192.0.2.1:8080").iter().map(|draft| draft.body.as_str()).collect::<Vec<_>>(), ["まだ早い"]);
+    assert!(drafts.get("192.0.2.2")[0].mailed_at.is_some());
+    assert_eq!(drafts.get("192.0.2.3:8080").len(), 1);
+    assert_eq!(mail::deliver_due(&paths, &config, &mut history).await.unwrap(), 0);
+
+    let mailed: Vec<(String, bool)> = history
+        .load()
+        .unwrap()
+        .into_iter()
+        .filter_map(|entry| match entry.event {
+            EventKind::Mailed { address, encrypted, .. } => Some((address, encrypted)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(mailed, [("alice@example.org".to_string(), true), ("bob@example.org".to_string(), false)]);
+}
+
+#[tokio::test]
+async fn gateway_needs_a_server_and_sender() {
+    let dir = TempDir::new("mail-config");
+    let paths = Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap();
+    let mut history = History::open(paths.history_file(), None).unwrap();
+    let missing = mail::deliver_due(&paths, &MailConfig::default(), &mut history).await.unwrap_err();
+    assert!(missing.to_string().contains("[mail] smtp"));
+    let config = MailConfig { smtp: Some("https://smtp.example.org".to_string()), from: Some("me@example.org".to_string()), ..Default::default() };
+    assert!(mail::deliver_due(&paths, &config, &mut history).await.is_err());
+}
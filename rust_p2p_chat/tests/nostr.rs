@@ -0,0 +1,179 @@
+// Nostrのリレーを経由して、暗号化したダイレクトメッセージ (NIP-17・NIP-04) を送り、相手が開いて履歴に記録できることを確かめる
+// リレーの代わりに、受け取ったイベントをそのまま配る偽物を使う
+
+#![cfg(feature = "nostr")]
+
+mod harness;
+
+use chrono::{Duration as Minutes, Local};
+use futures_util::{SinkExt, StreamExt};
+use harness::TempDir;
+use rust_p2p_chat::config::NostrConfig;
+use rust_p2p_chat::contacts::{AddressBook, Contact};
+use rust_p2p_chat::drafts::Drafts;
+use rust_p2p_chat::history::{Direction, EventKind, History};
+use rust_p2p_chat::nostr;
+use rust_p2p_chat::paths::{Paths, DEFAULT_PROFILE};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+// 受け取ったイベントを保存し、REQには宛先 (#p) と種類が一致するものを返す偽のリレー
+async fn fake_relay() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stored: Arc<Mutex<Vec<Value>>> = Arc::default();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let stored = Arc::clone(&stored);
+            tokio::spawn(async move {
+                let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(text))) = socket.next().await {
+                    let message: Value = serde_json::from_str(&text).unwrap();
+                    let replies = match message[0].as_str().unwrap() {
+                        "EVENT" => {
+                            stored.lock().unwrap().push(message[1].clone());
+                            vec![json!(["OK", message[1]["id"], true, ""])]
+                        }
+                        "REQ" => {
+                            let (subscription, filter) = (&message[1], &message[2]);
+                            let mut replies: Vec<Value> = stored
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .filter(|event| filter["kinds"].as_array().unwrap().contains(&event["kind"]))
+                                .filter(|event| event["tags"].as_array().unwrap().iter().any(|tag| tag[0] == "p" && filter["#p"].as_array().unwrap().contains(&tag[1])))
+                                .map(|event| json!(["EVENT", subscription, event]))
+                                .collect();
+                            replies.push(json!(["EOSE", subscription]));
+                            replies
+                        }
+                        _ => Vec::new(),
+                    };
+                    for reply in replies {
+                        socket.send(Message::Text(reply.to_string())).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    format!("ws://{}", addr)
+}
+
+// 一時ディレクトリに作った保存先。捨てると削除する
+struct Profile {
+    paths: Paths,
+    _dir: TempDir,
+}
+
+impl std::ops::Deref for Profile {
+    type Target = Paths;
+
+    fn deref(&self) -> &Paths {
+        &self.paths
+    }
+}
+
+fn profile(name: &str) -> Profile {
+    let dir = TempDir::new(&format!("nostr-{}", name));
+    Profile { paths: Paths::resolve(Some(&dir), DEFAULT_PROFILE, true).unwrap(), _dir: dir }
+}
+
+fn add_contact(paths: &Paths, name: &str, uri: &str, nostr: &str) {
+    let mut book = AddressBook::load(paths.contacts_file()).unwrap();
+    let contact: Contact = serde_json::from_value(json!({ "uri": uri, "nostr": nostr })).unwrap();
+    book.add(name, contact).unwrap();
+    book.save().unwrap();
+}
+
+fn nostr_messages(history: &History) -> Vec<(String, Direction, String)> {
+    history
+        .load()
+        .unwrap()
+        .into_iter()
+        .filter_map(|entry| match entry.event {
+            EventKind::Nostr { direction, body } => Some((entry.peer, direction, body)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn direct_messages_reach_the_peer_through_a_relay() {
+    let relay = fake_relay().await;
+    let (alice, bob) = (profile("alice"), profile("bob"));
+    let (alice_key, bob_key) = (nostr::keys(&alice).unwrap().public_key(), nostr::keys(&bob).unwrap().public_key());
+    // 鍵は証明書の鍵から導くため、何度読んでも同じ
+    assert_eq!(nostr::keys(&alice).unwrap().public_key(), alice_key);
+    assert_ne!(alice_key, bob_key);
+    add_contact(&alice, "bob", "wss://192.0.2.2:8080", &nostr::npub(&bob_key));
+    // ポートを省略したURIは、接続時と同じく8080の相手として記録する
+    add_contact(&bob, "alice", "wss://192.0.2.1", &alice_key.to_hex());
+
+    let mut alice_history = History::open(alice.history_file(), None).unwrap();
+    let gift_wrap = NostrConfig { relays: vec![relay.clone()], ..Default::default() };
+    let legacy = NostrConfig { nip04: true, ..gift_wrap.clone() };
+    assert_eq!(nostr::send(&alice, &gift_wrap, &mut alice_history, "bob", "NIP-17で送ります").await.unwrap(), 1);
+    assert_eq!(nostr::send(&alice, &legacy, &mut alice_history, &nostr::npub(&bob_key), "NIP-04で送ります").await.unwrap(), 1);
+    assert_eq!(
+        nostr_messages(&alice_history),
+        [
+            ("192.0.2.2:8080".to_string(), Direction::Outgoing, "NIP-17で送ります".to_string()),
+            ("192.0.2.2:8080".to_string(), Direction::Outgoing, "NIP-04で送ります".to_string()),
+        ]
+    );
+
+    // 相手の連絡先にあれば、その host:port (直接つながったときと同じ相手) として記録する
+    let mut bob_history = History::open(bob.history_file(), None).unwrap();
+    let received = nostr::receive(&bob, &gift_wrap, &mut bob_history).await.unwrap();
+    let mut bodies: Vec<&str> = received.iter().map(|(_, message)| message.body.as_str()).collect();
+    bodies.sort();
+    assert_eq!(bodies, ["NIP-04で送ります", "NIP-17で送ります"]);
+    assert!(received.iter().all(|(peer, message)| peer == "192.0.2.1:8080" && message.sender == alice_key));
+    assert_eq!(nostr_messages(&bob_history).len(), 2);
+    // 一度受け取ったものは、さかのぼって問い合わせても2度は返さない
+    assert!(nostr::receive(&bob, &gift_wrap, &mut bob_history).await.unwrap().is_empty());
+
+    // 自分宛てでないメッセージは開けない
+    let carol = profile("carol");
+    let mut carol_history = History::open(carol.history_file(), None).unwrap();
+    assert!(nostr::receive(&carol, &gift_wrap, &mut carol_history).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn gateway_sends_due_drafts() {
+    let relay = fake_relay().await;
+    let (alice, bob) = (profile("gateway-alice"), profile("gateway-bob"));
+    let bob_key = nostr::keys(&bob).unwrap().public_key();
+    add_contact(&alice, "bob", "wss://192.0.2.2:8080", &nostr::npub(&bob_key));
+    let (old, recent) = (Local::now() - Minutes::hours(2), Local::now());
+    let drafts = json!({
+        "192.0.2.2:8080": [{ "saved_at": old, "body": "届かなかった" }, { "saved_at": recent, "body": "まだ早い" }],
+        "192.0.2.3:8080": [{ "saved_at": old, "body": "連絡先なし" }],
+    });
+    std::fs::write(alice.drafts_file(), drafts.to_string()).unwrap();
+
+    let config = NostrConfig { relays: vec![relay], ..Default::default() };
+    let mut history = History::open(alice.history_file(), None).unwrap();
+    assert_eq!(nostr::deliver_due(&alice, &config, &mut history).await.unwrap(), 1);
+    let drafts = Drafts::load(alice.drafts_file()).unwrap();
+    assert_eq!(drafts.get("192.0.2.2:8080").iter().map(|draft| draft.body.as_str()).collect::<Vec<_>>(), ["まだ早い"]);
+    assert_eq!(drafts.get("192.0.2.3:8080").len(), 1);
+    assert_eq!(nostr::deliver_due(&alice, &config, &mut history).await.unwrap(), 0);
+
+    let mut bob_history = History::open(bob.history_file(), None).unwrap();
+    let received = nostr::receive(&bob, &config, &mut bob_history).await.unwrap();
+    assert_eq!(received.iter().map(|(_, message)| message.body.as_str()).collect::<Vec<_>>(), ["届かなかった"]);
+    // 送った側を連絡先に登録していなければnpubで記録する
+    assert!(received[0].0.starts_with("npub1"));
+}
+
+#[tokio::test]
+async fn relays_are_required() {
+    let paths = profile("no-relays");
+    let mut history = History::open(paths.history_file(), None).unwrap();
+    let missing = nostr::deliver_due(&paths, &NostrConfig::default(), &mut history).await.unwrap_err();
+    assert!(missing.to_string().contains("[nostr] relays"));
+    assert!(nostr::parse_public_key("npub1invalid").is_err());
+}
@@ -0,0 +1,150 @@
+// 送信の順番待ちがいっぱいのとき、設定した方針 (block / drop / disconnect) どおりに扱うことを確かめる
+
+use futures_util::{Sink, SinkExt};
+use rust_p2p_chat::config::QueuePolicy;
+use rust_p2p_chat::outbox::{self, Outbox, Writer};
+use rust_p2p_chat::protocol::Envelope;
+use rust_p2p_chat::runtime::Runtime;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+// permitsを足すまで書き込みが進まない、受け取りの遅い相手
+struct SlowPeer {
+    gate: Arc<Semaphore>,
+    written: Arc<Mutex<Vec<Message>>>,
+}
+
+impl SlowPeer {
+    fn new() -> Self {
+        Self { gate: Arc::new(Semaphore::new(0)), written: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    fn sink(&self) -> impl Sink<Message, Error = Error> + Unpin + Send + 'static {
+        let (gate, written) = (Arc::clone(&self.gate), Arc::clone(&self.written));
+        Box::pin(futures_util::sink::unfold((), move |(), message| {
+            let (gate, written) = (Arc::clone(&gate), Arc::clone(&written));
+            async move {
+                gate.acquire().await.unwrap().forget();
+                written.lock().unwrap().push(message);
+                Ok::<_, Error>(())
+            }
+        }))
+    }
+
+    fn written(&self) -> Vec<Message> {
+        self.written.lock().unwrap().clone()
+    }
+}
+
+fn start(peer: &SlowPeer, policy: QueuePolicy) -> (Outbox, Writer) {
+    outbox::spawn(&Runtime::default(), peer.sink(), 2, policy)
+}
+
+fn text(n: usize) -> Message {
+    Message::Text(format!("frame {}", n))
+}
+
+fn chat(n: usize) -> Message {
+    Message::Text(Envelope::Chat { seq: n as u64, body: format!("message {}", n), sig: None }.encode())
+}
+
+fn chunk(n: usize) -> Message {
+    Message::Text(Envelope::FileChunk { id: 1, offset: n as u64 * 4, data: vec![n as u8; 4].into() }.encode())
+}
+
+#[tokio::test]
+async fn block_waits_for_room() {
+    let peer = SlowPeer::new();
+    let (mut outbox, writer) = start(&peer, QueuePolicy::Block);
+
+    outbox.send(text(0)).await.unwrap();
+    // 書き込むタスクが最初のフレームを取り出すのを待つ
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // 書き込み中の1つのほかに、順番待ちの2つまでは待たずに入る
+    for n in 1..3 {
+        tokio::time::timeout(Duration::from_secs(1), outbox.send(text(n))).await.expect("順番待ちに入りません").unwrap();
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(100), outbox.send(text(3))).await.is_err(), "空きがないのに入りました");
+
+    peer.gate.add_permits(100);
+    tokio::time::timeout(Duration::from_secs(1), outbox.send(text(4))).await.expect("空きができても入りません").unwrap();
+    writer.finish(outbox, &Runtime::default(), Duration::from_secs(1)).await;
+    // 待っている間に取り消した送信 (3) は入らず、残りは順番どおりに書き込まれる
+    assert_eq!(peer.written(), vec![text(0), text(1), text(2), text(4)]);
+}
+
+#[tokio::test]
+async fn drop_discards_and_warns_once() {
+    let peer = SlowPeer::new();
+    let warnings = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&warnings);
+    let (outbox, writer) = start(&peer, QueuePolicy::Drop);
+    let mut outbox = outbox.on_drop(move || {
+        counted.fetch_add(1, Ordering::SeqCst);
+    });
+
+    outbox.send(chat(0)).await.unwrap();
+    // 書き込むタスクが最初のフレームを取り出すのを待つ
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for n in 1..10 {
+        tokio::time::timeout(Duration::from_millis(100), outbox.send(chat(n))).await.expect("Dropでは待たずに戻ります").unwrap();
+    }
+    assert_eq!(outbox.dropped(), 7);
+    assert_eq!(warnings.load(Ordering::SeqCst), 1);
+
+    peer.gate.add_permits(100);
+    writer.finish(outbox, &Runtime::default(), Duration::from_secs(1)).await;
+    assert_eq!(peer.written(), vec![chat(0), chat(1), chat(2)]);
+}
+
+#[tokio::test]
+async fn drop_keeps_transfer_and_control_frames() {
+    let peer = SlowPeer::new();
+    let (mut outbox, writer) = start(&peer, QueuePolicy::Drop);
+
+    outbox.send(chunk(0)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    outbox.send(chunk(1)).await.unwrap();
+    outbox.send(chunk(2)).await.unwrap();
+    // 順番待ちがいっぱいでも、ファイルのデータと応答は捨てずに空きを待つ
+    let pong = Message::Text(Envelope::Pong { sent_at: 7 }.encode());
+    let mut waiting = Box::pin(outbox.send(chunk(3)));
+    assert!(tokio::time::timeout(Duration::from_millis(100), &mut waiting).await.is_err(), "空きがないのに入りました");
+    peer.gate.add_permits(1);
+    tokio::time::timeout(Duration::from_secs(1), waiting).await.expect("空きができても入りません").unwrap();
+    // チャットのメッセージは捨てる
+    for n in 4..10 {
+        outbox.send(chat(n)).await.unwrap();
+    }
+    assert!(outbox.dropped() > 0);
+    peer.gate.add_permits(100);
+    for message in [chunk(5), pong.clone(), Message::Close(None)] {
+        tokio::time::timeout(Duration::from_secs(1), outbox.send(message)).await.expect("空きができても入りません").unwrap();
+    }
+
+    writer.finish(outbox, &Runtime::default(), Duration::from_secs(1)).await;
+    // 転送のチャンクは欠けずに順番どおり届く
+    let kept: Vec<Message> = peer.written().into_iter().filter(|message| !(4..10).any(|n| *message == chat(n))).collect();
+    assert_eq!(kept, vec![chunk(0), chunk(1), chunk(2), chunk(3), chunk(5), pong, Message::Close(None)]);
+}
+
+#[tokio::test]
+async fn disconnect_fails_when_full() {
+    let peer = SlowPeer::new();
+    let (mut outbox, writer) = start(&peer, QueuePolicy::Disconnect);
+
+    outbox.send(text(0)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    outbox.send(text(1)).await.unwrap();
+    outbox.send(text(2)).await.unwrap();
+    let error = outbox.send(text(3)).await.expect_err("いっぱいでも送れました");
+    assert!(matches!(error, Error::WriteBufferFull(message) if message == text(3)));
+
+    // 書き込めない相手のためにいつまでも待たない
+    let runtime = Runtime::default();
+    let finishing = writer.finish(outbox, &runtime, Duration::from_millis(100));
+    tokio::time::timeout(Duration::from_secs(1), finishing).await.expect("書き込めない相手を待ち続けています");
+}
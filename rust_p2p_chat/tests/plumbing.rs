@@ -0,0 +1,76 @@
+// スクリプト向けのサブコマンドの処理 (resolve・probe・招待) が、決まった項目の結果を返すことを確かめる
+
+mod harness;
+
+use harness::{Node, LISTEN_ADDR};
+use rust_p2p_chat::builder::IdentitySource;
+use rust_p2p_chat::contacts::{AddressBook, Contact};
+use rust_p2p_chat::plumbing::{self, Invite};
+use rust_p2p_chat::runtime::MemoryTransport;
+use serde_json::json;
+use std::time::Duration;
+
+#[tokio::test]
+async fn resolve_contact() {
+    let network = MemoryTransport::new();
+    let bob = Node::new(&network, "bob");
+    let contact: Contact = serde_json::from_value(json!({ "uri": "wss://192.0.2.1:8443", "fingerprint": "sha256:aa", "strict": true })).unwrap();
+    let mut book = AddressBook::load(bob.paths.contacts_file()).unwrap();
+    book.add("alice", contact).unwrap();
+    book.save().unwrap();
+
+    let settings = bob.peer.client().target("alice").unwrap().build().unwrap();
+    let resolved = serde_json::to_value(plumbing::resolve("alice", &settings).unwrap()).unwrap();
+    assert_eq!(
+        resolved,
+        json!({
+            "target": "alice",
+            "uri": "wss://192.0.2.1:8443/",
+            "peer": "192.0.2.1:8443",
+            "transport": "wss",
+            "contact": "alice",
+            "fingerprint": "sha256:aa",
+            "strict": true,
+        })
+    );
+}
+
+#[tokio::test]
+async fn probe_reports_handshake() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let fingerprint = IdentitySource::Profile.load(&alice.paths).unwrap().fingerprint();
+
+    // 受け付けたセッションを捨てると証明書を送る前に終わってしまうため、テストの終わりまで持っておく
+    let listening = tokio::spawn(async move {
+        let session = alice.peer.listen(LISTEN_ADDR.parse().unwrap()).await.ok();
+        std::future::pending::<()>().await;
+        drop(session);
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let settings = bob.peer.client().uri(format!("wss://{}#{}", LISTEN_ADDR, fingerprint)).build().unwrap();
+    let probe = plumbing::probe(&settings).await.expect("ハンドシェイクできません");
+    assert_eq!(probe.certificate.fingerprint, fingerprint);
+    assert_eq!(probe.certificate.pinned, Some(true));
+    assert_eq!(probe.certificate.trust, "unknown");
+    assert_eq!(probe.identity.as_deref(), Some(fingerprint.as_str()));
+    assert_eq!(probe.capabilities, vec!["signatures"]);
+    // known_peersには記録しない
+    assert!(!bob.paths.known_peers_file().exists());
+    listening.abort();
+}
+
+#[tokio::test]
+async fn invite_round_trip() {
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    let invite = Invite::new(&alice.paths, "wss://203.0.113.5:8080", Some("alice".to_string())).unwrap();
+    let fingerprint = IdentitySource::Profile.load(&alice.paths).unwrap().fingerprint();
+    assert_eq!(invite.connect_uri(), format!("wss://203.0.113.5:8080/#{}", fingerprint));
+
+    let encoded = invite.encode();
+    assert!(encoded.starts_with("p2pchat-invite1:"));
+    assert_eq!(Invite::decode(&encoded).unwrap(), invite);
+    assert!(Invite::decode("wss://203.0.113.5:8080").is_err());
+    assert!(Invite::new(&alice.paths, "ws://203.0.113.5:8080", None).is_err());
+}
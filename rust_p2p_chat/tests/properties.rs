@@ -0,0 +1,74 @@
+// ファイルの分割と組み立てについて、任意の内容と区切り位置で成り立つ性質を確かめる
+
+mod harness;
+
+use bytes::Bytes;
+use harness::TempDir;
+use proptest::prelude::*;
+use rust_p2p_chat::protocol::Envelope;
+use rust_p2p_chat::transfer::{hex, DownloadConfig, Downloads};
+
+fn downloads(dir: &TempDir) -> Downloads {
+    let config = DownloadConfig { dir: dir.to_path_buf(), per_peer: false, accept: true, max_bytes: None };
+    Downloads::new(config, "peer")
+}
+
+// 内容を区切り位置で分け、送る順のFileChunkにする (Envelopeとして一度テキストにしてから戻す)
+fn chunks(content: &[u8], mut cuts: Vec<usize>) -> Vec<(u64, Bytes)> {
+    cuts.iter_mut().for_each(|cut| *cut %= content.len() + 1);
+    cuts.sort_unstable();
+    cuts.dedup();
+    let mut bounds = vec![0];
+    bounds.extend(cuts);
+    bounds.push(content.len());
+    bounds
+        .windows(2)
+        .map(|range| Envelope::FileChunk { id: 1, offset: range[0] as u64, data: Bytes::copy_from_slice(&content[range[0]..range[1]]) })
+        .map(|envelope| match Envelope::decode(&envelope.encode()) {
+            Envelope::FileChunk { offset, data, .. } => (offset, data),
+            other => panic!("FileChunkに戻りません: {:?}", other),
+        })
+        .collect()
+}
+
+fn sha256(content: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, content).as_ref())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // どこで区切っても、順に届けば元の内容に戻る
+    #[test]
+    fn reassembles_at_any_split(content in proptest::collection::vec(any::<u8>(), 0..4096), cuts in proptest::collection::vec(any::<usize>(), 0..16)) {
+        let dir = TempDir::new("prop");
+        let mut downloads = downloads(&dir);
+        prop_assert!(downloads.start(1, "data.bin", content.len() as u64, &sha256(&content)).unwrap());
+        for (offset, data) in chunks(&content, cuts) {
+            downloads.chunk(1, offset, &data).unwrap();
+        }
+        let file = downloads.finish(1).unwrap().expect("保存されていません");
+        prop_assert_eq!(file.size, content.len() as u64);
+        prop_assert_eq!(std::fs::read(&file.path).unwrap(), content);
+    }
+
+    // 順序が入れ替わった場合は途中で受信をやめ、ファイルを保存しない
+    #[test]
+    fn rejects_reordered_chunks(content in proptest::collection::vec(any::<u8>(), 2..4096), cuts in proptest::collection::vec(any::<usize>(), 1..16), swap in any::<prop::sample::Index>()) {
+        let mut chunks = chunks(&content, cuts);
+        // 空のチャンクは入れ替えても区別できないので除く
+        chunks.retain(|(_, data)| !data.is_empty());
+        prop_assume!(chunks.len() >= 2);
+        let first = swap.index(chunks.len() - 1);
+        chunks.swap(first, first + 1);
+
+        let dir = TempDir::new("prop");
+        let mut downloads = downloads(&dir);
+        downloads.start(1, "data.bin", content.len() as u64, &sha256(&content)).unwrap();
+        let failed = chunks.iter().any(|(offset, data)| downloads.chunk(1, *offset, data).is_err());
+        prop_assert!(failed);
+        prop_assert!(downloads.finish(1).is_err());
+        prop_assert_eq!(downloads.active(), 0);
+        prop_assert!(!dir.join("data.bin").exists());
+    }
+}
@@ -0,0 +1,100 @@
+// 相手から受け取る量の制限が、上限を超えると受信を遅らせ、超え続けると切断する (ファイルのデータは遅らせるだけ) ことを確かめる
+
+use rust_p2p_chat::config::RateLimitConfig;
+use rust_p2p_chat::ratelimit::{RateLimiter, Verdict};
+use std::time::{Duration, Instant};
+
+fn limiter(messages_per_sec: u64, bytes_per_sec: u64, disconnect_after_secs: u64) -> RateLimiter {
+    RateLimiter::new(&RateLimitConfig { messages_per_sec, bytes_per_sec, disconnect_after_secs })
+}
+
+#[test]
+fn burst_then_throttle() {
+    let mut limiter = limiter(10, 0, 0);
+    let now = Instant::now();
+    // 2秒分まではまとめて受け取れる
+    for _ in 0..20 {
+        assert_eq!(limiter.check(now, 1, 100, false), Verdict::Allow);
+    }
+    assert_eq!(limiter.check(now, 1, 100, false), Verdict::Throttle { delay: Duration::from_millis(100), first: true });
+    assert!(matches!(limiter.check(now, 1, 100, false), Verdict::Throttle { first: false, .. }));
+    // 使った分は時間とともに戻る
+    assert_eq!(limiter.check(now + Duration::from_secs(1), 1, 100, false), Verdict::Allow);
+}
+
+#[test]
+fn bytes_are_limited_separately() {
+    let mut limiter = limiter(0, 1000, 0);
+    let now = Instant::now();
+    assert_eq!(limiter.check(now, 0, 2000, false), Verdict::Allow);
+    assert_eq!(limiter.check(now, 0, 500, false), Verdict::Throttle { delay: Duration::from_millis(500), first: true });
+    // メッセージの数は制限しない
+    assert_eq!(limiter.check(now + Duration::from_secs(1), 1000, 0, false), Verdict::Allow);
+}
+
+#[test]
+fn sustained_flood_disconnects() {
+    let mut limiter = limiter(10, 0, 5);
+    let started = Instant::now();
+    let mut now = started;
+    let disconnected = loop {
+        // 上限の2倍の速さで送り続ける
+        now += Duration::from_millis(50);
+        match limiter.check(now, 1, 0, false) {
+            Verdict::Disconnect => break now,
+            _ if now - started > Duration::from_secs(60) => panic!("切断されません"),
+            _ => {}
+        }
+    };
+    // まとめて受け取れる20件を2秒で使い切り、それから5秒超え続けたところで切る
+    let elapsed = disconnected - started;
+    assert!(elapsed >= Duration::from_secs(7) && elapsed < Duration::from_secs(8), "{:?}", elapsed);
+}
+
+#[test]
+fn file_data_is_throttled_but_not_disconnected() {
+    let mut limiter = limiter(0, 1000, 5);
+    let started = Instant::now();
+    let mut now = started;
+    let mut throttled = false;
+    while now - started < Duration::from_secs(60) {
+        // 上限の2倍の速さでファイルのデータを送り続ける
+        now += Duration::from_millis(50);
+        match limiter.check(now, 0, 100, true) {
+            Verdict::Disconnect => panic!("ファイルのデータで切断されました"),
+            Verdict::Throttle { first, .. } => {
+                assert!(!first, "ファイルのデータだけでは案内を出さない");
+                throttled = true;
+            }
+            Verdict::Allow => {}
+        }
+    }
+    assert!(throttled);
+    // ファイルのデータで上限を超えている間も、チャットは上限を超えたことにならない
+    assert!(matches!(limiter.check(now, 1, 10, false), Verdict::Throttle { first: false, .. }));
+}
+
+#[test]
+fn unlimited_by_zero() {
+    let mut limiter = limiter(0, 0, 1);
+    let now = Instant::now();
+    for _ in 0..10_000 {
+        assert_eq!(limiter.check(now, 1, 1 << 20, false), Verdict::Allow);
+    }
+}
+
+#[test]
+fn reconfigure_applies_new_limits() {
+    let mut limiter = limiter(10, 0, 0);
+    let now = Instant::now();
+    for _ in 0..20 {
+        assert_eq!(limiter.check(now, 1, 100, false), Verdict::Allow);
+    }
+    assert!(matches!(limiter.check(now, 1, 100, false), Verdict::Throttle { .. }));
+    // 同じ設定では数え直さない
+    limiter.reconfigure(&RateLimitConfig { messages_per_sec: 10, bytes_per_sec: 0, disconnect_after_secs: 0 });
+    assert!(matches!(limiter.check(now, 1, 100, false), Verdict::Throttle { .. }));
+    // 上限をなくすとすぐに受け取れる
+    limiter.reconfigure(&RateLimitConfig { messages_per_sec: 0, bytes_per_sec: 0, disconnect_after_secs: 0 });
+    assert_eq!(limiter.check(now, 1, 100, false), Verdict::Allow);
+}
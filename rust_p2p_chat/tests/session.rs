@@ -0,0 +1,324 @@
+// 2つのセッションをメモリ上の通信路でつなぎ、ハンドシェイクから切断・再接続までを確かめる
+
+mod harness;
+
+use futures_util::{stream, StreamExt};
+use harness::{connect, wait_for, wait_for_file, Node};
+use rust_p2p_chat::history::Direction;
+use rust_p2p_chat::runtime::MemoryTransport;
+use rust_p2p_chat::{DisconnectReason, Event, SessionCommand};
+
+#[tokio::test]
+async fn handshake_and_messages() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+
+    wait_for(&mut listener, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+    wait_for(&mut client, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+    client.send_text("こんにちは").unwrap();
+    let seq = wait_for(&mut client, |event| match event {
+        Event::MessageSent { seq, body } if body == "こんにちは" => Some(seq),
+        _ => None,
+    })
+    .await;
+    let received = wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { seq, body, .. } => Some((seq, body)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, (seq, "こんにちは".to_string()));
+    wait_for(&mut client, |event| matches!(event, Event::DeliveryAck { seq: acked } if acked == seq).then_some(())).await;
+
+    listener.send_text("やあ").unwrap();
+    let body = wait_for(&mut client, |event| match event {
+        Event::MessageReceived { body, .. } => Some(body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "やあ");
+}
+
+#[tokio::test]
+async fn file_transfer() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let (mut listener, client) = connect(&alice, &bob).await;
+
+    // 1つのチャンクに収まらない大きさにする
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let source = bob.dir.join("report.bin");
+    std::fs::write(&source, &content).unwrap();
+    client.send_file(&source).unwrap();
+
+    let (bytes, total) = wait_for(&mut listener, |event| match event {
+        Event::TransferProgress { direction: Direction::Incoming, bytes, total, .. } if Some(bytes) == total => Some((bytes, total)),
+        _ => None,
+    })
+    .await;
+    assert_eq!((bytes, total), (content.len() as u64, Some(content.len() as u64)));
+    let saved = wait_for_file(&alice.paths.downloads_dir(), "report.bin").await;
+    assert_eq!(std::fs::read(saved).unwrap(), content);
+}
+
+#[tokio::test]
+async fn close_and_resume() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    client.send_text("1回目").unwrap();
+    wait_for(&mut listener, |event| matches!(event, Event::MessageReceived { .. }).then_some(())).await;
+    client.close().unwrap();
+    let reason = wait_for(&mut client, |event| match event {
+        Event::Disconnected { reason, .. } => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reason, DisconnectReason::Closed);
+    let reason = wait_for(&mut listener, |event| match event {
+        Event::Disconnected { reason, .. } => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert!(matches!(reason, DisconnectReason::PeerClosed { .. }), "{:?}", reason);
+    listener.closed().await;
+    client.closed().await;
+
+    // 同じ保存先から接続し直すと、前回のセッションを引き継ぐ
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    wait_for(&mut client, |event| matches!(event, Event::Resumed).then_some(())).await;
+    client.send_text("2回目").unwrap();
+    let body = wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { body, .. } => Some(body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "2回目");
+}
+
+#[tokio::test]
+async fn stream_and_sink() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let (listener, client) = connect(&alice, &bob).await;
+
+    // 送り終わるとforwardがセッションを閉じ、相手に切断が伝わる
+    let commands = stream::iter(["1", "2", "3"]).map(|body| Ok(SessionCommand::SendText(body.to_string())));
+    let (client_sink, _client_events) = StreamExt::split(client);
+    let sent = tokio::spawn(commands.forward(client_sink));
+
+    let bodies: Vec<String> = listener
+        .filter_map(|event| async move {
+            match event {
+                Event::MessageReceived { body, .. } => Some(body),
+                _ => None,
+            }
+        })
+        .collect()
+        .await;
+    assert_eq!(bodies, ["1", "2", "3"]);
+    sent.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn web_page_and_plain_websocket() {
+    use futures_util::SinkExt;
+    use rust_p2p_chat::runtime::Transport;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    let settings = alice.peer.listener().addr(harness::LISTEN_ADDR.parse().unwrap()).web(true).build().unwrap();
+    // 待受を始めてからブラウザの代わりに接続する
+    let browser = async {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        // WebSocketへの切り替えでない要求にはページを返し、待受を続ける
+        let mut stream = network.connect("127.0.0.1", 8080).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("new WebSocket("));
+        let mut stream = network.connect("127.0.0.1", 8080).await.unwrap();
+        stream.write_all(b"GET /favicon.ico HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        // ページと同じく平文のWebSocketで参加する
+        let stream = network.connect("127.0.0.1", 8080).await.unwrap();
+        let (ws, _) = tokio_tungstenite::client_async("ws://127.0.0.1:8080/", stream).await.unwrap();
+        ws
+    };
+    let (listener, mut ws) = tokio::join!(alice.peer.listen_with(settings), browser);
+    let mut listener = listener.expect("接続を受け付けられません");
+
+    // 同じEnvelopeでやり取りする
+    ws.send(Message::text(r#"{"type":"resume","token":null,"since":0}"#)).await.unwrap();
+    ws.send(Message::text(r#"{"type":"chat","seq":1,"body":"ブラウザから"}"#)).await.unwrap();
+    let body = wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { body, .. } => Some(body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "ブラウザから");
+    let delivered = tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+        while let Some(Ok(message)) = ws.next().await {
+            if let Message::Text(text) = message {
+                if text.contains(r#""type":"delivered""#) {
+                    return text.to_string();
+                }
+            }
+        }
+        panic!("受信確認の前に接続が閉じられました");
+    })
+    .await
+    .expect("受信確認が届きません");
+    assert_eq!(delivered, r#"{"type":"delivered","seq":1}"#);
+}
+
+// 再送はトークンで再開したセッションにだけ、そのセッションを発行した後に送ったメッセージを返す
+// (同じIPアドレスから接続しただけの相手には、それまでの履歴を渡さない)
+#[tokio::test]
+async fn backfill_requires_a_resumed_session() {
+    use futures_util::SinkExt;
+    use rust_p2p_chat::protocol::Envelope;
+    use rust_p2p_chat::runtime::Transport;
+    use tokio_tungstenite::tungstenite::Message;
+
+    type Browser = tokio_tungstenite::WebSocketStream<rust_p2p_chat::runtime::BoxStream>;
+
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    // 待受を始めてから平文のWebSocketで接続し、Resumeを送る
+    let join = |frames: Vec<String>| {
+        let settings = alice.peer.listener().addr(harness::LISTEN_ADDR.parse().unwrap()).web(true).build().unwrap();
+        let network = network.clone();
+        let browser = async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let stream = network.connect("127.0.0.1", 8080).await.unwrap();
+            let (mut ws, _) = tokio_tungstenite::client_async("ws://127.0.0.1:8080/", stream).await.unwrap();
+            for frame in frames {
+                ws.send(Message::text(frame)).await.unwrap();
+            }
+            ws
+        };
+        let listening = alice.peer.listen_with(settings);
+        async move {
+            let (listener, ws) = tokio::join!(listening, browser);
+            (listener.expect("接続を受け付けられません"), ws)
+        }
+    };
+    // 条件に合うEnvelopeが届くまで読む
+    async fn next_envelope<T>(ws: &mut Browser, mut matches: impl FnMut(Envelope) -> Option<T>) -> T {
+        tokio::time::timeout(harness::EVENT_TIMEOUT, async {
+            while let Some(Ok(message)) = ws.next().await {
+                if let Message::Text(text) = message {
+                    if let Some(found) = matches(Envelope::decode(&text)) {
+                        return found;
+                    }
+                }
+            }
+            panic!("待っているメッセージの前に接続が閉じられました");
+        })
+        .await
+        .expect("待っているメッセージが届きません")
+    }
+    fn backfilled(envelope: Envelope) -> Option<Vec<String>> {
+        match envelope {
+            Envelope::Backfill { messages } => Some(messages.into_iter().map(|message| message.body).collect()),
+            _ => None,
+        }
+    }
+
+    // 最初の接続でトークンを受け取り、切断中に送られたことにするメッセージを記録する
+    let (mut listener, mut ws) = join(vec![r#"{"type":"resume","token":null,"since":0}"#.to_string()]).await;
+    let token = next_envelope(&mut ws, |envelope| match envelope {
+        Envelope::Session { token, .. } => Some(token),
+        _ => None,
+    })
+    .await;
+    listener.send_text("秘密").unwrap();
+    next_envelope(&mut ws, |envelope| matches!(envelope, Envelope::Chat { .. }).then_some(())).await;
+    ws.close(None).await.unwrap();
+    wait_for(&mut listener, |event| matches!(event, Event::Disconnected { .. }).then_some(())).await;
+    listener.closed().await;
+
+    // トークンのない接続には、Resumeでも BackfillRequest でも何も再送しない
+    let frames = vec![r#"{"type":"resume","token":null,"since":0}"#.to_string(), r#"{"type":"backfill_request","since":0}"#.to_string()];
+    let (listener, mut ws) = join(frames).await;
+    assert_eq!(next_envelope(&mut ws, backfilled).await, Vec::<String>::new());
+    drop(ws);
+    drop(listener);
+
+    // トークンで再開したセッションには、sinceが0でもセッションを発行した後に送ったメッセージだけを再送する
+    let resume = format!(r#"{{"type":"resume","token":"{}","since":1}}"#, token);
+    let (_listener, mut ws) = join(vec![resume, r#"{"type":"backfill_request","since":0}"#.to_string()]).await;
+    assert_eq!(next_envelope(&mut ws, backfilled).await, ["秘密"]);
+    assert_eq!(next_envelope(&mut ws, backfilled).await, ["秘密"]);
+}
+
+// [[webhooks]] の送り先に出来事が届き、署名が付き、5xxなら再送される
+#[cfg(feature = "discovery")]
+#[tokio::test]
+async fn webhooks() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // 受け取った要求の (ヘッダー, 本文) を送る。最初の要求にだけ503を返す
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", server.local_addr().unwrap());
+    let (requests_tx, mut requests) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut first = true;
+        while let Ok((mut stream, _)) = server.accept().await {
+            let mut buf = Vec::new();
+            let mut chunk = [0; 4096];
+            let (head, body) = loop {
+                let n = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&buf).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break (head.to_ascii_lowercase(), body.to_string());
+                    }
+                }
+            };
+            let status = if std::mem::take(&mut first) { "503 Service Unavailable" } else { "200 OK" };
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = requests_tx.send((head, body));
+        }
+    });
+
+    let network = MemoryTransport::new();
+    let config = format!("[[webhooks]]\nurl = \"{}\"\nsecret = \"s3cret\"\nevents = [\"peer.connected\", \"message.received\"]\n", url);
+    let (alice, bob) = (Node::with_config(&network, "alice", &config), Node::new(&network, "bob"));
+    let (mut listener, client) = connect(&alice, &bob).await;
+    wait_for(&mut listener, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+    client.send_text("フックへ").unwrap();
+
+    let mut received = Vec::new();
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"s3cret");
+    while received.len() < 3 {
+        let (head, body) = tokio::time::timeout(harness::EVENT_TIMEOUT, requests.recv()).await.expect("Webhookが届きません").unwrap();
+        let signature = head.lines().find_map(|line| line.strip_prefix("x-p2pchat-signature: sha256=")).expect("署名がありません");
+        let expected: String = ring::hmac::sign(&key, body.as_bytes()).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(signature, expected);
+        received.push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+    }
+    // 503で断られたpeer.connectedは同じidで再送される
+    let events: Vec<&str> = received.iter().map(|payload| payload["event"].as_str().unwrap()).collect();
+    assert_eq!(events.iter().filter(|event| **event == "peer.connected").count(), 2);
+    assert_eq!(received.iter().filter(|payload| payload["event"] == "peer.connected").map(|payload| &payload["id"]).collect::<std::collections::HashSet<_>>().len(), 1);
+    let message = received.iter().find(|payload| payload["event"] == "message.received").expect("メッセージの出来事が届きません");
+    assert_eq!(message["body"], "フックへ");
+}
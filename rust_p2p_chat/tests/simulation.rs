@@ -0,0 +1,424 @@
+// 遅れ・切断・接続の拒否・応答のない接続を加えたメモリ上の通信路で、受信確認・再接続・時間切れを確かめる
+// tokioの時間を止めて動かすので、遅れは実際には待たず、毎回同じ順序で進む
+
+mod harness;
+
+use harness::{connect, connect_with, wait_for, Node, LISTEN_ADDR};
+use rust_p2p_chat::builder::ReconnectPolicy;
+use rust_p2p_chat::exit;
+use rust_p2p_chat::runtime::{LinkConditions, MemoryTransport, Transport};
+use rust_p2p_chat::transport::{ConnectError, ConnectStage};
+use rust_p2p_chat::{DisconnectReason, Event};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const LATENCY: Duration = Duration::from_millis(250);
+
+#[tokio::test(start_paused = true)]
+async fn acks_over_slow_link() {
+    let network = MemoryTransport::new();
+    network.set_conditions(LinkConditions { latency: LATENCY, ..LinkConditions::default() });
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    wait_for(&mut client, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+    let started = Instant::now();
+    for body in ["1", "2", "3"] {
+        client.send_text(body).unwrap();
+    }
+    let mut acked = Vec::new();
+    while acked.len() < 3 {
+        acked.push(wait_for(&mut client, |event| match event {
+            Event::DeliveryAck { seq } => Some(seq),
+            _ => None,
+        })
+        .await);
+    }
+    // 受信確認は往復してから届き、続けて送ったメッセージは同じ往復の間に届く
+    let elapsed = started.elapsed();
+    assert!(elapsed >= LATENCY * 2, "{:?}", elapsed);
+    assert!(elapsed < LATENCY * 6, "{:?}", elapsed);
+    assert!(acked.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", acked);
+
+    let mut bodies = Vec::new();
+    while bodies.len() < 3 {
+        bodies.push(wait_for(&mut listener, |event| match event {
+            Event::MessageReceived { body, .. } => Some(body),
+            _ => None,
+        })
+        .await);
+    }
+    assert_eq!(bodies, ["1", "2", "3"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn reconnect_after_cut_backfills_lost_message() {
+    let network = MemoryTransport::new();
+    network.set_conditions(LinkConditions { latency: LATENCY, ..LinkConditions::default() });
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    // 再送はその前に受け取ったメッセージの通し番号から求めるので、1つは届けておく
+    listener.send_text("届いたメッセージ").unwrap();
+    wait_for(&mut client, |event| matches!(event, Event::MessageReceived { .. }).then_some(())).await;
+    // 再開に使うトークン (Session) は接続側のResumeに応えて送られるので、それが届くまで待つ
+    tokio::time::sleep(LATENCY * 2).await;
+
+    // 送った直後、相手に届く前に経路が切れる
+    listener.send_text("届かなかったメッセージ").unwrap();
+    wait_for(&mut listener, |event| matches!(event, Event::MessageSent { .. }).then_some(())).await;
+    network.cut_links();
+    wait_for(&mut listener, |event| matches!(event, Event::Disconnected { .. }).then_some(())).await;
+    wait_for(&mut client, |event| matches!(event, Event::Disconnected { .. }).then_some(())).await;
+    listener.closed().await;
+    client.closed().await;
+
+    // 経路が戻るまでの2回の接続は拒否され、3回目で届く
+    network.set_conditions(LinkConditions { latency: LATENCY, refuse_connects: 2, ..LinkConditions::default() });
+    let policy = ReconnectPolicy { attempts: 3, initial_delay: Duration::from_secs(1), max_delay: Duration::from_secs(30) };
+    let started = Instant::now();
+    let (_listener, mut client) = connect_with(&alice, &bob, |builder| builder.reconnect(policy)).await;
+    assert!(started.elapsed() >= policy.delay(1) + policy.delay(2), "{:?}", started.elapsed());
+
+    wait_for(&mut client, |event| matches!(event, Event::Resumed).then_some(())).await;
+    let (body, sent_at) = wait_for(&mut client, |event| match event {
+        Event::MessageReceived { body, sent_at, .. } => Some((body, sent_at)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "届かなかったメッセージ");
+    assert!(sent_at.is_some(), "再送されたメッセージには元の送信日時が付く");
+}
+
+#[tokio::test(start_paused = true)]
+async fn cut_during_transfer_leaves_no_partial_file() {
+    let network = MemoryTransport::new();
+    network.set_conditions(LinkConditions { latency: LATENCY, ..LinkConditions::default() });
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let (mut listener, client) = connect(&alice, &bob).await;
+
+    let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let source = bob.dir.join("large.bin");
+    std::fs::write(&source, &content).unwrap();
+    client.send_file(&source).unwrap();
+
+    // 最初のチャンクが届いたところで切る
+    wait_for(&mut listener, |event| match event {
+        Event::TransferProgress { bytes, total, .. } if bytes > 0 && Some(bytes) < total => Some(()),
+        _ => None,
+    })
+    .await;
+    network.cut_links();
+    wait_for(&mut listener, |event| matches!(event, Event::Disconnected { .. }).then_some(())).await;
+    listener.closed().await;
+
+    // 転送の再開はないので、受信途中の一時ファイルは消え、何も保存されない
+    let downloads = alice.paths.downloads_dir();
+    let leftovers: Vec<_> = walk(&downloads);
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}
+
+#[tokio::test(start_paused = true)]
+async fn transfer_waits_for_acks_and_lets_messages_through() {
+    let network = MemoryTransport::new();
+    network.set_conditions(LinkConditions { latency: LATENCY, ..LinkConditions::default() });
+    // 相手が受け取ったと知らせるまで、1チャンクより先に送らない
+    let alice = Node::new(&network, "alice");
+    let bob = Node::with_config(&network, "bob", "[connection]\ntransfer_window_kb = 64\n");
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    wait_for(&mut client, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+    let chunks = 8;
+    let content: Vec<u8> = (0..chunks * 64 * 1024).map(|i| (i % 251) as u8).collect();
+    let source = bob.dir.join("large.bin");
+    std::fs::write(&source, &content).unwrap();
+    let started = Instant::now();
+    client.send_file(&source).unwrap();
+    wait_for(&mut client, |event| matches!(event, Event::TransferProgress { bytes, .. } if bytes > 0).then_some(())).await;
+    client.send_text("転送中のメッセージ").unwrap();
+
+    // メッセージはファイルのデータの後ろで待たされず、受信し終える前に届く
+    let mut received = 0;
+    wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { .. } => Some(()),
+        Event::TransferProgress { bytes, .. } => {
+            received = bytes;
+            None
+        }
+        _ => None,
+    })
+    .await;
+    assert!(received < content.len() as u64, "{}", received);
+    wait_for(&mut listener, |event| match event {
+        Event::TransferProgress { bytes, total, .. } if Some(bytes) == total => Some(()),
+        _ => None,
+    })
+    .await;
+    // 2つ目からのチャンクは、それぞれ前のチャンクの受信確認が往復してから送られる
+    let elapsed = started.elapsed();
+    assert!(elapsed >= LATENCY * 2 * (chunks as u32 - 1), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn transfer_survives_a_full_queue_under_drop() {
+    let network = MemoryTransport::new();
+    // 受け取りの遅い相手に、小さな順番待ちで送る
+    let alice = Node::with_config(&network, "alice", "[rate_limit]\nbytes_per_sec = 65536\n");
+    let bob = Node::with_config(&network, "bob", "[connection]\nsend_queue = 1\nqueue_full = \"drop\"\n");
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    wait_for(&mut client, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+    let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let source = bob.dir.join("large.bin");
+    std::fs::write(&source, &content).unwrap();
+    client.send_file(&source).unwrap();
+
+    // 順番待ちがいっぱいでもチャンクは捨てられず、受信側は欠けのないファイルを保存する
+    loop {
+        let done = wait_for(&mut listener, |event| match event {
+            Event::TransferProgress { bytes, total, .. } => Some(Some(bytes) == total),
+            Event::Disconnected { reason, .. } => panic!("転送中に切断されました: {:?}", reason),
+            _ => None,
+        })
+        .await;
+        if done {
+            break;
+        }
+    }
+    let saved = harness::wait_for_file(&alice.paths.downloads_dir(), "large.bin").await;
+    assert_eq!(std::fs::read(saved).unwrap(), content);
+}
+
+#[tokio::test(start_paused = true)]
+async fn flooding_peer_is_throttled_then_disconnected() {
+    let network = MemoryTransport::new();
+    let alice = Node::with_config(&network, "alice", "[rate_limit]\nmessages_per_sec = 5\ndisconnect_after_secs = 3\n");
+    let bob = Node::new(&network, "bob");
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    wait_for(&mut client, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+    for n in 0..200 {
+        client.send_text(&format!("flood {}", n)).unwrap();
+    }
+    // 受け取りを遅らせると1回だけ知らせ、超え続けると切断する
+    let (mut received, mut warnings) = (0, 0);
+    let reason = wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { .. } => {
+            received += 1;
+            None
+        }
+        Event::Warning { .. } => {
+            warnings += 1;
+            None
+        }
+        Event::Disconnected { reason, .. } => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert!(matches!(reason, DisconnectReason::Error(_)), "{:?}", reason);
+    assert_eq!(warnings, 2, "遅らせる案内と切断の案内");
+    // まとめて受け取れる10件と、上限を超え続けた3秒の間に受け取った分だけが届く
+    assert!((10..=30).contains(&received), "{}", received);
+}
+
+#[tokio::test(start_paused = true)]
+async fn long_transfer_at_the_byte_limit_is_not_disconnected() {
+    let network = MemoryTransport::new();
+    const BYTES_PER_SEC: usize = 32 * 1024;
+    let alice = Node::with_config(&network, "alice", &format!("[rate_limit]\nbytes_per_sec = {}\n", BYTES_PER_SEC));
+    let bob = Node::new(&network, "bob");
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    wait_for(&mut client, |event| matches!(event, Event::PeerConnected { .. }).then_some(())).await;
+
+    // 上限の速さで送っても、切断されるまでの30秒より長くかかる大きさ
+    let content: Vec<u8> = (0..40 * BYTES_PER_SEC).map(|i| (i % 251) as u8).collect();
+    let source = bob.dir.join("large.bin");
+    std::fs::write(&source, &content).unwrap();
+    let started = Instant::now();
+    client.send_file(&source).unwrap();
+    wait_for(&mut client, |event| matches!(event, Event::TransferProgress { bytes, .. } if bytes > 0).then_some(())).await;
+    client.send_text("転送中のメッセージ").unwrap();
+
+    // ファイルのデータは遅らせるだけで、間に送ったメッセージも含めて切断されずに受け取り終える
+    let (mut messages, mut warnings) = (0, 0);
+    loop {
+        let done = wait_for(&mut listener, |event| match event {
+            Event::MessageReceived { .. } => {
+                messages += 1;
+                None
+            }
+            Event::Warning { .. } => {
+                warnings += 1;
+                None
+            }
+            Event::TransferProgress { bytes, total, .. } => Some(Some(bytes) == total),
+            Event::Disconnected { reason, .. } => panic!("転送中に切断されました: {:?}", reason),
+            _ => None,
+        })
+        .await;
+        if done {
+            break;
+        }
+    }
+    let elapsed = started.elapsed();
+    assert!(elapsed > Duration::from_secs(30), "{:?}", elapsed);
+    if messages == 0 {
+        wait_for(&mut listener, |event| matches!(event, Event::MessageReceived { .. }).then_some(())).await;
+    }
+    assert_eq!(warnings, 0, "ファイルのデータだけで受け取りを遅らせても案内は出さない");
+}
+
+#[tokio::test(start_paused = true)]
+async fn blackholed_connect_times_out() {
+    let network = MemoryTransport::new();
+    network.set_conditions(LinkConditions { drop_connects: 1, ..LinkConditions::default() });
+    let bob = Node::with_config(&network, "bob", "[connection]\nconnect_timeout_secs = 5\n");
+
+    let started = Instant::now();
+    let error = connect_alone(&bob).await;
+    assert!(matches!(error, ConnectError::Timeout { stage: ConnectStage::Tcp, after } if after == Duration::from_secs(5)), "{:?}", error);
+    assert_eq!(error.failure().code, exit::TIMEOUT);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(5) && elapsed < Duration::from_secs(6), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_tls_handshake_times_out() {
+    let network = MemoryTransport::new();
+    // TCP接続はすぐにできるが、TLSハンドシェイクの往復が間に合わない
+    network.set_conditions(LinkConditions { latency: Duration::from_secs(10), ..LinkConditions::default() });
+    let alice = Node::new(&network, "alice");
+    let bob = Node::with_config(&network, "bob", "[connection]\ntls_timeout_secs = 3\n");
+
+    let accepting = alice.peer.listen(LISTEN_ADDR.parse().unwrap());
+    let error = tokio::select! {
+        _ = accepting => panic!("接続を受け付けました"),
+        error = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            connect_alone(&bob).await
+        } => error,
+    };
+    assert!(matches!(error, ConnectError::Timeout { stage: ConnectStage::Tls, .. }), "{:?}", error);
+    assert!(error.to_string().contains("tls_timeout_secs"), "{}", error);
+}
+
+#[tokio::test(start_paused = true)]
+async fn silent_client_is_dropped_by_listener() {
+    let network = MemoryTransport::new();
+    let alice = Node::with_config(&network, "alice", "[connection]\ntls_timeout_secs = 3\n");
+
+    let addr: std::net::SocketAddr = LISTEN_ADDR.parse().unwrap();
+    let accepting = alice.peer.listen(addr);
+    let started = Instant::now();
+    let (result, _silent) = tokio::join!(accepting, async {
+        // TCP接続だけして何も送らない
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        network.connect(&addr.ip().to_string(), addr.port()).await.unwrap()
+    });
+    let error = match result {
+        Ok(_) => panic!("接続を受け付けました"),
+        Err(error) => error,
+    };
+    assert!(error.to_string().contains("tls_timeout_secs"), "{}", error);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(3) && elapsed < Duration::from_secs(4), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn zero_timeout_waits_without_limit() {
+    let network = MemoryTransport::new();
+    network.set_conditions(LinkConditions { latency: Duration::from_secs(10), ..LinkConditions::default() });
+    // 待受側もTLS・WebSocketのハンドシェイクに同じ設定を使う
+    let unlimited = "[connection]\nconnect_timeout_secs = 0\ntls_timeout_secs = 0\nhandshake_timeout_secs = 0\n";
+    let alice = Node::with_config(&network, "alice", unlimited);
+    let bob = Node::with_config(&network, "bob", unlimited);
+    connect(&alice, &bob).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn idle_connection_is_closed_by_listener() {
+    let network = MemoryTransport::new();
+    let alice = Node::with_config(&network, "alice", "[listen]\nidle_timeout_mins = 1\n");
+    let bob = Node::new(&network, "bob");
+    let (mut listener, mut client) = connect(&alice, &bob).await;
+    let started = Instant::now();
+
+    // メッセージのやり取りで期限が延び、自動で送り合うPingでは延びない
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    client.send_text("まだいます").unwrap();
+    tokio::time::sleep(Duration::from_secs(59)).await;
+    let reason = wait_for(&mut listener, |event| match event {
+        Event::Disconnected { reason, .. } => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reason, DisconnectReason::Idle);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(90) && elapsed < Duration::from_secs(92), "{:?}", elapsed);
+
+    // 接続側はネットワークの障害と区別できる
+    let reason = wait_for(&mut client, |event| match event {
+        Event::Disconnected { reason, .. } => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reason, DisconnectReason::PeerIdle);
+}
+
+#[tokio::test(start_paused = true)]
+async fn silent_peer_is_declared_unreachable_after_missed_pongs() {
+    let network = MemoryTransport::new();
+    let alice = Node::new(&network, "alice");
+    let bob = Node::with_config(&network, "bob", "[connection]\nping_interval_secs = 5\nmissed_pongs = 3\n");
+    let (_listener, mut client) = connect(&alice, &bob).await;
+
+    // 相手から何も届かない間だけPingを送り、そのPongで往復時間を測る
+    let (last, _) = wait_for(&mut client, |event| match event {
+        Event::Latency { last, average } => Some((last, average)),
+        _ => None,
+    })
+    .await;
+    assert!(last < Duration::from_secs(1), "{:?}", last);
+
+    // 接続を閉じないまま不通にすると、Pongが3回続けて届かずに切断する (最初のPingまで5秒、その後Pingごとに5秒待つ)
+    network.stall_links();
+    let stalled = Instant::now();
+    tokio::time::sleep(Duration::from_secs(15)).await;
+    let reason = wait_for(&mut client, |event| match event {
+        Event::Disconnected { reason, .. } => Some(reason),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reason, DisconnectReason::Unreachable);
+    let elapsed = stalled.elapsed();
+    assert!(elapsed >= Duration::from_secs(19) && elapsed <= Duration::from_secs(21), "{:?}", elapsed);
+}
+
+// bobから待受のアドレスに接続し、失敗した理由を返す
+async fn connect_alone(bob: &Node) -> ConnectError {
+    let settings = bob.peer.client().target(&format!("wss://{}", LISTEN_ADDR)).unwrap().build().unwrap();
+    let error = match bob.peer.connect_with(settings).await {
+        Ok(_) => panic!("接続できました"),
+        Err(error) => error,
+    };
+    match error.downcast::<ConnectError>() {
+        Ok(error) => *error,
+        Err(error) => panic!("接続の失敗の種類が分かりません: {}", error),
+    }
+}
+
+fn walk(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .flat_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                walk(&path)
+            } else {
+                vec![path]
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,44 @@
+// --syslog udp://... で、出来事がレベルに合ったseverityとfacility (セキュリティの出来事はauthpriv) のRFC 5424の形式で届くことを確かめる
+// ログの出力先はプロセスで1回しか設定できないため、1つのテストにまとめる
+
+use rust_p2p_chat::logging::{self, Facility, LogFormat, SyslogConfig, SyslogTarget, Verbosity};
+use std::net::UdpSocket;
+use std::time::Duration;
+
+fn receive(server: &UdpSocket) -> String {
+    let mut buf = [0; 4096];
+    let n = server.recv(&mut buf).expect("syslogにログが届きません");
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+#[test]
+fn events_reach_syslog() {
+    assert_eq!("local".parse(), Ok(SyslogTarget::Local));
+    assert_eq!("udp://logs.example.org".parse(), Ok(SyslogTarget::Udp("logs.example.org:514".to_string())));
+    assert!("tcp://logs.example.org".parse::<SyslogTarget>().is_err());
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let target = SyslogTarget::Udp(server.local_addr().unwrap().to_string());
+    let syslog = SyslogConfig { target, facility: Facility::Local3 };
+    logging::init(None, Some(syslog), LogFormat::Text, false, Some("info"), false, Verbosity::Normal, None).unwrap();
+
+    // 接続のスパンの項目も載る。local3 (19) × 8 + info (6)
+    tracing::info_span!("connection", peer = "192.0.2.1").in_scope(|| tracing::info!(bytes = 12, "TCP接続を受け付けました"));
+    let line = receive(&server);
+    assert!(line.starts_with("<158>1 "), "{}", line);
+    assert!(line.contains(&format!(" p2pchat {} - - ", std::process::id())), "{}", line);
+    assert!(line.ends_with("TCP接続を受け付けました peer=192.0.2.1 bytes=12"), "{}", line);
+
+    // infoより詳しいログは送らない
+    tracing::debug!("送らない");
+    // セキュリティの出来事はauthpriv (10) × 8 + warning (4)
+    tracing::warn!(security = true, peer = "192.0.2.9", "許可されていない相手からの接続を拒否しました");
+    let line = receive(&server);
+    assert!(line.starts_with("<84>1 "), "{}", line);
+    assert!(line.ends_with("許可されていない相手からの接続を拒否しました peer=192.0.2.9"), "{}", line);
+
+    // local3 × 8 + err (3)
+    tracing::error!("TLSハンドシェイクに失敗しました");
+    assert!(receive(&server).starts_with("<155>1 "));
+}
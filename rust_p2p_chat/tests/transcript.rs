@@ -0,0 +1,148 @@
+// 署名付きトランスクリプト: そのまま検証でき、本文・通し番号・署名・証明書のどれを書き換えても、
+// エントリを削除・並べ替えたり時刻・相手・向きを書き換えたりしても検証に失敗することを確かめる
+
+mod harness;
+
+use harness::TempDir;
+use rust_p2p_chat::history::{self, Direction, EventKind, ExportFilter, ExportFormat, History, Manifest, MessageSignature};
+use rust_p2p_chat::identity::{self, CertStore, Identity};
+use serde_json::Value;
+
+struct Fixture {
+    dir: TempDir,
+    /// 書き出したトランスクリプト (aliceが送った1件と、bobから受け取った1件)
+    transcript: Value,
+    bob_cert: String,
+    alice: Identity,
+}
+
+fn identity(dir: &std::path::Path, name: &str) -> Identity {
+    Identity::load_or_generate(dir.join(format!("{}.der", name)), dir.join(format!("{}.key", name))).unwrap()
+}
+
+fn signature(identity: &Identity, seq: u64, body: &str) -> MessageSignature {
+    MessageSignature { signer: identity.fingerprint(), sig: identity.sign(&identity::signed_content(seq, body)).unwrap() }
+}
+
+fn export(name: &str) -> Fixture {
+    let dir = TempDir::new(&format!("transcript-{}", name));
+    let (alice, bob) = (identity(&dir, "alice"), identity(&dir, "bob"));
+
+    let mut history = History::open(dir.join("history.jsonl"), None).unwrap();
+    let sent = EventKind::Message { direction: Direction::Outgoing, body: "会議は3時から".to_string() };
+    history.append_signed("bob", sent, |seq| Some(signature(&alice, seq, "会議は3時から"))).unwrap();
+    let received = EventKind::Message { direction: Direction::Incoming, body: "了解".to_string() };
+    history.append_remote("bob", 41, chrono::Local::now(), received, Some(signature(&bob, 41, "了解"))).unwrap();
+
+    let mut certs = CertStore::load(dir.join("certs.json")).unwrap();
+    certs.insert(&alice.cert_der);
+    let bob_fingerprint = certs.insert(&bob.cert_der);
+    let entries = history.load().unwrap();
+    let filter = ExportFilter { peer: None, since: None };
+    let manifest = Manifest::collect(&entries, &filter, &certs, &alice).unwrap();
+    let bob_cert = manifest.signers[&bob_fingerprint].clone();
+    let rendered = history::render_transcript(&entries, &filter, ExportFormat::Json, Some(manifest)).unwrap();
+    Fixture { dir, transcript: serde_json::from_str(&rendered).unwrap(), bob_cert, alice }
+}
+
+// 書き換えたトランスクリプトで署名が正しくないとされたエントリの通し番号
+fn invalid_after(fixture: &Fixture, tamper: impl FnOnce(&mut Value)) -> Vec<u64> {
+    let mut transcript = fixture.transcript.clone();
+    tamper(&mut transcript);
+    history::verify_transcript(&transcript.to_string()).unwrap().invalid
+}
+
+// 書き換えたトランスクリプトの全体の署名が正しいか
+fn sealed_after(fixture: &Fixture, tamper: impl FnOnce(&mut Value)) -> bool {
+    let mut transcript = fixture.transcript.clone();
+    tamper(&mut transcript);
+    history::verify_transcript(&transcript.to_string()).unwrap().sealed
+}
+
+#[test]
+fn untouched_transcript_verifies() {
+    let fixture = export("untouched");
+    let result = history::verify_transcript(&fixture.transcript.to_string()).unwrap();
+    assert_eq!((result.valid, result.unsigned, result.invalid), (2, 0, Vec::new()));
+    assert!(result.sealed);
+    assert_eq!(result.exporter, Some(fixture.alice.fingerprint()));
+    assert_eq!(result.signers.len(), 2);
+}
+
+#[test]
+fn edited_entries_break_the_seal() {
+    let fixture = export("seal");
+    // エントリを削除した・並べ替えた
+    assert!(!sealed_after(&fixture, |t| {
+        t["entries"].as_array_mut().unwrap().remove(0);
+    }));
+    assert!(!sealed_after(&fixture, |t| t["entries"].as_array_mut().unwrap().reverse()));
+    // 時刻・相手・向きを書き換えた (メッセージごとの署名の対象には含まれない)
+    assert!(!sealed_after(&fixture, |t| t["entries"][0]["timestamp"] = "2020-01-01T00:00:00+00:00".into()));
+    assert!(!sealed_after(&fixture, |t| t["entries"][0]["peer"] = "carol".into()));
+    assert!(!sealed_after(&fixture, |t| t["entries"][1]["direction"] = "outgoing".into()));
+    // 署名を取り除いて署名のないメッセージにした
+    assert!(!sealed_after(&fixture, |t| {
+        t["entries"][1].as_object_mut().unwrap().remove("signature");
+    }));
+    // 全体の署名がない
+    assert!(!sealed_after(&fixture, |t| {
+        t["manifest"].as_object_mut().unwrap().remove("seal");
+    }));
+}
+
+// 別の鍵で作り直したトランスクリプトは検証に通るが、署名者・エクスポートした側が別人として報告される
+#[test]
+fn resealed_transcript_reports_the_forger() {
+    let fixture = export("forged");
+    let mallory = identity(&fixture.dir, "mallory");
+    let mut transcript = fixture.transcript.clone();
+    transcript["entries"][0]["body"] = "会議は中止".into();
+    transcript["entries"][0]["signature"] = serde_json::to_value(signature(&mallory, 1, "会議は中止")).unwrap();
+    let entries: Vec<history::HistoryEntry> = serde_json::from_value(transcript["entries"].clone()).unwrap();
+    let mut certs = CertStore::load(fixture.dir.join("certs.json")).unwrap();
+    certs.insert(&mallory.cert_der);
+    certs.insert(&identity(&fixture.dir, "bob").cert_der);
+    let filter = ExportFilter { peer: None, since: None };
+    let manifest = Manifest::collect(&entries, &filter, &certs, &mallory).unwrap();
+    let rendered = history::render_transcript(&entries, &filter, ExportFormat::Json, Some(manifest)).unwrap();
+
+    let result = history::verify_transcript(&rendered).unwrap();
+    assert!(result.sealed && result.invalid.is_empty());
+    assert_eq!(result.exporter, Some(mallory.fingerprint()));
+    assert!(result.signers.contains(&mallory.fingerprint()));
+    assert!(!result.signers.contains(&fixture.alice.fingerprint()));
+}
+
+#[test]
+fn tampered_transcript_fails_verification() {
+    let fixture = export("tampered");
+    // 本文を書き換えた
+    assert_eq!(invalid_after(&fixture, |t| t["entries"][0]["body"] = "会議は4時から".into()), [1]);
+    // 通し番号を書き換えた (送ったメッセージはseq、受け取ったメッセージはremote_seqが署名の対象)
+    assert_eq!(invalid_after(&fixture, |t| t["entries"][0]["seq"] = 5.into()), [5]);
+    assert_eq!(invalid_after(&fixture, |t| t["entries"][1]["remote_seq"] = 42.into()), [2]);
+    // 別のメッセージの署名に差し替えた
+    assert_eq!(invalid_after(&fixture, |t| t["entries"][0]["signature"]["sig"] = t["entries"][1]["signature"]["sig"].clone()), [1]);
+    // 署名を壊した
+    assert_eq!(invalid_after(&fixture, |t| t["entries"][1]["signature"]["sig"] = "AAAA".into()), [2]);
+}
+
+#[test]
+fn wrong_signer_fails_verification() {
+    let fixture = export("signer");
+    // bobの署名をaliceのものだと偽った
+    assert_eq!(invalid_after(&fixture, |t| t["entries"][1]["signature"]["signer"] = t["entries"][0]["signature"]["signer"].clone()), [2]);
+    // 検証情報のaliceの証明書をbobの証明書に差し替えた (フィンガープリントが合わない)
+    let alice = fixture.transcript["entries"][0]["signature"]["signer"].as_str().unwrap().to_string();
+    assert_eq!(invalid_after(&fixture, |t| t["manifest"]["signers"][&alice] = fixture.bob_cert.clone().into()), [1]);
+    // 署名者の証明書が検証情報にない
+    assert_eq!(invalid_after(&fixture, |t| t["manifest"]["signers"] = serde_json::json!({})), [1, 2]);
+
+    // 検証情報がない・署名アルゴリズムが違う場合は検証そのものを断る
+    let mut transcript = fixture.transcript.clone();
+    transcript["manifest"]["algorithm"] = "RSA".into();
+    assert!(history::verify_transcript(&transcript.to_string()).is_err());
+    transcript.as_object_mut().unwrap().remove("manifest");
+    assert!(history::verify_transcript(&transcript.to_string()).is_err());
+}
@@ -0,0 +1,99 @@
+// 送信するファイルをチャンクごとに読むこと (全体をメモリに読み込まず、バッファを使い回す) と、
+// 受信したファイルの名前から保存先の外を指せないようにし、同名のファイルを上書きしないことを確かめる
+
+mod harness;
+
+use harness::TempDir;
+use p2pchat_core::pool;
+use rust_p2p_chat::protocol::Envelope;
+use rust_p2p_chat::transfer::{hex, move_to_unique_path, sanitize_file_name, unique_path, OutgoingFile, CHUNK_SIZE};
+
+#[tokio::test]
+async fn outgoing_file_is_read_chunk_by_chunk() {
+    let dir = TempDir::new("transfer-outgoing");
+    let path = dir.join("data.bin");
+    let content: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+
+    let mut file = OutgoingFile::open(7, &path).await.unwrap();
+    let sha256 = hex(ring::digest::digest(&ring::digest::SHA256, &content).as_ref());
+    assert_eq!(file.start(), Envelope::FileStart { id: 7, name: "data.bin".to_string(), size: content.len() as u64, sha256 });
+
+    let before = pool::CHUNKS.stats();
+    let mut received = Vec::new();
+    let mut offsets = Vec::new();
+    while let Some(chunk) = file.next_chunk().await.unwrap() {
+        let Envelope::FileChunk { id: 7, offset, data } = chunk else { panic!("FileChunkではありません: {:?}", chunk) };
+        assert!(data.len() <= CHUNK_SIZE);
+        offsets.push(offset);
+        // dataを捨てるとバッファはプールに戻る
+        received.extend_from_slice(&data);
+    }
+    assert_eq!(received, content);
+    assert_eq!(offsets, vec![0, CHUNK_SIZE as u64, 2 * CHUNK_SIZE as u64]);
+    assert_eq!(file.end(), Envelope::FileEnd { id: 7 });
+
+    // 1つ目のチャンクの後は、戻ったバッファを使い回す
+    let after = pool::CHUNKS.stats();
+    assert!(after.reused >= before.reused + 2, "{:?} -> {:?}", before, after);
+}
+
+#[test]
+fn file_names_cannot_leave_the_download_dir() {
+    // パス区切りはどちらも、最後の部分だけを残す
+    assert_eq!(sanitize_file_name("../x"), "x");
+    assert_eq!(sanitize_file_name("..\\x"), "x");
+    assert_eq!(sanitize_file_name("../../etc/passwd"), "passwd");
+    assert_eq!(sanitize_file_name("/etc/passwd"), "passwd");
+    assert_eq!(sanitize_file_name("C:\\Windows\\evil.exe"), "evil.exe");
+    assert_eq!(sanitize_file_name("C:evil.exe"), "C_evil.exe");
+    // 先頭の点 (隠しファイル・親ディレクトリ) は取り除く
+    assert_eq!(sanitize_file_name(".bashrc"), "bashrc");
+    assert_eq!(sanitize_file_name("...notes.txt"), "notes.txt");
+    // 制御文字とWindowsで使えない文字は _ にする
+    assert_eq!(sanitize_file_name("a\u{0}b\nc\u{1b}.txt"), "a_b_c_.txt");
+    assert_eq!(sanitize_file_name("what?<>|.txt"), "what____.txt");
+    // 何も残らない名前は既定の名前にする
+    for name in ["", ".", "..", "...", "../", "dir/", "  ", ". ."] {
+        assert_eq!(sanitize_file_name(name), "download", "{:?}", name);
+    }
+    assert_eq!(sanitize_file_name("レポート 2024.pdf"), "レポート 2024.pdf");
+}
+
+#[test]
+fn existing_files_get_a_number() {
+    let dir = TempDir::new("transfer-unique");
+    assert_eq!(unique_path(&dir, "report.pdf"), dir.join("report.pdf"));
+
+    std::fs::write(dir.join("report.pdf"), b"1").unwrap();
+    assert_eq!(unique_path(&dir, "report.pdf"), dir.join("report (1).pdf"));
+    std::fs::write(dir.join("report (1).pdf"), b"2").unwrap();
+    assert_eq!(unique_path(&dir, "report.pdf"), dir.join("report (2).pdf"));
+
+    // 拡張子がなければ末尾に、複数の点があれば最後の拡張子の前に付ける
+    std::fs::write(dir.join("notes"), b"").unwrap();
+    assert_eq!(unique_path(&dir, "notes"), dir.join("notes (1)"));
+    std::fs::write(dir.join("archive.tar.gz"), b"").unwrap();
+    assert_eq!(unique_path(&dir, "archive.tar.gz"), dir.join("archive.tar (1).gz"));
+}
+
+// 同じ名前のファイルを同時に保存しても、互いに上書きせずにすべて残る
+#[test]
+fn concurrent_saves_do_not_overwrite_each_other() {
+    let dir = TempDir::new("transfer-unique-race");
+    let saves: Vec<_> = (0..8)
+        .map(|n| {
+            let dir = dir.to_path_buf();
+            std::thread::spawn(move || {
+                let part = dir.join(format!("report.pdf.{}.part", n));
+                std::fs::write(&part, n.to_string()).unwrap();
+                let saved = move_to_unique_path(&part, &dir, "report.pdf").unwrap();
+                assert!(!part.exists());
+                saved
+            })
+        })
+        .collect();
+    let mut contents: Vec<String> = saves.into_iter().map(|save| std::fs::read_to_string(save.join().unwrap()).unwrap()).collect();
+    contents.sort();
+    assert_eq!(contents, (0..8).map(|n| n.to_string()).collect::<Vec<_>>());
+}
@@ -0,0 +1,102 @@
+// 履歴の暗号化: 正しいパスフレーズでは元に戻り、違うパスフレーズや壊れた暗号文はpanicせずにエラーになること、
+// 暗号化し直せなかった場合に鍵ファイルが残らないことを確かめる
+
+mod harness;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use harness::TempDir;
+use rust_p2p_chat::history::{EventKind, History};
+use rust_p2p_chat::vault::{self, Vault};
+
+// テストでは鍵の導出を軽くする (鍵ファイルを使う場合は既定の反復回数)
+const ITERATIONS: u32 = 1000;
+
+fn sealed_bytes(sealed: &str) -> Vec<u8> {
+    BASE64.decode(sealed.strip_prefix("enc1:").unwrap()).unwrap()
+}
+
+fn reseal(bytes: &[u8]) -> String {
+    format!("enc1:{}", BASE64.encode(bytes))
+}
+
+#[test]
+fn key_file_unlocks_with_the_right_passphrase_only() {
+    let dir = TempDir::new("vault");
+    let key_file = dir.join("history.key");
+
+    let created = Vault::create(&key_file, "correct horse").unwrap();
+    let sealed = created.seal("こんにちは".as_bytes()).unwrap();
+    assert!(vault::is_sealed(&sealed));
+
+    let unlocked = Vault::unlock(&key_file, "correct horse").unwrap();
+    assert_eq!(unlocked.open(&sealed).unwrap(), "こんにちは".as_bytes());
+
+    let error = Vault::unlock(&key_file, "wrong horse").err().expect("違うパスフレーズで開けました");
+    assert!(error.to_string().contains("パスフレーズが正しくありません"), "{}", error);
+}
+
+#[test]
+fn round_trip_and_wrong_passphrase() {
+    let salt = vault::random_salt().unwrap();
+    let vault = Vault::derive("passphrase", &salt, ITERATIONS).unwrap();
+    for plaintext in [&b""[..], b"hello", &[0u8, 255, 10, 13][..], &vec![7u8; 100_000][..]] {
+        let sealed = vault.seal(plaintext).unwrap();
+        assert_eq!(vault.open(&sealed).unwrap(), plaintext);
+    }
+    // 同じ平文でも毎回違うnonceで暗号化する
+    assert_ne!(vault.seal(b"hello").unwrap(), vault.seal(b"hello").unwrap());
+
+    let sealed = vault.seal(b"secret").unwrap();
+    let other = Vault::derive("another", &salt, ITERATIONS).unwrap();
+    assert!(other.open(&sealed).unwrap_err().to_string().contains("復号に失敗しました"));
+    // ソルトが違えば同じパスフレーズでも別の鍵になる
+    let salted = Vault::derive("passphrase", &vault::random_salt().unwrap(), ITERATIONS).unwrap();
+    assert!(salted.open(&sealed).is_err());
+    assert!(Vault::derive("passphrase", &salt, 0).is_err());
+}
+
+#[test]
+fn truncated_or_tampered_data_is_rejected() {
+    let vault = Vault::derive("passphrase", &vault::random_salt().unwrap(), ITERATIONS).unwrap();
+    let sealed = vault.seal(b"a message worth protecting").unwrap();
+    let bytes = sealed_bytes(&sealed);
+
+    // nonceより短い・認証タグが欠けた・末尾の1バイトが欠けた
+    for len in [0, 5, 12, 12 + 15, bytes.len() - 1] {
+        assert!(vault.open(&reseal(&bytes[..len])).is_err(), "{}バイトに切り詰めた暗号文を開けました", len);
+    }
+    // nonce・暗号文・認証タグのどの1ビットを変えても開けない
+    for index in [0, 11, 12, bytes.len() / 2, bytes.len() - 1] {
+        let mut tampered = bytes.clone();
+        tampered[index] ^= 0x01;
+        assert!(vault.open(&reseal(&tampered)).is_err(), "{}バイト目を書き換えた暗号文を開けました", index);
+    }
+    // 印がない・base64でない
+    assert!(vault.open(sealed.trim_start_matches("enc1:")).is_err());
+    assert!(vault.open("enc1:%%%").is_err());
+    assert!(!vault::is_sealed("plain text"));
+}
+
+// 履歴を暗号化し直せなかった場合は鍵ファイルを残さず、平文のままの履歴をもう一度暗号化できる
+#[test]
+fn failed_encryption_leaves_no_key_file() {
+    let dir = TempDir::new("vault-failed");
+    let (path, key_file) = (dir.join("history.jsonl"), dir.join("history.key"));
+    let mut history = History::open(&path, None).unwrap();
+    history.append("bob", EventKind::System { text: "接続しました".to_string() }).unwrap();
+    let plaintext = std::fs::read_to_string(&path).unwrap();
+
+    // 書き換えに使う一時ファイルを作れないようにする (ディスクがいっぱいの場合などの代わり)
+    let blocker = dir.join("history.jsonl.tmp");
+    std::fs::create_dir(&blocker).unwrap();
+    assert!(history.encrypt_all(&key_file, "correct horse").is_err());
+    assert!(!key_file.exists());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), plaintext);
+
+    std::fs::remove_dir(&blocker).unwrap();
+    assert_eq!(history.encrypt_all(&key_file, "correct horse").unwrap(), 1);
+    assert!(std::fs::read_to_string(&path).unwrap().lines().all(vault::is_sealed));
+    let history = History::open(&path, Some(Vault::unlock(&key_file, "correct horse").unwrap())).unwrap();
+    assert_eq!(history.load().unwrap().len(), 1);
+}
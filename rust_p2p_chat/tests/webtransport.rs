@@ -0,0 +1,71 @@
+// listen --webtransport の待受に https:// で接続し、WebTransportのストリームの上でも同じやり取りができることを確かめる
+// WebTransportはUDPのソケットを使うため、待受の空いているポートを先に探しておく
+
+#![cfg(feature = "webtransport")]
+
+mod harness;
+
+use harness::{wait_for, Node};
+use rust_p2p_chat::builder::IdentitySource;
+use rust_p2p_chat::runtime::MemoryTransport;
+use rust_p2p_chat::transport::ConnectError;
+use rust_p2p_chat::Event;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn free_udp_addr() -> SocketAddr {
+    std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn chat_over_webtransport() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let addr = free_udp_addr();
+    let fingerprint = IdentitySource::Profile.load(&alice.paths).unwrap().fingerprint();
+
+    let settings = alice.peer.listener().addr(addr).webtransport(true).build().unwrap();
+    let connecting = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let settings = bob.peer.client().uri(format!("https://{}/#{}", addr, fingerprint)).build().unwrap();
+        bob.peer.connect_with(settings).await
+    };
+    let (listener, client) = tokio::join!(alice.peer.listen_with(settings), connecting);
+    let (mut listener, mut client) = (listener.expect("接続を受け付けられません"), client.expect("接続できません"));
+
+    client.send_text("HTTP/3から").unwrap();
+    let body = wait_for(&mut listener, |event| match event {
+        Event::MessageReceived { body, .. } => Some(body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "HTTP/3から");
+    wait_for(&mut client, |event| matches!(event, Event::DeliveryAck { .. }).then_some(())).await;
+
+    listener.send_text("届きました").unwrap();
+    let body = wait_for(&mut client, |event| match event {
+        Event::MessageReceived { body, .. } => Some(body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(body, "届きました");
+}
+
+#[tokio::test]
+async fn pinned_fingerprint_is_checked() {
+    let network = MemoryTransport::new();
+    let (alice, bob) = (Node::new(&network, "alice"), Node::new(&network, "bob"));
+    let addr = free_udp_addr();
+
+    let settings = alice.peer.listener().addr(addr).webtransport(true).build().unwrap();
+    let listening = tokio::spawn(async move { alice.peer.listen_with(settings).await.map(|_| ()).map_err(|e| e.to_string()) });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let settings = bob.peer.client().uri(format!("https://{}/#sha256:00", addr)).build().unwrap();
+    let error = bob.peer.connect_with(settings).await.err().expect("フィンガープリントが違うのに接続できました");
+    assert!(matches!(error.downcast_ref::<ConnectError>(), Some(ConnectError::Rejected(_))), "{}", error);
+    listening.abort();
+
+    // https:// はUDPのため、プロキシは経由できない
+    let proxied = bob.peer.client().uri(format!("https://{}/", addr)).proxy(Some("socks5://127.0.0.1:9050".parse().unwrap())).build();
+    assert!(proxied.is_err());
+}